@@ -0,0 +1,195 @@
+//! Cache of chunks read from disk
+//!
+//! A lazy `ChunkStore` keeps only metadata in memory and reads chunks from
+//! their files on demand. Chunks read that way are kept here, keyed by
+//! `(content_hash, chunk_index)`, so a file being downloaded by several peers
+//! isn't read from disk once per request. The cache is bounded by the total
+//! size of the chunks it holds, evicting the least recently used first.
+
+use brisby_core::ContentHash;
+use std::collections::HashMap;
+
+/// Default bytes of chunk data kept by the seeder's chunk cache
+pub const DEFAULT_CHUNK_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Counters of a `ChunkCache`, for tuning its size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChunkCacheStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups that had to go to disk
+    pub misses: u64,
+    /// Chunks dropped to make room for others
+    pub evictions: u64,
+    /// Bytes of chunk data currently held
+    pub bytes: usize,
+}
+
+struct CachedChunk {
+    data: Vec<u8>,
+    /// Tick of the last lookup, for LRU eviction
+    last_used: u64,
+}
+
+/// Byte-bounded LRU cache of chunk data
+pub struct ChunkCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    entries: HashMap<(ContentHash, u32), CachedChunk>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl ChunkCache {
+    /// Create a cache holding at most `max_bytes` of chunk data
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            entries: HashMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Look up a chunk
+    pub fn get(&mut self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let Some(entry) = self.entries.get_mut(&(*content_hash, chunk_index)) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        entry.last_used = self.tick;
+        Some(entry.data.clone())
+    }
+
+    /// Store a chunk, evicting the least recently used until it fits
+    ///
+    /// Chunks larger than the whole cache aren't stored.
+    pub fn insert(&mut self, content_hash: &ContentHash, chunk_index: u32, data: Vec<u8>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+
+        self.remove(content_hash, chunk_index);
+        while self.used_bytes + data.len() > self.max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some((hash, index)) => {
+                    self.remove(&hash, index);
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.used_bytes += data.len();
+        self.entries.insert(
+            (*content_hash, chunk_index),
+            CachedChunk {
+                data,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    /// Drop one chunk
+    pub fn remove(&mut self, content_hash: &ContentHash, chunk_index: u32) {
+        if let Some(entry) = self.entries.remove(&(*content_hash, chunk_index)) {
+            self.used_bytes -= entry.data.len();
+        }
+    }
+
+    /// Drop every chunk of a file, e.g. after it was reloaded from disk
+    pub fn remove_file(&mut self, content_hash: &ContentHash) {
+        let used_bytes = &mut self.used_bytes;
+        self.entries.retain(|(hash, _), entry| {
+            let keep = hash != content_hash;
+            if !keep {
+                *used_bytes -= entry.data.len();
+            }
+            keep
+        });
+    }
+
+    /// Total size of the cached chunks
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    /// Hit, miss and eviction counts so far, and the bytes held now
+    pub fn stats(&self) -> ChunkCacheStats {
+        ChunkCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            bytes: self.used_bytes,
+        }
+    }
+
+    /// Number of cached chunks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used_to_fit() {
+        let mut cache = ChunkCache::new(10);
+        cache.insert(&[1u8; 32], 0, vec![0; 4]);
+        cache.insert(&[1u8; 32], 1, vec![1; 4]);
+
+        // Touch chunk 0 so chunk 1 is evicted to make room
+        assert!(cache.get(&[1u8; 32], 0).is_some());
+        cache.insert(&[2u8; 32], 0, vec![2; 6]);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.used_bytes(), 10);
+        assert!(cache.get(&[1u8; 32], 1).is_none());
+        assert_eq!(cache.get(&[1u8; 32], 0), Some(vec![0; 4]));
+
+        // Too big to cache at all, and nothing is evicted for it
+        cache.insert(&[3u8; 32], 0, vec![3; 11]);
+        assert_eq!(cache.len(), 2);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 1));
+        assert_eq!(stats.bytes, 10);
+    }
+
+    #[test]
+    fn test_remove_file() {
+        let mut cache = ChunkCache::new(100);
+        cache.insert(&[1u8; 32], 0, vec![0; 4]);
+        cache.insert(&[1u8; 32], 1, vec![1; 4]);
+        cache.insert(&[2u8; 32], 0, vec![2; 4]);
+
+        cache.remove_file(&[1u8; 32]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.used_bytes(), 4);
+        assert!(cache.get(&[2u8; 32], 0).is_some());
+
+        cache.remove(&[2u8; 32], 0);
+        assert!(cache.is_empty());
+        assert_eq!(cache.used_bytes(), 0);
+    }
+}
@@ -0,0 +1,158 @@
+//! Collections: sets of files shared as a unit
+//!
+//! A collection lists each file's full `FileMetadata` together with seeders
+//! to fetch it from, so a playlist or album can be handed to someone in one
+//! file and downloaded with `brisby import-collection` without searching for
+//! each part. Collections are written as JSON; `to_uris` gives the same set
+//! as `brisby://` URIs for reading or pasting, but those can't be imported
+//! since they lack the chunk layout.
+
+use crate::share::share_uri;
+use anyhow::{anyhow, Result};
+use brisby_core::FileMetadata;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Version of the collection format written by `to_json`
+pub const COLLECTION_VERSION: u32 = 1;
+
+/// One file of a collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionEntry {
+    pub metadata: FileMetadata,
+    /// Nym addresses (or relay routes) to download the file from
+    #[serde(default)]
+    pub seeders: Vec<String>,
+}
+
+/// A named list of files, see the module docs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Collection {
+    pub version: u32,
+    pub name: String,
+    pub files: Vec<CollectionEntry>,
+}
+
+impl Collection {
+    /// Create an empty collection
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            version: COLLECTION_VERSION,
+            name: name.into(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Add a file, or more seeders for a file already in the collection
+    pub fn add(&mut self, metadata: FileMetadata, seeders: &[String]) {
+        let entry = match self
+            .files
+            .iter_mut()
+            .find(|entry| entry.metadata.content_hash == metadata.content_hash)
+        {
+            Some(entry) => entry,
+            None => {
+                self.files.push(CollectionEntry {
+                    metadata,
+                    seeders: Vec::new(),
+                });
+                self.files.last_mut().expect("just pushed")
+            }
+        };
+        for seeder in seeders {
+            if !entry.seeders.contains(seeder) {
+                entry.seeders.push(seeder.clone());
+            }
+        }
+    }
+
+    /// Total size of the files in bytes
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.metadata.size).sum()
+    }
+
+    /// Serialize as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parse a collection written by `to_json`
+    pub fn from_json(json: &str) -> Result<Self> {
+        let collection: Collection = serde_json::from_str(json)?;
+        if collection.version > COLLECTION_VERSION {
+            return Err(anyhow!(
+                "collection format version {} is newer than this client supports ({})",
+                collection.version,
+                COLLECTION_VERSION
+            ));
+        }
+        Ok(collection)
+    }
+
+    /// The files' `brisby://` URIs, one per line
+    pub fn to_uris(&self) -> String {
+        self.files
+            .iter()
+            .map(|entry| share_uri(&entry.metadata) + "\n")
+            .collect()
+    }
+
+    /// Read a collection file
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Self::from_json(&json)
+            .map_err(|e| anyhow!("Invalid collection {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeder::ChunkStore;
+
+    #[test]
+    fn test_collection_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let mut shared = Vec::new();
+        let files = [("01 intro.flac", 1000), ("02 song.flac", 300_000), ("cover.jpg", 10)];
+        for (name, len) in files {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, vec![len as u8; len]).unwrap();
+            shared.push(store.add_file(&path).unwrap());
+        }
+
+        let mut collection = Collection::new("Live album");
+        let seeders = vec!["seeder-a".to_string()];
+        for metadata in &shared {
+            collection.add(metadata.clone(), &seeders);
+        }
+        // Adding a file again only merges in new seeders
+        let more = ["seeder-a".to_string(), "seeder-b".to_string()];
+        collection.add(shared[1].clone(), &more);
+        assert_eq!(collection.files.len(), 3);
+        assert_eq!(collection.files[1].seeders, vec!["seeder-a", "seeder-b"]);
+        assert_eq!(collection.total_size(), 1000 + 300_000 + 10);
+
+        let path = temp_dir.path().join("album.json");
+        std::fs::write(&path, collection.to_json().unwrap()).unwrap();
+        let parsed = Collection::load(&path).unwrap();
+        assert_eq!(parsed, collection);
+        assert_eq!(parsed.files[1].metadata.chunks, shared[1].chunks);
+
+        let uris = collection.to_uris();
+        assert_eq!(uris.lines().count(), 3);
+        assert_eq!(uris.lines().next().unwrap(), share_uri(&shared[0]));
+    }
+
+    #[test]
+    fn test_rejects_newer_or_malformed_collections() {
+        let mut newer = Collection::new("future");
+        newer.version = COLLECTION_VERSION + 1;
+        let error = Collection::from_json(&newer.to_json().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("newer"), "{}", error);
+
+        assert!(Collection::from_json("{\"name\": \"no files\"}").is_err());
+        assert!(Collection::from_json("brisby://abc").is_err());
+    }
+}
@@ -1,12 +1,33 @@
 //! Client configuration
 
+use crate::chunk_cache::DEFAULT_CHUNK_CACHE_BYTES;
+use crate::dispatch::{RequestTimeouts, DEFAULT_FIRST_RESPONSE_TIMEOUT, DEFAULT_IDLE_TIMEOUT};
+use crate::response_cache::DEFAULT_RESPONSE_CACHE_ENTRIES;
+use crate::search_cache::{
+    DEFAULT_CACHE_MAX_ENTRIES, DEFAULT_CACHE_MAX_STALE_SECS, DEFAULT_CACHE_TTL_SECS,
+};
+use crate::seeder::{
+    SeederLimits, DEFAULT_MAX_IN_FLIGHT_PER_CONTENT, DEFAULT_MAX_IN_FLIGHT_TOTAL,
+    DEFAULT_STATS_INTERVAL_SECS, DEFAULT_VERIFY_BATCH_SIZE,
+};
+use brisby_core::TransportConfig;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
+/// Settings from the config file
+///
+/// Sections and keys left out of the file take their defaults, and command
+/// line flags override whatever the file says.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     /// Data directory path
     pub data_dir: String,
 
+    /// Nym identity directory (defaults to a location outside data_dir)
+    pub identity_dir: Option<String>,
+
     /// Index provider configuration
     pub index_providers: Vec<IndexProviderConfig>,
 
@@ -15,6 +36,12 @@ pub struct Config {
 
     /// Transfer configuration
     pub transfer: TransferConfig,
+
+    /// Search cache configuration
+    pub search_cache: SearchCacheConfig,
+
+    /// Seeder configuration
+    pub seeder: SeederConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,17 +63,134 @@ pub struct DhtConfig {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TransferConfig {
     /// Maximum concurrent chunk requests
     pub max_concurrent_requests: usize,
-    /// Chunk request timeout in seconds
+    /// How long a download waits with no chunk response arriving, in
+    /// seconds
     pub request_timeout_secs: u64,
+    /// How long a seeder has to answer at all before it's given up on, in
+    /// seconds
+    pub first_response_timeout_secs: u64,
+    /// Seeders whose chunks are accepted without per-chunk verification;
+    /// the whole file is still checked once complete
+    pub trusted_seeders: Vec<String>,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 50,
+            request_timeout_secs: DEFAULT_IDLE_TIMEOUT.as_secs(),
+            first_response_timeout_secs: DEFAULT_FIRST_RESPONSE_TIMEOUT.as_secs(),
+            trusted_seeders: vec![],
+        }
+    }
+}
+
+impl TransferConfig {
+    /// Timeouts for chunk requests
+    pub fn request_timeouts(&self) -> RequestTimeouts {
+        RequestTimeouts {
+            first_response: Duration::from_secs(self.first_response_timeout_secs),
+            idle: Duration::from_secs(self.request_timeout_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchCacheConfig {
+    /// Whether search responses are cached
+    pub enabled: bool,
+    /// How long a cached response stays fresh, in seconds
+    pub ttl_secs: u64,
+    /// How long past `ttl_secs` a cached response is still shown while it
+    /// is refreshed, in seconds
+    pub max_stale_secs: u64,
+    /// Maximum number of cached responses
+    pub max_entries: usize,
+}
+
+impl Default for SearchCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: DEFAULT_CACHE_TTL_SECS,
+            max_stale_secs: DEFAULT_CACHE_MAX_STALE_SECS,
+            max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeederConfig {
+    /// Maximum concurrent chunk responses for any one file
+    pub max_in_flight_per_content: usize,
+    /// Maximum concurrent chunk responses overall
+    pub max_in_flight_total: usize,
+    /// Number of encoded chunk responses to cache for reuse (0 disables)
+    pub response_cache_size: usize,
+    /// Number of most requested files kept in memory across restarts; others
+    /// are read from disk on demand (0 loads every file into memory)
+    pub hot_set_size: usize,
+    /// Bytes of chunks read from disk to keep in memory. Files are only
+    /// loaded into memory up front if they all fit (0 loads every file)
+    pub chunk_cache_size: usize,
+    /// Count and log chunk requests without sending any chunk data
+    pub dry_run: bool,
+    /// Upload cap in bytes per second (0 for no limit)
+    pub upload_rate_limit: u64,
+    /// Seconds between scheduled re-verification passes (0 disables)
+    pub verify_interval_secs: u64,
+    /// Chunks checked per re-verification pass
+    pub verify_batch_size: usize,
+    /// Seconds between printed serve statistics summaries (0 disables)
+    pub stats_interval_secs: u64,
+    /// Serve files some chunks of which are missing; when off, such files
+    /// are neither served nor published
+    pub serve_incomplete: bool,
+    /// Zstd-compress chunk data before sending it, where that makes it
+    /// smaller
+    pub compress_chunks: bool,
+}
+
+impl Default for SeederConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_per_content: DEFAULT_MAX_IN_FLIGHT_PER_CONTENT,
+            max_in_flight_total: DEFAULT_MAX_IN_FLIGHT_TOTAL,
+            response_cache_size: DEFAULT_RESPONSE_CACHE_ENTRIES,
+            hot_set_size: 0,
+            chunk_cache_size: DEFAULT_CHUNK_CACHE_BYTES,
+            dry_run: false,
+            upload_rate_limit: 0,
+            verify_interval_secs: 0,
+            verify_batch_size: DEFAULT_VERIFY_BATCH_SIZE,
+            stats_interval_secs: DEFAULT_STATS_INTERVAL_SECS,
+            serve_incomplete: true,
+            compress_chunks: false,
+        }
+    }
+}
+
+impl SeederConfig {
+    /// Seeder limits described by this configuration
+    pub fn limits(&self) -> SeederLimits {
+        SeederLimits {
+            max_per_content: self.max_in_flight_per_content,
+            max_total: self.max_in_flight_total,
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             data_dir: "~/.brisby".to_string(),
+            identity_dir: None,
             index_providers: vec![IndexProviderConfig {
                 name: "default".to_string(),
                 nym_address: "".to_string(), // TODO: Set default provider
@@ -56,10 +200,9 @@ impl Default for Config {
                 k: 20,
                 alpha: 3,
             },
-            transfer: TransferConfig {
-                max_concurrent_requests: 50,
-                request_timeout_secs: 30,
-            },
+            transfer: TransferConfig::default(),
+            search_cache: SearchCacheConfig::default(),
+            seeder: SeederConfig::default(),
         }
     }
 }
@@ -72,13 +215,196 @@ impl Config {
         Ok(config)
     }
 
+    /// Load configuration from a file, or the defaults if there is no file
+    pub fn load_or_default(path: &std::path::Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::load(path)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))
+    }
+
     /// Expand ~ in data_dir path
-    pub fn data_dir(&self) -> std::path::PathBuf {
-        if self.data_dir.starts_with("~/") {
-            if let Some(home) = dirs::home_dir() {
-                return home.join(&self.data_dir[2..]);
-            }
+    pub fn data_dir(&self) -> anyhow::Result<PathBuf> {
+        expand_path(&self.data_dir)
+    }
+
+    /// Directory where the Nym identity is persisted
+    ///
+    /// Kept separate from the data directory so content can be backed up or
+    /// copied without carrying the mixnet identity along. Without an explicit
+    /// `identity_dir`, an identity still at `<data_dir>/nym` from before the
+    /// two were separated is kept, so the seeder's address doesn't change.
+    pub fn identity_dir(&self) -> anyhow::Result<PathBuf> {
+        if let Some(dir) = &self.identity_dir {
+            return expand_path(dir);
         }
-        std::path::PathBuf::from(&self.data_dir)
+        let legacy = self.data_dir()?.join(LEGACY_IDENTITY_DIR);
+        if legacy.is_dir() {
+            return Ok(legacy);
+        }
+        default_identity_dir()
+    }
+
+    /// Transport configuration that persists the identity in `identity_dir()`
+    pub fn transport_config(&self) -> anyhow::Result<TransportConfig> {
+        Ok(TransportConfig {
+            storage_path: Some(self.identity_dir()?),
+            ..Default::default()
+        })
+    }
+}
+
+/// Expand a leading `~` to the user's home directory
+///
+/// Fails instead of keeping a literal `~` when the home directory can't be
+/// determined, as happens for some service accounts and containers, so a
+/// directory named `~` is never created by accident.
+pub fn expand_path(path: &str) -> anyhow::Result<PathBuf> {
+    expand_path_with_home(path, dirs::home_dir())
+}
+
+fn expand_path_with_home(path: &str, home: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let rest = if path == "~" {
+        ""
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        rest
+    } else {
+        return Ok(PathBuf::from(path));
+    };
+
+    let home = home.ok_or_else(|| {
+        anyhow::anyhow!(
+            "cannot expand '{}': home directory is unknown, use an absolute path instead",
+            path
+        )
+    })?;
+    Ok(if rest.is_empty() { home } else { home.join(rest) })
+}
+
+/// Where the Nym identity was kept inside the data directory before
+/// `identity_dir` existed
+pub const LEGACY_IDENTITY_DIR: &str = "nym";
+
+/// Default Nym identity directory (`<config dir>/brisby/identity`)
+///
+/// Fails if there is no config or home directory, rather than keeping the
+/// identity wherever the client happens to be run from.
+pub fn default_identity_dir() -> anyhow::Result<PathBuf> {
+    let base = dirs::config_dir().or_else(dirs::home_dir).ok_or_else(|| {
+        anyhow::anyhow!(
+            "no config or home directory for the Nym identity; \
+             pass --identity-dir or set identity_dir in the config"
+        )
+    })?;
+    Ok(base.join("brisby").join("identity"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_uses_identity_dir() {
+        let config = Config {
+            data_dir: "/srv/brisby-data".to_string(),
+            identity_dir: Some("/srv/brisby-identity".to_string()),
+            ..Default::default()
+        };
+
+        let transport_config = config.transport_config().unwrap();
+        let storage_path = transport_config.storage_path.unwrap();
+        assert_eq!(storage_path, PathBuf::from("/srv/brisby-identity"));
+        assert!(!storage_path.starts_with(config.data_dir().unwrap()));
+    }
+
+    #[test]
+    fn test_default_identity_dir_outside_data_dir() {
+        let config = Config {
+            data_dir: "/srv/brisby-data".to_string(),
+            ..Default::default()
+        };
+
+        let storage_path = config.transport_config().unwrap().storage_path.unwrap();
+        assert_eq!(storage_path, default_identity_dir().unwrap());
+        assert!(!storage_path.starts_with(config.data_dir().unwrap()));
+    }
+
+    #[test]
+    fn test_identity_in_data_dir_is_kept() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: temp_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        assert_eq!(config.identity_dir().unwrap(), default_identity_dir().unwrap());
+
+        let legacy = temp_dir.path().join(LEGACY_IDENTITY_DIR);
+        std::fs::create_dir(&legacy).unwrap();
+        assert_eq!(config.identity_dir().unwrap(), legacy);
+
+        // An explicit directory still wins
+        let config = Config {
+            identity_dir: Some("/srv/brisby-identity".to_string()),
+            ..config
+        };
+        assert_eq!(config.identity_dir().unwrap(), PathBuf::from("/srv/brisby-identity"));
+    }
+
+    #[test]
+    fn test_config_file_settings_take_effect() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.toml");
+        assert_eq!(Config::load_or_default(&path).unwrap().data_dir, "~/.brisby");
+
+        // Keys left out keep their defaults, even within a section
+        std::fs::write(
+            &path,
+            r#"
+            identity_dir = "/srv/brisby-identity"
+
+            [transfer]
+            trusted_seeders = ["home-box"]
+
+            [seeder]
+            compress_chunks = true
+            "#,
+        )
+        .unwrap();
+        let config = Config::load_or_default(&path).unwrap();
+        assert_eq!(
+            config.transport_config().unwrap().storage_path,
+            Some(PathBuf::from("/srv/brisby-identity"))
+        );
+        assert_eq!(config.transfer.trusted_seeders, vec!["home-box"]);
+        assert_eq!(config.transfer.max_concurrent_requests, 50);
+        assert!(config.seeder.compress_chunks);
+        assert_eq!(config.seeder.verify_batch_size, DEFAULT_VERIFY_BATCH_SIZE);
+        assert_eq!(config.data_dir, "~/.brisby");
+
+        std::fs::write(&path, "data_dir = 5").unwrap();
+        assert!(Config::load_or_default(&path).is_err());
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = Some(PathBuf::from("/home/brisby"));
+        assert_eq!(
+            expand_path_with_home("~/.brisby", home.clone()).unwrap(),
+            PathBuf::from("/home/brisby/.brisby")
+        );
+        assert_eq!(expand_path_with_home("~", home.clone()).unwrap(), PathBuf::from("/home/brisby"));
+        assert_eq!(expand_path_with_home("/srv/data", home.clone()).unwrap(), PathBuf::from("/srv/data"));
+        // Only a leading `~` component is expanded
+        assert_eq!(expand_path_with_home("~other", home).unwrap(), PathBuf::from("~other"));
+    }
+
+    #[test]
+    fn test_expand_path_without_home_fails() {
+        let err = expand_path_with_home("~/.brisby", None).unwrap_err();
+        assert!(err.to_string().contains("home directory is unknown"));
+
+        // Paths that don't need the home directory are unaffected
+        assert_eq!(expand_path_with_home("/srv/data", None).unwrap(), PathBuf::from("/srv/data"));
     }
 }
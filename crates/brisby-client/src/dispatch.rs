@@ -0,0 +1,318 @@
+//! Routing responses to the requests awaiting them
+//!
+//! Replies arrive in any order and can be interleaved with unrelated messages
+//! (pings, late replies to requests that already timed out), so the next
+//! message off the transport isn't necessarily the one a caller is waiting
+//! for. Each request registers its ID with a `ResponseRouter` before it is
+//! sent; received envelopes are matched on `Envelope::request_id` and handed
+//! to the registered request over a oneshot channel. Anything unmatched is
+//! dropped.
+//!
+//! Waits are bounded by `RequestTimeouts`: a short one for the first message
+//! to arrive, telling a peer that never answers from one that is just slow,
+//! and a longer idle one that restarts with every message received, so a
+//! wait isn't cut off while replies are still coming in.
+
+use anyhow::{anyhow, Result};
+use brisby_core::proto::Envelope;
+use brisby_core::Transport;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Default `RequestTimeouts::first_response`
+pub const DEFAULT_FIRST_RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Default `RequestTimeouts::idle`
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    /// Give up if nothing at all arrives within this long of starting to wait
+    pub first_response: Duration,
+    /// Once something has arrived, give up after this long without anything
+    /// more
+    pub idle: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            first_response: DEFAULT_FIRST_RESPONSE_TIMEOUT,
+            idle: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Pending requests keyed by request ID
+#[derive(Default)]
+pub struct ResponseRouter {
+    pending: Mutex<HashMap<u64, oneshot::Sender<Envelope>>>,
+    /// When a message last arrived on the transport
+    last_received: Mutex<Option<Instant>>,
+}
+
+impl ResponseRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start waiting for the response to `request_id`
+    ///
+    /// Register before sending the request, so a fast reply can't arrive
+    /// before anyone is waiting for it.
+    pub fn register(&self, request_id: u64) -> PendingResponse {
+        let (tx, rx) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        // Forget requests whose waiters have given up
+        pending.retain(|_, tx| !tx.is_closed());
+        pending.insert(request_id, tx);
+        PendingResponse { request_id, rx }
+    }
+
+    /// Stop waiting for the response to `request_id`
+    pub fn cancel(&self, request_id: u64) {
+        self.pending.lock().unwrap().remove(&request_id);
+    }
+
+    /// Number of requests still waiting for a response
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Hand an envelope to the request it answers
+    ///
+    /// Returns `false` if no request is waiting for it.
+    pub fn dispatch(&self, envelope: Envelope) -> bool {
+        let request_id = envelope.request_id;
+        let Some(tx) = self.pending.lock().unwrap().remove(&request_id) else {
+            tracing::debug!("Dropping response to unknown request {}", request_id);
+            return false;
+        };
+        tx.send(envelope).is_ok()
+    }
+
+    /// When a message last arrived while pumping, if one ever has
+    pub fn last_received(&self) -> Option<Instant> {
+        *self.last_received.lock().unwrap()
+    }
+
+    /// Receive one message from `transport` and dispatch it
+    ///
+    /// Returns once a message has been handled or `timeout` passes without one.
+    /// Messages that fail to decode are logged and dropped.
+    pub async fn pump<T: Transport>(&self, transport: &T, timeout: Duration) -> Result<()> {
+        let msg = transport
+            .receive_timeout(timeout)
+            .await
+            .map_err(|e| anyhow!("Failed to receive: {}", e))?;
+        let Some(msg) = msg else {
+            return Ok(());
+        };
+        *self.last_received.lock().unwrap() = Some(Instant::now());
+
+        // Decode in place so chunk data isn't copied out of the receive buffer
+        match Envelope::from_vec(msg.data) {
+            Ok(envelope) => {
+                self.dispatch(envelope);
+            }
+            Err(e) => tracing::warn!("Failed to decode response: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for `pending`'s response, pumping `transport`
+    ///
+    /// Responses to other requests received meanwhile are dispatched to them,
+    /// so several callers can wait concurrently on the same transport.
+    /// Returns `None` on timeout, after which the request is cancelled.
+    pub async fn wait<T: Transport>(
+        &self,
+        transport: &T,
+        pending: &mut PendingResponse,
+        timeout: Duration,
+    ) -> Result<Option<Envelope>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(envelope) = pending.try_take() {
+                return Ok(Some(envelope));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.cancel(pending.request_id);
+                return Ok(None);
+            }
+
+            // Another caller's pump may deliver our response while we're receiving
+            tokio::select! {
+                biased;
+                envelope = &mut *pending => {
+                    if let Some(envelope) = envelope {
+                        return Ok(Some(envelope));
+                    }
+                }
+                result = self.pump(transport, remaining) => result?,
+            }
+        }
+    }
+
+    /// Wait for `pending`'s response within `timeouts`, pumping `transport`
+    ///
+    /// Like `wait`, except that the wait gives up after
+    /// `timeouts.first_response` only if nothing arrived on the transport in
+    /// that time. Once messages are arriving it carries on until
+    /// `timeouts.idle` passes without one. Returns `None` on timeout, after
+    /// which the request is cancelled.
+    pub async fn wait_with<T: Transport>(
+        &self,
+        transport: &T,
+        pending: &mut PendingResponse,
+        timeouts: RequestTimeouts,
+    ) -> Result<Option<Envelope>> {
+        let start = Instant::now();
+        loop {
+            if let Some(envelope) = pending.try_take() {
+                return Ok(Some(envelope));
+            }
+            let deadline = match self.last_received().filter(|at| *at > start) {
+                Some(at) => at + timeouts.idle,
+                None => start + timeouts.first_response,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.cancel(pending.request_id);
+                return Ok(None);
+            }
+
+            tokio::select! {
+                biased;
+                envelope = &mut *pending => {
+                    if let Some(envelope) = envelope {
+                        return Ok(Some(envelope));
+                    }
+                }
+                result = self.pump(transport, remaining) => result?,
+            }
+        }
+    }
+}
+
+/// A registered request awaiting its response
+///
+/// Resolves to the response once it is dispatched, or `None` if the request
+/// was cancelled. Something must be pumping the transport for that to
+/// happen; `ResponseRouter::wait` does both.
+pub struct PendingResponse {
+    request_id: u64,
+    rx: oneshot::Receiver<Envelope>,
+}
+
+impl PendingResponse {
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Take the response if it has already been dispatched
+    pub fn try_take(&mut self) -> Option<Envelope> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Option<Envelope>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(Result::ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::proto::{self, Payload};
+    use brisby_core::transport::mock::MockTransport;
+    use brisby_core::ReceivedMessage;
+
+    fn ping_response(request_id: u64) -> ReceivedMessage {
+        let envelope = Envelope::new(
+            request_id,
+            Payload::PingResponse(proto::PingResponse {
+                responder_id: vec![],
+            }),
+        );
+        ReceivedMessage::new(envelope.to_bytes(), None)
+    }
+
+    #[tokio::test]
+    async fn test_responses_routed_by_request_id() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let router = ResponseRouter::new();
+
+        let mut first = router.register(1);
+        let mut second = router.register(2);
+
+        // Replies arrive out of order, with an unsolicited message in between
+        transport.queue_message(ping_response(2));
+        transport.queue_message(ping_response(99));
+        transport.queue_message(ping_response(1));
+
+        let timeout = Duration::from_secs(1);
+        let response = router.wait(&transport, &mut first, timeout).await.unwrap();
+        assert_eq!(response.unwrap().request_id, 1);
+
+        // Already dispatched while waiting for the first
+        let response = second.try_take().unwrap();
+        assert_eq!(response.request_id, 2);
+        assert_eq!(router.pending_count(), 0);
+
+        // Nothing arrives for a third request
+        let mut third = router.register(3);
+        let response = router
+            .wait(&transport, &mut third, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+        assert_eq!(router.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_with_tells_slow_from_silent() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let transport = std::sync::Arc::new(transport);
+        let router = ResponseRouter::new();
+        let timeouts = RequestTimeouts {
+            first_response: Duration::from_millis(150),
+            idle: Duration::from_millis(150),
+        };
+
+        // Nothing arrives: given up after the first-response timeout
+        let mut silent = router.register(1);
+        let start = Instant::now();
+        let response = router.wait_with(&*transport, &mut silent, timeouts).await.unwrap();
+        assert!(response.is_none());
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // Messages keep arriving, so the response is waited for well past
+        // either timeout
+        let mut slow = router.register(2);
+        let feeder = transport.clone();
+        tokio::spawn(async move {
+            for request_id in 100..108 {
+                tokio::time::sleep(Duration::from_millis(75)).await;
+                feeder.queue_message(ping_response(request_id));
+            }
+            feeder.queue_message(ping_response(2));
+        });
+        let start = Instant::now();
+        let response = router.wait_with(&*transport, &mut slow, timeouts).await.unwrap();
+        assert_eq!(response.unwrap().request_id, 2);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+    }
+}
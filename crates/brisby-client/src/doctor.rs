@@ -0,0 +1,217 @@
+//! Environment diagnostics for `brisby doctor`
+//!
+//! Each check inspects one piece of local setup and reports pass, warn or
+//! fail with a short explanation, so setup problems surface in one place
+//! instead of as cryptic errors from individual commands.
+
+use crate::config::Config;
+use crate::network;
+use brisby_core::{NymAddress, Transport};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Outcome of a single diagnostic check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+/// A named diagnostic check and its result
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    /// Create a check result
+    pub fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Check that the config file exists and parses
+///
+/// Returns the parsed config alongside the check so later checks can use it.
+pub fn check_config(path: &Path) -> (Check, Option<Config>) {
+    const NAME: &str = "config";
+
+    if !path.exists() {
+        let check = Check::new(
+            NAME,
+            CheckStatus::Warn,
+            format!("{} not found, using defaults (run `brisby init`)", path.display()),
+        );
+        return (check, None);
+    }
+
+    match Config::load(path) {
+        Ok(config) => (
+            Check::new(NAME, CheckStatus::Pass, format!("{} is valid", path.display())),
+            Some(config),
+        ),
+        Err(e) => (
+            Check::new(NAME, CheckStatus::Fail, format!("{} is invalid: {}", path.display(), e)),
+            None,
+        ),
+    }
+}
+
+/// Check that the data directory exists and is writable
+pub fn check_data_dir(path: &Path) -> Check {
+    const NAME: &str = "data dir";
+
+    if !path.exists() {
+        return Check::new(
+            NAME,
+            CheckStatus::Warn,
+            format!("{} does not exist yet (created on first share)", path.display()),
+        );
+    }
+    if !path.is_dir() {
+        return Check::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("{} is not a directory", path.display()),
+        );
+    }
+
+    let probe = path.join(".brisby-doctor-probe");
+    match std::fs::write(&probe, b"ok") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            Check::new(NAME, CheckStatus::Pass, format!("{} is writable", path.display()))
+        }
+        Err(e) => Check::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("{} is not writable: {}", path.display(), e),
+        ),
+    }
+}
+
+/// Check whether the binary was built with the `nym` feature
+pub fn check_nym_feature(enabled: bool) -> Check {
+    const NAME: &str = "nym feature";
+
+    if enabled {
+        Check::new(NAME, CheckStatus::Pass, "built with Nym support")
+    } else {
+        Check::new(
+            NAME,
+            CheckStatus::Warn,
+            "built without Nym support; only --mock works (rebuild with --features nym)",
+        )
+    }
+}
+
+/// Check whether a persisted Nym identity exists
+pub fn check_identity(dir: &Path) -> Check {
+    const NAME: &str = "identity";
+
+    let has_files = std::fs::read_dir(dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+
+    if has_files {
+        Check::new(NAME, CheckStatus::Pass, format!("found in {}", dir.display()))
+    } else {
+        Check::new(
+            NAME,
+            CheckStatus::Warn,
+            format!("none in {} (created on first seed)", dir.display()),
+        )
+    }
+}
+
+/// Check that an index provider answers a ping
+pub async fn check_index_provider<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    timeout: Duration,
+) -> Check {
+    const NAME: &str = "index provider";
+
+    match network::ping_index_provider(transport, index_provider, timeout).await {
+        Ok(rtt) => Check::new(
+            NAME,
+            CheckStatus::Pass,
+            format!("responded in {} ms", rtt.as_millis()),
+        ),
+        Err(e) => Check::new(NAME, CheckStatus::Fail, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_check_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.toml");
+
+        let (check, config) = check_config(&path);
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(config.is_none());
+
+        std::fs::write(&path, "not = [valid").unwrap();
+        let (check, config) = check_config(&path);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(config.is_none());
+
+        std::fs::write(&path, toml::to_string_pretty(&Config::default()).unwrap()).unwrap();
+        let (check, config) = check_config(&path);
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(config.is_some());
+    }
+
+    #[test]
+    fn test_check_data_dir() {
+        let temp_dir = TempDir::new().unwrap();
+
+        assert_eq!(check_data_dir(temp_dir.path()).status, CheckStatus::Pass);
+        assert_eq!(
+            check_data_dir(&temp_dir.path().join("missing")).status,
+            CheckStatus::Warn
+        );
+
+        let file = temp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(check_data_dir(&file).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_check_nym_feature() {
+        assert_eq!(check_nym_feature(true).status, CheckStatus::Pass);
+        assert_eq!(check_nym_feature(false).status, CheckStatus::Warn);
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let temp_dir = TempDir::new().unwrap();
+        assert_eq!(check_identity(temp_dir.path()).status, CheckStatus::Warn);
+
+        std::fs::write(temp_dir.path().join("private_identity.pem"), b"key").unwrap();
+        assert_eq!(check_identity(temp_dir.path()).status, CheckStatus::Pass);
+    }
+}
@@ -0,0 +1,177 @@
+//! Persistent download state for resuming downloads
+//!
+//! A resumable download records what it is fetching (metadata and seeders)
+//! through a `DownloadStateStore`, so the backend can be swapped: files on
+//! disk for the CLI, memory for tests, or whatever an embedding application
+//! already uses. Which chunks have arrived is known from the
+//! `PartialDownload` holding them, so it isn't saved twice.
+
+use anyhow::Result;
+use brisby_core::{ContentHash, FileMetadata};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Everything needed to resume a download
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedDownloadState {
+    pub metadata: FileMetadata,
+    /// Seeder addresses known for the content
+    pub seeders: Vec<String>,
+}
+
+impl SavedDownloadState {
+    pub fn new(metadata: FileMetadata, seeders: Vec<String>) -> Self {
+        Self { metadata, seeders }
+    }
+
+    pub fn content_hash(&self) -> &ContentHash {
+        &self.metadata.content_hash
+    }
+}
+
+/// Backend persisting download state between runs
+pub trait DownloadStateStore: Send + Sync {
+    /// Save state, replacing any earlier state for the same content
+    fn save(&self, state: &SavedDownloadState) -> Result<()>;
+
+    /// Load the state saved for `content_hash`, if any
+    fn load(&self, content_hash: &ContentHash) -> Result<Option<SavedDownloadState>>;
+
+    /// Forget the state for `content_hash`; a no-op if none is saved
+    fn remove(&self, content_hash: &ContentHash) -> Result<()>;
+}
+
+/// Stores each download's state as `<dir>/<content hash>/state.json`
+///
+/// Uses the same per-content directories as `PartialDownload`, so state sits
+/// next to the chunks it describes.
+pub struct FsDownloadStateStore {
+    dir: PathBuf,
+}
+
+impl FsDownloadStateStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn state_path(&self, content_hash: &ContentHash) -> PathBuf {
+        self.dir
+            .join(brisby_core::hash_to_hex(content_hash))
+            .join("state.json")
+    }
+}
+
+impl DownloadStateStore for FsDownloadStateStore {
+    fn save(&self, state: &SavedDownloadState) -> Result<()> {
+        let path = self.state_path(state.content_hash());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replace atomically so an interrupted save keeps the previous state
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(state)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn load(&self, content_hash: &ContentHash) -> Result<Option<SavedDownloadState>> {
+        let path = self.state_path(content_hash);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+    }
+
+    fn remove(&self, content_hash: &ContentHash) -> Result<()> {
+        match std::fs::remove_file(self.state_path(content_hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps download state in memory, for tests and short-lived embedders
+#[derive(Default)]
+pub struct MemoryDownloadStateStore {
+    states: Mutex<HashMap<ContentHash, SavedDownloadState>>,
+}
+
+impl MemoryDownloadStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DownloadStateStore for MemoryDownloadStateStore {
+    fn save(&self, state: &SavedDownloadState) -> Result<()> {
+        self.states
+            .lock()
+            .unwrap()
+            .insert(*state.content_hash(), state.clone());
+        Ok(())
+    }
+
+    fn load(&self, content_hash: &ContentHash) -> Result<Option<SavedDownloadState>> {
+        Ok(self.states.lock().unwrap().get(content_hash).cloned())
+    }
+
+    fn remove(&self, content_hash: &ContentHash) -> Result<()> {
+        self.states.lock().unwrap().remove(content_hash);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::{ChunkInfo, HashAlgorithm};
+
+    fn metadata(chunk_count: u32) -> FileMetadata {
+        FileMetadata {
+            content_hash: [4u8; 32],
+            hash_algo: HashAlgorithm::default(),
+            filename: "album.flac".to_string(),
+            size: chunk_count as u64 * 10,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            chunks: (0..chunk_count)
+                .map(|index| ChunkInfo {
+                    index,
+                    hash: [index as u8; 32],
+                    size: 10,
+                })
+                .collect(),
+            keywords: vec![],
+            created_at: 0,
+            encryption: None,
+        }
+    }
+
+    fn round_trip(store: &dyn DownloadStateStore) {
+        let state = SavedDownloadState::new(metadata(10), vec!["seeder".to_string()]);
+        store.save(&state).unwrap();
+
+        let loaded = store.load(&[4u8; 32]).unwrap().unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.metadata.chunks.len(), 10);
+
+        store.remove(&[4u8; 32]).unwrap();
+        assert!(store.load(&[4u8; 32]).unwrap().is_none());
+        // Removing again is harmless
+        store.remove(&[4u8; 32]).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        round_trip(&MemoryDownloadStateStore::new());
+    }
+
+    #[test]
+    fn test_fs_store_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        round_trip(&FsDownloadStateStore::new(temp_dir.path().to_path_buf()));
+    }
+}
@@ -2,15 +2,108 @@
 //!
 //! Handles downloading files chunk by chunk from seeders via the Nym network.
 
+use crate::dispatch::{PendingResponse, RequestTimeouts, ResponseRouter};
+use crate::download_store::{DownloadStateStore, SavedDownloadState};
+use crate::inspect::{chunk_status, ChunkStatus};
+use crate::partials::PartialDownload;
+use crate::reassembly::{chunk_offset, Decryption, ReassemblyWriter};
+use crate::seeder::ChunkStore;
+use crate::seeder_stats::{SeederScoreboard, SeederStats};
 use anyhow::{anyhow, Result};
 use brisby_core::proto::{self, Envelope, Payload};
-use brisby_core::{chunk::verify_chunk, ContentHash, FileMetadata, NymAddress, Transport};
+use brisby_core::{
+    chunk::{decompress_chunk, verify_chunk, verify_chunk_with_proof, ChunkKey},
+    retry_with_backoff, ChunkEncryption, ContentHash, FileMetadata, HashAlgorithm, NymAddress,
+    SeederRoute, Transport,
+};
 use std::collections::{HashMap, HashSet};
-use std::io::Write;
-use std::path::Path;
+use std::io::{Seek, SeekFrom, Write};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+/// A chunk received from a seeder, already checked against the chunk hash
+/// it came with
+#[derive(Debug, Clone)]
+pub struct ChunkReply {
+    pub index: u32,
+    pub data: Vec<u8>,
+    /// File the seeder says the chunk belongs to
+    pub content_hash: ContentHash,
+    /// Merkle proof of the chunk, empty if the seeder sent none
+    pub proof: Vec<ContentHash>,
+}
+
+/// Chunk hashes from a source trusted independently of the index and seeders
+///
+/// Read from a file manifest (the `metadata.json` or `metadata.bin` a
+/// seeder's chunk store keeps) obtained out of band. When set, every chunk
+/// must match its hash in the list, whatever hash or proof the seeder sent
+/// along with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalHashList {
+    pub content_hash: ContentHash,
+    pub hash_algo: HashAlgorithm,
+    /// Hash of each chunk, by index
+    pub hashes: Vec<ContentHash>,
+    /// How the chunks are encrypted, if they are
+    pub encryption: Option<ChunkEncryption>,
+}
+
+impl ExternalHashList {
+    pub fn from_metadata(metadata: &FileMetadata) -> Self {
+        Self {
+            content_hash: metadata.content_hash,
+            hash_algo: metadata.hash_algo,
+            hashes: metadata.chunks.iter().map(|chunk| chunk.hash).collect(),
+            encryption: metadata.encryption,
+        }
+    }
+
+    /// Read the chunk hashes from a manifest file, in either metadata format
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let (metadata, _) = crate::metadata_file::decode(&bytes)
+            .map_err(|e| anyhow!("Invalid manifest {}: {}", path.display(), e))?;
+        Ok(Self::from_metadata(&metadata))
+    }
+
+    /// Use the list's chunk hashes, algorithm and encryption in `metadata`
+    ///
+    /// Fails if the list is for other content or a different chunk count.
+    pub fn apply_to(&self, metadata: &mut FileMetadata) -> Result<()> {
+        if self.content_hash != metadata.content_hash {
+            return Err(anyhow!(
+                "Hash list is for {}, not {}",
+                brisby_core::hash_to_hex(&self.content_hash),
+                brisby_core::hash_to_hex(&metadata.content_hash)
+            ));
+        }
+        if self.hashes.len() != metadata.chunks.len() {
+            return Err(anyhow!(
+                "Hash list has {} chunks, expected {}",
+                self.hashes.len(),
+                metadata.chunks.len()
+            ));
+        }
+        metadata.hash_algo = self.hash_algo;
+        metadata.encryption = self.encryption;
+        for (chunk, hash) in metadata.chunks.iter_mut().zip(&self.hashes) {
+            chunk.hash = *hash;
+        }
+        Ok(())
+    }
+
+    /// Check chunk `chunk_index` against the list
+    pub fn verify(&self, chunk_index: u32, data: &[u8]) -> bool {
+        self.hashes
+            .get(chunk_index as usize)
+            .is_some_and(|expected| verify_chunk(self.hash_algo, data, expected))
+    }
+}
+
 /// Download state for tracking progress
 #[derive(Debug, Clone)]
 pub struct DownloadState {
@@ -52,10 +145,75 @@ impl DownloadState {
     }
 }
 
+/// Suffix of the zero-filled file `reassemble_available` writes next to the
+/// output file
+pub const PARTIAL_OUTPUT_SUFFIX: &str = ".part";
+
+/// Where `reassemble_available` writes the incomplete file for `output_path`
+///
+/// Kept apart from `output_path` itself so a later run, which won't replace
+/// an existing file by default, still finishes the download.
+pub fn partial_output_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_os_string();
+    path.push(PARTIAL_OUTPUT_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Times a chunk is asked of the same seeder, when it keeps timing out,
+/// before the next seeder is tried
+const ATTEMPTS_PER_SEEDER: u32 = 2;
+
+/// Wait before asking a seeder again, doubled for each further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// How a download ended, see `Downloader::with_completion_threshold`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    /// Every chunk was fetched
+    Complete,
+    /// The completion threshold was reached with these chunks still missing,
+    /// so the file can't be checked against its content hash
+    Partial { missing: Vec<u32> },
+}
+
+impl DownloadOutcome {
+    fn from_missing(missing: Vec<u32>) -> Self {
+        if missing.is_empty() {
+            DownloadOutcome::Complete
+        } else {
+            DownloadOutcome::Partial { missing }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        *self == DownloadOutcome::Complete
+    }
+}
+
 /// Downloader for fetching files from the network
 pub struct Downloader<'a, T: Transport> {
     transport: &'a T,
     request_counter: AtomicU64,
+    /// Routes chunk responses to the requests awaiting them
+    router: ResponseRouter,
+    /// Where resumable downloads record their progress, if anywhere
+    state_store: Option<&'a dyn DownloadStateStore>,
+    /// Chunks already held locally, see `with_local_store`
+    local_store: Option<&'a ChunkStore>,
+    /// Most seeders asked for any one chunk before giving up on it
+    max_seeders_per_chunk: Option<usize>,
+    /// How each seeder has done so far, for choosing whom to ask
+    scoreboard: SeederScoreboard,
+    /// Trusted chunk hashes every chunk must match, if given
+    external_hashes: Option<ExternalHashList>,
+    /// Fraction of chunks after which resumable downloads stop, if below 1
+    completion_threshold: Option<f64>,
+    /// Seeders whose chunks aren't hash-checked one by one
+    trusted_seeders: HashSet<NymAddress>,
+    /// How long to wait for chunk responses
+    timeouts: RequestTimeouts,
+    /// Key to decrypt encrypted files with, see `with_key`
+    key: Option<ChunkKey>,
 }
 
 impl<'a, T: Transport> Downloader<'a, T> {
@@ -64,7 +222,189 @@ impl<'a, T: Transport> Downloader<'a, T> {
         Self {
             transport,
             request_counter: AtomicU64::new(1),
+            router: ResponseRouter::new(),
+            state_store: None,
+            local_store: None,
+            max_seeders_per_chunk: None,
+            scoreboard: SeederScoreboard::new(),
+            external_hashes: None,
+            completion_threshold: None,
+            trusted_seeders: HashSet::new(),
+            timeouts: RequestTimeouts::default(),
+            key: None,
+        }
+    }
+
+    /// Record resumable download progress in `store`
+    pub fn with_state_store(mut self, store: &'a dyn DownloadStateStore) -> Self {
+        self.state_store = Some(store);
+        self
+    }
+
+    /// Take chunks `store` already holds instead of fetching them
+    ///
+    /// Resumable downloads copy every chunk of the file the store has and
+    /// that matches its hash, and only request the rest. A file held in full
+    /// is written out without sending anything.
+    pub fn with_local_store(mut self, store: &'a ChunkStore) -> Self {
+        self.local_store = Some(store);
+        self
+    }
+
+    /// Give up on a chunk after asking this many seeders (0 asks them all)
+    ///
+    /// The cap applies to the order given, which for search results is most
+    /// recently published first, so it keeps the likeliest ones. A chunk
+    /// nobody has then fails after a few quick "not found" replies instead of
+    /// a pass over the whole list.
+    pub fn with_max_seeders_per_chunk(mut self, max: usize) -> Self {
+        self.max_seeders_per_chunk = (max > 0).then_some(max);
+        self
+    }
+
+    /// Spread equally scored seeders across gateways, see
+    /// `SeederScoreboard::with_gateway_diversity`
+    pub fn with_gateway_diversity(mut self, enabled: bool) -> Self {
+        self.scoreboard = self.scoreboard.with_gateway_diversity(enabled);
+        self
+    }
+
+    /// Check every chunk against `hashes` as well
+    ///
+    /// This catches chunks that a seeder's own hash would vouch for, e.g. for
+    /// files with a flat content hash, where chunks carry no proof.
+    pub fn with_external_hashes(mut self, hashes: ExternalHashList) -> Self {
+        self.external_hashes = Some(hashes);
+        self
+    }
+
+    /// Stop once this fraction of the file's chunks is verified, e.g. 0.9
+    /// for a preview that doesn't need the last rare chunks
+    ///
+    /// Applies to `download_resumable`, which then reports a
+    /// `DownloadOutcome::Partial` listing the chunks not fetched.
+    /// Thresholds of 1 or more (or not above 0) mean the whole file.
+    pub fn with_completion_threshold(mut self, threshold: f64) -> Self {
+        self.completion_threshold = (threshold > 0.0 && threshold < 1.0).then_some(threshold);
+        self
+    }
+
+    /// Accept chunks from `seeders` without checking each one's hash or
+    /// proof
+    ///
+    /// Only for seeders you run or otherwise trust: a bad chunk from one of
+    /// them is only caught by the whole-file check once the download is
+    /// complete, which then fails as a whole. Saves hashing every chunk
+    /// twice on downloads between your own machines.
+    pub fn with_trusted_seeders(mut self, seeders: impl IntoIterator<Item = NymAddress>) -> Self {
+        self.trusted_seeders.extend(seeders);
+        self
+    }
+
+    /// Wait for chunk responses within `timeouts`
+    ///
+    /// A seeder that hasn't answered anything within
+    /// `timeouts.first_response` is given up on, and in parallel downloads
+    /// not asked again. Otherwise requests wait as long as responses keep
+    /// arriving, until `timeouts.idle` passes without any.
+    pub fn with_timeouts(mut self, timeouts: RequestTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Decrypt encrypted files with `key` as they're written out
+    ///
+    /// Chunks are still verified as sent, before decrypting. Writing out a
+    /// file that isn't encrypted, or was encrypted under another key, fails.
+    /// Without a key, encrypted files are written out encrypted.
+    pub fn with_key(mut self, key: ChunkKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    fn is_trusted(&self, seeder: &NymAddress) -> bool {
+        self.trusted_seeders.contains(seeder)
+    }
+
+    /// Check a reply from `seeder` with `check_chunk_reply` and against any
+    /// external hashes, or only that it is the chunk asked for if the seeder
+    /// is trusted
+    fn check_reply(
+        &self,
+        metadata: &FileMetadata,
+        requested: u32,
+        reply: &ChunkReply,
+        seeder: &NymAddress,
+    ) -> brisby_core::Result<()> {
+        if self.is_trusted(seeder) {
+            return check_chunk_identity(metadata, requested, reply);
+        }
+        check_chunk_reply(metadata, requested, reply)?;
+        if let Some(hashes) = &self.external_hashes {
+            if !hashes.verify(reply.index, &reply.data) {
+                return Err(brisby_core::Error::InvalidData(format!(
+                    "chunk {} doesn't match the external hash list",
+                    reply.index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// How each seeder asked so far has done, most successful first
+    pub fn seeder_stats(&self) -> Vec<(NymAddress, SeederStats)> {
+        self.scoreboard.snapshot()
+    }
+
+    /// The seeders to ask for each chunk, most promising first
+    ///
+    /// See `SeederScoreboard::rank`.
+    fn seeders_to_try(&self, seeders: &[NymAddress]) -> Vec<NymAddress> {
+        let capped = match self.max_seeders_per_chunk {
+            Some(max) => &seeders[..max.min(seeders.len())],
+            None => seeders,
+        };
+        self.scoreboard.rank(capped)
+    }
+
+    /// The next seeder in round-robin order, passing over benched ones
+    /// unless all of them are
+    fn next_seeder<'s>(
+        &self,
+        seeders: &'s [NymAddress],
+        seeder_index: &mut usize,
+    ) -> &'s NymAddress {
+        let start = *seeder_index;
+        for offset in 0..seeders.len() {
+            let seeder = &seeders[(start + offset) % seeders.len()];
+            if !self.scoreboard.is_benched(seeder) {
+                *seeder_index = start + offset + 1;
+                return seeder;
+            }
+        }
+        *seeder_index = start + 1;
+        &seeders[start % seeders.len()]
+    }
+
+    /// `next_seeder`, also passing over `silent` seeders unless all of
+    /// them are
+    fn next_live_seeder<'s>(
+        &self,
+        seeders: &'s [NymAddress],
+        seeder_index: &mut usize,
+        silent: &HashSet<NymAddress>,
+    ) -> &'s NymAddress {
+        let first = self.next_seeder(seeders, seeder_index);
+        if !silent.contains(first) {
+            return first;
+        }
+        for _ in 1..seeders.len() {
+            let seeder = self.next_seeder(seeders, seeder_index);
+            if !silent.contains(seeder) {
+                return seeder;
+            }
         }
+        first
     }
 
     /// Get a unique request ID
@@ -73,29 +413,59 @@ impl<'a, T: Transport> Downloader<'a, T> {
     }
 
     /// Request a specific chunk from a seeder
+    ///
+    /// Returns the pending request, which resolves to the response carrying
+    /// its request ID; pass it to `receive_chunk` to wait for it.
+    ///
+    /// A relay route is sent to its index provider wrapped in a
+    /// `RelayRequest`; the reply comes back from the index under the same
+    /// request ID, so callers don't need to tell the two apart.
     pub async fn request_chunk(
         &self,
         seeder: &NymAddress,
         content_hash: &ContentHash,
         chunk_index: u32,
-    ) -> Result<()> {
+    ) -> Result<PendingResponse> {
         let request_id = self.next_request_id();
+        let pending = self.router.register(request_id);
 
-        // Create SURB placeholder - in real implementation, we'd use Nym's SURB system
-        // For now we use an empty SURB since we're doing request-response pattern
-        let surb = Vec::new();
-
+        // The seeder replies through the SURBs Nym attaches to the message,
+        // by sender tag, so the request's own SURB field stays empty
         let envelope = proto::chunk_request(
             request_id,
             content_hash.to_vec(),
             chunk_index,
-            surb,
+            Vec::new(),
         );
 
-        self.transport
-            .send(seeder, envelope.to_bytes())
-            .await
-            .map_err(|e| anyhow!("Failed to send chunk request: {}", e))?;
+        let (recipient, message) = match SeederRoute::parse(seeder.as_str()) {
+            SeederRoute::Direct(address) => (address, envelope),
+            SeederRoute::Relay {
+                index_provider,
+                token,
+            } => {
+                let relay = Envelope::new(
+                    request_id,
+                    Payload::RelayRequest(proto::RelayRequest {
+                        token,
+                        payload: envelope.to_bytes(),
+                    }),
+                );
+                (index_provider, relay)
+            }
+        };
+
+        // A request not sent within the first-response timeout couldn't have
+        // been answered within it either
+        let sent = self
+            .transport
+            .send_timeout(&recipient, message.to_bytes(), self.timeouts.first_response)
+            .await;
+        if let Err(e) = sent {
+            self.router.cancel(request_id);
+            let message = format!("Failed to send chunk request: {}", e);
+            return Err(anyhow::Error::new(e).context(message));
+        }
 
         tracing::debug!(
             "Requested chunk {} from {}",
@@ -103,49 +473,48 @@ impl<'a, T: Transport> Downloader<'a, T> {
             seeder.as_str()
         );
 
-        Ok(())
+        Ok(pending)
     }
 
-    /// Wait for and process a chunk response
-    pub async fn receive_chunk(
+    /// Ask `seeder` for a chunk and wait for the reply, as one attempt of
+    /// `retry_with_backoff`
+    ///
+    /// A send or wait that times out is an `Error::Timeout`, worth trying
+    /// again; a reply that doesn't parse is not.
+    async fn attempt_chunk(
         &self,
-        timeout: std::time::Duration,
-    ) -> Result<Option<(u32, Vec<u8>, ContentHash)>> {
-        match self.transport.receive_timeout(timeout).await {
-            Ok(Some(msg)) => {
-                let envelope = Envelope::from_bytes(&msg.data)
-                    .map_err(|e| anyhow!("Failed to decode response: {}", e))?;
-
-                match envelope.payload {
-                    Some(Payload::ChunkResponse(resp)) => {
-                        // Verify chunk hash
-                        if resp.chunk_hash.len() != 32 {
-                            return Err(anyhow!("Invalid chunk hash length"));
-                        }
-                        let mut expected_hash = [0u8; 32];
-                        expected_hash.copy_from_slice(&resp.chunk_hash);
-
-                        if !verify_chunk(&resp.data, &expected_hash) {
-                            return Err(anyhow!("Chunk hash verification failed"));
-                        }
-
-                        // Convert content hash
-                        if resp.content_hash.len() != 32 {
-                            return Err(anyhow!("Invalid content hash length"));
-                        }
-                        let mut content_hash = [0u8; 32];
-                        content_hash.copy_from_slice(&resp.content_hash);
+        seeder: &NymAddress,
+        metadata: &FileMetadata,
+        chunk_index: u32,
+    ) -> brisby_core::Result<ChunkReply> {
+        let mut pending = self
+            .request_chunk(seeder, &metadata.content_hash, chunk_index)
+            .await
+            .map_err(core_error)?;
+        self.receive_chunk(&mut pending, seeder, self.timeouts, metadata.hash_algo)
+            .await
+            .map_err(core_error)?
+            .ok_or(brisby_core::Error::Timeout(self.timeouts.idle))
+    }
 
-                        Ok(Some((resp.chunk_index, resp.data, content_hash)))
-                    }
-                    Some(Payload::ErrorResponse(err)) => {
-                        Err(anyhow!("Error from seeder: {} ({})", err.message, err.code))
-                    }
-                    _ => Err(anyhow!("Unexpected response type")),
-                }
-            }
-            Ok(None) => Ok(None), // Timeout
-            Err(e) => Err(anyhow!("Failed to receive: {}", e)),
+    /// Wait for the response to a chunk request sent to `seeder`
+    ///
+    /// Other messages received meanwhile are routed to the requests they
+    /// answer, or dropped. Unless the seeder is trusted, the chunk is checked
+    /// against the hash the seeder sent using `hash_algo`, the algorithm
+    /// named in the file's metadata. Returns `None` on timeout, see
+    /// `ResponseRouter::wait_with`.
+    pub async fn receive_chunk(
+        &self,
+        pending: &mut PendingResponse,
+        seeder: &NymAddress,
+        timeouts: RequestTimeouts,
+        hash_algo: HashAlgorithm,
+    ) -> Result<Option<ChunkReply>> {
+        let hash_algo = (!self.is_trusted(seeder)).then_some(hash_algo);
+        match self.router.wait_with(self.transport, pending, timeouts).await? {
+            Some(envelope) => parse_chunk_response(envelope, hash_algo).map(Some),
+            None => Ok(None),
         }
     }
 
@@ -162,7 +531,6 @@ impl<'a, T: Transport> Downloader<'a, T> {
 
         let mut chunks = Vec::new();
         let total_chunks = metadata.chunks.len() as u32;
-        let timeout = std::time::Duration::from_secs(30);
 
         for chunk_idx in 0..total_chunks {
             progress_callback(chunk_idx, total_chunks);
@@ -170,28 +538,28 @@ impl<'a, T: Transport> Downloader<'a, T> {
             let mut received = false;
 
             // Try each seeder until we get the chunk
-            for seeder in seeders {
+            for seeder in &self.seeders_to_try(seeders) {
                 tracing::debug!("Requesting chunk {} from {}", chunk_idx, seeder.as_str());
 
-                self.request_chunk(seeder, &metadata.content_hash, chunk_idx)
-                    .await?;
-
-                match self.receive_chunk(timeout).await {
-                    Ok(Some((idx, data, hash))) => {
-                        if idx == chunk_idx && hash == metadata.content_hash {
-                            chunks.push((idx, data));
-                            received = true;
-                            break;
+                let sent_at = Instant::now();
+                let response = retry_with_backoff(ATTEMPTS_PER_SEEDER, RETRY_BASE_DELAY, || {
+                    self.attempt_chunk(seeder, metadata, chunk_idx)
+                })
+                .await;
+                match response {
+                    Ok(reply) => {
+                        if let Err(e) = self.check_reply(metadata, chunk_idx, &reply, seeder) {
+                            tracing::warn!("Rejecting chunk from {}: {}", seeder.as_str(), e);
+                            self.scoreboard.record_failure(seeder);
+                            continue;
                         }
-                    }
-                    Ok(None) => {
-                        tracing::warn!(
-                            "Timeout waiting for chunk {} from {}",
-                            chunk_idx,
-                            seeder.as_str()
-                        );
+                        self.scoreboard.record_success(seeder, sent_at.elapsed());
+                        chunks.push((reply.index, reply.data));
+                        received = true;
+                        break;
                     }
                     Err(e) => {
+                        self.scoreboard.record_failure(seeder);
                         tracing::warn!(
                             "Error receiving chunk {} from {}: {}",
                             chunk_idx,
@@ -204,8 +572,9 @@ impl<'a, T: Transport> Downloader<'a, T> {
 
             if !received {
                 return Err(anyhow!(
-                    "Failed to download chunk {} after trying all seeders",
-                    chunk_idx
+                    "Failed to download chunk {} after trying {} seeder(s)",
+                    chunk_idx,
+                    self.seeders_to_try(seeders).len()
                 ));
             }
         }
@@ -229,208 +598,953 @@ impl<'a, T: Transport> Downloader<'a, T> {
             return Err(anyhow!("No seeders available"));
         }
 
+        let wanted: Vec<u32> = (0..metadata.chunks.len() as u32).collect();
+        let mut received_chunks: HashMap<u32, Vec<u8>> = HashMap::new();
+
+        self.fetch_parallel(
+            metadata,
+            seeders,
+            &wanted,
+            concurrency,
+            None,
+            |chunk_idx, data| {
+                received_chunks.insert(chunk_idx, data);
+                Ok(())
+            },
+            progress_callback,
+        )
+        .await?;
+
+        // Convert to sorted vec
+        let mut chunks: Vec<(u32, Vec<u8>)> = received_chunks.into_iter().collect();
+        chunks.sort_by_key(|(idx, _)| *idx);
+
+        Ok(chunks)
+    }
+
+    /// Download all chunks for a file, resuming from a partial download
+    ///
+    /// Chunks already saved in `partial` are not requested again; each newly
+    /// received chunk is saved there before the next is awaited, so an
+    /// interrupted download picks up where it left off. Once this returns
+    /// `DownloadOutcome::Complete`, every chunk is in `partial`; see
+    /// `reassemble_partial`, or `reassemble_available` for a partial outcome.
+    ///
+    /// With a state store, the metadata and seeders (merged with any saved
+    /// earlier) are saved before fetching starts, so a later attempt can be
+    /// made without passing the seeders again. Saved state that can't be
+    /// read or is for a different layout of the file is ignored.
+    pub async fn download_resumable(
+        &self,
+        metadata: &FileMetadata,
+        seeders: &[NymAddress],
+        concurrency: usize,
+        partial: &PartialDownload,
+        progress_callback: impl Fn(u32, u32),
+    ) -> Result<DownloadOutcome> {
+        // A wrong key is caught before anything is fetched
+        Decryption::for_file(metadata, self.key.as_ref())?;
+
         let total_chunks = metadata.chunks.len() as u32;
-        if total_chunks == 0 {
-            return Ok(Vec::new());
+        let wanted = partial.missing_chunks(total_chunks);
+        if (wanted.len() as u32) < total_chunks {
+            tracing::info!(
+                "Resuming download: {}/{} chunks already saved",
+                total_chunks - wanted.len() as u32,
+                total_chunks
+            );
+        }
+        let wanted = self.copy_local_chunks(metadata, &wanted, |chunk_idx, data| {
+            partial.write_chunk(chunk_idx, &data)
+        })?;
+        if wanted.is_empty() {
+            progress_callback(total_chunks, total_chunks);
+            return Ok(DownloadOutcome::Complete);
+        }
+
+        let state = self.resume_state(metadata, seeders)?;
+        let seeders: Vec<NymAddress> = match &state {
+            Some(state) => state.seeders.iter().map(NymAddress::new).collect(),
+            None => seeders.to_vec(),
+        };
+        if seeders.is_empty() {
+            return Err(anyhow!("No seeders available"));
         }
 
-        let concurrency = concurrency.min(total_chunks as usize).max(1);
-        let timeout = Duration::from_secs(30);
-        let retry_limit = 3;
+        let missing = self
+            .fetch_parallel(
+                metadata,
+                &seeders,
+                &wanted,
+                concurrency,
+                self.completion_threshold,
+                |chunk_idx, data| partial.write_chunk(chunk_idx, &data),
+                progress_callback,
+            )
+            .await?;
+
+        Ok(DownloadOutcome::from_missing(missing))
+    }
 
-        // Track state
-        let mut received_chunks: HashMap<u32, Vec<u8>> = HashMap::new();
-        let mut pending_chunks: HashSet<u32> = HashSet::new();
-        let mut next_chunk_to_request: u32 = 0;
-        let mut seeder_index: usize = 0;
-        let mut retry_counts: HashMap<u32, usize> = HashMap::new();
+    /// Hand the `wanted` chunks the local store holds to `on_chunk`,
+    /// returning the ones it doesn't
+    ///
+    /// A chunk is only taken if the store has the same file, chunked the
+    /// same way, and the chunk matches the store's hash for it and any
+    /// external hashes. Whatever slips past that is still caught by the
+    /// whole-file check when the file is written out.
+    fn copy_local_chunks(
+        &self,
+        metadata: &FileMetadata,
+        wanted: &[u32],
+        mut on_chunk: impl FnMut(u32, Vec<u8>) -> Result<()>,
+    ) -> Result<Vec<u32>> {
+        let Some(store) = self.local_store else {
+            return Ok(wanted.to_vec());
+        };
+        let Some(local) = store.get_metadata(&metadata.content_hash) else {
+            return Ok(wanted.to_vec());
+        };
+        let same_layout = local.chunks.len() == metadata.chunks.len()
+            && local
+                .chunks
+                .iter()
+                .zip(&metadata.chunks)
+                .all(|(ours, theirs)| theirs.size == 0 || ours.size == theirs.size);
+        if !same_layout {
+            tracing::debug!("Local copy of {} is chunked differently", local.filename);
+            return Ok(wanted.to_vec());
+        }
 
-        // Initial batch of requests
-        while pending_chunks.len() < concurrency && next_chunk_to_request < total_chunks {
-            let chunk_idx = next_chunk_to_request;
-            let seeder = &seeders[seeder_index % seeders.len()];
+        let mut missing = Vec::new();
+        for &chunk_idx in wanted {
+            let info = &local.chunks[chunk_idx as usize];
+            let Some(data) = store.read_chunk(&metadata.content_hash, chunk_idx) else {
+                missing.push(chunk_idx);
+                continue;
+            };
+            let status = chunk_status(Some(data.as_slice()), info, local.hash_algo);
+            let verified = status == ChunkStatus::Present
+                && self
+                    .external_hashes
+                    .as_ref()
+                    .is_none_or(|hashes| hashes.verify(chunk_idx, &data));
+            if verified {
+                on_chunk(chunk_idx, data)?;
+            } else {
+                tracing::warn!(
+                    "Local chunk {} of {} doesn't verify, fetching it",
+                    chunk_idx,
+                    local.filename
+                );
+                missing.push(chunk_idx);
+            }
+        }
 
-            tracing::debug!(
-                "Requesting chunk {} from {} (parallel batch)",
-                chunk_idx,
-                seeder.as_str()
+        let copied = wanted.len() - missing.len();
+        if copied > 0 {
+            tracing::info!(
+                "Using {}/{} chunks already held locally",
+                copied,
+                metadata.chunks.len()
             );
+        }
+        Ok(missing)
+    }
+
+    /// Saved state for a resumable download, updated for this attempt
+    ///
+    /// `None` without a state store.
+    fn resume_state(
+        &self,
+        metadata: &FileMetadata,
+        seeders: &[NymAddress],
+    ) -> Result<Option<SavedDownloadState>> {
+        let Some(store) = self.state_store else {
+            return Ok(None);
+        };
+
+        let mut known_seeders = match store.load(&metadata.content_hash) {
+            Ok(Some(saved)) if saved.metadata == *metadata => saved.seeders,
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                tracing::warn!("Ignoring saved download state: {}", e);
+                Vec::new()
+            }
+        };
+        for seeder in seeders {
+            if !known_seeders.iter().any(|s| s == seeder.as_str()) {
+                known_seeders.push(seeder.as_str().to_string());
+            }
+        }
+
+        let state = SavedDownloadState::new(metadata.clone(), known_seeders);
+        store.save(&state)?;
 
-            self.request_chunk(seeder, &metadata.content_hash, chunk_idx)
-                .await?;
+        Ok(Some(state))
+    }
 
-            pending_chunks.insert(chunk_idx);
-            next_chunk_to_request += 1;
-            seeder_index += 1;
+    /// Fetch the `wanted` chunks with parallel requests, handing each verified
+    /// chunk to `on_chunk` as it arrives
+    ///
+    /// Progress is reported against the whole file, counting chunks outside
+    /// `wanted` as already done. With a `completion_threshold`, stops as soon
+    /// as that fraction of the whole file is done. Returns the wanted chunks
+    /// not fetched, empty unless stopped early.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_parallel(
+        &self,
+        metadata: &FileMetadata,
+        seeders: &[NymAddress],
+        wanted: &[u32],
+        concurrency: usize,
+        completion_threshold: Option<f64>,
+        mut on_chunk: impl FnMut(u32, Vec<u8>) -> Result<()>,
+        progress_callback: impl Fn(u32, u32),
+    ) -> Result<Vec<u32>> {
+        let total_chunks = metadata.chunks.len() as u32;
+        if wanted.is_empty() {
+            return Ok(Vec::new());
         }
 
+        let already_done = total_chunks.saturating_sub(wanted.len() as u32);
+        let concurrency = concurrency.min(wanted.len()).max(1);
+        // Every retry goes to another seeder, so the per-chunk cap bounds them too
+        let retry_limit = match self.max_seeders_per_chunk {
+            Some(max) => max.saturating_sub(1).min(3),
+            None => 3,
+        };
+
+        // Chunks requested but not yet received. The request is `None` once it
+        // has been answered with an error, leaving the chunk to the stall retry.
+        let mut pending_chunks: HashMap<u32, Option<PendingResponse>> = HashMap::new();
+        // Who each chunk was last asked of and when, for the seeder stats
+        let mut asked: HashMap<u32, (NymAddress, Instant)> = HashMap::new();
+        let mut received_chunks: HashSet<u32> = HashSet::new();
+        let mut next_to_request: usize = 0;
+        let mut seeder_index: usize = 0;
+        let mut retry_counts: HashMap<u32, usize> = HashMap::new();
+        // Seeders that have answered anything, and ones that didn't answer
+        // within the first-response timeout and aren't asked again
+        let mut responsive: HashSet<NymAddress> = HashSet::new();
+        let mut silent: HashSet<NymAddress> = HashSet::new();
+
         // Receive loop with timeout tracking
         let mut last_receive_time = Instant::now();
 
-        while received_chunks.len() < total_chunks as usize {
+        while received_chunks.len() < wanted.len() {
+            // Keep up to `concurrency` requests in flight
+            while pending_chunks.len() < concurrency && next_to_request < wanted.len() {
+                let chunk_idx = wanted[next_to_request];
+                let seeder = self.next_live_seeder(seeders, &mut seeder_index, &silent);
+
+                tracing::debug!(
+                    "Requesting chunk {} from {} (parallel batch)",
+                    chunk_idx,
+                    seeder.as_str()
+                );
+
+                let pending = self
+                    .request_chunk(seeder, &metadata.content_hash, chunk_idx)
+                    .await?;
+
+                pending_chunks.insert(chunk_idx, Some(pending));
+                asked.insert(chunk_idx, (seeder.clone(), Instant::now()));
+                next_to_request += 1;
+            }
+
+            // Give up early on requests to seeders that haven't answered at all
+            let unanswered: Vec<u32> = pending_chunks
+                .iter()
+                .filter(|(_, pending)| pending.is_some())
+                .map(|(chunk_idx, _)| *chunk_idx)
+                .filter(|chunk_idx| {
+                    let (seeder, sent_at) = &asked[chunk_idx];
+                    !responsive.contains(seeder)
+                        && sent_at.elapsed() > self.timeouts.first_response
+                })
+                .collect();
+            for chunk_idx in unanswered {
+                let (seeder, _) = asked[&chunk_idx].clone();
+                if silent.insert(seeder.clone()) {
+                    tracing::warn!(
+                        "No response from {} within {:?}, asking other seeders",
+                        seeder.as_str(),
+                        self.timeouts.first_response
+                    );
+                }
+                if let Some(Some(stale)) = pending_chunks.remove(&chunk_idx) {
+                    self.router.cancel(stale.request_id());
+                }
+                self.scoreboard.record_failure(&seeder);
+                let (pending, seeder) = self
+                    .retry_chunk(
+                        metadata,
+                        seeders,
+                        chunk_idx,
+                        &mut retry_counts,
+                        &mut seeder_index,
+                        retry_limit,
+                        &silent,
+                    )
+                    .await?;
+                pending_chunks.insert(chunk_idx, Some(pending));
+                asked.insert(chunk_idx, (seeder, Instant::now()));
+            }
+
             // Check for overall timeout (no progress)
-            if last_receive_time.elapsed() > timeout && !pending_chunks.is_empty() {
+            if last_receive_time.elapsed() > self.timeouts.idle && !pending_chunks.is_empty() {
                 // Timeout - retry pending chunks
                 tracing::warn!(
                     "Timeout waiting for chunks, {} pending, retrying...",
                     pending_chunks.len()
                 );
 
-                // Collect chunks to retry
-                let chunks_to_retry: Vec<u32> = pending_chunks.iter().copied().collect();
-                pending_chunks.clear();
-
+                let chunks_to_retry: Vec<u32> = pending_chunks.keys().copied().collect();
                 for chunk_idx in chunks_to_retry {
-                    let count = retry_counts.entry(chunk_idx).or_insert(0);
-                    *count += 1;
-
-                    if *count > retry_limit {
-                        return Err(anyhow!(
-                            "Failed to download chunk {} after {} retries",
-                            chunk_idx,
-                            retry_limit
-                        ));
+                    if let Some(Some(stale)) = pending_chunks.remove(&chunk_idx) {
+                        self.router.cancel(stale.request_id());
+                        if let Some((seeder, _)) = asked.get(&chunk_idx) {
+                            self.scoreboard.record_failure(seeder);
+                        }
                     }
-
-                    // Retry with next seeder
-                    let seeder = &seeders[seeder_index % seeders.len()];
-                    tracing::debug!(
-                        "Retrying chunk {} from {} (attempt {})",
-                        chunk_idx,
-                        seeder.as_str(),
-                        count
-                    );
-
-                    self.request_chunk(seeder, &metadata.content_hash, chunk_idx)
+                    let (pending, seeder) = self
+                        .retry_chunk(
+                            metadata,
+                            seeders,
+                            chunk_idx,
+                            &mut retry_counts,
+                            &mut seeder_index,
+                            retry_limit,
+                            &silent,
+                        )
                         .await?;
-
-                    pending_chunks.insert(chunk_idx);
-                    seeder_index += 1;
+                    pending_chunks.insert(chunk_idx, Some(pending));
+                    asked.insert(chunk_idx, (seeder, Instant::now()));
                 }
 
                 last_receive_time = Instant::now();
             }
 
-            // Try to receive a response (short timeout to stay responsive)
-            match self.receive_chunk(Duration::from_millis(500)).await {
-                Ok(Some((chunk_idx, data, content_hash))) => {
-                    if content_hash != metadata.content_hash {
-                        tracing::warn!(
-                            "Received chunk {} with wrong content hash, ignoring",
-                            chunk_idx
-                        );
-                        continue;
-                    }
+            // Route the next response to its request (short timeout to stay responsive)
+            if let Err(e) = self
+                .router
+                .pump(self.transport, Duration::from_millis(500))
+                .await
+            {
+                tracing::debug!("Error receiving chunk: {}", e);
+            }
 
-                    if received_chunks.contains_key(&chunk_idx) {
-                        tracing::debug!("Received duplicate chunk {}, ignoring", chunk_idx);
-                        continue;
-                    }
+            let answered: Vec<(u32, Envelope)> = pending_chunks
+                .iter_mut()
+                .filter_map(|(chunk_idx, pending)| Some((*chunk_idx, pending.as_mut()?.try_take()?)))
+                .collect();
 
-                    // Store the chunk
-                    received_chunks.insert(chunk_idx, data);
-                    pending_chunks.remove(&chunk_idx);
-                    last_receive_time = Instant::now();
+            for (chunk_idx, envelope) in answered {
+                pending_chunks.insert(chunk_idx, None);
+                let (seeder, sent_at) = asked[&chunk_idx].clone();
+                responsive.insert(seeder.clone());
 
-                    progress_callback(received_chunks.len() as u32, total_chunks);
+                let hash_algo = (!self.is_trusted(&seeder)).then_some(metadata.hash_algo);
+                let reply = match parse_chunk_response(envelope, hash_algo) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        tracing::debug!("Error receiving chunk {}: {}", chunk_idx, e);
+                        self.scoreboard.record_failure(&seeder);
+                        continue;
+                    }
+                };
+
+                // A bad reply settles nothing, so ask the next seeder right away
+                if let Err(e) = self.check_reply(metadata, chunk_idx, &reply, &seeder) {
+                    tracing::warn!("Rejecting chunk from {}: {}", seeder.as_str(), e);
+                    self.scoreboard.record_failure(&seeder);
+                    let (pending, seeder) = self
+                        .retry_chunk(
+                            metadata,
+                            seeders,
+                            chunk_idx,
+                            &mut retry_counts,
+                            &mut seeder_index,
+                            retry_limit,
+                            &silent,
+                        )
+                        .await?;
+                    pending_chunks.insert(chunk_idx, Some(pending));
+                    asked.insert(chunk_idx, (seeder, Instant::now()));
+                    continue;
+                }
+                self.scoreboard.record_success(&seeder, sent_at.elapsed());
 
-                    tracing::debug!(
-                        "Received chunk {} ({}/{})",
-                        chunk_idx,
-                        received_chunks.len(),
-                        total_chunks
-                    );
+                // Store the chunk
+                on_chunk(chunk_idx, reply.data)?;
+                received_chunks.insert(chunk_idx);
+                pending_chunks.remove(&chunk_idx);
+                last_receive_time = Instant::now();
 
-                    // Send next request if we have more chunks to request
-                    while pending_chunks.len() < concurrency
-                        && next_chunk_to_request < total_chunks
-                    {
-                        let chunk_idx = next_chunk_to_request;
-                        let seeder = &seeders[seeder_index % seeders.len()];
+                let done = already_done + received_chunks.len() as u32;
+                progress_callback(done, total_chunks);
 
-                        self.request_chunk(seeder, &metadata.content_hash, chunk_idx)
-                            .await?;
+                tracing::debug!("Received chunk {} ({}/{})", chunk_idx, done, total_chunks);
+            }
 
-                        pending_chunks.insert(chunk_idx);
-                        next_chunk_to_request += 1;
-                        seeder_index += 1;
-                    }
-                }
-                Ok(None) => {
-                    // Short timeout, continue loop
-                }
-                Err(e) => {
-                    tracing::debug!("Error receiving chunk: {}", e);
+            let done = already_done + received_chunks.len() as u32;
+            let enough = completion_threshold
+                .is_some_and(|threshold| done as f64 / total_chunks as f64 >= threshold);
+            if enough && received_chunks.len() < wanted.len() {
+                tracing::info!(
+                    "Stopping at {}/{} chunks, completion threshold reached",
+                    done,
+                    total_chunks
+                );
+                for pending in pending_chunks.values().flatten() {
+                    self.router.cancel(pending.request_id());
                 }
+                break;
             }
         }
 
-        // Convert to sorted vec
-        let mut chunks: Vec<(u32, Vec<u8>)> = received_chunks.into_iter().collect();
-        chunks.sort_by_key(|(idx, _)| *idx);
-
-        Ok(chunks)
+        Ok(wanted
+            .iter()
+            .copied()
+            .filter(|chunk_idx| !received_chunks.contains(chunk_idx))
+            .collect())
     }
 
-    /// Reassemble chunks into the final file
-    pub fn reassemble_to_file(
+    /// Request a chunk again from the next seeder, giving up once it has
+    /// been retried `retry_limit` times
+    ///
+    /// Returns the pending request and the seeder it went to.
+    #[allow(clippy::too_many_arguments)]
+    async fn retry_chunk(
         &self,
-        chunks: Vec<(u32, Vec<u8>)>,
         metadata: &FileMetadata,
-        output_path: &Path,
-    ) -> Result<()> {
-        // Sort chunks by index
-        let mut sorted: Vec<_> = chunks.into_iter().collect();
-        sorted.sort_by_key(|(idx, _)| *idx);
-
-        // Create output file
-        let mut file = std::fs::File::create(output_path)?;
+        seeders: &[NymAddress],
+        chunk_idx: u32,
+        retry_counts: &mut HashMap<u32, usize>,
+        seeder_index: &mut usize,
+        retry_limit: usize,
+        silent: &HashSet<NymAddress>,
+    ) -> Result<(PendingResponse, NymAddress)> {
+        let count = retry_counts.entry(chunk_idx).or_insert(0);
+        *count += 1;
+
+        if *count > retry_limit {
+            return Err(anyhow!(
+                "Failed to download chunk {} after {} retries",
+                chunk_idx,
+                retry_limit
+            ));
+        }
+
+        // Retry with next seeder
+        let seeder = self.next_live_seeder(seeders, seeder_index, silent);
+        tracing::debug!(
+            "Retrying chunk {} from {} (attempt {})",
+            chunk_idx,
+            seeder.as_str(),
+            count
+        );
+
+        let pending = self
+            .request_chunk(seeder, &metadata.content_hash, chunk_idx)
+            .await?;
+        Ok((pending, seeder.clone()))
+    }
+
+    /// Download only chunks `[start_index, start_index + count)` of a file
+    ///
+    /// Each chunk is verified and written to `output_path` at its offset within
+    /// the full file. The rest of the file is left as a zero-filled region, so
+    /// the whole-file hash is intentionally not checked.
+    pub async fn download_range(
+        &self,
+        metadata: &FileMetadata,
+        seeders: &[NymAddress],
+        start_index: u32,
+        count: u32,
+        output_path: &Path,
+    ) -> Result<()> {
+        if seeders.is_empty() {
+            return Err(anyhow!("No seeders available"));
+        }
+
+        let total_chunks = metadata.chunks.len() as u32;
+        let end_index = start_index
+            .checked_add(count)
+            .filter(|end| *end <= total_chunks)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Chunk range {}..{} out of bounds, file has {} chunks",
+                    start_index,
+                    start_index as u64 + count as u64,
+                    total_chunks
+                )
+            })?;
+
+        let decryption = Decryption::for_file(metadata, self.key.as_ref())?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(output_path)?;
+
+        for chunk_idx in start_index..end_index {
+            let expected = &metadata.chunks[chunk_idx as usize];
+            let mut data = None;
+
+            for seeder in &self.seeders_to_try(seeders) {
+                let sent_at = Instant::now();
+                let response = retry_with_backoff(ATTEMPTS_PER_SEEDER, RETRY_BASE_DELAY, || {
+                    self.attempt_chunk(seeder, metadata, chunk_idx)
+                })
+                .await;
+                match response {
+                    Ok(reply) => {
+                        if let Err(e) = self.check_reply(metadata, chunk_idx, &reply, seeder) {
+                            tracing::warn!("Rejecting chunk from {}: {}", seeder.as_str(), e);
+                            self.scoreboard.record_failure(seeder);
+                            continue;
+                        }
+                        let chunk = reply.data;
+                        // Chunk hashes of all zeros mean the hash is unknown
+                        if expected.hash != [0u8; 32]
+                            && !self.is_trusted(seeder)
+                            && !verify_chunk(metadata.hash_algo, &chunk, &expected.hash)
+                        {
+                            tracing::warn!(
+                                "Chunk {} from {} does not match metadata hash",
+                                chunk_idx,
+                                seeder.as_str()
+                            );
+                            self.scoreboard.record_failure(seeder);
+                            continue;
+                        }
+                        self.scoreboard.record_success(seeder, sent_at.elapsed());
+                        data = Some(chunk);
+                        break;
+                    }
+                    Err(e) => {
+                        self.scoreboard.record_failure(seeder);
+                        tracing::warn!(
+                            "Error receiving chunk {} from {}: {}",
+                            chunk_idx,
+                            seeder.as_str(),
+                            e
+                        );
+                    }
+                }
+            }
 
-        // Write chunks in order
-        let mut total_written = 0u64;
-        for (idx, data) in sorted {
-            tracing::trace!("Writing chunk {} ({} bytes)", idx, data.len());
+            let mut data = data.ok_or_else(|| {
+                anyhow!(
+                    "Failed to download chunk {} after trying {} seeder(s)",
+                    chunk_idx,
+                    self.seeders_to_try(seeders).len()
+                )
+            })?;
+            if let Some(decryption) = &decryption {
+                decryption.decrypt(chunk_idx, &mut data);
+            }
+
+            file.seek(SeekFrom::Start(chunk_offset(metadata, chunk_idx)))?;
             file.write_all(&data)?;
-            total_written += data.len() as u64;
         }
 
-        // Verify total size if the metadata included it
-        if metadata.size != 0 && total_written != metadata.size {
-            return Err(anyhow!(
-                "Size mismatch: expected {} bytes, wrote {} bytes",
-                metadata.size,
-                total_written
-            ));
+        // Extend to the full size so offsets match the complete file
+        if metadata.size > file.metadata()?.len() {
+            file.set_len(metadata.size)?;
+        }
+        file.sync_all()?;
+
+        tracing::info!(
+            "Downloaded chunks {}..{} of {}",
+            start_index,
+            end_index,
+            metadata.filename
+        );
+
+        Ok(())
+    }
+
+    /// Write downloaded chunks to `output_path` and verify the result
+    ///
+    /// Chunks may be in any order. See `ReassemblyWriter` to write them as
+    /// they arrive instead.
+    pub fn reassemble_to_file(
+        &self,
+        chunks: Vec<(u32, Vec<u8>)>,
+        metadata: &FileMetadata,
+        output_path: &Path,
+    ) -> Result<()> {
+        let mut writer = ReassemblyWriter::new(output_path, metadata, self.key.as_ref())?;
+        for (idx, data) in chunks {
+            writer.write_chunk(idx, &data)?;
         }
+        writer.finalize()
+    }
 
-        // Verify final file hash
+    /// Write whatever chunks `partial` holds into the `partial_output_path`
+    /// of `output_path`, leaving the missing ones zero-filled, and return
+    /// where it went
+    ///
+    /// For a `DownloadOutcome::Partial`: the result can't match the content
+    /// hash, so unlike `reassemble_partial` it isn't checked.
+    pub fn reassemble_available(
+        &self,
+        partial: &PartialDownload,
+        metadata: &FileMetadata,
+        output_path: &Path,
+    ) -> Result<PathBuf> {
+        let decryption = Decryption::for_file(metadata, self.key.as_ref())?;
+        let part_path = partial_output_path(output_path);
+        let mut file = std::fs::File::create(&part_path)?;
+        for idx in 0..metadata.chunks.len() as u32 {
+            if partial.has_chunk(idx) {
+                let mut data = partial.read_chunk(idx)?;
+                if let Some(decryption) = &decryption {
+                    decryption.decrypt(idx, &mut data);
+                }
+                file.seek(SeekFrom::Start(chunk_offset(metadata, idx)))?;
+                file.write_all(&data)?;
+            }
+        }
         file.sync_all()?;
         drop(file);
+        extend_to_size(&part_path, metadata.size)?;
+        Ok(part_path)
+    }
 
-        let final_hash = {
-            let data = std::fs::read(output_path)?;
-            *blake3::hash(&data).as_bytes()
-        };
+    /// Assemble a completed partial download into `output_path`
+    ///
+    /// Chunks are read from `partial` one at a time, so the file is never
+    /// held in memory. Once the file verifies, any zero-filled copy an
+    /// earlier `reassemble_available` left is removed.
+    pub fn reassemble_partial(
+        &self,
+        partial: &PartialDownload,
+        metadata: &FileMetadata,
+        output_path: &Path,
+    ) -> Result<()> {
+        let mut writer = ReassemblyWriter::new(output_path, metadata, self.key.as_ref())?;
+        for idx in 0..metadata.chunks.len() as u32 {
+            let data = partial
+                .read_chunk(idx)
+                .map_err(|e| anyhow!("Missing partial chunk {}: {}", idx, e))?;
+            writer.write_chunk(idx, &data)?;
+        }
+        writer.finalize()?;
 
-        if final_hash != metadata.content_hash {
-            std::fs::remove_file(output_path)?;
-            return Err(anyhow!("Final file hash verification failed"));
+        let part_path = partial_output_path(output_path);
+        if part_path.exists() {
+            std::fs::remove_file(&part_path)?;
         }
+        Ok(())
+    }
+}
 
-        tracing::info!(
-            "Successfully downloaded and verified {} ({} bytes)",
-            metadata.filename,
-            metadata.size
-        );
+/// Zero-fill `path` up to `size` bytes (if known), so chunk offsets match
+/// the complete file even where chunks are missing
+fn extend_to_size(path: &Path, size: u64) -> Result<()> {
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    if size > file.metadata()?.len() {
+        file.set_len(size)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// What to do when a download's output file already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExists {
+    /// Leave the existing file alone and don't download
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Write to the first free `<stem>.<n>.<ext>` next to it instead
+    Rename,
+}
+
+impl fmt::Display for OnExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnExists::Skip => write!(f, "skip"),
+            OnExists::Overwrite => write!(f, "overwrite"),
+            OnExists::Rename => write!(f, "rename"),
+        }
+    }
+}
+
+impl FromStr for OnExists {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(OnExists::Skip),
+            "overwrite" => Ok(OnExists::Overwrite),
+            "rename" => Ok(OnExists::Rename),
+            other => Err(anyhow!(
+                "unknown policy '{}' (expected skip, overwrite or rename)",
+                other
+            )),
+        }
     }
 }
 
+/// Decide where a download should be written
+///
+/// Returns `None` if `path` exists and `policy` is `Skip`, so nothing is
+/// downloaded and the existing file is never truncated.
+pub fn resolve_output_path(path: &Path, policy: OnExists) -> Option<PathBuf> {
+    if !path.exists() {
+        return Some(path.to_path_buf());
+    }
+
+    match policy {
+        OnExists::Skip => None,
+        OnExists::Overwrite => Some(path.to_path_buf()),
+        OnExists::Rename => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path.extension().map(|e| e.to_string_lossy());
+            (1u32..)
+                .map(|n| {
+                    let name = match &extension {
+                        Some(ext) => format!("{}.{}.{}", stem, n, ext),
+                        None => format!("{}.{}", stem, n),
+                    };
+                    path.with_file_name(name)
+                })
+                .find(|candidate| !candidate.exists())
+        }
+    }
+}
+
+/// The core error behind `error`, for `retry_with_backoff`
+///
+/// Errors from elsewhere become `Error::Protocol`, which isn't retried.
+fn core_error(error: anyhow::Error) -> brisby_core::Error {
+    error
+        .downcast::<brisby_core::Error>()
+        .unwrap_or_else(|e| brisby_core::Error::Protocol(e.to_string()))
+}
+
+/// Extract and verify the chunk carried by a response
+///
+/// The chunk is checked against the hash the seeder sent using `hash_algo`,
+/// or not at all if `None` (for trusted seeders). Compressed chunks are
+/// decompressed first, since the hash is of the chunk itself. Error
+/// responses and other message types are turned into errors.
+fn parse_chunk_response(
+    envelope: Envelope,
+    hash_algo: Option<HashAlgorithm>,
+) -> Result<ChunkReply> {
+    match envelope.payload {
+        Some(Payload::ChunkResponse(resp)) => {
+            let data: Vec<u8> = match resp.compression {
+                // Sole owner of the buffer, so this reuses its allocation
+                proto::compression::NONE => resp.data.into(),
+                compression => decompress_chunk(compression, &resp.data)?,
+            };
+
+            // Verify chunk hash
+            if resp.chunk_hash.len() != 32 {
+                return Err(anyhow!("Invalid chunk hash length"));
+            }
+            let mut expected_hash = [0u8; 32];
+            expected_hash.copy_from_slice(&resp.chunk_hash);
+
+            if let Some(hash_algo) = hash_algo {
+                if !verify_chunk(hash_algo, &data, &expected_hash) {
+                    return Err(anyhow!("Chunk hash verification failed"));
+                }
+            }
+
+            // Convert content hash
+            if resp.content_hash.len() != 32 {
+                return Err(anyhow!("Invalid content hash length"));
+            }
+            let mut content_hash = [0u8; 32];
+            content_hash.copy_from_slice(&resp.content_hash);
+
+            let proof = resp
+                .proof
+                .iter()
+                .map(|hash| ContentHash::try_from(hash.as_slice()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| anyhow!("Invalid proof hash length"))?;
+
+            Ok(ChunkReply {
+                index: resp.chunk_index,
+                data,
+                content_hash,
+                proof,
+            })
+        }
+        Some(Payload::ErrorResponse(err)) => {
+            Err(anyhow!("Error from seeder: {} ({})", err.message, err.code))
+        }
+        _ => Err(anyhow!("Unexpected response type")),
+    }
+}
+
+/// Check that a verified chunk is the one that was requested
+///
+/// If the file's content hash is a Merkle root, the chunk must also come with
+/// a proof against it, so a seeder can't vouch for a chunk with its own hash.
+fn check_chunk_reply(
+    metadata: &FileMetadata,
+    requested: u32,
+    reply: &ChunkReply,
+) -> brisby_core::Result<()> {
+    check_chunk_identity(metadata, requested, reply)?;
+
+    let total_chunks = metadata.chunks.len() as u32;
+    if metadata.hash_algo.is_merkle()
+        && !verify_chunk_with_proof(
+            metadata.hash_algo,
+            &reply.data,
+            reply.index,
+            total_chunks,
+            metadata.size,
+            &reply.proof,
+            &metadata.content_hash,
+        )
+    {
+        return Err(brisby_core::Error::InvalidData(format!(
+            "chunk {} doesn't match the content hash",
+            reply.index
+        )));
+    }
+    Ok(())
+}
+
+/// Check that a reply carries the requested, non-empty chunk of the file,
+/// without checking its contents
+fn check_chunk_identity(
+    metadata: &FileMetadata,
+    requested: u32,
+    reply: &ChunkReply,
+) -> brisby_core::Result<()> {
+    check_chunk_index(reply.index, metadata.chunks.len() as u32)?;
+    if reply.index != requested || reply.content_hash != metadata.content_hash {
+        return Err(brisby_core::Error::InvalidData(format!(
+            "reply carries chunk {} of {}, expected chunk {}",
+            reply.index,
+            &brisby_core::hash_to_hex(&reply.content_hash)[..8],
+            requested
+        )));
+    }
+    check_chunk_not_empty(metadata, reply.index, &reply.data)
+}
+
+/// Reject chunk indices outside `0..total_chunks`
+///
+/// A misbehaving seeder could otherwise make an out-of-range chunk count
+/// towards completion.
+fn check_chunk_index(chunk_index: u32, total_chunks: u32) -> brisby_core::Result<()> {
+    if chunk_index >= total_chunks {
+        return Err(brisby_core::Error::InvalidChunkIndex {
+            index: chunk_index,
+            total: total_chunks,
+        });
+    }
+    Ok(())
+}
+
+/// Reject empty data for a chunk that can't be empty
+///
+/// Empty data with the hash of empty input passes hash verification, so
+/// without this a seeder could pass off a missing chunk as a valid one.
+/// Every chunk but the last holds data whether or not the metadata records
+/// its size; the last one is checked only when its size is known.
+fn check_chunk_not_empty(
+    metadata: &FileMetadata,
+    chunk_index: u32,
+    data: &[u8],
+) -> brisby_core::Result<()> {
+    if !data.is_empty() {
+        return Ok(());
+    }
+    let expected_size = metadata
+        .chunks
+        .get(chunk_index as usize)
+        .map_or(0, |info| info.size);
+    if expected_size > 0 {
+        return Err(brisby_core::Error::InvalidData(format!(
+            "chunk {} is empty, expected {} bytes",
+            chunk_index, expected_size
+        )));
+    }
+    if (chunk_index as usize) + 1 < metadata.chunks.len() {
+        return Err(brisby_core::Error::InvalidData(format!(
+            "chunk {} is empty, but only the last chunk may be",
+            chunk_index
+        )));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use brisby_core::transport::mock::MockTransport;
+    use brisby_core::CHUNK_SIZE;
+
+    /// A seeder's reply carrying chunk `idx` of `metadata` and its Merkle proof
+    fn proven_chunk_response(
+        request_id: u64,
+        metadata: &FileMetadata,
+        idx: u32,
+        data: Vec<u8>,
+    ) -> Envelope {
+        let chunk_hash = blake3::hash(&data).as_bytes().to_vec();
+        let mut response =
+            proto::chunk_response(request_id, metadata.content_hash.to_vec(), idx, data, chunk_hash);
+        if let Some(Payload::ChunkResponse(resp)) = &mut response.payload {
+            let proof = metadata.merkle_proof(idx).unwrap();
+            resp.proof = proof.iter().map(|hash| hash.to_vec()).collect();
+        }
+        response
+    }
+
+    #[test]
+    fn test_on_exists_policies() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let existing = temp_dir.path().join("movie.mkv");
+        std::fs::write(&existing, b"keep me").unwrap();
+
+        // Skip (the default) refuses to touch an existing file
+        assert_eq!(OnExists::default(), OnExists::Skip);
+        assert_eq!(resolve_output_path(&existing, OnExists::Skip), None);
+
+        // Overwrite must be asked for explicitly
+        assert_eq!(
+            resolve_output_path(&existing, OnExists::Overwrite),
+            Some(existing.clone())
+        );
+
+        // Rename picks the first free numbered name
+        assert_eq!(
+            resolve_output_path(&existing, OnExists::Rename),
+            Some(temp_dir.path().join("movie.1.mkv"))
+        );
+        std::fs::write(temp_dir.path().join("movie.1.mkv"), b"").unwrap();
+        assert_eq!(
+            resolve_output_path(&existing, OnExists::Rename),
+            Some(temp_dir.path().join("movie.2.mkv"))
+        );
+        assert_eq!(std::fs::read(&existing).unwrap(), b"keep me");
+
+        // Every policy writes to a path that doesn't exist yet as is
+        let fresh = temp_dir.path().join("new.bin");
+        for policy in [OnExists::Skip, OnExists::Overwrite, OnExists::Rename] {
+            assert_eq!(resolve_output_path(&fresh, policy), Some(fresh.clone()));
+        }
+
+        assert_eq!("rename".parse::<OnExists>().unwrap(), OnExists::Rename);
+        assert!("clobber".parse::<OnExists>().is_err());
+    }
 
     #[test]
     fn test_download_state() {
@@ -467,6 +1581,187 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_unavailable_chunk_fails_after_max_seeders() {
+        use brisby_core::proto::error_codes;
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let metadata = FileMetadata {
+            content_hash: [9u8; 32],
+            hash_algo: HashAlgorithm::default(),
+            filename: "missing.bin".to_string(),
+            size: 10,
+            chunk_size: CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            chunks: vec![brisby_core::ChunkInfo {
+                index: 0,
+                hash: [0u8; 32],
+                size: 10,
+            }],
+            keywords: vec![],
+            created_at: 0,
+            encryption: None,
+        };
+
+        // Every seeder asked says it doesn't have the chunk
+        for request_id in 1..=3 {
+            let not_found =
+                proto::error_response(request_id, error_codes::NOT_FOUND, "not found".to_string());
+            transport.queue_message(ReceivedMessage::new(not_found.to_bytes(), None));
+        }
+
+        let seeders: Vec<NymAddress> = (0..20)
+            .map(|i| NymAddress::new(format!("seeder-{}", i)))
+            .collect();
+        let downloader = Downloader::new(&transport).with_max_seeders_per_chunk(3);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            downloader.download_sequential(&metadata, &seeders, |_, _| {}),
+        )
+        .await
+        .expect("gave up without waiting on the remaining seeders");
+        assert!(result.is_err());
+
+        // Only the first three seeders were asked
+        let asked: Vec<NymAddress> = transport
+            .get_sent_messages()
+            .into_iter()
+            .map(|(seeder, _)| seeder)
+            .collect();
+        assert_eq!(asked, seeders[..3]);
+    }
+
+    #[tokio::test]
+    async fn test_sequential_download_prefers_responsive_seeder() {
+        use brisby_core::proto::error_codes;
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 + 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 4);
+
+        // The first seeder fails the first request, the second answers the rest
+        let unavailable =
+            proto::error_response(1, error_codes::UNAVAILABLE, "unavailable".to_string());
+        transport.queue_message(ReceivedMessage::new(unavailable.to_bytes(), None));
+        for idx in 0..4u32 {
+            let data = chunks[idx as usize].clone();
+            let response = proven_chunk_response(idx as u64 + 2, &metadata, idx, data);
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+
+        let [dead, alive] = ["dead-seeder", "alive-seeder"].map(NymAddress::new);
+        let downloader = Downloader::new(&transport);
+        let received = downloader
+            .download_sequential(&metadata, &[dead.clone(), alive.clone()], |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(received.len(), 4);
+
+        // After its failure the first seeder isn't asked first again
+        let asked: Vec<NymAddress> = transport
+            .get_sent_messages()
+            .into_iter()
+            .map(|(seeder, _)| seeder)
+            .collect();
+        let mut expected = vec![alive.clone(); 5];
+        expected[0] = dead.clone();
+        assert_eq!(asked, expected);
+
+        let stats = downloader.seeder_stats();
+        assert_eq!(stats[0].0, alive);
+        assert_eq!((stats[0].1.successes, stats[0].1.failures), (4, 0));
+        assert!(stats[0].1.latency.is_some());
+        assert_eq!(stats[1].0, dead);
+        assert_eq!((stats[1].1.successes, stats[1].1.failures), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_silent_seeder_abandoned_before_slow_one() {
+        use brisby_core::ReceivedMessage;
+        use std::sync::Arc;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let transport = Arc::new(transport);
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 5 + 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 6);
+
+        // The slow seeder answers each request 100ms after the last answer,
+        // so the whole file takes longer than the first-response timeout;
+        // the silent one never answers
+        let [silent, slow] = ["silent-seeder", "slow-seeder"].map(NymAddress::new);
+        let seeder_end = transport.clone();
+        let (responder_metadata, responder_chunks) = (metadata.clone(), chunks.clone());
+        let responder = tokio::spawn(async move {
+            let mut answered = 0;
+            loop {
+                let sent = seeder_end.get_sent_messages();
+                let Some((_, request)) = sent
+                    .iter()
+                    .filter(|(to, _)| to.as_str() == "slow-seeder")
+                    .nth(answered)
+                else {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                };
+                let request = Envelope::from_bytes(request).unwrap();
+                let Some(Payload::ChunkRequest(req)) = request.payload else {
+                    panic!("expected a chunk request");
+                };
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let idx = req.chunk_index;
+                let data = responder_chunks[idx as usize].clone();
+                let response =
+                    proven_chunk_response(request.request_id, &responder_metadata, idx, data);
+                seeder_end.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+                answered += 1;
+            }
+        });
+
+        let downloader = Downloader::new(&*transport).with_timeouts(RequestTimeouts {
+            first_response: Duration::from_millis(500),
+            idle: Duration::from_secs(5),
+        });
+        let start = Instant::now();
+        let received = downloader
+            .download_parallel(&metadata, &[silent.clone(), slow.clone()], 2, |_, _| {})
+            .await
+            .unwrap();
+        responder.abort();
+        assert_eq!(received.len(), 6);
+        assert!(start.elapsed() > Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The silent seeder got chunks 0 and 2 before its first request
+        // timed out, and nothing after
+        let asked: Vec<NymAddress> = transport
+            .get_sent_messages()
+            .into_iter()
+            .map(|(to, _)| to)
+            .collect();
+        assert_eq!(asked.len(), 8);
+        assert_eq!(asked[..3], [silent.clone(), slow.clone(), silent.clone()]);
+        assert!(asked[3..].iter().all(|to| *to == slow));
+        let stats: HashMap<NymAddress, SeederStats> =
+            downloader.seeder_stats().into_iter().collect();
+        assert_eq!((stats[&slow].successes, stats[&slow].failures), (6, 0));
+        assert_eq!((stats[&silent].successes, stats[&silent].failures), (0, 2));
+    }
+
     #[tokio::test]
     async fn test_reassemble_allows_unknown_sizes() {
         let mut transport = MockTransport::new();
@@ -474,19 +1769,23 @@ mod tests {
         let downloader = Downloader::new(&transport);
 
         let data = b"short-file";
-        let content_hash = *blake3::hash(data).as_bytes();
+        let content_hash = brisby_core::merkle::root(&[*blake3::hash(data).as_bytes()], 10);
         let metadata = FileMetadata {
             content_hash,
+            hash_algo: HashAlgorithm::default(),
             filename: "short.txt".to_string(),
             size: 0, // unknown total size
+            chunk_size: CHUNK_SIZE as u32,
+            content_defined: None,
             mime_type: None,
             chunks: vec![brisby_core::ChunkInfo {
                 index: 0,
-                hash: content_hash, // not used in reassemble_to_file, but provide something consistent
+                hash: *blake3::hash(data).as_bytes(),
                 size: 0, // unknown chunk size
             }],
             keywords: vec![],
             created_at: 0,
+            encryption: None,
         };
 
         let output = tempfile::NamedTempFile::new().unwrap();
@@ -497,4 +1796,608 @@ mod tests {
         let written = std::fs::read(output.path()).unwrap();
         assert_eq!(written, data);
     }
+
+    #[tokio::test]
+    async fn test_download_parallel_rejects_out_of_range_chunk() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let data = b"only-chunk".to_vec();
+        let content_hash = brisby_core::merkle::root(&[*blake3::hash(&data).as_bytes()], 10);
+        let metadata = FileMetadata {
+            content_hash,
+            hash_algo: HashAlgorithm::default(),
+            filename: "single.txt".to_string(),
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            chunks: vec![brisby_core::ChunkInfo {
+                index: 0,
+                hash: *blake3::hash(&data).as_bytes(),
+                size: data.len() as u32,
+            }],
+            keywords: vec![],
+            created_at: 0,
+            encryption: None,
+        };
+
+        // A bogus chunk with an index past the end arrives first
+        let bogus = b"bogus".to_vec();
+        let bogus_response = proto::chunk_response(
+            1,
+            content_hash.to_vec(),
+            7,
+            bogus.clone(),
+            blake3::hash(&bogus).as_bytes().to_vec(),
+        );
+        transport.queue_message(ReceivedMessage::new(bogus_response.to_bytes(), None));
+
+        // Rejecting the first reply re-requests the chunk as request 2
+        let valid_response = proto::chunk_response(
+            2,
+            content_hash.to_vec(),
+            0,
+            data.clone(),
+            blake3::hash(&data).as_bytes().to_vec(),
+        );
+        transport.queue_message(ReceivedMessage::new(valid_response.to_bytes(), None));
+
+        let downloader = Downloader::new(&transport);
+        let seeder = NymAddress::new("seeder-address");
+        let chunks = downloader
+            .download_parallel(&metadata, &[seeder], 4, |_, _| {})
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![(0, data)]);
+    }
+
+    #[tokio::test]
+    async fn test_download_parallel_rejects_empty_chunk() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let data = b"only-chunk".to_vec();
+        let content_hash = brisby_core::merkle::root(&[*blake3::hash(&data).as_bytes()], 10);
+        let metadata = FileMetadata {
+            content_hash,
+            hash_algo: HashAlgorithm::default(),
+            filename: "single.txt".to_string(),
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            chunks: vec![brisby_core::ChunkInfo {
+                index: 0,
+                hash: [0u8; 32], // unknown, as for downloads started from search results
+                size: data.len() as u32,
+            }],
+            keywords: vec![],
+            created_at: 0,
+            encryption: None,
+        };
+
+        // An empty chunk carrying the hash of empty input verifies against itself
+        let empty_response = proto::chunk_response(
+            1,
+            content_hash.to_vec(),
+            0,
+            vec![],
+            blake3::hash(b"").as_bytes().to_vec(),
+        );
+        transport.queue_message(ReceivedMessage::new(empty_response.to_bytes(), None));
+
+        // Rejecting the first reply re-requests the chunk as request 2
+        let valid_response = proto::chunk_response(
+            2,
+            content_hash.to_vec(),
+            0,
+            data.clone(),
+            blake3::hash(&data).as_bytes().to_vec(),
+        );
+        transport.queue_message(ReceivedMessage::new(valid_response.to_bytes(), None));
+
+        let downloader = Downloader::new(&transport);
+        let seeder = NymAddress::new("seeder-address");
+        let chunks = downloader
+            .download_parallel(&metadata, &[seeder], 4, |_, _| {})
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![(0, data)]);
+        assert!(check_chunk_not_empty(&metadata, 0, &[]).is_err());
+
+        // Without recorded sizes, only the last chunk may be empty
+        let mut unsized_metadata = metadata.clone();
+        unsized_metadata.chunks.push(unsized_metadata.chunks[0].clone());
+        for info in &mut unsized_metadata.chunks {
+            info.size = 0;
+        }
+        assert!(check_chunk_not_empty(&unsized_metadata, 0, &[]).is_err());
+        assert!(check_chunk_not_empty(&unsized_metadata, 1, &[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_download_parallel_rejects_chunk_failing_proof() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert!(metadata.hash_algo.is_merkle());
+
+        // A forged chunk 0 whose hash matches itself, sent with chunk 0's
+        // real proof
+        let forged = proven_chunk_response(1, &metadata, 0, vec![7u8; CHUNK_SIZE]);
+        transport.queue_message(ReceivedMessage::new(forged.to_bytes(), None));
+
+        // Chunk 1 is fine; rejecting chunk 0 re-requests it as request 3
+        for (request_id, idx) in [(2, 1u32), (3, 0)] {
+            let response =
+                proven_chunk_response(request_id, &metadata, idx, chunks[idx as usize].clone());
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+
+        let downloader = Downloader::new(&transport);
+        let seeder = NymAddress::new("seeder-address");
+        let mut received = downloader
+            .download_parallel(&metadata, &[seeder], 4, |_, _| {})
+            .await
+            .unwrap();
+        received.sort();
+
+        assert_eq!(received, vec![(0, chunks[0].clone()), (1, chunks[1].clone())]);
+    }
+
+    #[tokio::test]
+    async fn test_external_hash_list_catches_self_consistent_chunk() {
+        use brisby_core::ReceivedMessage;
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (manifest, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        let manifest_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(manifest_file.path(), serde_json::to_string(&manifest).unwrap()).unwrap();
+
+        // What a downloader knows from a search result for a file with a flat
+        // content hash: no chunk hashes and no proofs to check
+        let mut metadata = manifest.clone();
+        metadata.hash_algo = HashAlgorithm::Blake3;
+        for chunk in &mut metadata.chunks {
+            chunk.hash = [0u8; 32];
+        }
+
+        // A forged chunk 0 sent with its own hash, then the real chunks
+        let forged = vec![7u8; CHUNK_SIZE];
+        let reply = |request_id, idx: u32, data: Vec<u8>| {
+            let chunk_hash = blake3::hash(&data).as_bytes().to_vec();
+            let response = proto::chunk_response(
+                request_id,
+                metadata.content_hash.to_vec(),
+                idx,
+                data,
+                chunk_hash,
+            );
+            ReceivedMessage::new(response.to_bytes(), None)
+        };
+        let seeders = [NymAddress::new("forger"), NymAddress::new("honest")];
+
+        // The seeder's hash alone lets the forged chunk through
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        transport.queue_message(reply(1, 0, forged.clone()));
+        transport.queue_message(reply(2, 1, chunks[1].clone()));
+        let received = Downloader::new(&transport)
+            .download_sequential(&metadata, &seeders, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(received[0], (0, forged.clone()));
+
+        // The external list rejects it and the chunk is fetched from the next seeder
+        let hashes = ExternalHashList::load(manifest_file.path()).unwrap();
+        let mut checked = metadata.clone();
+        hashes.apply_to(&mut checked).unwrap();
+        assert_eq!(checked.hash_algo, manifest.hash_algo);
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        transport.queue_message(reply(1, 0, forged));
+        transport.queue_message(reply(2, 0, chunks[0].clone()));
+        transport.queue_message(reply(3, 1, chunks[1].clone()));
+        let received = Downloader::new(&transport)
+            .with_external_hashes(hashes.clone())
+            .download_sequential(&metadata, &seeders, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(received, vec![(0, chunks[0].clone()), (1, chunks[1].clone())]);
+
+        // A list for other content is refused up front
+        let mut other = metadata.clone();
+        other.content_hash = [1u8; 32];
+        assert!(hashes.apply_to(&mut other).is_err());
+    }
+
+    #[test]
+    fn test_check_chunk_index() {
+        assert!(check_chunk_index(0, 1).is_ok());
+        assert!(matches!(
+            check_chunk_index(1, 1),
+            Err(brisby_core::Error::InvalidChunkIndex { index: 1, total: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_range() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        // 6-chunk file with distinct content per chunk
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 6 - 100)
+            .map(|i| (i / CHUNK_SIZE + 1) as u8)
+            .collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 6);
+
+        // Chunks 2 and 3 are requested in turn as requests 1 and 2
+        for idx in 2..4u32 {
+            let data = chunks[idx as usize].clone();
+            let response = proven_chunk_response(idx as u64 - 1, &metadata, idx, data);
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+
+        let downloader = Downloader::new(&transport);
+        let seeder = NymAddress::new("seeder-address");
+        let output = tempfile::NamedTempFile::new().unwrap();
+        downloader
+            .download_range(&metadata, &[seeder], 2, 2, output.path())
+            .await
+            .unwrap();
+
+        // Only chunks 2 and 3 were requested
+        assert_eq!(transport.get_sent_messages().len(), 2);
+
+        let written = std::fs::read(output.path()).unwrap();
+        assert_eq!(written.len() as u64, metadata.size);
+        assert!(written[..CHUNK_SIZE * 2].iter().all(|&b| b == 0));
+        assert_eq!(
+            &written[CHUNK_SIZE * 2..CHUNK_SIZE * 4],
+            &content[CHUNK_SIZE * 2..CHUNK_SIZE * 4]
+        );
+        assert!(written[CHUNK_SIZE * 4..].iter().all(|&b| b == 0));
+    }
+
+    #[tokio::test]
+    async fn test_trusted_seeder_skips_chunk_verification() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 - 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        let content_hash = metadata.content_hash.to_vec();
+
+        // A forged chunk 1 that matches its own hash but not the file
+        let forged = vec![0x55u8; chunks[1].len()];
+        let forged_hash = blake3::hash(&forged).as_bytes().to_vec();
+        let forged_reply = |request_id: u64, chunk_hash: Vec<u8>| {
+            let response = proto::chunk_response(
+                request_id,
+                content_hash.clone(),
+                1,
+                forged.clone(),
+                chunk_hash,
+            );
+            ReceivedMessage::new(response.to_bytes(), None)
+        };
+
+        let trusted = NymAddress::new("my-own-seeder");
+        let other = NymAddress::new("someone-else");
+        let downloader = Downloader::new(&transport).with_trusted_seeders([trusted.clone()]);
+
+        // Even a chunk not matching the hash sent with it gets through from
+        // a trusted seeder, and nothing from it is checked against the file
+        let reply = ChunkReply {
+            index: 1,
+            data: forged.clone(),
+            content_hash: metadata.content_hash,
+            proof: vec![],
+        };
+        for (seeder, accepted) in [(&other, false), (&trusted, true)] {
+            let mut pending = downloader
+                .request_chunk(seeder, &metadata.content_hash, 1)
+                .await
+                .unwrap();
+            transport.queue_message(forged_reply(pending.request_id(), vec![0u8; 32]));
+            let received = downloader
+                .receive_chunk(&mut pending, seeder, RequestTimeouts::default(), metadata.hash_algo)
+                .await;
+            assert_eq!(received.is_ok(), accepted);
+            assert_eq!(
+                downloader.check_reply(&metadata, 1, &reply, seeder).is_ok(),
+                accepted
+            );
+        }
+
+        // The whole-file check still runs and catches it. Requests 1 and 2
+        // went out above
+        let response = proven_chunk_response(3, &metadata, 0, chunks[0].clone());
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        transport.queue_message(forged_reply(4, forged_hash));
+        let out_dir = tempfile::TempDir::new().unwrap();
+        let partial = PartialDownload::open(out_dir.path(), &metadata.content_hash).unwrap();
+        let outcome = downloader
+            .download_resumable(&metadata, &[trusted], 4, &partial, |_, _| {})
+            .await
+            .unwrap();
+        assert!(outcome.is_complete());
+        let output = out_dir.path().join("file.bin");
+        let result = downloader.reassemble_partial(&partial, &metadata, &output);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Final file hash verification failed"), "{}", error);
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_to_different_output() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 - 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 3);
+
+        let queue_chunk = |request_id: u64, idx: u32| {
+            let data = chunks[idx as usize].clone();
+            let response = proven_chunk_response(request_id, &metadata, idx, data);
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        };
+
+        let data_dir = tempfile::TempDir::new().unwrap();
+        let partials_dir = data_dir.path().join("partials");
+        let state_store = crate::download_store::MemoryDownloadStateStore::new();
+        let downloader = Downloader::new(&transport).with_state_store(&state_store);
+        let seeders = [NymAddress::new("seeder-address")];
+
+        // First attempt is interrupted after two of the three chunks arrive
+        queue_chunk(1, 0);
+        queue_chunk(2, 1);
+        let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+        let interrupted = tokio::time::timeout(
+            Duration::from_millis(300),
+            downloader.download_resumable(&metadata, &seeders, 4, &partial, |_, _| {}),
+        )
+        .await;
+        assert!(interrupted.is_err());
+        assert_eq!(partial.missing_chunks(3), vec![2]);
+        let sent_before = transport.get_sent_messages().len();
+
+        // The saved state remembers what was being fetched and from whom
+        let saved = state_store.load(&metadata.content_hash).unwrap().unwrap();
+        assert_eq!(saved.metadata, metadata);
+        assert_eq!(saved.seeders, vec!["seeder-address".to_string()]);
+
+        // Second attempt, to a different output name, only fetches the last
+        // chunk, using the saved seeders even though none are passed
+        // Requests 1 to 3 went out in the first attempt
+        queue_chunk(4, 2);
+        let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+        downloader
+            .download_resumable(&metadata, &[], 4, &partial, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(transport.get_sent_messages().len(), sent_before + 1);
+        assert!(partial.missing_chunks(3).is_empty());
+
+        let output = data_dir.path().join("renamed.bin");
+        downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+
+        partial.remove().unwrap();
+        assert!(!partials_dir
+            .join(brisby_core::hash_to_hex(&metadata.content_hash))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_download_state_is_ignored() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 241) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+
+        // A save cut short leaves truncated state behind
+        let data_dir = tempfile::TempDir::new().unwrap();
+        let partials_dir = data_dir.path().join("partials");
+        let state_store = crate::download_store::FsDownloadStateStore::new(partials_dir.clone());
+        let state = SavedDownloadState::new(metadata.clone(), vec!["old-seeder".to_string()]);
+        state_store.save(&state).unwrap();
+        let state_path = partials_dir
+            .join(brisby_core::hash_to_hex(&metadata.content_hash))
+            .join("state.json");
+        let json = std::fs::read(&state_path).unwrap();
+        std::fs::write(&state_path, &json[..json.len() / 2]).unwrap();
+
+        // The download starts over with the seeders given, and saves them
+        for (idx, chunk) in (0u32..).zip(&chunks) {
+            let response = proven_chunk_response(idx as u64 + 1, &metadata, idx, chunk.clone());
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+        let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+        let downloader = Downloader::new(&transport).with_state_store(&state_store);
+        let seeders = [NymAddress::new("seeder-address")];
+        let outcome = downloader
+            .download_resumable(&metadata, &seeders, 4, &partial, |_, _| {})
+            .await
+            .unwrap();
+        assert!(outcome.is_complete());
+        let saved = state_store.load(&metadata.content_hash).unwrap().unwrap();
+        assert_eq!(saved.seeders, vec!["seeder-address".to_string()]);
+
+        let output = data_dir.path().join("file.bin");
+        downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_local_store() {
+        use brisby_core::ReceivedMessage;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let data_dir = tempfile::TempDir::new().unwrap();
+        let source = data_dir.path().join("file.bin");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 - 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        let mut store = ChunkStore::new(data_dir.path().join("chunks"));
+        let metadata = store.add_file(&source).unwrap();
+
+        // Content held in full is written out without sending anything,
+        // even with no seeders to ask
+        let downloader = Downloader::new(&transport).with_local_store(&store);
+        let partials_dir = data_dir.path().join("partials");
+        let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+        let outcome = downloader
+            .download_resumable(&metadata, &[], 4, &partial, |_, _| {})
+            .await
+            .unwrap();
+        assert!(outcome.is_complete());
+        let output = data_dir.path().join("copy.bin");
+        downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+        assert!(transport.get_sent_messages().is_empty());
+
+        // With a chunk gone from disk, only that one is fetched
+        let chunk_path = store.file_dir(&metadata.content_hash).join("chunk_000001");
+        let chunk = std::fs::read(&chunk_path).unwrap();
+        std::fs::remove_file(&chunk_path).unwrap();
+        let mut lazy = ChunkStore::new_lazy(data_dir.path().join("chunks"));
+        assert!(lazy.load_file(&metadata.content_hash).unwrap());
+        let response = proven_chunk_response(1, &metadata, 1, chunk);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let downloader = Downloader::new(&transport).with_local_store(&lazy);
+        partial.remove().unwrap();
+        let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+        let seeders = [NymAddress::new("seeder-address")];
+        downloader
+            .download_resumable(&metadata, &seeders, 4, &partial, |_, _| {})
+            .await
+            .unwrap();
+        let output = data_dir.path().join("second.bin");
+        downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+        assert_eq!(transport.get_sent_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_completion_threshold_stops_with_partial_file() {
+        use brisby_core::ReceivedMessage;
+        use brisby_core::proto::error_codes;
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 10 - 10).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 10);
+
+        // Nobody has chunks 3 and 7; every other chunk arrives
+        for idx in 0..10u32 {
+            let request_id = idx as u64 + 1;
+            let response = if idx == 3 || idx == 7 {
+                proto::error_response(request_id, error_codes::NOT_FOUND, "not found".to_string())
+            } else {
+                proven_chunk_response(request_id, &metadata, idx, chunks[idx as usize].clone())
+            };
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+
+        let data_dir = tempfile::TempDir::new().unwrap();
+        let partial = PartialDownload::open(data_dir.path(), &metadata.content_hash).unwrap();
+        let downloader = Downloader::new(&transport).with_completion_threshold(0.8);
+        let seeders = [NymAddress::new("seeder-address")];
+
+        // Without the threshold this would wait on the stall retry
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(5),
+            downloader.download_resumable(&metadata, &seeders, 10, &partial, |_, _| {}),
+        )
+        .await
+        .expect("stops at the threshold")
+        .unwrap();
+        assert_eq!(outcome, DownloadOutcome::Partial { missing: vec![3, 7] });
+        assert!(!outcome.is_complete());
+
+        // The partial file has every chunk received, the missing ones zeroed,
+        // and is kept apart from the output path
+        let output = data_dir.path().join("partial.bin");
+        let part_path = downloader
+            .reassemble_available(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(part_path, partial_output_path(&output));
+        let mut expected = content.clone();
+        for idx in [3, 7] {
+            expected[idx * CHUNK_SIZE..(idx + 1) * CHUNK_SIZE].fill(0);
+        }
+        assert_eq!(std::fs::read(&part_path).unwrap(), expected);
+        // So a second run, which skips existing files by default, still
+        // fetches the rest and completes the file
+        assert_eq!(resolve_output_path(&output, OnExists::Skip), Some(output.clone()));
+        assert!(downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .is_err());
+
+        for (request_id, idx) in [(1, 3u32), (2, 7)] {
+            let response =
+                proven_chunk_response(request_id, &metadata, idx, chunks[idx as usize].clone());
+            transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        }
+        let downloader = Downloader::new(&transport);
+        let outcome = downloader
+            .download_resumable(&metadata, &seeders, 10, &partial, |_, _| {})
+            .await
+            .unwrap();
+        assert!(outcome.is_complete());
+        downloader
+            .reassemble_partial(&partial, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+        assert!(!part_path.exists());
+    }
 }
@@ -0,0 +1,204 @@
+//! Chunk map inspection for `brisby inspect` and `brisby verify`
+//!
+//! Shows what a seeder actually holds for a file: every chunk from the
+//! metadata alongside whether it is on disk and still matches its hash.
+//! The same check runs over a downloaded copy of the file.
+
+use crate::seeder::ChunkStore;
+use brisby_core::{chunk::verify_chunk, ChunkInfo, ContentHash, FileMetadata, HashAlgorithm};
+use std::fmt::{self, Write};
+use std::io::Read;
+use std::path::Path;
+
+/// State of one stored chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStatus {
+    /// On disk and matches its hash
+    Present,
+    /// Not on disk
+    Missing,
+    /// On disk but the hash or size doesn't match the metadata
+    Corrupt,
+}
+
+impl fmt::Display for ChunkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkStatus::Present => write!(f, "present"),
+            ChunkStatus::Missing => write!(f, "missing"),
+            ChunkStatus::Corrupt => write!(f, "corrupt"),
+        }
+    }
+}
+
+/// One row of the chunk map
+#[derive(Debug, Clone)]
+pub struct ChunkReport {
+    pub index: u32,
+    pub hash: ContentHash,
+    pub size: u32,
+    pub status: ChunkStatus,
+}
+
+/// Check stored chunk data (`None` if absent) against its metadata entry
+pub fn chunk_status(data: Option<&[u8]>, info: &ChunkInfo, hash_algo: HashAlgorithm) -> ChunkStatus {
+    match data {
+        None => ChunkStatus::Missing,
+        Some(data)
+            if data.len() == info.size as usize && verify_chunk(hash_algo, data, &info.hash) =>
+        {
+            ChunkStatus::Present
+        }
+        Some(_) => ChunkStatus::Corrupt,
+    }
+}
+
+/// Check every chunk of a stored file against its metadata
+///
+/// Returns `None` if the store has no metadata for `content_hash`.
+pub fn inspect_chunks(store: &ChunkStore, content_hash: &ContentHash) -> Option<Vec<ChunkReport>> {
+    let metadata = store.get_metadata(content_hash)?;
+
+    let reports = metadata
+        .chunks
+        .iter()
+        .map(|info| {
+            let data = store.read_chunk(content_hash, info.index);
+            ChunkReport {
+                index: info.index,
+                hash: info.hash,
+                size: info.size,
+                status: chunk_status(data.as_deref(), info, metadata.hash_algo),
+            }
+        })
+        .collect();
+
+    Some(reports)
+}
+
+/// Check a file on disk chunk by chunk against its metadata
+///
+/// Reads one chunk at a time, so memory use doesn't grow with the file.
+/// Chunks past the end of a truncated file are reported missing; bytes past
+/// the last chunk aren't looked at, so compare the file's length separately.
+pub fn inspect_file(path: &Path, metadata: &FileMetadata) -> std::io::Result<Vec<ChunkReport>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut data = Vec::new();
+    let mut reports = Vec::with_capacity(metadata.chunks.len());
+    for info in &metadata.chunks {
+        data.clear();
+        file.by_ref().take(info.size as u64).read_to_end(&mut data)?;
+        let data = if data.is_empty() && info.size > 0 {
+            None
+        } else {
+            Some(data.as_slice())
+        };
+        reports.push(ChunkReport {
+            index: info.index,
+            hash: info.hash,
+            size: info.size,
+            status: chunk_status(data, info, metadata.hash_algo),
+        });
+    }
+    Ok(reports)
+}
+
+/// Render the metadata and chunk map as shown by `brisby inspect`
+pub fn format_inspection(metadata: &FileMetadata, reports: &[ChunkReport]) -> String {
+    let mut out = String::new();
+    let count = |status| reports.iter().filter(|r| r.status == status).count();
+
+    let _ = writeln!(out, "File:      {}", metadata.filename);
+    let _ = writeln!(out, "Hash:      {}", brisby_core::hash_to_hex(&metadata.content_hash));
+    let _ = writeln!(out, "Algorithm: {}", metadata.hash_algo);
+    let _ = writeln!(out, "Size:      {} bytes ({} chunks)", metadata.size, metadata.chunks.len());
+    if let Some(mime_type) = &metadata.mime_type {
+        let _ = writeln!(out, "MIME type: {}", mime_type);
+    }
+    let _ = writeln!(
+        out,
+        "Chunks:    {} present, {} missing, {} corrupt",
+        count(ChunkStatus::Present),
+        count(ChunkStatus::Missing),
+        count(ChunkStatus::Corrupt)
+    );
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{:>6}  {:<64}  {:>8}  STATUS", "INDEX", "HASH", "SIZE");
+    for report in reports {
+        let _ = writeln!(
+            out,
+            "{:>6}  {:<64}  {:>8}  {}",
+            report.index,
+            brisby_core::hash_to_hex(&report.hash),
+            report.size,
+            report.status
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::CHUNK_SIZE;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect_reports_missing_and_corrupt_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let path = temp_dir.path().join("three_chunks.bin");
+        std::fs::write(&path, vec![3u8; CHUNK_SIZE * 2 + 10]).unwrap();
+
+        let metadata = ChunkStore::new(storage_dir.clone()).add_file(&path).unwrap();
+        let file_dir = storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        std::fs::remove_file(file_dir.join("chunk_000001")).unwrap();
+        std::fs::write(file_dir.join("chunk_000002"), b"bit rot").unwrap();
+
+        let mut store = ChunkStore::new(storage_dir);
+        assert!(store.load_file(&metadata.content_hash).unwrap());
+        let reports = inspect_chunks(&store, &metadata.content_hash).unwrap();
+
+        let statuses: Vec<_> = reports.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![ChunkStatus::Present, ChunkStatus::Missing, ChunkStatus::Corrupt]
+        );
+
+        let output = format_inspection(&metadata, &reports);
+        assert!(output.contains("1 present, 1 missing, 1 corrupt"));
+        assert!(output
+            .lines()
+            .any(|line| line.trim_start().starts_with("1 ") && line.ends_with("missing")));
+
+        assert!(inspect_chunks(&store, &[0u8; 32]).is_none());
+    }
+
+    #[test]
+    fn test_inspect_file_on_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("three_chunks.bin");
+        let mut content = vec![3u8; CHUNK_SIZE * 2 + 10];
+        std::fs::write(&path, &content).unwrap();
+        let metadata = ChunkStore::new(temp_dir.path().join("chunks")).add_file(&path).unwrap();
+
+        let statuses = |path: &Path| -> Vec<_> {
+            inspect_file(path, &metadata).unwrap().iter().map(|r| r.status).collect()
+        };
+        assert_eq!(statuses(&path), vec![ChunkStatus::Present; 3]);
+
+        // A flipped byte in the middle chunk, then a cut into it
+        content[CHUNK_SIZE + 5] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(
+            statuses(&path),
+            vec![ChunkStatus::Present, ChunkStatus::Corrupt, ChunkStatus::Present]
+        );
+        std::fs::write(&path, &content[..CHUNK_SIZE + 5]).unwrap();
+        assert_eq!(
+            statuses(&path),
+            vec![ChunkStatus::Present, ChunkStatus::Corrupt, ChunkStatus::Missing]
+        );
+    }
+}
@@ -2,8 +2,24 @@
 //!
 //! This library provides the core functionality for the Brisby P2P file sharing client.
 
+pub mod chunk_cache;
+pub mod collection;
 pub mod config;
+pub mod dispatch;
+pub mod doctor;
+pub mod download_store;
 pub mod downloader;
+pub mod inspect;
 pub mod local_index;
+pub mod manifest;
+pub mod metadata_file;
 pub mod network;
+pub mod partials;
+pub mod publish;
+pub mod rate_limit;
+pub mod reassembly;
+pub mod response_cache;
+pub mod search_cache;
 pub mod seeder;
+pub mod seeder_stats;
+pub mod share;
@@ -1,7 +1,11 @@
 //! Local file index using SQLite FTS5
 
-use brisby_core::{ContentHash, FileMetadata, SearchResult};
+use brisby_core::{keywords, ContentDefinedSizes, ContentHash, FileMetadata, SearchResult};
 use rusqlite::{params, Connection, Result};
+use std::fmt::Write;
+
+/// Name of the local index database in the data directory
+pub const LOCAL_INDEX_FILE: &str = "index.db";
 
 /// Local index for shared files
 pub struct LocalIndex {
@@ -58,7 +62,7 @@ impl LocalIndex {
 
     /// Add a file to the index
     pub fn add(&self, metadata: &FileMetadata) -> Result<()> {
-        let keywords = metadata.keywords.join(" ");
+        let keywords = keywords::normalize(&metadata.keywords).join(" ");
         let metadata_json = serde_json::to_string(metadata).unwrap_or_default();
 
         self.conn.execute(
@@ -84,9 +88,15 @@ impl LocalIndex {
 
     /// Search for files matching a query
     pub fn search(&self, query: &str, max_results: u32) -> Result<Vec<SearchResult>> {
+        let Some(match_expression) = keywords::match_expression(query) else {
+            return Ok(vec![]);
+        };
         let mut stmt = self.conn.prepare(
             r#"
-            SELECT f.content_hash, f.filename, f.size, f.chunk_count, bm25(files_fts) as rank
+            SELECT f.content_hash, f.filename, f.size, f.chunk_count, bm25(files_fts) as rank,
+                   json_extract(f.metadata_json, '$.chunk_size'),
+                   json_extract(f.metadata_json, '$.content_defined.min_size'),
+                   json_extract(f.metadata_json, '$.content_defined.avg_size')
             FROM files_fts fts
             JOIN files f ON f.rowid = fts.rowid
             WHERE files_fts MATCH ?
@@ -96,20 +106,36 @@ impl LocalIndex {
         )?;
 
         let results = stmt
-            .query_map(params![query, max_results], |row| {
+            .query_map(params![match_expression, max_results], |row| {
                 let hash_bytes: Vec<u8> = row.get(0)?;
                 let mut content_hash = [0u8; 32];
                 if hash_bytes.len() == 32 {
                     content_hash.copy_from_slice(&hash_bytes);
                 }
 
+                let min_chunk_size = row.get::<_, Option<i64>>(6)?;
+                let content_defined = match (min_chunk_size, row.get::<_, Option<i64>>(7)?) {
+                    (Some(min_size), Some(avg_size)) => Some(ContentDefinedSizes {
+                        min_size: min_size as u32,
+                        avg_size: avg_size as u32,
+                    }),
+                    _ => None,
+                };
+
                 Ok(SearchResult {
                     content_hash,
                     filename: row.get(1)?,
                     size: row.get::<_, i64>(2)? as u64,
                     chunk_count: row.get::<_, i64>(3)? as u32,
+                    // Metadata saved before chunk sizes were recorded has none
+                    chunk_size: row
+                        .get::<_, Option<i64>>(5)?
+                        .map_or(brisby_core::CHUNK_SIZE as u32, |size| size as u32),
+                    content_defined,
                     relevance: -row.get::<_, f64>(4)? as f32, // bm25 returns negative scores
                     seeders: vec![], // Local index doesn't track seeders
+                    relay_tokens: vec![],
+                    seeder_count: 0,
                 })
             })?
             .collect::<Result<Vec<_>>>()?;
@@ -130,6 +156,15 @@ impl LocalIndex {
         Ok(result.and_then(|json| serde_json::from_str(&json).ok()))
     }
 
+    /// Check whether a file is in the index
+    pub fn contains(&self, content_hash: &ContentHash) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM files WHERE content_hash = ?)",
+            params![content_hash.as_slice()],
+            |row| row.get(0),
+        )
+    }
+
     /// Remove a file from the index
     pub fn remove(&self, content_hash: &ContentHash) -> Result<bool> {
         let rows = self.conn.execute(
@@ -156,6 +191,61 @@ impl LocalIndex {
     }
 }
 
+/// Render shared files as listed by `brisby list`, oldest first
+pub fn format_list(files: &[FileMetadata]) -> String {
+    let mut files: Vec<&FileMetadata> = files.iter().collect();
+    files.sort_by(|a, b| {
+        a.created_at
+            .cmp(&b.created_at)
+            .then(a.filename.cmp(&b.filename))
+    });
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<64}  {:>12}  {:>6}  {:<16}  FILENAME",
+        "HASH", "SIZE", "CHUNKS", "SHARED (UTC)"
+    );
+    for metadata in files {
+        let _ = writeln!(
+            out,
+            "{:<64}  {:>12}  {:>6}  {:<16}  {}",
+            brisby_core::hash_to_hex(&metadata.content_hash),
+            metadata.size,
+            metadata.chunks.len(),
+            format_timestamp(metadata.created_at),
+            metadata.filename
+        );
+    }
+    out
+}
+
+/// Format Unix seconds as `YYYY-MM-DD HH:MM` in UTC
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let minutes = secs % 86400 / 60;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -164,8 +254,11 @@ mod tests {
     fn create_test_metadata() -> FileMetadata {
         FileMetadata {
             content_hash: [1u8; 32],
+            hash_algo: brisby_core::HashAlgorithm::default(),
             filename: "test_file.txt".to_string(),
             size: 1024,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
             mime_type: Some("text/plain".to_string()),
             chunks: vec![brisby_core::ChunkInfo {
                 index: 0,
@@ -174,16 +267,37 @@ mod tests {
             }],
             keywords: vec!["test".to_string(), "file".to_string()],
             created_at: 1000,
+            encryption: None,
         }
     }
 
+    #[test]
+    fn test_format_list() {
+        let mut older = create_test_metadata();
+        older.content_hash = [3u8; 32];
+        older.filename = "older.txt".to_string();
+        older.created_at = 951_782_400; // 2000-02-29 00:00 UTC
+        let mut newer = create_test_metadata();
+        newer.created_at = 1_700_000_000; // 2023-11-14 22:13:20 UTC
+
+        let out = format_list(&[newer, older]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(&"03".repeat(32)));
+        assert!(lines[1].contains("2000-02-29 00:00"));
+        assert!(lines[1].ends_with("older.txt"));
+        assert!(lines[2].contains("  1024       1  2023-11-14 22:13  test_file.txt"));
+    }
+
     #[test]
     fn test_add_and_get() {
         let temp = NamedTempFile::new().unwrap();
         let index = LocalIndex::open(temp.path()).unwrap();
 
         let metadata = create_test_metadata();
+        assert!(!index.contains(&metadata.content_hash).unwrap());
         index.add(&metadata).unwrap();
+        assert!(index.contains(&metadata.content_hash).unwrap());
 
         let retrieved = index.get(&metadata.content_hash).unwrap().unwrap();
         assert_eq!(retrieved.filename, metadata.filename);
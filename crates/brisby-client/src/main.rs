@@ -1,16 +1,18 @@
 //! Brisby - Privacy-preserving P2P file sharing client
 
 use anyhow::Result;
+use brisby_core::chunk::{ChunkKey, ChunkingStrategy};
+use brisby_core::keywords::QueryMode;
 use brisby_core::Transport;
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-mod config;
-mod downloader;
-mod local_index;
-mod network;
-mod seeder;
+use brisby_client::{
+    collection, config, doctor, downloader, inspect, local_index, manifest, metadata_file, seeder,
+    share,
+};
+#[cfg(feature = "nym")]
+use brisby_client::{download_store, network, partials, publish, search_cache, seeder_stats};
 
 #[derive(Parser)]
 #[command(name = "brisby")]
@@ -28,9 +30,20 @@ struct Cli {
     #[arg(long)]
     mock: bool,
 
-    /// Data directory
-    #[arg(short, long, default_value = "~/.brisby")]
-    data_dir: String,
+    /// Data directory (default: data_dir from the config file, or ~/.brisby)
+    #[arg(short, long)]
+    data_dir: Option<String>,
+
+    /// Nym identity directory, kept separate from the data directory
+    /// (default: identity_dir from the config file, <config dir>/brisby/identity,
+    /// or <data dir>/nym if an identity is already there)
+    #[arg(long)]
+    identity_dir: Option<String>,
+
+    /// Format to store file metadata in: json, or binary for a smaller and
+    /// faster to load form (existing JSON is converted when loaded)
+    #[arg(long, default_value = "json")]
+    metadata_format: metadata_file::MetadataFormat,
 
     #[command(subcommand)]
     command: Commands,
@@ -40,9 +53,30 @@ struct Cli {
 enum Commands {
     /// Pre-process a file so seed can share it with other peers
     Share {
-        /// Path to the file to share
+        /// Path to the file to share, or a directory with --recursive
         #[arg(required = true)]
         file: String,
+
+        /// Share every file under the directory
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// With --recursive, skip files larger than this many bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+
+        /// With --recursive, only share files matching this glob (repeatable)
+        #[arg(long = "include")]
+        include: Vec<String>,
+
+        /// With --recursive, skip files and directories matching this glob (repeatable)
+        #[arg(long = "exclude")]
+        exclude: Vec<String>,
+
+        /// Encrypt the chunks under this key: 64 hex characters, or a
+        /// passphrase to derive one from
+        #[arg(long)]
+        key: Option<String>,
     },
 
     /// Search for files
@@ -51,21 +85,78 @@ enum Commands {
         #[arg(required = true)]
         query: String,
 
+        /// Pass the query to the index as FTS5 syntax, so AND, OR, NOT,
+        /// parentheses and prefix* work
+        #[arg(long)]
+        advanced: bool,
+
         /// Maximum number of results
         #[arg(short, long, default_value = "20")]
         max_results: u32,
 
+        /// Skip this many results, to page through a large result set
+        #[arg(long, default_value = "0")]
+        offset: u32,
+
+        /// Drop results below this fraction of the best match's relevance (0-1)
+        #[arg(long, default_value = "0")]
+        min_relevance: f32,
+
+        /// Only files of at least this many bytes
+        #[arg(long)]
+        min_size: Option<u64>,
+
+        /// Only files of at most this many bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+
+        /// Only files of this MIME type, e.g. video/mp4, or video for any video
+        #[arg(long = "type")]
+        mime_type: Option<String>,
+
+        /// Only files first published in the last this many seconds
+        #[arg(long)]
+        published_within: Option<u64>,
+
         /// Index provider Nym address
         #[arg(short, long)]
         index_provider: String,
+
+        /// Always query the index provider, bypassing the search cache
+        #[arg(long)]
+        no_cache: bool,
+
+        /// How long cached search results stay fresh, in seconds
+        #[arg(long)]
+        cache_ttl: Option<u64>,
+
+        /// Maximum number of cached searches
+        #[arg(long)]
+        cache_size: Option<usize>,
     },
 
-    /// Download a file by its content hash
-    Download {
+    /// List who is seeding a file, as known to an index provider
+    Seeders {
         /// Content hash (hex-encoded)
         #[arg(required = true)]
         hash: String,
 
+        /// Index provider Nym address
+        #[arg(short, long)]
+        index_provider: String,
+    },
+
+    /// Download a file by its content hash
+    Download {
+        /// Content hash (hex-encoded); can be left out with --manifest
+        #[arg(required_unless_present = "manifest")]
+        hash: Option<String>,
+
+        /// Manifest written by export, giving the file's real chunk hashes
+        /// and sizes in place of --chunks, --size and --chunk-size
+        #[arg(long)]
+        manifest: Option<String>,
+
         /// Output path
         #[arg(short, long)]
         output: Option<String>,
@@ -74,7 +165,7 @@ enum Commands {
         #[arg(short, long, required = true)]
         seeder: Vec<String>,
 
-        /// Expected number of chunks (from search results)
+        /// Expected number of chunks (from search results; ignored with --manifest)
         #[arg(short, long, default_value = "1")]
         chunks: u32,
 
@@ -86,26 +177,182 @@ enum Commands {
         #[arg(long)]
         size: Option<u64>,
 
+        /// Chunk size the file was shared with, in bytes
+        #[arg(long, default_value_t = brisby_core::CHUNK_SIZE as u32)]
+        chunk_size: u32,
+
+        /// The file is chunked by content, as search results say; its chunk
+        /// sizes vary, so they have to come from --manifest
+        #[arg(long)]
+        content_defined: bool,
+
         /// Number of parallel chunk requests (default: 4, max: 16)
         #[arg(short, long, default_value = "4")]
         parallel: usize,
+
+        /// What to do if the output file exists: skip, overwrite or rename
+        #[arg(long, default_value = "skip")]
+        on_exists: downloader::OnExists,
+
+        /// Give up on a chunk after asking this many seeders (0 asks them all)
+        #[arg(long, default_value = "0")]
+        max_seeders_per_chunk: usize,
+
+        /// Among equally good seeders, prefer ones on different Nym gateways
+        #[arg(long)]
+        diverse_gateways: bool,
+
+        /// Manifest JSON from a trusted source to check each chunk against
+        #[arg(long)]
+        hash_list: Option<String>,
+
+        /// Stop once this fraction of chunks (e.g. 0.95) is verified, keeping
+        /// a partial file without checking the whole-file hash
+        #[arg(long)]
+        completion_threshold: Option<f64>,
+
+        /// Seeder you run or trust whose chunks skip per-chunk verification
+        /// (can repeat); the whole file is still checked
+        #[arg(long)]
+        trusted_seeder: Vec<String>,
+
+        /// Key to decrypt an encrypted file with, as given to share; needs
+        /// the file's manifest from --hash-list or --manifest
+        #[arg(long)]
+        key: Option<String>,
+    },
+
+    /// Write shared files and their seeders to a collection others can import
+    ExportCollection {
+        /// Content hashes (hex-encoded) to include (default: every shared file)
+        hash: Vec<String>,
+
+        /// Output path
+        #[arg(short, long, required = true)]
+        output: String,
+
+        /// Name of the collection
+        #[arg(short, long, default_value = "Brisby collection")]
+        name: String,
+
+        /// Seeder Nym address(es) to list for every file, e.g. our own
+        #[arg(short, long)]
+        seeder: Vec<String>,
+
+        /// Write brisby:// URIs, one per line, instead of an importable manifest
+        #[arg(long)]
+        uris: bool,
+    },
+
+    /// Download every file in a collection
+    ImportCollection {
+        /// Collection manifest written by export-collection
+        #[arg(required = true)]
+        file: String,
+
+        /// Directory to save the files in
+        #[arg(short, long, default_value = ".")]
+        output_dir: String,
+
+        /// Extra seeder Nym address(es) to try for every file
+        #[arg(short, long)]
+        seeder: Vec<String>,
+
+        /// Number of parallel chunk requests per file (default: 4, max: 16)
+        #[arg(short, long, default_value = "4")]
+        parallel: usize,
+
+        /// What to do if an output file exists: skip, overwrite or rename
+        #[arg(long, default_value = "skip")]
+        on_exists: downloader::OnExists,
+
+        /// Only list the files, without downloading them
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Write a shared file's manifest, for importing on another machine
+    Export {
+        /// Content hash (hex-encoded)
+        #[arg(required = true)]
+        hash: String,
+
+        /// Output path (default: standard output)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Check a manifest written by export and add it to the local index
+    Import {
+        /// Manifest file
+        #[arg(required = true)]
+        file: String,
     },
 
     /// List locally shared files
     List,
 
+    /// Show a shared file's metadata and the state of each stored chunk
+    Inspect {
+        /// Content hash (hex-encoded)
+        #[arg(required = true)]
+        hash: String,
+    },
+
+    /// Check a file on disk against its content hash
+    ///
+    /// Uses the file's metadata from the data directory, if it is there, to
+    /// check each chunk and say which ones are damaged.
+    Verify {
+        /// Expected content hash (hex-encoded)
+        #[arg(required = true)]
+        hash: String,
+
+        /// Path to the file
+        #[arg(required = true)]
+        path: String,
+
+        /// Chunk size the file was shared with, in bytes, if its metadata
+        /// isn't in the data directory; the largest chunk size if it was
+        /// chunked by content
+        #[arg(long, default_value_t = brisby_core::CHUNK_SIZE as u32)]
+        chunk_size: u32,
+
+        /// Smallest chunk size, if the file was chunked by content
+        #[arg(long, requires = "avg_chunk_size")]
+        min_chunk_size: Option<u32>,
+
+        /// Average chunk size, if the file was chunked by content
+        #[arg(long, requires = "min_chunk_size")]
+        avg_chunk_size: Option<u32>,
+    },
+
     /// Show status and statistics
     Status,
 
     /// Initialize configuration
     Init,
 
+    /// Check the local setup and report problems
+    Doctor {
+        /// Index provider Nym address to ping (defaults to the first one in the config)
+        #[arg(short, long)]
+        index_provider: Option<String>,
+    },
+
     /// Start seeding (make available for download) previously shared files
+    ///
+    /// Options left out come from the [seeder] section of the config file.
     Seed {
         /// Files to share (optional, loads all from storage if not specified)
         #[arg(short, long)]
         file: Vec<String>,
 
+        /// Serve the given files from where they are instead of copying them
+        /// into the data directory; they must stay put and unchanged
+        #[arg(long)]
+        link: bool,
+
         /// Also publish to index provider
         #[arg(short, long)]
         publish: bool,
@@ -113,6 +360,66 @@ enum Commands {
         /// Index provider Nym address (required if --publish is used)
         #[arg(short, long)]
         index_provider: Option<String>,
+
+        /// Publish under a rendezvous token instead of our address; the index
+        /// provider relays downloaders' requests so neither learns the other
+        #[arg(long)]
+        anonymous: bool,
+
+        /// Maximum concurrent chunk responses for any one file
+        #[arg(long)]
+        max_in_flight_per_file: Option<usize>,
+
+        /// Maximum concurrent chunk responses overall
+        #[arg(long)]
+        max_in_flight: Option<usize>,
+
+        /// Number of encoded chunk responses to cache for hot content (0 disables)
+        #[arg(long)]
+        response_cache_size: Option<usize>,
+
+        /// Keep this many of the most requested files in memory across
+        /// restarts and read the rest from disk on demand (0 loads everything)
+        #[arg(long)]
+        hot_set_size: Option<usize>,
+
+        /// Bytes of chunks read from disk to keep in memory; files are only
+        /// loaded into memory up front if they all fit (0 loads everything)
+        #[arg(long)]
+        chunk_cache_size: Option<usize>,
+
+        /// Count and log chunk requests without sending any chunk data, to
+        /// gauge demand; a report is printed on shutdown
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Cap upload at this many bytes per second; replies are delayed,
+        /// not dropped (0 for no limit)
+        #[arg(long)]
+        rate_limit: Option<u64>,
+
+        /// Re-verify a rotating batch of stored chunks every this many
+        /// seconds, repairing or withholding corrupt ones (0 disables)
+        #[arg(long)]
+        verify_interval: Option<u64>,
+
+        /// Chunks checked per re-verification pass
+        #[arg(long)]
+        verify_batch_size: Option<usize>,
+
+        /// Print a summary of requests and chunks served every this many
+        /// seconds (0 disables)
+        #[arg(long)]
+        stats_interval: Option<u64>,
+
+        /// Only serve and publish files whose chunks are all on disk
+        #[arg(long)]
+        complete_only: bool,
+
+        /// Zstd-compress chunks before sending them, where that makes them
+        /// smaller
+        #[arg(long)]
+        compress: bool,
     },
 }
 
@@ -132,36 +439,227 @@ async fn main() -> Result<()> {
         .with(filter)
         .init();
 
+    // Flags override the config file, which overrides the defaults
+    let mut settings = match config::expand_path(&cli.config)
+        .and_then(|path| config::Config::load_or_default(&path))
+    {
+        Ok(settings) => settings,
+        // Doctor reports a broken config file itself
+        Err(_) if matches!(cli.command, Commands::Doctor { .. }) => config::Config::default(),
+        Err(e) => return Err(e),
+    };
+    if let Some(data_dir) = &cli.data_dir {
+        settings.data_dir = data_dir.clone();
+    }
+    if let Some(identity_dir) = &cli.identity_dir {
+        settings.identity_dir = Some(identity_dir.clone());
+    }
+    let data_dir = settings.data_dir.clone();
+    // Search and download use a throwaway identity unless one is given explicitly
+    let client_identity =
+        settings.identity_dir.as_ref().map(|_| settings.transport_config()).transpose()?;
+
     match cli.command {
-        Commands::Share { file } => {
-            share_file(&file, &cli.data_dir).await?;
+        Commands::Share {
+            file,
+            recursive,
+            max_size,
+            include,
+            exclude,
+            key,
+        } => {
+            let key = key.as_deref().map(parse_chunk_key);
+            if recursive {
+                let filter = share::ShareFilter {
+                    max_size,
+                    include,
+                    exclude,
+                };
+                share_directory(&file, &filter, cli.metadata_format, key, &data_dir)?;
+            } else {
+                share_file(&file, cli.metadata_format, key, &data_dir).await?;
+            }
         }
-        Commands::Search { query, max_results, index_provider } => {
+        Commands::Search {
+            query,
+            max_results,
+            offset,
+            min_relevance,
+            min_size,
+            max_size,
+            mime_type,
+            published_within,
+            advanced,
+            index_provider,
+            no_cache,
+            cache_ttl,
+            cache_size,
+        } => {
+            let file_config = &settings.search_cache;
+            let cache_config = config::SearchCacheConfig {
+                enabled: file_config.enabled && !no_cache,
+                ttl_secs: cache_ttl.unwrap_or(file_config.ttl_secs),
+                max_stale_secs: file_config.max_stale_secs,
+                max_entries: cache_size.unwrap_or(file_config.max_entries),
+            };
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let filter = brisby_core::SearchFilter {
+                min_size,
+                max_size,
+                mime_type,
+                published_after: published_within.map(|secs| now.saturating_sub(secs)),
+            };
+            let mode = if advanced {
+                QueryMode::Advanced
+            } else {
+                QueryMode::Keywords
+            };
             search_files(
                 &query,
+                mode,
                 max_results,
+                offset,
+                min_relevance,
+                &filter,
                 &index_provider,
+                &cache_config,
+                client_identity,
                 cli.mock,
-                &cli.data_dir,
+                &data_dir,
             )
             .await?;
         }
-        Commands::Download { hash, output, seeder, chunks, filename, size, parallel } => {
+        Commands::Seeders {
+            hash,
+            index_provider,
+        } => {
+            list_seeders(&hash, &index_provider, client_identity, cli.mock, &data_dir)
+                .await?;
+        }
+        Commands::Download {
+            hash,
+            manifest: manifest_path,
+            output,
+            seeder,
+            chunks,
+            filename,
+            size,
+            chunk_size,
+            content_defined,
+            parallel,
+            on_exists,
+            max_seeders_per_chunk,
+            diverse_gateways,
+            hash_list,
+            completion_threshold,
+            trusted_seeder,
+            key,
+        } => {
+            let manifest = manifest_path
+                .map(|path| manifest::Manifest::load(std::path::Path::new(&path)))
+                .transpose()?;
+            let hash = match (hash, &manifest) {
+                (Some(hash), _) => hash,
+                (None, Some(manifest)) => brisby_core::hash_to_hex(&manifest.metadata.content_hash),
+                (None, None) => anyhow::bail!("A content hash or --manifest is required"),
+            };
+            let mut transfer_config = settings.transfer.clone();
+            transfer_config.trusted_seeders.extend(trusted_seeder);
             download_file(
                 &hash,
+                manifest.as_ref(),
                 output.as_deref(),
+                on_exists,
                 &seeder,
                 chunks,
                 filename.as_deref(),
                 size,
+                chunk_size,
+                content_defined,
                 parallel.min(16), // Cap at 16 parallel requests
+                max_seeders_per_chunk,
+                diverse_gateways,
+                hash_list.as_deref(),
+                completion_threshold,
+                key.as_deref().map(parse_chunk_key),
+                &transfer_config,
+                client_identity,
                 cli.mock,
-                &cli.data_dir,
+                &data_dir,
             )
             .await?;
         }
+        Commands::ExportCollection {
+            hash,
+            output,
+            name,
+            seeder,
+            uris,
+        } => {
+            export_collection(&hash, &output, &name, &seeder, uris, &data_dir)?;
+        }
+        Commands::ImportCollection {
+            file,
+            output_dir,
+            seeder,
+            parallel,
+            on_exists,
+            list,
+        } => {
+            let collection = collection::Collection::load(std::path::Path::new(&file))?;
+            println!(
+                "{}: {} files, {} bytes",
+                collection.name,
+                collection.files.len(),
+                collection.total_size()
+            );
+            for entry in &collection.files {
+                println!(
+                    "  {}  {} ({} bytes)",
+                    brisby_core::hash_to_hex(&entry.metadata.content_hash),
+                    entry.metadata.filename,
+                    entry.metadata.size
+                );
+            }
+            if !list {
+                import_collection(
+                    &collection,
+                    &output_dir,
+                    &seeder,
+                    parallel.min(16),
+                    on_exists,
+                    &settings.transfer,
+                    client_identity,
+                    cli.mock,
+                    &data_dir,
+                )
+                .await?;
+            }
+        }
+        Commands::Export { hash, output } => {
+            export_manifest(&hash, output.as_deref(), &data_dir)?;
+        }
+        Commands::Import { file } => {
+            import_manifest(&file, &data_dir)?;
+        }
         Commands::List => {
-            list_files(&cli.data_dir).await?;
+            list_files(&data_dir).await?;
+        }
+        Commands::Inspect { hash } => {
+            inspect_file(&hash, &data_dir).await?;
+        }
+        Commands::Verify {
+            hash,
+            path,
+            chunk_size,
+            min_chunk_size,
+            avg_chunk_size,
+        } => {
+            let chunking = chunking_from_args(chunk_size, min_chunk_size, avg_chunk_size)?;
+            verify_download(&hash, &path, chunking, &data_dir)?;
         }
         Commands::Status => {
             show_status().await?;
@@ -169,13 +667,56 @@ async fn main() -> Result<()> {
         Commands::Init => {
             init_config().await?;
         }
-        Commands::Seed { file, publish, index_provider } => {
+        Commands::Doctor { index_provider } => {
+            run_doctor(&cli.config, &settings, index_provider.as_deref(), cli.mock).await?;
+        }
+        Commands::Seed {
+            file,
+            link,
+            publish,
+            index_provider,
+            anonymous,
+            max_in_flight_per_file,
+            max_in_flight,
+            response_cache_size,
+            hot_set_size,
+            chunk_cache_size,
+            dry_run,
+            rate_limit,
+            verify_interval,
+            verify_batch_size,
+            stats_interval,
+            complete_only,
+            compress,
+        } => {
+            let file_config = &settings.seeder;
+            let seeder_config = config::SeederConfig {
+                max_in_flight_per_content: max_in_flight_per_file
+                    .unwrap_or(file_config.max_in_flight_per_content),
+                max_in_flight_total: max_in_flight.unwrap_or(file_config.max_in_flight_total),
+                response_cache_size: response_cache_size
+                    .unwrap_or(file_config.response_cache_size),
+                hot_set_size: hot_set_size.unwrap_or(file_config.hot_set_size),
+                chunk_cache_size: chunk_cache_size.unwrap_or(file_config.chunk_cache_size),
+                dry_run: dry_run || file_config.dry_run,
+                upload_rate_limit: rate_limit.unwrap_or(file_config.upload_rate_limit),
+                verify_interval_secs: verify_interval.unwrap_or(file_config.verify_interval_secs),
+                verify_batch_size: verify_batch_size.unwrap_or(file_config.verify_batch_size),
+                stats_interval_secs: stats_interval.unwrap_or(file_config.stats_interval_secs),
+                serve_incomplete: file_config.serve_incomplete && !complete_only,
+                compress_chunks: compress || file_config.compress_chunks,
+            };
             start_seeding(
                 &file,
+                link,
                 publish,
                 index_provider.as_deref(),
+                anonymous,
+                &seeder_config,
+                cli.metadata_format,
+                settings.transport_config()?,
                 cli.mock,
-                &cli.data_dir,
+                &data_dir,
             )
             .await?;
         }
@@ -184,35 +725,47 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn share_file(path: &str, data_dir: &str) -> Result<()> {
-    use std::path::Path;
-
-    let path = Path::new(path);
-    if !path.exists() {
-        anyhow::bail!("File not found: {}", path.display());
-    }
-
+async fn share_file(
+    path: &str,
+    metadata_format: metadata_file::MetadataFormat,
+    key: Option<ChunkKey>,
+    data_dir: &str,
+) -> Result<()> {
     // Set up chunk storage
-    let data_path = expand_path(data_dir);
+    let data_path = config::expand_path(data_dir)?;
     std::fs::create_dir_all(&data_path)?;
-    let chunks_dir = data_path.join("chunks");
+    let mut store =
+        seeder::ChunkStore::new(data_path.join("chunks")).with_metadata_format(metadata_format);
+    if let Some(key) = key {
+        store = store.with_encryption_key(key);
+    }
 
-    let mut store = seeder::ChunkStore::new(chunks_dir);
+    let result = share::share_file(&mut store, std::path::Path::new(path))?;
+    let metadata = &result.metadata;
 
-    // Add file to chunk store (this chunks and stores locally)
-    tracing::info!("Processing file: {}", path.display());
-    let metadata = store.add_file(path)?;
+    let index = local_index::LocalIndex::open(&data_path.join(local_index::LOCAL_INDEX_FILE))?;
+    index.add(metadata)?;
 
     tracing::info!(
         "File stored: {} bytes, {} chunks",
         metadata.size,
         metadata.chunks.len()
     );
-    tracing::info!("Content hash: {}", brisby_core::hash_to_hex(&metadata.content_hash));
+    tracing::info!("Chunks stored in {}", result.chunk_dir.display());
 
     println!("Shared: {}", metadata.filename);
     println!("Hash: {}", brisby_core::hash_to_hex(&metadata.content_hash));
     println!("Size: {} bytes ({} chunks)", metadata.size, metadata.chunks.len());
+    println!("URI: {}", result.uri);
+    if metadata.encryption.is_some() {
+        println!();
+        println!("Chunks are encrypted. To download, pass the key with --key and this manifest");
+        println!("with --hash-list:");
+        println!(
+            "  {}",
+            result.chunk_dir.join(metadata_format.file_name()).display()
+        );
+    }
     println!();
     println!("File is stored locally. To make it available on the network:");
     println!("  brisby seed --publish --index-provider <ADDRESS>");
@@ -220,18 +773,75 @@ async fn share_file(path: &str, data_dir: &str) -> Result<()> {
     Ok(())
 }
 
+fn share_directory(
+    dir: &str,
+    filter: &share::ShareFilter,
+    metadata_format: metadata_file::MetadataFormat,
+    key: Option<ChunkKey>,
+    data_dir: &str,
+) -> Result<()> {
+    let dir = std::path::Path::new(dir);
+
+    let data_path = config::expand_path(data_dir)?;
+    std::fs::create_dir_all(&data_path)?;
+    let mut store =
+        seeder::ChunkStore::new(data_path.join("chunks")).with_metadata_format(metadata_format);
+    if let Some(key) = key {
+        store = store.with_encryption_key(key);
+    }
+
+    let summary = share::share_directory(&mut store, dir, filter)?;
+
+    let index = local_index::LocalIndex::open(&data_path.join(local_index::LOCAL_INDEX_FILE))?;
+    for (path, metadata) in &summary.shared {
+        index.add(metadata)?;
+        println!(
+            "{}  {}",
+            brisby_core::hash_to_hex(&metadata.content_hash),
+            path.display()
+        );
+    }
+    for (path, reason) in &summary.skipped {
+        tracing::info!("Skipped {}: {}", path.display(), reason);
+    }
+    for (path, error) in &summary.failed {
+        eprintln!("Failed: {}: {}", path.display(), error);
+    }
+
+    println!();
+    println!(
+        "Shared {} file(s), {} bytes; skipped {}, failed {}",
+        summary.shared.len(),
+        summary.shared_bytes(),
+        summary.skipped.len(),
+        summary.failed.len()
+    );
+    if !summary.shared.is_empty() {
+        println!();
+        println!("Files are stored locally. To make them available on the network:");
+        println!("  brisby seed --publish --index-provider <ADDRESS>");
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn search_files(
     query: &str,
+    mode: QueryMode,
     max_results: u32,
+    offset: u32,
+    min_relevance: f32,
+    filter: &brisby_core::SearchFilter,
     index_provider: &str,
+    cache_config: &config::SearchCacheConfig,
+    identity: Option<brisby_core::TransportConfig>,
     use_mock: bool,
     data_dir: &str,
 ) -> Result<()> {
     tracing::info!("Searching for: {} (max {} results)", query, max_results);
     tracing::info!("Index provider: {}", index_provider);
 
-    let index_addr = brisby_core::NymAddress::new(index_provider);
-
     if use_mock {
         // Use mock transport
         let mut transport = brisby_core::transport::mock::MockTransport::new();
@@ -246,12 +856,31 @@ async fn search_files(
         {
             use brisby_core::NymTransport;
 
+            let index_addr = brisby_core::NymAddress::try_new(index_provider)?;
+            let cache = if cache_config.enabled {
+                let data_path = config::expand_path(data_dir)?;
+                std::fs::create_dir_all(&data_path)?;
+                Some(
+                    search_cache::SearchCache::open(
+                        &data_path.join("search_cache.db"),
+                        cache_config.ttl_secs,
+                        cache_config.max_entries,
+                    )?
+                    .with_max_stale(cache_config.max_stale_secs),
+                )
+            } else {
+                None
+            };
+
             // Use a temporary directory for Nym storage to avoid conflicts with seeder
             let temp_dir = tempfile::tempdir()?;
-            let nym_path = temp_dir.path().join("nym");
+            let transport_config = identity.unwrap_or_else(|| brisby_core::TransportConfig {
+                storage_path: Some(temp_dir.path().join("nym")),
+                ..Default::default()
+            });
 
             tracing::info!("Connecting to Nym network...");
-            let mut transport = NymTransport::with_storage(nym_path);
+            let mut transport = NymTransport::new(transport_config);
             transport.connect().await?;
 
             tracing::info!("Connected to Nym network");
@@ -261,23 +890,57 @@ async fn search_files(
 
             // Perform search
             tracing::info!("Sending search query...");
-            let results = network::search_index_provider(&transport, &index_addr, query, max_results).await?;
+            let page = network::search_negotiated(
+                &transport,
+                &index_addr,
+                query,
+                mode,
+                max_results,
+                offset,
+                min_relevance,
+                filter,
+                cache.as_ref(),
+            )
+            .await?;
+            let results = page.results;
+            if page.refresh.is_some() {
+                println!("Showing cached results, refreshing them from the index provider");
+            }
 
             if results.is_empty() {
                 println!("No results found for '{}'", query);
             } else {
-                println!("Found {} results for '{}':", results.len(), query);
+                let first = offset as usize + 1;
+                let last = offset as usize + results.len();
+                match page.total {
+                    Some(total) => {
+                        println!("Showing {}-{} of {} results for '{}':", first, last, total, query)
+                    }
+                    None if offset > 0 => {
+                        println!("Showing results {}-{} for '{}':", first, last, query)
+                    }
+                    None => println!("Found {} results for '{}':", results.len(), query),
+                }
                 println!();
                 for (i, result) in results.iter().enumerate() {
                     println!(
-                        "{}. {} ({} bytes, {} chunks)",
-                        i + 1,
+                        "{}. {} ({} bytes, {} chunks, {} seeders)",
+                        first + i,
                         result.filename,
                         result.size,
-                        result.chunk_count
+                        result.chunk_count,
+                        result.seeder_count
                     );
                     println!("   Hash: {}", brisby_core::hash_to_hex(&result.content_hash));
                     println!("   Relevance: {:.2}", result.relevance);
+                    if let Some(sizes) = result.content_defined {
+                        println!(
+                            "   Chunked by content, {} to {} bytes (download with --manifest)",
+                            sizes.min_size, result.chunk_size
+                        );
+                    } else if result.chunk_size != brisby_core::CHUNK_SIZE as u32 {
+                        println!("   Chunk size: {} (download with --chunk-size)", result.chunk_size);
+                    }
                     if !result.seeders.is_empty() {
                         println!("   Seeders:");
                         for seeder in &result.seeders {
@@ -288,13 +951,21 @@ async fn search_files(
                 }
             }
 
+            // Stale results have been shown; refresh them for next time
+            if let (Some(refresh), Some(cache)) = (page.refresh, &cache) {
+                if let Err(e) = refresh.run(&transport, &index_addr, cache).await {
+                    tracing::warn!("Failed to refresh cached search: {}", e);
+                }
+            }
+
             transport.disconnect().await?;
         }
 
         #[cfg(not(feature = "nym"))]
         {
             // Suppress unused variable warnings in non-nym build
-            let _ = (&index_addr, &data_dir);
+            let _ = (&mode, &offset, &min_relevance, &filter);
+            let _ = (&cache_config, &identity, &data_dir);
             anyhow::bail!("Nym transport not available. Compile with --features nym or use --mock");
         }
     }
@@ -302,35 +973,170 @@ async fn search_files(
     Ok(())
 }
 
-fn expand_path(path: &str) -> PathBuf {
-    if path.starts_with("~/") {
-        if let Some(home) = dirs::home_dir() {
-            return home.join(&path[2..]);
+async fn list_seeders(
+    hash: &str,
+    index_provider: &str,
+    identity: Option<brisby_core::TransportConfig>,
+    use_mock: bool,
+    data_dir: &str,
+) -> Result<()> {
+    let content_hash = brisby_core::hex_to_hash(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+
+    // We may hold the file ourselves, whatever the index knows
+    let index_path = config::expand_path(data_dir)?.join(local_index::LOCAL_INDEX_FILE);
+    if index_path.exists() {
+        if let Some(metadata) = local_index::LocalIndex::open(&index_path)?.get(&content_hash)? {
+            println!("Shared locally as {}", metadata.filename);
+        }
+    }
+
+    if use_mock {
+        println!("Mock mode: would look up seeders of {} on {}", hash, index_provider);
+        println!("(No real network connection in mock mode)");
+        return Ok(());
+    }
+
+    #[cfg(feature = "nym")]
+    {
+        use brisby_core::NymTransport;
+
+        let index_addr = brisby_core::NymAddress::try_new(index_provider)?;
+
+        // Use a temporary directory for Nym storage to avoid conflicts with seeder
+        let temp_dir = tempfile::tempdir()?;
+        let transport_config = identity.unwrap_or_else(|| brisby_core::TransportConfig {
+            storage_path: Some(temp_dir.path().join("nym")),
+            ..Default::default()
+        });
+
+        tracing::info!("Connecting to Nym network...");
+        let mut transport = NymTransport::new(transport_config);
+        transport.connect().await?;
+
+        let seeders = network::find_seeders(&transport, &index_addr, &content_hash).await?;
+        if seeders.is_empty() {
+            println!("No seeders listed for {}", hash);
+        } else {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            println!("Seeders of {} ({}):", hash, seeders.len());
+            print!("{}", network::format_seeders(&seeders, now));
         }
+
+        transport.disconnect().await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "nym"))]
+    {
+        // Suppress unused variable warnings in non-nym build
+        let _ = (&content_hash, &identity);
+        anyhow::bail!("Nym transport not available. Compile with --features nym or use --mock");
     }
-    PathBuf::from(path)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download_file(
     hash: &str,
+    manifest: Option<&manifest::Manifest>,
     output: Option<&str>,
+    on_exists: downloader::OnExists,
     seeders: &[String],
     chunk_count: u32,
     filename: Option<&str>,
     size: Option<u64>,
+    chunk_size: u32,
+    content_defined: bool,
     parallel: usize,
+    max_seeders_per_chunk: usize,
+    diverse_gateways: bool,
+    hash_list: Option<&str>,
+    completion_threshold: Option<f64>,
+    key: Option<ChunkKey>,
+    transfer_config: &config::TransferConfig,
+    identity: Option<brisby_core::TransportConfig>,
     use_mock: bool,
     data_dir: &str,
 ) -> Result<()> {
     use std::path::Path;
 
-    if seeders.is_empty() {
+    let content_hash =
+        brisby_core::hex_to_hash(hash).map_err(|e| anyhow::anyhow!("Invalid hash: {}", e))?;
+
+    // Content already in our own chunk store is copied from there, and needs
+    // nothing from the network if all of it is
+    let data_path = config::expand_path(data_dir)?;
+    let mut local_store = seeder::ChunkStore::new_lazy(data_path.join("chunks"));
+    let held_locally =
+        local_store.load_file(&content_hash)? && local_store.is_complete(&content_hash);
+    let local_metadata = local_store.get_metadata(&content_hash).cloned();
+
+    if seeders.is_empty() && !held_locally {
         anyhow::bail!("At least one seeder address required. Use -s <address>");
     }
+    if completion_threshold.is_some_and(|t| !(t > 0.0 && t < 1.0)) {
+        anyhow::bail!("--completion-threshold must be between 0 and 1");
+    }
+    if let Some(manifest) = manifest {
+        if manifest.metadata.content_hash != content_hash {
+            anyhow::bail!(
+                "Manifest is for {}, not {}",
+                brisby_core::hash_to_hex(&manifest.metadata.content_hash),
+                hash
+            );
+        }
+    }
+    // Content-defined chunks vary in size, so where each one goes in the file
+    // is only known from the chunk list in the file's metadata
+    if content_defined && local_metadata.is_none() && manifest.is_none() {
+        anyhow::bail!(
+            "Files chunked by content can only be downloaded with their manifest (--manifest)"
+        );
+    }
+    // The content hash covers the file size, so chunks can't be checked
+    // against it without knowing the size
+    if size.is_none() && local_metadata.is_none() && manifest.is_none() {
+        anyhow::bail!("--size is needed to check chunks against the content hash");
+    }
+    // A manifest's chunk hashes are as good as a hash list's
+    let external_hashes = match hash_list {
+        Some(path) => Some(downloader::ExternalHashList::load(Path::new(path))?),
+        None => manifest.map(|m| downloader::ExternalHashList::from_metadata(&m.metadata)),
+    };
+    let encrypted = external_hashes
+        .as_ref()
+        .is_some_and(|hashes| hashes.encryption.is_some())
+        || local_metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.encryption.is_some());
+    if key.is_some() && !encrypted {
+        anyhow::bail!(
+            "--key needs the encrypted file's manifest with --hash-list or --manifest, \
+             which holds its nonce"
+        );
+    }
+    if encrypted && key.is_none() {
+        println!("File is encrypted and no --key was given; it will be saved encrypted");
+    }
 
     let default_filename = format!("{}.download", &hash[..8]);
-    let output_filename = filename.unwrap_or(&default_filename);
-    let output_path = Path::new(output.unwrap_or(output_filename));
+    // The manifest may come from someone else: only take the name from it
+    let manifest_filename = manifest
+        .and_then(|manifest| Path::new(&manifest.metadata.filename).file_name())
+        .and_then(|name| name.to_str());
+    let output_filename = filename.or(manifest_filename).unwrap_or(&default_filename);
+    let requested_path = Path::new(output.unwrap_or(output_filename));
+    let Some(output_path) = downloader::resolve_output_path(requested_path, on_exists) else {
+        println!(
+            "{} already exists, not downloading (use --on-exists overwrite or rename)",
+            requested_path.display()
+        );
+        return Ok(());
+    };
+    let output_path = output_path.as_path();
 
     tracing::info!("Downloading: {}", hash);
     tracing::info!("From {} seeder(s) with {} parallel requests", seeders.len(), parallel);
@@ -344,61 +1150,114 @@ async fn download_file(
 
     #[cfg(feature = "nym")]
     {
-        use brisby_core::{ChunkInfo, FileMetadata, NymTransport};
+        use brisby_core::{ChunkInfo, FileMetadata, HashAlgorithm, NymTransport};
         use std::sync::atomic::{AtomicU32, Ordering};
         use std::time::Instant;
 
-        let content_hash = brisby_core::hex_to_hash(hash)
-            .map_err(|e| anyhow::anyhow!("Invalid hash: {}", e))?;
-
-        // Create a minimal FileMetadata for the downloader
-        // In a real scenario, we'd get full metadata from the index provider
-        let size_hint = size.unwrap_or(0);
-        let chunk_entries: Vec<ChunkInfo> = (0..chunk_count)
-            .map(|i| {
-                // If we know the total size, derive per-chunk sizes; otherwise mark as unknown (0)
-                let chunk_size = if size_hint > 0 {
-                    let offset = i as u64 * brisby_core::CHUNK_SIZE as u64;
-                    let remaining = size_hint.saturating_sub(offset);
-                    remaining.min(brisby_core::CHUNK_SIZE as u64) as u32
-                } else {
-                    0
+        let seeder_addresses = seeders
+            .iter()
+            .map(|s| parse_seeder(s))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Create a minimal FileMetadata for the downloader, unless our own
+        // copy or a manifest has the real one. In a real scenario, we'd get
+        // full metadata from the index provider
+        let known_metadata =
+            local_metadata.or_else(|| manifest.map(|manifest| manifest.metadata.clone()));
+        let mut metadata = match known_metadata {
+            Some(known) => FileMetadata {
+                filename: output_filename.to_string(),
+                ..known
+            },
+            None => {
+                let mut metadata = FileMetadata {
+                    content_hash,
+                    // Search results don't name an algorithm, but seeders move files
+                    // with flat hashes over to Merkle ones before publishing, so
+                    // it's a Merkle root that each chunk's proof is checked against
+                    hash_algo: HashAlgorithm::default(),
+                    filename: output_filename.to_string(),
+                    size: size.unwrap_or(0),
+                    chunk_size,
+                    // Content-defined files need their metadata, see above
+                    content_defined: None,
+                    mime_type: None,
+                    chunks: vec![],
+                    keywords: vec![],
+                    created_at: 0,
+                    encryption: None,
                 };
-
-                ChunkInfo {
-                    index: i,
-                    hash: [0u8; 32], // We verify chunks by their own hash in the response
-                    size: chunk_size,
-                }
-            })
-            .collect();
-
-        let metadata = FileMetadata {
-            content_hash,
-            filename: output_filename.to_string(),
-            size: size_hint,
-            mime_type: None,
-            chunks: chunk_entries,
-            keywords: vec![],
-            created_at: 0,
+                metadata.chunks = (0..chunk_count)
+                    .map(|i| {
+                        // If we know the total size, derive per-chunk sizes;
+                        // otherwise mark as unknown (0)
+                        let chunk_size = if metadata.size > 0 {
+                            let offset = i as u64 * metadata.chunk_size as u64;
+                            let remaining = metadata.size.saturating_sub(offset);
+                            remaining.min(metadata.chunk_size as u64) as u32
+                        } else {
+                            0
+                        };
+
+                        ChunkInfo {
+                            index: i,
+                            hash: [0u8; 32], // Chunks are verified by their Merkle proofs instead
+                            size: chunk_size,
+                        }
+                    })
+                    .collect();
+                metadata
+            }
         };
+        let chunk_count = metadata.chunks.len() as u32;
+        if let Some(hashes) = &external_hashes {
+            hashes.apply_to(&mut metadata)?;
+            println!("Checking chunks against {}", hash_list.unwrap_or("the manifest"));
+        }
 
         // Use a temporary directory for Nym storage to avoid conflicts with seeder
         let temp_dir = tempfile::tempdir()?;
-        let nym_path = temp_dir.path().join("nym");
-
-        tracing::info!("Connecting to Nym network...");
-        let mut transport = NymTransport::with_storage(nym_path);
-        transport.connect().await?;
-
-        tracing::info!("Connected to Nym network");
-
-        let seeder_addresses: Vec<brisby_core::NymAddress> = seeders
-            .iter()
-            .map(|s| brisby_core::NymAddress::new(s))
-            .collect();
+        let transport_config = identity.unwrap_or_else(|| brisby_core::TransportConfig {
+            storage_path: Some(temp_dir.path().join("nym")),
+            ..Default::default()
+        });
+
+        let mut transport = NymTransport::new(transport_config);
+        if held_locally {
+            println!("All {} chunks are held locally, not connecting", chunk_count);
+        } else {
+            tracing::info!("Connecting to Nym network...");
+            transport.connect().await?;
+            tracing::info!("Connected to Nym network");
+        }
 
-        let dl = downloader::Downloader::new(&transport);
+        // Progress is kept by content hash, independent of the output path
+        let partials_dir = data_path.join("partials");
+        let state_store = download_store::FsDownloadStateStore::new(partials_dir.clone());
+        let mut dl = downloader::Downloader::new(&transport)
+            .with_state_store(&state_store)
+            .with_local_store(&local_store)
+            .with_max_seeders_per_chunk(max_seeders_per_chunk)
+            .with_gateway_diversity(diverse_gateways)
+            .with_timeouts(transfer_config.request_timeouts());
+        if let Some(threshold) = completion_threshold {
+            dl = dl.with_completion_threshold(threshold);
+        }
+        if !transfer_config.trusted_seeders.is_empty() {
+            println!(
+                "Not verifying chunks from {} trusted seeder(s) until the file is complete",
+                transfer_config.trusted_seeders.len()
+            );
+            dl = dl.with_trusted_seeders(
+                transfer_config.trusted_seeders.iter().map(brisby_core::NymAddress::new),
+            );
+        }
+        if let Some(hashes) = external_hashes {
+            dl = dl.with_external_hashes(hashes);
+        }
+        if let Some(key) = key {
+            dl = dl.with_key(key);
+        }
 
         println!(
             "Downloading {} chunks from {} seeder(s) ({} parallel requests)...",
@@ -410,8 +1269,10 @@ async fn download_file(
         let start_time = Instant::now();
         let last_printed = AtomicU32::new(0);
 
-        let chunks = dl
-            .download_parallel(&metadata, &seeder_addresses, parallel, |current, total| {
+        let partial = partials::PartialDownload::open(&partials_dir, &content_hash)?;
+
+        let outcome = dl
+            .download_resumable(&metadata, &seeder_addresses, parallel, &partial, |current, total| {
                 // Only print every 5 chunks or at completion to reduce noise
                 let last = last_printed.load(Ordering::Relaxed);
                 if current >= last + 5 || current == total {
@@ -423,9 +1284,33 @@ async fn download_file(
 
         let elapsed = start_time.elapsed();
 
-        dl.reassemble_to_file(chunks, &metadata, output_path)?;
+        if let downloader::DownloadOutcome::Partial { missing } = outcome {
+            // Keep the saved chunks and state so the rest can be fetched later
+            let part_path = dl.reassemble_available(&partial, &metadata, output_path)?;
+            println!(
+                "Partial download: {} ({}/{} chunks, {:.1}s)",
+                part_path.display(),
+                chunk_count as usize - missing.len(),
+                chunk_count,
+                elapsed.as_secs_f64()
+            );
+            println!("Missing chunks (zero-filled): {:?}", missing);
+            println!("The whole-file hash was not checked; run download again to finish it");
+            transport.disconnect().await?;
+            return Ok(());
+        }
+
+        let assembled = dl.reassemble_partial(&partial, &metadata, output_path);
+        // Saved chunks are useless once assembled, and suspect if the file failed to verify
+        if let Err(e) = state_store.remove(&content_hash) {
+            tracing::warn!("Failed to clean up download state: {}", e);
+        }
+        if let Err(e) = partial.remove() {
+            tracing::warn!("Failed to clean up partial download: {}", e);
+        }
+        assembled?;
 
-        let size_bytes = size.unwrap_or(0);
+        let size_bytes = metadata.size;
         if size_bytes > 0 {
             let speed_kbps = (size_bytes as f64 / 1024.0) / elapsed.as_secs_f64();
             println!(
@@ -442,6 +1327,12 @@ async fn download_file(
             );
         }
 
+        let stats = dl.seeder_stats();
+        if !stats.is_empty() {
+            println!();
+            print!("{}", seeder_stats::format_summary(&stats));
+        }
+
         transport.disconnect().await?;
 
         Ok(())
@@ -450,28 +1341,77 @@ async fn download_file(
     #[cfg(not(feature = "nym"))]
     {
         // Suppress unused variable warnings in non-nym build
-        let _ = (&seeders, &chunk_count, &filename, &size, &parallel, &data_dir);
+        let _ = (&seeders, &chunk_count, &filename, &size, &chunk_size, &parallel);
+        let _ = (&max_seeders_per_chunk, &diverse_gateways, &external_hashes);
+        let _ = (&completion_threshold, &key, &transfer_config, &identity, &data_dir);
         anyhow::bail!("Nym transport not available. Compile with --features nym or use --mock");
     }
 }
 
+/// Key from a `--key` value: 64 hex characters are the key itself, anything
+/// else is a passphrase to derive it from
+fn parse_chunk_key(value: &str) -> ChunkKey {
+    let mut key = [0u8; 32];
+    if value.len() == 64 && hex::decode_to_slice(value, &mut key).is_ok() {
+        key
+    } else {
+        brisby_core::chunk::derive_chunk_key(value)
+    }
+}
+
+/// Check a `--seeder` value is a well-formed address or relay route
+#[cfg(feature = "nym")]
+fn parse_seeder(seeder: &str) -> Result<brisby_core::NymAddress> {
+    match brisby_core::SeederRoute::parse(seeder) {
+        brisby_core::SeederRoute::Direct(address) => address.validate()?,
+        brisby_core::SeederRoute::Relay { index_provider, .. } => index_provider.validate()?,
+    }
+    Ok(brisby_core::NymAddress::new(seeder))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn start_seeding(
     files: &[String],
+    link: bool,
     publish: bool,
     index_provider: Option<&str>,
+    anonymous: bool,
+    seeder_config: &config::SeederConfig,
+    metadata_format: metadata_file::MetadataFormat,
+    transport_config: brisby_core::TransportConfig,
     use_mock: bool,
     data_dir: &str,
 ) -> Result<()> {
     use std::path::Path;
 
-    let data_path = expand_path(data_dir);
+    let data_path = config::expand_path(data_dir)?;
     std::fs::create_dir_all(&data_path)?;
     let chunks_dir = data_path.join("chunks");
 
-    // Create chunk store and load existing files
-    let mut store = seeder::ChunkStore::new(chunks_dir);
+    // Create chunk store and load existing files. With a chunk cache, only
+    // metadata is loaded and chunks are read from disk as requested, unless
+    // every file fits in the cache. With a hot set, the files that were
+    // popular last run are pulled into memory.
+    let hot_set_path = data_path.join("hot_set.json");
+    let cache_size = seeder_config.chunk_cache_size;
+    let store = if seeder_config.hot_set_size > 0 || cache_size > 0 {
+        seeder::ChunkStore::new_lazy(chunks_dir).with_chunk_cache(cache_size)
+    } else {
+        seeder::ChunkStore::new(chunks_dir)
+    };
+    let mut store = store.with_metadata_format(metadata_format);
     let loaded = store.load_all()?;
     tracing::info!("Loaded {} existing files from storage", loaded);
+    if cache_size > 0 && store.stored_bytes() <= cache_size as u64 {
+        let all: Vec<_> = store.list_files().iter().map(|m| m.content_hash).collect();
+        store.prewarm(&all)?;
+        tracing::info!("All files fit in the chunk cache, serving from memory");
+    } else if seeder_config.hot_set_size > 0 {
+        let mut hot_set = seeder::load_hot_set(&hot_set_path)?;
+        hot_set.truncate(seeder_config.hot_set_size);
+        let warmed = store.prewarm(&hot_set)?;
+        tracing::info!("Prewarmed {} popular files", warmed);
+    }
 
     // Add any new files
     for file_path in files {
@@ -480,8 +1420,11 @@ async fn start_seeding(
             tracing::warn!("File not found: {}", file_path);
             continue;
         }
-        match store.add_file(path) {
+        let added = if link { store.link_file(path) } else { store.add_file(path) };
+        match added {
             Ok(metadata) => {
+                let index_path = data_path.join(local_index::LOCAL_INDEX_FILE);
+                local_index::LocalIndex::open(&index_path)?.add(&metadata)?;
                 println!("Added: {} ({})", metadata.filename, brisby_core::hash_to_hex(&metadata.content_hash));
             }
             Err(e) => {
@@ -498,10 +1441,13 @@ async fn start_seeding(
 
     println!("Seeding {} file(s)", file_count);
     for metadata in store.list_files() {
-        println!("  - {} ({} bytes, {} chunks)",
+        let withheld =
+            !seeder_config.serve_incomplete && !store.is_complete(&metadata.content_hash);
+        println!("  - {} ({} bytes, {} chunks){}",
             metadata.filename,
             metadata.size,
-            metadata.chunks.len()
+            metadata.chunks.len(),
+            if withheld { " [incomplete, not served]" } else { "" }
         );
     }
 
@@ -515,11 +1461,15 @@ async fn start_seeding(
     {
         use brisby_core::NymTransport;
 
-        let nym_path = data_path.join("nym");
-        std::fs::create_dir_all(&nym_path)?;
+        let index_nym = index_provider.map(brisby_core::NymAddress::try_new).transpose()?;
+
+        if let Some(ref identity_dir) = transport_config.storage_path {
+            std::fs::create_dir_all(identity_dir)?;
+            tracing::info!("Using Nym identity at {}", identity_dir.display());
+        }
 
         tracing::info!("Connecting to Nym network...");
-        let mut transport = NymTransport::with_storage(nym_path);
+        let mut transport = NymTransport::new(transport_config);
         transport.connect().await?;
 
         let our_address = transport.our_address()
@@ -527,31 +1477,150 @@ async fn start_seeding(
 
         println!("Connected to Nym network");
         println!("Address: {}", our_address);
+        if !transport.is_address_stable() {
+            println!("Warning: this address is ephemeral and changes when the seeder restarts");
+            if publish {
+                tracing::warn!(
+                    "Publishing an ephemeral address; listings will point nowhere after a restart"
+                );
+            }
+        }
         println!();
         println!("Seeder is running. Press Ctrl+C to stop.");
 
-        // Publish to index provider if requested
+        // Publish to index provider if requested, remembering what to
+        // unpublish on shutdown
+        let mut published = None;
         if publish {
-            if let Some(index_addr) = index_provider {
-                let index_nym = brisby_core::NymAddress::new(index_addr);
+            if let Some(index_nym) = index_nym {
                 let our_nym = our_address.clone();
 
-                for metadata in store.list_files() {
-                    tracing::info!("Publishing {} to index provider", metadata.filename);
-                    if let Err(e) = network::publish_to_index_provider(&transport, &index_nym, metadata, &our_nym).await {
-                        tracing::error!("Failed to publish {}: {}", metadata.filename, e);
-                    } else {
-                        println!("Published: {}", metadata.filename);
-                    }
+                let files: Vec<_> = store
+                    .list_files()
+                    .into_iter()
+                    .filter(|m| {
+                        seeder_config.serve_incomplete || store.is_complete(&m.content_hash)
+                    })
+                    .collect();
+                let state_path = data_path.join("publish_state.json");
+                let report = publish::publish_files(
+                    &transport,
+                    &index_nym,
+                    &files,
+                    &|hash, index| store.read_chunk(hash, index),
+                    &our_nym,
+                    anonymous,
+                    &state_path,
+                    &publish::RetryPolicy::default(),
+                )
+                .await?;
+
+                let filename = |hash: &brisby_core::ContentHash| {
+                    files
+                        .iter()
+                        .find(|m| &m.content_hash == hash)
+                        .map_or_else(|| brisby_core::hash_to_hex(hash), |m| m.filename.clone())
+                };
+                for hash in &report.succeeded {
+                    println!("Published: {}", filename(hash));
+                }
+                for (hash, error) in &report.failed {
+                    println!("Failed to publish: {}: {}", filename(hash), error);
+                }
+                if !report.is_complete() {
+                    println!(
+                        "{} file(s) failed to publish; they will be retried on the next --publish run",
+                        report.failed.len()
+                    );
                 }
+
+                // Only files published in this run can be unpublished; the
+                // tokens for an earlier, interrupted run's are gone
+                published = Some((index_nym, our_nym, report.unpublish_tokens));
             } else {
                 tracing::warn!("--publish specified but no --index-provider given");
             }
         }
 
         // Create seeder and run message loop
-        let seeder_service = seeder::Seeder::new(store);
-        seeder::run_seeder_loop(&transport, &seeder_service).await?;
+        let seeder_service = seeder::Seeder::new(store)
+            .with_limits(seeder_config.limits())
+            .with_response_cache(seeder_config.response_cache_size)
+            .with_dry_run(seeder_config.dry_run)
+            .with_serve_incomplete(seeder_config.serve_incomplete)
+            .with_compression(seeder_config.compress_chunks)
+            .with_rate_limit(seeder_config.upload_rate_limit);
+        if seeder_config.dry_run {
+            println!("Dry run: chunk requests are counted but not served");
+        }
+        let seeder_service = std::sync::Arc::new(seeder_service);
+        let verify = async {
+            if seeder_config.verify_interval_secs == 0 {
+                return std::future::pending().await;
+            }
+            seeder::run_verify_schedule(
+                seeder_service.clone(),
+                std::time::Duration::from_secs(seeder_config.verify_interval_secs),
+                seeder_config.verify_batch_size,
+            )
+            .await
+        };
+        let report_stats = async {
+            if seeder_config.stats_interval_secs == 0 {
+                return std::future::pending().await;
+            }
+            let period = std::time::Duration::from_secs(seeder_config.stats_interval_secs);
+            let start = tokio::time::Instant::now() + period;
+            let mut interval = tokio::time::interval_at(start, period);
+            loop {
+                interval.tick().await;
+                println!("Served: {}", seeder_service.metrics().summary());
+            }
+        };
+        tokio::select! {
+            result = seeder::run_seeder_loop(&transport, seeder_service.clone()) => result?,
+            _ = verify => {}
+            _ = report_stats => {}
+            _ = tokio::signal::ctrl_c() => println!("Shutting down"),
+        }
+        println!("Served: {}", seeder_service.metrics().summary());
+
+        let cache_stats = seeder_service.store().read().await.cache_stats();
+        if cache_stats.hits + cache_stats.misses > 0 {
+            println!(
+                "Chunk cache: {} hits, {} misses, {} evictions, {} bytes held",
+                cache_stats.hits, cache_stats.misses, cache_stats.evictions, cache_stats.bytes
+            );
+        }
+
+        let verify_stats = seeder_service.verify_stats();
+        if verify_stats.chunks_verified > 0 {
+            println!(
+                "Verified {} chunks: {} repaired, {} corrupt",
+                verify_stats.chunks_verified,
+                verify_stats.chunks_repaired,
+                verify_stats.corrupt_chunks.len()
+            );
+        }
+
+        if seeder_config.dry_run {
+            print_demand(&seeder_service.demand());
+        }
+
+        // Remember what was popular so the next run can prewarm it
+        let hot_set = seeder_service.hottest(seeder_config.hot_set_size);
+        if !hot_set.is_empty() {
+            seeder::save_hot_set(&hot_set_path, &hot_set)?;
+        }
+
+        // Stop being listed for files we no longer serve
+        if let Some((index_nym, our_nym, listed)) = &published {
+            let report = publish::unpublish_files(&transport, index_nym, listed, our_nym).await;
+            println!("Unpublished {} file(s)", report.succeeded.len());
+            for (hash, error) in &report.failed {
+                println!("Failed to unpublish: {}: {}", brisby_core::hash_to_hex(hash), error);
+            }
+        }
 
         transport.disconnect().await?;
         Ok(())
@@ -559,42 +1628,316 @@ async fn start_seeding(
 
     #[cfg(not(feature = "nym"))]
     {
-        let _ = (&index_provider, &publish, &data_dir);
+        let _ = (&index_provider, &publish, &anonymous, &transport_config, &data_dir);
         anyhow::bail!("Nym transport not available. Compile with --features nym or use --mock");
     }
 }
 
+#[cfg(feature = "nym")]
+fn print_demand(demand: &[seeder::FileDemand]) {
+    if demand.is_empty() {
+        println!("No chunk requests received.");
+        return;
+    }
+    println!("{:<64}  {:>8}  {:>10}", "HASH", "REQUESTS", "REQUESTERS");
+    for file in demand {
+        println!(
+            "{:<64}  {:>8}  {:>10}",
+            brisby_core::hash_to_hex(&file.content_hash),
+            file.requests,
+            file.distinct_requesters
+        );
+    }
+}
+
 async fn list_files(data_dir: &str) -> Result<()> {
-    let data_path = expand_path(data_dir);
-    let chunks_dir = data_path.join("chunks");
+    let data_path = config::expand_path(data_dir)?;
+    let index_path = data_path.join(local_index::LOCAL_INDEX_FILE);
+
+    // Don't create an empty index just to list it
+    let files = if index_path.exists() {
+        local_index::LocalIndex::open(&index_path)?.list()?
+    } else {
+        Vec::new()
+    };
 
-    if !chunks_dir.exists() {
+    if files.is_empty() {
         println!("No shared files found.");
         println!("Use 'brisby share <file>' to add files.");
         return Ok(());
     }
 
-    let mut store = seeder::ChunkStore::new(chunks_dir);
-    let loaded = store.load_all()?;
+    println!("Shared files ({}):\n", files.len());
+    print!("{}", local_index::format_list(&files));
 
-    if loaded == 0 {
-        println!("No shared files found.");
-        println!("Use 'brisby share <file>' to add files.");
+    Ok(())
+}
+
+fn export_collection(
+    hashes: &[String],
+    output: &str,
+    name: &str,
+    seeders: &[String],
+    uris: bool,
+    data_dir: &str,
+) -> Result<()> {
+    let data_path = config::expand_path(data_dir)?;
+    let mut store = seeder::ChunkStore::new(data_path.join("chunks"));
+    let mut collection = collection::Collection::new(name);
+
+    if hashes.is_empty() {
+        store.load_all()?;
+        let mut files = store.list_files();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        for metadata in files {
+            collection.add(metadata.clone(), seeders);
+        }
+    }
+    for hash in hashes {
+        let content_hash = brisby_core::hex_to_hash(hash)
+            .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+        let metadata = store
+            .load_file(&content_hash)?
+            .then(|| store.get_metadata(&content_hash))
+            .flatten()
+            .ok_or_else(|| anyhow::anyhow!("No shared file with hash {}", hash))?;
+        collection.add(metadata.clone(), seeders);
+    }
+    if collection.files.is_empty() {
+        anyhow::bail!("No shared files to export. Use 'brisby share <file>' to add files.");
+    }
+
+    let contents = if uris {
+        collection.to_uris()
+    } else {
+        collection.to_json()?
+    };
+    std::fs::write(output, contents)?;
+    println!(
+        "Exported {} files ({} bytes) to {}",
+        collection.files.len(),
+        collection.total_size(),
+        output
+    );
+    if !uris && seeders.is_empty() {
+        println!("No seeders listed; importers will need to pass --seeder.");
+    }
+    Ok(())
+}
+
+fn export_manifest(hash: &str, output: Option<&str>, data_dir: &str) -> Result<()> {
+    let content_hash = brisby_core::hex_to_hash(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+
+    // Files imported but not fetched yet are only in the index
+    let data_path = config::expand_path(data_dir)?;
+    let mut store = seeder::ChunkStore::new_lazy(data_path.join("chunks"));
+    let index_path = data_path.join(local_index::LOCAL_INDEX_FILE);
+    let metadata = if store.load_file(&content_hash)? {
+        store.get_metadata(&content_hash).cloned()
+    } else if index_path.exists() {
+        local_index::LocalIndex::open(&index_path)?.get(&content_hash)?
+    } else {
+        None
+    };
+    let metadata = metadata.ok_or_else(|| anyhow::anyhow!("No shared file with hash {}", hash))?;
+
+    let json = manifest::Manifest::new(metadata).to_json()?;
+    match output {
+        Some(path) => {
+            std::fs::write(path, json + "\n")?;
+            eprintln!("Wrote manifest of {} to {}", hash, path);
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+fn import_manifest(path: &str, data_dir: &str) -> Result<()> {
+    let manifest = manifest::Manifest::load(std::path::Path::new(path))?;
+    let metadata = &manifest.metadata;
+    let hash = brisby_core::hash_to_hex(&metadata.content_hash);
+
+    let data_path = config::expand_path(data_dir)?;
+    std::fs::create_dir_all(&data_path)?;
+    let index = local_index::LocalIndex::open(&data_path.join(local_index::LOCAL_INDEX_FILE))?;
+    if index.contains(&metadata.content_hash)? {
+        println!("{} ({}) is already in the local index", metadata.filename, hash);
         return Ok(());
     }
+    index.add(metadata)?;
 
-    println!("Shared files ({}):\n", loaded);
+    println!("Imported: {}", metadata.filename);
+    println!("Hash: {}", hash);
+    println!("Size: {} bytes ({} chunks)", metadata.size, metadata.chunks.len());
+    Ok(())
+}
 
-    for metadata in store.list_files() {
-        println!("  {}", metadata.filename);
-        println!("    Hash:   {}", brisby_core::hash_to_hex(&metadata.content_hash));
-        println!("    Size:   {} bytes ({} chunks)", metadata.size, metadata.chunks.len());
-        if !metadata.keywords.is_empty() {
-            println!("    Keywords: {}", metadata.keywords.join(", "));
+/// Download each file of a collection in turn, carrying on past failures
+#[allow(clippy::too_many_arguments)]
+async fn import_collection(
+    collection: &collection::Collection,
+    output_dir: &str,
+    extra_seeders: &[String],
+    parallel: usize,
+    on_exists: downloader::OnExists,
+    transfer_config: &config::TransferConfig,
+    identity: Option<brisby_core::TransportConfig>,
+    use_mock: bool,
+    data_dir: &str,
+) -> Result<()> {
+    let output_dir = config::expand_path(output_dir)?;
+    std::fs::create_dir_all(&output_dir)?;
+
+    let mut failed = 0;
+    for entry in &collection.files {
+        let metadata = &entry.metadata;
+        let hash = brisby_core::hash_to_hex(&metadata.content_hash);
+        let mut seeders = entry.seeders.clone();
+        seeders.extend(extra_seeders.iter().cloned());
+        // The manifest came from someone else: never write outside output_dir
+        let Some(name) = std::path::Path::new(&metadata.filename).file_name() else {
+            eprintln!("Skipping {}: unusable filename {:?}", hash, metadata.filename);
+            failed += 1;
+            continue;
+        };
+        let output = output_dir.join(name);
+
+        // The collection lists each file's chunks, as its manifest would
+        let file_manifest = manifest::Manifest::new(metadata.clone());
+
+        println!("\nDownloading {}", metadata.filename);
+        let result = download_file(
+            &hash,
+            Some(&file_manifest),
+            output.to_str(),
+            on_exists,
+            &seeders,
+            metadata.chunks.len() as u32,
+            Some(&metadata.filename),
+            Some(metadata.size),
+            metadata.chunk_size,
+            metadata.content_defined.is_some(),
+            parallel,
+            0,
+            false,
+            None,
+            None,
+            None,
+            transfer_config,
+            identity.clone(),
+            use_mock,
+            data_dir,
+        )
+        .await;
+        if let Err(e) = result {
+            eprintln!("Failed to download {}: {}", metadata.filename, e);
+            failed += 1;
         }
-        println!();
     }
 
+    if failed > 0 {
+        anyhow::bail!("{} of {} files failed to download", failed, collection.files.len());
+    }
+    println!("\nDownloaded all {} files of {}", collection.files.len(), collection.name);
+    Ok(())
+}
+
+async fn inspect_file(hash: &str, data_dir: &str) -> Result<()> {
+    let content_hash = brisby_core::hex_to_hash(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+
+    let data_path = config::expand_path(data_dir)?;
+    let mut store = seeder::ChunkStore::new(data_path.join("chunks"));
+    if !store.load_file(&content_hash)? {
+        anyhow::bail!("No shared file with hash {}", hash);
+    }
+
+    let metadata = store
+        .get_metadata(&content_hash)
+        .ok_or_else(|| anyhow::anyhow!("No shared file with hash {}", hash))?;
+    let reports = inspect::inspect_chunks(&store, &content_hash).unwrap_or_default();
+    print!("{}", inspect::format_inspection(metadata, &reports));
+
+    Ok(())
+}
+
+/// Chunking given by `--chunk-size`, `--min-chunk-size` and `--avg-chunk-size`
+fn chunking_from_args(
+    chunk_size: u32,
+    min_chunk_size: Option<u32>,
+    avg_chunk_size: Option<u32>,
+) -> Result<ChunkingStrategy> {
+    let content_defined = min_chunk_size
+        .zip(avg_chunk_size)
+        .map(|(min_size, avg_size)| brisby_core::ContentDefinedSizes { min_size, avg_size });
+    let chunking = ChunkingStrategy::from_sizes(chunk_size, content_defined);
+    chunking.validate().map_err(|_| {
+        anyhow::anyhow!("Chunk sizes must be nonzero, and smallest to average to largest")
+    })?;
+    Ok(chunking)
+}
+
+fn verify_file(path: &str, hash: &str, chunking: ChunkingStrategy) -> Result<()> {
+    let expected = brisby_core::hex_to_hash(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+
+    // Without metadata to name the algorithm, assume the one files are shared with
+    let path = std::path::Path::new(path);
+    let hash_algo = brisby_core::HashAlgorithm::default();
+    if !brisby_core::chunk::verify_file(path, hash_algo, chunking, &expected)? {
+        anyhow::bail!("{} does not match {}", path.display(), hash);
+    }
+
+    println!("OK: {} matches {}", path.display(), hash);
+    Ok(())
+}
+
+fn verify_download(
+    hash: &str,
+    path: &str,
+    chunking: ChunkingStrategy,
+    data_dir: &str,
+) -> Result<()> {
+    let content_hash = brisby_core::hex_to_hash(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid content hash: {}", e))?;
+
+    // Without metadata, all there is to go on is the whole-file hash
+    let mut store = seeder::ChunkStore::new_lazy(config::expand_path(data_dir)?.join("chunks"));
+    if !store.load_file(&content_hash).unwrap_or(false) {
+        return verify_file(path, hash, chunking);
+    }
+    let metadata = store
+        .get_metadata(&content_hash)
+        .ok_or_else(|| anyhow::anyhow!("No metadata for {}", hash))?;
+    if metadata.encryption.is_some() {
+        anyhow::bail!("{} is a private share; its hashes don't cover the decrypted file", hash);
+    }
+
+    let path = std::path::Path::new(path);
+    let size = std::fs::metadata(path)?.len();
+    let reports = inspect::inspect_file(path, metadata)?;
+    let bad: Vec<_> = reports
+        .iter()
+        .filter(|report| report.status != inspect::ChunkStatus::Present)
+        .collect();
+    for report in &bad {
+        println!("Chunk {} is {}", report.index, report.status);
+    }
+    if size != metadata.size {
+        println!("File is {} bytes, expected {}", size, metadata.size);
+    }
+    if !bad.is_empty() || size != metadata.size {
+        anyhow::bail!(
+            "{} does not match {} ({} of {} chunks damaged)",
+            path.display(),
+            hash,
+            bad.len(),
+            reports.len()
+        );
+    }
+
+    println!("OK: {} matches {} ({} chunks)", path.display(), hash, reports.len());
     Ok(())
 }
 
@@ -609,6 +1952,108 @@ async fn show_status() -> Result<()> {
     Ok(())
 }
 
+async fn run_doctor(
+    config_path: &str,
+    settings: &config::Config,
+    index_provider: Option<&str>,
+    use_mock: bool,
+) -> Result<()> {
+    use doctor::CheckStatus;
+
+    // Paths that can't be expanded are reported as failures rather than aborting
+    let unexpandable = |name, e: anyhow::Error| doctor::Check::new(name, CheckStatus::Fail, e.to_string());
+
+    let (config_check, file_config) = match config::expand_path(config_path) {
+        Ok(path) => doctor::check_config(&path),
+        Err(e) => (unexpandable("config", e), None),
+    };
+    let mut checks = vec![
+        config_check,
+        settings
+            .data_dir()
+            .map_or_else(|e| unexpandable("data dir", e), |dir| doctor::check_data_dir(&dir)),
+        doctor::check_nym_feature(cfg!(feature = "nym")),
+        settings
+            .identity_dir()
+            .map_or_else(|e| unexpandable("identity", e), |dir| doctor::check_identity(&dir)),
+    ];
+
+    // Fall back to the first index provider in the config file
+    let index_provider = index_provider.map(str::to_string).or_else(|| {
+        file_config?
+            .index_providers
+            .into_iter()
+            .map(|p| p.nym_address)
+            .find(|addr| !addr.is_empty())
+    });
+
+    match index_provider {
+        Some(addr) if !use_mock => {
+            #[cfg(feature = "nym")]
+            {
+                use brisby_core::NymTransport;
+
+                println!("Pinging index provider {} ...", addr);
+                // Ephemeral identity so the check never touches the seeder's
+                let mut transport = NymTransport::new(brisby_core::TransportConfig::default());
+                let check = match brisby_core::NymAddress::try_new(addr) {
+                    Err(e) => doctor::Check {
+                        name: "index provider",
+                        status: CheckStatus::Fail,
+                        detail: e.to_string(),
+                    },
+                    Ok(index_nym) => match transport.connect().await {
+                        Ok(()) => {
+                            let timeout = std::time::Duration::from_secs(30);
+                            let check =
+                                doctor::check_index_provider(&transport, &index_nym, timeout).await;
+                            let _ = transport.disconnect().await;
+                            check
+                        }
+                        Err(e) => doctor::Check {
+                            name: "index provider",
+                            status: CheckStatus::Fail,
+                            detail: format!("could not connect to Nym network: {}", e),
+                        },
+                    },
+                };
+                checks.push(check);
+            }
+
+            #[cfg(not(feature = "nym"))]
+            {
+                let _ = addr;
+                checks.push(doctor::Check {
+                    name: "index provider",
+                    status: CheckStatus::Warn,
+                    detail: "skipped, built without Nym support".to_string(),
+                });
+            }
+        }
+        Some(_) => checks.push(doctor::Check {
+            name: "index provider",
+            status: CheckStatus::Warn,
+            detail: "skipped in mock mode".to_string(),
+        }),
+        None => checks.push(doctor::Check {
+            name: "index provider",
+            status: CheckStatus::Warn,
+            detail: "none configured (use --index-provider)".to_string(),
+        }),
+    }
+
+    for check in &checks {
+        println!("[{}] {:<15} {}", check.status, check.name, check.detail);
+    }
+
+    let failed = checks.iter().filter(|c| c.status == CheckStatus::Fail).count();
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
+    }
+
+    Ok(())
+}
+
 async fn init_config() -> Result<()> {
     use config::Config;
 
@@ -634,7 +2079,7 @@ async fn init_config() -> Result<()> {
     // Create other directories
     std::fs::create_dir_all(config_dir.join("chunks"))?;
     std::fs::create_dir_all(config_dir.join("downloads"))?;
-    std::fs::create_dir_all(config_dir.join("nym"))?;
+    std::fs::create_dir_all(config::default_identity_dir()?)?;
 
     println!("Initialized Brisby at: {}", config_dir.display());
 
@@ -0,0 +1,137 @@
+//! Portable manifests of single shared files
+//!
+//! `brisby export` writes a file's `FileMetadata` (content hash, chunk
+//! hashes and sizes, filename and keywords) as a versioned JSON manifest, and
+//! `brisby import` checks one and registers it in the local index. Moving a
+//! seeder to a new machine then needs only the manifests, not the chunk
+//! store: the files can be fetched again from other seeders, or re-shared
+//! from copies already on the new machine.
+//!
+//! Manifests aren't signed. Files with Merkle content hashes are
+//! self-certifying instead: the chunk hashes must hash up to the content
+//! hash, so a manifest can't be altered without changing the hash it is for.
+
+use anyhow::{anyhow, Result};
+use brisby_core::{hash_to_hex, merkle, FileMetadata};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Version of the manifest format written by `to_json`
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Customary extension of manifest files
+pub const MANIFEST_EXTENSION: &str = "brisbymanifest";
+
+/// One file's metadata, see the module docs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub metadata: FileMetadata,
+}
+
+impl Manifest {
+    /// Wrap `metadata` in a manifest of the current version
+    pub fn new(metadata: FileMetadata) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            metadata,
+        }
+    }
+
+    /// Serialize as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parse and check a manifest written by `to_json`
+    pub fn from_json(json: &str) -> Result<Self> {
+        let manifest: Manifest = serde_json::from_str(json)?;
+        if manifest.version == 0 || manifest.version > MANIFEST_VERSION {
+            return Err(anyhow!(
+                "unsupported manifest format version {} (this client reads 1 to {})",
+                manifest.version,
+                MANIFEST_VERSION
+            ));
+        }
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Read a manifest file
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Self::from_json(&json).map_err(|e| anyhow!("Invalid manifest {}: {}", path.display(), e))
+    }
+
+    /// Check that the chunk list describes the file it claims to
+    fn validate(&self) -> Result<()> {
+        let metadata = &self.metadata;
+        for (position, chunk) in metadata.chunks.iter().enumerate() {
+            if chunk.index as usize != position {
+                return Err(anyhow!("chunk {} is listed as chunk {}", position, chunk.index));
+            }
+            if metadata.chunk_size > 0 && chunk.size > metadata.chunk_size {
+                return Err(anyhow!(
+                    "chunk {} is {} bytes, more than the chunk size of {}",
+                    position,
+                    chunk.size,
+                    metadata.chunk_size
+                ));
+            }
+        }
+
+        let chunk_bytes: u64 = metadata.chunks.iter().map(|chunk| chunk.size as u64).sum();
+        if chunk_bytes != metadata.size {
+            return Err(anyhow!(
+                "chunks add up to {} bytes, but the file is {}",
+                chunk_bytes,
+                metadata.size
+            ));
+        }
+
+        if metadata.hash_algo.is_merkle() {
+            let leaves: Vec<_> = metadata.chunks.iter().map(|chunk| chunk.hash).collect();
+            if merkle::root(&leaves, metadata.size) != metadata.content_hash {
+                return Err(anyhow!(
+                    "chunk hashes don't match content hash {}",
+                    hash_to_hex(&metadata.content_hash)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeder::ChunkStore;
+
+    #[test]
+    fn test_manifest_round_trip_and_validation() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("song.flac");
+        std::fs::write(&path, vec![7u8; brisby_core::CHUNK_SIZE * 2 + 100]).unwrap();
+        let metadata = ChunkStore::new(temp_dir.path().join("chunks")).add_file(&path).unwrap();
+        assert!(metadata.hash_algo.is_merkle());
+
+        let manifest = Manifest::new(metadata);
+        let manifest_path = temp_dir.path().join("song.brisbymanifest");
+        std::fs::write(&manifest_path, manifest.to_json().unwrap()).unwrap();
+        assert_eq!(Manifest::load(&manifest_path).unwrap(), manifest);
+
+        let rejects = |edit: fn(&mut Manifest), reason: &str| {
+            let mut edited = manifest.clone();
+            edit(&mut edited);
+            let err = Manifest::from_json(&edited.to_json().unwrap()).unwrap_err();
+            assert!(err.to_string().contains(reason), "{}", err);
+        };
+        rejects(|m| m.version = MANIFEST_VERSION + 1, "unsupported manifest format");
+        rejects(|m| m.metadata.chunks[1].hash[0] ^= 1, "don't match content hash");
+        rejects(|m| m.metadata.size += 1, "add up to");
+        rejects(|m| m.metadata.chunks.swap(0, 1), "listed as chunk");
+        rejects(|m| m.metadata.chunks[2].size = u32::MAX, "more than the chunk size");
+
+        assert!(Manifest::from_json("{\"version\": 1}").is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//! On-disk formats of a stored file's metadata
+//!
+//! `ChunkStore` keeps each file's `FileMetadata` next to its chunks, by
+//! default as pretty-printed JSON in `metadata.json`. JSON writes every chunk
+//! hash out as an array of 32 numbers, so for files with thousands of chunks
+//! it is large and slow to parse when the store loads. The binary format
+//! keeps the same metadata in `metadata.bin` as a protobuf message, with the
+//! chunk hashes packed back to back.
+//!
+//! Readers tell the formats apart by the binary format's magic prefix, so a
+//! manifest in either one can be given to anything that reads metadata, e.g.
+//! `download --hash-list`. JSON stays the default, being readable and
+//! editable by hand.
+
+use anyhow::{anyhow, Result};
+use brisby_core::{
+    ChunkEncryption, ChunkInfo, ContentDefinedSizes, ContentHash, FileMetadata, HashAlgorithm,
+};
+use prost::Message;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Metadata file name in the JSON format
+pub const JSON_FILE: &str = "metadata.json";
+
+/// Metadata file name in the binary format
+pub const BINARY_FILE: &str = "metadata.bin";
+
+/// Start of every binary metadata file, never the start of a JSON one
+const MAGIC: &[u8] = b"BRISBYM1";
+
+/// How stored metadata is serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataFormat {
+    /// Pretty-printed JSON
+    #[default]
+    Json,
+    /// Compact protobuf encoding
+    Binary,
+}
+
+impl MetadataFormat {
+    /// Name of the metadata file in this format
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            MetadataFormat::Json => JSON_FILE,
+            MetadataFormat::Binary => BINARY_FILE,
+        }
+    }
+}
+
+impl fmt::Display for MetadataFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataFormat::Json => write!(f, "json"),
+            MetadataFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl FromStr for MetadataFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(MetadataFormat::Json),
+            "binary" => Ok(MetadataFormat::Binary),
+            other => Err(anyhow!(
+                "unknown metadata format '{}' (expected json or binary)",
+                other
+            )),
+        }
+    }
+}
+
+/// `FileMetadata` as stored in the binary format
+///
+/// Chunk indexes aren't stored: chunk `i` is the `i`th hash and size.
+#[derive(Clone, PartialEq, Message)]
+struct StoredMetadata {
+    #[prost(bytes, tag = "1")]
+    content_hash: Vec<u8>,
+    #[prost(string, tag = "2")]
+    hash_algo: String,
+    #[prost(string, tag = "3")]
+    filename: String,
+    #[prost(uint64, tag = "4")]
+    size: u64,
+    #[prost(uint32, tag = "5")]
+    chunk_size: u32,
+    #[prost(string, optional, tag = "6")]
+    mime_type: Option<String>,
+    /// Every chunk's hash, 32 bytes each
+    #[prost(bytes, tag = "7")]
+    chunk_hashes: Vec<u8>,
+    #[prost(uint32, repeated, tag = "8")]
+    chunk_sizes: Vec<u32>,
+    #[prost(string, repeated, tag = "9")]
+    keywords: Vec<String>,
+    #[prost(uint64, tag = "10")]
+    created_at: u64,
+    /// Set, along with `key_check`, for encrypted files only
+    #[prost(bytes, optional, tag = "11")]
+    encryption_nonce: Option<Vec<u8>>,
+    #[prost(bytes, optional, tag = "12")]
+    key_check: Option<Vec<u8>>,
+    /// Set, along with `avg_chunk_size`, for content-defined chunks only
+    #[prost(uint32, optional, tag = "13")]
+    min_chunk_size: Option<u32>,
+    #[prost(uint32, optional, tag = "14")]
+    avg_chunk_size: Option<u32>,
+}
+
+/// Serialize metadata in `format`
+pub fn encode(metadata: &FileMetadata, format: MetadataFormat) -> Result<Vec<u8>> {
+    if format == MetadataFormat::Json {
+        return Ok(serde_json::to_string_pretty(metadata)?.into_bytes());
+    }
+
+    if let Some((i, chunk)) = (0u32..)
+        .zip(&metadata.chunks)
+        .find(|(i, chunk)| chunk.index != *i)
+    {
+        return Err(anyhow!("Chunk {} is listed at position {}", chunk.index, i));
+    }
+    let stored = StoredMetadata {
+        content_hash: metadata.content_hash.to_vec(),
+        hash_algo: metadata.hash_algo.to_string(),
+        filename: metadata.filename.clone(),
+        size: metadata.size,
+        chunk_size: metadata.chunk_size,
+        mime_type: metadata.mime_type.clone(),
+        chunk_hashes: metadata.chunks.iter().flat_map(|chunk| chunk.hash).collect(),
+        chunk_sizes: metadata.chunks.iter().map(|chunk| chunk.size).collect(),
+        keywords: metadata.keywords.clone(),
+        created_at: metadata.created_at,
+        encryption_nonce: metadata.encryption.map(|e| e.nonce.to_vec()),
+        key_check: metadata.encryption.map(|e| e.key_check.to_vec()),
+        min_chunk_size: metadata.content_defined.map(|sizes| sizes.min_size),
+        avg_chunk_size: metadata.content_defined.map(|sizes| sizes.avg_size),
+    };
+    let mut bytes = MAGIC.to_vec();
+    stored.encode(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Parse metadata in either format, returning which one it was in
+pub fn decode(bytes: &[u8]) -> Result<(FileMetadata, MetadataFormat)> {
+    let Some(encoded) = bytes.strip_prefix(MAGIC) else {
+        return Ok((serde_json::from_slice(bytes)?, MetadataFormat::Json));
+    };
+
+    let stored = StoredMetadata::decode(encoded)?;
+    let content_hash: ContentHash = stored
+        .content_hash
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Content hash is {} bytes", stored.content_hash.len()))?;
+    if stored.chunk_hashes.len() != stored.chunk_sizes.len() * 32 {
+        return Err(anyhow!(
+            "{} bytes of chunk hashes for {} chunks",
+            stored.chunk_hashes.len(),
+            stored.chunk_sizes.len()
+        ));
+    }
+    let chunks = (0u32..)
+        .zip(stored.chunk_hashes.chunks_exact(32).zip(&stored.chunk_sizes))
+        .map(|(index, (hash, size))| ChunkInfo {
+            index,
+            hash: hash.try_into().expect("chunks_exact yields 32 bytes"),
+            size: *size,
+        })
+        .collect();
+    let encryption = match (stored.encryption_nonce, stored.key_check) {
+        (None, None) => None,
+        (Some(nonce), Some(key_check)) => Some(ChunkEncryption {
+            nonce: nonce
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Encryption nonce is {} bytes", nonce.len()))?,
+            key_check: key_check
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Key check is {} bytes", key_check.len()))?,
+        }),
+        _ => return Err(anyhow!("Encryption nonce without key check, or the reverse")),
+    };
+    let content_defined = match (stored.min_chunk_size, stored.avg_chunk_size) {
+        (None, None) => None,
+        (Some(min_size), Some(avg_size)) => Some(ContentDefinedSizes { min_size, avg_size }),
+        _ => return Err(anyhow!("Smallest chunk size without average, or the reverse")),
+    };
+
+    let metadata = FileMetadata {
+        content_hash,
+        hash_algo: HashAlgorithm::from_str(&stored.hash_algo)
+            .map_err(|e| anyhow!("{}", e))?,
+        filename: stored.filename,
+        size: stored.size,
+        chunk_size: stored.chunk_size,
+        content_defined,
+        mime_type: stored.mime_type,
+        chunks,
+        keywords: stored.keywords,
+        created_at: stored.created_at,
+        encryption,
+    };
+    Ok((metadata, MetadataFormat::Binary))
+}
+
+/// Read the metadata kept in `dir`, if there is any
+///
+/// Looks for the binary file first, then the JSON one.
+pub fn read(dir: &Path) -> Result<Option<(FileMetadata, MetadataFormat)>> {
+    for name in [BINARY_FILE, JSON_FILE] {
+        let path = dir.join(name);
+        if path.exists() {
+            let bytes = std::fs::read(&path)?;
+            let decoded =
+                decode(&bytes).map_err(|e| anyhow!("Invalid metadata {}: {}", path.display(), e))?;
+            return Ok(Some(decoded));
+        }
+    }
+    Ok(None)
+}
+
+/// Write metadata into `dir` in `format`, then remove any copy in the other
+/// format
+pub fn write(dir: &Path, metadata: &FileMetadata, format: MetadataFormat) -> Result<()> {
+    std::fs::write(dir.join(format.file_name()), encode(metadata, format)?)?;
+    let other = match format {
+        MetadataFormat::Json => BINARY_FILE,
+        MetadataFormat::Binary => JSON_FILE,
+    };
+    remove_if_present(&dir.join(other))
+}
+
+/// Remove the metadata kept in `dir`, in whichever format
+pub fn remove(dir: &Path) -> Result<()> {
+    remove_if_present(&dir.join(BINARY_FILE))?;
+    remove_if_present(&dir.join(JSON_FILE))
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_metadata(chunk_count: u32) -> FileMetadata {
+        FileMetadata {
+            content_hash: [7u8; 32],
+            hash_algo: HashAlgorithm::Blake3Merkle,
+            filename: "large.mkv".to_string(),
+            size: chunk_count as u64 * 1024 - 100,
+            chunk_size: 1024,
+            content_defined: None,
+            mime_type: Some("video/x-matroska".to_string()),
+            chunks: (0..chunk_count)
+                .map(|index| ChunkInfo {
+                    index,
+                    hash: *blake3::hash(&index.to_le_bytes()).as_bytes(),
+                    size: if index + 1 == chunk_count { 924 } else { 1024 },
+                })
+                .collect(),
+            keywords: vec!["large".to_string(), "mkv".to_string()],
+            created_at: 1_700_000_000,
+            encryption: None,
+        }
+    }
+
+    #[test]
+    fn test_binary_is_smaller_and_round_trips() {
+        let metadata = large_metadata(5000);
+        let json = encode(&metadata, MetadataFormat::Json).unwrap();
+        let binary = encode(&metadata, MetadataFormat::Binary).unwrap();
+        assert!(
+            binary.len() * 10 < json.len(),
+            "binary {} bytes, JSON {} bytes",
+            binary.len(),
+            json.len()
+        );
+
+        // Both decode to the same metadata, each detected by its contents
+        let (from_json, format) = decode(&json).unwrap();
+        assert_eq!(format, MetadataFormat::Json);
+        let (from_binary, format) = decode(&binary).unwrap();
+        assert_eq!(format, MetadataFormat::Binary);
+        assert_eq!(from_json, metadata);
+        assert_eq!(from_binary, from_json);
+
+        let unknown_type = FileMetadata {
+            mime_type: None,
+            chunks: vec![],
+            ..metadata
+        };
+        let binary = encode(&unknown_type, MetadataFormat::Binary).unwrap();
+        assert_eq!(decode(&binary).unwrap().0, unknown_type);
+
+        let encrypted = FileMetadata {
+            encryption: Some(ChunkEncryption {
+                nonce: [1; 8],
+                key_check: [2; 16],
+            }),
+            ..unknown_type
+        };
+        let binary = encode(&encrypted, MetadataFormat::Binary).unwrap();
+        assert_eq!(decode(&binary).unwrap().0, encrypted);
+        let json = encode(&encrypted, MetadataFormat::Json).unwrap();
+        assert_eq!(decode(&json).unwrap().0, encrypted);
+
+        let content_defined = FileMetadata {
+            content_defined: Some(ContentDefinedSizes {
+                min_size: 256,
+                avg_size: 512,
+            }),
+            ..encrypted
+        };
+        let binary = encode(&content_defined, MetadataFormat::Binary).unwrap();
+        assert_eq!(decode(&binary).unwrap().0, content_defined);
+        let json = encode(&content_defined, MetadataFormat::Json).unwrap();
+        assert_eq!(decode(&json).unwrap().0, content_defined);
+    }
+
+    #[test]
+    fn test_rejects_malformed_binary() {
+        let mut metadata = large_metadata(3);
+        let mut binary = encode(&metadata, MetadataFormat::Binary).unwrap();
+        binary.truncate(binary.len() - 5);
+        assert!(decode(&binary).is_err());
+
+        metadata.chunks.swap(0, 1);
+        assert!(encode(&metadata, MetadataFormat::Binary).is_err());
+    }
+
+    #[test]
+    fn test_write_replaces_other_format() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let metadata = large_metadata(3);
+
+        write(temp_dir.path(), &metadata, MetadataFormat::Json).unwrap();
+        write(temp_dir.path(), &metadata, MetadataFormat::Binary).unwrap();
+        assert!(!temp_dir.path().join(JSON_FILE).exists());
+        let (read_back, format) = read(temp_dir.path()).unwrap().unwrap();
+        assert_eq!((read_back, format), (metadata, MetadataFormat::Binary));
+
+        remove(temp_dir.path()).unwrap();
+        assert!(read(temp_dir.path()).unwrap().is_none());
+    }
+}
@@ -3,8 +3,13 @@
 //! Handles connecting to the Nym mixnet and communicating with index providers.
 
 use anyhow::{anyhow, Result};
-use brisby_core::proto::{self, Envelope, Payload};
-use brisby_core::{NymAddress, Transport};
+use brisby_core::proto::{self, capabilities, error_codes, Envelope, Payload};
+use brisby_core::keywords::QueryMode;
+use brisby_core::{
+    retry_with_backoff, ContentHash, NymAddress, SearchFilter, Seeder, SeederRoute, Transport,
+};
+use crate::search_cache::SearchCache;
+use std::fmt::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::LazyLock;
 use std::time::Duration;
@@ -28,33 +33,149 @@ pub fn next_request_id() -> u64 {
     REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
+/// Longest a request may take to be handed to the transport
+///
+/// A send that hasn't gone through by then fails with `Error::Timeout`
+/// rather than holding up the operation while the client is backed up.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Times a search is sent before giving up on the index provider, when
+/// it keeps timing out
+const SEARCH_ATTEMPTS: u32 = 3;
+
+/// Wait before sending a timed-out search again, doubled for each further
+/// attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Wrap a transport error in what was being done, keeping it as the source
+/// so callers can still tell a timeout from other failures, see `is_timeout`
+fn transport_error(what: &str, error: brisby_core::Error) -> anyhow::Error {
+    let message = format!("{}: {}", what, error);
+    anyhow::Error::new(error).context(message)
+}
+
+/// An `Error::Timeout` after `timeout`, for a wait that got no reply
+fn timeout_error(what: &str, timeout: Duration) -> anyhow::Error {
+    transport_error(what, brisby_core::Error::Timeout(timeout))
+}
+
+/// Whether `error` is, or was caused by, a transport timeout
+///
+/// Timeouts are worth retrying; a reply that didn't decode or a request the
+/// index provider turned down is not.
+pub fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<brisby_core::Error>(),
+            Some(brisby_core::Error::Timeout(_))
+        )
+    })
+}
+
+/// An index provider turned a publish request down
+///
+/// Unlike a lost or garbled reply, asking again won't change the answer, so
+/// publishing doesn't retry these.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct PublishRejected(pub String);
+
+/// One page of search results
+#[derive(Debug, Clone, Default)]
+pub struct SearchPage {
+    pub results: Vec<brisby_core::SearchResult>,
+    /// Matches across all pages, if the index provider counted them
+    pub total: Option<u32>,
+    /// Set when the results are a stale cached response, to be refreshed
+    /// once they have been shown
+    pub refresh: Option<SearchRefresh>,
+}
+
+/// A stale cached search response to fetch again, see `search_with_cache`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRefresh {
+    query: String,
+    max_results: u32,
+}
+
+impl SearchRefresh {
+    /// Ask the index provider again and replace the cached response
+    pub async fn run<T: Transport>(
+        &self,
+        transport: &T,
+        index_provider: &NymAddress,
+        cache: &SearchCache,
+    ) -> Result<()> {
+        let page = search_index_provider(
+            transport,
+            index_provider,
+            &self.query,
+            QueryMode::Keywords,
+            self.max_results,
+            0,
+            0.0,
+            &SearchFilter::default(),
+        )
+        .await?;
+        cache.put(index_provider.as_str(), &self.query, self.max_results, &page.results)?;
+        Ok(())
+    }
+}
+
 /// Search for files on an index provider
+///
+/// `min_relevance` asks the provider to drop results below that fraction of
+/// the best result's relevance; 0 keeps everything. `offset` skips that many
+/// results, for paging; providers without `capabilities::PAGINATION` ignore
+/// it. Likewise, `filter` is ignored by providers without
+/// `capabilities::SEARCH_FILTERS`, and `QueryMode::Advanced` by providers
+/// without `capabilities::ADVANCED_QUERY`.
+///
+/// Anonymous seeders' relay tokens are folded into `seeders` as relay routes
+/// through `index_provider`, see `SeederRoute`. A search that gets no reply
+/// in time is sent again, backing off, up to `SEARCH_ATTEMPTS` times.
+#[allow(clippy::too_many_arguments)]
 pub async fn search_index_provider<T: Transport>(
     transport: &T,
     index_provider: &NymAddress,
     query: &str,
+    mode: QueryMode,
     max_results: u32,
-) -> Result<Vec<brisby_core::SearchResult>> {
+    offset: u32,
+    min_relevance: f32,
+    filter: &SearchFilter,
+) -> Result<SearchPage> {
     let request_id = next_request_id();
 
     // Create search request
-    let envelope = proto::search_request(request_id, query.to_string(), max_results);
+    let request = proto::SearchRequest {
+        query: query.to_string(),
+        max_results,
+        min_relevance,
+        offset,
+        advanced: mode == QueryMode::Advanced,
+        ..Default::default()
+    };
+    let limit = request.result_limit() as usize;
+    let envelope = Envelope::new(request_id, Payload::SearchRequest(request.with_filter(filter)));
 
     tracing::debug!("Sending search request to {}", index_provider.as_str());
 
-    // Send request
-    transport
-        .send(index_provider, envelope.to_bytes())
-        .await
-        .map_err(|e| anyhow!("Failed to send search request: {}", e))?;
-
-    // Wait for response with timeout
+    // Send the request and wait for the response, asking again if it times out
+    let request_bytes = envelope.to_bytes();
     let timeout = Duration::from_secs(30);
-    let response = transport
-        .receive_timeout(timeout)
-        .await
-        .map_err(|e| anyhow!("Failed to receive response: {}", e))?
-        .ok_or_else(|| anyhow!("Timeout waiting for search response"))?;
+    let response = retry_with_backoff(SEARCH_ATTEMPTS, RETRY_BASE_DELAY, || {
+        let data = request_bytes.clone();
+        async move {
+            transport.send_timeout(index_provider, data, SEND_TIMEOUT).await?;
+            transport
+                .receive_timeout(timeout)
+                .await?
+                .ok_or(brisby_core::Error::Timeout(timeout))
+        }
+    })
+    .await
+    .map_err(|e| transport_error("No search response", e))?;
 
     // Decode response
     let envelope = Envelope::from_bytes(&response.data)
@@ -71,7 +192,17 @@ pub async fn search_index_provider<T: Transport>(
 
     // Process response
     match envelope.payload {
-        Some(Payload::SearchResponse(resp)) => {
+        Some(Payload::SearchResponse(mut resp)) => {
+            // Don't let a misbehaving provider make us process more than we asked for
+            if resp.results.len() > limit {
+                tracing::warn!(
+                    "{} returned {} results, {} requested; dropping the rest",
+                    index_provider,
+                    resp.results.len(),
+                    limit
+                );
+                resp.results.truncate(limit);
+            }
             let results: Vec<brisby_core::SearchResult> = resp
                 .results
                 .into_iter()
@@ -81,17 +212,260 @@ pub async fn search_index_provider<T: Transport>(
                     }
                     let mut hash = [0u8; 32];
                     hash.copy_from_slice(&r.content_hash);
+                    // Providers that don't count seeders only list them
+                    let seeder_count = match r.seeder_count {
+                        0 => (r.seeders.len() + r.relay_tokens.len()) as u32,
+                        count => count,
+                    };
+                    let mut seeders = r.seeders;
+                    seeders.extend(r.relay_tokens.into_iter().map(|token| {
+                        SeederRoute::Relay {
+                            index_provider: index_provider.clone(),
+                            token,
+                        }
+                        .to_string()
+                    }));
                     Some(brisby_core::SearchResult {
                         content_hash: hash,
                         filename: r.filename,
                         size: r.size,
                         chunk_count: r.chunk_count,
+                        chunk_size: match r.chunk_size {
+                            0 => brisby_core::CHUNK_SIZE as u32,
+                            size => size,
+                        },
+                        content_defined: r.content_defined.map(Into::into),
                         relevance: r.relevance,
-                        seeders: r.seeders,
+                        seeders,
+                        relay_tokens: Vec::new(),
+                        seeder_count,
                     })
                 })
                 .collect();
-            Ok(results)
+            Ok(SearchPage {
+                results,
+                total: (resp.total_results > 0).then_some(resp.total_results),
+                refresh: None,
+            })
+        }
+        Some(Payload::ErrorResponse(err)) => {
+            Err(anyhow!("Index provider error: {} (code {})", err.message, err.code))
+        }
+        _ => Err(anyhow!("Unexpected response type")),
+    }
+}
+
+/// Search for files, serving results from the search cache when possible
+///
+/// On a cache miss the index provider is queried and the response is cached.
+/// A stale cached response is served as is, without waiting on the mixnet,
+/// with a `SearchPage::refresh` for the caller to run once the results have
+/// been shown. Pass `None` to bypass the cache entirely.
+///
+/// Only unfiltered first pages are cached, without their total. Because the
+/// threshold is relative to the best result, a cached response can still
+/// answer a search with a `min_relevance` by filtering it locally. Searches
+/// with a `filter` or in `QueryMode::Advanced` always go to the provider.
+#[allow(clippy::too_many_arguments)]
+pub async fn search_with_cache<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    query: &str,
+    mode: QueryMode,
+    max_results: u32,
+    offset: u32,
+    min_relevance: f32,
+    filter: &SearchFilter,
+    cache: Option<&SearchCache>,
+) -> Result<SearchPage> {
+    let cache = cache.filter(|_| offset == 0 && filter.is_empty() && mode == QueryMode::Keywords);
+    if let Some(cache) = cache {
+        match cache.get(index_provider.as_str(), query, max_results) {
+            Ok(Some(cached)) => {
+                tracing::debug!("Serving search for '{}' from cache", query);
+                let mut results = cached.results;
+                brisby_core::SearchResult::retain_min_relevance(&mut results, min_relevance);
+                let refresh = cached.stale.then(|| SearchRefresh {
+                    query: query.to_string(),
+                    max_results,
+                });
+                return Ok(SearchPage {
+                    results,
+                    total: None,
+                    refresh,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read search cache: {}", e),
+        }
+    }
+
+    let page = search_index_provider(
+        transport,
+        index_provider,
+        query,
+        mode,
+        max_results,
+        offset,
+        min_relevance,
+        filter,
+    )
+    .await?;
+
+    if let Some(cache) = cache.filter(|_| min_relevance <= 0.0) {
+        if let Err(e) = cache.put(index_provider.as_str(), query, max_results, &page.results) {
+            tracing::warn!("Failed to update search cache: {}", e);
+        }
+    }
+
+    Ok(page)
+}
+
+/// Search, letting the index provider apply `min_relevance` and `offset`
+/// only if it can
+///
+/// Providers that don't advertise `capabilities::MIN_RELEVANCE` are asked for
+/// unfiltered results, which are then filtered locally (and can be cached).
+/// Pages past the first are cut locally from the results up to the end of
+/// the page when the provider can't page, or when filtering locally, since
+/// the threshold has to be applied before paging. Locally paged searches
+/// have no total.
+///
+/// A `filter` can't be applied locally, since results don't carry the MIME
+/// type or publish time, so providers without
+/// `capabilities::SEARCH_FILTERS` fail filtered searches instead. Likewise,
+/// providers without `capabilities::ADVANCED_QUERY` fail advanced searches
+/// rather than matching the operators as words.
+#[allow(clippy::too_many_arguments)]
+pub async fn search_negotiated<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    query: &str,
+    mode: QueryMode,
+    max_results: u32,
+    offset: u32,
+    min_relevance: f32,
+    filter: &SearchFilter,
+    cache: Option<&SearchCache>,
+) -> Result<SearchPage> {
+    let plain = filter.is_empty() && mode == QueryMode::Keywords;
+    if min_relevance <= 0.0 && offset == 0 && plain {
+        return search_with_cache(
+            transport,
+            index_provider,
+            query,
+            mode,
+            max_results,
+            0,
+            min_relevance,
+            filter,
+            cache,
+        )
+        .await;
+    }
+
+    let caps = query_capabilities(transport, index_provider).await?;
+    if !filter.is_empty() && !caps.supports(capabilities::SEARCH_FILTERS) {
+        return Err(anyhow!(
+            "Index provider {} can't filter search results",
+            index_provider.as_str()
+        ));
+    }
+    if mode == QueryMode::Advanced && !caps.supports(capabilities::ADVANCED_QUERY) {
+        return Err(anyhow!(
+            "Index provider {} doesn't support advanced queries",
+            index_provider.as_str()
+        ));
+    }
+    let filter_locally = min_relevance > 0.0 && !caps.supports(capabilities::MIN_RELEVANCE);
+    let page_locally =
+        offset > 0 && (filter_locally || !caps.supports(capabilities::PAGINATION));
+    if !filter_locally && !page_locally {
+        return search_with_cache(
+            transport,
+            index_provider,
+            query,
+            mode,
+            max_results,
+            offset,
+            min_relevance,
+            filter,
+            cache,
+        )
+        .await;
+    }
+
+    if filter_locally {
+        tracing::debug!("Index provider can't filter by relevance, filtering locally");
+    }
+    if page_locally {
+        tracing::debug!("Paging search results locally");
+    }
+    let (asked_max, asked_offset) = if page_locally {
+        (max_results.saturating_add(offset), 0)
+    } else {
+        (max_results, offset)
+    };
+    let asked_min_relevance = if filter_locally { 0.0 } else { min_relevance };
+    let mut page = search_with_cache(
+        transport,
+        index_provider,
+        query,
+        mode,
+        asked_max,
+        asked_offset,
+        asked_min_relevance,
+        filter,
+        cache,
+    )
+    .await?;
+
+    if filter_locally {
+        brisby_core::SearchResult::retain_min_relevance(&mut page.results, min_relevance);
+        page.total = None;
+    }
+    if page_locally {
+        page.results.drain(..page.results.len().min(offset as usize));
+        page.total = None;
+    }
+    Ok(page)
+}
+
+/// Ask an index provider which optional features it supports
+///
+/// Providers that predate capability negotiation reject the request as an
+/// unexpected message type; they are assumed to support only the features
+/// every provider has (`CapabilitiesResponse::legacy`).
+pub async fn query_capabilities<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+) -> Result<proto::CapabilitiesResponse> {
+    let request_id = next_request_id();
+    let envelope = Envelope::new(
+        request_id,
+        Payload::CapabilitiesRequest(proto::CapabilitiesRequest {}),
+    );
+
+    transport
+        .send_timeout(index_provider, envelope.to_bytes(), SEND_TIMEOUT)
+        .await
+        .map_err(|e| transport_error("Failed to send capabilities request", e))?;
+
+    let timeout = Duration::from_secs(30);
+    let response = transport
+        .receive_timeout(timeout)
+        .await
+        .map_err(|e| transport_error("Failed to receive response", e))?
+        .ok_or_else(|| timeout_error("Timeout waiting for capabilities response", timeout))?;
+
+    let envelope = Envelope::from_bytes(&response.data)
+        .map_err(|e| anyhow!("Failed to decode response: {}", e))?;
+
+    match envelope.payload {
+        Some(Payload::CapabilitiesResponse(caps)) => Ok(caps),
+        Some(Payload::ErrorResponse(err)) if err.code == error_codes::INVALID_MESSAGE => {
+            tracing::debug!("Index provider doesn't support capability negotiation");
+            Ok(proto::CapabilitiesResponse::legacy())
         }
         Some(Payload::ErrorResponse(err)) => {
             Err(anyhow!("Index provider error: {} (code {})", err.message, err.code))
@@ -100,13 +474,131 @@ pub async fn search_index_provider<T: Transport>(
     }
 }
 
+/// Ask an index provider who is seeding `content_hash`
+///
+/// Sends the DHT's `FindValueRequest`, which providers advertising
+/// `capabilities::FIND_VALUE` answer from their index. Anonymous seeders come
+/// back as relay routes through `index_provider`, see `SeederRoute`, and
+/// seeders whose listing has expired aren't included.
+pub async fn find_seeders<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    content_hash: &ContentHash,
+) -> Result<Vec<Seeder>> {
+    let request_id = next_request_id();
+    let envelope = Envelope::new(
+        request_id,
+        Payload::FindValueRequest(proto::FindValueRequest {
+            key: content_hash.to_vec(),
+        }),
+    );
+
+    transport
+        .send_timeout(index_provider, envelope.to_bytes(), SEND_TIMEOUT)
+        .await
+        .map_err(|e| transport_error("Failed to send seeder lookup", e))?;
+
+    let timeout = Duration::from_secs(30);
+    let response = transport
+        .receive_timeout(timeout)
+        .await
+        .map_err(|e| transport_error("Failed to receive response", e))?
+        .ok_or_else(|| timeout_error("Timeout waiting for seeder lookup response", timeout))?;
+
+    let envelope = Envelope::from_bytes(&response.data)
+        .map_err(|e| anyhow!("Failed to decode response: {}", e))?;
+
+    match envelope.payload {
+        Some(Payload::FindValueResponse(resp)) => Ok(resp
+            .seeders
+            .into_iter()
+            .map(|seeder| {
+                let nym_address = if seeder.relay_token.is_empty() {
+                    seeder.nym_address
+                } else {
+                    SeederRoute::Relay {
+                        index_provider: index_provider.clone(),
+                        token: seeder.relay_token,
+                    }
+                    .to_string()
+                };
+                Seeder {
+                    nym_address,
+                    chunk_bitmap: seeder.chunk_bitmap,
+                    last_seen: seeder.last_seen,
+                    expires_at: seeder.expires_at,
+                }
+            })
+            .filter(|seeder| !seeder.nym_address.is_empty())
+            .collect()),
+        Some(Payload::ErrorResponse(err)) if err.code == error_codes::INVALID_MESSAGE => Err(
+            anyhow!("Index provider {} can't look up seeders", index_provider.as_str()),
+        ),
+        Some(Payload::ErrorResponse(err)) => {
+            Err(anyhow!("Index provider error: {} (code {})", err.message, err.code))
+        }
+        _ => Err(anyhow!("Unexpected response type")),
+    }
+}
+
+/// Seeders listed by `find_seeders`, one per line with how long ago each
+/// published the file and how long its listing has left
+pub fn format_seeders(seeders: &[Seeder], now: u64) -> String {
+    let mut out = String::new();
+    for seeder in seeders {
+        let _ = write!(out, "  {}", seeder.nym_address);
+        if seeder.last_seen > 0 {
+            let age = now.saturating_sub(seeder.last_seen);
+            let _ = write!(out, "  seen {} ago", format_secs(age));
+        }
+        if seeder.expires_at > 0 {
+            let left = seeder.expires_at.saturating_sub(now);
+            let _ = write!(out, ", expires in {}", format_secs(left));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Rough duration for display: `45s`, `12m`, `5h 30m` or `3d 4h`
+fn format_secs(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
 /// Publish file metadata to an index provider
+///
+/// With `anonymous`, the provider lists us by rendezvous token instead of
+/// `our_address` and relays downloaders' requests to us.
+///
+/// Providers that require proof of possession answer with a
+/// `PublishChallenge` for one chunk first; `read_chunk` supplies it by index.
+/// Only files with Merkle content hashes can be proven this way, so files
+/// with flat hashes are refused before anything is sent. `ChunkStore` moves
+/// such files over to Merkle hashes when it loads them.
+///
+/// Returns the token to unpublish the file with later, which is empty if
+/// the provider predates them.
 pub async fn publish_to_index_provider<T: Transport>(
     transport: &T,
     index_provider: &NymAddress,
     metadata: &brisby_core::FileMetadata,
     our_address: &NymAddress,
-) -> Result<()> {
+    anonymous: bool,
+    read_chunk: impl Fn(u32) -> Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    if !metadata.hash_algo.is_merkle() {
+        return Err(PublishRejected(format!(
+            "{} has a flat content hash, which index providers can't check; \
+             reload it into the chunk store to move it to a Merkle hash",
+            metadata.filename
+        ))
+        .into());
+    }
     let request_id = next_request_id();
 
     // Create publish request
@@ -119,6 +611,11 @@ pub async fn publish_to_index_provider<T: Transport>(
             size: metadata.size,
             chunk_count: metadata.chunks.len() as u32,
             nym_address: our_address.as_str().to_string(),
+            tags: Vec::new(),
+            anonymous,
+            mime_type: metadata.mime_type.clone().unwrap_or_default(),
+            chunk_size: metadata.chunk_size,
+            content_defined: metadata.content_defined.map(Into::into),
         }),
     );
 
@@ -126,43 +623,219 @@ pub async fn publish_to_index_provider<T: Transport>(
 
     // Send request
     transport
-        .send(index_provider, envelope.to_bytes())
+        .send_timeout(index_provider, envelope.to_bytes(), SEND_TIMEOUT)
         .await
-        .map_err(|e| anyhow!("Failed to send publish request: {}", e))?;
+        .map_err(|e| transport_error("Failed to send publish request", e))?;
 
     // Wait for response with timeout
     let timeout = Duration::from_secs(30);
-    let response = transport
-        .receive_timeout(timeout)
-        .await
-        .map_err(|e| anyhow!("Failed to receive response: {}", e))?
-        .ok_or_else(|| anyhow!("Timeout waiting for publish response"))?;
+    let mut envelope = receive_publish_reply(transport, timeout).await?;
 
-    // Decode response
-    let envelope = Envelope::from_bytes(&response.data)
-        .map_err(|e| anyhow!("Failed to decode response: {}", e))?;
+    if let Some(challenge) = envelope.as_publish_challenge() {
+        let proof = answer_challenge(metadata, challenge, read_chunk)?;
+        tracing::debug!("Proving possession of chunk {}", challenge.chunk_index);
+        transport
+            .send_timeout(
+                index_provider,
+                Envelope::new(request_id, Payload::PublishProof(proof)).to_bytes(),
+                SEND_TIMEOUT,
+            )
+            .await
+            .map_err(|e| transport_error("Failed to send publish proof", e))?;
+        envelope = receive_publish_reply(transport, timeout).await?;
+    }
 
     // Process response
     match envelope.payload {
         Some(Payload::PublishResponse(resp)) => {
             if resp.success {
-                Ok(())
+                Ok(resp.unpublish_token)
             } else {
-                Err(anyhow!("Publish failed: {}", resp.error))
+                Err(PublishRejected(format!("Publish failed: {}", resp.error)).into())
             }
         }
         Some(Payload::ErrorResponse(err)) => {
-            Err(anyhow!("Index provider error: {} (code {})", err.message, err.code))
+            let message = format!("Index provider error: {} (code {})", err.message, err.code);
+            // A busy provider may well accept the publish later
+            if err.code == error_codes::UNAVAILABLE {
+                Err(anyhow!(message))
+            } else {
+                Err(PublishRejected(message).into())
+            }
         }
         _ => Err(anyhow!("Unexpected response type")),
     }
 }
 
+/// Wait for the index provider's next reply while publishing
+async fn receive_publish_reply<T: Transport>(transport: &T, timeout: Duration) -> Result<Envelope> {
+    let response = transport
+        .receive_timeout(timeout)
+        .await
+        .map_err(|e| transport_error("Failed to receive response", e))?
+        .ok_or_else(|| timeout_error("Timeout waiting for publish response", timeout))?;
+
+    Envelope::from_bytes(&response.data).map_err(|e| anyhow!("Failed to decode response: {}", e))
+}
+
+/// Build the answer to a proof-of-possession challenge
+///
+/// Failing to answer is final: asking again gets a challenge we can't
+/// answer either.
+fn answer_challenge(
+    metadata: &brisby_core::FileMetadata,
+    challenge: &proto::PublishChallenge,
+    read_chunk: impl Fn(u32) -> Option<Vec<u8>>,
+) -> Result<proto::PublishProof> {
+    if challenge.content_hash != metadata.content_hash {
+        return Err(anyhow!("Challenge is for a different file"));
+    }
+    let Some(proof) = metadata.merkle_proof(challenge.chunk_index) else {
+        return Err(PublishRejected(format!(
+            "Can't prove possession of chunk {}: not a Merkle-hashed file",
+            challenge.chunk_index
+        ))
+        .into());
+    };
+    let Some(data) = read_chunk(challenge.chunk_index) else {
+        return Err(PublishRejected(format!(
+            "Can't prove possession of chunk {}: chunk not available",
+            challenge.chunk_index
+        ))
+        .into());
+    };
+
+    Ok(proto::PublishProof {
+        nonce: challenge.nonce.clone(),
+        data: data.into(),
+        proof: proof.iter().map(|hash| hash.to_vec()).collect(),
+    })
+}
+
+/// Ask an index provider to stop listing us as a seeder of a file
+///
+/// Used when a seeder shuts down, so the file drops out of search results
+/// straight away instead of when its TTL runs out. `unpublish_token` is the
+/// one `publish_to_index_provider` returned for our latest publish of the
+/// file. Messages other than the reply, such as chunk requests still
+/// arriving, are skipped.
+pub async fn unpublish_from_index_provider<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    content_hash: &brisby_core::ContentHash,
+    our_address: &NymAddress,
+    unpublish_token: &[u8],
+    timeout: Duration,
+) -> Result<()> {
+    let request_id = next_request_id();
+    let envelope = Envelope::new(
+        request_id,
+        Payload::UnpublishRequest(proto::UnpublishRequest {
+            content_hash: content_hash.to_vec(),
+            nym_address: our_address.as_str().to_string(),
+            unpublish_token: unpublish_token.to_vec(),
+        }),
+    );
+
+    tracing::debug!("Sending unpublish request to {}", index_provider.as_str());
+    transport
+        .send_timeout(index_provider, envelope.to_bytes(), timeout)
+        .await
+        .map_err(|e| transport_error("Failed to send unpublish request", e))?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let response = transport
+            .receive_timeout(remaining)
+            .await
+            .map_err(|e| transport_error("Failed to receive response", e))?
+            .ok_or_else(|| timeout_error("Timeout waiting for unpublish response", timeout))?;
+        let Ok(envelope) = Envelope::from_bytes(&response.data) else {
+            continue;
+        };
+        if envelope.request_id != request_id {
+            continue;
+        }
+
+        return match envelope.payload {
+            Some(Payload::UnpublishResponse(resp)) if resp.success => Ok(()),
+            Some(Payload::UnpublishResponse(resp)) => {
+                Err(anyhow!("Unpublish failed: {}", resp.error))
+            }
+            Some(Payload::ErrorResponse(err)) => {
+                Err(anyhow!("Index provider error: {} (code {})", err.message, err.code))
+            }
+            _ => Err(anyhow!("Unexpected response type")),
+        };
+    }
+}
+
+/// Check that an index provider is reachable
+///
+/// Sends a ping and treats any decodable reply (including an error response
+/// from providers that don't handle pings) as proof of life. Returns the
+/// round-trip time.
+pub async fn ping_index_provider<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    timeout: Duration,
+) -> Result<Duration> {
+    let request_id = next_request_id();
+    let envelope = Envelope::new(
+        request_id,
+        Payload::PingRequest(proto::PingRequest {
+            sender_id: Vec::new(),
+        }),
+    );
+
+    let started = std::time::Instant::now();
+    transport
+        .send_timeout(index_provider, envelope.to_bytes(), timeout)
+        .await
+        .map_err(|e| transport_error("Failed to send ping", e))?;
+
+    let response = transport
+        .receive_timeout(timeout)
+        .await
+        .map_err(|e| transport_error("Failed to receive response", e))?
+        .ok_or_else(|| timeout_error("Timeout waiting for ping response", timeout))?;
+
+    Envelope::from_bytes(&response.data)
+        .map_err(|e| anyhow!("Failed to decode response: {}", e))?;
+
+    Ok(started.elapsed())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use brisby_core::transport::mock::MockTransport;
-    use brisby_core::{proto, ReceivedMessage, SenderTag};
+    use brisby_core::{proto, ReceivedMessage};
+
+    /// Negotiated search for `test` on the test provider, without a cache
+    async fn search(
+        transport: &MockTransport,
+        max_results: u32,
+        offset: u32,
+        min_relevance: f32,
+        filter: &SearchFilter,
+        mode: QueryMode,
+    ) -> Result<SearchPage> {
+        let index_provider = NymAddress::new("test-index-provider");
+        search_negotiated(
+            transport,
+            &index_provider,
+            "test",
+            mode,
+            max_results,
+            offset,
+            min_relevance,
+            filter,
+            None,
+        )
+        .await
+    }
 
     #[tokio::test]
     async fn test_search_index_provider() {
@@ -181,15 +854,444 @@ mod tests {
                 chunk_count: 1,
                 relevance: 1.0,
                 seeders: vec!["test-seeder".to_string()],
+                relay_tokens: vec![],
+                seeder_count: 1,
+                chunk_size: 0,
+                content_defined: None,
+            }],
+        );
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let filter = SearchFilter::default();
+        let page = search_index_provider(
+            &transport,
+            &index_provider,
+            "test",
+            QueryMode::Keywords,
+            10,
+            0,
+            0.0,
+            &filter,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.results.len(), 1);
+        assert_eq!(page.results[0].filename, "test.txt");
+        assert_eq!(page.results[0].seeder_count, 1);
+        // Providers that don't count matches leave the total unknown
+        assert_eq!(page.total, None);
+    }
+
+    #[tokio::test]
+    async fn test_search_truncates_excess_results() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let index_provider = NymAddress::new("test-index-provider");
+
+        // A provider ignoring the requested maximum
+        let results: Vec<proto::SearchResult> = (0..50u8)
+            .map(|i| proto::SearchResult {
+                content_hash: vec![i; 32],
+                filename: format!("file{}.txt", i),
+                size: 1024,
+                chunk_count: 1,
+                relevance: 1.0,
+                seeders: vec!["test-seeder".to_string()],
+                relay_tokens: vec![],
+                seeder_count: 1,
+                chunk_size: 0,
+                content_defined: None,
+            })
+            .collect();
+        let response = proto::search_response(0, results);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let page = search_index_provider(
+            &transport,
+            &index_provider,
+            "test",
+            QueryMode::Keywords,
+            5,
+            0,
+            0.0,
+            &SearchFilter::default(),
+        )
+        .await
+        .unwrap();
+        let names: Vec<&str> = page.results.iter().map(|r| r.filename.as_str()).collect();
+        assert_eq!(names, vec!["file0.txt", "file1.txt", "file2.txt", "file3.txt", "file4.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_with_cache_hits_within_ttl() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let index_provider = NymAddress::new("test-index-provider");
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let cache = SearchCache::open(temp.path(), 60, 10).unwrap();
+
+        let response = proto::search_response(
+            0,
+            vec![proto::SearchResult {
+                content_hash: vec![1u8; 32],
+                filename: "cached.txt".to_string(),
+                size: 1024,
+                chunk_count: 1,
+                relevance: 1.0,
+                seeders: vec!["test-seeder".to_string()],
+                relay_tokens: vec![],
+                seeder_count: 1,
+                chunk_size: 0,
+                content_defined: None,
             }],
         );
         transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
 
-        let results = search_index_provider(&transport, &index_provider, "test", 10)
+        let filter = SearchFilter::default();
+        let first = search_with_cache(
+            &transport,
+            &index_provider,
+            "cached",
+            QueryMode::Keywords,
+            10,
+            0,
+            0.0,
+            &filter,
+            Some(&cache),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.results.len(), 1);
+        assert_eq!(transport.get_sent_messages().len(), 1);
+
+        // Second identical search is answered from the cache without a request
+        let second = search_with_cache(
+            &transport,
+            &index_provider,
+            "Cached",
+            QueryMode::Keywords,
+            10,
+            0,
+            0.0,
+            &filter,
+            Some(&cache),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.results.len(), 1);
+        assert_eq!(second.results[0].filename, "cached.txt");
+        assert!(second.refresh.is_none());
+        assert_eq!(transport.get_sent_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_search_served_then_refreshed() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let index_provider = NymAddress::new("test-index-provider");
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        // Everything cached is stale at once, but still served
+        let cache = SearchCache::open(temp.path(), 0, 10).unwrap();
+        let filter = SearchFilter::default();
+        let search = || {
+            search_with_cache(
+                &transport,
+                &index_provider,
+                "stale",
+                QueryMode::Keywords,
+                10,
+                0,
+                0.0,
+                &filter,
+                Some(&cache),
+            )
+        };
+
+        let response = proto::search_response(0, vec![weighted_result(1, 1.0)]);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        let first = search().await.unwrap();
+        assert!(first.refresh.is_none());
+        assert_eq!(transport.get_sent_messages().len(), 1);
+
+        // The stale response comes back without a request, with a refresh
+        let second = search().await.unwrap();
+        assert_eq!(second.results[0].filename, "1.txt");
+        assert_eq!(transport.get_sent_messages().len(), 1);
+        let refresh = second.refresh.expect("stale results are refreshed");
+
+        // which asks the provider again and replaces the cached response
+        let response = proto::search_response(0, vec![weighted_result(2, 1.0)]);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        refresh.run(&transport, &index_provider, &cache).await.unwrap();
+        assert_eq!(transport.get_sent_messages().len(), 2);
+        let cached = cache.get(index_provider.as_str(), "stale", 10).unwrap().unwrap();
+        assert_eq!(cached.results[0].filename, "2.txt");
+    }
+
+    fn weighted_result(byte: u8, relevance: f32) -> proto::SearchResult {
+        proto::SearchResult {
+            content_hash: vec![byte; 32],
+            filename: format!("{}.txt", byte),
+            size: 1024,
+            chunk_count: 1,
+            relevance,
+            seeders: vec!["test-seeder".to_string()],
+            relay_tokens: vec![],
+            seeder_count: 1,
+            chunk_size: 0,
+            content_defined: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_filters_locally_for_legacy_provider() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        // An old provider rejects the capabilities request and ignores the threshold
+        let rejection = proto::error_response(
+            0,
+            error_codes::INVALID_MESSAGE,
+            "unexpected message type".to_string(),
+        );
+        transport.queue_message(ReceivedMessage::new(rejection.to_bytes(), None));
+        let response = proto::search_response(
+            0,
+            vec![weighted_result(1, 10.0), weighted_result(2, 1.0)],
+        );
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let filter = SearchFilter::default();
+        let page = search(&transport, 10, 0, 0.5, &filter, QueryMode::Keywords)
+            .await
+            .unwrap();
+        assert_eq!(page.results.len(), 1);
+        assert_eq!(page.results[0].filename, "1.txt");
+
+        let sent = transport.get_sent_messages();
+        assert!(Envelope::from_bytes(&sent[0].1).unwrap().as_capabilities_request().is_some());
+        let search = Envelope::from_bytes(&sent[1].1).unwrap().into_search_request().unwrap();
+        assert_eq!(search.min_relevance, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_search_delegates_threshold_to_capable_provider() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        let caps = Envelope::new(
+            0,
+            Payload::CapabilitiesResponse(proto::CapabilitiesResponse {
+                protocol_version: brisby_core::PROTOCOL_VERSION as u32,
+                features: capabilities::SEARCH | capabilities::MIN_RELEVANCE,
+            }),
+        );
+        transport.queue_message(ReceivedMessage::new(caps.to_bytes(), None));
+        let response = proto::search_response(0, vec![weighted_result(1, 10.0)]);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let filter = SearchFilter::default();
+        let page = search(&transport, 10, 0, 0.5, &filter, QueryMode::Keywords)
             .await
             .unwrap();
+        assert_eq!(page.results.len(), 1);
+
+        let sent = transport.get_sent_messages();
+        let search = Envelope::from_bytes(&sent[1].1).unwrap().into_search_request().unwrap();
+        assert_eq!(search.min_relevance, 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_search_pages_locally_without_provider_support() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        // A provider that filters by relevance but predates paging
+        let caps = Envelope::new(
+            0,
+            Payload::CapabilitiesResponse(proto::CapabilitiesResponse {
+                protocol_version: brisby_core::PROTOCOL_VERSION as u32,
+                features: capabilities::SEARCH | capabilities::MIN_RELEVANCE,
+            }),
+        );
+        transport.queue_message(ReceivedMessage::new(caps.to_bytes(), None));
+        let response = proto::search_response(
+            0,
+            (1..=5).map(|byte| weighted_result(byte, 10.0)).collect(),
+        );
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let filter = SearchFilter::default();
+        let page = search(&transport, 2, 3, 0.0, &filter, QueryMode::Keywords)
+            .await
+            .unwrap();
+        let names: Vec<_> = page.results.iter().map(|r| r.filename.as_str()).collect();
+        assert_eq!(names, ["4.txt", "5.txt"]);
+        assert_eq!(page.total, None);
+
+        // Everything up to the end of the page was asked for
+        let sent = transport.get_sent_messages();
+        let search = Envelope::from_bytes(&sent[1].1).unwrap().into_search_request().unwrap();
+        assert_eq!((search.max_results, search.offset), (5, 0));
+    }
+
+    #[tokio::test]
+    async fn test_filtered_search_needs_provider_support() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let filter = SearchFilter {
+            min_size: Some(1000),
+            mime_type: Some("video".to_string()),
+            ..Default::default()
+        };
+        let caps_response = |features| {
+            let caps = Envelope::new(
+                0,
+                Payload::CapabilitiesResponse(proto::CapabilitiesResponse {
+                    protocol_version: brisby_core::PROTOCOL_VERSION as u32,
+                    features,
+                }),
+            );
+            ReceivedMessage::new(caps.to_bytes(), None)
+        };
 
-        assert_eq!(results.len(), 1);
-        assert_eq!(results[0].filename, "test.txt");
+        // A provider that would ignore the filters isn't asked to search at all
+        transport.queue_message(caps_response(capabilities::SEARCH));
+        let result = search(&transport, 10, 0, 0.0, &filter, QueryMode::Keywords).await;
+        assert!(result.is_err());
+        assert_eq!(transport.get_sent_messages().len(), 1);
+
+        transport.queue_message(caps_response(
+            capabilities::SEARCH | capabilities::SEARCH_FILTERS,
+        ));
+        let response = proto::search_response(0, vec![weighted_result(1, 10.0)]);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        let page = search(&transport, 10, 0, 0.0, &filter, QueryMode::Keywords)
+            .await
+            .unwrap();
+        assert_eq!(page.results.len(), 1);
+
+        let sent = transport.get_sent_messages();
+        let search = Envelope::from_bytes(&sent[2].1).unwrap().into_search_request().unwrap();
+        assert_eq!(search.filter(), filter);
+    }
+
+    #[tokio::test]
+    async fn test_advanced_search_needs_provider_support() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let filter = SearchFilter::default();
+        let caps_response = |features| {
+            let caps = Envelope::new(
+                0,
+                Payload::CapabilitiesResponse(proto::CapabilitiesResponse {
+                    protocol_version: brisby_core::PROTOCOL_VERSION as u32,
+                    features,
+                }),
+            );
+            ReceivedMessage::new(caps.to_bytes(), None)
+        };
+
+        // Operators would be matched as words by an older provider
+        transport.queue_message(caps_response(capabilities::SEARCH));
+        let result = search(&transport, 10, 0, 0.0, &filter, QueryMode::Advanced).await;
+        assert!(result.is_err());
+        assert_eq!(transport.get_sent_messages().len(), 1);
+
+        transport.queue_message(caps_response(
+            capabilities::SEARCH | capabilities::ADVANCED_QUERY,
+        ));
+        let response = proto::search_response(0, vec![weighted_result(1, 10.0)]);
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+        let page = search(&transport, 10, 0, 0.0, &filter, QueryMode::Advanced)
+            .await
+            .unwrap();
+        assert_eq!(page.results.len(), 1);
+
+        let sent = transport.get_sent_messages();
+        let search = Envelope::from_bytes(&sent[2].1).unwrap().into_search_request().unwrap();
+        assert!(search.advanced);
+    }
+
+    #[tokio::test]
+    async fn test_find_seeders_lists_and_prints() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let index_provider = NymAddress::new("test-index-provider");
+
+        let response = Envelope::new(
+            0,
+            Payload::FindValueResponse(proto::FindValueResponse {
+                seeders: vec![
+                    proto::ProtoSeeder {
+                        nym_address: "seeder-a".to_string(),
+                        last_seen: 9_700,
+                        expires_at: 96_400,
+                        ..Default::default()
+                    },
+                    proto::ProtoSeeder {
+                        relay_token: vec![0xab; 4],
+                        last_seen: 5_000,
+                        expires_at: 12_200,
+                        ..Default::default()
+                    },
+                ],
+                nodes: vec![],
+            }),
+        );
+        transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+        let seeders = find_seeders(&transport, &index_provider, &[3u8; 32]).await.unwrap();
+        let sent = Envelope::from_bytes(&transport.get_sent_messages()[0].1).unwrap();
+        assert_eq!(sent.into_find_value_request().unwrap().key, vec![3u8; 32]);
+
+        assert_eq!(seeders.len(), 2);
+        let relayed = SeederRoute::parse(&seeders[1].nym_address);
+        assert!(matches!(
+            relayed,
+            SeederRoute::Relay { ref index_provider, ref token }
+                if index_provider.as_str() == "test-index-provider" && token == &[0xab; 4]
+        ));
+        let printed = format_seeders(&seeders, 10_000);
+        let lines: Vec<_> = printed.lines().collect();
+        assert_eq!(lines[0], "  seeder-a  seen 5m ago, expires in 1d 0h");
+        assert_eq!(
+            lines[1],
+            format!("  {}  seen 1h 23m ago, expires in 36m", seeders[1].nym_address)
+        );
+
+        // Providers without seeder lookups reject the message type
+        let rejection = proto::error_response(
+            0,
+            error_codes::INVALID_MESSAGE,
+            "unexpected message type".to_string(),
+        );
+        transport.queue_message(ReceivedMessage::new(rejection.to_bytes(), None));
+        assert!(find_seeders(&transport, &index_provider, &[3u8; 32]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_are_told_apart() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let index_provider = NymAddress::new("test-index-provider");
+        let timeout = Duration::from_millis(50);
+
+        // No reply at all is a timeout, still described as one
+        let error = ping_index_provider(&transport, &index_provider, timeout)
+            .await
+            .unwrap_err();
+        assert!(is_timeout(&error));
+        assert!(error.to_string().contains("Timeout waiting for ping response"));
+
+        // A reply that doesn't decode is a failure, not a timeout
+        transport.queue_message(ReceivedMessage::new(vec![0xff; 8], None));
+        let error = ping_index_provider(&transport, &index_provider, timeout)
+            .await
+            .unwrap_err();
+        assert!(!is_timeout(&error));
     }
 }
@@ -0,0 +1,104 @@
+//! On-disk storage for partially downloaded files
+//!
+//! Received chunks are kept under `<partials dir>/<content hash>/chunk_NNNNNN`,
+//! keyed by content hash rather than output filename. A download can then be
+//! resumed to a different output path, and two downloads of the same content
+//! share progress.
+
+use anyhow::{anyhow, Result};
+use brisby_core::ContentHash;
+use std::path::{Path, PathBuf};
+
+/// Chunks received so far for one piece of content
+pub struct PartialDownload {
+    dir: PathBuf,
+}
+
+impl PartialDownload {
+    /// Open (creating if needed) the partial download for `content_hash`
+    pub fn open(partials_dir: &Path, content_hash: &ContentHash) -> Result<Self> {
+        let dir = partials_dir.join(brisby_core::hash_to_hex(content_hash));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Directory holding this download's chunks
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn chunk_path(&self, chunk_index: u32) -> PathBuf {
+        self.dir.join(format!("chunk_{:06}", chunk_index))
+    }
+
+    /// Check whether a chunk has been saved
+    pub fn has_chunk(&self, chunk_index: u32) -> bool {
+        self.chunk_path(chunk_index).exists()
+    }
+
+    /// Save a received chunk
+    ///
+    /// Written to a temporary file and renamed so an interrupted write never
+    /// leaves a truncated chunk behind.
+    pub fn write_chunk(&self, chunk_index: u32, data: &[u8]) -> Result<()> {
+        let path = self.chunk_path(chunk_index);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Read a saved chunk
+    pub fn read_chunk(&self, chunk_index: u32) -> Result<Vec<u8>> {
+        Ok(std::fs::read(self.chunk_path(chunk_index))?)
+    }
+
+    /// Indices in `0..total_chunks` that have not been saved yet
+    pub fn missing_chunks(&self, total_chunks: u32) -> Vec<u32> {
+        (0..total_chunks).filter(|i| !self.has_chunk(*i)).collect()
+    }
+
+    /// Read every chunk in `0..total_chunks`, failing if any is missing
+    pub fn read_all(&self, total_chunks: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+        (0..total_chunks)
+            .map(|i| {
+                self.read_chunk(i)
+                    .map(|data| (i, data))
+                    .map_err(|e| anyhow!("Missing partial chunk {}: {}", i, e))
+            })
+            .collect()
+    }
+
+    /// Delete the partial download once it is complete or unusable
+    pub fn remove(self) -> Result<()> {
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keyed_by_content_hash() {
+        let temp_dir = TempDir::new().unwrap();
+        let partial = PartialDownload::open(temp_dir.path(), &[7u8; 32]).unwrap();
+
+        assert_eq!(partial.dir(), temp_dir.path().join(brisby_core::hash_to_hex(&[7u8; 32])));
+        assert_eq!(partial.missing_chunks(3), vec![0, 1, 2]);
+
+        partial.write_chunk(1, b"chunk one").unwrap();
+        assert!(partial.dir().join("chunk_000001").exists());
+        assert_eq!(partial.missing_chunks(3), vec![0, 2]);
+
+        // Reopening the same content sees the same progress
+        let reopened = PartialDownload::open(temp_dir.path(), &[7u8; 32]).unwrap();
+        assert_eq!(reopened.read_chunk(1).unwrap(), b"chunk one");
+        assert!(reopened.read_all(3).is_err());
+
+        reopened.remove().unwrap();
+        assert!(!temp_dir.path().join(brisby_core::hash_to_hex(&[7u8; 32])).exists());
+    }
+}
@@ -0,0 +1,525 @@
+//! Resumable publishing of shared files to index providers
+//!
+//! Publishing walks every shared file and sends a publish request for each.
+//! A request that goes unanswered, e.g. because the mixnet dropped it, is
+//! retried with backoff per `RetryPolicy`. Every run publishes every shared
+//! file, which refreshes the listings' TTLs and yields a fresh unpublish token
+//! for each. Files still waiting to be published are recorded in a small state
+//! file, so if a run fails or is interrupted midway the next run sends the
+//! files that were never confirmed first.
+//!
+//! When the seeder shuts down, its files are unpublished again so they don't
+//! linger in search results until their TTL runs out. That takes the token
+//! the provider handed back for each publish, which is only kept in memory.
+
+use crate::network::{
+    publish_to_index_provider, query_capabilities, unpublish_from_index_provider, PublishRejected,
+};
+use anyhow::{anyhow, bail, Result};
+use brisby_core::proto::capabilities;
+use brisby_core::{ContentHash, FileMetadata, NymAddress, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::time::Duration;
+
+/// How often to ask again when a publish request goes unanswered
+///
+/// Only lost, garbled or "busy" replies are retried; a provider refusing the
+/// publish is final. The wait doubles after each failed attempt, up to
+/// `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts per file, including the first
+    pub max_attempts: u32,
+    /// Wait after the first failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before attempt `attempt + 1`, after `attempt` failed ones
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// How long to wait for each unpublish reply when the seeder shuts down
+pub const UNPUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of publishing a batch of files
+#[derive(Debug, Default, Clone)]
+pub struct PublishReport {
+    /// Content hashes the index provider accepted
+    pub succeeded: Vec<ContentHash>,
+    /// Content hashes that failed, with the error message
+    pub failed: Vec<(ContentHash, String)>,
+    /// Token to unpublish each accepted file with, see `unpublish_files`
+    pub unpublish_tokens: Vec<(ContentHash, Vec<u8>)>,
+}
+
+impl PublishReport {
+    /// Check whether every file was published
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Files still waiting to be published, per index provider
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PublishState {
+    /// Index provider address -> hex content hashes not yet published
+    pending: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PublishState {
+    /// Load the state file, returning an empty state if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Save the state file, removing it once nothing is pending
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if self.pending.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Content hashes still pending for a provider
+    pub fn pending(&self, index_provider: &str) -> Option<&BTreeSet<String>> {
+        self.pending.get(index_provider)
+    }
+
+    fn set_pending(&mut self, index_provider: &str, hashes: BTreeSet<String>) {
+        if hashes.is_empty() {
+            self.pending.remove(index_provider);
+        } else {
+            self.pending.insert(index_provider.to_string(), hashes);
+        }
+    }
+
+    fn mark_published(&mut self, index_provider: &str, hash: &str) {
+        if let Some(hashes) = self.pending.get_mut(index_provider) {
+            hashes.remove(hash);
+            if hashes.is_empty() {
+                self.pending.remove(index_provider);
+            }
+        }
+    }
+}
+
+/// Publish files to an index provider, resuming a previous incomplete run
+///
+/// Every file is published, so listings made by earlier runs get their TTL
+/// refreshed and the report holds an unpublish token for each. If
+/// `state_path` records files left pending for this provider by an earlier
+/// run, those go first. The state file is updated after each file so an
+/// interrupted run can be resumed.
+///
+/// Publishing `anonymous`ly fails up front if the provider can't relay, since
+/// it would otherwise list our address in search results.
+///
+/// `read_chunk` supplies chunks for providers that challenge us to prove we
+/// hold a file, see `publish_to_index_provider`.
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_files<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    files: &[&FileMetadata],
+    read_chunk: &dyn Fn(&ContentHash, u32) -> Option<Vec<u8>>,
+    our_address: &NymAddress,
+    anonymous: bool,
+    state_path: &Path,
+    retry: &RetryPolicy,
+) -> Result<PublishReport> {
+    let provider = index_provider.as_str();
+    if anonymous {
+        let caps = query_capabilities(transport, index_provider).await?;
+        if !caps.supports(capabilities::RELAY) {
+            bail!("Index provider {} can't relay to anonymous seeders", provider);
+        }
+    }
+    let mut state = PublishState::load(state_path)?;
+
+    let all: BTreeSet<String> = files
+        .iter()
+        .map(|m| brisby_core::hash_to_hex(&m.content_hash))
+        .collect();
+
+    // Files left over from a previous run, if still shared, go first
+    let left_over: BTreeSet<String> = state
+        .pending(provider)
+        .map(|pending| pending.intersection(&all).cloned().collect())
+        .unwrap_or_default();
+    if !left_over.is_empty() {
+        tracing::info!(
+            "Resuming publish: {} file(s) still pending for {}",
+            left_over.len(),
+            provider
+        );
+    }
+    let mut ordered: Vec<&FileMetadata> = files.to_vec();
+    ordered.sort_by_key(|m| !left_over.contains(&brisby_core::hash_to_hex(&m.content_hash)));
+
+    state.set_pending(provider, all);
+    state.save(state_path)?;
+
+    let mut report = PublishReport::default();
+    for metadata in ordered {
+        let hex = brisby_core::hash_to_hex(&metadata.content_hash);
+        tracing::info!("Publishing {} to index provider", metadata.filename);
+        let result = publish_with_retry(
+            transport,
+            index_provider,
+            metadata,
+            read_chunk,
+            our_address,
+            anonymous,
+            retry,
+        )
+        .await;
+        match result {
+            Ok(token) => {
+                state.mark_published(provider, &hex);
+                state.save(state_path)?;
+                report.succeeded.push(metadata.content_hash);
+                report.unpublish_tokens.push((metadata.content_hash, token));
+            }
+            Err(e) => {
+                tracing::error!("Failed to publish {}: {}", metadata.filename, e);
+                report.failed.push((metadata.content_hash, e.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Publish one file, retrying per `retry` until it is accepted or refused,
+/// and return its unpublish token
+async fn publish_with_retry<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    metadata: &FileMetadata,
+    read_chunk: &dyn Fn(&ContentHash, u32) -> Option<Vec<u8>>,
+    our_address: &NymAddress,
+    anonymous: bool,
+    retry: &RetryPolicy,
+) -> Result<Vec<u8>> {
+    let max_attempts = retry.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let result = publish_to_index_provider(
+            transport,
+            index_provider,
+            metadata,
+            our_address,
+            anonymous,
+            |index| read_chunk(&metadata.content_hash, index),
+        )
+        .await;
+        let error = match result {
+            Ok(token) => return Ok(token),
+            Err(e) if e.is::<PublishRejected>() => return Err(e),
+            Err(e) if attempt >= max_attempts => {
+                return Err(anyhow!("{} (gave up after {} attempts)", e, attempt));
+            }
+            Err(e) => e,
+        };
+
+        let backoff = retry.backoff(attempt);
+        tracing::warn!(
+            "Publishing {} failed (attempt {} of {}): {}; retrying in {:?}",
+            metadata.filename,
+            attempt,
+            max_attempts,
+            error,
+            backoff
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Ask an index provider to stop listing us for each of `files`, given with
+/// the unpublish token from publishing it
+///
+/// Each file is tried once, so an unreachable provider doesn't hold up
+/// shutdown for long; files that fail still drop out when their TTL expires.
+pub async fn unpublish_files<T: Transport>(
+    transport: &T,
+    index_provider: &NymAddress,
+    files: &[(ContentHash, Vec<u8>)],
+    our_address: &NymAddress,
+) -> PublishReport {
+    let mut report = PublishReport::default();
+    for (content_hash, token) in files {
+        let result = unpublish_from_index_provider(
+            transport,
+            index_provider,
+            content_hash,
+            our_address,
+            token,
+            UNPUBLISH_TIMEOUT,
+        )
+        .await;
+        match result {
+            Ok(()) => report.succeeded.push(*content_hash),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to unpublish {}: {}",
+                    brisby_core::hash_to_hex(content_hash),
+                    e
+                );
+                report.failed.push((*content_hash, e.to_string()));
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::proto::{self, Envelope, Payload};
+    use brisby_core::transport::mock::MockTransport;
+    use brisby_core::ReceivedMessage;
+    use tempfile::TempDir;
+
+    fn metadata(byte: u8, name: &str) -> FileMetadata {
+        FileMetadata {
+            content_hash: [byte; 32],
+            hash_algo: brisby_core::HashAlgorithm::default(),
+            filename: name.to_string(),
+            size: 10,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            chunks: vec![],
+            keywords: vec![],
+            created_at: 0,
+            encryption: None,
+        }
+    }
+
+    fn no_chunks(_: &ContentHash, _: u32) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn publish_response(success: bool) -> ReceivedMessage {
+        let envelope = Envelope::new(
+            0,
+            Payload::PublishResponse(proto::PublishResponse {
+                success,
+                error: if success { String::new() } else { "disk full".to_string() },
+                unpublish_token: if success { vec![7; 32] } else { Vec::new() },
+            }),
+        );
+        ReceivedMessage::new(envelope.to_bytes(), None)
+    }
+
+    #[tokio::test]
+    async fn test_resume_republishes_failed_entries_first() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_path = temp_dir.path().join("publish_state.json");
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let provider = NymAddress::new("index-provider");
+        let ours = NymAddress::new("our-address");
+
+        let files = [metadata(1, "a.txt"), metadata(2, "b.txt"), metadata(3, "c.txt")];
+        let refs: Vec<&FileMetadata> = files.iter().collect();
+        let retry = RetryPolicy::default();
+
+        // The second file fails mid-batch
+        transport.queue_message(publish_response(true));
+        transport.queue_message(publish_response(false));
+        transport.queue_message(publish_response(true));
+
+        let report = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, false, &state_path, &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.succeeded, vec![[1u8; 32], [3u8; 32]]);
+        assert_eq!(
+            report.unpublish_tokens,
+            vec![([1u8; 32], vec![7; 32]), ([3u8; 32], vec![7; 32])]
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, [2u8; 32]);
+        assert_eq!(transport.get_sent_messages().len(), 3);
+
+        let state = PublishState::load(&state_path).unwrap();
+        let pending = state.pending("index-provider").unwrap();
+        assert_eq!(pending.len(), 1);
+        assert!(pending.contains(&brisby_core::hash_to_hex(&[2u8; 32])));
+
+        // The next run retries the failed file first, then refreshes the
+        // others and gets a token for every file
+        for _ in 0..3 {
+            transport.queue_message(publish_response(true));
+        }
+        let report = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, false, &state_path, &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.succeeded, vec![[2u8; 32], [1u8; 32], [3u8; 32]]);
+        assert_eq!(report.unpublish_tokens.len(), 3);
+        assert!(report.is_complete());
+
+        let sent = transport.get_sent_messages();
+        assert_eq!(sent.len(), 6);
+        let req = Envelope::from_bytes(&sent[3].1).unwrap().into_publish_request().unwrap();
+        assert_eq!(req.filename, "b.txt");
+
+        // Nothing is pending any more
+        assert!(!state_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_publishes_files_shared_since() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_path = temp_dir.path().join("publish_state.json");
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let provider = NymAddress::new("index-provider");
+        let ours = NymAddress::new("our-address");
+        let retry = RetryPolicy::default();
+
+        // The first run leaves b.txt pending
+        let files = [metadata(1, "a.txt"), metadata(2, "b.txt")];
+        let refs: Vec<&FileMetadata> = files.iter().collect();
+        transport.queue_message(publish_response(true));
+        transport.queue_message(publish_response(false));
+        let report = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, false, &state_path, &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.succeeded, vec![[1u8; 32]]);
+
+        // c.txt is shared before the next run, which publishes it along
+        // with the others, b.txt first
+        let files = [metadata(1, "a.txt"), metadata(2, "b.txt"), metadata(3, "c.txt")];
+        let refs: Vec<&FileMetadata> = files.iter().collect();
+        for _ in 0..3 {
+            transport.queue_message(publish_response(true));
+        }
+        let report = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, false, &state_path, &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.succeeded, vec![[2u8; 32], [1u8; 32], [3u8; 32]]);
+        assert!(report.is_complete());
+
+        let sent = transport.get_sent_messages();
+        assert_eq!(sent.len(), 5);
+        let filenames: Vec<_> = sent[2..]
+            .iter()
+            .map(|(_, data)| Envelope::from_bytes(data).unwrap().into_publish_request().unwrap())
+            .map(|req| req.filename)
+            .collect();
+        assert_eq!(filenames, vec!["b.txt", "a.txt", "c.txt"]);
+        assert!(!state_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_lost_publish_is_retried() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_path = temp_dir.path().join("publish_state.json");
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let provider = NymAddress::new("index-provider");
+        let ours = NymAddress::new("our-address");
+        let files = [metadata(1, "a.txt"), metadata(2, "b.txt")];
+        let refs: Vec<&FileMetadata> = files.iter().collect();
+        let retry = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+
+        // The first reply is garbled in transit; the retry gets through. The
+        // second file's replies are lost both times.
+        transport.queue_message(ReceivedMessage::new(vec![0xff; 8], None));
+        transport.queue_message(publish_response(true));
+        transport.queue_message(ReceivedMessage::new(vec![0xff; 8], None));
+        transport.queue_message(ReceivedMessage::new(vec![0xff; 8], None));
+
+        let report = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, false, &state_path, &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.succeeded, vec![[1u8; 32]]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].1.contains("gave up after 2 attempts"));
+        assert_eq!(transport.get_sent_messages().len(), 4);
+
+        let default = RetryPolicy::default();
+        assert_eq!(default.backoff(1), Duration::from_secs(2));
+        assert_eq!(default.backoff(3), Duration::from_secs(8));
+        assert_eq!(default.backoff(10), default.max_backoff);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_publish_needs_relay_support() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_path = temp_dir.path().join("publish_state.json");
+
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let provider = NymAddress::new("index-provider");
+        let ours = NymAddress::new("our-address");
+        let files = [metadata(1, "a.txt")];
+        let refs: Vec<&FileMetadata> = files.iter().collect();
+
+        // A provider that predates capability negotiation can't relay
+        let legacy = proto::error_response(
+            0,
+            proto::error_codes::INVALID_MESSAGE,
+            "unexpected message type".to_string(),
+        );
+        transport.queue_message(ReceivedMessage::new(legacy.to_bytes(), None));
+
+        let retry = RetryPolicy::default();
+        let result = publish_files(
+            &transport, &provider, &refs, &no_chunks, &ours, true, &state_path, &retry,
+        )
+        .await;
+        assert!(result.is_err());
+        // Only the capabilities request went out; our address was never sent
+        let sent = transport.get_sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert!(Envelope::from_bytes(&sent[0].1)
+            .unwrap()
+            .as_capabilities_request()
+            .is_some());
+    }
+}
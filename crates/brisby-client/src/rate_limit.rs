@@ -0,0 +1,127 @@
+//! Upload rate limiting for the seeder
+//!
+//! A token bucket refilled at the configured rate, holding at most one
+//! second's worth of bytes. Sending takes tokens out of the bucket; when there
+//! aren't enough, the sender waits until the deficit has been refilled rather
+//! than having the reply dropped. A reply larger than the bucket can still be
+//! sent: the bucket goes into debt and later replies wait it off.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting bytes sent per second
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Refill rate, 0 for no limit
+    bytes_per_sec: u64,
+    /// Bytes that can be sent right away; negative while in debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        let capacity = self.bytes_per_sec as f64;
+        self.tokens = (self.tokens + elapsed * capacity).min(capacity);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_sec` (0 for no limit)
+    ///
+    /// The bucket starts full, so the first second's worth goes out at once.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Current limit in bytes per second, 0 if unlimited
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    /// Change the limit, keeping any debt already built up
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.bytes_per_sec = bytes_per_sec;
+        bucket.tokens = bucket.tokens.min(bytes_per_sec as f64);
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// sending them
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.bytes_per_sec == 0 {
+            return Duration::ZERO;
+        }
+        bucket.refill(now);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_sec as f64)
+        }
+    }
+
+    /// Wait until `bytes` may be sent under the limit
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            tracing::trace!("Rate limit: delaying {} bytes by {:?}", bytes, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paces_to_rate() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        limiter.bucket.lock().unwrap().last_refill = start;
+
+        // A full bucket covers the first second's worth
+        assert_eq!(limiter.reserve(600, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(400, start), Duration::ZERO);
+
+        // Then each send waits for its bytes to be refilled
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(500, start), Duration::from_secs(1));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.reserve(250, later), Duration::from_millis(250));
+
+        // Idle time refills the bucket, but never beyond one second's worth
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(1000, idle), Duration::ZERO);
+        assert_eq!(limiter.reserve(100, idle), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_rate_changes_at_runtime() {
+        let limiter = RateLimiter::new(0);
+        assert_eq!(limiter.reserve(1 << 30, Instant::now()), Duration::ZERO);
+
+        limiter.set_rate(100);
+        assert_eq!(limiter.rate(), 100);
+        let now = Instant::now();
+        let delay = limiter.reserve(300, now);
+        assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(3));
+
+        limiter.set_rate(0);
+        assert_eq!(limiter.reserve(300, now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,334 @@
+//! Writing downloaded chunks into the output file
+//!
+//! Chunks are written at their offset as they arrive, in any order, so a
+//! download never has to hold the whole file in memory. The content hash is
+//! computed while writing: chunks are fed to the hasher in index order, and
+//! one that arrives ahead of a gap is read back from the output file once the
+//! gap is filled. The final check then needs no second pass over the file.
+//!
+//! Chunks of an encrypted file are verified and hashed as they were sent,
+//! encrypted, and written out decrypted when the writer has the key.
+
+use anyhow::{anyhow, Result};
+use brisby_core::chunk::{check_chunk_key, decrypt_chunk, encrypt_chunk, ChunkKey};
+use brisby_core::hash::Hasher;
+use brisby_core::{ChunkEncryption, FileMetadata};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Output file being assembled from verified chunks
+pub struct ReassemblyWriter<'a> {
+    metadata: &'a FileMetadata,
+    path: PathBuf,
+    file: File,
+    hasher: Hasher,
+    /// First chunk not yet fed to the hasher
+    next_to_hash: u32,
+    /// Lengths of chunks written ahead of `next_to_hash`
+    written_ahead: BTreeMap<u32, usize>,
+    total_written: u64,
+    /// Decrypts chunks on their way to the file, if they are encrypted and
+    /// the key was given
+    decryption: Option<Decryption>,
+}
+
+impl<'a> ReassemblyWriter<'a> {
+    /// Create (or truncate) the output file at `path`
+    ///
+    /// With a `key`, an encrypted file is written out decrypted; fails if
+    /// the key is the wrong one or the file isn't encrypted. Without one,
+    /// it is written as it was sent.
+    pub fn new(path: &Path, metadata: &'a FileMetadata, key: Option<&ChunkKey>) -> Result<Self> {
+        let decryption = Decryption::for_file(metadata, key)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            metadata,
+            path: path.to_path_buf(),
+            file,
+            hasher: metadata.hash_algo.hasher(),
+            next_to_hash: 0,
+            written_ahead: BTreeMap::new(),
+            total_written: 0,
+            decryption,
+        })
+    }
+
+    /// Write one chunk at its offset in the file
+    ///
+    /// The chunk should already be verified against its hash. Each chunk can
+    /// be written once.
+    pub fn write_chunk(&mut self, chunk_index: u32, data: &[u8]) -> Result<()> {
+        let total_chunks = self.metadata.chunks.len() as u32;
+        if chunk_index >= total_chunks {
+            return Err(anyhow!(
+                "Chunk {} is out of range ({} chunks)",
+                chunk_index,
+                total_chunks
+            ));
+        }
+        if chunk_index < self.next_to_hash || self.written_ahead.contains_key(&chunk_index) {
+            return Err(anyhow!("Chunk {} written twice", chunk_index));
+        }
+
+        if let Some(expected) =
+            expected_size(self.metadata, chunk_index).filter(|&size| size != data.len())
+        {
+            return Err(anyhow!(
+                "Chunk {} is {} bytes, expected {}",
+                chunk_index,
+                data.len(),
+                expected
+            ));
+        }
+
+        tracing::trace!("Writing chunk {} ({} bytes)", chunk_index, data.len());
+        self.file
+            .seek(SeekFrom::Start(chunk_offset(self.metadata, chunk_index)))?;
+        match &self.decryption {
+            Some(decryption) => {
+                let mut plain = data.to_vec();
+                decryption.decrypt(chunk_index, &mut plain);
+                self.file.write_all(&plain)?;
+            }
+            None => self.file.write_all(data)?,
+        }
+        self.total_written += data.len() as u64;
+
+        if chunk_index != self.next_to_hash {
+            self.written_ahead.insert(chunk_index, data.len());
+            return Ok(());
+        }
+        self.hasher.update(data);
+        self.hasher.end_chunk();
+        self.next_to_hash += 1;
+        self.hash_written_ahead()
+    }
+
+    /// Catch the hash up with chunks that were written ahead of the next one
+    fn hash_written_ahead(&mut self) -> Result<()> {
+        while let Some(len) = self.written_ahead.remove(&self.next_to_hash) {
+            let mut buf = vec![0u8; len];
+            self.file
+                .seek(SeekFrom::Start(chunk_offset(self.metadata, self.next_to_hash)))?;
+            self.file.read_exact(&mut buf)?;
+            // The hash is of the chunks as sent
+            if let Some(decryption) = &self.decryption {
+                decryption.encrypt(self.next_to_hash, &mut buf);
+            }
+            self.hasher.update(&buf);
+            self.hasher.end_chunk();
+            self.next_to_hash += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Check the assembled file against the metadata
+    ///
+    /// Fails if any chunk is missing, or if the size or content hash doesn't
+    /// match; on a mismatch the output file is removed.
+    pub fn finalize(self) -> Result<()> {
+        let total_chunks = self.metadata.chunks.len() as u32;
+        if self.next_to_hash < total_chunks {
+            return Err(anyhow!(
+                "Missing chunk {} of {}",
+                self.next_to_hash,
+                total_chunks
+            ));
+        }
+
+        // Verify total size if the metadata included it
+        if self.metadata.size != 0 && self.total_written != self.metadata.size {
+            return Err(anyhow!(
+                "Size mismatch: expected {} bytes, wrote {} bytes",
+                self.metadata.size,
+                self.total_written
+            ));
+        }
+
+        self.file.sync_all()?;
+        drop(self.file);
+
+        if self.hasher.finalize() != self.metadata.content_hash {
+            std::fs::remove_file(&self.path)?;
+            return Err(anyhow!("Final file hash verification failed"));
+        }
+
+        tracing::info!(
+            "Successfully downloaded and verified {} ({} bytes)",
+            self.metadata.filename,
+            self.total_written
+        );
+
+        Ok(())
+    }
+}
+
+/// Key and parameters for decrypting an encrypted file's chunks
+#[derive(Clone, Copy)]
+pub(crate) struct Decryption {
+    key: ChunkKey,
+    encryption: ChunkEncryption,
+}
+
+impl Decryption {
+    /// How to decrypt `metadata`'s chunks with `key`, if it was given
+    ///
+    /// Fails if the key is the wrong one or the file isn't encrypted.
+    pub(crate) fn for_file(metadata: &FileMetadata, key: Option<&ChunkKey>) -> Result<Option<Self>> {
+        match (metadata.encryption, key) {
+            (_, None) => Ok(None),
+            (None, Some(_)) => Err(anyhow!("{} is not encrypted", metadata.filename)),
+            (Some(encryption), Some(key)) => {
+                check_chunk_key(key, &encryption)?;
+                Ok(Some(Self {
+                    key: *key,
+                    encryption,
+                }))
+            }
+        }
+    }
+
+    pub(crate) fn decrypt(&self, chunk_index: u32, data: &mut [u8]) {
+        decrypt_chunk(&self.key, &self.encryption, chunk_index, data);
+    }
+
+    fn encrypt(&self, chunk_index: u32, data: &mut [u8]) {
+        encrypt_chunk(&self.key, &self.encryption, chunk_index, data);
+    }
+}
+
+/// Size a chunk must have, if known
+///
+/// Offsets of later chunks assume a chunk has its recorded size, or a full
+/// chunk if the size is unknown and it isn't the last.
+fn expected_size(metadata: &FileMetadata, chunk_index: u32) -> Option<usize> {
+    let info = metadata.chunks.get(chunk_index as usize)?;
+    if info.size > 0 {
+        Some(info.size as usize)
+    } else if (chunk_index as usize) + 1 < metadata.chunks.len() {
+        Some(metadata.chunk_size as usize)
+    } else {
+        None
+    }
+}
+
+/// Byte offset of a chunk within the full file
+///
+/// Uses the recorded chunk sizes, falling back to the metadata's chunk size
+/// for chunks whose size is unknown.
+pub(crate) fn chunk_offset(metadata: &FileMetadata, chunk_index: u32) -> u64 {
+    metadata.chunks[..chunk_index as usize]
+        .iter()
+        .map(|c| {
+            if c.size > 0 {
+                c.size as u64
+            } else {
+                metadata.chunk_size as u64
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::CHUNK_SIZE;
+    use std::io::Write;
+
+    #[test]
+    fn test_chunks_written_out_of_order() {
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 4 - 100).map(|i| (i % 251) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+        assert_eq!(chunks.len(), 4);
+
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = ReassemblyWriter::new(output.path(), &metadata, None).unwrap();
+        for idx in [2u32, 0, 3] {
+            writer.write_chunk(idx, &chunks[idx as usize]).unwrap();
+        }
+        assert!(writer.write_chunk(0, &chunks[0]).is_err());
+        assert!(writer.write_chunk(4, &chunks[0]).is_err());
+        writer.write_chunk(1, &chunks[1]).unwrap();
+        writer.finalize().unwrap();
+
+        assert_eq!(std::fs::read(output.path()).unwrap(), content);
+    }
+
+    #[test]
+    fn test_incomplete_or_corrupt_file_rejected() {
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        source.write_all(&vec![9u8; CHUNK_SIZE + 10]).unwrap();
+        let (metadata, chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("out.bin");
+
+        let mut writer = ReassemblyWriter::new(&output, &metadata, None).unwrap();
+        writer.write_chunk(1, &chunks[1]).unwrap();
+        assert!(writer.finalize().is_err());
+
+        // Right sizes, wrong content: caught by the running hash
+        let mut writer = ReassemblyWriter::new(&output, &metadata, None).unwrap();
+        writer.write_chunk(0, &vec![8u8; CHUNK_SIZE]).unwrap();
+        writer.write_chunk(1, &chunks[1]).unwrap();
+        assert!(writer.finalize().is_err());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_encrypted_chunks_written_decrypted() {
+        use brisby_core::chunk::{chunk_encryption, derive_chunk_key};
+
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 - 7).map(|i| (i % 241) as u8).collect();
+        source.write_all(&content).unwrap();
+        let (plain, mut chunks) = brisby_core::chunk::chunk_file(source.path()).unwrap();
+
+        // Encrypt the chunks and describe them as a seeder would
+        let key = derive_chunk_key("reassembly");
+        let encryption = chunk_encryption(&key, [3; 8]);
+        let mut hasher = plain.hash_algo.hasher();
+        let mut metadata = plain.clone();
+        for (idx, chunk) in chunks.iter_mut().enumerate() {
+            encrypt_chunk(&key, &encryption, idx as u32, chunk);
+            hasher.update(chunk);
+            hasher.end_chunk();
+            metadata.chunks[idx].hash = plain.hash_algo.hash(chunk);
+        }
+        metadata.content_hash = hasher.finalize();
+        metadata.encryption = Some(encryption);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("out.bin");
+        let wrong = derive_chunk_key("wrong");
+        assert!(ReassemblyWriter::new(&output, &metadata, Some(&wrong)).is_err());
+        assert!(ReassemblyWriter::new(&output, &plain, Some(&key)).is_err());
+
+        // Chunks written ahead are re-encrypted to hash them
+        let mut writer = ReassemblyWriter::new(&output, &metadata, Some(&key)).unwrap();
+        writer.write_chunk(2, &chunks[2]).unwrap();
+        writer.write_chunk(1, &chunks[1]).unwrap();
+        writer.write_chunk(0, &chunks[0]).unwrap();
+        writer.finalize().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+
+        // Without the key the file is written as it was sent
+        let mut writer = ReassemblyWriter::new(&output, &metadata, None).unwrap();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            writer.write_chunk(idx as u32, chunk).unwrap();
+        }
+        writer.finalize().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), chunks.concat());
+    }
+}
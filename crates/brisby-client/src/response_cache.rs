@@ -0,0 +1,167 @@
+//! Cache of recently encoded chunk responses
+//!
+//! When several downloaders fetch the same hot chunk, the seeder would
+//! otherwise hash and encode an identical `ChunkResponse` for each of them.
+//! Encoded payloads are kept here, keyed by `(content_hash, chunk_index)`,
+//! and reused until evicted or the chunk stores change.
+
+use brisby_core::ContentHash;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Default number of encoded chunk responses kept
+pub const DEFAULT_RESPONSE_CACHE_ENTRIES: usize = 16;
+
+struct CachedResponse {
+    /// Store generation the response was encoded at
+    generation: u64,
+    /// Encoded payload field, see `Payload::encode_field`
+    payload: Arc<Vec<u8>>,
+    /// Tick of the last lookup, for LRU eviction
+    last_used: u64,
+}
+
+/// Size-limited LRU cache of encoded chunk responses
+pub struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<(ContentHash, u32), CachedResponse>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ResponseCache {
+    /// Create a cache holding at most `capacity` responses
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look up an encoded response
+    ///
+    /// Entries encoded at a different store `generation` are stale and
+    /// dropped, since the chunk may have changed underneath them.
+    pub fn get(
+        &mut self,
+        content_hash: &ContentHash,
+        chunk_index: u32,
+        generation: u64,
+    ) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let key = (*content_hash, chunk_index);
+
+        match self.entries.get_mut(&key) {
+            Some(entry) if entry.generation == generation => {
+                entry.last_used = self.tick;
+                self.hits += 1;
+                Some(entry.payload.clone())
+            }
+            Some(_) => {
+                self.entries.remove(&key);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Store an encoded response, evicting the least recently used if full
+    ///
+    /// Returns the payload that was evicted or replaced, if any, so its
+    /// buffer can be reused once nothing else holds it.
+    pub fn insert(
+        &mut self,
+        content_hash: &ContentHash,
+        chunk_index: u32,
+        generation: u64,
+        payload: Arc<Vec<u8>>,
+    ) -> Option<Arc<Vec<u8>>> {
+        if self.capacity == 0 {
+            return Some(payload);
+        }
+
+        let key = (*content_hash, chunk_index);
+        let mut evicted = None;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                evicted = self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        let replaced = self.entries.insert(
+            key,
+            CachedResponse {
+                generation,
+                payload,
+                last_used: self.tick,
+            },
+        );
+        replaced.or(evicted).map(|entry| entry.payload)
+    }
+
+    /// Number of lookups answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups that missed
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Number of cached responses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert(&[1u8; 32], 0, 0, Arc::new(vec![1]));
+        cache.insert(&[1u8; 32], 1, 0, Arc::new(vec![2]));
+
+        // Touch chunk 0 so chunk 1 is the eviction candidate
+        assert!(cache.get(&[1u8; 32], 0, 0).is_some());
+        let evicted = cache.insert(&[2u8; 32], 0, 0, Arc::new(vec![3]));
+        assert_eq!(evicted.as_deref(), Some(&vec![2]));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&[1u8; 32], 1, 0).is_none());
+        assert!(cache.get(&[1u8; 32], 0, 0).is_some());
+    }
+
+    #[test]
+    fn test_stale_generation_misses() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert(&[1u8; 32], 0, 5, Arc::new(vec![1]));
+
+        assert!(cache.get(&[1u8; 32], 0, 6).is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.hits(), 0);
+        assert_eq!(cache.misses(), 1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Client-side cache of recent search responses
+//!
+//! Repeated searches for the same term would otherwise cost a full mixnet
+//! round trip each time. Responses are cached in a small SQLite table keyed by
+//! normalized query and index provider, and served while they are younger than
+//! the configured TTL. For a while past the TTL they are still served, marked
+//! stale, so they can be shown at once and refreshed from the index provider
+//! afterwards (stale-while-revalidate).
+
+use brisby_core::SearchResult;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+
+/// Default time-to-live for cached search responses (5 minutes)
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+
+/// Default maximum number of cached search responses
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 100;
+
+/// Default time past the TTL that a cached response is still served while
+/// it is refreshed (1 hour)
+pub const DEFAULT_CACHE_MAX_STALE_SECS: u64 = 3600;
+
+/// Cached results for a search
+#[derive(Debug, Clone)]
+pub struct CachedSearch {
+    pub results: Vec<SearchResult>,
+    /// Older than the TTL, so the entry should be refreshed
+    pub stale: bool,
+}
+
+/// Persistent cache of search responses
+pub struct SearchCache {
+    conn: Connection,
+    /// How long a cached response stays fresh, in seconds
+    ttl_secs: u64,
+    /// How long past the TTL a cached response is still served as stale
+    max_stale_secs: u64,
+    /// Maximum number of cached responses before the least recently used are evicted
+    max_entries: usize,
+}
+
+impl SearchCache {
+    /// Open or create the search cache database
+    pub fn open(path: &std::path::Path, ttl_secs: u64, max_entries: usize) -> Result<Self> {
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS search_cache (
+                query TEXT NOT NULL,
+                provider TEXT NOT NULL,
+                max_results INTEGER NOT NULL,
+                results_json TEXT NOT NULL,
+                cached_at INTEGER NOT NULL,
+                last_used INTEGER NOT NULL,
+                PRIMARY KEY (query, provider)
+            );
+            "#,
+        )?;
+
+        Ok(Self {
+            conn,
+            ttl_secs,
+            max_stale_secs: DEFAULT_CACHE_MAX_STALE_SECS,
+            max_entries,
+        })
+    }
+
+    /// Serve responses for up to `secs` past their TTL, marked stale
+    ///
+    /// 0 stops serving responses once they are past the TTL.
+    pub fn with_max_stale(mut self, secs: u64) -> Self {
+        self.max_stale_secs = secs;
+        self
+    }
+
+    /// Normalize a query so trivially different spellings share a cache entry
+    ///
+    /// Lowercases the query and collapses runs of whitespace.
+    pub fn normalize_query(query: &str) -> String {
+        query
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Look up cached results for a query on a provider
+    ///
+    /// Entries older than the TTL are returned marked stale, until they are
+    /// past the TTL by more than the maximum staleness. Returns `None` if
+    /// nothing usable is cached, or the entry was cached with a smaller
+    /// `max_results` than requested.
+    pub fn get(
+        &self,
+        provider: &str,
+        query: &str,
+        max_results: u32,
+    ) -> Result<Option<CachedSearch>> {
+        let query = Self::normalize_query(query);
+        let now = unix_now();
+
+        let row: Option<(i64, String, i64)> = self
+            .conn
+            .query_row(
+                "SELECT max_results, results_json, cached_at FROM search_cache WHERE query = ? AND provider = ?",
+                params![query, provider],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let Some((cached_max, results_json, cached_at)) = row else {
+            return Ok(None);
+        };
+
+        let age = now.saturating_sub(cached_at as u64);
+        let stale = age >= self.ttl_secs;
+        let too_old = age >= self.ttl_secs.saturating_add(self.max_stale_secs);
+        if too_old || (cached_max as u32) < max_results {
+            return Ok(None);
+        }
+
+        let Ok(mut results) = serde_json::from_str::<Vec<SearchResult>>(&results_json) else {
+            return Ok(None);
+        };
+        results.truncate(max_results as usize);
+
+        self.conn.execute(
+            "UPDATE search_cache SET last_used = ? WHERE query = ? AND provider = ?",
+            params![now as i64, query, provider],
+        )?;
+
+        Ok(Some(CachedSearch { results, stale }))
+    }
+
+    /// Store the results of a search, evicting the least recently used entries
+    /// if the cache is over capacity
+    pub fn put(
+        &self,
+        provider: &str,
+        query: &str,
+        max_results: u32,
+        results: &[SearchResult],
+    ) -> Result<()> {
+        let query = Self::normalize_query(query);
+        let now = unix_now() as i64;
+        let results_json = serde_json::to_string(results).unwrap_or_default();
+
+        self.conn.execute(
+            r#"
+            INSERT OR REPLACE INTO search_cache
+            (query, provider, max_results, results_json, cached_at, last_used)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+            params![query, provider, max_results, results_json, now, now],
+        )?;
+
+        self.conn.execute(
+            r#"
+            DELETE FROM search_cache WHERE rowid NOT IN (
+                SELECT rowid FROM search_cache ORDER BY last_used DESC, rowid DESC LIMIT ?
+            )
+            "#,
+            params![self.max_entries as i64],
+        )?;
+
+        Ok(())
+    }
+
+    /// Number of cached responses
+    pub fn len(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM search_cache", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Check whether the cache is empty
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn result(name: &str) -> SearchResult {
+        SearchResult {
+            content_hash: [1u8; 32],
+            filename: name.to_string(),
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            relevance: 1.0,
+            seeders: vec!["seeder".to_string()],
+            relay_tokens: vec![],
+            seeder_count: 1,
+        }
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let temp = NamedTempFile::new().unwrap();
+        let cache = SearchCache::open(temp.path(), 60, 10).unwrap();
+
+        cache.put("provider", "Test  Query", 10, &[result("a.txt")]).unwrap();
+
+        let hit = cache.get("provider", "test query", 10).unwrap().unwrap();
+        assert!(!hit.stale);
+        assert_eq!(hit.results.len(), 1);
+        assert_eq!(hit.results[0].filename, "a.txt");
+
+        // Different provider misses
+        assert!(cache.get("other", "test query", 10).unwrap().is_none());
+        // Asking for more results than were cached misses
+        assert!(cache.get("provider", "test query", 20).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_expired_entry_served_stale() {
+        let temp = NamedTempFile::new().unwrap();
+        let cache = SearchCache::open(temp.path(), 0, 10).unwrap();
+
+        cache.put("provider", "query", 10, &[result("a.txt")]).unwrap();
+        let hit = cache.get("provider", "query", 10).unwrap().unwrap();
+        assert!(hit.stale);
+        assert_eq!(hit.results[0].filename, "a.txt");
+
+        // Past the maximum staleness too, it misses
+        let cache = cache.with_max_stale(0);
+        assert!(cache.get("provider", "query", 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_evicts_over_capacity() {
+        let temp = NamedTempFile::new().unwrap();
+        let cache = SearchCache::open(temp.path(), 60, 2).unwrap();
+
+        cache.put("provider", "one", 10, &[]).unwrap();
+        cache.put("provider", "two", 10, &[]).unwrap();
+        cache.put("provider", "three", 10, &[]).unwrap();
+
+        assert_eq!(cache.len().unwrap(), 2);
+    }
+}
@@ -2,12 +2,26 @@
 //!
 //! Handles storing chunks locally and responding to chunk requests over Nym.
 
-use anyhow::Result;
+use crate::chunk_cache::{ChunkCache, ChunkCacheStats};
+use crate::inspect::{chunk_status, ChunkStatus};
+use crate::metadata_file::{self, MetadataFormat};
+use crate::rate_limit::RateLimiter;
+use crate::response_cache::ResponseCache;
+use anyhow::{anyhow, Result};
 use brisby_core::proto::{self, Envelope, Payload};
-use brisby_core::{chunk::chunk_file, ContentHash, FileMetadata, ReceivedMessage, SenderTag, Transport};
-use std::collections::HashMap;
+use brisby_core::chunk::{
+    chunk_encryption, chunk_file_appended, chunk_file_streaming_with_strategy, compress_chunk,
+    encrypt_chunk, ChunkKey, ChunkingStrategy,
+};
+use brisby_core::merkle::MerkleTree;
+use brisby_core::{
+    ChunkInfo, ContentHash, FileMetadata, HashAlgorithm, ReceivedMessage, SenderTag, Transport,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 /// Chunk storage for seeding files
@@ -16,8 +30,72 @@ pub struct ChunkStore {
     storage_dir: PathBuf,
     /// In-memory cache of file metadata
     metadata: HashMap<ContentHash, FileMetadata>,
+    /// Merkle trees of files with Merkle content hashes, for chunk proofs
+    merkle_trees: HashMap<ContentHash, MerkleTree>,
     /// In-memory chunk cache (content_hash -> chunk_index -> chunk_data)
     chunks: HashMap<ContentHash, HashMap<u32, Vec<u8>>>,
+    /// Files loaded with chunks missing from disk
+    incomplete: HashSet<ContentHash>,
+    /// Bumped whenever stored chunks change
+    generation: u64,
+    /// Leave chunks on disk when loading files, see `new_lazy`
+    lazy: bool,
+    /// How newly added files are chunked
+    chunking: ChunkingStrategy,
+    /// Format metadata is written in, see `with_metadata_format`
+    metadata_format: MetadataFormat,
+    /// Chunks read from disk on demand because they weren't in memory
+    disk_reads: AtomicU64,
+    /// Chunks recently read from disk, see `with_chunk_cache`
+    chunk_cache: Mutex<ChunkCache>,
+    /// Files served from where they are instead of from copied chunks,
+    /// see `link_file`
+    linked: HashMap<ContentHash, LinkedFile>,
+    /// Key newly added files are encrypted under, see `with_encryption_key`
+    encryption_key: Option<ChunkKey>,
+}
+
+/// Name of the file recording where a linked file's content lives
+const SOURCE_FILE: &str = "source";
+
+/// Name of chunk `index`'s file within a file's directory
+fn chunk_file_name(index: u32) -> String {
+    format!("chunk_{:06}", index)
+}
+
+/// A file whose chunks are read from its original path
+struct LinkedFile {
+    path: PathBuf,
+    /// Byte offset of each chunk in the file
+    offsets: Vec<u64>,
+}
+
+impl LinkedFile {
+    fn new(path: PathBuf, metadata: &FileMetadata) -> Self {
+        let offsets = metadata
+            .chunks
+            .iter()
+            .scan(0u64, |offset, chunk| {
+                let start = *offset;
+                *offset += chunk.size as u64;
+                Some(start)
+            })
+            .collect();
+        Self { path, offsets }
+    }
+
+    /// Read the bytes chunk `chunk_index` was made from, as the file is now
+    fn read_range(&self, metadata: &FileMetadata, chunk_index: u32) -> Option<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let offset = *self.offsets.get(chunk_index as usize)?;
+        let size = metadata.chunks.get(chunk_index as usize)?.size;
+        let mut file = std::fs::File::open(&self.path).ok()?;
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        let mut data = Vec::with_capacity(size as usize);
+        file.take(size as u64).read_to_end(&mut data).ok()?;
+        Some(data)
+    }
 }
 
 impl ChunkStore {
@@ -26,44 +104,329 @@ impl ChunkStore {
         Self {
             storage_dir,
             metadata: HashMap::new(),
+            merkle_trees: HashMap::new(),
             chunks: HashMap::new(),
+            incomplete: HashSet::new(),
+            generation: 0,
+            lazy: false,
+            chunking: ChunkingStrategy::default(),
+            metadata_format: MetadataFormat::default(),
+            disk_reads: AtomicU64::new(0),
+            chunk_cache: Mutex::new(ChunkCache::new(0)),
+            linked: HashMap::new(),
+            encryption_key: None,
+        }
+    }
+
+    /// Create a chunk store that only loads metadata up front
+    ///
+    /// Chunks of loaded files stay on disk and are read per request by
+    /// `read_chunk` until `prewarm` pulls them into memory.
+    pub fn new_lazy(storage_dir: PathBuf) -> Self {
+        Self {
+            lazy: true,
+            ..Self::new(storage_dir)
         }
     }
 
+    /// Keep up to `max_bytes` of chunks read from disk in memory
+    ///
+    /// Only matters for chunks `read_chunk` has to fetch from disk, i.e.
+    /// those of a lazy store's files that weren't prewarmed. Off by default.
+    pub fn with_chunk_cache(mut self, max_bytes: usize) -> Self {
+        self.chunk_cache = Mutex::new(ChunkCache::new(max_bytes));
+        self
+    }
+
+    /// Chunk newly added files into chunks of `chunk_size` bytes
+    ///
+    /// Files already in the store keep the size they were added with.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        self.with_chunking(ChunkingStrategy::Fixed(chunk_size))
+    }
+
+    /// Chunk newly added files with `chunking`
+    ///
+    /// Files already in the store keep the chunking they were added with.
+    /// `append_file` only works on files with fixed-size chunks.
+    pub fn with_chunking(mut self, chunking: ChunkingStrategy) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Write file metadata in `format`
+    ///
+    /// With the binary format, metadata found in JSON is converted when it
+    /// is loaded. Binary metadata is read either way but left as it is.
+    pub fn with_metadata_format(mut self, format: MetadataFormat) -> Self {
+        self.metadata_format = format;
+        self
+    }
+
+    /// Encrypt newly added files under `key`
+    ///
+    /// Each file gets a fresh nonce, so adding the same file twice stores it
+    /// twice under different content hashes. Only peers given the key can
+    /// read what they download; everyone else, seeders included, only ever
+    /// sees the encrypted chunks. Linking and appending need the plaintext
+    /// on disk, so they're refused while a key is set.
+    pub fn with_encryption_key(mut self, key: ChunkKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Directory holding the chunks and metadata of one file
+    pub fn file_dir(&self, content_hash: &ContentHash) -> PathBuf {
+        self.storage_dir.join(brisby_core::hash_to_hex(content_hash))
+    }
+
+    fn chunk_path(&self, content_hash: &ContentHash, chunk_index: u32) -> PathBuf {
+        self.file_dir(content_hash).join(chunk_file_name(chunk_index))
+    }
+
     /// Add a file to the store
+    ///
+    /// The file is chunked as a stream, so only one chunk is held at a time
+    /// on top of what the store keeps in memory. A lazy store keeps nothing,
+    /// which makes it the way to share files larger than available memory.
     pub fn add_file(&mut self, path: &Path) -> Result<FileMetadata> {
-        // Chunk the file
-        let (metadata, chunks) = chunk_file(path)?;
+        let (metadata, chunk_map) = match self.encryption_key {
+            Some(key) => self.write_encrypted_chunks(path, &key)?,
+            None => self.write_chunks(path)?,
+        };
+        let file_dir = self.file_dir(&metadata.content_hash);
+
+        // Save metadata last, so a file is only picked up by `load_all` once
+        // all of its chunks are on disk. A copy replaces any link.
+        metadata_file::write(&file_dir, &metadata, self.metadata_format)?;
+        if self.linked.remove(&metadata.content_hash).is_some() {
+            std::fs::remove_file(file_dir.join(SOURCE_FILE))?;
+        }
 
-        // Store chunks in memory
+        self.chunk_cache.lock().unwrap().remove_file(&metadata.content_hash);
+        self.incomplete.remove(&metadata.content_hash);
+        if self.lazy {
+            self.chunks.remove(&metadata.content_hash);
+        } else {
+            self.chunks.insert(metadata.content_hash, chunk_map);
+        }
+        self.insert_metadata(metadata.clone());
+        self.generation += 1;
+
+        tracing::info!(
+            "Added file {} ({} chunks)",
+            metadata.filename,
+            metadata.chunks.len()
+        );
+
+        Ok(metadata)
+    }
+
+    /// Chunk a file into its directory, returning its metadata and the
+    /// chunks to keep in memory
+    fn write_chunks(&self, path: &Path) -> Result<(FileMetadata, HashMap<u32, Vec<u8>>)> {
+        let (metadata, chunks) = chunk_file_streaming_with_strategy(path, self.chunking)?;
+
+        let file_dir = self.file_dir(&metadata.content_hash);
+        std::fs::create_dir_all(&file_dir)?;
+
+        // Persist each chunk as it is read, keeping a copy only if this
+        // store serves from memory
+        let mut chunk_map = HashMap::new();
+        for (index, chunk) in chunks.enumerate() {
+            let chunk = chunk?;
+            std::fs::write(self.chunk_path(&metadata.content_hash, index as u32), &chunk)?;
+            if !self.lazy {
+                chunk_map.insert(index as u32, chunk);
+            }
+        }
+
+        Ok((metadata, chunk_map))
+    }
+
+    /// Like `write_chunks`, encrypting each chunk under `key`
+    ///
+    /// The content hash is of the encrypted chunks, so it's only known once
+    /// they're all written. They go into a staging directory first, which
+    /// is then moved to where the hash says.
+    fn write_encrypted_chunks(
+        &self,
+        path: &Path,
+        key: &ChunkKey,
+    ) -> Result<(FileMetadata, HashMap<u32, Vec<u8>>)> {
+        let (plain, chunks) = chunk_file_streaming_with_strategy(path, self.chunking)?;
+        let mut nonce = [0u8; 8];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|e| anyhow!("Failed to pick an encryption nonce: {}", e))?;
+        let encryption = chunk_encryption(key, nonce);
+
+        let staging_dir = self
+            .storage_dir
+            .join(format!(".staging-{}", hex::encode(nonce)));
+        std::fs::create_dir_all(&staging_dir)?;
+
+        let mut hasher = plain.hash_algo.hasher();
+        let mut chunk_infos = Vec::with_capacity(plain.chunks.len());
         let mut chunk_map = HashMap::new();
-        for (index, chunk) in chunks.into_iter().enumerate() {
-            chunk_map.insert(index as u32, chunk);
+        for (index, chunk) in chunks.enumerate() {
+            let index = index as u32;
+            let mut chunk = chunk?;
+            encrypt_chunk(key, &encryption, index, &mut chunk);
+            hasher.update(&chunk);
+            hasher.end_chunk();
+            chunk_infos.push(ChunkInfo {
+                index,
+                hash: plain.hash_algo.hash(&chunk),
+                size: chunk.len() as u32,
+            });
+            std::fs::write(staging_dir.join(chunk_file_name(index)), &chunk)?;
+            if !self.lazy {
+                chunk_map.insert(index, chunk);
+            }
         }
 
-        self.chunks.insert(metadata.content_hash, chunk_map);
-        self.metadata.insert(metadata.content_hash, metadata.clone());
+        let metadata = FileMetadata {
+            content_hash: hasher.finalize(),
+            chunks: chunk_infos,
+            encryption: Some(encryption),
+            ..plain
+        };
+        std::fs::rename(&staging_dir, self.file_dir(&metadata.content_hash))?;
+
+        Ok((metadata, chunk_map))
+    }
+
+    /// Add a file to the store without copying its chunks
+    ///
+    /// Only the metadata and the file's path are stored; chunks are read
+    /// from the file at their offsets whenever they are requested, which
+    /// saves duplicating large files already on disk. The file may move or
+    /// change after this, so every chunk read from it is checked against
+    /// the metadata and withheld if it doesn't match. A file already stored
+    /// with its chunks copied is left as it is.
+    pub fn link_file(&mut self, path: &Path) -> Result<FileMetadata> {
+        if self.encryption_key.is_some() {
+            return Err(anyhow!("Encrypted files can't be linked, only added"));
+        }
+        let path = path.canonicalize()?;
+        let (metadata, _) = chunk_file_streaming_with_strategy(&path, self.chunking)?;
+        if self.metadata.contains_key(&metadata.content_hash)
+            && !self.linked.contains_key(&metadata.content_hash)
+        {
+            return Ok(metadata);
+        }
 
-        // Also persist chunks to disk for durability
-        let file_dir = self.storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        let file_dir = self.file_dir(&metadata.content_hash);
         std::fs::create_dir_all(&file_dir)?;
+        let source = path
+            .to_str()
+            .ok_or_else(|| anyhow!("{} is not valid UTF-8", path.display()))?;
+        std::fs::write(file_dir.join(SOURCE_FILE), source)?;
+        metadata_file::write(&file_dir, &metadata, self.metadata_format)?;
+
+        self.chunk_cache.lock().unwrap().remove_file(&metadata.content_hash);
+        self.incomplete.remove(&metadata.content_hash);
+        self.chunks.remove(&metadata.content_hash);
+        self.linked.insert(metadata.content_hash, LinkedFile::new(path, &metadata));
+        self.insert_metadata(metadata.clone());
+        self.generation += 1;
 
-        // Save metadata
-        let metadata_path = file_dir.join("metadata.json");
-        let metadata_json = serde_json::to_string_pretty(&metadata)?;
-        std::fs::write(&metadata_path, metadata_json)?;
+        tracing::info!(
+            "Linked file {} ({} chunks)",
+            metadata.filename,
+            metadata.chunks.len()
+        );
+
+        Ok(metadata)
+    }
+
+    /// Whether a file's chunks are read from its original path, see
+    /// `link_file`
+    pub fn is_linked(&self, content_hash: &ContentHash) -> bool {
+        self.linked.contains_key(content_hash)
+    }
+
+    /// Update a stored file that has grown by appending
+    ///
+    /// The file at `path` must start with the content stored as `previous`.
+    /// Only the chunks past the unchanged prefix are read and written: the
+    /// new trailing chunks, plus the previously-final chunk if it was partial.
+    /// The file moves to its new content hash, replacing `previous` in the
+    /// store; if nothing was appended the metadata is returned unchanged.
+    pub fn append_file(&mut self, path: &Path, previous: &ContentHash) -> Result<FileMetadata> {
+        if self.linked.contains_key(previous) {
+            return Err(anyhow!(
+                "{} is linked rather than stored; link the grown file instead",
+                brisby_core::hash_to_hex(previous)
+            ));
+        }
+        let Some(previous) = self.metadata.get(previous).cloned() else {
+            return Err(anyhow!(
+                "{} is not in the store",
+                brisby_core::hash_to_hex(previous)
+            ));
+        };
+        if previous.encryption.is_some() {
+            return Err(anyhow!(
+                "{} is encrypted; add the grown file instead",
+                brisby_core::hash_to_hex(&previous.content_hash)
+            ));
+        }
+        let (metadata, first_new, chunks) = chunk_file_appended(path, &previous)?;
+        if metadata.content_hash == previous.content_hash {
+            return Ok(previous);
+        }
+        if self.metadata.contains_key(&metadata.content_hash) {
+            return Err(anyhow!(
+                "{} is already in the store",
+                brisby_core::hash_to_hex(&metadata.content_hash)
+            ));
+        }
+
+        // Move the unchanged chunks to the new content hash. The old metadata
+        // goes first, so an interrupted append never leaves a directory whose
+        // metadata doesn't match its name or chunks.
+        let old_dir = self.storage_dir.join(brisby_core::hash_to_hex(&previous.content_hash));
+        let new_dir = self.storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        metadata_file::remove(&old_dir)?;
+        std::fs::rename(&old_dir, &new_dir)?;
 
-        // Save chunks
-        if let Some(chunks) = self.chunks.get(&metadata.content_hash) {
-            for (index, data) in chunks {
-                let chunk_path = file_dir.join(format!("chunk_{:06}", index));
-                std::fs::write(&chunk_path, data)?;
+        // Chunks already in memory stay there, whether or not the store is lazy
+        let mut chunk_map = self.chunks.remove(&previous.content_hash);
+        if !self.lazy {
+            chunk_map.get_or_insert_with(HashMap::new);
+        }
+        if let Some(chunk_map) = &mut chunk_map {
+            chunk_map.retain(|index, _| *index < first_new);
+        }
+        for (offset, chunk) in chunks.enumerate() {
+            let chunk = chunk?;
+            let index = first_new + offset as u32;
+            std::fs::write(self.chunk_path(&metadata.content_hash, index), &chunk)?;
+            if let Some(chunk_map) = &mut chunk_map {
+                chunk_map.insert(index, chunk);
             }
         }
 
+        metadata_file::write(&new_dir, &metadata, self.metadata_format)?;
+
+        if let Some(chunk_map) = chunk_map {
+            self.chunks.insert(metadata.content_hash, chunk_map);
+        }
+        self.metadata.remove(&previous.content_hash);
+        self.merkle_trees.remove(&previous.content_hash);
+        self.chunk_cache.lock().unwrap().remove_file(&previous.content_hash);
+        // Appending rewrote the tail, but chunks missing before still are
+        if self.incomplete.remove(&previous.content_hash) {
+            self.incomplete.insert(metadata.content_hash);
+        }
+        self.insert_metadata(metadata.clone());
+        self.generation += 1;
+
         tracing::info!(
-            "Added file {} ({} chunks)",
+            "Appended to {} ({} of {} chunks new)",
             metadata.filename,
+            metadata.chunks.len() as u32 - first_new,
             metadata.chunks.len()
         );
 
@@ -71,32 +434,146 @@ impl ChunkStore {
     }
 
     /// Load a file's chunks from disk
+    ///
+    /// A lazy store loads only the metadata. A file stored under a flat
+    /// BLAKE3 hash, or under a Merkle root of the earlier kind that didn't
+    /// cover the chunk count and size, is moved over to a current Merkle
+    /// hash first, and loaded under that; see `migrate_to_merkle`.
     pub fn load_file(&mut self, content_hash: &ContentHash) -> Result<bool> {
         let file_dir = self.storage_dir.join(brisby_core::hash_to_hex(content_hash));
-        let metadata_path = file_dir.join("metadata.json");
-
-        if !metadata_path.exists() {
+        let Some((metadata, format)) = metadata_file::read(&file_dir)? else {
             return Ok(false);
+        };
+        let leaves: Vec<ContentHash> = metadata.chunks.iter().map(|chunk| chunk.hash).collect();
+        if !metadata.hash_algo.is_merkle()
+            || brisby_core::merkle::root(&leaves, metadata.size) != metadata.content_hash
+        {
+            return self.migrate_to_merkle(&file_dir, metadata, format);
+        }
+        if format == MetadataFormat::Json && self.metadata_format == MetadataFormat::Binary {
+            metadata_file::write(&file_dir, &metadata, MetadataFormat::Binary)?;
+            tracing::debug!("Converted metadata of {} to binary", metadata.filename);
+        }
+        let chunk_count = metadata.chunks.len() as u32;
+
+        self.chunks.remove(content_hash);
+        self.chunk_cache.lock().unwrap().remove_file(content_hash);
+        self.linked.remove(content_hash);
+        let source_path = file_dir.join(SOURCE_FILE);
+        if source_path.exists() {
+            // Linked files are always read on demand. One whose source has
+            // gone or changed size can't be served in full.
+            let source = PathBuf::from(std::fs::read_to_string(&source_path)?);
+            let linked = LinkedFile::new(source, &metadata);
+            let intact = std::fs::metadata(&linked.path).is_ok_and(|m| m.len() == metadata.size);
+            if intact {
+                self.incomplete.remove(content_hash);
+            } else {
+                tracing::warn!(
+                    "Source of linked file {} is missing or changed: {}",
+                    metadata.filename,
+                    linked.path.display()
+                );
+                self.incomplete.insert(*content_hash);
+            }
+            self.linked.insert(*content_hash, linked);
+            self.insert_metadata(metadata);
+            self.generation += 1;
+            return Ok(true);
         }
+        if (0..chunk_count).all(|i| self.chunk_path(content_hash, i).exists()) {
+            self.incomplete.remove(content_hash);
+        } else {
+            self.incomplete.insert(*content_hash);
+        }
+        self.insert_metadata(metadata);
+        if !self.lazy {
+            self.load_chunks(content_hash, chunk_count)?;
+        }
+        self.generation += 1;
+
+        Ok(true)
+    }
+
+    /// Move a file stored under a flat BLAKE3 hash or an outdated Merkle root
+    /// over to the Merkle root of its chunk hashes, and load it under that
+    ///
+    /// A flat hash can only be checked once the whole file is in, so
+    /// downloaders can't verify chunks of such a file as they arrive, and
+    /// index providers can't challenge its seeders for one. Earlier Merkle
+    /// roots hashed leaves like inner nodes and left out the chunk count and
+    /// size, so a chunk could pass for a subtree. The chunk hashes are the
+    /// same throughout, so nothing but the metadata is rewritten. The file
+    /// is published under its new hash from then on.
+    fn migrate_to_merkle(
+        &mut self,
+        file_dir: &Path,
+        mut metadata: FileMetadata,
+        format: MetadataFormat,
+    ) -> Result<bool> {
+        let old_hash = metadata.content_hash;
+        let leaves: Vec<ContentHash> = metadata.chunks.iter().map(|chunk| chunk.hash).collect();
+        metadata.hash_algo = HashAlgorithm::Blake3Merkle;
+        metadata.content_hash = brisby_core::merkle::root(&leaves, metadata.size);
+
+        let new_dir = self.file_dir(&metadata.content_hash);
+        if new_dir != file_dir {
+            if new_dir.exists() {
+                tracing::warn!(
+                    "Not loading {}: stored again under its Merkle hash {}",
+                    brisby_core::hash_to_hex(&old_hash),
+                    brisby_core::hash_to_hex(&metadata.content_hash)
+                );
+                return Ok(false);
+            }
+            std::fs::rename(file_dir, &new_dir)?;
+        }
+        metadata_file::write(&new_dir, &metadata, format)?;
+        tracing::info!(
+            "Moved {} from hash {} to Merkle hash {}",
+            metadata.filename,
+            brisby_core::hash_to_hex(&old_hash),
+            brisby_core::hash_to_hex(&metadata.content_hash)
+        );
 
-        // Load metadata
-        let metadata_json = std::fs::read_to_string(&metadata_path)?;
-        let metadata: FileMetadata = serde_json::from_str(&metadata_json)?;
+        self.load_file(&metadata.content_hash)
+    }
 
-        // Load chunks
+    /// Read a file's chunks that are present on disk into memory
+    fn load_chunks(&mut self, content_hash: &ContentHash, chunk_count: u32) -> Result<()> {
         let mut chunk_map = HashMap::new();
-        for i in 0..metadata.chunks.len() {
-            let chunk_path = file_dir.join(format!("chunk_{:06}", i));
+        for i in 0..chunk_count {
+            let chunk_path = self.chunk_path(content_hash, i);
             if chunk_path.exists() {
                 let data = std::fs::read(&chunk_path)?;
-                chunk_map.insert(i as u32, data);
+                chunk_map.insert(i, data);
             }
         }
 
         self.chunks.insert(*content_hash, chunk_map);
-        self.metadata.insert(*content_hash, metadata);
+        Ok(())
+    }
 
-        Ok(true)
+    /// Pull the chunks of already loaded files into memory
+    ///
+    /// Used with a lazy store so popular files are served from memory right
+    /// away. Unknown hashes and files already in memory are skipped. Returns
+    /// the number of files warmed.
+    pub fn prewarm(&mut self, content_hashes: &[ContentHash]) -> Result<usize> {
+        let mut warmed = 0;
+        for content_hash in content_hashes {
+            if self.chunks.contains_key(content_hash) || self.linked.contains_key(content_hash) {
+                continue;
+            }
+            let Some(metadata) = self.metadata.get(content_hash) else {
+                continue;
+            };
+            let chunk_count = metadata.chunks.len() as u32;
+            self.load_chunks(content_hash, chunk_count)?;
+            warmed += 1;
+        }
+
+        Ok(warmed)
     }
 
     /// Load all files from storage directory
@@ -106,20 +583,26 @@ impl ChunkStore {
             return Ok(0);
         }
 
-        let mut count = 0;
+        // Listed up front, since loading can rename directories
+        let mut hashes = Vec::new();
         for entry in std::fs::read_dir(&self.storage_dir)? {
             let entry = entry?;
             if entry.file_type()?.is_dir() {
                 let name = entry.file_name();
                 let name_str = name.to_string_lossy();
                 if let Ok(hash) = brisby_core::hex_to_hash(&name_str) {
-                    if self.load_file(&hash)? {
-                        count += 1;
-                    }
+                    hashes.push(hash);
                 }
             }
         }
 
+        let mut count = 0;
+        for hash in hashes {
+            if self.load_file(&hash)? {
+                count += 1;
+            }
+        }
+
         Ok(count)
     }
 
@@ -130,228 +613,1706 @@ impl ChunkStore {
             .and_then(|chunks| chunks.get(&chunk_index))
     }
 
+    /// Get a chunk from memory, falling back to disk for files not in memory
+    ///
+    /// Chunks read from disk go through the chunk cache, if one is set.
+    pub fn read_chunk(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<u8>> {
+        if let Some(chunks) = self.chunks.get(content_hash) {
+            return chunks.get(&chunk_index).cloned();
+        }
+
+        let metadata = self.metadata.get(content_hash)?;
+        if chunk_index as usize >= metadata.chunks.len() {
+            return None;
+        }
+        if let Some(data) = self.chunk_cache.lock().unwrap().get(content_hash, chunk_index) {
+            return Some(data);
+        }
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        let data = self.read_chunk_from_disk(content_hash, chunk_index)?;
+        if self.linked.contains_key(content_hash) {
+            // Nothing stops a linked file changing under us
+            let info = &metadata.chunks[chunk_index as usize];
+            if chunk_status(Some(&data), info, metadata.hash_algo) != ChunkStatus::Present {
+                tracing::warn!(
+                    "Chunk {} of linked file {} no longer matches its source, withholding it",
+                    chunk_index,
+                    metadata.filename
+                );
+                return None;
+            }
+        }
+        self.chunk_cache
+            .lock()
+            .unwrap()
+            .insert(content_hash, chunk_index, data.clone());
+        Some(data)
+    }
+
+    /// Read a chunk's file, bypassing any in-memory copy
+    ///
+    /// Chunks of linked files are read from the source file, unchecked.
+    pub fn read_chunk_from_disk(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<u8>> {
+        if let Some(linked) = self.linked.get(content_hash) {
+            return linked.read_range(self.metadata.get(content_hash)?, chunk_index);
+        }
+        std::fs::read(self.chunk_path(content_hash, chunk_index)).ok()
+    }
+
+    /// Check a chunk's file on disk against the metadata
+    ///
+    /// Returns `None` if the file isn't in the store.
+    pub fn verify_chunk_on_disk(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<ChunkStatus> {
+        let metadata = self.metadata.get(content_hash)?;
+        let info = metadata.chunks.get(chunk_index as usize)?;
+        let data = self.read_chunk_from_disk(content_hash, chunk_index);
+        Some(chunk_status(data.as_deref(), info, metadata.hash_algo))
+    }
+
+    /// Rewrite a chunk's file on disk from its in-memory copy
+    ///
+    /// Only an in-memory copy that still matches the metadata is written back,
+    /// returning `true`. A copy that doesn't match is dropped so it stops being
+    /// served, and `false` is returned.
+    pub fn repair_chunk(&mut self, content_hash: &ContentHash, chunk_index: u32) -> Result<bool> {
+        let Some(metadata) = self.metadata.get(content_hash) else {
+            return Ok(false);
+        };
+        let Some(info) = metadata.chunks.get(chunk_index as usize) else {
+            return Ok(false);
+        };
+        let Some(data) = self.get_chunk(content_hash, chunk_index) else {
+            return Ok(false);
+        };
+
+        if chunk_status(Some(data), info, metadata.hash_algo) == ChunkStatus::Present {
+            std::fs::write(self.chunk_path(content_hash, chunk_index), data)?;
+            self.chunk_cache.lock().unwrap().remove(content_hash, chunk_index);
+            return Ok(true);
+        }
+
+        if let Some(chunks) = self.chunks.get_mut(content_hash) {
+            chunks.remove(&chunk_index);
+        }
+        self.generation += 1;
+        Ok(false)
+    }
+
+    /// Number of chunks `read_chunk` had to read from disk
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of chunk data held in the chunk cache
+    pub fn chunk_cache_bytes(&self) -> usize {
+        self.chunk_cache.lock().unwrap().used_bytes()
+    }
+
+    /// Hits, misses and evictions of the chunk cache, and the bytes it holds
+    pub fn cache_stats(&self) -> ChunkCacheStats {
+        self.chunk_cache.lock().unwrap().stats()
+    }
+
+    /// Total size of the stored files
+    pub fn stored_bytes(&self) -> u64 {
+        self.metadata.values().map(|metadata| metadata.size).sum()
+    }
+
+    /// Remember a file's metadata, building its Merkle tree if it has one
+    fn insert_metadata(&mut self, metadata: FileMetadata) {
+        if metadata.hash_algo.is_merkle() {
+            let leaves: Vec<ContentHash> = metadata.chunks.iter().map(|c| c.hash).collect();
+            self.merkle_trees
+                .insert(metadata.content_hash, MerkleTree::new(&leaves, metadata.size));
+        } else {
+            self.merkle_trees.remove(&metadata.content_hash);
+        }
+        self.metadata.insert(metadata.content_hash, metadata);
+    }
+
+    /// Check whether every chunk of a stored file was on disk when it was
+    /// loaded; `false` for files not in the store
+    pub fn is_complete(&self, content_hash: &ContentHash) -> bool {
+        self.metadata.contains_key(content_hash) && !self.incomplete.contains(content_hash)
+    }
+
     /// Get metadata for a file
     pub fn get_metadata(&self, content_hash: &ContentHash) -> Option<&FileMetadata> {
         self.metadata.get(content_hash)
     }
 
+    /// Merkle proof for one chunk of a file, see `FileMetadata::merkle_proof`
+    pub fn merkle_proof(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<ContentHash>> {
+        self.merkle_trees.get(content_hash)?.proof(chunk_index)
+    }
+
+    /// Counter that changes whenever stored chunks change
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// List all stored files
     pub fn list_files(&self) -> Vec<&FileMetadata> {
         self.metadata.values().collect()
     }
 }
 
-/// Seeder service that handles incoming chunk requests
-pub struct Seeder {
-    store: Arc<RwLock<ChunkStore>>,
+/// Default cap on concurrent chunk responses for a single file
+pub const DEFAULT_MAX_IN_FLIGHT_PER_CONTENT: usize = 8;
+
+/// Default cap on concurrent chunk responses across all files
+pub const DEFAULT_MAX_IN_FLIGHT_TOTAL: usize = 32;
+
+/// Caps on concurrent chunk responses
+///
+/// Requests beyond either cap are answered with an `UNAVAILABLE` error so the
+/// downloader retries later, keeping one popular file from starving the rest.
+#[derive(Debug, Clone, Copy)]
+pub struct SeederLimits {
+    /// Maximum concurrent responses for any one content hash
+    pub max_per_content: usize,
+    /// Maximum concurrent responses overall
+    pub max_total: usize,
 }
 
-impl Seeder {
-    /// Create a new seeder
-    pub fn new(store: ChunkStore) -> Self {
+impl Default for SeederLimits {
+    fn default() -> Self {
         Self {
-            store: Arc::new(RwLock::new(store)),
+            max_per_content: DEFAULT_MAX_IN_FLIGHT_PER_CONTENT,
+            max_total: DEFAULT_MAX_IN_FLIGHT_TOTAL,
         }
     }
+}
 
-    /// Get access to the chunk store
-    pub fn store(&self) -> &Arc<RwLock<ChunkStore>> {
-        &self.store
-    }
+/// Chunk responses currently being served
+#[derive(Default)]
+struct InFlight {
+    total: usize,
+    per_content: HashMap<ContentHash, usize>,
+}
 
-    /// Handle an incoming message
-    pub async fn handle_message(&self, msg: &ReceivedMessage) -> Option<(SenderTag, Vec<u8>)> {
-        let sender_tag = msg.sender_tag.as_ref()?;
+/// Releases an in-flight slot when dropped
+///
+/// Travels with the reply it was claimed for, so the slot stays taken until
+/// `run_seeder_loop` has sent the reply, not just built it.
+struct InFlightGuard {
+    in_flight: Arc<Mutex<InFlight>>,
+    content_hash: ContentHash,
+}
 
-        let envelope = match Envelope::from_bytes(&msg.data) {
-            Ok(env) => env,
-            Err(e) => {
-                tracing::warn!("Failed to decode message: {}", e);
-                let response = proto::error_response(
-                    0,
-                    proto::error_codes::INVALID_MESSAGE,
-                    format!("decode error: {}", e),
-                );
-                return Some((sender_tag.clone(), response.to_bytes()));
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.total -= 1;
+        if let Some(count) = in_flight.per_content.get_mut(&self.content_hash) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.per_content.remove(&self.content_hash);
             }
-        };
+        }
+    }
+}
 
-        let request_id = envelope.request_id;
-        let response = match envelope.payload {
-            Some(Payload::ChunkRequest(req)) => {
-                self.handle_chunk_request(request_id, req).await
-            }
-            Some(Payload::PingRequest(_)) => {
-                proto::Envelope::new(
-                    request_id,
-                    Payload::PingResponse(proto::PingResponse {
-                        responder_id: vec![], // Empty for now, could use node ID
-                    }),
-                )
-            }
-            Some(other) => {
-                tracing::warn!("Unexpected message type: {:?}", other);
-                proto::error_response(
-                    request_id,
-                    proto::error_codes::INVALID_MESSAGE,
-                    "unexpected message type".to_string(),
-                )
-            }
-            None => {
-                proto::error_response(
-                    request_id,
-                    proto::error_codes::INVALID_MESSAGE,
-                    "empty payload".to_string(),
-                )
-            }
-        };
+/// A reply ready to send, with the in-flight slot it holds until sent
+struct Reply {
+    sender_tag: SenderTag,
+    bytes: Vec<u8>,
+    in_flight: Option<InFlightGuard>,
+}
+
+impl Reply {
+    /// A reply that doesn't count against the in-flight caps
+    fn new(sender_tag: SenderTag, bytes: Vec<u8>) -> Self {
+        Self {
+            sender_tag,
+            bytes,
+            in_flight: None,
+        }
+    }
+}
+
+/// Scratch buffers for encoding chunk responses, shared by handler tasks
+///
+/// Each response is encoded into a buffer taken from here and handed back
+/// once the message has been built, so the payload-sized buffer is reused
+/// instead of allocated per response.
+#[derive(Default)]
+struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Idle buffers a `BufferPool` keeps; more are dropped when returned
+const MAX_POOLED_BUFFERS: usize = 8;
 
-        Some((sender_tag.clone(), response.to_bytes()))
+impl BufferPool {
+    /// Take a buffer, allocating a new one if none are free
+    fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
     }
 
-    /// Handle a chunk request
-    async fn handle_chunk_request(
-        &self,
-        request_id: u64,
-        req: proto::ChunkRequest,
-    ) -> Envelope {
-        // Validate content hash
-        if req.content_hash.len() != 32 {
-            return proto::error_response(
-                request_id,
-                proto::error_codes::INVALID_DATA,
-                "invalid content hash length".to_string(),
-            );
+    /// Hand a buffer back for reuse
+    fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buf);
         }
+    }
+}
 
-        let mut content_hash = [0u8; 32];
-        content_hash.copy_from_slice(&req.content_hash);
+/// Largest message the seeder will decode
+///
+/// Seeders only answer chunk requests, range requests and pings, which carry
+/// no reply SURBs and are all far smaller than this, so anything bigger is
+/// junk and is dropped unread.
+pub const MAX_REQUEST_SIZE: usize = 4 * 1024;
 
-        tracing::info!(
-            "Chunk request: {} chunk {}",
-            &brisby_core::hash_to_hex(&content_hash)[..8],
-            req.chunk_index
-        );
+/// Requests `run_seeder_loop` accepts before it waits for their replies to be
+/// sent
+pub const MAX_PENDING_REPLIES: usize = 64;
 
-        let store = self.store.read().await;
+/// Default number of chunks checked per scheduled verification pass
+pub const DEFAULT_VERIFY_BATCH_SIZE: usize = 64;
 
-        // Get the chunk
-        match store.get_chunk(&content_hash, req.chunk_index) {
-            Some(data) => {
-                // Compute chunk hash
-                let chunk_hash = *blake3::hash(data).as_bytes();
+/// Default seconds between serve statistics summaries printed while seeding
+pub const DEFAULT_STATS_INTERVAL_SECS: u64 = 60;
 
-                tracing::debug!(
-                    "Sending chunk {} ({} bytes)",
-                    req.chunk_index,
-                    data.len()
-                );
+/// Results of scheduled chunk verification so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyStats {
+    /// Chunks checked against their hashes
+    pub chunks_verified: u64,
+    /// Corrupt chunks rewritten from an intact in-memory copy
+    pub chunks_repaired: u64,
+    /// Chunks found corrupt and not repaired, which are no longer served
+    pub corrupt_chunks: Vec<(ContentHash, u32)>,
+}
 
-                Envelope::new(
-                    request_id,
-                    Payload::ChunkResponse(proto::ChunkResponse {
-                        content_hash: content_hash.to_vec(),
-                        chunk_index: req.chunk_index,
-                        data: data.clone(),
-                        chunk_hash: chunk_hash.to_vec(),
-                    }),
-                )
-            }
+/// Demand for one file, as recorded in dry-run mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDemand {
+    pub content_hash: ContentHash,
+    /// Chunk requests received
+    pub requests: u64,
+    /// Distinct sender tags the requests came from
+    pub distinct_requesters: usize,
+}
+
+/// What a seeder has served, as returned by `Seeder::metrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeederMetrics {
+    /// Chunk and range requests received
+    pub chunk_requests: u64,
+    /// Chunks sent, counting each chunk of a range response
+    pub chunks_served: u64,
+    /// Reply bytes sent by `run_seeder_loop`
+    pub bytes_served: u64,
+    /// Requests for chunks or files we don't have
+    pub not_found: u64,
+    /// Chunk requests received per file we hold
+    pub requests_per_file: HashMap<ContentHash, u64>,
+}
+
+impl SeederMetrics {
+    /// One-line summary, as printed periodically by `brisby seed`
+    pub fn summary(&self) -> String {
+        format!(
+            "{} requests, {} chunks served ({} bytes), {} not found, {} files requested",
+            self.chunk_requests,
+            self.chunks_served,
+            self.bytes_served,
+            self.not_found,
+            self.requests_per_file.len()
+        )
+    }
+}
+
+/// Seeder service that handles incoming chunk requests
+pub struct Seeder {
+    /// Chunk stores consulted in order (e.g. fast cache before bulk archive)
+    stores: Vec<Arc<RwLock<ChunkStore>>>,
+    /// Caps on concurrent chunk responses
+    limits: SeederLimits,
+    /// Chunk responses currently being served
+    in_flight: Arc<Mutex<InFlight>>,
+    /// Recently encoded chunk responses, if enabled
+    response_cache: Option<Mutex<ResponseCache>>,
+    /// Reusable buffers for encoding chunk responses
+    encode_buffers: BufferPool,
+    /// Messages dropped by `precheck` without a full decode
+    dropped_before_decode: AtomicU64,
+    /// Serve statistics; per-file request counts also find the hot set
+    metrics: Arc<Mutex<SeederMetrics>>,
+    /// Log and count chunk requests without sending any chunk data
+    dry_run: bool,
+    /// Serve files with chunks missing, see `with_serve_incomplete`
+    serve_incomplete: bool,
+    /// Compress chunk data where it helps, see `with_compression`
+    compress: bool,
+    /// Sender tags seen per file in dry-run mode
+    requesters: Mutex<HashMap<ContentHash, HashSet<SenderTag>>>,
+    /// Position of the next scheduled verification pass among all chunks
+    verify_cursor: Mutex<usize>,
+    /// Results of scheduled verification
+    verify_stats: Mutex<VerifyStats>,
+    /// Paces replies sent by `run_seeder_loop`
+    rate_limiter: RateLimiter,
+}
+
+impl Seeder {
+    /// Create a new seeder
+    pub fn new(store: ChunkStore) -> Self {
+        Self::from_stores(vec![store])
+    }
+
+    /// Create a seeder that answers from several chunk stores
+    ///
+    /// Stores are consulted in order and the first one holding the requested
+    /// chunk answers, so faster tiers should come first. Fails if `stores`
+    /// is empty.
+    pub fn with_stores(stores: Vec<ChunkStore>) -> Result<Self> {
+        if stores.is_empty() {
+            return Err(anyhow!("Seeder needs at least one chunk store"));
+        }
+        Ok(Self::from_stores(stores))
+    }
+
+    fn from_stores(stores: Vec<ChunkStore>) -> Self {
+        Self {
+            stores: stores
+                .into_iter()
+                .map(|store| Arc::new(RwLock::new(store)))
+                .collect(),
+            limits: SeederLimits::default(),
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
+            response_cache: None,
+            encode_buffers: BufferPool::default(),
+            dropped_before_decode: AtomicU64::new(0),
+            metrics: Arc::new(Mutex::new(SeederMetrics::default())),
+            dry_run: false,
+            serve_incomplete: true,
+            compress: false,
+            requesters: Mutex::new(HashMap::new()),
+            verify_cursor: Mutex::new(0),
+            verify_stats: Mutex::new(VerifyStats::default()),
+            rate_limiter: RateLimiter::new(0),
+        }
+    }
+
+    /// Cache up to `capacity` encoded chunk responses (0 disables the cache)
+    pub fn with_response_cache(mut self, capacity: usize) -> Self {
+        self.response_cache = (capacity > 0).then(|| Mutex::new(ResponseCache::new(capacity)));
+        self
+    }
+
+    /// Set the caps on concurrent chunk responses
+    pub fn with_limits(mut self, limits: SeederLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Cap upload at `bytes_per_sec` (0 for no limit)
+    ///
+    /// `run_seeder_loop` delays replies, never drops them, to stay under the
+    /// cap on average. Bursts of up to a second's worth go out at once.
+    pub fn with_rate_limit(self, bytes_per_sec: u64) -> Self {
+        self.set_rate_limit(bytes_per_sec);
+        self
+    }
+
+    /// Change the upload cap of a running seeder (0 for no limit)
+    pub fn set_rate_limit(&self, bytes_per_sec: u64) {
+        self.rate_limiter.set_rate(bytes_per_sec);
+    }
+
+    /// Current upload cap in bytes per second, 0 if unlimited
+    pub fn rate_limit(&self) -> u64 {
+        self.rate_limiter.rate()
+    }
+
+    /// Record chunk requests without serving them
+    ///
+    /// Requests for files we hold are counted and answered with an
+    /// `UNAVAILABLE` "serving disabled" error instead of chunk data, so demand
+    /// can be measured before committing upload bandwidth.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Whether to serve chunks of files we only hold in part (the default)
+    ///
+    /// When off, requests for any chunk of an incomplete file are answered
+    /// `NOT_FOUND`, as if the file weren't held at all, so downloaders don't
+    /// spend requests on a seeder that can't give them the whole file.
+    pub fn with_serve_incomplete(mut self, serve_incomplete: bool) -> Self {
+        self.serve_incomplete = serve_incomplete;
+        self
+    }
+
+    /// Whether to zstd-compress chunk data before sending it (off by default)
+    ///
+    /// Chunks that don't get smaller, such as those of media files, are sent
+    /// as they are. Worth it for text and documents when upload bandwidth
+    /// is scarcer than CPU; downloaders decompress before checking hashes.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Claim an in-flight slot for `content_hash`, or `None` if a cap is reached
+    fn try_begin(&self, content_hash: &ContentHash) -> Option<InFlightGuard> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let for_content = in_flight.per_content.get(content_hash).copied().unwrap_or(0);
+        if in_flight.total >= self.limits.max_total || for_content >= self.limits.max_per_content {
+            return None;
+        }
+
+        in_flight.total += 1;
+        *in_flight.per_content.entry(*content_hash).or_insert(0) += 1;
+        Some(InFlightGuard {
+            in_flight: self.in_flight.clone(),
+            content_hash: *content_hash,
+        })
+    }
+
+    /// Count a chunk request for a file we hold; only those are counted per
+    /// file, so requests for made-up hashes can't grow the table
+    fn record_request(&self, content_hash: &ContentHash) {
+        *self
+            .metrics
+            .lock()
+            .unwrap()
+            .requests_per_file
+            .entry(*content_hash)
+            .or_insert(0) += 1;
+    }
+
+    /// Count a request answered with `chunks` chunks
+    fn record_served(&self, content_hash: &ContentHash, chunks: u64) {
+        self.record_request(content_hash);
+        self.metrics.lock().unwrap().chunks_served += chunks;
+    }
+
+    /// Count a request for something we don't have and refuse it
+    fn not_found(&self, request_id: u64) -> Vec<u8> {
+        self.metrics.lock().unwrap().not_found += 1;
+        proto::error_response(
+            request_id,
+            proto::error_codes::NOT_FOUND,
+            "chunk not found".to_string(),
+        )
+        .to_bytes()
+    }
+
+    /// Snapshot of what has been served so far
+    pub fn metrics(&self) -> SeederMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// The `n` most requested files, most requested first
+    pub fn hottest(&self, n: usize) -> Vec<ContentHash> {
+        let metrics = self.metrics.lock().unwrap();
+        let mut ranked: Vec<_> = metrics.requests_per_file.iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        ranked.into_iter().take(n).map(|(hash, _)| *hash).collect()
+    }
+
+    /// Demand recorded in dry-run mode, most requested first
+    pub fn demand(&self) -> Vec<FileDemand> {
+        let metrics = self.metrics.lock().unwrap();
+        let requesters = self.requesters.lock().unwrap();
+        let mut demand: Vec<_> = requesters
+            .iter()
+            .map(|(hash, tags)| FileDemand {
+                content_hash: *hash,
+                requests: metrics.requests_per_file.get(hash).copied().unwrap_or(0),
+                distinct_requesters: tags.len(),
+            })
+            .collect();
+        demand.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then(a.content_hash.cmp(&b.content_hash))
+        });
+        demand
+    }
+
+    /// Results of scheduled verification so far
+    pub fn verify_stats(&self) -> VerifyStats {
+        self.verify_stats.lock().unwrap().clone()
+    }
+
+    fn is_corrupt(&self, content_hash: &ContentHash, chunk_index: u32) -> bool {
+        self.verify_stats
+            .lock()
+            .unwrap()
+            .corrupt_chunks
+            .contains(&(*content_hash, chunk_index))
+    }
+
+    /// Check the next `batch_size` chunks on disk, repairing what can be repaired
+    ///
+    /// Successive calls rotate through every chunk of every store, so a long
+    /// running seeder eventually checks all of them. Corrupt chunks are
+    /// rewritten from an intact in-memory copy if there is one; otherwise they
+    /// are recorded in `verify_stats` and no longer served. Returns the chunks
+    /// found corrupt in this batch.
+    pub async fn verify_batch(&self, batch_size: usize) -> Vec<(ContentHash, u32)> {
+        let mut all = BTreeSet::new();
+        for store in &self.stores {
+            for metadata in store.read().await.list_files() {
+                all.extend((0..metadata.chunks.len() as u32).map(|i| (metadata.content_hash, i)));
+            }
+        }
+        if all.is_empty() {
+            return Vec::new();
+        }
+
+        let all: Vec<_> = all.into_iter().collect();
+        let batch: Vec<_> = {
+            let mut cursor = self.verify_cursor.lock().unwrap();
+            let start = *cursor % all.len();
+            let take = batch_size.min(all.len());
+            *cursor = start + take;
+            all.iter().cycle().skip(start).take(take).copied().collect()
+        };
+
+        let mut found = Vec::new();
+        for (content_hash, chunk_index) in batch {
+            let Some(status) = self.verify_on_disk(&content_hash, chunk_index).await else {
+                continue;
+            };
+            self.verify_stats.lock().unwrap().chunks_verified += 1;
+
+            let key = (content_hash, chunk_index);
+            if status != ChunkStatus::Corrupt {
+                self.verify_stats.lock().unwrap().corrupt_chunks.retain(|c| c != &key);
+                continue;
+            }
+
+            tracing::warn!(
+                "Chunk {} of {} is corrupt on disk",
+                chunk_index,
+                &brisby_core::hash_to_hex(&content_hash)[..8]
+            );
+            found.push(key);
+            match self.repair(&content_hash, chunk_index).await {
+                Ok(true) => {
+                    tracing::info!("Repaired chunk {} from memory", chunk_index);
+                    let mut stats = self.verify_stats.lock().unwrap();
+                    stats.chunks_repaired += 1;
+                    stats.corrupt_chunks.retain(|c| c != &key);
+                }
+                Ok(false) => {
+                    let mut stats = self.verify_stats.lock().unwrap();
+                    if !stats.corrupt_chunks.contains(&key) {
+                        stats.corrupt_chunks.push(key);
+                    }
+                }
+                Err(e) => tracing::error!("Failed to repair chunk {}: {}", chunk_index, e),
+            }
+        }
+
+        found
+    }
+
+    /// Disk status of a chunk in the first store that has its file
+    async fn verify_on_disk(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<ChunkStatus> {
+        for store in &self.stores {
+            if let Some(status) = store.read().await.verify_chunk_on_disk(content_hash, chunk_index) {
+                return Some(status);
+            }
+        }
+        None
+    }
+
+    /// Repair a chunk in the first store that has its file
+    async fn repair(&self, content_hash: &ContentHash, chunk_index: u32) -> Result<bool> {
+        for store in &self.stores {
+            let mut store = store.write().await;
+            if store.get_metadata(content_hash).is_some() {
+                return store.repair_chunk(content_hash, chunk_index);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Number of messages dropped as oversized or of the wrong type before decoding
+    pub fn dropped_before_decode(&self) -> u64 {
+        self.dropped_before_decode.load(Ordering::Relaxed)
+    }
+
+    /// Cheap checks run before a full decode
+    ///
+    /// Only the size and the envelope header are looked at, so a flood of
+    /// large or irrelevant messages costs neither payload decoding nor
+    /// allocation.
+    fn precheck(data: &[u8]) -> std::result::Result<(), String> {
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err(format!("{} bytes exceeds {}", data.len(), MAX_REQUEST_SIZE));
+        }
+
+        let header = Envelope::peek_header(data).map_err(|e| e.to_string())?;
+        match header.payload_tag {
+            Some(
+                proto::payload_tags::CHUNK_REQUEST
+                | proto::payload_tags::CHUNK_RANGE_REQUEST
+                | proto::payload_tags::PING_REQUEST,
+            ) => Ok(()),
+            Some(tag) => Err(format!("unexpected payload tag {}", tag)),
+            None => Err("empty payload".to_string()),
+        }
+    }
+
+    /// Get access to the primary chunk store
+    pub fn store(&self) -> &Arc<RwLock<ChunkStore>> {
+        &self.stores[0]
+    }
+
+    /// Get access to all chunk stores, in lookup order
+    pub fn stores(&self) -> &[Arc<RwLock<ChunkStore>>] {
+        &self.stores
+    }
+
+    /// Combined generation of all chunk stores; changes whenever any store does
+    async fn generation(&self) -> u64 {
+        let mut generation = 0;
+        for store in &self.stores {
+            generation += store.read().await.generation();
+        }
+        generation
+    }
+
+    /// Get a chunk from the first store that has it
+    pub async fn get_chunk(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<u8>> {
+        for store in &self.stores {
+            if let Some(data) = store.read().await.read_chunk(content_hash, chunk_index) {
+                return Some(data);
+            }
+        }
+        None
+    }
+
+    /// Hash algorithm of a file, from the first store that has it
+    async fn hash_algo(&self, content_hash: &ContentHash) -> Option<HashAlgorithm> {
+        for store in &self.stores {
+            if let Some(metadata) = store.read().await.get_metadata(content_hash) {
+                return Some(metadata.hash_algo);
+            }
+        }
+        None
+    }
+
+    /// Merkle proof for a chunk, from the first store that has one
+    async fn merkle_proof(&self, content_hash: &ContentHash, chunk_index: u32) -> Option<Vec<ContentHash>> {
+        for store in &self.stores {
+            if let Some(proof) = store.read().await.merkle_proof(content_hash, chunk_index) {
+                return Some(proof);
+            }
+        }
+        None
+    }
+
+    /// Check whether any store holds every chunk of a file
+    pub async fn is_complete(&self, content_hash: &ContentHash) -> bool {
+        for store in &self.stores {
+            if store.read().await.is_complete(content_hash) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether chunks of a file may be served, see `with_serve_incomplete`
+    async fn may_serve(&self, content_hash: &ContentHash) -> bool {
+        self.serve_incomplete || self.is_complete(content_hash).await
+    }
+
+    /// Get file metadata from the first store that has it
+    pub async fn get_metadata(&self, content_hash: &ContentHash) -> Option<FileMetadata> {
+        for store in &self.stores {
+            if let Some(metadata) = store.read().await.get_metadata(content_hash) {
+                return Some(metadata.clone());
+            }
+        }
+        None
+    }
+
+    /// Handle an incoming message, returning the reply and the sender tag
+    /// to send it to
+    ///
+    /// Messages without a sender tag go unanswered. The in-flight slot a
+    /// chunk reply takes is given back on return; `run_seeder_loop` keeps it
+    /// until the reply has been sent.
+    pub async fn handle_message(&self, msg: &ReceivedMessage) -> Option<(SenderTag, Vec<u8>)> {
+        let reply = self.handle(msg).await?;
+        Some((reply.sender_tag, reply.bytes))
+    }
+
+    /// `handle_message`, keeping the reply's in-flight slot
+    async fn handle(&self, msg: &ReceivedMessage) -> Option<Reply> {
+        let sender_tag = msg.sender_tag.as_ref()?;
+
+        if let Err(reason) = Self::precheck(&msg.data) {
+            self.dropped_before_decode.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("Dropping message before decode: {}", reason);
+            return None;
+        }
+
+        let envelope = match Envelope::from_bytes(&msg.data) {
+            Ok(env) => env,
+            Err(e) => {
+                tracing::warn!("Failed to decode message: {}", e);
+                let response = proto::error_response(
+                    0,
+                    proto::error_codes::INVALID_MESSAGE,
+                    format!("decode error: {}", e),
+                );
+                return Some(Reply::new(sender_tag.clone(), response.to_bytes()));
+            }
+        };
+
+        let request_id = envelope.request_id;
+        let response = match envelope.payload {
+            Some(Payload::ChunkRequest(req)) => {
+                // Chunk responses come back already encoded so they can be cached
+                let (bytes, in_flight) =
+                    self.handle_chunk_request(request_id, req, sender_tag).await;
+                let sender_tag = sender_tag.clone();
+                return Some(Reply { sender_tag, bytes, in_flight });
+            }
+            Some(Payload::ChunkRangeRequest(req)) => {
+                let (bytes, in_flight) =
+                    self.handle_chunk_range_request(request_id, req, sender_tag).await;
+                let sender_tag = sender_tag.clone();
+                return Some(Reply { sender_tag, bytes, in_flight });
+            }
+            Some(Payload::PingRequest(_)) => {
+                proto::Envelope::new(
+                    request_id,
+                    Payload::PingResponse(proto::PingResponse {
+                        responder_id: vec![], // Empty for now, could use node ID
+                    }),
+                )
+            }
+            Some(other) => {
+                tracing::warn!("Unexpected message type: {:?}", other);
+                proto::error_response(
+                    request_id,
+                    proto::error_codes::INVALID_MESSAGE,
+                    "unexpected message type".to_string(),
+                )
+            }
+            None => {
+                proto::error_response(
+                    request_id,
+                    proto::error_codes::INVALID_MESSAGE,
+                    "empty payload".to_string(),
+                )
+            }
+        };
+
+        Some(Reply::new(sender_tag.clone(), response.to_bytes()))
+    }
+
+    /// Handle a chunk request, returning the encoded response and the
+    /// in-flight slot to hold until it is sent
+    async fn handle_chunk_request(
+        &self,
+        request_id: u64,
+        req: proto::ChunkRequest,
+        sender_tag: &SenderTag,
+    ) -> (Vec<u8>, Option<InFlightGuard>) {
+        self.metrics.lock().unwrap().chunk_requests += 1;
+
+        // Validate content hash
+        if req.content_hash.len() != 32 {
+            let response = proto::error_response(
+                request_id,
+                proto::error_codes::INVALID_DATA,
+                "invalid content hash length".to_string(),
+            );
+            return (response.to_bytes(), None);
+        }
+
+        let mut content_hash = [0u8; 32];
+        content_hash.copy_from_slice(&req.content_hash);
+
+        tracing::info!(
+            "Chunk request: {} chunk {}",
+            &brisby_core::hash_to_hex(&content_hash)[..8],
+            req.chunk_index
+        );
+
+        if !self.may_serve(&content_hash).await {
+            tracing::debug!("Not serving chunk of incomplete file");
+            return (self.not_found(request_id), None);
+        }
+
+        if self.dry_run {
+            let response = self.record_dry_run(request_id, &content_hash, sender_tag).await;
+            return (response, None);
+        }
+
+        // Cap concurrent responses so one hot file can't starve the others
+        let Some(in_flight) = self.try_begin(&content_hash) else {
+            tracing::debug!(
+                "Busy, rejecting request for {} chunk {}",
+                &brisby_core::hash_to_hex(&content_hash)[..8],
+                req.chunk_index
+            );
+            let response = proto::error_response(
+                request_id,
+                proto::error_codes::UNAVAILABLE,
+                "busy, retry later".to_string(),
+            );
+            return (response.to_bytes(), None);
+        };
+
+        // Reuse a recent encoding of the same chunk if the stores haven't changed
+        let generation = self.generation().await;
+        if let Some(cache) = &self.response_cache {
+            let cached = cache.lock().unwrap().get(&content_hash, req.chunk_index, generation);
+            if let Some(payload) = cached {
+                tracing::debug!("Sending cached chunk {}", req.chunk_index);
+                self.record_served(&content_hash, 1);
+                let response = Envelope::encode_with_payload(request_id, &payload);
+                return (response, Some(in_flight));
+            }
+        }
+
+        match self.chunk_response(&content_hash, req.chunk_index).await {
+            Some(chunk) => {
+                self.record_served(&content_hash, 1);
+                let mut payload = self.encode_buffers.take();
+                Payload::ChunkResponse(chunk).encode_field_into(&mut payload);
+                let response = Envelope::encode_with_payload(request_id, &payload);
+
+                // The cache keeps the encoding; whatever it lets go of goes
+                // back to the pool once no reply still shares it
+                let released = match &self.response_cache {
+                    Some(cache) => cache.lock().unwrap().insert(
+                        &content_hash,
+                        req.chunk_index,
+                        generation,
+                        Arc::new(payload),
+                    ),
+                    None => Some(Arc::new(payload)),
+                };
+                if let Some(buf) = released.and_then(|payload| Arc::try_unwrap(payload).ok()) {
+                    self.encode_buffers.put(buf);
+                }
+
+                (response, Some(in_flight))
+            }
             None => {
                 tracing::warn!(
                     "Chunk not found: {} index {}",
                     &brisby_core::hash_to_hex(&content_hash)[..8],
                     req.chunk_index
                 );
-                proto::error_response(
-                    request_id,
-                    proto::error_codes::NOT_FOUND,
-                    "chunk not found".to_string(),
-                )
+                (self.not_found(request_id), None)
             }
         }
     }
-}
 
-/// Run the seeder message loop
-pub async fn run_seeder_loop<T: Transport>(
-    transport: &T,
-    seeder: &Seeder,
-) -> Result<()> {
-    tracing::info!("Starting seeder message loop");
+    /// Handle a range request, answering with the first chunks of the range
+    ///
+    /// Up to `MAX_CHUNKS_PER_RANGE` chunks are sent, stopping early at the
+    /// end of the file or a chunk we can't serve. The whole range takes one
+    /// in-flight slot, returned with the response.
+    async fn handle_chunk_range_request(
+        &self,
+        request_id: u64,
+        req: proto::ChunkRangeRequest,
+        sender_tag: &SenderTag,
+    ) -> (Vec<u8>, Option<InFlightGuard>) {
+        self.metrics.lock().unwrap().chunk_requests += 1;
+
+        if req.content_hash.len() != 32 {
+            let response = proto::error_response(
+                request_id,
+                proto::error_codes::INVALID_DATA,
+                "invalid content hash length".to_string(),
+            );
+            return (response.to_bytes(), None);
+        }
+        if req.start_index > req.end_index {
+            let response = proto::error_response(
+                request_id,
+                proto::error_codes::INVALID_DATA,
+                "range ends before it starts".to_string(),
+            );
+            return (response.to_bytes(), None);
+        }
+
+        let mut content_hash = [0u8; 32];
+        content_hash.copy_from_slice(&req.content_hash);
+
+        tracing::info!(
+            "Chunk range request: {} chunks {}..={}",
+            &brisby_core::hash_to_hex(&content_hash)[..8],
+            req.start_index,
+            req.end_index
+        );
+
+        if !self.may_serve(&content_hash).await {
+            tracing::debug!("Not serving chunks of incomplete file");
+            return (self.not_found(request_id), None);
+        }
+
+        if self.dry_run {
+            let response = self.record_dry_run(request_id, &content_hash, sender_tag).await;
+            return (response, None);
+        }
+
+        let Some(in_flight) = self.try_begin(&content_hash) else {
+            let response = proto::error_response(
+                request_id,
+                proto::error_codes::UNAVAILABLE,
+                "busy, retry later".to_string(),
+            );
+            return (response.to_bytes(), None);
+        };
+
+        let last = req
+            .end_index
+            .min(req.start_index.saturating_add(proto::MAX_CHUNKS_PER_RANGE - 1));
+        let mut chunks = Vec::new();
+        for chunk_index in req.start_index..=last {
+            match self.chunk_response(&content_hash, chunk_index).await {
+                Some(chunk) => chunks.push(chunk),
+                None => break,
+            }
+        }
+
+        if chunks.is_empty() {
+            return (self.not_found(request_id), None);
+        }
+
+        self.record_served(&content_hash, chunks.len() as u64);
+        tracing::debug!(
+            "Sending chunks {}..={}",
+            req.start_index,
+            req.start_index + chunks.len() as u32 - 1
+        );
+        let response = Envelope::new(
+            request_id,
+            Payload::ChunkRangeResponse(proto::ChunkRangeResponse {
+                content_hash: content_hash.to_vec(),
+                chunks,
+            }),
+        );
+        (response.to_bytes(), Some(in_flight))
+    }
+
+    /// Build the response for one chunk, or `None` if we can't serve it
+    async fn chunk_response(
+        &self,
+        content_hash: &ContentHash,
+        chunk_index: u32,
+    ) -> Option<proto::ChunkResponse> {
+        if self.is_corrupt(content_hash, chunk_index) {
+            tracing::warn!("Not serving corrupt chunk {}", chunk_index);
+            return None;
+        }
+        let data = self.get_chunk(content_hash, chunk_index).await?;
+
+        // Compute chunk hash with the algorithm the file was shared under
+        let hash_algo = self.hash_algo(content_hash).await.unwrap_or_default();
+        let chunk_hash = hash_algo.hash(&data);
+        let proof = self
+            .merkle_proof(content_hash, chunk_index)
+            .await
+            .unwrap_or_default();
+
+        // The hash stays that of the chunk itself, as the metadata lists it
+        let (data, compression) = match self.compress.then(|| compress_chunk(&data)).flatten() {
+            Some(compressed) => {
+                tracing::debug!(
+                    "Sending chunk {} ({} bytes, {} compressed)",
+                    chunk_index,
+                    data.len(),
+                    compressed.len()
+                );
+                (compressed, proto::compression::ZSTD)
+            }
+            None => {
+                tracing::debug!("Sending chunk {} ({} bytes)", chunk_index, data.len());
+                (data, proto::compression::NONE)
+            }
+        };
+
+        Some(proto::ChunkResponse {
+            content_hash: content_hash.to_vec(),
+            chunk_index,
+            data: data.into(),
+            chunk_hash: chunk_hash.to_vec(),
+            proof: proof.iter().map(|hash| hash.to_vec()).collect(),
+            compression,
+        })
+    }
+
+    /// Count a chunk request in dry-run mode and refuse it
+    async fn record_dry_run(
+        &self,
+        request_id: u64,
+        content_hash: &ContentHash,
+        sender_tag: &SenderTag,
+    ) -> Vec<u8> {
+        // As with served requests, only files we hold are counted
+        if self.get_metadata(content_hash).await.is_none() {
+            return self.not_found(request_id);
+        }
+
+        self.record_request(content_hash);
+        self.requesters
+            .lock()
+            .unwrap()
+            .entry(*content_hash)
+            .or_default()
+            .insert(sender_tag.clone());
+
+        proto::error_response(
+            request_id,
+            proto::error_codes::UNAVAILABLE,
+            "serving disabled".to_string(),
+        )
+        .to_bytes()
+    }
+}
+
+/// Load the persisted hot set, most requested first
+///
+/// A missing file means no hot set yet.
+pub fn load_hot_set(path: &Path) -> Result<Vec<ContentHash>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let hashes: Vec<String> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    hashes
+        .iter()
+        .map(|hex| {
+            brisby_core::hex_to_hash(hex)
+                .map_err(|e| anyhow::anyhow!("Invalid hash '{}' in hot set: {}", hex, e))
+        })
+        .collect()
+}
+
+/// Persist the hot set so the next run can prewarm it
+pub fn save_hot_set(path: &Path, hot_set: &[ContentHash]) -> Result<()> {
+    let hashes: Vec<String> = hot_set.iter().map(brisby_core::hash_to_hex).collect();
+    std::fs::write(path, serde_json::to_string_pretty(&hashes)?)?;
+    Ok(())
+}
+
+/// Re-verify `batch_size` chunks every `interval`, forever
+///
+/// Runs alongside `run_seeder_loop` to catch silent disk corruption before a
+/// downloader does; see `Seeder::verify_batch`.
+pub async fn run_verify_schedule(seeder: Arc<Seeder>, interval: Duration, batch_size: usize) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; wait a full interval before the first pass
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let corrupt = seeder.verify_batch(batch_size).await;
+        if !corrupt.is_empty() {
+            tracing::warn!("Scheduled verification found {} corrupt chunk(s)", corrupt.len());
+        }
+    }
+}
+
+/// Run the seeder message loop
+///
+/// Each request is handled on its own task so slow chunk reads don't hold up
+/// other downloaders; replies are funnelled back and sent from this loop.
+///
+/// Sending replies takes priority over receiving: whenever a reply is ready
+/// it is sent before the next request is accepted, and no more requests are
+/// accepted while `MAX_PENDING_REPLIES` are still unanswered. Downloaders
+/// already waiting are served before new ones are let in, and the pending
+/// receive, which holds the mixnet client while waiting, is dropped as soon as
+/// there is a reply to send.
+pub async fn run_seeder_loop<T: Transport>(
+    transport: &T,
+    seeder: Arc<Seeder>,
+) -> Result<()> {
+    tracing::info!("Starting seeder message loop");
+
+    // Handlers always report back, with `None` if there's nothing to send,
+    // so every accepted request is accounted for
+    let (reply_tx, mut reply_rx) = tokio::sync::mpsc::unbounded_channel::<Option<Reply>>();
+    let mut pending = 0usize;
+
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(reply) = reply_rx.recv() => {
+                pending -= 1;
+                let Some(Reply { sender_tag, bytes, in_flight }) = reply else {
+                    continue;
+                };
+                let len = bytes.len();
+                seeder.rate_limiter.acquire(len).await;
+                match transport.send_reply(&sender_tag, bytes).await {
+                    Ok(()) => seeder.metrics.lock().unwrap().bytes_served += len as u64,
+                    Err(e) => tracing::error!("Failed to send reply: {}", e),
+                }
+                // Only now is the response no longer in flight
+                drop(in_flight);
+            }
+
+            received = transport.receive_timeout(std::time::Duration::from_secs(30)),
+                if pending < MAX_PENDING_REPLIES =>
+            {
+                match received {
+                    Ok(Some(msg)) => {
+                        pending += 1;
+                        let seeder = seeder.clone();
+                        let reply_tx = reply_tx.clone();
+                        tokio::spawn(async move {
+                            // A panicking handler still counts as answered
+                            let handler = async move { seeder.handle(&msg).await };
+                            let reply = tokio::spawn(handler).await.ok().flatten();
+                            let _ = reply_tx.send(reply);
+                        });
+                    }
+                    Ok(None) => {
+                        // Timeout, continue
+                        tracing::debug!("No messages received in timeout period");
+                    }
+                    Err(e) => {
+                        tracing::error!("Error receiving message: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{NamedTempFile, TempDir};
+    use std::io::Write;
+
+    #[test]
+    fn test_chunk_store_add_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+
+        // Create a test file
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(b"Hello, World! This is test data for chunking.").unwrap();
+        test_file.flush().unwrap();
+
+        let metadata = store.add_file(test_file.path()).unwrap();
+        assert_eq!(metadata.filename, test_file.path().file_name().unwrap().to_string_lossy());
+        assert_eq!(metadata.chunks.len(), 1); // Small file = 1 chunk
+
+        // Verify chunk retrieval
+        let chunk = store.get_chunk(&metadata.content_hash, 0);
+        assert!(chunk.is_some());
+        assert_eq!(chunk.unwrap(), b"Hello, World! This is test data for chunking.");
+    }
+
+    #[test]
+    fn test_lazy_store_adds_file_without_buffering_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new_lazy(temp_dir.path().join("chunks"));
+
+        let content: Vec<u8> = (0..brisby_core::CHUNK_SIZE * 2 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(&content).unwrap();
+        test_file.flush().unwrap();
+
+        let metadata = store.add_file(test_file.path()).unwrap();
+        assert_eq!(metadata.chunks.len(), 3);
+
+        // Nothing is kept in memory; chunks are served from disk
+        assert!(store.get_chunk(&metadata.content_hash, 0).is_none());
+        let chunks: Vec<Vec<u8>> = (0..3)
+            .map(|i| store.read_chunk(&metadata.content_hash, i).unwrap())
+            .collect();
+        assert_eq!(chunks.concat(), content);
+    }
+
+    #[test]
+    fn test_append_only_processes_new_chunks() {
+        use brisby_core::CHUNK_SIZE;
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let mut store = ChunkStore::new(storage_dir.clone());
+
+        let log_path = temp_dir.path().join("events.log");
+        let mut content = vec![1u8; CHUNK_SIZE + 100];
+        std::fs::write(&log_path, &content).unwrap();
+        let original = store.add_file(&log_path).unwrap();
+
+        // Mark the full first chunk on disk; an append must not rewrite it
+        let old_dir = storage_dir.join(brisby_core::hash_to_hex(&original.content_hash));
+        std::fs::write(old_dir.join("chunk_000000"), b"untouched").unwrap();
+
+        // Grow the file past the partial second chunk into a third
+        content.extend(vec![2u8; CHUNK_SIZE]);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all(&vec![2u8; CHUNK_SIZE])
+            .unwrap();
+
+        let appended = store.append_file(&log_path, &original.content_hash).unwrap();
+        let (expected, _) = brisby_core::chunk::chunk_file(&log_path).unwrap();
+        assert_eq!(appended.content_hash, expected.content_hash);
+        assert_eq!(appended.chunks, expected.chunks);
+        assert_eq!(appended.chunks[0], original.chunks[0]);
+        assert!(store.get_metadata(&original.content_hash).is_none());
+        assert!(!old_dir.exists());
+
+        let new_dir = storage_dir.join(brisby_core::hash_to_hex(&appended.content_hash));
+        assert_eq!(std::fs::read(new_dir.join("chunk_000000")).unwrap(), b"untouched");
+        let tail: Vec<u8> = (1..3)
+            .flat_map(|i| std::fs::read(new_dir.join(format!("chunk_{:06}", i))).unwrap())
+            .collect();
+        assert_eq!(tail, content[CHUNK_SIZE..]);
+        assert_eq!(store.get_chunk(&appended.content_hash, 2).unwrap(), &content[CHUNK_SIZE * 2..]);
+
+        // Reloading picks the file up under its new hash
+        let mut reloaded = ChunkStore::new(storage_dir);
+        assert_eq!(reloaded.load_all().unwrap(), 1);
+        assert!(reloaded.get_metadata(&appended.content_hash).is_some());
+
+        // Rewriting earlier content isn't an append
+        content[0] = 9;
+        std::fs::write(&log_path, &content).unwrap();
+        assert!(store.append_file(&log_path, &appended.content_hash).is_err());
+    }
+
+    #[test]
+    fn test_chunk_store_load_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+
+        let content_hash;
+        {
+            let mut store = ChunkStore::new(storage_dir.clone());
+
+            let mut test_file = NamedTempFile::new().unwrap();
+            test_file.write_all(b"Persistent test data").unwrap();
+            test_file.flush().unwrap();
+
+            let metadata = store.add_file(test_file.path()).unwrap();
+            content_hash = metadata.content_hash;
+        }
+
+        // Create new store and load
+        let mut store2 = ChunkStore::new(storage_dir);
+        assert!(store2.load_file(&content_hash).unwrap());
+
+        let chunk = store2.get_chunk(&content_hash, 0);
+        assert!(chunk.is_some());
+        assert_eq!(chunk.unwrap(), b"Persistent test data");
+    }
+
+    /// Store `path` as a file shared before Merkle hashes: under its flat
+    /// BLAKE3 hash, with that algorithm recorded
+    fn store_legacy_file(storage_dir: &Path, path: &Path) -> (FileMetadata, ContentHash) {
+        let metadata = ChunkStore::new(storage_dir.to_path_buf()).add_file(path).unwrap();
+        let legacy_hash = *blake3::hash(&std::fs::read(path).unwrap()).as_bytes();
+        let legacy = FileMetadata {
+            content_hash: legacy_hash,
+            hash_algo: HashAlgorithm::Blake3,
+            ..metadata.clone()
+        };
+        let merkle_dir = storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        let legacy_dir = storage_dir.join(brisby_core::hash_to_hex(&legacy_hash));
+        std::fs::rename(merkle_dir, &legacy_dir).unwrap();
+        metadata_file::write(&legacy_dir, &legacy, MetadataFormat::Json).unwrap();
+        (metadata, legacy_hash)
+    }
+
+    #[test]
+    fn test_legacy_file_moves_to_merkle_hash() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let path = temp_dir.path().join("old.bin");
+        use brisby_core::CHUNK_SIZE;
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let (metadata, legacy_hash) = store_legacy_file(&storage_dir, &path);
+
+        let mut store = ChunkStore::new(storage_dir.clone());
+        assert_eq!(store.load_all().unwrap(), 1);
+        assert!(store.get_metadata(&legacy_hash).is_none());
+        let migrated = store.get_metadata(&metadata.content_hash).unwrap();
+        assert_eq!(migrated.hash_algo, HashAlgorithm::Blake3Merkle);
+        assert_eq!(migrated.chunks, metadata.chunks);
+        assert!(migrated.merkle_proof(1).is_some());
+        let last_chunk = store.get_chunk(&metadata.content_hash, 2).unwrap();
+        assert_eq!(last_chunk, &content[CHUNK_SIZE * 2..]);
+        assert!(!storage_dir.join(brisby_core::hash_to_hex(&legacy_hash)).exists());
+
+        // The move sticks
+        let mut reloaded = ChunkStore::new(storage_dir);
+        assert_eq!(reloaded.load_all().unwrap(), 1);
+        assert!(reloaded.get_metadata(&metadata.content_hash).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_prewarmed_chunks_served_without_disk_read() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+
+        let (hot, cold) = {
+            let mut store = ChunkStore::new(storage_dir.clone());
+            let mut add = |content: &[u8]| {
+                let mut file = NamedTempFile::new().unwrap();
+                file.write_all(content).unwrap();
+                file.flush().unwrap();
+                store.add_file(file.path()).unwrap().content_hash
+            };
+            (add(b"popular file"), add(b"rarely requested file"))
+        };
+
+        let hot_set_path = temp_dir.path().join("hot_set.json");
+        save_hot_set(&hot_set_path, &[hot]).unwrap();
+
+        // Restart: only metadata is loaded, then the saved hot set is prewarmed
+        let mut store = ChunkStore::new_lazy(storage_dir);
+        assert_eq!(store.load_all().unwrap(), 2);
+        let hot_set = load_hot_set(&hot_set_path).unwrap();
+        assert_eq!(store.prewarm(&hot_set).unwrap(), 1);
+
+        let seeder = Seeder::new(store);
+        assert_eq!(seeder.get_chunk(&hot, 0).await.unwrap(), b"popular file");
+        assert_eq!(seeder.store().read().await.disk_reads(), 0);
+
+        assert_eq!(seeder.get_chunk(&cold, 0).await.unwrap(), b"rarely requested file");
+        assert_eq!(seeder.store().read().await.disk_reads(), 1);
+    }
+
+    #[test]
+    fn test_lazy_store_caches_disk_reads() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let path = temp_dir.path().join("big.bin");
+        let data: Vec<u8> = (0..4 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let content_hash = ChunkStore::new(storage_dir.clone())
+            .with_chunk_size(1024)
+            .add_file(&path)
+            .unwrap()
+            .content_hash;
+
+        // Room for two of the four chunks
+        let mut store = ChunkStore::new_lazy(storage_dir).with_chunk_cache(2048);
+        assert_eq!(store.load_all().unwrap(), 1);
+        assert_eq!(store.stored_bytes(), 4096);
+        assert!(store.get_chunk(&content_hash, 0).is_none());
+
+        assert_eq!(store.read_chunk(&content_hash, 0).unwrap(), &data[..1024]);
+        assert_eq!(store.read_chunk(&content_hash, 0).unwrap(), &data[..1024]);
+        assert_eq!(store.disk_reads(), 1);
+
+        for index in 1..4 {
+            store.read_chunk(&content_hash, index).unwrap();
+        }
+        assert_eq!(store.disk_reads(), 4);
+        assert_eq!(store.chunk_cache_bytes(), 2048);
+
+        // Chunk 0 was evicted; the two most recent are still cached
+        store.read_chunk(&content_hash, 3).unwrap();
+        store.read_chunk(&content_hash, 0).unwrap();
+        assert_eq!(store.disk_reads(), 5);
+        let stats = store.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 5, 3));
+        assert_eq!(stats.bytes, 2048);
+
+        // Reloading the file drops its cached chunks
+        store.load_file(&content_hash).unwrap();
+        assert_eq!(store.chunk_cache_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_hottest_ranks_by_requests() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let mut add = |content: &[u8]| {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(content).unwrap();
+            file.flush().unwrap();
+            store.add_file(file.path()).unwrap().content_hash
+        };
+        let (a, b) = (add(b"file a"), add(b"file b"));
+        let seeder = Seeder::new(store);
+
+        let request = |hash: ContentHash, id| {
+            let envelope = proto::chunk_request(id, hash.to_vec(), 0, vec![]);
+            ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+        };
+        seeder.handle_message(&request(a, 1)).await.unwrap();
+        seeder.handle_message(&request(b, 2)).await.unwrap();
+        seeder.handle_message(&request(b, 3)).await.unwrap();
+        // Requests for content we don't hold aren't counted
+        seeder.handle_message(&request([9u8; 32], 4)).await.unwrap();
+
+        assert_eq!(seeder.hottest(10), vec![b, a]);
+        assert_eq!(seeder.hottest(1), vec![b]);
+
+        let metrics = seeder.metrics();
+        assert_eq!(metrics.chunk_requests, 4);
+        assert_eq!(metrics.chunks_served, 3);
+        assert_eq!(metrics.not_found, 1);
+        assert_eq!(metrics.requests_per_file.get(&b), Some(&2));
+        // Bytes are only counted once `run_seeder_loop` has sent the reply
+        assert_eq!(metrics.bytes_served, 0);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_verification_detects_corruption() {
+        use brisby_core::CHUNK_SIZE;
 
-    loop {
-        match transport.receive_timeout(std::time::Duration::from_secs(30)).await {
-            Ok(Some(msg)) => {
-                if let Some((sender_tag, response_bytes)) = seeder.handle_message(&msg).await {
-                    if let Err(e) = transport.send_reply(&sender_tag, response_bytes).await {
-                        tracing::error!("Failed to send reply: {}", e);
-                    }
-                }
-            }
-            Ok(None) => {
-                // Timeout, continue
-                tracing::debug!("No messages received in timeout period");
-            }
-            Err(e) => {
-                tracing::error!("Error receiving message: {}", e);
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            }
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let path = temp_dir.path().join("two_chunks.bin");
+        std::fs::write(&path, vec![5u8; CHUNK_SIZE + 10]).unwrap();
+        let metadata = ChunkStore::new(storage_dir.clone()).add_file(&path).unwrap();
+        let hash = metadata.content_hash;
+        let chunk_path = storage_dir
+            .join(brisby_core::hash_to_hex(&hash))
+            .join("chunk_000001");
+
+        // With the chunk in memory, the disk copy is rewritten from it
+        let mut store = ChunkStore::new(storage_dir.clone());
+        store.load_file(&hash).unwrap();
+        let seeder = Seeder::new(store);
+        std::fs::write(&chunk_path, b"bit rot").unwrap();
+        assert_eq!(seeder.verify_batch(2).await, vec![(hash, 1)]);
+        assert_eq!(std::fs::read(&chunk_path).unwrap(), vec![5u8; 10]);
+        assert_eq!(seeder.verify_stats().chunks_repaired, 1);
+
+        // A lazy store has no copy to repair from, so the chunk stops being served
+        let mut store = ChunkStore::new_lazy(storage_dir);
+        store.load_file(&hash).unwrap();
+        let seeder = Arc::new(Seeder::new(store));
+        std::fs::write(&chunk_path, b"bit rot").unwrap();
+        let schedule = run_verify_schedule(seeder.clone(), Duration::from_millis(10), 1);
+        let _ = tokio::time::timeout(Duration::from_millis(200), schedule).await;
+
+        let stats = seeder.verify_stats();
+        assert!(stats.chunks_verified >= 2);
+        assert_eq!(stats.chunks_repaired, 0);
+        assert_eq!(stats.corrupt_chunks, vec![(hash, 1)]);
+
+        let request = proto::chunk_request(1, hash.to_vec(), 1, vec![]);
+        let msg = ReceivedMessage::new(request.to_bytes(), Some(SenderTag::new(vec![0u8; 16])));
+        let (_, response_bytes) = seeder.handle_message(&msg).await.unwrap();
+        let err = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_error_response()
+            .expect("Expected ErrorResponse");
+        assert_eq!(err.code, proto::error_codes::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_counts_without_serving() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(b"Dry run data").unwrap();
+        test_file.flush().unwrap();
+        let hash = store.add_file(test_file.path()).unwrap().content_hash;
+        let seeder = Seeder::new(store).with_dry_run(true);
+
+        let request = |hash: ContentHash, id, tag: u8| {
+            let envelope = proto::chunk_request(id, hash.to_vec(), 0, vec![]);
+            ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![tag; 16])))
+        };
+        for (id, tag) in [(1, 1), (2, 1), (3, 2)] {
+            let (_, response_bytes) = seeder.handle_message(&request(hash, id, tag)).await.unwrap();
+            let response = Envelope::from_bytes(&response_bytes).unwrap();
+            assert!(response.as_chunk_response().is_none());
+            let err = response.into_error_response().expect("Expected ErrorResponse");
+            assert_eq!(err.code, proto::error_codes::UNAVAILABLE);
+            assert_eq!(err.message, "serving disabled");
         }
+        // Requests for content we don't hold aren't counted
+        seeder.handle_message(&request([9u8; 32], 4, 3)).await.unwrap();
+
+        assert_eq!(
+            seeder.demand(),
+            vec![FileDemand {
+                content_hash: hash,
+                requests: 3,
+                distinct_requesters: 2,
+            }]
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use brisby_core::transport::mock::MockTransport;
-    use tempfile::{NamedTempFile, TempDir};
-    use std::io::Write;
+    #[tokio::test]
+    async fn test_incomplete_files_withheld_when_guard_off() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+
+        let (partial, full) = {
+            let mut store = ChunkStore::new(storage_dir.clone()).with_chunk_size(4);
+            let mut add = |content: &[u8]| {
+                let mut file = NamedTempFile::new().unwrap();
+                file.write_all(content).unwrap();
+                file.flush().unwrap();
+                store.add_file(file.path()).unwrap().content_hash
+            };
+            let hashes = (add(b"aaaabbbbcccc"), add(b"dddd"));
+            std::fs::remove_file(store.chunk_path(&hashes.0, 2)).unwrap();
+            hashes
+        };
+
+        let load = || {
+            let mut store = ChunkStore::new(storage_dir.clone());
+            assert_eq!(store.load_all().unwrap(), 2);
+            assert!(!store.is_complete(&partial));
+            assert!(store.is_complete(&full));
+            store
+        };
+        let request = |hash: ContentHash| {
+            let envelope = proto::chunk_request(1, hash.to_vec(), 0, vec![]);
+            ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![1; 16])))
+        };
+
+        // By default the chunks we do have are served
+        let seeder = Seeder::new(load());
+        let (_, response_bytes) = seeder.handle_message(&request(partial)).await.unwrap();
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+        assert_eq!(response.as_chunk_response().unwrap().data.as_ref(), b"aaaa");
+
+        let seeder = Seeder::new(load()).with_serve_incomplete(false);
+        let (_, response_bytes) = seeder.handle_message(&request(partial)).await.unwrap();
+        let err = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_error_response()
+            .expect("Expected ErrorResponse");
+        assert_eq!(err.code, proto::error_codes::NOT_FOUND);
+
+        let range = proto::Envelope::new(
+            2,
+            Payload::ChunkRangeRequest(proto::ChunkRangeRequest {
+                content_hash: partial.to_vec(),
+                start_index: 0,
+                end_index: 1,
+                surb: vec![],
+            }),
+        );
+        let message = ReceivedMessage::new(range.to_bytes(), Some(SenderTag::new(vec![1; 16])));
+        let (_, response_bytes) = seeder.handle_message(&message).await.unwrap();
+        let err = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_error_response()
+            .expect("Expected ErrorResponse");
+        assert_eq!(err.code, proto::error_codes::NOT_FOUND);
+
+        // Complete files are still served
+        let (_, response_bytes) = seeder.handle_message(&request(full)).await.unwrap();
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+        assert_eq!(response.as_chunk_response().unwrap().data.as_ref(), b"dddd");
+        assert_eq!(seeder.metrics().not_found, 2);
+    }
 
     #[test]
-    fn test_chunk_store_add_file() {
+    fn test_metadata_records_hash_algo() {
         let temp_dir = TempDir::new().unwrap();
-        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let storage_dir = temp_dir.path().join("chunks");
+        let mut store = ChunkStore::new(storage_dir.clone());
 
-        // Create a test file
         let mut test_file = NamedTempFile::new().unwrap();
-        test_file.write_all(b"Hello, World! This is test data for chunking.").unwrap();
+        test_file.write_all(b"Hashed test data").unwrap();
         test_file.flush().unwrap();
 
         let metadata = store.add_file(test_file.path()).unwrap();
-        assert_eq!(metadata.filename, test_file.path().file_name().unwrap().to_string_lossy());
-        assert_eq!(metadata.chunks.len(), 1); // Small file = 1 chunk
+        let metadata_path = storage_dir
+            .join(brisby_core::hash_to_hex(&metadata.content_hash))
+            .join("metadata.json");
+        let json = std::fs::read_to_string(&metadata_path).unwrap();
+        assert!(json.contains(r#""hash_algo": "blake3-merkle""#));
 
-        // Verify chunk retrieval
-        let chunk = store.get_chunk(&metadata.content_hash, 0);
-        assert!(chunk.is_some());
-        assert_eq!(chunk.unwrap(), b"Hello, World! This is test data for chunking.");
+        // Manifests written before the field existed default to flat BLAKE3,
+        // and are moved over to Merkle hashes as they load. This hash was a
+        // Merkle root all along, so it stays the same.
+        let legacy = json.replace(r#""hash_algo": "blake3-merkle","#, "");
+        let parsed: FileMetadata = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(parsed.hash_algo, HashAlgorithm::Blake3);
+        std::fs::write(&metadata_path, &legacy).unwrap();
+        let mut reloaded = ChunkStore::new(storage_dir.clone());
+        assert!(reloaded.load_file(&metadata.content_hash).unwrap());
+        assert_eq!(
+            reloaded.get_metadata(&metadata.content_hash).unwrap().hash_algo,
+            HashAlgorithm::Blake3Merkle
+        );
+        assert_eq!(std::fs::read_to_string(&metadata_path).unwrap(), json);
+
+        // Unknown algorithms are rejected instead of verified as BLAKE3
+        std::fs::write(&metadata_path, json.replace("blake3-merkle", "sha3-256")).unwrap();
+        let mut rejected = ChunkStore::new(storage_dir);
+        assert!(rejected.load_file(&metadata.content_hash).is_err());
     }
 
     #[test]
-    fn test_chunk_store_load_file() {
+    fn test_binary_metadata_converted_on_load() {
         let temp_dir = TempDir::new().unwrap();
         let storage_dir = temp_dir.path().join("chunks");
 
-        let content_hash;
-        {
-            let mut store = ChunkStore::new(storage_dir.clone());
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(b"Stored as JSON first").unwrap();
+        test_file.flush().unwrap();
+        let metadata = ChunkStore::new(storage_dir.clone())
+            .add_file(test_file.path())
+            .unwrap();
+        let file_dir = storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        assert!(file_dir.join(metadata_file::JSON_FILE).is_file());
 
-            let mut test_file = NamedTempFile::new().unwrap();
-            test_file.write_all(b"Persistent test data").unwrap();
-            test_file.flush().unwrap();
+        let mut binary =
+            ChunkStore::new(storage_dir.clone()).with_metadata_format(MetadataFormat::Binary);
+        assert_eq!(binary.load_all().unwrap(), 1);
+        assert!(file_dir.join(metadata_file::BINARY_FILE).is_file());
+        assert!(!file_dir.join(metadata_file::JSON_FILE).exists());
 
-            let metadata = store.add_file(test_file.path()).unwrap();
-            content_hash = metadata.content_hash;
-        }
+        // A JSON store still reads binary metadata, and leaves it alone
+        let mut json = ChunkStore::new(storage_dir);
+        assert_eq!(json.load_all().unwrap(), 1);
+        assert_eq!(json.get_metadata(&metadata.content_hash), Some(&metadata));
+        assert_eq!(json.read_chunk(&metadata.content_hash, 0).unwrap(), b"Stored as JSON first");
+        assert!(file_dir.join(metadata_file::BINARY_FILE).is_file());
+    }
 
-        // Create new store and load
-        let mut store2 = ChunkStore::new(storage_dir);
-        assert!(store2.load_file(&content_hash).unwrap());
+    #[tokio::test]
+    async fn test_junk_dropped_before_decode() {
+        let temp_dir = TempDir::new().unwrap();
+        let seeder = Seeder::new(ChunkStore::new(temp_dir.path().join("chunks")));
+        let tag = || Some(SenderTag::new(vec![0u8; 16]));
 
-        let chunk = store2.get_chunk(&content_hash, 0);
-        assert!(chunk.is_some());
-        assert_eq!(chunk.unwrap(), b"Persistent test data");
+        // Oversized junk that wouldn't even decode
+        let junk = ReceivedMessage::new(vec![0xffu8; MAX_REQUEST_SIZE + 1], tag());
+        assert!(seeder.handle_message(&junk).await.is_none());
+        assert_eq!(seeder.dropped_before_decode(), 1);
+
+        // Well-formed, but not something a seeder answers
+        let search = proto::search_request(1, "query".to_string(), 10);
+        let msg = ReceivedMessage::new(search.to_bytes(), tag());
+        assert!(seeder.handle_message(&msg).await.is_none());
+        assert_eq!(seeder.dropped_before_decode(), 2);
+
+        // Pings still get through
+        let ping = Envelope::new(2, Payload::PingRequest(proto::PingRequest { sender_id: vec![] }));
+        let msg = ReceivedMessage::new(ping.to_bytes(), tag());
+        assert!(seeder.handle_message(&msg).await.is_some());
+        assert_eq!(seeder.dropped_before_decode(), 2);
     }
 
     #[tokio::test]
@@ -384,12 +2345,508 @@ mod tests {
         let (_, response_bytes) = seeder.handle_message(&msg).await.unwrap();
         let response = Envelope::from_bytes(&response_bytes).unwrap();
 
-        match response.payload {
-            Some(Payload::ChunkResponse(resp)) => {
-                assert_eq!(resp.chunk_index, 0);
-                assert_eq!(resp.data, b"Seeder test data");
+        let resp = response.into_chunk_response().expect("Expected ChunkResponse");
+        assert_eq!(resp.chunk_index, 0);
+        assert_eq!(resp.data, &b"Seeder test data"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_seeder_compresses_only_when_smaller() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let text = b"all work and no play makes jack a dull boy\n".repeat(200);
+        let text_path = temp_dir.path().join("text.txt");
+        std::fs::write(&text_path, &text).unwrap();
+        let text_metadata = store.add_file(&text_path).unwrap();
+        let tiny_path = temp_dir.path().join("tiny.txt");
+        std::fs::write(&tiny_path, b"hi").unwrap();
+        let tiny_metadata = store.add_file(&tiny_path).unwrap();
+        let seeder = Seeder::new(store).with_compression(true);
+
+        let request = |content_hash: &ContentHash| {
+            let request = Envelope::new(
+                1,
+                Payload::ChunkRequest(proto::ChunkRequest {
+                    content_hash: content_hash.to_vec(),
+                    chunk_index: 0,
+                    surb: vec![],
+                }),
+            );
+            ReceivedMessage::new(request.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+        };
+
+        let (_, response_bytes) = seeder
+            .handle_message(&request(&text_metadata.content_hash))
+            .await
+            .unwrap();
+        let resp = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_chunk_response()
+            .expect("Expected ChunkResponse");
+        assert_eq!(resp.compression, proto::compression::ZSTD);
+        assert!(resp.data.len() < text.len());
+        // The hash is of the chunk as chunked, not as sent
+        assert_eq!(resp.chunk_hash, text_metadata.chunks[0].hash.to_vec());
+        let data = brisby_core::chunk::decompress_chunk(resp.compression, &resp.data).unwrap();
+        assert_eq!(data, text);
+
+        // Compressing two bytes only makes them bigger
+        let (_, response_bytes) = seeder
+            .handle_message(&request(&tiny_metadata.content_hash))
+            .await
+            .unwrap();
+        let resp = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_chunk_response()
+            .expect("Expected ChunkResponse");
+        assert_eq!(resp.compression, proto::compression::NONE);
+        assert_eq!(resp.data, &b"hi"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_linked_file_served_from_source() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let mut store = ChunkStore::new(storage_dir.clone()).with_chunk_size(1024);
+        let data: Vec<u8> = (0..3 * 1024 + 10).map(|i| (i % 251) as u8).collect();
+        let path = temp_dir.path().join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let metadata = store.link_file(&path).unwrap();
+        assert!(store.is_linked(&metadata.content_hash));
+        assert!(store.is_complete(&metadata.content_hash));
+
+        // Only the metadata and source path are stored, no chunk copies
+        let file_dir = storage_dir.join(brisby_core::hash_to_hex(&metadata.content_hash));
+        assert!(!file_dir.join("chunk_000000").exists());
+
+        // A reloaded store serves every chunk straight from the source file
+        let mut reloaded = ChunkStore::new(storage_dir.clone());
+        assert_eq!(reloaded.load_all().unwrap(), 1);
+        assert!(reloaded.is_linked(&metadata.content_hash));
+        let seeder = Seeder::new(reloaded);
+        let mut served = Vec::new();
+        for chunk_index in 0..4 {
+            let request = Envelope::new(
+                chunk_index as u64 + 1,
+                Payload::ChunkRequest(proto::ChunkRequest {
+                    content_hash: metadata.content_hash.to_vec(),
+                    chunk_index,
+                    surb: vec![],
+                }),
+            );
+            let msg = ReceivedMessage::new(request.to_bytes(), Some(SenderTag::new(vec![0u8; 16])));
+            let (_, response_bytes) = seeder.handle_message(&msg).await.unwrap();
+            let resp = Envelope::from_bytes(&response_bytes)
+                .unwrap()
+                .into_chunk_response()
+                .expect("Expected ChunkResponse");
+            served.extend_from_slice(&resp.data);
+        }
+        assert_eq!(served, data);
+
+        // A changed source is caught on read rather than served
+        let mut changed = data.clone();
+        changed[5] ^= 0xff;
+        std::fs::write(&path, &changed).unwrap();
+        let mut store = ChunkStore::new(storage_dir);
+        store.load_all().unwrap();
+        assert!(store.read_chunk(&metadata.content_hash, 0).is_none());
+        assert_eq!(store.read_chunk(&metadata.content_hash, 1).unwrap(), &data[1024..2048]);
+        assert_eq!(
+            store.verify_chunk_on_disk(&metadata.content_hash, 0),
+            Some(ChunkStatus::Corrupt)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_seeder_serves_chunk_ranges() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks")).with_chunk_size(1024);
+        let data: Vec<u8> = (0..6 * 1024).map(|i| (i % 251) as u8).collect();
+        let path = temp_dir.path().join("ranged.bin");
+        std::fs::write(&path, &data).unwrap();
+        let metadata = store.add_file(&path).unwrap();
+        let seeder = Seeder::new(store);
+
+        let ask = |start_index, end_index| {
+            let request = Envelope::new(
+                7,
+                Payload::ChunkRangeRequest(proto::ChunkRangeRequest {
+                    content_hash: metadata.content_hash.to_vec(),
+                    start_index,
+                    end_index,
+                    surb: vec![],
+                }),
+            );
+            ReceivedMessage::new(request.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+        };
+
+        // The whole file is more than one response may carry
+        let (_, response_bytes) = seeder.handle_message(&ask(0, 5)).await.unwrap();
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+        let range = response.into_chunk_range_response().expect("Expected ChunkRangeResponse");
+        assert_eq!(range.chunks.len(), proto::MAX_CHUNKS_PER_RANGE as usize);
+        for (i, chunk) in range.chunks.iter().enumerate() {
+            assert_eq!(chunk.chunk_index, i as u32);
+            assert_eq!(chunk.data, &data[i * 1024..(i + 1) * 1024]);
+            let proof: Vec<ContentHash> = chunk
+                .proof
+                .iter()
+                .map(|hash| hash.as_slice().try_into().unwrap())
+                .collect();
+            assert!(brisby_core::chunk::verify_chunk_with_proof(
+                metadata.hash_algo,
+                &chunk.data,
+                chunk.chunk_index,
+                6,
+                metadata.size,
+                &proof,
+                &metadata.content_hash,
+            ));
+        }
+
+        // A range past the end of the file stops at the last chunk
+        let (_, response_bytes) = seeder.handle_message(&ask(4, 100)).await.unwrap();
+        let range = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_chunk_range_response()
+            .unwrap();
+        let indices: Vec<u32> = range.chunks.iter().map(|chunk| chunk.chunk_index).collect();
+        assert_eq!(indices, vec![4, 5]);
+
+        for (start, end, code) in [
+            (6, 8, proto::error_codes::NOT_FOUND),
+            (3, 1, proto::error_codes::INVALID_DATA),
+        ] {
+            let (_, response_bytes) = seeder.handle_message(&ask(start, end)).await.unwrap();
+            let error = Envelope::from_bytes(&response_bytes)
+                .unwrap()
+                .into_error_response()
+                .unwrap();
+            assert_eq!(error.code, code);
+        }
+
+        // Each chunk of a range response counts as served
+        let metrics = seeder.metrics();
+        assert_eq!((metrics.chunk_requests, metrics.chunks_served), (4, 6));
+        assert_eq!(metrics.not_found, 1);
+    }
+
+    #[tokio::test]
+    async fn test_seeder_serves_from_second_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let active = ChunkStore::new(temp_dir.path().join("active"));
+        let mut archive = ChunkStore::new(temp_dir.path().join("archive"));
+
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(b"Archived test data").unwrap();
+        test_file.flush().unwrap();
+
+        let metadata = archive.add_file(test_file.path()).unwrap();
+        let seeder = Seeder::with_stores(vec![active, archive]).unwrap();
+        assert!(Seeder::with_stores(Vec::new()).is_err());
+
+        assert!(seeder.store().read().await.get_chunk(&metadata.content_hash, 0).is_none());
+        assert!(seeder.get_metadata(&metadata.content_hash).await.is_some());
+
+        let request = Envelope::new(
+            1,
+            Payload::ChunkRequest(proto::ChunkRequest {
+                content_hash: metadata.content_hash.to_vec(),
+                chunk_index: 0,
+                surb: vec![],
+            }),
+        );
+        let msg = ReceivedMessage::new(
+            request.to_bytes(),
+            Some(SenderTag::new(vec![0u8; 16])),
+        );
+
+        let (_, response_bytes) = seeder.handle_message(&msg).await.unwrap();
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+
+        let resp = response.into_chunk_response().expect("Expected ChunkResponse");
+        assert_eq!(resp.data, &b"Archived test data"[..]);
+    }
+
+    /// Transport that holds every reply in `send_reply` until a permit is
+    /// added to `sends`, with the given requests waiting to be received
+    struct GatedTransport {
+        requests: Mutex<Vec<ReceivedMessage>>,
+        sends: tokio::sync::Semaphore,
+        sent: AtomicU64,
+    }
+
+    impl Transport for GatedTransport {
+        async fn connect(&mut self) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        async fn disconnect(&mut self) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        fn our_address(&self) -> Option<&brisby_core::NymAddress> {
+            None
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        async fn send(&self, _: &brisby_core::NymAddress, _: Vec<u8>) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        async fn send_reply(&self, _: &SenderTag, _: Vec<u8>) -> brisby_core::Result<()> {
+            self.sends.acquire().await.unwrap().forget();
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn receive(&self) -> brisby_core::Result<ReceivedMessage> {
+            std::future::pending().await
+        }
+
+        async fn receive_timeout(
+            &self,
+            timeout: Duration,
+        ) -> brisby_core::Result<Option<ReceivedMessage>> {
+            let next = self.requests.lock().unwrap().pop();
+            if next.is_none() {
+                tokio::time::sleep(timeout).await;
+            }
+            Ok(next)
+        }
+    }
+
+    /// Poll until `done`, failing after five seconds
+    async fn wait_until(done: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting");
+    }
+
+    #[tokio::test]
+    async fn test_saturated_content_does_not_block_others() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+
+        let mut hot_file = NamedTempFile::new().unwrap();
+        hot_file.write_all(b"Popular file").unwrap();
+        hot_file.flush().unwrap();
+        let hot = store.add_file(hot_file.path()).unwrap();
+
+        let mut cold_file = NamedTempFile::new().unwrap();
+        cold_file.write_all(b"Quiet file").unwrap();
+        cold_file.flush().unwrap();
+        let cold = store.add_file(cold_file.path()).unwrap();
+
+        let seeder = Seeder::new(store).with_limits(SeederLimits {
+            max_per_content: 2,
+            max_total: 3,
+        });
+
+        let request = |content_hash: &ContentHash| {
+            let envelope = Envelope::new(
+                1,
+                Payload::ChunkRequest(proto::ChunkRequest {
+                    content_hash: content_hash.to_vec(),
+                    chunk_index: 0,
+                    surb: vec![],
+                }),
+            );
+            ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+        };
+
+        // Saturate the hot file with replies the transport hasn't sent yet
+        let seeder = Arc::new(seeder);
+        let transport = GatedTransport {
+            requests: Mutex::new(vec![request(&hot.content_hash), request(&hot.content_hash)]),
+            sends: tokio::sync::Semaphore::new(0),
+            sent: AtomicU64::new(0),
+        };
+        let in_flight = || seeder.in_flight.lock().unwrap().total;
+
+        let body = async {
+            wait_until(|| in_flight() == 2).await;
+            let (_, bytes) = seeder.handle_message(&request(&hot.content_hash)).await.unwrap();
+            let err = Envelope::from_bytes(&bytes).unwrap().into_error_response().unwrap();
+            assert_eq!(err.code, proto::error_codes::UNAVAILABLE);
+
+            // Other content is still served straight away
+            let started = std::time::Instant::now();
+            let (_, bytes) = seeder.handle_message(&request(&cold.content_hash)).await.unwrap();
+            assert!(started.elapsed() < Duration::from_millis(100));
+            let resp = Envelope::from_bytes(&bytes).unwrap().into_chunk_response().unwrap();
+            assert_eq!(resp.data, &b"Quiet file"[..]);
+
+            // The global cap applies across content
+            let c = seeder.try_begin(&cold.content_hash).unwrap();
+            assert!(seeder.try_begin(&cold.content_hash).is_none());
+            drop(c);
+
+            // Sent replies free their slots
+            transport.sends.add_permits(1);
+            wait_until(|| transport.sent.load(Ordering::Relaxed) == 1).await;
+            wait_until(|| in_flight() == 1).await;
+            assert!(seeder.try_begin(&hot.content_hash).is_some());
+        };
+        tokio::select! {
+            _ = run_seeder_loop(&transport, seeder.clone()) => unreachable!(),
+            _ = body => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_chunk_request_reuses_encoding() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+
+        let mut test_file = NamedTempFile::new().unwrap();
+        test_file.write_all(b"Hot chunk").unwrap();
+        test_file.flush().unwrap();
+        let metadata = store.add_file(test_file.path()).unwrap();
+
+        let seeder = Seeder::new(store).with_response_cache(4);
+        let request = |request_id: u64| {
+            let envelope = proto::chunk_request(request_id, metadata.content_hash.to_vec(), 0, vec![]);
+            ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+        };
+        let hits = || seeder.response_cache.as_ref().unwrap().lock().unwrap().hits();
+
+        let (_, first) = seeder.handle_message(&request(1)).await.unwrap();
+        assert_eq!(hits(), 0);
+
+        let (_, second) = seeder.handle_message(&request(2)).await.unwrap();
+        assert_eq!(hits(), 1);
+
+        // Same payload, each under its own request ID
+        let first = Envelope::from_bytes(&first).unwrap();
+        let second = Envelope::from_bytes(&second).unwrap();
+        assert_eq!(first.request_id, 1);
+        assert_eq!(second.request_id, 2);
+        assert_eq!(first.payload, second.payload);
+
+        // Changing the store invalidates the cached encoding
+        let mut other_file = NamedTempFile::new().unwrap();
+        other_file.write_all(b"Another file").unwrap();
+        other_file.flush().unwrap();
+        seeder.store().write().await.add_file(other_file.path()).unwrap();
+
+        seeder.handle_message(&request(3)).await.unwrap();
+        assert_eq!(hits(), 1);
+    }
+
+    /// Transport with a flood of chunk requests waiting, logging receives
+    /// (`true`) and replies (`false`) in the order they happen
+    struct FloodedTransport {
+        requests: Mutex<Vec<ReceivedMessage>>,
+        log: Mutex<Vec<bool>>,
+    }
+
+    impl Transport for FloodedTransport {
+        async fn connect(&mut self) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        async fn disconnect(&mut self) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        fn our_address(&self) -> Option<&brisby_core::NymAddress> {
+            None
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        async fn send(&self, _: &brisby_core::NymAddress, _: Vec<u8>) -> brisby_core::Result<()> {
+            Ok(())
+        }
+
+        async fn send_reply(&self, _: &SenderTag, _: Vec<u8>) -> brisby_core::Result<()> {
+            self.log.lock().unwrap().push(false);
+            Ok(())
+        }
+
+        async fn receive(&self) -> brisby_core::Result<ReceivedMessage> {
+            std::future::pending().await
+        }
+
+        async fn receive_timeout(
+            &self,
+            timeout: Duration,
+        ) -> brisby_core::Result<Option<ReceivedMessage>> {
+            let next = self.requests.lock().unwrap().pop();
+            match next {
+                Some(msg) => {
+                    self.log.lock().unwrap().push(true);
+                    Ok(Some(msg))
+                }
+                None => {
+                    tokio::time::sleep(timeout).await;
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replies_not_starved_by_incoming_requests() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let path = temp_dir.path().join("popular.bin");
+        std::fs::write(&path, vec![1u8; 1000]).unwrap();
+        let hash = store.add_file(&path).unwrap().content_hash;
+        let seeder = Arc::new(Seeder::new(store));
+
+        let total = MAX_PENDING_REPLIES * 3;
+        let requests = (0..total as u64)
+            .map(|id| {
+                let envelope = proto::chunk_request(id, hash.to_vec(), 0, vec![]);
+                ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+            })
+            .collect();
+        let transport = FloodedTransport {
+            requests: Mutex::new(requests),
+            log: Mutex::new(Vec::new()),
+        };
+
+        let all_replied = async {
+            while transport.log.lock().unwrap().len() < total * 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                _ = run_seeder_loop(&transport, seeder.clone()) => unreachable!(),
+                _ = all_replied => {}
+            }
+        })
+        .await
+        .expect("every request should be answered");
+
+        // Replies go out while requests are still waiting, and no more than
+        // MAX_PENDING_REPLIES requests are ever left unanswered
+        let log = transport.log.lock().unwrap();
+        let first_reply = log.iter().position(|received| !received).unwrap();
+        assert!(first_reply <= MAX_PENDING_REPLIES);
+        let mut unanswered = 0usize;
+        for &received in log.iter() {
+            if received {
+                unanswered += 1;
+            } else {
+                unanswered -= 1;
             }
-            _ => panic!("Expected ChunkResponse"),
+            assert!(unanswered <= MAX_PENDING_REPLIES);
         }
+        assert_eq!(seeder.metrics().chunk_requests, total as u64);
     }
 }
@@ -0,0 +1,336 @@
+//! Per-seeder health tracking for choosing whom to ask for chunks
+//!
+//! Each seeder's successes, failures and a moving average of its response
+//! latency are recorded as chunks are fetched. Seeders are then tried fastest
+//! first and ones that keep failing last. A seeder that fails
+//! `BENCH_AFTER_FAILURES` times in a row is benched for `BENCH_DURATION`:
+//! it isn't asked at all unless every seeder is benched.
+//!
+//! Optionally, seeders that score the same are spread across Nym gateways, so
+//! that one unreachable gateway doesn't take out every seeder tried first.
+
+use brisby_core::NymAddress;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Consecutive failures after which a seeder is benched
+pub const BENCH_AFTER_FAILURES: u32 = 3;
+
+/// How long a benched seeder is left alone
+pub const BENCH_DURATION: Duration = Duration::from_secs(120);
+
+/// Weight of the newest sample in the latency average
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// What a download has seen of one seeder
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeederStats {
+    /// Chunks received and verified
+    pub successes: u64,
+    /// Timeouts, error replies and rejected chunks
+    pub failures: u64,
+    /// Failures since the last success
+    pub consecutive_failures: u32,
+    /// Exponentially weighted moving average of the response time
+    pub latency: Option<Duration>,
+    /// Not asked again before this, unless every seeder is benched
+    pub benched_until: Option<Instant>,
+}
+
+impl SeederStats {
+    pub fn record_success(&mut self, latency: Duration) {
+        self.successes += 1;
+        self.consecutive_failures = 0;
+        self.benched_until = None;
+        self.latency = Some(match self.latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_EWMA_WEIGHT) + latency.mul_f64(LATENCY_EWMA_WEIGHT)
+            }
+            None => latency,
+        });
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= BENCH_AFTER_FAILURES {
+            self.benched_until = Some(now + BENCH_DURATION);
+        }
+    }
+
+    pub fn is_benched(&self, now: Instant) -> bool {
+        self.benched_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Stats for every seeder a download has asked
+#[derive(Debug, Default)]
+pub struct SeederScoreboard {
+    stats: Mutex<HashMap<NymAddress, SeederStats>>,
+    /// Break ties between seeders in favour of gateways not picked yet
+    prefer_diverse_gateways: bool,
+}
+
+impl SeederScoreboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Order equally scored seeders so their gateways alternate
+    ///
+    /// Seeders behind the same gateway all become unreachable together when
+    /// it goes down. With this set, `rank` puts a seeder on a gateway it
+    /// hasn't used yet ahead of a tied one on a gateway already used.
+    /// Addresses that don't parse count as being on a gateway of their own.
+    pub fn with_gateway_diversity(mut self, enabled: bool) -> Self {
+        self.prefer_diverse_gateways = enabled;
+        self
+    }
+
+    pub fn record_success(&self, seeder: &NymAddress, latency: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        stats
+            .entry(seeder.clone())
+            .or_default()
+            .record_success(latency);
+    }
+
+    pub fn record_failure(&self, seeder: &NymAddress) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(seeder.clone()).or_default();
+        entry.record_failure(Instant::now());
+        if entry.benched_until.is_some() && entry.consecutive_failures == BENCH_AFTER_FAILURES {
+            tracing::info!(
+                "Benching {} for {:?} after {} failures in a row",
+                seeder.as_str(),
+                BENCH_DURATION,
+                BENCH_AFTER_FAILURES
+            );
+        }
+    }
+
+    pub fn get(&self, seeder: &NymAddress) -> Option<SeederStats> {
+        self.stats.lock().unwrap().get(seeder).cloned()
+    }
+
+    pub fn is_benched(&self, seeder: &NymAddress) -> bool {
+        self.get(seeder)
+            .is_some_and(|stats| stats.is_benched(Instant::now()))
+    }
+
+    /// `seeders` in the order to try them
+    ///
+    /// Benched seeders are left out, unless all of them are benched. The rest
+    /// are ordered by failures since their last success, then by average
+    /// latency; seeders not asked yet count as fastest, so each gets a try.
+    /// Ties keep the order given, or are spread across gateways, see
+    /// `with_gateway_diversity`.
+    pub fn rank(&self, seeders: &[NymAddress]) -> Vec<NymAddress> {
+        let now = Instant::now();
+        let stats = self.stats.lock().unwrap();
+        let mut ranked: Vec<(&NymAddress, Option<&SeederStats>)> = seeders
+            .iter()
+            .map(|seeder| (seeder, stats.get(seeder)))
+            .collect();
+
+        if ranked
+            .iter()
+            .any(|(_, s)| !s.is_some_and(|s| s.is_benched(now)))
+        {
+            ranked.retain(|(_, s)| !s.is_some_and(|s| s.is_benched(now)));
+        }
+        let score = |s: Option<&SeederStats>| {
+            s.map_or((0, Duration::ZERO), |s| {
+                (s.consecutive_failures, s.latency.unwrap_or_default())
+            })
+        };
+        ranked.sort_by_key(|(_, s)| score(*s));
+
+        if self.prefer_diverse_gateways {
+            let mut picks: HashMap<String, usize> = HashMap::new();
+            ranked
+                .chunk_by(|(_, a), (_, b)| score(*a) == score(*b))
+                .flat_map(|tied| {
+                    spread_gateways(tied.iter().map(|(seeder, _)| *seeder), &mut picks)
+                })
+                .collect()
+        } else {
+            ranked
+                .into_iter()
+                .map(|(seeder, _)| seeder.clone())
+                .collect()
+        }
+    }
+
+    /// Stats of every seeder asked, most successful first
+    pub fn snapshot(&self) -> Vec<(NymAddress, SeederStats)> {
+        let mut snapshot: Vec<_> = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(seeder, stats)| (seeder.clone(), stats.clone()))
+            .collect();
+        snapshot.sort_by(|(a_seeder, a), (b_seeder, b)| {
+            b.successes
+                .cmp(&a.successes)
+                .then(a.failures.cmp(&b.failures))
+                .then(a_seeder.as_str().cmp(b_seeder.as_str()))
+        });
+        snapshot
+    }
+}
+
+/// Order `tied` seeders so that the ones on the gateways picked least often
+/// so far come first, keeping the given order otherwise
+///
+/// `picks` counts how often each gateway was picked, across calls.
+fn spread_gateways<'a>(
+    tied: impl Iterator<Item = &'a NymAddress>,
+    picks: &mut HashMap<String, usize>,
+) -> Vec<NymAddress> {
+    let gateway = |seeder: &NymAddress| match seeder.components() {
+        Some(parts) => parts.gateway_id,
+        None => seeder.as_str().to_string(),
+    };
+    let mut remaining: Vec<&NymAddress> = tied.collect();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let next = (0..remaining.len())
+            .min_by_key(|&i| picks.get(&gateway(remaining[i])).copied().unwrap_or(0))
+            .unwrap();
+        let seeder = remaining.remove(next);
+        *picks.entry(gateway(seeder)).or_default() += 1;
+        ordered.push(seeder.clone());
+    }
+    ordered
+}
+
+/// Render seeder stats as printed at the end of a download
+pub fn format_summary(stats: &[(NymAddress, SeederStats)]) -> String {
+    let mut out = String::new();
+    let now = Instant::now();
+    let _ = writeln!(
+        out,
+        "{:>8}  {:>8}  {:>10}  SEEDER",
+        "CHUNKS", "FAILURES", "LATENCY"
+    );
+    for (seeder, stats) in stats {
+        let latency = match stats.latency {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "-".to_string(),
+        };
+        let benched = if stats.is_benched(now) {
+            " (benched)"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "{:>8}  {:>8}  {:>10}  {}{}",
+            stats.successes,
+            stats.failures,
+            latency,
+            seeder.as_str(),
+            benched
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_average_and_benching() {
+        let now = Instant::now();
+        let mut stats = SeederStats::default();
+        stats.record_success(Duration::from_millis(100));
+        stats.record_success(Duration::from_millis(200));
+        assert_eq!(stats.latency, Some(Duration::from_millis(130)));
+
+        for _ in 1..BENCH_AFTER_FAILURES {
+            stats.record_failure(now);
+        }
+        assert!(!stats.is_benched(now));
+        stats.record_failure(now);
+        assert!(stats.is_benched(now));
+        assert!(!stats.is_benched(now + BENCH_DURATION));
+
+        // One success clears the bench and the failure streak
+        stats.record_success(Duration::from_millis(130));
+        assert!(!stats.is_benched(now));
+        assert_eq!(stats.consecutive_failures, 0);
+        assert_eq!(stats.failures, BENCH_AFTER_FAILURES as u64);
+    }
+
+    #[test]
+    fn test_rank_prefers_fast_responsive_seeders() {
+        let [slow, fast, flaky, dead, new] =
+            ["slow", "fast", "flaky", "dead", "new"].map(NymAddress::new);
+        let scoreboard = SeederScoreboard::new();
+        scoreboard.record_success(&slow, Duration::from_millis(900));
+        scoreboard.record_success(&fast, Duration::from_millis(50));
+        scoreboard.record_success(&flaky, Duration::from_millis(10));
+        scoreboard.record_failure(&flaky);
+        for _ in 0..BENCH_AFTER_FAILURES {
+            scoreboard.record_failure(&dead);
+        }
+
+        let seeders = [
+            dead.clone(),
+            slow.clone(),
+            flaky.clone(),
+            fast.clone(),
+            new.clone(),
+        ];
+        assert_eq!(scoreboard.rank(&seeders), vec![new, fast, slow, flaky]);
+        assert!(scoreboard.is_benched(&dead));
+
+        // With nobody else left, benched seeders are still tried
+        assert_eq!(
+            scoreboard.rank(std::slice::from_ref(&dead)),
+            vec![dead.clone()]
+        );
+
+        let snapshot = scoreboard.snapshot();
+        assert_eq!(snapshot.len(), 4);
+        assert_eq!(snapshot.last().unwrap().0, dead);
+        assert!(format_summary(&snapshot).contains("dead (benched)"));
+    }
+
+    #[test]
+    fn test_rank_spreads_ties_across_gateways() {
+        let [a1, a2, a3, b1, c1, slow_b] = [
+            "a1.key@gatewayA",
+            "a2.key@gatewayA",
+            "a3.key@gatewayA",
+            "b1.key@gatewayB",
+            "c1.key@gatewayC",
+            "b2.key@gatewayB",
+        ]
+        .map(NymAddress::new);
+        let seeders = [
+            a1.clone(),
+            a2.clone(),
+            a3.clone(),
+            b1.clone(),
+            c1.clone(),
+            slow_b.clone(),
+        ];
+
+        // Off by default: ties keep the order given
+        let scoreboard = SeederScoreboard::new();
+        scoreboard.record_success(&slow_b, Duration::from_millis(500));
+        assert_eq!(scoreboard.rank(&seeders), seeders.to_vec());
+
+        // Equally scored seeders alternate gateways; a worse score still
+        // ranks last even though its gateway is picked least
+        let scoreboard = SeederScoreboard::new().with_gateway_diversity(true);
+        scoreboard.record_success(&slow_b, Duration::from_millis(500));
+        assert_eq!(scoreboard.rank(&seeders), vec![a1, b1, c1, a2, a3, slow_b]);
+    }
+}
@@ -0,0 +1,369 @@
+//! Adding local files to the chunk store for `brisby share`
+//!
+//! `share_file` adds one file and returns what a caller needs to hand it
+//! out. For `--recursive`, the directory is walked in name order and each
+//! regular file that passes the filter is added to the chunk store. Symlinks
+//! are skipped rather than followed, so a link cycle can't make the walk run
+//! forever. One file failing to read doesn't stop the rest; failures are
+//! collected in the summary.
+
+use crate::seeder::ChunkStore;
+use anyhow::{anyhow, Result};
+use brisby_core::FileMetadata;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Scheme of the URIs handed out for shared files
+pub const URI_SCHEME: &str = "brisby";
+
+/// A file added to the chunk store
+#[derive(Debug, Clone)]
+pub struct ShareResult {
+    pub metadata: FileMetadata,
+    /// `brisby:` URI naming the file, see `share_uri`
+    pub uri: String,
+    /// Where the file's chunks and metadata are stored
+    pub chunk_dir: PathBuf,
+}
+
+/// Chunk `path` into `store`
+pub fn share_file(store: &mut ChunkStore, path: &Path) -> Result<ShareResult> {
+    if !path.exists() {
+        return Err(anyhow!("File not found: {}", path.display()));
+    }
+    if path.is_dir() {
+        return Err(anyhow!(
+            "{} is a directory (use --recursive to share its files)",
+            path.display()
+        ));
+    }
+
+    tracing::info!("Processing file: {}", path.display());
+    let metadata = store.add_file(path)?;
+    Ok(ShareResult {
+        uri: share_uri(&metadata),
+        chunk_dir: store.file_dir(&metadata.content_hash),
+        metadata,
+    })
+}
+
+/// URI for a shared file: `brisby://<content hash>?name=<filename>&size=<bytes>`
+///
+/// The filename is percent-encoded; only the hash is needed to find seeders.
+pub fn share_uri(metadata: &FileMetadata) -> String {
+    format!(
+        "{}://{}?name={}&size={}",
+        URI_SCHEME,
+        brisby_core::hash_to_hex(&metadata.content_hash),
+        percent_encode(&metadata.filename),
+        metadata.size
+    )
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+/// Which files under the directory to share
+///
+/// Patterns are matched against the path relative to the shared directory,
+/// with `/` separators. A pattern without a `/` is matched against the file
+/// or directory name alone. `*` matches within one path component, `**`
+/// across components and `?` any single character.
+#[derive(Debug, Clone, Default)]
+pub struct ShareFilter {
+    /// Skip files larger than this many bytes
+    pub max_size: Option<u64>,
+    /// If not empty, only share files matching one of these
+    pub include: Vec<String>,
+    /// Skip files and whole directories matching any of these
+    pub exclude: Vec<String>,
+}
+
+impl ShareFilter {
+    fn is_excluded(&self, relative: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| matches_path(pattern, relative))
+    }
+
+    fn is_included(&self, relative: &str) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| matches_path(pattern, relative))
+    }
+}
+
+/// Why a file under the directory wasn't shared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Larger than `ShareFilter::max_size`
+    TooLarge(u64),
+    /// Matched an exclude pattern, or no include pattern
+    Filtered,
+    /// A symlink, socket or other non-regular file
+    NotRegular,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::TooLarge(size) => write!(f, "too large ({} bytes)", size),
+            SkipReason::Filtered => write!(f, "filtered"),
+            SkipReason::NotRegular => write!(f, "not a regular file"),
+        }
+    }
+}
+
+/// Outcome of sharing a directory, with paths relative to it
+#[derive(Debug, Default)]
+pub struct ShareSummary {
+    pub shared: Vec<(PathBuf, FileMetadata)>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl ShareSummary {
+    /// Total size of the shared files in bytes
+    pub fn shared_bytes(&self) -> u64 {
+        self.shared.iter().map(|(_, metadata)| metadata.size).sum()
+    }
+}
+
+/// Add every file under `dir` that passes `filter` to `store`
+///
+/// Fails only if `dir` itself can't be read; problems with individual files
+/// and subdirectories end up in `ShareSummary::failed`.
+pub fn share_directory(
+    store: &mut ChunkStore,
+    dir: &Path,
+    filter: &ShareFilter,
+) -> Result<ShareSummary> {
+    if !dir.is_dir() {
+        return Err(anyhow!("Not a directory: {}", dir.display()));
+    }
+
+    let mut summary = ShareSummary::default();
+    let mut pending = list_dir(dir)?;
+    pending.reverse();
+
+    while let Some(path) = pending.pop() {
+        let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+        let relative_str = relative_string(&relative);
+
+        let info = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                summary.failed.push((relative, e.to_string()));
+                continue;
+            }
+        };
+
+        if info.is_dir() {
+            if filter.is_excluded(&relative_str) {
+                summary.skipped.push((relative, SkipReason::Filtered));
+                continue;
+            }
+            match list_dir(&path) {
+                // Keep name order: the stack is popped from the end
+                Ok(entries) => pending.extend(entries.into_iter().rev()),
+                Err(e) => summary.failed.push((relative, e.to_string())),
+            }
+            continue;
+        }
+
+        if !info.is_file() {
+            summary.skipped.push((relative, SkipReason::NotRegular));
+            continue;
+        }
+        if filter.is_excluded(&relative_str) || !filter.is_included(&relative_str) {
+            summary.skipped.push((relative, SkipReason::Filtered));
+            continue;
+        }
+        if let Some(max_size) = filter.max_size.filter(|&max| info.len() > max) {
+            tracing::debug!("Skipping {} (over {} bytes)", relative_str, max_size);
+            summary
+                .skipped
+                .push((relative, SkipReason::TooLarge(info.len())));
+            continue;
+        }
+
+        tracing::info!("Processing file: {}", path.display());
+        match store.add_file(&path) {
+            Ok(metadata) => summary.shared.push((relative, metadata)),
+            Err(e) => summary.failed.push((relative, e.to_string())),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Entries of `dir`, sorted by name
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn relative_string(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Match `pattern` against a relative path, see `ShareFilter`
+fn matches_path(pattern: &str, relative: &str) -> bool {
+    if pattern.contains('/') {
+        glob_match(pattern.as_bytes(), relative.as_bytes())
+    } else {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        glob_match(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directories at all
+            let no_dirs = rest
+                .strip_prefix(b"/")
+                .is_some_and(|rest| glob_match(rest, text));
+            no_dirs || (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let component_end = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=component_end).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && glob_match(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_share_file_result() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("my song (live).flac");
+        std::fs::write(&path, vec![7u8; 1500]).unwrap();
+
+        let storage_dir = temp_dir.path().join("chunks");
+        let mut store = ChunkStore::new(storage_dir.clone());
+        let result = share_file(&mut store, &path).unwrap();
+
+        let stored = store.get_metadata(&result.metadata.content_hash).unwrap();
+        assert_eq!(result.metadata.content_hash, stored.content_hash);
+        assert_eq!(result.metadata.filename, "my song (live).flac");
+        assert_eq!(result.metadata.size, 1500);
+        let hex = brisby_core::hash_to_hex(&stored.content_hash);
+        assert_eq!(
+            result.uri,
+            format!("brisby://{}?name=my%20song%20%28live%29.flac&size=1500", hex)
+        );
+        assert_eq!(result.chunk_dir, storage_dir.join(&hex));
+        assert!(result.chunk_dir.join("metadata.json").is_file());
+
+        assert!(share_file(&mut store, temp_dir.path()).is_err());
+        assert!(share_file(&mut store, &temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        assert!(matches_path("*.txt", "notes/today.txt"));
+        assert!(!matches_path("*.txt", "notes/today.txt.bak"));
+        assert!(matches_path("notes/*.txt", "notes/today.txt"));
+        assert!(!matches_path("notes/*.txt", "notes/old/today.txt"));
+        assert!(matches_path("notes/**/*.txt", "notes/old/today.txt"));
+        assert!(matches_path("notes/**/*.txt", "notes/today.txt"));
+        assert!(!matches_path("**/a.txt", "notes/ba.txt"));
+        assert!(matches_path("file?.bin", "file1.bin"));
+        assert!(!matches_path("file?.bin", "file10.bin"));
+    }
+
+    #[test]
+    fn test_share_directory_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("music");
+        std::fs::create_dir_all(root.join("album/disc2")).unwrap();
+        std::fs::create_dir_all(root.join(".cache")).unwrap();
+        std::fs::write(root.join("cover.jpg"), b"jpeg bytes").unwrap();
+        std::fs::write(root.join("album/track01.flac"), vec![1u8; 300]).unwrap();
+        std::fs::write(root.join("album/disc2/track02.flac"), vec![2u8; 400]).unwrap();
+        std::fs::write(root.join("album/huge.flac"), vec![3u8; 5000]).unwrap();
+        std::fs::write(root.join("album/notes.tmp"), b"scratch").unwrap();
+        std::fs::write(root.join(".cache/index"), b"cached").unwrap();
+
+        let storage_dir = temp_dir.path().join("chunks");
+        let mut store = ChunkStore::new(storage_dir.clone());
+        let filter = ShareFilter {
+            max_size: Some(1000),
+            include: vec![],
+            exclude: vec!["*.tmp".to_string(), ".cache".to_string()],
+        };
+        let summary = share_directory(&mut store, &root, &filter).unwrap();
+
+        let shared: Vec<_> = summary
+            .shared
+            .iter()
+            .map(|(path, _)| relative_string(path))
+            .collect();
+        assert_eq!(
+            shared,
+            vec![
+                "album/disc2/track02.flac",
+                "album/track01.flac",
+                "cover.jpg"
+            ]
+        );
+        assert_eq!(summary.shared_bytes(), 400 + 300 + 10);
+        assert!(summary.failed.is_empty());
+        assert!(summary
+            .skipped
+            .contains(&(PathBuf::from("album/huge.flac"), SkipReason::TooLarge(5000))));
+        assert!(summary
+            .skipped
+            .contains(&(PathBuf::from("album/notes.tmp"), SkipReason::Filtered)));
+        assert!(summary
+            .skipped
+            .contains(&(PathBuf::from(".cache"), SkipReason::Filtered)));
+
+        // Every shared file is chunked on disk and picked up by a fresh store
+        let mut reloaded = ChunkStore::new(storage_dir);
+        assert_eq!(reloaded.load_all().unwrap(), 3);
+        for (path, metadata) in &summary.shared {
+            let stored = reloaded.get_metadata(&metadata.content_hash).unwrap();
+            assert_eq!(stored.filename, path.file_name().unwrap().to_string_lossy());
+            let data = reloaded.read_chunk(&metadata.content_hash, 0).unwrap();
+            assert_eq!(data, std::fs::read(root.join(path)).unwrap());
+        }
+
+        // Include patterns narrow the set further
+        let filter = ShareFilter {
+            include: vec!["**/*.flac".to_string()],
+            ..filter
+        };
+        let mut store = ChunkStore::new(temp_dir.path().join("flac_only"));
+        let summary = share_directory(&mut store, &root, &filter).unwrap();
+        assert_eq!(summary.shared.len(), 2);
+        assert_eq!(store.list_files().len(), 2);
+
+        assert!(share_directory(&mut store, &root.join("cover.jpg"), &filter).is_err());
+    }
+}
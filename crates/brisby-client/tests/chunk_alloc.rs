@@ -0,0 +1,80 @@
+//! Allocation bound for the chunk receive hot path
+//!
+//! Lives in its own test binary because it installs a counting global
+//! allocator, which would otherwise see allocations from unrelated tests.
+
+use brisby_client::dispatch::RequestTimeouts;
+use brisby_client::downloader::Downloader;
+use brisby_core::proto;
+use brisby_core::transport::mock::MockTransport;
+use brisby_core::{HashAlgorithm, NymAddress, ReceivedMessage, Transport};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const CHUNK_LEN: usize = 256 * 1024;
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_receive_chunk_does_not_copy_data() {
+    let mut transport = MockTransport::new();
+    transport.connect().await.unwrap();
+
+    let downloader = Downloader::new(&transport);
+    let seeder = NymAddress::new("seeder-address");
+    let mut pending = downloader
+        .request_chunk(&seeder, &[1u8; 32], 0)
+        .await
+        .unwrap();
+
+    let data = vec![0xabu8; CHUNK_LEN];
+    let chunk_hash = *blake3::hash(&data).as_bytes();
+    let response = proto::chunk_response(
+        pending.request_id(),
+        vec![1u8; 32],
+        0,
+        data,
+        chunk_hash.to_vec(),
+    );
+    transport.queue_message(ReceivedMessage::new(response.to_bytes(), None));
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let reply = downloader
+        .receive_chunk(&mut pending, &seeder, RequestTimeouts::default(), HashAlgorithm::Blake3)
+        .await
+        .unwrap()
+        .unwrap();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+
+    assert_eq!(reply.index, 0);
+    assert_eq!(reply.data.len(), CHUNK_LEN);
+    // Decoding used to copy the whole chunk out of the receive buffer; now
+    // only the small header fields are allocated.
+    assert!(
+        allocated < CHUNK_LEN / 16,
+        "receiving a {} byte chunk allocated {} bytes",
+        CHUNK_LEN,
+        allocated
+    );
+}
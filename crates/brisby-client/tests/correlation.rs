@@ -0,0 +1,266 @@
+//! Request/response correlation under concurrency
+//!
+//! Several downloaders keep many chunk requests in flight against one seeder
+//! over a `MockNetwork` that delays, reorders and drops messages. Every chunk
+//! carries its file and index in its bytes, so a response handed to the wrong
+//! request shows up as soon as it is looked at, even where a mismatched hash
+//! or retry would otherwise paper over it. Link conditions come from a fixed
+//! seed, so a failure can be replayed.
+
+use brisby_client::dispatch::RequestTimeouts;
+use brisby_client::downloader::Downloader;
+use brisby_client::seeder::{run_seeder_loop, ChunkStore, Seeder, SeederLimits};
+use brisby_core::transport::mock::{LinkConditions, MockNetwork, MockTransport};
+use brisby_core::{FileMetadata, NymAddress, Transport};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SEEDER_ADDRESS: &str = "seeder.mock";
+const CHUNK_SIZE: usize = 1024;
+const CHUNKS_PER_FILE: u32 = 24;
+const FILES: u32 = 3;
+
+/// Timeouts far above the simulated latency, so only lost messages time out
+const TIMEOUTS: RequestTimeouts = RequestTimeouts {
+    first_response: Duration::from_secs(1),
+    idle: Duration::from_secs(1),
+};
+
+/// Shorter timeouts for collecting replies that are all already on their
+/// way, so each lost one doesn't hold the test up for long
+const COLLECT_TIMEOUTS: RequestTimeouts = RequestTimeouts {
+    first_response: Duration::from_millis(300),
+    idle: Duration::from_millis(300),
+};
+
+/// Chunk `index` of file `file`: the pair repeated to fill the chunk
+fn tagged_chunk(file: u32, index: u32) -> Vec<u8> {
+    let mut tag = file.to_le_bytes().to_vec();
+    tag.extend_from_slice(&index.to_le_bytes());
+    tag.iter().copied().cycle().take(CHUNK_SIZE).collect()
+}
+
+/// The file and index a chunk says it is, if it is a whole tagged chunk
+fn chunk_tag(data: &[u8]) -> Option<(u32, u32)> {
+    let file = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let index = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?);
+    (data == tagged_chunk(file, index).as_slice()).then_some((file, index))
+}
+
+/// One seeder sharing `FILES` tagged files and `downloaders` nodes to fetch
+/// them, all on a network with `conditions`
+struct Swarm {
+    seeder_transport: MockTransport,
+    downloader_transports: Vec<MockTransport>,
+    seeder: Arc<Seeder>,
+    network: MockNetwork,
+    /// Each file's metadata and content, by file number
+    files: Vec<(FileMetadata, Vec<u8>)>,
+}
+
+impl Swarm {
+    async fn new(dir: &Path, downloaders: usize, conditions: LinkConditions) -> Self {
+        let network = MockNetwork::with_conditions(conditions);
+        let mut seeder_transport = network.transport(SEEDER_ADDRESS);
+        seeder_transport.connect().await.unwrap();
+        let mut downloader_transports = Vec::new();
+        for i in 0..downloaders {
+            let mut transport = network.transport(format!("downloader-{}.mock", i));
+            transport.connect().await.unwrap();
+            downloader_transports.push(transport);
+        }
+
+        let mut store = ChunkStore::new(dir.join("chunks")).with_chunk_size(CHUNK_SIZE);
+        let mut files = Vec::new();
+        for file in 0..FILES {
+            let content: Vec<u8> = (0..CHUNKS_PER_FILE)
+                .flat_map(|index| tagged_chunk(file, index))
+                .collect();
+            let path = dir.join(format!("file-{}.bin", file));
+            std::fs::write(&path, &content).unwrap();
+            let metadata = store.add_file(&path).unwrap();
+            assert_eq!(metadata.chunks.len() as u32, CHUNKS_PER_FILE);
+            files.push((metadata, content));
+        }
+
+        // Keep the seeder from turning requests away as busy, so every
+        // failure here is down to the network
+        let seeder = Seeder::new(store).with_limits(SeederLimits {
+            max_per_content: 1024,
+            max_total: 1024,
+        });
+
+        Self {
+            seeder_transport,
+            downloader_transports,
+            seeder: Arc::new(seeder),
+            network,
+            files,
+        }
+    }
+
+    fn seeders(&self) -> Vec<NymAddress> {
+        vec![NymAddress::new(SEEDER_ADDRESS)]
+    }
+
+    /// Drive `work` against the running seeder loop
+    async fn run<F: Future>(&self, work: F) -> F::Output {
+        let seeder_loop = run_seeder_loop(&self.seeder_transport, self.seeder.clone());
+        tokio::select! {
+            output = work => output,
+            result = seeder_loop => panic!("seeder loop exited: {:?}", result),
+        }
+    }
+}
+
+/// Request every chunk of every file at once, interleaving the files, then
+/// collect the replies
+///
+/// Panics on any reply that isn't the chunk it was matched to. Returns how
+/// many requests got no reply.
+async fn request_all_at_once(swarm: &Swarm, transport: &MockTransport) -> usize {
+    let downloader = Downloader::new(transport);
+    let seeder = NymAddress::new(SEEDER_ADDRESS);
+
+    let mut requests = Vec::new();
+    for index in 0..CHUNKS_PER_FILE {
+        for (file, (metadata, _)) in swarm.files.iter().enumerate() {
+            let pending = downloader
+                .request_chunk(&seeder, &metadata.content_hash, index)
+                .await
+                .unwrap();
+            requests.push((file as u32, index, pending));
+        }
+    }
+
+    let mut unanswered = 0;
+    for (file, index, mut pending) in requests {
+        let hash_algo = swarm.files[file as usize].0.hash_algo;
+        let reply = downloader
+            .receive_chunk(&mut pending, &seeder, COLLECT_TIMEOUTS, hash_algo)
+            .await
+            .unwrap();
+        let Some(reply) = reply else {
+            unanswered += 1;
+            continue;
+        };
+        assert_eq!(reply.index, index, "reply for file {} chunk {}", file, index);
+        assert_eq!(
+            reply.content_hash, swarm.files[file as usize].0.content_hash,
+            "reply for file {} chunk {}",
+            file, index
+        );
+        assert_eq!(
+            chunk_tag(&reply.data),
+            Some((file, index)),
+            "data for file {} chunk {}",
+            file,
+            index
+        );
+    }
+    unanswered
+}
+
+#[tokio::test]
+async fn test_reordered_replies_reach_their_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let conditions = LinkConditions::latency(0x5eed_0001, Duration::from_millis(30));
+    let swarm = Swarm::new(temp_dir.path(), 3, conditions).await;
+
+    let [a, b, c] = &swarm.downloader_transports[..] else {
+        unreachable!()
+    };
+    let unanswered = swarm
+        .run(async {
+            let (a, b, c) = tokio::join!(
+                request_all_at_once(&swarm, a),
+                request_all_at_once(&swarm, b),
+                request_all_at_once(&swarm, c),
+            );
+            a + b + c
+        })
+        .await;
+
+    // Nothing is lost, only held up and reordered
+    assert_eq!(unanswered, 0);
+    assert_eq!(
+        swarm.seeder.metrics().chunks_served,
+        3 * (FILES * CHUNKS_PER_FILE) as u64
+    );
+}
+
+#[tokio::test]
+async fn test_lossy_replies_reach_their_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let conditions =
+        LinkConditions::latency(0x5eed_0002, Duration::from_millis(30)).with_loss(0.1);
+    let swarm = Swarm::new(temp_dir.path(), 2, conditions).await;
+
+    let [a, b] = &swarm.downloader_transports[..] else {
+        unreachable!()
+    };
+    let unanswered = swarm
+        .run(async {
+            let (a, b) = tokio::join!(
+                request_all_at_once(&swarm, a),
+                request_all_at_once(&swarm, b),
+            );
+            a + b
+        })
+        .await;
+
+    // Lost requests and lost replies both go unanswered, and nothing else
+    // takes their place
+    assert!(swarm.network.dropped() > 0);
+    assert!(unanswered > 0);
+    assert!(unanswered as u64 <= swarm.network.dropped());
+}
+
+#[tokio::test]
+async fn test_concurrent_parallel_downloads_under_loss() {
+    let temp_dir = TempDir::new().unwrap();
+    let conditions =
+        LinkConditions::latency(0x5eed_0003, Duration::from_millis(20)).with_loss(0.05);
+    let swarm = Swarm::new(temp_dir.path(), FILES as usize, conditions).await;
+    let seeders = swarm.seeders();
+
+    // Each node downloads a different file, all at once
+    let downloaders: Vec<Downloader<'_, MockTransport>> = swarm
+        .downloader_transports
+        .iter()
+        .map(|transport| Downloader::new(transport).with_timeouts(TIMEOUTS))
+        .collect();
+    let [d0, d1, d2] = &downloaders[..] else {
+        unreachable!()
+    };
+    let [f0, f1, f2] = &swarm.files[..] else {
+        unreachable!()
+    };
+    let (r0, r1, r2) = swarm
+        .run(async {
+            tokio::join!(
+                d0.download_parallel(&f0.0, &seeders, 8, |_, _| {}),
+                d1.download_parallel(&f1.0, &seeders, 8, |_, _| {}),
+                d2.download_parallel(&f2.0, &seeders, 8, |_, _| {}),
+            )
+        })
+        .await;
+
+    for (file, (downloader, chunks)) in downloaders.iter().zip([r0, r1, r2]).enumerate() {
+        let chunks = chunks.unwrap();
+        assert_eq!(chunks.len() as u32, CHUNKS_PER_FILE);
+        for (index, data) in &chunks {
+            assert_eq!(chunk_tag(data), Some((file as u32, *index)));
+        }
+
+        let (metadata, content) = &swarm.files[file];
+        let output = temp_dir.path().join(format!("downloaded-{}.bin", file));
+        downloader
+            .reassemble_to_file(chunks, metadata, &output)
+            .unwrap();
+        assert_eq!(&std::fs::read(&output).unwrap(), content);
+    }
+}
@@ -0,0 +1,204 @@
+//! End-to-end transfer tests
+//!
+//! Unlike `integration.rs`, which checks hand-encoded messages in isolation,
+//! these run a real seeder loop and a real `Downloader` on the two ends of a
+//! `loopback_pair`, so sender tags, reply routing and chunk verification are
+//! all exercised together.
+
+use brisby_client::downloader::{Downloader, ExternalHashList};
+use brisby_client::partials::PartialDownload;
+use brisby_client::seeder::{run_seeder_loop, ChunkStore, Seeder};
+use brisby_core::chunk::derive_chunk_key;
+use brisby_core::transport::mock::{loopback_pair, LoopbackTransport};
+use brisby_core::{FileMetadata, NymAddress, Transport, CHUNK_SIZE};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// A seeder and a downloader connected to each other
+struct Harness {
+    seeder_transport: LoopbackTransport,
+    downloader_transport: LoopbackTransport,
+    seeder: Arc<Seeder>,
+}
+
+impl Harness {
+    /// Share `path` from a fresh seeder and return the file's metadata
+    async fn new(storage_dir: &Path, path: &Path) -> (Self, FileMetadata) {
+        Self::with_compression(storage_dir, path, false).await
+    }
+
+    /// `new`, with the seeder compressing chunks if `compress` is set
+    async fn with_compression(
+        storage_dir: &Path,
+        path: &Path,
+        compress: bool,
+    ) -> (Self, FileMetadata) {
+        let store = ChunkStore::new(storage_dir.to_path_buf());
+        Self::with_store(store, path, compress).await
+    }
+
+    /// Share `path` by adding it to `store`
+    async fn with_store(
+        mut store: ChunkStore,
+        path: &Path,
+        compress: bool,
+    ) -> (Self, FileMetadata) {
+        let (seeder_transport, downloader_transport) = loopback_pair();
+
+        let metadata = store.add_file(path).unwrap();
+
+        let harness = Self {
+            seeder_transport,
+            downloader_transport,
+            seeder: Arc::new(Seeder::new(store).with_compression(compress)),
+        };
+        (harness, metadata)
+    }
+
+    fn seeders(&self) -> Vec<NymAddress> {
+        vec![self.seeder_transport.our_address().unwrap().clone()]
+    }
+
+    /// Drive `download` against the running seeder loop
+    async fn run<F: Future>(&self, download: F) -> F::Output {
+        let seeder_loop = run_seeder_loop(&self.seeder_transport, self.seeder.clone());
+        tokio::select! {
+            output = download => output,
+            result = seeder_loop => panic!("seeder loop exited: {:?}", result),
+        }
+    }
+}
+
+/// Write a file spanning several chunks with a non-repeating byte pattern
+fn write_multi_chunk_file(dir: &Path) -> (std::path::PathBuf, Vec<u8>) {
+    let content: Vec<u8> = (0..CHUNK_SIZE * 2 + CHUNK_SIZE / 2)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let path = dir.join("multi_chunk.bin");
+    std::fs::write(&path, &content).unwrap();
+    (path, content)
+}
+
+#[tokio::test]
+async fn test_sequential_download_end_to_end() {
+    let temp_dir = TempDir::new().unwrap();
+    let (path, content) = write_multi_chunk_file(temp_dir.path());
+    let (harness, metadata) = Harness::new(&temp_dir.path().join("chunks"), &path).await;
+    assert_eq!(metadata.chunks.len(), 3);
+
+    let downloader = Downloader::new(&harness.downloader_transport);
+    let chunks = harness
+        .run(downloader.download_sequential(&metadata, &harness.seeders(), |_, _| {}))
+        .await
+        .unwrap();
+    assert_eq!(chunks.len(), 3);
+
+    let metrics = harness.seeder.metrics();
+    assert_eq!(metrics.chunks_served, 3);
+    assert!(metrics.bytes_served > content.len() as u64);
+
+    let output = temp_dir.path().join("downloaded.bin");
+    downloader
+        .reassemble_to_file(chunks, &metadata, &output)
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+}
+
+#[tokio::test]
+async fn test_parallel_download_end_to_end() {
+    let temp_dir = TempDir::new().unwrap();
+    let (path, content) = write_multi_chunk_file(temp_dir.path());
+    let (harness, metadata) = Harness::new(&temp_dir.path().join("chunks"), &path).await;
+
+    let downloader = Downloader::new(&harness.downloader_transport);
+    let chunks = harness
+        .run(downloader.download_parallel(&metadata, &harness.seeders(), 3, |_, _| {}))
+        .await
+        .unwrap();
+
+    let output = temp_dir.path().join("downloaded.bin");
+    downloader
+        .reassemble_to_file(chunks, &metadata, &output)
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+}
+
+#[tokio::test]
+async fn test_compressed_download_end_to_end() {
+    let temp_dir = TempDir::new().unwrap();
+    let (path, content) = write_multi_chunk_file(temp_dir.path());
+    let (harness, metadata) =
+        Harness::with_compression(&temp_dir.path().join("chunks"), &path, true).await;
+
+    let downloader = Downloader::new(&harness.downloader_transport);
+    let chunks = harness
+        .run(downloader.download_parallel(&metadata, &harness.seeders(), 3, |_, _| {}))
+        .await
+        .unwrap();
+
+    // The repeating pattern compresses well, and chunks are checked and
+    // written out decompressed
+    assert!(harness.seeder.metrics().bytes_served < content.len() as u64 / 2);
+    let output = temp_dir.path().join("downloaded.bin");
+    downloader
+        .reassemble_to_file(chunks, &metadata, &output)
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+}
+
+#[tokio::test]
+async fn test_encrypted_download_end_to_end() {
+    let temp_dir = TempDir::new().unwrap();
+    let (path, content) = write_multi_chunk_file(temp_dir.path());
+    let key = derive_chunk_key("end to end");
+    let store = ChunkStore::new(temp_dir.path().join("chunks")).with_encryption_key(key);
+    let (harness, shared) = Harness::with_store(store, &path, false).await;
+    assert!(shared.encryption.is_some());
+
+    // The seeder only ever holds encrypted chunks
+    let chunk_dir = temp_dir
+        .path()
+        .join("chunks")
+        .join(brisby_core::hash_to_hex(&shared.content_hash));
+    let stored = std::fs::read(chunk_dir.join("chunk_000000")).unwrap();
+    assert_eq!(stored.len(), CHUNK_SIZE);
+    assert_ne!(stored[..], content[..CHUNK_SIZE]);
+
+    // The downloader learns the nonce from the manifest, as with --hash-list
+    let hashes = ExternalHashList::load(&chunk_dir.join("metadata.json")).unwrap();
+    let mut metadata = FileMetadata {
+        encryption: None,
+        ..shared.clone()
+    };
+    hashes.apply_to(&mut metadata).unwrap();
+    assert_eq!(metadata, shared);
+
+    let downloader = Downloader::new(&harness.downloader_transport)
+        .with_external_hashes(hashes)
+        .with_key(key);
+    let partials_dir = temp_dir.path().join("partials");
+    let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+    let outcome = harness
+        .run(downloader.download_resumable(&metadata, &harness.seeders(), 3, &partial, |_, _| {}))
+        .await
+        .unwrap();
+    assert!(outcome.is_complete());
+    let output = temp_dir.path().join("downloaded.bin");
+    downloader
+        .reassemble_partial(&partial, &metadata, &output)
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), content);
+
+    // A wrong key is caught before anything is fetched
+    let wrong = Downloader::new(&harness.downloader_transport)
+        .with_key(derive_chunk_key("not the key"));
+    partial.remove().unwrap();
+    let partial = PartialDownload::open(&partials_dir, &metadata.content_hash).unwrap();
+    assert!(harness
+        .run(wrong.download_resumable(&metadata, &harness.seeders(), 3, &partial, |_, _| {}))
+        .await
+        .is_err());
+    assert_eq!(partial.missing_chunks(metadata.chunks.len() as u32).len(), 3);
+}
@@ -36,18 +36,19 @@ async fn test_full_flow_mock() {
             size: metadata.size,
             chunk_count: metadata.chunks.len() as u32,
             nym_address: "test-seeder-address".to_string(),
+            tags: vec![],
+            anonymous: false,
+            mime_type: metadata.mime_type.clone().unwrap_or_default(),
+            chunk_size: metadata.chunk_size,
+            content_defined: None,
         }),
     );
 
     // Verify the publish request is valid
     let decoded = Envelope::from_bytes(&publish_request.to_bytes()).unwrap();
-    match decoded.payload {
-        Some(Payload::PublishRequest(req)) => {
-            assert_eq!(req.filename, "test.txt");
-            assert_eq!(req.nym_address, "test-seeder-address");
-        }
-        _ => panic!("Expected PublishRequest"),
-    }
+    let req = decoded.as_publish_request().expect("Expected PublishRequest");
+    assert_eq!(req.filename, "test.txt");
+    assert_eq!(req.nym_address, "test-seeder-address");
 
     // 4. Simulate search response
     let search_response = proto::search_response(
@@ -59,6 +60,10 @@ async fn test_full_flow_mock() {
             chunk_count: metadata.chunks.len() as u32,
             relevance: 1.0,
             seeders: vec!["test-seeder-address".to_string()],
+            relay_tokens: vec![],
+            seeder_count: 1,
+            chunk_size: metadata.chunk_size,
+            content_defined: None,
         }],
     );
 
@@ -70,13 +75,9 @@ async fn test_full_flow_mock() {
 
     // Verify chunk request
     let decoded = Envelope::from_bytes(&chunk_request.to_bytes()).unwrap();
-    match decoded.payload {
-        Some(Payload::ChunkRequest(req)) => {
-            assert_eq!(req.chunk_index, 0);
-            assert_eq!(req.content_hash, metadata.content_hash.to_vec());
-        }
-        _ => panic!("Expected ChunkRequest"),
-    }
+    let req = decoded.as_chunk_request().expect("Expected ChunkRequest");
+    assert_eq!(req.chunk_index, 0);
+    assert_eq!(req.content_hash, metadata.content_hash.to_vec());
 
     // Create chunk response
     let chunk_hash = *blake3::hash(&chunks[0]).as_bytes();
@@ -85,24 +86,22 @@ async fn test_full_flow_mock() {
         Payload::ChunkResponse(proto::ChunkResponse {
             content_hash: metadata.content_hash.to_vec(),
             chunk_index: 0,
-            data: chunks[0].clone(),
+            data: chunks[0].clone().into(),
             chunk_hash: chunk_hash.to_vec(),
+            proof: vec![],
+            compression: proto::compression::NONE,
         }),
     );
 
     // Verify chunk response contains correct data
     let decoded = Envelope::from_bytes(&chunk_response.to_bytes()).unwrap();
-    match decoded.payload {
-        Some(Payload::ChunkResponse(resp)) => {
-            assert_eq!(resp.chunk_index, 0);
-            assert_eq!(resp.data, test_content.to_vec());
-
-            // Verify chunk hash
-            let computed_hash = *blake3::hash(&resp.data).as_bytes();
-            assert_eq!(resp.chunk_hash, computed_hash.to_vec());
-        }
-        _ => panic!("Expected ChunkResponse"),
-    }
+    let resp = decoded.as_chunk_response().expect("Expected ChunkResponse");
+    assert_eq!(resp.chunk_index, 0);
+    assert_eq!(resp.data, test_content.to_vec());
+
+    // Verify chunk hash
+    let computed_hash = *blake3::hash(&resp.data).as_bytes();
+    assert_eq!(resp.chunk_hash, computed_hash.to_vec());
 
     // 6. Verify reassembly
     let reassembled = chunks.concat();
@@ -163,6 +162,10 @@ fn test_message_roundtrip() {
                 chunk_count: 4,
                 relevance: 0.95,
                 seeders: vec!["seeder1".to_string(), "seeder2".to_string()],
+                relay_tokens: vec![],
+                seeder_count: 2,
+                chunk_size: 0,
+                content_defined: None,
             }],
         ),
         proto::chunk_request(3, vec![2u8; 32], 5, vec![0u8; 16]),
@@ -171,8 +174,10 @@ fn test_message_roundtrip() {
             Payload::ChunkResponse(proto::ChunkResponse {
                 content_hash: vec![3u8; 32],
                 chunk_index: 2,
-                data: vec![4u8; 100],
+                data: vec![4u8; 100].into(),
                 chunk_hash: vec![5u8; 32],
+                proof: vec![vec![6u8; 32]],
+                compression: proto::compression::NONE,
             }),
         ),
         proto::error_response(5, 404, "Not found".to_string()),
@@ -196,12 +201,16 @@ fn test_search_result_seeders() {
         filename: "multi-seeder.txt".to_string(),
         size: 2048,
         chunk_count: 8,
+        chunk_size: 256,
+        content_defined: None,
         relevance: 0.8,
         seeders: vec![
             "seeder1.nym".to_string(),
             "seeder2.nym".to_string(),
             "seeder3.nym".to_string(),
         ],
+        relay_tokens: vec![],
+        seeder_count: 3,
     };
 
     assert_eq!(result.seeders.len(), 3);
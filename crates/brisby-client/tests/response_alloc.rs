@@ -0,0 +1,71 @@
+//! Allocation bound for the chunk serving hot path
+//!
+//! Lives in its own test binary because it installs a counting global
+//! allocator, which would otherwise see allocations from unrelated tests.
+
+use brisby_client::seeder::{ChunkStore, Seeder};
+use brisby_core::proto;
+use brisby_core::{ReceivedMessage, SenderTag};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const CHUNK_LEN: usize = 256 * 1024;
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_chunk_response_reuses_encode_buffer() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("file.bin");
+    std::fs::write(&path, vec![0xabu8; CHUNK_LEN]).unwrap();
+    let mut store = ChunkStore::new(temp_dir.path().join("chunks")).with_chunk_size(CHUNK_LEN);
+    let metadata = store.add_file(&path).unwrap();
+    let seeder = Seeder::new(store);
+
+    let request = |id| {
+        let envelope = proto::chunk_request(id, metadata.content_hash.to_vec(), 0, vec![]);
+        ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])))
+    };
+
+    // The first response fills the buffer pool
+    let (_, first) = seeder.handle_message(&request(1)).await.unwrap();
+    assert!(first.len() > CHUNK_LEN);
+
+    let message = request(2);
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let (_, response) = seeder.handle_message(&message).await.unwrap();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+
+    assert!(response.len() > CHUNK_LEN);
+    // Each response used to allocate the chunk read from disk, its encoded
+    // payload and the message; the payload buffer is now reused, leaving
+    // only the chunk and the message the transport takes ownership of.
+    assert!(
+        allocated < CHUNK_LEN * 5 / 2,
+        "serving a {} byte chunk allocated {} bytes",
+        CHUNK_LEN,
+        allocated
+    );
+}
@@ -1,11 +1,254 @@
 //! File chunking and reassembly
 
-use crate::{error::Result, types::*, CHUNK_SIZE};
-use std::io::{Read, Write};
+use crate::{error::Result, types::*, HashAlgorithm, CHUNK_SIZE};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 /// Chunk a file and compute its metadata
+///
+/// Holds every chunk in memory at once; large files should go through
+/// `chunk_file_streaming` instead.
 pub fn chunk_file(path: &Path) -> Result<(FileMetadata, Vec<Vec<u8>>)> {
+    chunk_file_with_size(path, CHUNK_SIZE)
+}
+
+/// Chunk a file into chunks of `chunk_size` bytes
+///
+/// Peers downloading the file must use the same size, which is recorded in
+/// `FileMetadata::chunk_size`.
+pub fn chunk_file_with_size(path: &Path, chunk_size: usize) -> Result<(FileMetadata, Vec<Vec<u8>>)> {
+    chunk_file_with_strategy(path, ChunkingStrategy::Fixed(chunk_size))
+}
+
+/// Chunk a file with the given strategy
+pub fn chunk_file_with_strategy(
+    path: &Path,
+    strategy: ChunkingStrategy,
+) -> Result<(FileMetadata, Vec<Vec<u8>>)> {
+    let (metadata, chunks) = chunk_file_streaming_with_strategy(path, strategy)?;
+    let chunks = chunks.collect::<Result<Vec<_>>>()?;
+    Ok((metadata, chunks))
+}
+
+/// Chunk a file without holding its chunks in memory
+///
+/// A first pass hashes the file to build its metadata. The returned reader
+/// then reads the file again, yielding one chunk at a time, so memory use is
+/// bounded by a single chunk however large the file is. Each chunk is checked
+/// against the metadata as it is read, in case the file changed in between.
+pub fn chunk_file_streaming(path: &Path) -> Result<(FileMetadata, ChunkReader)> {
+    chunk_file_streaming_with_size(path, CHUNK_SIZE)
+}
+
+/// `chunk_file_streaming` with a caller-chosen chunk size
+pub fn chunk_file_streaming_with_size(
+    path: &Path,
+    chunk_size: usize,
+) -> Result<(FileMetadata, ChunkReader)> {
+    chunk_file_streaming_with_strategy(path, ChunkingStrategy::Fixed(chunk_size))
+}
+
+/// `chunk_file_streaming` with a caller-chosen chunking strategy
+pub fn chunk_file_streaming_with_strategy(
+    path: &Path,
+    strategy: ChunkingStrategy,
+) -> Result<(FileMetadata, ChunkReader)> {
+    strategy.validate()?;
+    let metadata = scan_file(path, strategy, None)?;
+    let chunks = ChunkReader::open(path, &metadata, 0)?;
+    Ok((metadata, chunks))
+}
+
+/// How a file is split into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkingStrategy {
+    /// Chunks of exactly this many bytes, apart from a shorter last chunk
+    Fixed(usize),
+    /// Chunk boundaries picked by a rolling hash of the content
+    ///
+    /// A boundary falls wherever the gear hash of the preceding bytes matches,
+    /// which happens about once every `avg_size` bytes, but never before
+    /// `min_size` or after `max_size`. Boundaries move with the content, so
+    /// inserting data only changes the chunks around the insertion, and
+    /// identical stretches of different files chunk the same way.
+    ContentDefined {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+}
+
+impl Default for ChunkingStrategy {
+    fn default() -> Self {
+        ChunkingStrategy::Fixed(CHUNK_SIZE)
+    }
+}
+
+impl ChunkingStrategy {
+    /// Content-defined chunking averaging `CHUNK_SIZE`
+    pub fn content_defined() -> Self {
+        ChunkingStrategy::ContentDefined {
+            min_size: CHUNK_SIZE / 4,
+            avg_size: CHUNK_SIZE,
+            max_size: CHUNK_SIZE * 4,
+        }
+    }
+
+    /// The strategy a file with these recorded sizes was chunked with, see
+    /// `FileMetadata::chunking`
+    pub fn from_sizes(chunk_size: u32, content_defined: Option<ContentDefinedSizes>) -> Self {
+        match content_defined {
+            Some(sizes) => ChunkingStrategy::ContentDefined {
+                min_size: sizes.min_size as usize,
+                avg_size: sizes.avg_size as usize,
+                max_size: chunk_size as usize,
+            },
+            None => ChunkingStrategy::Fixed(chunk_size as usize),
+        }
+    }
+
+    /// Smallest and average chunk sizes to record, if chunking by content
+    pub fn content_defined_sizes(&self) -> Option<ContentDefinedSizes> {
+        match *self {
+            ChunkingStrategy::Fixed(_) => None,
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                avg_size,
+                ..
+            } => Some(ContentDefinedSizes {
+                min_size: min_size as u32,
+                avg_size: avg_size as u32,
+            }),
+        }
+    }
+
+    /// Largest chunk this strategy produces
+    pub fn max_chunk_size(&self) -> usize {
+        match *self {
+            ChunkingStrategy::Fixed(size) => size,
+            ChunkingStrategy::ContentDefined { max_size, .. } => max_size,
+        }
+    }
+
+    /// Fewest and most chunks a file of `size` bytes can split into
+    ///
+    /// Fixed-size chunking always gives the same count. Content-defined
+    /// chunks can be anywhere from the smallest to the largest size, apart
+    /// from a shorter last chunk.
+    pub fn chunk_count_range(&self, size: u64) -> std::ops::RangeInclusive<u64> {
+        match *self {
+            ChunkingStrategy::Fixed(chunk_size) => {
+                let count = size.div_ceil(chunk_size.max(1) as u64);
+                count..=count
+            }
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                max_size,
+                ..
+            } => size.div_ceil(max_size.max(1) as u64)..=size.div_ceil(min_size.max(1) as u64),
+        }
+    }
+
+    /// Smallest and largest size chunk `index` of a `size`-byte file split
+    /// into `chunk_count` chunks can have
+    ///
+    /// Fixed-size chunks have exactly one size. Content-defined chunks only
+    /// have bounds, and the last chunk has no lower bound beyond holding a
+    /// byte.
+    pub fn chunk_size_range(
+        &self,
+        index: u32,
+        chunk_count: u32,
+        size: u64,
+    ) -> std::ops::RangeInclusive<u64> {
+        let last = index + 1 >= chunk_count;
+        match *self {
+            ChunkingStrategy::Fixed(chunk_size) => {
+                let chunk_size = chunk_size as u64;
+                let expected = if last {
+                    size.saturating_sub(chunk_size * (chunk_count.max(1) as u64 - 1))
+                } else {
+                    chunk_size
+                };
+                expected..=expected
+            }
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                max_size,
+                ..
+            } => {
+                let min = if last { 1 } else { min_size as u64 };
+                min.min(size)..=(max_size as u64).min(size)
+            }
+        }
+    }
+
+    /// Fail unless the sizes are usable: nonzero, in order, and small enough
+    /// to record. A content-defined average must be at least 2, since the
+    /// boundary test needs a mask of at least one bit.
+    pub fn validate(&self) -> Result<()> {
+        let valid = match *self {
+            ChunkingStrategy::Fixed(size) => size > 0,
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } => 0 < min_size && 2 <= avg_size && min_size <= avg_size && avg_size <= max_size,
+        };
+        if !valid || self.max_chunk_size() > u32::MAX as usize {
+            return Err(crate::error::Error::InvalidData(format!(
+                "invalid chunking strategy {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Chunk a file that has grown by appending to a previously chunked version
+///
+/// `previous` must use fixed-size chunking; the file is chunked with the
+/// same size. Every full chunk of `previous` is checked against the file,
+/// along with the start of its final partial chunk, so a file that changed
+/// anywhere other than its end is rejected. Returns the new metadata, the
+/// index of the first chunk that differs from `previous`, and a reader
+/// yielding only the chunks from that index on. The previously-final chunk is among them unless it
+/// was already full.
+pub fn chunk_file_appended(
+    path: &Path,
+    previous: &FileMetadata,
+) -> Result<(FileMetadata, u32, ChunkReader)> {
+    let strategy = previous.chunking();
+    if previous.content_defined.is_some() {
+        return Err(crate::error::Error::InvalidData(
+            "content-defined chunks can't be extended by an append".to_string(),
+        ));
+    }
+    strategy.validate()?;
+    let metadata = scan_file(path, strategy, Some(previous))?;
+    let first_new = previous
+        .chunks
+        .iter()
+        .take_while(|c| c.size == previous.chunk_size)
+        .count() as u32;
+    let first_new = if metadata.content_hash == previous.content_hash {
+        metadata.chunks.len() as u32
+    } else {
+        first_new
+    };
+    let chunks = ChunkReader::open(path, &metadata, first_new)?;
+    Ok((metadata, first_new, chunks))
+}
+
+/// Hash a file chunk by chunk to build its metadata
+///
+/// With `previous`, fails unless the file starts with the content `previous`
+/// describes.
+fn scan_file(
+    path: &Path,
+    strategy: ChunkingStrategy,
+    previous: Option<&FileMetadata>,
+) -> Result<FileMetadata> {
     let file = std::fs::File::open(path)?;
     let file_size = file.metadata()?.len();
     let filename = path
@@ -14,47 +257,74 @@ pub fn chunk_file(path: &Path) -> Result<(FileMetadata, Vec<Vec<u8>>)> {
         .unwrap_or("unknown")
         .to_string();
 
-    let mut reader = std::io::BufReader::new(file);
-    let mut chunks_data = Vec::new();
+    let hash_algo = previous.map(|p| p.hash_algo).unwrap_or_default();
+    if let Some(previous) = previous {
+        if file_size < previous.size {
+            return Err(crate::error::Error::InvalidData(format!(
+                "{} shrank from {} to {} bytes, not an append",
+                filename, previous.size, file_size
+            )));
+        }
+    }
+
+    let mut reader = BufReader::new(file);
+    let mut buffer = Vec::new();
     let mut chunks_info = Vec::new();
-    let mut content_hasher = blake3::Hasher::new();
+    let mut content_hasher = hash_algo.hasher();
     let mut index = 0u32;
 
     loop {
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let bytes_read = reader.read(&mut buffer)?;
-
+        let bytes_read = match strategy {
+            ChunkingStrategy::Fixed(size) => {
+                buffer.resize(size, 0);
+                read_full(&mut reader, &mut buffer)?
+            }
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } => read_content_defined(&mut reader, &mut buffer, min_size, avg_size, max_size)?,
+        };
         if bytes_read == 0 {
             break;
         }
+        let chunk = &buffer[..bytes_read];
 
-        buffer.truncate(bytes_read);
-
-        // Hash the chunk
-        let chunk_hash = blake3::hash(&buffer);
-        let hash: ContentHash = *chunk_hash.as_bytes();
+        // The previous content must be an unchanged prefix
+        if let Some(old) = previous.and_then(|p| p.chunks.get(index as usize)) {
+            let old_len = (old.size as usize).min(bytes_read);
+            if !hash_algo.verify(&chunk[..old_len], &old.hash) {
+                return Err(crate::error::Error::InvalidData(format!(
+                    "{} changed in chunk {}, not an append",
+                    filename, index
+                )));
+            }
+        }
 
         // Feed the full file hasher with raw bytes
-        content_hasher.update(&buffer);
+        content_hasher.update(chunk);
+        content_hasher.end_chunk();
         chunks_info.push(ChunkInfo {
             index,
-            hash,
+            hash: hash_algo.hash(chunk),
             size: bytes_read as u32,
         });
-        chunks_data.push(buffer);
 
         index += 1;
     }
 
     // Compute file hash from the full file contents
-    let content_hash: ContentHash = *content_hasher.finalize().as_bytes();
+    let content_hash = content_hasher.finalize();
 
-    let keywords = FileMetadata::extract_keywords(&filename);
+    let keywords = crate::keywords::extract(&filename);
 
-    let metadata = FileMetadata {
+    Ok(FileMetadata {
         content_hash,
+        hash_algo,
         filename,
         size: file_size,
+        chunk_size: strategy.max_chunk_size() as u32,
+        content_defined: strategy.content_defined_sizes(),
         mime_type: detect_mime_type(path),
         chunks: chunks_info,
         keywords,
@@ -62,9 +332,144 @@ pub fn chunk_file(path: &Path) -> Result<(FileMetadata, Vec<Vec<u8>>)> {
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs(),
-    };
+        encryption: None,
+    })
+}
+
+/// Reads a file's chunks one at a time, see `chunk_file_streaming`
+pub struct ChunkReader {
+    reader: BufReader<std::fs::File>,
+    /// Expected chunks not yet read
+    chunks: std::vec::IntoIter<ChunkInfo>,
+    hash_algo: HashAlgorithm,
+}
+
+impl ChunkReader {
+    /// Read `metadata`'s chunks from `first_index` on out of the file at `path`
+    fn open(path: &Path, metadata: &FileMetadata, first_index: u32) -> Result<Self> {
+        let offset: u64 = metadata
+            .chunks
+            .iter()
+            .take(first_index as usize)
+            .map(|c| c.size as u64)
+            .sum();
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            chunks: metadata.chunks.get(first_index as usize..).unwrap_or_default().to_vec().into_iter(),
+            hash_algo: metadata.hash_algo,
+        })
+    }
+}
+
+impl Iterator for ChunkReader {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let info = self.chunks.next()?;
+        let mut buffer = vec![0u8; info.size as usize];
+
+        let chunk = read_full(&mut self.reader, &mut buffer).and_then(|bytes_read| {
+            if bytes_read != buffer.len() || !self.hash_algo.verify(&buffer, &info.hash) {
+                return Err(crate::error::Error::InvalidData(format!(
+                    "chunk {} changed while the file was being chunked",
+                    info.index
+                )));
+            }
+            Ok(buffer)
+        });
+
+        // Nothing after a failed chunk can be trusted
+        if chunk.is_err() {
+            self.chunks = Vec::new().into_iter();
+        }
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+/// Fill `buf` from `reader`, stopping early only at end of file
+///
+/// Returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Gear hash values for each byte, generated with splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x6272_6973_6279_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Read the next content-defined chunk from `reader` into `buf`
+///
+/// Each byte shifts the gear hash left, so its top bits depend only on the
+/// last 64 bytes; a boundary falls where enough of them are zero to happen
+/// about once every `avg_size` bytes. Returns the chunk length, 0 at end of
+/// file.
+fn read_content_defined(
+    reader: &mut impl BufRead,
+    buf: &mut Vec<u8>,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> Result<usize> {
+    let shift = 64 - avg_size.ilog2();
+    let mut hash = 0u64;
+    buf.clear();
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            break;
+        }
+
+        let mut used = 0;
+        let mut boundary = false;
+        for &byte in available {
+            used += 1;
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let len = buf.len() + used;
+            if len >= max_size || (len >= min_size && hash >> shift == 0) {
+                boundary = true;
+                break;
+            }
+        }
+        buf.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if boundary {
+            break;
+        }
+    }
 
-    Ok((metadata, chunks_data))
+    Ok(buf.len())
 }
 
 /// Reassemble chunks into a file
@@ -84,11 +489,11 @@ pub fn reassemble_file(
 
     // Verify each chunk hash
     for (i, (chunk_data, chunk_info)) in chunks.iter().zip(&metadata.chunks).enumerate() {
-        let computed_hash = blake3::hash(chunk_data);
-        if computed_hash.as_bytes() != &chunk_info.hash {
+        let computed_hash = metadata.hash_algo.hash(chunk_data);
+        if computed_hash != chunk_info.hash {
             return Err(crate::error::Error::HashMismatch {
                 expected: hash_to_hex(&chunk_info.hash),
-                actual: hex::encode(computed_hash.as_bytes()),
+                actual: hash_to_hex(&computed_hash),
             });
         }
 
@@ -111,10 +516,234 @@ pub fn reassemble_file(
     Ok(())
 }
 
+/// Buffer size for hashing whole files
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Hash a whole file without reading it into memory
+///
+/// The file is fed to the hasher through a fixed-size buffer, so memory use
+/// doesn't grow with the file. Merkle roots depend on where chunks end, so
+/// the file is split with `chunking`, the way it was when it was shared.
+pub fn hash_file(
+    path: &Path,
+    hash_algo: HashAlgorithm,
+    chunking: ChunkingStrategy,
+) -> Result<ContentHash> {
+    hash_reader(std::fs::File::open(path)?, hash_algo, chunking, HASH_BUFFER_SIZE)
+}
+
+/// Check a whole file against its expected content hash, see `hash_file`
+pub fn verify_file(
+    path: &Path,
+    hash_algo: HashAlgorithm,
+    chunking: ChunkingStrategy,
+    expected_hash: &ContentHash,
+) -> Result<bool> {
+    Ok(&hash_file(path, hash_algo, chunking)? == expected_hash)
+}
+
+/// Hash everything `reader` yields, `buffer_size` bytes at a time
+fn hash_reader(
+    mut reader: impl Read,
+    hash_algo: HashAlgorithm,
+    chunking: ChunkingStrategy,
+    buffer_size: usize,
+) -> Result<ContentHash> {
+    chunking.validate()?;
+    let mut hasher = hash_algo.hasher();
+    let chunk_size = match chunking {
+        ChunkingStrategy::Fixed(chunk_size) => chunk_size,
+        ChunkingStrategy::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        } => {
+            // A boundary can only be found by looking at the content, so
+            // each chunk is read whole; memory use is bounded by `max_size`
+            let mut reader = BufReader::with_capacity(buffer_size, reader);
+            let mut chunk = Vec::new();
+            while read_content_defined(&mut reader, &mut chunk, min_size, avg_size, max_size)? > 0
+            {
+                hasher.update(&chunk);
+                hasher.end_chunk();
+            }
+            return Ok(hasher.finalize());
+        }
+    };
+
+    let mut buffer = vec![0u8; buffer_size];
+    let mut chunk_filled = 0;
+    loop {
+        // Never read across a chunk boundary
+        let want = buffer_size.min(chunk_size - chunk_filled);
+        match reader.read(&mut buffer[..want]) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                chunk_filled += n;
+                if chunk_filled == chunk_size {
+                    hasher.end_chunk();
+                    chunk_filled = 0;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hasher.finalize())
+}
+
 /// Verify a single chunk against its expected hash
-pub fn verify_chunk(data: &[u8], expected_hash: &ContentHash) -> bool {
-    let computed = blake3::hash(data);
-    computed.as_bytes() == expected_hash
+pub fn verify_chunk(hash_algo: HashAlgorithm, data: &[u8], expected_hash: &ContentHash) -> bool {
+    hash_algo.verify(data, expected_hash)
+}
+
+/// Verify a chunk against the file's content hash using a Merkle proof
+///
+/// `proof` comes from `FileMetadata::merkle_proof`, usually sent by a seeder
+/// alongside the chunk, and needn't be trusted. `chunk_count` and
+/// `file_size` are part of the content hash, so a chunk only verifies for
+/// the file's real layout. Always fails unless `hash_algo` makes content
+/// hashes Merkle roots.
+pub fn verify_chunk_with_proof(
+    hash_algo: HashAlgorithm,
+    data: &[u8],
+    chunk_index: u32,
+    chunk_count: u32,
+    file_size: u64,
+    proof: &[ContentHash],
+    content_hash: &ContentHash,
+) -> bool {
+    hash_algo.is_merkle()
+        && crate::merkle::verify_proof(
+            &hash_algo.hash(data),
+            chunk_index,
+            chunk_count,
+            file_size,
+            proof,
+            content_hash,
+        )
+}
+
+/// zstd level chunks are compressed at for sending
+const CHUNK_COMPRESSION_LEVEL: i32 = 3;
+
+/// Largest chunk `decompress_chunk` will inflate to
+///
+/// Keeps a seeder from sending a few bytes that expand to gigabytes. Well
+/// above the largest chunks any chunking strategy makes by default;
+/// `compress_chunk` leaves bigger chunks as they are, so they still get
+/// through.
+pub const MAX_DECOMPRESSED_CHUNK_SIZE: usize = 16 * CHUNK_SIZE;
+
+/// Compress a chunk for sending with `proto::compression::ZSTD`
+///
+/// Returns `None` if compressing doesn't make the chunk smaller, as for
+/// already compressed media, so it should go out as it is.
+pub fn compress_chunk(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() > MAX_DECOMPRESSED_CHUNK_SIZE {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(data, CHUNK_COMPRESSION_LEVEL).ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Undo the `compression` a chunk was sent with, one of
+/// `proto::compression`
+///
+/// Chunk hashes are of the decompressed bytes, so this comes before
+/// verification.
+pub fn decompress_chunk(compression: u32, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        crate::proto::compression::NONE => Ok(data.to_vec()),
+        crate::proto::compression::ZSTD => {
+            zstd::bulk::decompress(data, MAX_DECOMPRESSED_CHUNK_SIZE).map_err(|e| {
+                crate::error::Error::InvalidData(format!("failed to decompress chunk: {}", e))
+            })
+        }
+        other => Err(crate::error::Error::InvalidData(format!(
+            "unknown chunk compression {}",
+            other
+        ))),
+    }
+}
+
+/// Key a private share's chunks are encrypted under
+pub type ChunkKey = [u8; 32];
+
+/// Context string for `derive_chunk_key`, so passphrase-derived keys never
+/// collide with other uses of blake3 over the same bytes
+const CHUNK_KEY_CONTEXT: &str = "brisby chunk encryption key v1";
+
+/// Derive a chunk key from a passphrase
+///
+/// There's no salt or stretching, so a passphrase is only as good as it is
+/// long; a random key is better where it can be passed around.
+pub fn derive_chunk_key(passphrase: &str) -> ChunkKey {
+    blake3::derive_key(CHUNK_KEY_CONTEXT, passphrase.as_bytes())
+}
+
+/// Encryption parameters for a new file under `key`, with a per-file `nonce`
+/// that must never be reused with the same key
+pub fn chunk_encryption(key: &ChunkKey, nonce: [u8; 8]) -> ChunkEncryption {
+    ChunkEncryption {
+        nonce,
+        key_check: key_check(key, &nonce),
+    }
+}
+
+fn key_check(key: &ChunkKey, nonce: &[u8; 8]) -> [u8; 16] {
+    let hash = blake3::keyed_hash(key, nonce);
+    let mut check = [0u8; 16];
+    check.copy_from_slice(&hash.as_bytes()[..16]);
+    check
+}
+
+/// Check `key` is the one a file was encrypted under
+///
+/// Decrypting with the wrong key doesn't fail, it just yields noise, so
+/// downloaders check this before writing anything out.
+pub fn check_chunk_key(key: &ChunkKey, encryption: &ChunkEncryption) -> Result<()> {
+    if key_check(key, &encryption.nonce) == encryption.key_check {
+        Ok(())
+    } else {
+        Err(crate::error::Error::InvalidData(
+            "wrong key for encrypted file".to_string(),
+        ))
+    }
+}
+
+/// Encrypt chunk `chunk_index` of a file in place with ChaCha20
+///
+/// Each chunk gets its own keystream from the file nonce and its index, so
+/// chunks can be encrypted and decrypted in any order. The ciphertext is the
+/// same length as the chunk.
+pub fn encrypt_chunk(
+    key: &ChunkKey,
+    encryption: &ChunkEncryption,
+    chunk_index: u32,
+    data: &mut [u8],
+) {
+    use chacha20::cipher::{KeyIvInit, StreamCipher};
+
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&encryption.nonce);
+    nonce[8..].copy_from_slice(&chunk_index.to_le_bytes());
+    chacha20::ChaCha20::new(key.into(), &nonce.into()).apply_keystream(data);
+}
+
+/// Decrypt chunk `chunk_index` of a file in place, undoing `encrypt_chunk`
+///
+/// Chunk hashes are of the encrypted bytes, so this comes after
+/// verification.
+pub fn decrypt_chunk(
+    key: &ChunkKey,
+    encryption: &ChunkEncryption,
+    chunk_index: u32,
+    data: &mut [u8],
+) {
+    // A stream cipher is its own inverse
+    encrypt_chunk(key, encryption, chunk_index, data)
 }
 
 /// Simple MIME type detection based on file extension
@@ -175,6 +804,214 @@ mod tests {
         assert_eq!(reassembled, test_data);
     }
 
+    #[test]
+    fn test_streaming_matches_chunk_file() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data: Vec<u8> = (0..CHUNK_SIZE * 3 + 7).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&test_data).unwrap();
+
+        let (metadata, chunks) = chunk_file_streaming(temp_file.path()).unwrap();
+        assert_eq!(chunks.size_hint(), (4, Some(4)));
+        let streamed: Vec<Vec<u8>> = chunks.collect::<Result<_>>().unwrap();
+
+        let (expected_metadata, expected_chunks) = chunk_file(temp_file.path()).unwrap();
+        assert_eq!(metadata.content_hash, expected_metadata.content_hash);
+        assert_eq!(metadata.chunks, expected_metadata.chunks);
+        assert_eq!(streamed, expected_chunks);
+        assert_eq!(streamed.concat(), test_data);
+    }
+
+    #[test]
+    fn test_streaming_detects_file_changed_after_metadata() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(&vec![1u8; CHUNK_SIZE * 2]).unwrap();
+
+        let (_, mut chunks) = chunk_file_streaming(temp_file.path()).unwrap();
+        assert!(chunks.next().unwrap().is_ok());
+
+        // Overwrite the second chunk before it is read
+        std::fs::write(temp_file.path(), vec![2u8; CHUNK_SIZE * 2]).unwrap();
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_chunk_with_custom_size() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&test_data).unwrap();
+
+        let (metadata, chunks) = chunk_file_with_size(temp_file.path(), 4096).unwrap();
+        assert_eq!(metadata.chunk_size, 4096);
+        let sizes: Vec<u32> = metadata.chunks.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, vec![4096, 4096, 1808]);
+        assert_eq!(chunks.concat(), test_data);
+        assert!(chunk_file_with_size(temp_file.path(), 0).is_err());
+
+        // Metadata saved before the chunk size was recorded used the default
+        let mut json = serde_json::to_value(&metadata).unwrap();
+        json.as_object_mut().unwrap().remove("chunk_size");
+        let old: FileMetadata = serde_json::from_value(json).unwrap();
+        assert_eq!(old.chunk_size as usize, CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_content_defined_prepend_moves_first_boundary_only() {
+        let strategy = ChunkingStrategy::ContentDefined {
+            min_size: 256,
+            avg_size: 1024,
+            max_size: 4096,
+        };
+        // xorshift noise, so boundaries depend on the content
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let test_data: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        let mut original = NamedTempFile::new().unwrap();
+        original.write_all(&test_data).unwrap();
+        let (metadata, chunks) = chunk_file_with_strategy(original.path(), strategy).unwrap();
+        assert!(metadata.chunks.len() > 10);
+        assert!(metadata.chunks.iter().all(|c| (c.size as usize) <= 4096));
+        assert_eq!(chunks.concat(), test_data);
+
+        let mut prepended = NamedTempFile::new().unwrap();
+        prepended.write_all(b"a few new bytes").unwrap();
+        prepended.write_all(&test_data).unwrap();
+        let (shifted, _) = chunk_file_with_strategy(prepended.path(), strategy).unwrap();
+
+        // Only the first chunk grows; every later chunk is unchanged
+        assert_eq!(shifted.chunks.len(), metadata.chunks.len());
+        assert_eq!(shifted.chunks[0].size, metadata.chunks[0].size + 15);
+        let hashes = |m: &FileMetadata| m.chunks[1..].iter().map(|c| c.hash).collect::<Vec<_>>();
+        assert_eq!(hashes(&shifted), hashes(&metadata));
+
+        // Fixed-size chunking loses every chunk to the same change
+        let (fixed, _) = chunk_file_with_size(original.path(), 1024).unwrap();
+        let (fixed_shifted, _) = chunk_file_with_size(prepended.path(), 1024).unwrap();
+        assert!(fixed.chunks[1..]
+            .iter()
+            .zip(&fixed_shifted.chunks[1..])
+            .all(|(a, b)| a.hash != b.hash));
+    }
+
+    #[test]
+    fn test_content_defined_rejects_tiny_average() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(b"some bytes to chunk").unwrap();
+
+        let strategy = ChunkingStrategy::ContentDefined {
+            min_size: 1,
+            avg_size: 1,
+            max_size: 1,
+        };
+        assert!(strategy.validate().is_err());
+        assert!(chunk_file_with_strategy(temp_file.path(), strategy).is_err());
+
+        let strategy = ChunkingStrategy::ContentDefined {
+            min_size: 1,
+            avg_size: 2,
+            max_size: 4,
+        };
+        let (_, chunks) = chunk_file_with_strategy(temp_file.path(), strategy).unwrap();
+        assert_eq!(chunks.concat(), b"some bytes to chunk");
+    }
+
+    #[test]
+    fn test_streaming_file_verification() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&test_data).unwrap();
+        let expected = blake3::hash(&test_data);
+
+        // Many refills of a buffer far smaller than the file
+        let file = std::fs::File::open(temp_file.path()).unwrap();
+        let fixed = ChunkingStrategy::default();
+        let hash = hash_reader(file, HashAlgorithm::Blake3, fixed, 7).unwrap();
+        assert_eq!(hash, *expected.as_bytes());
+
+        let path = temp_file.path();
+        assert!(verify_file(path, HashAlgorithm::Blake3, fixed, expected.as_bytes()).unwrap());
+        assert!(!verify_file(path, HashAlgorithm::Blake3, fixed, &[0u8; 32]).unwrap());
+
+        // Merkle roots come out the same when buffer and chunk ends don't line up
+        let (metadata, _) = chunk_file_with_size(path, 100).unwrap();
+        let file = std::fs::File::open(path).unwrap();
+        let hash = hash_reader(file, HashAlgorithm::Blake3Merkle, metadata.chunking(), 7).unwrap();
+        assert_eq!(hash, metadata.content_hash);
+        let algo = HashAlgorithm::Blake3Merkle;
+        assert!(verify_file(path, algo, metadata.chunking(), &metadata.content_hash).unwrap());
+
+        // Content-defined chunks end where the content says, not every 100 bytes
+        let content_defined = ChunkingStrategy::ContentDefined {
+            min_size: 16,
+            avg_size: 64,
+            max_size: 256,
+        };
+        let (metadata, _) = chunk_file_with_strategy(path, content_defined).unwrap();
+        assert_eq!(metadata.chunking(), content_defined);
+        assert!(verify_file(path, algo, metadata.chunking(), &metadata.content_hash).unwrap());
+        assert!(!verify_file(path, algo, ChunkingStrategy::Fixed(256), &metadata.content_hash)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_chunks_verify_against_content_hash() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&test_data).unwrap();
+
+        let (metadata, chunks) = chunk_file_with_size(temp_file.path(), 1000).unwrap();
+        let leaves: Vec<ContentHash> = metadata.chunks.iter().map(|c| c.hash).collect();
+        assert_eq!(metadata.content_hash, crate::merkle::root(&leaves, 5000));
+
+        let algo = metadata.hash_algo;
+        let hash = &metadata.content_hash;
+        for (index, chunk) in chunks.iter().enumerate() {
+            let index = index as u32;
+            let proof = metadata.merkle_proof(index).unwrap();
+            assert!(verify_chunk_with_proof(algo, chunk, index, 5, 5000, &proof, hash));
+            // A different chunk in this position is caught, as is a wrong size
+            let other = &chunks[(index as usize + 1) % 5];
+            assert!(!verify_chunk_with_proof(algo, other, index, 5, 5000, &proof, hash));
+            assert!(!verify_chunk_with_proof(algo, chunk, index, 5, 4999, &proof, hash));
+        }
+        assert!(metadata.merkle_proof(5).is_none());
+
+        // Flat hashes have no proofs
+        let mut legacy = metadata.clone();
+        legacy.hash_algo = HashAlgorithm::Blake3;
+        assert!(legacy.merkle_proof(0).is_none());
+    }
+
+    #[test]
+    fn test_file_of_child_hashes_does_not_match() {
+        // Two chunk hashes behind the node prefix once hashed, as a one-chunk
+        // file, to the content hash of the two-chunk file
+        let mut real_file = NamedTempFile::new().unwrap();
+        real_file.write_all(&[7u8; 1500]).unwrap();
+        let (real, _) = chunk_file_with_size(real_file.path(), 1000).unwrap();
+
+        let mut forged = vec![1u8];
+        forged.extend_from_slice(&real.chunks[0].hash);
+        forged.extend_from_slice(&real.chunks[1].hash);
+        let mut forged_file = NamedTempFile::new().unwrap();
+        forged_file.write_all(&forged).unwrap();
+
+        let algo = HashAlgorithm::Blake3Merkle;
+        let hash = &real.content_hash;
+        for chunking in [ChunkingStrategy::Fixed(1000), ChunkingStrategy::default()] {
+            assert!(!verify_file(forged_file.path(), algo, chunking, hash).unwrap());
+        }
+        assert!(!verify_chunk_with_proof(algo, &forged, 0, 1, 65, &[], hash));
+        assert!(!verify_chunk_with_proof(algo, &forged, 0, 2, 1500, &[], hash));
+    }
+
     #[test]
     fn test_content_hash_matches_raw_data() {
         let mut temp_file = NamedTempFile::new().unwrap();
@@ -183,17 +1020,113 @@ mod tests {
 
         let (metadata, _) = chunk_file(temp_file.path()).unwrap();
 
-        let expected = blake3::hash(test_data);
-        assert_eq!(metadata.content_hash, *expected.as_bytes());
+        // A single chunk's hash is the only leaf
+        let chunk_hash = *blake3::hash(test_data).as_bytes();
+        assert_eq!(metadata.hash_algo, HashAlgorithm::Blake3Merkle);
+        assert_eq!(metadata.chunks[0].hash, chunk_hash);
+        assert_eq!(metadata.content_hash, crate::merkle::root(&[chunk_hash], 11));
+    }
+
+    #[test]
+    fn test_keywords_extracted_from_filename() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("Big_Buck-Bunny.1080p.mkv");
+        std::fs::write(&path, b"video").unwrap();
+
+        let (metadata, _) = chunk_file(&path).unwrap();
+        assert_eq!(metadata.keywords, crate::keywords::extract("Big_Buck-Bunny.1080p.mkv"));
+        assert_eq!(metadata.keywords, vec!["big", "buck", "bunny", "1080p", "mkv"]);
+    }
+
+    #[test]
+    fn test_chunk_count_range_matches_chunk_file() {
+        let content_defined = ChunkingStrategy::ContentDefined {
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+        };
+        let strategies = [ChunkingStrategy::default(), ChunkingStrategy::Fixed(1000), content_defined];
+        for strategy in strategies {
+            let chunk_size = strategy.max_chunk_size();
+            for size in [0, 1, chunk_size, chunk_size + 1, chunk_size * 3] {
+                let mut temp_file = NamedTempFile::new().unwrap();
+                let data: Vec<u8> = (0..size).map(|i| (i * 7 % 251) as u8).collect();
+                temp_file.write_all(&data).unwrap();
+
+                let (metadata, _) = chunk_file_with_strategy(temp_file.path(), strategy).unwrap();
+                let count = metadata.chunks.len() as u64;
+                assert!(strategy.chunk_count_range(size as u64).contains(&count));
+                assert_eq!(metadata.chunking(), strategy);
+                for (index, chunk) in metadata.chunks.iter().enumerate() {
+                    let range = strategy.chunk_size_range(index as u32, count as u32, size as u64);
+                    assert!(range.contains(&(chunk.size as u64)));
+                }
+            }
+        }
+        assert_eq!(ChunkingStrategy::Fixed(1000).chunk_count_range(3001), 4..=4);
+        assert_eq!(content_defined.chunk_count_range(3000), 3..=47);
+        assert_eq!(ChunkingStrategy::Fixed(1000).chunk_size_range(2, 4, 3001), 1000..=1000);
+        assert_eq!(ChunkingStrategy::Fixed(1000).chunk_size_range(3, 4, 3001), 1..=1);
+        assert_eq!(content_defined.chunk_size_range(0, 3, 3000), 64..=1024);
+    }
+
+    #[test]
+    fn test_compress_chunk_roundtrip() {
+        use crate::proto::compression;
+
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let compressed = compress_chunk(&text).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(decompress_chunk(compression::ZSTD, &compressed).unwrap(), text);
+
+        // The chunk hash is of what comes out, not what went over the wire
+        let hash = HashAlgorithm::Blake3.hash(&text);
+        let restored = decompress_chunk(compression::ZSTD, &compressed).unwrap();
+        assert!(verify_chunk(HashAlgorithm::Blake3, &restored, &hash));
+
+        assert_eq!(decompress_chunk(compression::NONE, &text).unwrap(), text);
+        assert!(decompress_chunk(99, &compressed).is_err());
+        assert!(decompress_chunk(compression::ZSTD, b"not zstd").is_err());
+    }
+
+    #[test]
+    fn test_compress_chunk_skips_incompressible() {
+        // Hashes of a counter look random, so zstd can't shrink them
+        let noise: Vec<u8> = (0u32..2048)
+            .flat_map(|i| *blake3::hash(&i.to_le_bytes()).as_bytes())
+            .collect();
+        assert!(compress_chunk(&noise).is_none());
+        assert!(compress_chunk(&[]).is_none());
+    }
+
+    #[test]
+    fn test_encrypt_chunk_roundtrip() {
+        let key = derive_chunk_key("correct horse battery staple");
+        let encryption = chunk_encryption(&key, [7; 8]);
+        let plain = b"some chunk of a private file".to_vec();
+
+        let mut data = plain.clone();
+        encrypt_chunk(&key, &encryption, 3, &mut data);
+        assert_eq!(data.len(), plain.len());
+        assert_ne!(data, plain);
+
+        // Another index gets another keystream
+        let mut other = plain.clone();
+        encrypt_chunk(&key, &encryption, 4, &mut other);
+        assert_ne!(other, data);
+
+        decrypt_chunk(&key, &encryption, 3, &mut data);
+        assert_eq!(data, plain);
+
+        assert!(check_chunk_key(&key, &encryption).is_ok());
+        let wrong = derive_chunk_key("correct horse battery stapler");
+        assert!(check_chunk_key(&wrong, &encryption).is_err());
     }
 
     #[test]
-    fn test_extract_keywords() {
-        let keywords = FileMetadata::extract_keywords("Big_Buck-Bunny.1080p.mkv");
-        assert!(keywords.contains(&"big".to_string()));
-        assert!(keywords.contains(&"buck".to_string()));
-        assert!(keywords.contains(&"bunny".to_string()));
-        assert!(keywords.contains(&"1080p".to_string()));
-        assert!(keywords.contains(&"mkv".to_string()));
+    fn test_decompress_chunk_is_bounded() {
+        let bomb = zstd::bulk::compress(&vec![0u8; MAX_DECOMPRESSED_CHUNK_SIZE + 1], 3).unwrap();
+        assert!(decompress_chunk(crate::proto::compression::ZSTD, &bomb).is_err());
+        assert!(compress_chunk(&vec![0u8; MAX_DECOMPRESSED_CHUNK_SIZE + 1]).is_none());
     }
 }
@@ -1,5 +1,6 @@
 //! Error types for Brisby
 
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -43,8 +44,22 @@ pub enum Error {
     #[error("Receive failed: {0}")]
     ReceiveFailed(String),
 
+    #[error("operation timed out after {0:?}")]
+    Timeout(Duration),
+
     #[error("Invalid address: {0}")]
     InvalidAddress(String),
 }
 
+impl Error {
+    /// Whether the failure may go away by itself, so the operation is worth
+    /// trying again
+    ///
+    /// Only timeouts and failed receives count; a decode error or a
+    /// rejected request comes out the same however often it is retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Timeout(_) | Error::ReceiveFailed(_))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,164 @@
+//! Content hash algorithms
+//!
+//! Every hash in file metadata is computed with the algorithm named in
+//! `FileMetadata::hash_algo`, so verification dispatches on it instead of
+//! assuming one scheme. Chunk hashes are BLAKE3 under every algorithm; they
+//! differ in how the content hash covers the file. Names that this build
+//! doesn't recognize are rejected rather than verified with the wrong hash.
+
+use crate::error::{Error, Result};
+use crate::merkle::MerkleHasher;
+use crate::types::ContentHash;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Algorithm used for content and chunk hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum HashAlgorithm {
+    /// Unkeyed BLAKE3 of the whole file
+    Blake3,
+    /// Merkle root over the BLAKE3 chunk hashes, see `crate::merkle`
+    ///
+    /// Lets each chunk be verified against the content hash on its own.
+    #[default]
+    Blake3Merkle,
+}
+
+impl HashAlgorithm {
+    /// Name used in manifests
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Blake3Merkle => "blake3-merkle",
+        }
+    }
+
+    /// Whether content hashes are Merkle roots over the chunk hashes
+    pub fn is_merkle(&self) -> bool {
+        matches!(self, HashAlgorithm::Blake3Merkle)
+    }
+
+    /// Hash `data` in one go, as for a chunk hash
+    pub fn hash(&self, data: &[u8]) -> ContentHash {
+        match self {
+            HashAlgorithm::Blake3 | HashAlgorithm::Blake3Merkle => *blake3::hash(data).as_bytes(),
+        }
+    }
+
+    /// Check `data` against an expected hash
+    pub fn verify(&self, data: &[u8], expected: &ContentHash) -> bool {
+        &self.hash(data) == expected
+    }
+
+    /// Start an incremental content hash, for content fed in pieces
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Blake3Merkle => Hasher::Blake3Merkle(Box::default()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "blake3-merkle" => Ok(HashAlgorithm::Blake3Merkle),
+            other => Err(Error::InvalidData(format!(
+                "unsupported hash algorithm: {}",
+                other
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for HashAlgorithm {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<HashAlgorithm> for String {
+    fn from(algo: HashAlgorithm) -> Self {
+        algo.name().to_string()
+    }
+}
+
+/// Incremental content hasher for any `HashAlgorithm`
+///
+/// Content is fed in order; `end_chunk` marks where each chunk ends, which
+/// only matters to Merkle roots.
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Blake3Merkle(Box<MerkleHasher>),
+}
+
+impl Hasher {
+    /// Feed more data into the hash, as part of the current chunk
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Blake3Merkle(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Mark the end of the current chunk
+    pub fn end_chunk(&mut self) {
+        match self {
+            Hasher::Blake3(_) => {}
+            Hasher::Blake3Merkle(hasher) => hasher.end_chunk(),
+        }
+    }
+
+    /// Finish and return the hash
+    pub fn finalize(&self) -> ContentHash {
+        match self {
+            Hasher::Blake3(hasher) => *hasher.finalize().as_bytes(),
+            Hasher::Blake3Merkle(hasher) => hasher.finalize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blake3_dispatch() {
+        let algo = HashAlgorithm::Blake3;
+        let expected = *blake3::hash(b"brisby").as_bytes();
+
+        assert_eq!(algo.hash(b"brisby"), expected);
+        assert!(algo.verify(b"brisby", &expected));
+        assert!(!algo.verify(b"tampered", &expected));
+
+        let mut hasher = algo.hasher();
+        hasher.update(b"bris");
+        hasher.update(b"by");
+        assert_eq!(hasher.finalize(), expected);
+    }
+
+    #[test]
+    fn test_unknown_algorithm_rejected() {
+        assert_eq!("blake3".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Blake3);
+        assert_eq!(
+            "blake3-merkle".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Blake3Merkle
+        );
+        assert!("sha3-256".parse::<HashAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Keyword normalization shared by indexing and search
+//!
+//! Keywords are extracted from the filename when a file is chunked, stored by
+//! the client's local index and by index providers, and matched against search
+//! queries. Every one of those steps tokenizes text here, so a query matches
+//! the same files whether they were indexed locally or published to a
+//! provider, and whichever client published them.
+//!
+//! Text is split on anything that isn't alphanumeric and lowercased. Stored
+//! keywords also drop tokens shorter than `MIN_KEYWORD_LEN` and repeats;
+//! queries keep every token so that a query like `episode_7` still narrows
+//! the match.
+//!
+//! Since every token is quoted, no query text can be read as an FTS5
+//! operator. Users who want the operators opt into `QueryMode::Advanced`,
+//! where the query is handed to FTS5 as written.
+
+/// Tokens shorter than this (in characters) aren't kept as keywords
+pub const MIN_KEYWORD_LEN: usize = 2;
+
+/// How a search query is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryMode {
+    /// Words to match, whatever characters they contain, see
+    /// `match_expression`
+    #[default]
+    Keywords,
+    /// An FTS5 query, so `AND`, `OR`, `NOT`, `NEAR`, parentheses, `*`
+    /// prefixes and column filters work; malformed ones fail to run
+    Advanced,
+}
+
+/// Split text into lowercase alphanumeric tokens
+pub fn tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Keywords for `text`, e.g. a filename: its tokens of at least
+/// `MIN_KEYWORD_LEN` characters, first occurrence only
+pub fn extract(text: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    for token in tokens(text) {
+        if token.chars().count() >= MIN_KEYWORD_LEN && !keywords.contains(&token) {
+            keywords.push(token);
+        }
+    }
+    keywords
+}
+
+/// Normalize keywords as sent by a publisher, which may not have used
+/// `extract` (older clients, or hand-written lists)
+pub fn normalize(keywords: &[String]) -> Vec<String> {
+    extract(&keywords.join(" "))
+}
+
+/// SQLite FTS5 `MATCH` expression for a search query
+///
+/// Each whitespace-separated word becomes a quoted phrase of its tokens, and
+/// all phrases must match. Returns `None` if the query has no tokens at all,
+/// since an empty expression is an FTS syntax error.
+pub fn match_expression(query: &str) -> Option<String> {
+    let phrases: Vec<String> = query
+        .split_whitespace()
+        .map(|word| tokens(word).collect::<Vec<_>>().join(" "))
+        .filter(|phrase| !phrase.is_empty())
+        .map(|phrase| format!("\"{}\"", phrase))
+        .collect();
+    (!phrases.is_empty()).then(|| phrases.join(" "))
+}
+
+/// SQLite FTS5 `MATCH` expression for a query in `mode`
+///
+/// Returns `None` if there is nothing to match.
+pub fn query_expression(query: &str, mode: QueryMode) -> Option<String> {
+    match mode {
+        QueryMode::Keywords => match_expression(query),
+        QueryMode::Advanced => {
+            let query = query.trim();
+            (!query.is_empty()).then(|| query.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(
+            extract("Big_Buck-Bunny.1080p.BIG.mkv"),
+            vec!["big", "buck", "bunny", "1080p", "mkv"]
+        );
+        // Short tokens are dropped, counting characters rather than bytes
+        assert_eq!(extract("a é éé x1"), vec!["éé", "x1"]);
+    }
+
+    #[test]
+    fn test_normalize_matches_extract() {
+        let sent = vec!["Big_Buck".to_string(), "bunny".to_string(), "BUNNY".to_string()];
+        assert_eq!(normalize(&sent), extract("big buck bunny"));
+    }
+
+    #[test]
+    fn test_match_expression() {
+        assert_eq!(match_expression("hello").as_deref(), Some("\"hello\""));
+        assert_eq!(
+            match_expression("Hello World").as_deref(),
+            Some("\"hello\" \"world\"")
+        );
+        // Punctuation separates tokens within a phrase and can't break out of it
+        assert_eq!(match_expression("test-file").as_deref(), Some("\"test file\""));
+        assert_eq!(match_expression("file:name").as_deref(), Some("\"file name\""));
+        assert_eq!(
+            match_expression("say \"hello\"").as_deref(),
+            Some("\"say\" \"hello\"")
+        );
+        assert_eq!(match_expression("episode_7").as_deref(), Some("\"episode 7\""));
+        assert_eq!(match_expression(" -- \"\" "), None);
+
+        // FTS5 syntax is only ever matched as words
+        assert_eq!(match_expression("foo\"bar").as_deref(), Some("\"foo bar\""));
+        assert_eq!(match_expression("a AND").as_deref(), Some("\"a\" \"and\""));
+        assert_eq!(match_expression("(x OR y*)").as_deref(), Some("\"x\" \"or\" \"y\""));
+        assert_eq!(match_expression("*"), None);
+    }
+
+    #[test]
+    fn test_advanced_query_passed_through() {
+        assert_eq!(
+            query_expression(" big OR buck* ", QueryMode::Advanced).as_deref(),
+            Some("big OR buck*")
+        );
+        assert_eq!(
+            query_expression("big OR buck*", QueryMode::Keywords),
+            match_expression("big OR buck*")
+        );
+        assert_eq!(query_expression("  ", QueryMode::Advanced), None);
+    }
+}
@@ -5,6 +5,9 @@
 
 pub mod chunk;
 pub mod error;
+pub mod hash;
+pub mod keywords;
+pub mod merkle;
 pub mod proto;
 pub mod transport;
 pub mod types;
@@ -13,7 +16,11 @@ pub mod types;
 pub mod nym_transport;
 
 pub use error::{Error, Result};
-pub use transport::{NymAddress, ReceivedMessage, SenderTag, Transport, TransportConfig, TransportHandle};
+pub use hash::HashAlgorithm;
+pub use transport::{
+    retry_with_backoff, NymAddress, NymAddressParts, ReceivedMessage, SenderTag, Transport,
+    TransportConfig, TransportHandle,
+};
 pub use types::*;
 
 #[cfg(feature = "nym")]
@@ -0,0 +1,268 @@
+//! Merkle trees over chunk hashes
+//!
+//! Under `HashAlgorithm::Blake3Merkle` a file's content hash is the root of a
+//! binary tree whose leaves are its chunk hashes. A single chunk can then be
+//! checked against the content hash with a proof made of the sibling hashes
+//! on its path to the root, without the rest of the file or any chunk hashes
+//! from the seeder.
+//!
+//! Each node hash starts with a prefix byte saying what it is. A leaf hashes
+//! its chunk's hash, and a parent its two children; a node without a sibling
+//! is carried up to the next level unchanged. Without the prefixes a chunk
+//! made of two child hashes would pass for the parent of those children, so
+//! a one-chunk file could stand in for a bigger one. The content hash seals
+//! the top node together with the chunk count and the file size, so neither
+//! can be changed under it either.
+
+use crate::types::ContentHash;
+
+/// Prefix of leaf hashes, over a chunk hash
+const LEAF_PREFIX: u8 = 0;
+/// Prefix of parent node hashes, over the two children
+const NODE_PREFIX: u8 = 1;
+/// Prefix of the content hash, over the counts and the top node
+const ROOT_PREFIX: u8 = 2;
+
+fn leaf(chunk_hash: &ContentHash) -> ContentHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(chunk_hash);
+    *hasher.finalize().as_bytes()
+}
+
+fn parent(left: &ContentHash, right: &ContentHash) -> ContentHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+/// The content hash of a file of `leaf_count` chunks and `size` bytes whose
+/// tree has `top` as its top node
+fn seal(top: &ContentHash, leaf_count: u64, size: u64) -> ContentHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[ROOT_PREFIX]);
+    hasher.update(&leaf_count.to_le_bytes());
+    hasher.update(&size.to_le_bytes());
+    hasher.update(top);
+    *hasher.finalize().as_bytes()
+}
+
+/// Every level of a Merkle tree, from the leaves up to the top node
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    levels: Vec<Vec<ContentHash>>,
+    size: u64,
+}
+
+impl MerkleTree {
+    /// Build the tree over `chunk_hashes`, in chunk order, of a file of
+    /// `size` bytes
+    pub fn new(chunk_hashes: &[ContentHash], size: u64) -> Self {
+        let mut levels = vec![chunk_hashes.iter().map(leaf).collect::<Vec<_>>()];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels, size }
+    }
+
+    /// Number of leaves
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// The root hash, which is the file's content hash
+    pub fn root(&self) -> ContentHash {
+        let top = match self.levels.last().and_then(|level| level.first()) {
+            Some(top) => *top,
+            None => *blake3::hash(&[]).as_bytes(),
+        };
+        seal(&top, self.leaf_count() as u64, self.size)
+    }
+
+    /// Sibling hashes from leaf `index` up to the top node, see `verify_proof`
+    ///
+    /// Returns `None` if `index` is out of range.
+    pub fn proof(&self, index: u32) -> Option<Vec<ContentHash>> {
+        let mut index = index as usize;
+        if index >= self.leaf_count() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
+
+/// Root of the tree over `chunk_hashes` of a file of `size` bytes
+pub fn root(chunk_hashes: &[ContentHash], size: u64) -> ContentHash {
+    MerkleTree::new(chunk_hashes, size).root()
+}
+
+/// Check that `chunk_hash` is chunk `index` of a file of `leaf_count`
+/// chunks and `size` bytes with the given `root`
+pub fn verify_proof(
+    chunk_hash: &ContentHash,
+    index: u32,
+    leaf_count: u32,
+    size: u64,
+    proof: &[ContentHash],
+    root: &ContentHash,
+) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+
+    let mut node = leaf(chunk_hash);
+    let mut index = index;
+    let mut width = leaf_count;
+    let mut siblings = proof.iter();
+    while width > 1 {
+        // The last node of an odd-sized level has no sibling
+        if index ^ 1 < width {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            node = if index.is_multiple_of(2) {
+                parent(&node, sibling)
+            } else {
+                parent(sibling, &node)
+            };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+
+    siblings.next().is_none() && &seal(&node, leaf_count as u64, size) == root
+}
+
+/// Incremental Merkle root over content fed chunk by chunk
+#[derive(Default)]
+pub struct MerkleHasher {
+    leaves: Vec<ContentHash>,
+    current: blake3::Hasher,
+    current_len: usize,
+    size: u64,
+}
+
+impl MerkleHasher {
+    /// Feed more data into the current chunk
+    pub fn update(&mut self, data: &[u8]) {
+        self.current.update(data);
+        self.current_len += data.len();
+        self.size += data.len() as u64;
+    }
+
+    /// Finish the current chunk; a no-op if it is empty
+    pub fn end_chunk(&mut self) {
+        if self.current_len > 0 {
+            self.leaves.push(*self.current.finalize().as_bytes());
+            self.current.reset();
+            self.current_len = 0;
+        }
+    }
+
+    /// Root over the chunks so far, including an unfinished one
+    pub fn finalize(&self) -> ContentHash {
+        if self.current_len == 0 {
+            return root(&self.leaves, self.size);
+        }
+        let mut leaves = self.leaves.clone();
+        leaves.push(*self.current.finalize().as_bytes());
+        root(&leaves, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<ContentHash> {
+        (0..count).map(|i| *blake3::hash(&[i]).as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_proofs_verify_for_every_leaf() {
+        for count in 1..=9u8 {
+            let leaves = leaves(count);
+            let size = count as u64 * 100;
+            let tree = MerkleTree::new(&leaves, size);
+            let root = tree.root();
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let i = index as u32;
+                let n = count as u32;
+                let proof = tree.proof(i).unwrap();
+                assert!(verify_proof(leaf, i, n, size, &proof, &root));
+
+                // Wrong leaf, position, tree size or file size
+                let other = leaves[(index + 1) % leaves.len()];
+                if other != *leaf {
+                    assert!(!verify_proof(&other, i, n, size, &proof, &root));
+                }
+                assert!(!verify_proof(leaf, n, n, size, &proof, &root));
+                assert!(!verify_proof(leaf, i, n * 2, size, &proof, &root));
+                assert!(!verify_proof(leaf, i, n, size + 1, &proof, &root));
+            }
+            assert!(tree.proof(count as u32).is_none());
+        }
+    }
+
+    #[test]
+    fn test_small_trees() {
+        assert_eq!(root(&[], 0), seal(blake3::hash(&[]).as_bytes(), 0, 0));
+        let single = leaves(1);
+        assert_eq!(root(&single, 5), seal(&leaf(&single[0]), 1, 5));
+
+        let three = leaves(3);
+        let [a, b, c] = [0, 1, 2].map(|i| leaf(&three[i]));
+        assert_eq!(root(&three, 9), seal(&parent(&parent(&a, &b), &c), 3, 9));
+    }
+
+    #[test]
+    fn test_chunk_of_child_hashes_is_not_a_parent() {
+        // A chunk holding the node prefix and two chunk hashes used to hash
+        // to the parent of those chunks, passing for the two-chunk file
+        let two = leaves(2);
+        let real = root(&two, 200);
+        let mut forged = vec![NODE_PREFIX];
+        forged.extend_from_slice(&two[0]);
+        forged.extend_from_slice(&two[1]);
+        let forged_hash = *blake3::hash(&forged).as_bytes();
+
+        assert_ne!(root(&[forged_hash], forged.len() as u64), real);
+        assert!(!verify_proof(&forged_hash, 0, 1, forged.len() as u64, &[], &real));
+        assert!(!verify_proof(&forged_hash, 0, 1, 200, &[], &real));
+        assert!(!verify_proof(&forged_hash, 0, 2, 200, &[], &real));
+    }
+
+    #[test]
+    fn test_hasher_matches_tree() {
+        let chunks: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let mut hasher = MerkleHasher::default();
+        for chunk in chunks {
+            // Split within a chunk doesn't matter, only chunk ends do
+            hasher.update(&chunk[..2]);
+            hasher.update(&chunk[2..]);
+            hasher.end_chunk();
+        }
+
+        let leaves: Vec<ContentHash> = chunks.iter().map(|c| *blake3::hash(c).as_bytes()).collect();
+        assert_eq!(hasher.finalize(), root(&leaves, 16));
+    }
+}
@@ -9,16 +9,45 @@ use crate::{Error, Result};
 use nym_sdk::mixnet::{self, IncludedSurbs, MixnetClient, MixnetMessageSender, ReconstructedMessage};
 use std::convert::TryInto;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::sync::Mutex;
 
 /// Size of an AnonymousSenderTag in bytes
 const SENDER_TAG_SIZE: usize = 16;
 
+/// Attempts to rebuild a dead client before giving up
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// Wait after the first failed reconnect attempt, doubled after each one
+const RECONNECT_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+type SharedClient = Arc<Mutex<MixnetClient>>;
+
+/// The live client, shared with reconnect tasks
+///
+/// A reconnect runs in its own task so it finishes even if the call that
+/// started it is cancelled, e.g. by `receive_timeout` running out.
+#[derive(Default)]
+struct ClientSlot {
+    client: RwLock<Option<SharedClient>>,
+    /// Held while a reconnect is under way, so only one runs at a time
+    reconnecting: Mutex<()>,
+}
+
+impl ClientSlot {
+    fn current(&self) -> Option<SharedClient> {
+        self.client.read().unwrap().clone()
+    }
+
+    fn set(&self, client: Option<SharedClient>) -> Option<SharedClient> {
+        std::mem::replace(&mut *self.client.write().unwrap(), client)
+    }
+}
+
 /// Real Nym mixnet transport
 pub struct NymTransport {
     config: TransportConfig,
-    client: Option<Arc<Mutex<MixnetClient>>>,
+    slot: Arc<ClientSlot>,
     address: Option<NymAddress>,
 }
 
@@ -27,7 +56,7 @@ impl NymTransport {
     pub fn new(config: TransportConfig) -> Self {
         Self {
             config,
-            client: None,
+            slot: Arc::new(ClientSlot::default()),
             address: None,
         }
     }
@@ -52,11 +81,11 @@ impl NymTransport {
         });
         ReceivedMessage::new(msg.message, sender_tag)
     }
-}
 
-impl Transport for NymTransport {
-    async fn connect(&mut self) -> Result<()> {
-        let client = if let Some(ref storage_path) = self.config.storage_path {
+    /// Connect a new client, loading the identity from the storage path if
+    /// there is one
+    async fn build_client(config: &TransportConfig) -> Result<MixnetClient> {
+        if let Some(ref storage_path) = config.storage_path {
             // Use persistent storage
             let storage_paths = mixnet::StoragePaths::new_from_dir(storage_path)
                 .map_err(|e| Error::ConnectionFailed(e.to_string()))?;
@@ -68,26 +97,151 @@ impl Transport for NymTransport {
                 .map_err(|e| Error::ConnectionFailed(e.to_string()))?
                 .connect_to_mixnet()
                 .await
-                .map_err(|e| Error::ConnectionFailed(e.to_string()))?
+                .map_err(|e| Error::ConnectionFailed(e.to_string()))
         } else {
             // Ephemeral session
             mixnet::MixnetClient::connect_new()
                 .await
-                .map_err(|e| Error::ConnectionFailed(e.to_string()))?
-        };
+                .map_err(|e| Error::ConnectionFailed(e.to_string()))
+        }
+    }
+
+    /// The current client, or `not_connected` if there is none
+    fn client(&self, not_connected: fn(String) -> Error) -> Result<SharedClient> {
+        self.slot
+            .current()
+            .ok_or_else(|| not_connected("not connected".to_string()))
+    }
+
+    /// Replace the client with a new one from the same identity, keeping our
+    /// address
+    ///
+    /// Tries up to `MAX_RECONNECT_ATTEMPTS` times, backing off between
+    /// attempts. Sends and receives still using the old client fail or time
+    /// out and pick up the new one on their next call; none of them holds
+    /// anything the reconnect waits for. An ephemeral identity can't be
+    /// restored, so without a storage path this fails; `connect` again for a
+    /// new address instead. Fails too if not connected.
+    pub async fn reconnect(&self) -> Result<()> {
+        let current = self.client(Error::ConnectionFailed)?;
+        self.replace_client(current).await
+    }
+
+    /// Rebuild the client unless `dead` has already been replaced, or the
+    /// transport disconnected meanwhile
+    async fn replace_client(&self, dead: SharedClient) -> Result<()> {
+        if self.config.storage_path.is_none() {
+            return Err(Error::ConnectionFailed(
+                "an ephemeral identity can't be restored; connect again for a new address"
+                    .to_string(),
+            ));
+        }
+
+        let slot = self.slot.clone();
+        let config = self.config.clone();
+        let task = tokio::spawn(async move {
+            let _reconnecting = slot.reconnecting.lock().await;
+            match slot.current() {
+                Some(current) if Arc::ptr_eq(&current, &dead) => {}
+                // Another caller got there first
+                _ => return Ok(()),
+            }
+
+            let mut attempt = 1;
+            let client = loop {
+                match Self::build_client(&config).await {
+                    Ok(client) => break client,
+                    Err(_) if attempt < MAX_RECONNECT_ATTEMPTS => {
+                        let delay = RECONNECT_BASE_DELAY * 2u32.pow(attempt - 1);
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            let old = slot.set(Some(Arc::new(Mutex::new(client))));
+
+            // Shut the old client down, unless a call still holds it
+            drop(dead);
+            if let Some(old) = old.and_then(|old| Arc::try_unwrap(old).ok()) {
+                old.into_inner().disconnect().await;
+            }
+            Ok(())
+        });
+        task.await
+            .map_err(|e| Error::ConnectionFailed(format!("reconnect task failed: {}", e)))?
+    }
+
+    /// Whether a dead client is rebuilt without being asked: only with
+    /// `auto_reconnect` set and an identity to rebuild it from
+    fn reconnects_automatically(&self) -> bool {
+        self.config.auto_reconnect && self.config.storage_path.is_some()
+    }
+
+    /// Deal with `dead` having failed: reconnect if enabled, then return
+    /// `error` either way, since the operation it failed is lost
+    async fn recover(&self, dead: SharedClient, error: Error) -> Error {
+        if !self.reconnects_automatically() {
+            return error;
+        }
+        match self.replace_client(dead).await {
+            Ok(()) => error,
+            Err(e) => e,
+        }
+    }
+
+    /// Wait for the next message, reconnecting if the client's message
+    /// stream ends
+    ///
+    /// The client lock is only held while waiting on that one client, so a
+    /// reconnect never waits on a receive.
+    async fn next_message(&self) -> Result<ReceivedMessage> {
+        loop {
+            let client = self.client(Error::ReceiveFailed)?;
+            // wait_for_messages() returns Option<Vec<ReconstructedMessage>>
+            let received = client.lock().await.wait_for_messages().await;
+            match received {
+                Some(mut messages) => {
+                    if let Some(msg) = messages.pop() {
+                        return Ok(Self::convert_message(msg));
+                    }
+                }
+                // The stream only ends once the client has shut down
+                None => {
+                    if !self.reconnects_automatically() {
+                        return Err(Error::ReceiveFailed("connection lost".to_string()));
+                    }
+                    self.replace_client(client).await?;
+                    continue;
+                }
+            }
+            // Brief sleep to avoid busy-waiting
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Transport for NymTransport {
+    async fn connect(&mut self) -> Result<()> {
+        let client = Self::build_client(&self.config).await?;
 
         let addr = client.nym_address();
         self.address = Some(NymAddress::new(addr.to_string()));
-        self.client = Some(Arc::new(Mutex::new(client)));
+        self.slot.set(Some(Arc::new(Mutex::new(client))));
 
         Ok(())
     }
 
     async fn disconnect(&mut self) -> Result<()> {
-        if let Some(client) = self.client.take() {
-            let client = Arc::try_unwrap(client)
-                .map_err(|_| Error::Transport("client still in use".to_string()))?
-                .into_inner();
+        if let Some(client) = self.slot.set(None) {
+            let client = match Arc::try_unwrap(client) {
+                Ok(client) => client.into_inner(),
+                Err(client) => {
+                    // Put it back so the transport is left as it was
+                    self.slot.set(Some(client));
+                    return Err(Error::Transport("client still in use".to_string()));
+                }
+            };
             // disconnect() returns () in this SDK version
             client.disconnect().await;
         }
@@ -95,19 +249,36 @@ impl Transport for NymTransport {
         Ok(())
     }
 
+    async fn rotate_identity(&mut self, storage_path: Option<PathBuf>) -> Result<()> {
+        // Reconnecting from the same directory would load the same identity
+        if storage_path.is_some() && storage_path == self.config.storage_path {
+            return Err(Error::Transport("a new identity needs a new storage path".to_string()));
+        }
+
+        // Fails if the client is still shared, leaving it connected
+        self.disconnect().await?;
+
+        // If connecting fails the transport stays disconnected rather than
+        // falling back to the old identity; `connect` retries with the new one
+        self.config.storage_path = storage_path;
+        self.connect().await
+    }
+
     fn our_address(&self) -> Option<&NymAddress> {
         self.address.as_ref()
     }
 
     fn is_connected(&self) -> bool {
-        self.client.is_some()
+        self.slot.current().is_some()
+    }
+
+    fn is_address_stable(&self) -> bool {
+        // Keys in the storage directory are reloaded on the next connect
+        self.config.storage_path.is_some()
     }
 
     async fn send(&self, recipient: &NymAddress, data: Vec<u8>) -> Result<()> {
-        let client = self
-            .client
-            .as_ref()
-            .ok_or_else(|| Error::SendFailed("not connected".to_string()))?;
+        let client = self.client(Error::SendFailed)?;
 
         let recipient_addr: mixnet::Recipient = recipient
             .as_str()
@@ -117,21 +288,20 @@ impl Transport for NymTransport {
         // Always include at least one SURB so the receiver can reply
         let surbs = IncludedSurbs::new(self.config.surbs_per_message.max(1));
 
-        client
+        let sent = client
             .lock()
             .await
             .send_message(recipient_addr, data, surbs)
-            .await
-            .map_err(|e| Error::SendFailed(e.to_string()))?;
-
-        Ok(())
+            .await;
+        match sent {
+            Ok(()) => Ok(()),
+            // The message is gone either way; later sends use the new client
+            Err(e) => Err(self.recover(client, Error::SendFailed(e.to_string())).await),
+        }
     }
 
     async fn send_reply(&self, sender_tag: &SenderTag, data: Vec<u8>) -> Result<()> {
-        let client = self
-            .client
-            .as_ref()
-            .ok_or_else(|| Error::SendFailed("not connected".to_string()))?;
+        let client = self.client(Error::SendFailed)?;
 
         // Convert our SenderTag back to Nym's AnonymousSenderTag
         let tag_bytes: [u8; SENDER_TAG_SIZE] = sender_tag
@@ -140,57 +310,27 @@ impl Transport for NymTransport {
             .map_err(|_| Error::SendFailed("invalid sender tag size".to_string()))?;
         let anon_tag = mixnet::AnonymousSenderTag::from_bytes(tag_bytes);
 
-        client
-            .lock()
-            .await
-            .send_reply(anon_tag, data)
-            .await
-            .map_err(|e| Error::SendFailed(e.to_string()))?;
-
-        Ok(())
+        let sent = client.lock().await.send_reply(anon_tag, data).await;
+        match sent {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.recover(client, Error::SendFailed(e.to_string())).await),
+        }
     }
 
     async fn receive(&self) -> Result<ReceivedMessage> {
-        let client = self
-            .client
-            .as_ref()
-            .ok_or_else(|| Error::ReceiveFailed("not connected".to_string()))?;
-
-        loop {
-            // wait_for_messages() returns Option<Vec<ReconstructedMessage>>
-            if let Some(mut messages) = client.lock().await.wait_for_messages().await {
-                if let Some(msg) = messages.pop() {
-                    return Ok(Self::convert_message(msg));
-                }
-            }
-            // Brief sleep to avoid busy-waiting
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-        }
+        self.next_message().await
     }
 
     async fn receive_timeout(
         &self,
         timeout: std::time::Duration,
     ) -> Result<Option<ReceivedMessage>> {
-        let client = self
-            .client
-            .as_ref()
-            .ok_or_else(|| Error::ReceiveFailed("not connected".to_string()))?;
-
-        // Use tokio timeout
-        match tokio::time::timeout(timeout, async {
-            loop {
-                if let Some(mut messages) = client.lock().await.wait_for_messages().await {
-                    if let Some(msg) = messages.pop() {
-                        return Ok::<_, Error>(Some(Self::convert_message(msg)));
-                    }
-                }
-                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-            }
-        })
-        .await
-        {
-            Ok(result) => result,
+        // Fail straight away rather than time out when not connected
+        self.client(Error::ReceiveFailed)?;
+
+        // A reconnect cut short by the timeout carries on in its own task
+        match tokio::time::timeout(timeout, self.next_message()).await {
+            Ok(result) => result.map(Some),
             Err(_) => Ok(None), // Timeout
         }
     }
@@ -198,6 +338,38 @@ impl Transport for NymTransport {
 
 #[cfg(test)]
 mod tests {
-    // Tests require a running Nym network, so they're integration tests
-    // See tests/nym_integration.rs
+    // Tests that talk to the mixnet require a running Nym network, so
+    // they're integration tests. See tests/nym_integration.rs
+    use super::*;
+
+    #[test]
+    fn test_persistent_storage_address_is_stable() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let transport = NymTransport::with_storage(temp_dir.path().join("nym"));
+        assert!(transport.is_address_stable());
+    }
+
+    #[test]
+    fn test_ephemeral_address_is_not_stable() {
+        assert!(!NymTransport::with_defaults().is_address_stable());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_needs_a_connection_and_stored_identity() {
+        assert!(TransportConfig::default().auto_reconnect);
+
+        // Nothing to reconnect before the first connect
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let transport = NymTransport::with_storage(temp_dir.path().join("nym"));
+        assert!(matches!(
+            transport.reconnect().await,
+            Err(Error::ConnectionFailed(_))
+        ));
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(matches!(
+            transport.receive_timeout(timeout).await,
+            Err(Error::ReceiveFailed(_))
+        ));
+        assert!(!transport.is_connected());
+    }
 }
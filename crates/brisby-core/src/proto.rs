@@ -3,7 +3,8 @@
 //! These are manually defined to match the brisby.proto schema,
 //! avoiding the need for protoc at build time.
 
-use crate::{Error, Result, PROTOCOL_VERSION};
+use crate::{Error, Result, SearchFilter, PROTOCOL_VERSION};
+use bytes::Bytes;
 use prost::Message;
 
 /// Message envelope wrapping all protocol messages
@@ -16,7 +17,7 @@ pub struct Envelope {
     #[prost(uint64, tag = "2")]
     pub request_id: u64,
     /// The actual message payload
-    #[prost(oneof = "Payload", tags = "10, 11, 20, 21, 30, 31, 40, 41, 42, 43, 44, 45, 46, 47, 100")]
+    #[prost(oneof = "Payload", tags = "10, 11, 12, 13, 20, 21, 22, 23, 24, 30, 31, 32, 33, 34, 35, 40, 41, 42, 43, 44, 45, 46, 47, 100")]
     pub payload: Option<Payload>,
 }
 
@@ -27,14 +28,32 @@ pub enum Payload {
     SearchRequest(SearchRequest),
     #[prost(message, tag = "11")]
     SearchResponse(SearchResponse),
+    #[prost(message, tag = "12")]
+    CapabilitiesRequest(CapabilitiesRequest),
+    #[prost(message, tag = "13")]
+    CapabilitiesResponse(CapabilitiesResponse),
     #[prost(message, tag = "20")]
     ChunkRequest(ChunkRequest),
     #[prost(message, tag = "21")]
     ChunkResponse(ChunkResponse),
+    #[prost(message, tag = "22")]
+    RelayRequest(RelayRequest),
+    #[prost(message, tag = "23")]
+    ChunkRangeRequest(ChunkRangeRequest),
+    #[prost(message, tag = "24")]
+    ChunkRangeResponse(ChunkRangeResponse),
     #[prost(message, tag = "30")]
     PublishRequest(PublishRequest),
     #[prost(message, tag = "31")]
     PublishResponse(PublishResponse),
+    #[prost(message, tag = "32")]
+    UnpublishRequest(UnpublishRequest),
+    #[prost(message, tag = "33")]
+    UnpublishResponse(UnpublishResponse),
+    #[prost(message, tag = "34")]
+    PublishChallenge(PublishChallenge),
+    #[prost(message, tag = "35")]
+    PublishProof(PublishProof),
     #[prost(message, tag = "40")]
     FindNodeRequest(FindNodeRequest),
     #[prost(message, tag = "41")]
@@ -61,14 +80,72 @@ pub enum Payload {
 pub struct SearchRequest {
     #[prost(string, tag = "1")]
     pub query: String,
+    /// See `result_limit`
     #[prost(uint32, tag = "2")]
     pub max_results: u32,
+    /// Drop results below this fraction of the best result's relevance (0 keeps all)
+    #[prost(float, tag = "3")]
+    pub min_relevance: f32,
+    /// Number of results to skip, for paging
+    #[prost(uint32, tag = "4")]
+    pub offset: u32,
+    /// Smallest file size in bytes (0 for no minimum)
+    #[prost(uint64, tag = "5")]
+    pub min_size: u64,
+    /// Largest file size in bytes (0 for no maximum)
+    #[prost(uint64, tag = "6")]
+    pub max_size: u64,
+    /// MIME type or top-level type to match (empty for any)
+    #[prost(string, tag = "7")]
+    pub mime_type: String,
+    /// Only files first published after this Unix timestamp (0 for any)
+    #[prost(uint64, tag = "8")]
+    pub published_after: u64,
+    /// Read `query` as FTS5 syntax instead of plain keywords
+    #[prost(bool, tag = "9")]
+    pub advanced: bool,
+}
+
+/// Most results an index provider returns for one `SearchRequest`
+pub const MAX_SEARCH_RESULTS: u32 = 100;
+
+impl SearchRequest {
+    /// Most results to return: `max_results`, where 0 (or anything above
+    /// `MAX_SEARCH_RESULTS`) means `MAX_SEARCH_RESULTS`
+    pub fn result_limit(&self) -> u32 {
+        match self.max_results {
+            0 => MAX_SEARCH_RESULTS,
+            n => n.min(MAX_SEARCH_RESULTS),
+        }
+    }
+
+    /// The filters set on this request
+    pub fn filter(&self) -> SearchFilter {
+        SearchFilter {
+            min_size: (self.min_size > 0).then_some(self.min_size),
+            max_size: (self.max_size > 0).then_some(self.max_size),
+            mime_type: (!self.mime_type.is_empty()).then(|| self.mime_type.clone()),
+            published_after: (self.published_after > 0).then_some(self.published_after),
+        }
+    }
+
+    /// Set the request's filters from `filter`
+    pub fn with_filter(mut self, filter: &SearchFilter) -> Self {
+        self.min_size = filter.min_size.unwrap_or(0);
+        self.max_size = filter.max_size.unwrap_or(0);
+        self.mime_type = filter.mime_type.clone().unwrap_or_default();
+        self.published_after = filter.published_after.unwrap_or(0);
+        self
+    }
 }
 
 #[derive(Clone, PartialEq, Message)]
 pub struct SearchResponse {
     #[prost(message, repeated, tag = "1")]
     pub results: Vec<SearchResult>,
+    /// Matches across all pages; 0 from providers that don't count them
+    #[prost(uint32, tag = "2")]
+    pub total_results: u32,
 }
 
 #[derive(Clone, PartialEq, Message)]
@@ -85,6 +162,76 @@ pub struct SearchResult {
     pub relevance: f32,
     #[prost(string, repeated, tag = "6")]
     pub seeders: Vec<String>,
+    /// Rendezvous tokens for seeders that published anonymously
+    #[prost(bytes, repeated, tag = "7")]
+    pub relay_tokens: Vec<Vec<u8>>,
+    /// Seeders listed for the file, 0 if not counted
+    #[prost(uint32, tag = "8")]
+    pub seeder_count: u32,
+    /// Size of every chunk but the last; 0 from providers that predate it,
+    /// meaning `CHUNK_SIZE`
+    #[prost(uint32, tag = "9")]
+    pub chunk_size: u32,
+    /// Set for files chunked by content, `chunk_size` being the largest chunk
+    #[prost(message, optional, tag = "10")]
+    pub content_defined: Option<ContentDefinedChunking>,
+}
+
+/// Chunk sizes of a file chunked by content, see `types::ContentDefinedSizes`
+#[derive(Clone, PartialEq, Message)]
+pub struct ContentDefinedChunking {
+    #[prost(uint32, tag = "1")]
+    pub min_size: u32,
+    #[prost(uint32, tag = "2")]
+    pub avg_size: u32,
+}
+
+impl From<crate::types::ContentDefinedSizes> for ContentDefinedChunking {
+    fn from(sizes: crate::types::ContentDefinedSizes) -> Self {
+        Self {
+            min_size: sizes.min_size,
+            avg_size: sizes.avg_size,
+        }
+    }
+}
+
+impl From<ContentDefinedChunking> for crate::types::ContentDefinedSizes {
+    fn from(chunking: ContentDefinedChunking) -> Self {
+        Self {
+            min_size: chunking.min_size,
+            avg_size: chunking.avg_size,
+        }
+    }
+}
+
+// Capability negotiation
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CapabilitiesRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CapabilitiesResponse {
+    /// Protocol version the index provider speaks
+    #[prost(uint32, tag = "1")]
+    pub protocol_version: u32,
+    /// Bitmask of supported features, see `capabilities`
+    #[prost(uint64, tag = "2")]
+    pub features: u64,
+}
+
+impl CapabilitiesResponse {
+    /// What an index provider that predates capability negotiation supports
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION as u32,
+            features: capabilities::SEARCH | capabilities::PUBLISH,
+        }
+    }
+
+    /// Check whether every bit of `feature` is supported
+    pub fn supports(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
 }
 
 // Transfer messages
@@ -105,10 +252,66 @@ pub struct ChunkResponse {
     pub content_hash: Vec<u8>,
     #[prost(uint32, tag = "2")]
     pub chunk_index: u32,
-    #[prost(bytes, tag = "3")]
-    pub data: Vec<u8>,
+    /// Chunk payload; shares the receive buffer when decoded with `Envelope::from_vec`
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: Bytes,
     #[prost(bytes, tag = "4")]
     pub chunk_hash: Vec<u8>,
+    /// Sibling hashes proving the chunk against `content_hash`, empty unless
+    /// the file has a Merkle content hash
+    #[prost(bytes, repeated, tag = "5")]
+    pub proof: Vec<Vec<u8>>,
+    /// How `data` is compressed, one of `compression`; `chunk_hash` is of
+    /// the decompressed bytes
+    #[prost(uint32, tag = "6")]
+    pub compression: u32,
+}
+
+/// A request for chunks `start_index..=end_index` of one file in one message
+///
+/// Seeders answer with at most `MAX_CHUNKS_PER_RANGE` chunks from the start
+/// of the range; the client asks again for whatever is left.
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkRangeRequest {
+    #[prost(bytes, tag = "1")]
+    pub content_hash: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub start_index: u32,
+    /// Last chunk wanted, inclusive
+    #[prost(uint32, tag = "3")]
+    pub end_index: u32,
+    #[prost(bytes, tag = "4")]
+    pub surb: Vec<u8>,
+}
+
+/// Consecutive chunks from the start of a requested range
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkRangeResponse {
+    #[prost(bytes, tag = "1")]
+    pub content_hash: Vec<u8>,
+    /// In index order, each verified like a single `ChunkResponse`
+    #[prost(message, repeated, tag = "2")]
+    pub chunks: Vec<ChunkResponse>,
+}
+
+/// Most chunks a seeder sends in one `ChunkRangeResponse`
+///
+/// Four default-sized chunks keep a response around 1 MiB, so one reply
+/// doesn't tie up the mixnet route for long.
+pub const MAX_CHUNKS_PER_RANGE: u32 = 4;
+
+/// A request for an index provider to pass on to an anonymous seeder
+///
+/// The seeder's reply comes back through the index provider under the
+/// request ID of the wrapped envelope.
+#[derive(Clone, PartialEq, Message)]
+pub struct RelayRequest {
+    /// Rendezvous token from `SearchResult::relay_tokens`
+    #[prost(bytes, tag = "1")]
+    pub token: Vec<u8>,
+    /// Encoded `Envelope` to deliver to the seeder
+    #[prost(bytes, tag = "2")]
+    pub payload: Vec<u8>,
 }
 
 // Publishing messages
@@ -127,6 +330,21 @@ pub struct PublishRequest {
     pub chunk_count: u32,
     #[prost(string, tag = "6")]
     pub nym_address: String,
+    #[prost(string, repeated, tag = "7")]
+    pub tags: Vec<String>,
+    /// List the seeder under a rendezvous token instead of its address
+    #[prost(bool, tag = "8")]
+    pub anonymous: bool,
+    /// MIME type detected by the publisher (empty if unknown)
+    #[prost(string, tag = "9")]
+    pub mime_type: String,
+    /// Size of every chunk but the last; 0 from publishers that predate it,
+    /// meaning `CHUNK_SIZE`
+    #[prost(uint32, tag = "10")]
+    pub chunk_size: u32,
+    /// Set for files chunked by content, `chunk_size` being the largest chunk
+    #[prost(message, optional, tag = "11")]
+    pub content_defined: Option<ContentDefinedChunking>,
 }
 
 #[derive(Clone, PartialEq, Message)]
@@ -135,6 +353,57 @@ pub struct PublishResponse {
     pub success: bool,
     #[prost(string, tag = "2")]
     pub error: String,
+    /// Secret that unpublishes this listing, see `UnpublishRequest`; a new
+    /// one is handed out on every publish, and empty from older providers
+    #[prost(bytes, tag = "3")]
+    pub unpublish_token: Vec<u8>,
+}
+
+/// Stop listing `nym_address` as a seeder of `content_hash`
+///
+/// Only honored with the `unpublish_token` from the seeder's latest
+/// `PublishResponse` for the file, so others can't unlist it.
+#[derive(Clone, PartialEq, Message)]
+pub struct UnpublishRequest {
+    #[prost(bytes, tag = "1")]
+    pub content_hash: Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub nym_address: String,
+    #[prost(bytes, tag = "3")]
+    pub unpublish_token: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct UnpublishResponse {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(string, tag = "2")]
+    pub error: String,
+}
+
+/// Sent in reply to a `PublishRequest`: the publisher must prove it holds
+/// chunk `chunk_index` before the file is listed
+#[derive(Clone, PartialEq, Message)]
+pub struct PublishChallenge {
+    #[prost(bytes, tag = "1")]
+    pub content_hash: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub chunk_index: u32,
+    /// Identifies the challenge; echoed back in `PublishProof`
+    #[prost(bytes, tag = "3")]
+    pub nonce: Vec<u8>,
+}
+
+/// Answer to a `PublishChallenge`: the challenged chunk and its Merkle proof
+/// against the content hash
+#[derive(Clone, PartialEq, Message)]
+pub struct PublishProof {
+    #[prost(bytes, tag = "1")]
+    pub nonce: Vec<u8>,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data: Bytes,
+    #[prost(bytes, repeated, tag = "3")]
+    pub proof: Vec<Vec<u8>>,
 }
 
 // DHT messages
@@ -181,6 +450,11 @@ pub struct ProtoSeeder {
     pub chunk_bitmap: Vec<u8>,
     #[prost(uint64, tag = "3")]
     pub last_seen: u64,
+    #[prost(uint64, tag = "4")]
+    pub expires_at: u64,
+    /// Set instead of `nym_address` for anonymous seeders
+    #[prost(bytes, tag = "5")]
+    pub relay_token: Vec<u8>,
 }
 
 #[derive(Clone, PartialEq, Message)]
@@ -236,10 +510,85 @@ impl Envelope {
         self.encode_to_vec()
     }
 
+    /// Encode the envelope into `buf`, replacing what it held
+    ///
+    /// `buf` keeps its allocation, so encoding message after message into
+    /// the same buffer only allocates when one is larger than any before.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(self.encoded_len());
+        self.encode(buf).expect("a Vec grows to fit");
+    }
+
     /// Decode an envelope from bytes, checking version compatibility
     pub fn from_bytes(buf: &[u8]) -> Result<Self> {
-        let envelope = Self::decode(buf)?;
+        Self::check_version(Self::decode(buf)?)
+    }
 
+    /// Decode an envelope from an owned receive buffer, checking version compatibility
+    ///
+    /// Unlike `from_bytes`, large payloads such as `ChunkResponse::data` are
+    /// not copied out: they become views into `buf`, which is kept alive for
+    /// as long as the payload is.
+    pub fn from_vec(buf: Vec<u8>) -> Result<Self> {
+        Self::check_version(Self::decode(Bytes::from(buf))?)
+    }
+
+    /// Encode an envelope around a payload already encoded with `Payload::encode_field`
+    ///
+    /// Produces the same bytes as `Envelope::new(request_id, payload).to_bytes()`,
+    /// letting a payload be encoded once and sent under many request IDs.
+    pub fn encode_with_payload(request_id: u64, payload_field: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Self::encode_with_payload_into(request_id, payload_field, &mut buf);
+        buf
+    }
+
+    /// `encode_with_payload` into `buf`, replacing what it held, see
+    /// `encode_into`
+    pub fn encode_with_payload_into(request_id: u64, payload_field: &[u8], buf: &mut Vec<u8>) {
+        let head = Self {
+            version: PROTOCOL_VERSION as u32,
+            request_id,
+            payload: None,
+        };
+        buf.clear();
+        buf.reserve(head.encoded_len() + payload_field.len());
+        head.encode(buf).expect("a Vec grows to fit");
+        buf.extend_from_slice(payload_field);
+    }
+
+    /// Read the envelope header without decoding the payload
+    ///
+    /// Walks the top-level fields only, skipping over the payload's bytes, so
+    /// a receiver can reject the wrong message type or version before paying
+    /// for a full decode.
+    pub fn peek_header(mut buf: &[u8]) -> Result<EnvelopeHeader> {
+        use prost::encoding::{decode_key, decode_varint, skip_field, DecodeContext, WireType};
+
+        let mut header = EnvelopeHeader::default();
+        while !buf.is_empty() {
+            let (tag, wire_type) = decode_key(&mut buf)?;
+            match (tag, wire_type) {
+                (1, WireType::Varint) => header.version = decode_varint(&mut buf)? as u32,
+                (2, WireType::Varint) => header.request_id = decode_varint(&mut buf)?,
+                (_, WireType::LengthDelimited) if tag >= 10 => {
+                    let len = decode_varint(&mut buf)?;
+                    if len > buf.len() as u64 {
+                        return Err(prost::DecodeError::new("buffer underflow").into());
+                    }
+                    buf = &buf[len as usize..];
+                    header.payload_tag = Some(tag);
+                    header.payload_len = len as usize;
+                }
+                _ => skip_field(wire_type, tag, &mut buf, DecodeContext::default())?,
+            }
+        }
+
+        Ok(header)
+    }
+
+    fn check_version(envelope: Self) -> Result<Self> {
         if envelope.version != PROTOCOL_VERSION as u32 {
             return Err(Error::VersionMismatch {
                 expected: PROTOCOL_VERSION,
@@ -251,6 +600,87 @@ impl Envelope {
     }
 }
 
+/// Top-level envelope fields, read by `Envelope::peek_header`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u32,
+    pub request_id: u64,
+    /// Field tag of the payload variant, see `payload_tags`
+    pub payload_tag: Option<u32>,
+    /// Encoded length of the payload
+    pub payload_len: usize,
+}
+
+impl Payload {
+    /// Encode this payload as the envelope field it occupies, for use with
+    /// `Envelope::encode_with_payload`
+    pub fn encode_field(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_field_into(&mut buf);
+        buf
+    }
+
+    /// `encode_field` into `buf`, replacing what it held, see
+    /// `Envelope::encode_into`
+    pub fn encode_field_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(self.encoded_len());
+        self.encode(buf);
+    }
+}
+
+/// Generate typed accessors on `Envelope` for each payload variant
+macro_rules! payload_accessors {
+    ($($variant:ident => $as_fn:ident, $into_fn:ident;)*) => {
+        impl Envelope {
+            $(
+                #[doc = concat!("Borrow the payload if it is a `", stringify!($variant), "`")]
+                pub fn $as_fn(&self) -> Option<&$variant> {
+                    match &self.payload {
+                        Some(Payload::$variant(msg)) => Some(msg),
+                        _ => None,
+                    }
+                }
+
+                #[doc = concat!("Take the payload if it is a `", stringify!($variant), "`")]
+                pub fn $into_fn(self) -> Option<$variant> {
+                    match self.payload {
+                        Some(Payload::$variant(msg)) => Some(msg),
+                        _ => None,
+                    }
+                }
+            )*
+        }
+    };
+}
+
+payload_accessors! {
+    SearchRequest => as_search_request, into_search_request;
+    SearchResponse => as_search_response, into_search_response;
+    CapabilitiesRequest => as_capabilities_request, into_capabilities_request;
+    CapabilitiesResponse => as_capabilities_response, into_capabilities_response;
+    ChunkRequest => as_chunk_request, into_chunk_request;
+    ChunkResponse => as_chunk_response, into_chunk_response;
+    RelayRequest => as_relay_request, into_relay_request;
+    ChunkRangeRequest => as_chunk_range_request, into_chunk_range_request;
+    ChunkRangeResponse => as_chunk_range_response, into_chunk_range_response;
+    PublishRequest => as_publish_request, into_publish_request;
+    PublishResponse => as_publish_response, into_publish_response;
+    UnpublishRequest => as_unpublish_request, into_unpublish_request;
+    UnpublishResponse => as_unpublish_response, into_unpublish_response;
+    PublishChallenge => as_publish_challenge, into_publish_challenge;
+    PublishProof => as_publish_proof, into_publish_proof;
+    FindNodeRequest => as_find_node_request, into_find_node_request;
+    FindNodeResponse => as_find_node_response, into_find_node_response;
+    FindValueRequest => as_find_value_request, into_find_value_request;
+    FindValueResponse => as_find_value_response, into_find_value_response;
+    StoreRequest => as_store_request, into_store_request;
+    StoreResponse => as_store_response, into_store_response;
+    PingRequest => as_ping_request, into_ping_request;
+    PingResponse => as_ping_response, into_ping_response;
+    ErrorResponse => as_error_response, into_error_response;
+}
+
 /// Error codes
 pub mod error_codes {
     // Protocol errors (1xx)
@@ -266,19 +696,93 @@ pub mod error_codes {
     pub const INVALID_DATA: u32 = 301;
 }
 
-/// Helper functions to create common message types
+/// Compression of `ChunkResponse::data`
+pub mod compression {
+    pub const NONE: u32 = 0;
+    pub const ZSTD: u32 = 1;
+}
+
+/// Feature bits reported in `CapabilitiesResponse::features`
+pub mod capabilities {
+    /// Answers `SearchRequest`
+    pub const SEARCH: u64 = 1 << 0;
+    /// Accepts `PublishRequest`
+    pub const PUBLISH: u64 = 1 << 1;
+    /// Applies `SearchRequest::min_relevance` itself
+    pub const MIN_RELEVANCE: u64 = 1 << 2;
+    /// Lists anonymous seeders by token and relays `RelayRequest`s to them
+    pub const RELAY: u64 = 1 << 3;
+    /// Accepts `UnpublishRequest`
+    pub const UNPUBLISH: u64 = 1 << 4;
+    /// Answers `PublishRequest` with a `PublishChallenge` and only lists the
+    /// file once a `PublishProof` checks out
+    pub const PUBLISH_PROOF: u64 = 1 << 5;
+    /// Honours `SearchRequest::offset` and fills in
+    /// `SearchResponse::total_results`
+    pub const PAGINATION: u64 = 1 << 6;
+    /// Applies the size, MIME type and recency filters of `SearchRequest`
+    pub const SEARCH_FILTERS: u64 = 1 << 7;
+    /// Runs `SearchRequest::advanced` queries as FTS5 syntax
+    pub const ADVANCED_QUERY: u64 = 1 << 8;
+    /// Answers a `FindValueRequest` for a content hash with the file's
+    /// seeders
+    pub const FIND_VALUE: u64 = 1 << 9;
+}
+
+/// Envelope field tags of the payload variants
+pub mod payload_tags {
+    pub const SEARCH_REQUEST: u32 = 10;
+    pub const SEARCH_RESPONSE: u32 = 11;
+    pub const CAPABILITIES_REQUEST: u32 = 12;
+    pub const CAPABILITIES_RESPONSE: u32 = 13;
+    pub const CHUNK_REQUEST: u32 = 20;
+    pub const CHUNK_RESPONSE: u32 = 21;
+    pub const RELAY_REQUEST: u32 = 22;
+    pub const CHUNK_RANGE_REQUEST: u32 = 23;
+    pub const CHUNK_RANGE_RESPONSE: u32 = 24;
+    pub const PUBLISH_REQUEST: u32 = 30;
+    pub const PUBLISH_RESPONSE: u32 = 31;
+    pub const UNPUBLISH_REQUEST: u32 = 32;
+    pub const UNPUBLISH_RESPONSE: u32 = 33;
+    pub const PUBLISH_CHALLENGE: u32 = 34;
+    pub const PUBLISH_PROOF: u32 = 35;
+    pub const FIND_NODE_REQUEST: u32 = 40;
+    pub const FIND_NODE_RESPONSE: u32 = 41;
+    pub const FIND_VALUE_REQUEST: u32 = 42;
+    pub const FIND_VALUE_RESPONSE: u32 = 43;
+    pub const STORE_REQUEST: u32 = 44;
+    pub const STORE_RESPONSE: u32 = 45;
+    pub const PING_REQUEST: u32 = 46;
+    pub const PING_RESPONSE: u32 = 47;
+    pub const ERROR_RESPONSE: u32 = 100;
+}
+
+// Helper functions to create common message types
 
 pub fn search_request(request_id: u64, query: String, max_results: u32) -> Envelope {
     Envelope::new(
         request_id,
-        Payload::SearchRequest(SearchRequest { query, max_results }),
+        Payload::SearchRequest(SearchRequest {
+            query,
+            max_results,
+            min_relevance: 0.0,
+            offset: 0,
+            min_size: 0,
+            max_size: 0,
+            mime_type: String::new(),
+            published_after: 0,
+            advanced: false,
+        }),
     )
 }
 
 pub fn search_response(request_id: u64, results: Vec<SearchResult>) -> Envelope {
     Envelope::new(
         request_id,
-        Payload::SearchResponse(SearchResponse { results }),
+        Payload::SearchResponse(SearchResponse {
+            results,
+            total_results: 0,
+        }),
     )
 }
 
@@ -310,8 +814,10 @@ pub fn chunk_response(
         Payload::ChunkResponse(ChunkResponse {
             content_hash,
             chunk_index,
-            data,
+            data: data.into(),
             chunk_hash,
+            proof: vec![],
+            compression: compression::NONE,
         }),
     )
 }
@@ -336,4 +842,129 @@ mod tests {
         assert_eq!(original.version, decoded.version);
         assert_eq!(original.request_id, decoded.request_id);
     }
+
+    #[test]
+    fn test_from_vec_shares_chunk_buffer() {
+        let data = vec![7u8; 256 * 1024];
+        let bytes = chunk_response(1, vec![1u8; 32], 3, data.clone(), vec![2u8; 32]).to_bytes();
+        let buf_range = bytes.as_ptr_range();
+
+        let resp = Envelope::from_vec(bytes).unwrap().into_chunk_response().unwrap();
+        assert_eq!(resp.chunk_index, 3);
+        assert_eq!(resp.data, data);
+        // The payload points into the receive buffer rather than a copy
+        assert!(buf_range.contains(&resp.data.as_ptr()));
+    }
+
+    #[test]
+    fn test_peek_header() {
+        let bytes = chunk_response(9, vec![1u8; 32], 3, vec![7u8; 1000], vec![2u8; 32]).to_bytes();
+        let header = Envelope::peek_header(&bytes).unwrap();
+        assert_eq!(header.version, PROTOCOL_VERSION as u32);
+        assert_eq!(header.request_id, 9);
+        assert_eq!(header.payload_tag, Some(payload_tags::CHUNK_RESPONSE));
+        assert!(header.payload_len > 1000);
+
+        // A payload claiming more bytes than the buffer holds is rejected
+        assert!(Envelope::peek_header(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_encode_with_payload_matches_full_encoding() {
+        let payload = Payload::ChunkResponse(ChunkResponse {
+            content_hash: vec![1u8; 32],
+            chunk_index: 4,
+            data: vec![9u8; 1000].into(),
+            chunk_hash: vec![2u8; 32],
+            proof: vec![vec![3u8; 32]],
+            compression: compression::ZSTD,
+        });
+        let field = payload.encode_field();
+
+        for request_id in [0, 7, u64::MAX] {
+            assert_eq!(
+                Envelope::encode_with_payload(request_id, &field),
+                Envelope::new(request_id, payload.clone()).to_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_into_reuses_buffer() {
+        let large = chunk_response(1, vec![1u8; 32], 0, vec![7u8; 64 * 1024], vec![2u8; 32]);
+        let small = chunk_response(2, vec![1u8; 32], 1, vec![8u8; 1000], vec![2u8; 32]);
+
+        let mut buf = Vec::new();
+        large.encode_into(&mut buf);
+        assert_eq!(buf, large.to_bytes());
+        let allocation = buf.as_ptr();
+        let capacity = buf.capacity();
+
+        // Later encodes that fit land in the same allocation
+        for envelope in [&small, &large, &small] {
+            envelope.encode_into(&mut buf);
+            assert_eq!(buf, envelope.to_bytes());
+            assert_eq!(buf.as_ptr(), allocation);
+            assert_eq!(buf.capacity(), capacity);
+        }
+
+        let payload = large.payload.clone().unwrap();
+        let mut field = Vec::new();
+        payload.encode_field_into(&mut field);
+        Envelope::encode_with_payload_into(3, &field, &mut buf);
+        assert_eq!(buf, Envelope::new(3, payload).to_bytes());
+        assert_eq!(buf.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn test_payload_accessors() {
+        let empty = Envelope {
+            version: PROTOCOL_VERSION as u32,
+            request_id: 1,
+            payload: None,
+        };
+
+        macro_rules! check {
+            ($($variant:ident => $as_fn:ident, $into_fn:ident;)*) => {$(
+                let envelope = Envelope::new(1, Payload::$variant($variant::default()));
+                assert_eq!(envelope.$as_fn(), Some(&$variant::default()));
+                assert_eq!(envelope.clone().$into_fn(), Some($variant::default()));
+                assert!(empty.$as_fn().is_none());
+                assert!(empty.clone().$into_fn().is_none());
+            )*};
+        }
+
+        check! {
+            SearchRequest => as_search_request, into_search_request;
+            SearchResponse => as_search_response, into_search_response;
+            CapabilitiesRequest => as_capabilities_request, into_capabilities_request;
+            CapabilitiesResponse => as_capabilities_response, into_capabilities_response;
+            ChunkRequest => as_chunk_request, into_chunk_request;
+            ChunkResponse => as_chunk_response, into_chunk_response;
+            RelayRequest => as_relay_request, into_relay_request;
+            ChunkRangeRequest => as_chunk_range_request, into_chunk_range_request;
+            ChunkRangeResponse => as_chunk_range_response, into_chunk_range_response;
+            PublishRequest => as_publish_request, into_publish_request;
+            PublishResponse => as_publish_response, into_publish_response;
+            UnpublishRequest => as_unpublish_request, into_unpublish_request;
+            UnpublishResponse => as_unpublish_response, into_unpublish_response;
+    PublishChallenge => as_publish_challenge, into_publish_challenge;
+    PublishProof => as_publish_proof, into_publish_proof;
+            FindNodeRequest => as_find_node_request, into_find_node_request;
+            FindNodeResponse => as_find_node_response, into_find_node_response;
+            FindValueRequest => as_find_value_request, into_find_value_request;
+            FindValueResponse => as_find_value_response, into_find_value_response;
+            StoreRequest => as_store_request, into_store_request;
+            StoreResponse => as_store_response, into_store_response;
+            PingRequest => as_ping_request, into_ping_request;
+            PingResponse => as_ping_response, into_ping_response;
+            ErrorResponse => as_error_response, into_error_response;
+        }
+
+        // A different variant doesn't match
+        let search = search_request(1, "query".to_string(), 10);
+        assert_eq!(search.as_search_request().unwrap().query, "query");
+        assert!(search.as_chunk_request().is_none());
+        assert!(search.into_search_response().is_none());
+    }
 }
@@ -5,24 +5,118 @@
 //! client in production.
 
 use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
 use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 
 /// A Nym network address
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct NymAddress(String);
 
 impl NymAddress {
     /// Create a new NymAddress from a string
+    ///
+    /// The address isn't checked, so mock and placeholder addresses work;
+    /// use `try_new` for addresses typed in by a user.
     pub fn new(address: impl Into<String>) -> Self {
         Self(address.into())
     }
 
+    /// Create a NymAddress, failing unless it is a well-formed
+    /// `client_id.enc_key@gateway_id` address, see `validate`
+    pub fn try_new(address: impl Into<String>) -> Result<Self> {
+        let address = Self(address.into());
+        address.validate()?;
+        Ok(address)
+    }
+
+    /// Check the address has the `client_id.enc_key@gateway_id` form with
+    /// every part base58
+    ///
+    /// The error says which part is wrong, so a typo is caught here rather
+    /// than as a send failure deep in the Nym client.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |problem: String| {
+            Err(Error::InvalidAddress(format!(
+                "{}; expected <identity key>.<encryption key>@<gateway>",
+                problem
+            )))
+        };
+
+        if self.0.is_empty() {
+            return invalid("empty address".to_string());
+        }
+        let Some((identity, gateway_id)) = self.0.split_once('@') else {
+            return invalid(format!("'{}' has no '@' before the gateway", self.0));
+        };
+        let Some((client_id, enc_key)) = identity.split_once('.') else {
+            return invalid(format!(
+                "'{}' has no '.' between the identity and encryption keys",
+                self.0
+            ));
+        };
+        for (name, part) in [
+            ("identity key", client_id),
+            ("encryption key", enc_key),
+            ("gateway", gateway_id),
+        ] {
+            if part.is_empty() {
+                return invalid(format!("'{}' has an empty {}", self.0, name));
+            }
+            if !is_base58(part) {
+                return invalid(format!("the {} of '{}' is not base58", name, self.0));
+            }
+        }
+        Ok(())
+    }
+
     /// Get the address as a string slice
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Split a canonical `client_id.enc_key@gateway_id` address into its parts
+    ///
+    /// Returns `None` unless all three parts are present and base58, e.g. for
+    /// mock addresses. The address itself is stored as given either way.
+    pub fn components(&self) -> Option<NymAddressParts> {
+        let (identity, gateway_id) = self.0.split_once('@')?;
+        let (client_id, enc_key) = identity.split_once('.')?;
+        let parts = [client_id, enc_key, gateway_id];
+        if !parts.iter().all(|part| is_base58(part)) {
+            return None;
+        }
+
+        Some(NymAddressParts {
+            client_id: client_id.to_string(),
+            enc_key: enc_key.to_string(),
+            gateway_id: gateway_id.to_string(),
+        })
+    }
+}
+
+/// Parts of a Nym address, see `NymAddress::components`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NymAddressParts {
+    /// The client's identity key
+    pub client_id: String,
+    /// The client's encryption key
+    pub enc_key: String,
+    /// Identity key of the gateway the client is connected through
+    pub gateway_id: String,
+}
+
+/// Non-empty and made only of base58 (Bitcoin alphabet) characters
+fn is_base58(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() && !matches!(b, b'0' | b'O' | b'I' | b'l'))
 }
 
 impl fmt::Display for NymAddress {
@@ -41,11 +135,7 @@ impl FromStr for NymAddress {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        // Basic validation - Nym addresses are base58 encoded
-        if s.is_empty() {
-            return Err(Error::InvalidAddress("empty address".to_string()));
-        }
-        Ok(Self(s.to_string()))
+        Self::try_new(s)
     }
 }
 
@@ -65,6 +155,7 @@ impl From<&str> for NymAddress {
 ///
 /// When a message is received with SURBs (Single Use Reply Blocks), the sender
 /// tag can be used to send a reply without knowing the sender's actual address.
+/// It displays and serializes as a hex string.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct SenderTag(Vec<u8>);
 
@@ -85,12 +176,39 @@ impl SenderTag {
     }
 }
 
+impl fmt::Display for SenderTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
 impl fmt::Debug for SenderTag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "SenderTag({} bytes)", self.0.len())
     }
 }
 
+impl Serialize for SenderTag {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SenderTag {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let hex_str = String::deserialize(deserializer)?;
+        hex::decode(&hex_str)
+            .map(Self)
+            .map_err(|e| serde::de::Error::custom(format!("invalid sender tag: {}", e)))
+    }
+}
+
 /// A message received from the mixnet
 #[derive(Clone, Debug)]
 pub struct ReceivedMessage {
@@ -116,6 +234,10 @@ pub struct TransportConfig {
     pub surbs_per_message: u32,
     /// Whether to use testnet instead of mainnet
     pub use_testnet: bool,
+    /// Rebuild the client from the same identity when it dies, instead of
+    /// failing every send and receive from then on. Needs a `storage_path`,
+    /// since an ephemeral identity can't be restored
+    pub auto_reconnect: bool,
 }
 
 impl Default for TransportConfig {
@@ -124,6 +246,7 @@ impl Default for TransportConfig {
             storage_path: None,
             surbs_per_message: 5,
             use_testnet: false,
+            auto_reconnect: true,
         }
     }
 }
@@ -148,9 +271,35 @@ pub trait Transport: Send + Sync {
     /// Check if we're connected
     fn is_connected(&self) -> bool;
 
+    /// Whether `our_address` survives a restart
+    ///
+    /// True only when the identity is loaded from persistent storage, so an
+    /// address published to an index provider stays reachable after the
+    /// process comes back. Ephemeral identities get a new address on every
+    /// connect, as do transports that can't tell.
+    fn is_address_stable(&self) -> bool {
+        false
+    }
+
     /// Send a message to a specific address
     async fn send(&self, recipient: &NymAddress, data: Vec<u8>) -> Result<()>;
 
+    /// Send a message, giving up after `timeout`
+    ///
+    /// Fails with `Error::Timeout` if the send hasn't gone through by then,
+    /// e.g. because the client is backed up, instead of waiting on it
+    /// indefinitely.
+    async fn send_timeout(
+        &self,
+        recipient: &NymAddress,
+        data: Vec<u8>,
+        timeout: std::time::Duration,
+    ) -> Result<()> {
+        tokio::time::timeout(timeout, self.send(recipient, data))
+            .await
+            .map_err(|_| Error::Timeout(timeout))?
+    }
+
     /// Send an anonymous reply using a sender tag
     async fn send_reply(&self, sender_tag: &SenderTag, data: Vec<u8>) -> Result<()>;
 
@@ -159,6 +308,57 @@ pub trait Transport: Send + Sync {
 
     /// Try to receive a message with a timeout
     async fn receive_timeout(&self, timeout: std::time::Duration) -> Result<Option<ReceivedMessage>>;
+
+    /// Disconnect and reconnect under a fresh identity, with a new address
+    ///
+    /// Taking `&mut self` means every send and receive has finished before
+    /// the old identity goes away. The new identity is created at (or loaded
+    /// from) `storage_path`, or is ephemeral if `None`; the old one is left
+    /// where it was. Messages still on their way to the old address, and
+    /// replies to messages sent under it, are lost. Transports without
+    /// identities return an error and are left as they were.
+    async fn rotate_identity(&mut self, storage_path: Option<PathBuf>) -> Result<()> {
+        let _ = storage_path;
+        Err(Error::Transport("identity rotation not supported".to_string()))
+    }
+}
+
+/// Run `op` up to `max_attempts` times, backing off between attempts
+///
+/// Only transient failures (see `Error::is_transient`) are retried; any
+/// other failure is returned straight away, as is the last one once the
+/// attempts run out. `op` always runs at least once. The wait starts at
+/// `base_delay` and doubles after each attempt, plus up to half as much
+/// again at random, so clients that failed together don't retry in
+/// lockstep.
+pub async fn retry_with_backoff<T, F, Fut>(
+    max_attempts: u32,
+    base_delay: std::time::Duration,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if e.is_transient() && attempt < max_attempts => {
+                let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+                tokio::time::sleep(delay + jitter(delay / 2)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A random duration of up to `max`
+fn jitter(max: std::time::Duration) -> std::time::Duration {
+    // Each RandomState is seeded afresh, which is random enough to spread
+    // out retries
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
 }
 
 /// A shareable transport handle
@@ -168,19 +368,242 @@ pub mod mock {
     //! Mock transport for testing and development
 
     use super::*;
-    use std::collections::VecDeque;
+    use std::collections::{HashMap, VecDeque};
     use std::sync::Mutex;
 
+    type Inbox = Arc<Mutex<VecDeque<ReceivedMessage>>>;
+
+    /// Latency and loss applied to every message on a `MockNetwork`
+    ///
+    /// Which messages are dropped and how long each one is held up is drawn
+    /// from a generator seeded with `seed`, so the same seed gives the same
+    /// sequence of decisions and a failing run can be replayed.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct LinkConditions {
+        /// Seed for the loss and delay decisions
+        pub seed: u64,
+        /// Fraction of messages silently dropped, from 0 to 1
+        pub loss: f64,
+        /// Each message is held up by a delay up to this long, so messages
+        /// can overtake each other
+        pub max_delay: std::time::Duration,
+    }
+
+    impl LinkConditions {
+        /// Delays up to `max_delay` and no loss
+        pub fn latency(seed: u64, max_delay: std::time::Duration) -> Self {
+            Self {
+                seed,
+                loss: 0.0,
+                max_delay,
+            }
+        }
+
+        /// Also drop this fraction of messages
+        pub fn with_loss(mut self, loss: f64) -> Self {
+            self.loss = loss;
+            self
+        }
+    }
+
+    /// SplitMix64, enough to make loss and delay reproducible without
+    /// pulling in a random number crate
+    struct SplitMix64(u64);
+
+    impl SplitMix64 {
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        /// Uniform in `[0, 1)`
+        fn next_f64(&mut self) -> f64 {
+            (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    /// An in-memory network routing messages between mock transports
+    ///
+    /// Every `send` is delivered to the recipient's inbox together with a
+    /// fresh sender tag, and `send_reply` with that tag lands back in the
+    /// original sender's inbox, mimicking SURB replies on the mixnet.
+    /// Delivery is immediate and lossless unless the network was created
+    /// with `with_conditions`.
+    #[derive(Clone, Default)]
+    pub struct MockNetwork {
+        state: Arc<Mutex<NetworkState>>,
+    }
+
+    #[derive(Default)]
+    struct NetworkState {
+        inboxes: HashMap<NymAddress, Inbox>,
+        /// Which address each issued sender tag replies to
+        reply_routes: HashMap<Vec<u8>, NymAddress>,
+        next_tag: u64,
+        conditions: Option<(LinkConditions, SplitMix64)>,
+        /// Delayed messages and when they reach their inbox
+        in_transit: Vec<(std::time::Instant, Inbox, ReceivedMessage)>,
+        /// Messages dropped by the link conditions
+        dropped: u64,
+    }
+
+    impl NetworkState {
+        /// Put `msg` in `inbox`, or drop or delay it under the link conditions
+        fn route(&mut self, inbox: Inbox, msg: ReceivedMessage) {
+            let Some((conditions, rng)) = &mut self.conditions else {
+                inbox.lock().unwrap().push_back(msg);
+                return;
+            };
+            // Always draw both, so one decision doesn't shift the next
+            let lost = rng.next_f64() < conditions.loss;
+            let delay = conditions.max_delay.mul_f64(rng.next_f64());
+            if lost {
+                self.dropped += 1;
+            } else if delay.is_zero() {
+                inbox.lock().unwrap().push_back(msg);
+            } else {
+                let ready_at = std::time::Instant::now() + delay;
+                self.in_transit.push((ready_at, inbox, msg));
+            }
+        }
+
+        /// Move delayed messages that are due into their inboxes, earliest
+        /// first
+        fn release_due(&mut self) {
+            let now = std::time::Instant::now();
+            let (mut due, waiting): (Vec<_>, Vec<_>) = self
+                .in_transit
+                .drain(..)
+                .partition(|(ready_at, _, _)| *ready_at <= now);
+            self.in_transit = waiting;
+            due.sort_by_key(|(ready_at, _, _)| *ready_at);
+            for (_, inbox, msg) in due {
+                inbox.lock().unwrap().push_back(msg);
+            }
+        }
+    }
+
+    impl MockNetwork {
+        /// Create an empty network
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Create an empty network that delays and drops messages as
+        /// `conditions` say
+        pub fn with_conditions(conditions: LinkConditions) -> Self {
+            let network = Self::new();
+            network.state.lock().unwrap().conditions =
+                Some((conditions, SplitMix64(conditions.seed)));
+            network
+        }
+
+        /// Number of messages dropped so far by the link conditions
+        pub fn dropped(&self) -> u64 {
+            self.state.lock().unwrap().dropped
+        }
+
+        /// Create a transport attached to this network at `address`
+        pub fn transport(&self, address: impl Into<NymAddress>) -> MockTransport {
+            let address = address.into();
+            let inbox = Inbox::default();
+            self.state
+                .lock()
+                .unwrap()
+                .inboxes
+                .insert(address.clone(), inbox.clone());
+
+            MockTransport {
+                address: Some(address),
+                incoming: inbox,
+                network: Some(self.clone()),
+                ..MockTransport::new()
+            }
+        }
+
+        fn deliver(&self, from: &NymAddress, to: &NymAddress, data: Vec<u8>) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            let inbox = state
+                .inboxes
+                .get(to)
+                .cloned()
+                .ok_or_else(|| Error::SendFailed(format!("unknown recipient {}", to)))?;
+
+            state.next_tag += 1;
+            let tag = state.next_tag.to_le_bytes().to_vec();
+            state.reply_routes.insert(tag.clone(), from.clone());
+
+            state.route(inbox, ReceivedMessage::new(data, Some(SenderTag::new(tag))));
+            Ok(())
+        }
+
+        fn deliver_reply(&self, sender_tag: &SenderTag, data: Vec<u8>) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            let inbox = state
+                .reply_routes
+                .get(sender_tag.as_bytes())
+                .and_then(|addr| state.inboxes.get(addr))
+                .cloned()
+                .ok_or_else(|| Error::SendFailed("unknown sender tag".to_string()))?;
+
+            state.route(inbox, ReceivedMessage::new(data, None));
+            Ok(())
+        }
+
+        fn release_due(&self) {
+            self.state.lock().unwrap().release_due();
+        }
+
+        /// Move a transport from `old` to `new` with an empty inbox
+        fn readdress(&self, old: Option<&NymAddress>, new: &NymAddress) -> Inbox {
+            let mut state = self.state.lock().unwrap();
+            if let Some(old) = old {
+                state.inboxes.remove(old);
+            }
+            let inbox = Inbox::default();
+            state.inboxes.insert(new.clone(), inbox.clone());
+            inbox
+        }
+    }
+
+    /// One end of a `loopback_pair`
+    pub type LoopbackTransport = MockTransport;
+
+    /// Two connected transports that can only reach each other
+    ///
+    /// Shorthand for a `MockNetwork` with two nodes, `loopback-a.mock` and
+    /// `loopback-b.mock`: a send from one lands in the other's receive
+    /// queue with a fresh sender tag, and a reply to that tag lands back in
+    /// the sender's queue.
+    pub fn loopback_pair() -> (LoopbackTransport, LoopbackTransport) {
+        let network = MockNetwork::new();
+        let mut a = network.transport("loopback-a.mock");
+        let mut b = network.transport("loopback-b.mock");
+        a.connected = true;
+        b.connected = true;
+        (a, b)
+    }
+
     /// A mock transport for testing
+    ///
+    /// Standalone transports only record what is sent; transports created by
+    /// `MockNetwork::transport` also deliver it.
     pub struct MockTransport {
         address: Option<NymAddress>,
         connected: bool,
         /// Messages to deliver on receive()
-        incoming: Mutex<VecDeque<ReceivedMessage>>,
+        incoming: Inbox,
         /// Messages that were sent
         outgoing: Mutex<Vec<(NymAddress, Vec<u8>)>>,
         /// Replies that were sent
         replies: Mutex<Vec<(SenderTag, Vec<u8>)>>,
+        /// Network that routes sent messages, if any
+        network: Option<MockNetwork>,
+        /// Identities rotated through, for minting new addresses
+        rotations: u32,
     }
 
     impl MockTransport {
@@ -189,9 +612,11 @@ pub mod mock {
             Self {
                 address: None,
                 connected: false,
-                incoming: Mutex::new(VecDeque::new()),
+                incoming: Inbox::default(),
                 outgoing: Mutex::new(Vec::new()),
                 replies: Mutex::new(Vec::new()),
+                network: None,
+                rotations: 0,
             }
         }
 
@@ -200,6 +625,32 @@ pub mod mock {
             self.incoming.lock().unwrap().push_back(msg);
         }
 
+        /// Queue several messages to be received, in order
+        pub fn queue_messages(&self, msgs: Vec<ReceivedMessage>) {
+            self.incoming.lock().unwrap().extend(msgs);
+        }
+
+        /// Number of messages waiting to be received
+        pub fn pending_incoming_len(&self) -> usize {
+            if let Some(network) = &self.network {
+                network.release_due();
+            }
+            self.incoming.lock().unwrap().len()
+        }
+
+        /// Set the address this transport reports as its own
+        ///
+        /// On a mock network, messages to the new address arrive here and
+        /// the old one stops being reachable. Queued messages are kept.
+        pub fn set_our_address(&mut self, address: NymAddress) {
+            if let Some(network) = &self.network {
+                let inbox = network.readdress(self.address.as_ref(), &address);
+                inbox.lock().unwrap().append(&mut self.incoming.lock().unwrap());
+                self.incoming = inbox;
+            }
+            self.address = Some(address);
+        }
+
         /// Get all sent messages
         pub fn get_sent_messages(&self) -> Vec<(NymAddress, Vec<u8>)> {
             self.outgoing.lock().unwrap().clone()
@@ -209,6 +660,18 @@ pub mod mock {
         pub fn get_sent_replies(&self) -> Vec<(SenderTag, Vec<u8>)> {
             self.replies.lock().unwrap().clone()
         }
+
+        /// Take all sent replies, leaving none behind
+        pub fn take_sent_replies(&self) -> Vec<(SenderTag, Vec<u8>)> {
+            std::mem::take(&mut *self.replies.lock().unwrap())
+        }
+
+        fn pop_incoming(&self) -> Option<ReceivedMessage> {
+            if let Some(network) = &self.network {
+                network.release_due();
+            }
+            self.incoming.lock().unwrap().pop_front()
+        }
     }
 
     impl Default for MockTransport {
@@ -219,7 +682,9 @@ pub mod mock {
 
     impl Transport for MockTransport {
         async fn connect(&mut self) -> Result<()> {
-            self.address = Some(NymAddress::new("mock-address-12345.mock"));
+            if self.address.is_none() {
+                self.address = Some(NymAddress::new("mock-address-12345.mock"));
+            }
             self.connected = true;
             Ok(())
         }
@@ -241,6 +706,9 @@ pub mod mock {
             if !self.connected {
                 return Err(Error::SendFailed("not connected".to_string()));
             }
+            if let (Some(network), Some(address)) = (&self.network, &self.address) {
+                network.deliver(address, recipient, data.clone())?;
+            }
             self.outgoing.lock().unwrap().push((recipient.clone(), data));
             Ok(())
         }
@@ -249,13 +717,16 @@ pub mod mock {
             if !self.connected {
                 return Err(Error::SendFailed("not connected".to_string()));
             }
+            if let Some(network) = &self.network {
+                network.deliver_reply(sender_tag, data.clone())?;
+            }
             self.replies.lock().unwrap().push((sender_tag.clone(), data));
             Ok(())
         }
 
         async fn receive(&self) -> Result<ReceivedMessage> {
             loop {
-                if let Some(msg) = self.incoming.lock().unwrap().pop_front() {
+                if let Some(msg) = self.pop_incoming() {
                     return Ok(msg);
                 }
                 // In a real implementation, this would block
@@ -269,13 +740,35 @@ pub mod mock {
         ) -> Result<Option<ReceivedMessage>> {
             let start = std::time::Instant::now();
             while start.elapsed() < timeout {
-                if let Some(msg) = self.incoming.lock().unwrap().pop_front() {
+                if let Some(msg) = self.pop_incoming() {
                     return Ok(Some(msg));
                 }
                 tokio::time::sleep(std::time::Duration::from_millis(10)).await;
             }
             Ok(None)
         }
+
+        async fn rotate_identity(&mut self, _storage_path: Option<PathBuf>) -> Result<()> {
+            self.disconnect().await?;
+
+            // `alice.mock` becomes `alice.mock~1`, then `alice.mock~2`, ...
+            self.rotations += 1;
+            let old = self.address.take();
+            let base = old.as_ref().map_or("mock-address-12345.mock", |address| {
+                address
+                    .as_str()
+                    .rsplit_once('~')
+                    .map_or(address.as_str(), |(base, _)| base)
+            });
+            let new = NymAddress::new(format!("{}~{}", base, self.rotations));
+
+            self.incoming = match &self.network {
+                Some(network) => network.readdress(old.as_ref(), &new),
+                None => Inbox::default(),
+            };
+            self.address = Some(new);
+            self.connect().await
+        }
     }
 
     #[cfg(test)]
@@ -332,5 +825,373 @@ pub mod mock {
             assert_eq!(replies.len(), 1);
             assert_eq!(replies[0].1, b"reply data");
         }
+
+        #[tokio::test]
+        async fn test_mock_transport_queue_helpers() {
+            let mut transport = MockTransport::new();
+            transport.set_our_address(NymAddress::new("alice.mock"));
+            transport.connect().await.unwrap();
+            assert_eq!(transport.our_address().unwrap().as_str(), "alice.mock");
+
+            transport.queue_messages(vec![
+                ReceivedMessage::new(b"one".to_vec(), None),
+                ReceivedMessage::new(b"two".to_vec(), None),
+            ]);
+            assert_eq!(transport.pending_incoming_len(), 2);
+            let timeout = std::time::Duration::from_millis(100);
+            let first = transport.receive_timeout(timeout).await.unwrap().unwrap();
+            assert_eq!(first.data, b"one");
+            assert_eq!(transport.pending_incoming_len(), 1);
+
+            let tag = SenderTag::new(vec![1, 2, 3, 4]);
+            transport.send_reply(&tag, b"a".to_vec()).await.unwrap();
+            transport.send_reply(&tag, b"b".to_vec()).await.unwrap();
+            let replies = transport.take_sent_replies();
+            assert_eq!(replies.len(), 2);
+            assert_eq!(replies[1].1, b"b");
+            assert!(transport.take_sent_replies().is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_mock_set_our_address_on_network() {
+            let network = MockNetwork::new();
+            let mut alice = network.transport("alice.mock");
+            let mut bob = network.transport("bob.mock");
+            alice.connect().await.unwrap();
+            bob.connect().await.unwrap();
+
+            bob.send(&NymAddress::new("alice.mock"), b"early".to_vec()).await.unwrap();
+            alice.set_our_address(NymAddress::new("alice2.mock"));
+            assert!(bob.send(&NymAddress::new("alice.mock"), vec![]).await.is_err());
+            bob.send(&NymAddress::new("alice2.mock"), b"late".to_vec()).await.unwrap();
+
+            // Messages queued before the change are still there
+            assert_eq!(alice.pending_incoming_len(), 2);
+            let timeout = std::time::Duration::from_millis(100);
+            assert_eq!(alice.receive_timeout(timeout).await.unwrap().unwrap().data, b"early");
+            assert_eq!(alice.receive_timeout(timeout).await.unwrap().unwrap().data, b"late");
+        }
+
+        #[tokio::test]
+        async fn test_mock_network_routes_replies() {
+            let network = MockNetwork::new();
+            let mut alice = network.transport("alice.mock");
+            let mut bob = network.transport("bob.mock");
+            alice.connect().await.unwrap();
+            bob.connect().await.unwrap();
+            assert_eq!(alice.our_address().unwrap().as_str(), "alice.mock");
+
+            alice.send(&NymAddress::new("bob.mock"), b"ping".to_vec()).await.unwrap();
+            let request = bob
+                .receive_timeout(std::time::Duration::from_millis(100))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.data, b"ping");
+
+            let tag = request.sender_tag.unwrap();
+            bob.send_reply(&tag, b"pong".to_vec()).await.unwrap();
+            let reply = alice
+                .receive_timeout(std::time::Duration::from_millis(100))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply.data, b"pong");
+            assert!(reply.sender_tag.is_none());
+
+            // Unknown recipients fail like an unreachable address would
+            assert!(alice.send(&NymAddress::new("carol.mock"), vec![]).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_loopback_pair() {
+            let (a, b) = loopback_pair();
+            assert!(a.is_connected() && b.is_connected());
+            let timeout = std::time::Duration::from_millis(100);
+
+            // Each request gets its own tag, and each reply goes to its sender
+            for (i, data) in [b"one", b"two"].into_iter().enumerate() {
+                a.send(b.our_address().unwrap(), data.to_vec()).await.unwrap();
+                let request = b.receive_timeout(timeout).await.unwrap().unwrap();
+                assert_eq!(request.data, data);
+                let tag = request.sender_tag.unwrap();
+                b.send_reply(&tag, vec![i as u8]).await.unwrap();
+                let reply = a.receive_timeout(timeout).await.unwrap().unwrap();
+                assert_eq!(reply.data, [i as u8]);
+            }
+            let tags: Vec<_> = b.take_sent_replies().into_iter().map(|(tag, _)| tag).collect();
+            assert_ne!(tags[0], tags[1]);
+
+            // Unissued tags have nowhere to go
+            assert!(b.send_reply(&SenderTag::new(vec![0xff; 4]), vec![]).await.is_err());
+            assert_eq!(b.pending_incoming_len(), 0);
+        }
+
+        /// Send 100 numbered messages from alice to bob and return the
+        /// numbers bob receives, in arrival order
+        async fn send_numbered(network: &MockNetwork) -> Vec<u8> {
+            let mut alice = network.transport("alice.mock");
+            let mut bob = network.transport("bob.mock");
+            alice.connect().await.unwrap();
+            bob.connect().await.unwrap();
+
+            for i in 0..100u8 {
+                alice.send(&NymAddress::new("bob.mock"), vec![i]).await.unwrap();
+            }
+            let mut received = Vec::new();
+            let timeout = std::time::Duration::from_millis(100);
+            while let Some(msg) = bob.receive_timeout(timeout).await.unwrap() {
+                received.push(msg.data[0]);
+            }
+            received
+        }
+
+        #[tokio::test]
+        async fn test_mock_network_conditions() {
+            let conditions =
+                LinkConditions::latency(7, std::time::Duration::from_millis(20)).with_loss(0.2);
+            let network = MockNetwork::with_conditions(conditions);
+            let received = send_numbered(&network).await;
+
+            // Some are lost, the rest arrive once each, not all in order
+            assert_eq!(received.len() as u64 + network.dropped(), 100);
+            assert!(network.dropped() > 0);
+            let mut sorted = received.clone();
+            sorted.sort();
+            sorted.dedup();
+            assert_eq!(sorted.len(), received.len());
+            assert_ne!(sorted, received);
+
+            // The same seed drops the same messages
+            let replay = send_numbered(&MockNetwork::with_conditions(conditions)).await;
+            let mut replay_sorted = replay.clone();
+            replay_sorted.sort();
+            assert_eq!(replay_sorted, sorted);
+        }
+
+        #[tokio::test]
+        async fn test_mock_rotate_identity() {
+            let network = MockNetwork::new();
+            let mut alice = network.transport("alice.mock");
+            let mut bob = network.transport("bob.mock");
+            alice.connect().await.unwrap();
+            bob.connect().await.unwrap();
+            let timeout = std::time::Duration::from_millis(100);
+
+            alice.rotate_identity(None).await.unwrap();
+            assert!(alice.is_connected());
+            let new_address = alice.our_address().unwrap().clone();
+            assert_eq!(new_address.as_str(), "alice.mock~1");
+
+            // The old address is gone; the new one is reachable
+            let old_address = NymAddress::new("alice.mock");
+            assert!(bob.send(&old_address, b"stale".to_vec()).await.is_err());
+            bob.send(&new_address, b"hello".to_vec()).await.unwrap();
+            let received = alice.receive_timeout(timeout).await.unwrap().unwrap();
+            assert_eq!(received.data, b"hello");
+
+            // And requests sent under the new identity still get replies
+            alice.send(&NymAddress::new("bob.mock"), b"ping".to_vec()).await.unwrap();
+            let request = bob.receive_timeout(timeout).await.unwrap().unwrap();
+            bob.send_reply(&request.sender_tag.unwrap(), b"pong".to_vec())
+                .await
+                .unwrap();
+            let reply = alice.receive_timeout(timeout).await.unwrap().unwrap();
+            assert_eq!(reply.data, b"pong");
+
+            alice.rotate_identity(None).await.unwrap();
+            assert_eq!(alice.our_address().unwrap().as_str(), "alice.mock~2");
+        }
+
+        #[tokio::test]
+        async fn test_mock_address_is_not_stable() {
+            // Mock identities are minted in memory and gone after a restart
+            let mut transport = MockTransport::new();
+            transport.connect().await.unwrap();
+            assert!(!transport.is_address_stable());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_components() {
+        let address = NymAddress::new(concat!(
+            "8Ur2sFjyaNTWtwTkpPtDqrGTtGrAbNRPu3cnVBdBE8Ey",
+            ".3m4rjFzpS6Ndx1dXMBhnUzgQUEt8ep4C5EbmR1Yhy5rj",
+            "@3sMzpyxEtTMEtUZ3UaEQFE4VkEiM1MGUfVvQnzwgJ1GS",
+        ));
+        let parts = address.components().unwrap();
+        assert_eq!(parts.client_id, "8Ur2sFjyaNTWtwTkpPtDqrGTtGrAbNRPu3cnVBdBE8Ey");
+        assert_eq!(parts.enc_key, "3m4rjFzpS6Ndx1dXMBhnUzgQUEt8ep4C5EbmR1Yhy5rj");
+        assert_eq!(parts.gateway_id, "3sMzpyxEtTMEtUZ3UaEQFE4VkEiM1MGUfVvQnzwgJ1GS");
+    }
+
+    #[test]
+    fn test_malformed_address_has_no_components() {
+        // The shortest well-formed address, for contrast with the cases below
+        assert!(NymAddress::new("abc.def@ghk").components().is_some());
+
+        for malformed in [
+            "",
+            "alice.mock",
+            "abc.def",
+            "abc@ghk",
+            ".def@ghk",
+            "abc.@ghk",
+            "abc.def@",
+            "abc.def@gh@k",
+            "abc.def.xyz@ghk",
+            "ab0.def@ghk",
+            "abc.def@gh k",
+        ] {
+            assert_eq!(NymAddress::new(malformed).components(), None, "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_address_serde_matches_display() {
+        let address = NymAddress::new("abc.def@ghk");
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, format!("\"{}\"", address));
+        assert_eq!(serde_json::from_str::<NymAddress>(&json).unwrap(), address);
+    }
+
+    #[test]
+    fn test_sender_tag_serde_roundtrip() {
+        let tag = SenderTag::new(vec![0x00, 0x1f, 0xab, 0xff]);
+        assert_eq!(tag.to_string(), "001fabff");
+        let json = serde_json::to_string(&tag).unwrap();
+        assert_eq!(json, format!("\"{}\"", tag));
+        assert_eq!(serde_json::from_str::<SenderTag>(&json).unwrap(), tag);
+
+        assert!(serde_json::from_str::<SenderTag>("\"not hex\"").is_err());
+        assert!(serde_json::from_str::<SenderTag>("[0, 31]").is_err());
+    }
+
+    #[test]
+    fn test_validate_address() {
+        let valid = concat!(
+            "8Ur2sFjyaNTWtwTkpPtDqrGTtGrAbNRPu3cnVBdBE8Ey",
+            ".3m4rjFzpS6Ndx1dXMBhnUzgQUEt8ep4C5EbmR1Yhy5rj",
+            "@3sMzpyxEtTMEtUZ3UaEQFE4VkEiM1MGUfVvQnzwgJ1GS",
+        );
+        assert_eq!(NymAddress::try_new(valid).unwrap().as_str(), valid);
+        assert_eq!(valid.parse::<NymAddress>().unwrap().as_str(), valid);
+
+        let message = |address: &str| match NymAddress::try_new(address) {
+            Err(Error::InvalidAddress(message)) => message,
+            other => panic!("{:?} was accepted: {:?}", address, other),
+        };
+        assert!(message("").contains("empty address"));
+        assert!("".parse::<NymAddress>().is_err());
+        assert!(message("alice.mock").contains("no '@'"));
+        assert!(message("abc@ghk").contains("no '.'"));
+        assert!(message(".def@ghk").contains("empty identity key"));
+        assert!(message("abc.def@").contains("empty gateway"));
+        assert!(message("abc.d0f@ghk").contains("encryption key"));
+        assert!(message("abc.def@gh@k").contains("gateway"));
+
+        // Unchecked construction still takes anything
+        assert_eq!(NymAddress::new("alice.mock").as_str(), "alice.mock");
+    }
+
+    /// A transport whose sends never complete, like a backed-up client
+    struct StuckTransport;
+
+    impl Transport for StuckTransport {
+        async fn connect(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn disconnect(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn our_address(&self) -> Option<&NymAddress> {
+            None
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        async fn send(&self, _recipient: &NymAddress, _data: Vec<u8>) -> Result<()> {
+            std::future::pending().await
+        }
+
+        async fn send_reply(&self, _sender_tag: &SenderTag, _data: Vec<u8>) -> Result<()> {
+            std::future::pending().await
+        }
+
+        async fn receive(&self) -> Result<ReceivedMessage> {
+            std::future::pending().await
+        }
+
+        async fn receive_timeout(
+            &self,
+            _timeout: std::time::Duration,
+        ) -> Result<Option<ReceivedMessage>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout_gives_up_on_stuck_send() {
+        let timeout = std::time::Duration::from_millis(50);
+        let recipient = NymAddress::new("bob.mock");
+
+        let result = StuckTransport.send_timeout(&recipient, b"hi".to_vec(), timeout).await;
+        assert!(matches!(result, Err(Error::Timeout(t)) if t == timeout));
+
+        // Sends that go through aren't affected
+        let mut transport = mock::MockTransport::new();
+        transport.connect().await.unwrap();
+        transport.send_timeout(&recipient, b"hi".to_vec(), timeout).await.unwrap();
+        assert_eq!(transport.get_sent_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff() {
+        let delay = std::time::Duration::from_millis(1);
+
+        // Fails twice, then succeeds
+        let mut calls = 0;
+        let result = retry_with_backoff(5, delay, || {
+            calls += 1;
+            let call = calls;
+            async move {
+                match call {
+                    1 => Err(Error::Timeout(delay)),
+                    2 => Err(Error::ReceiveFailed("connection reset".to_string())),
+                    _ => Ok(call),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up once the attempts run out
+        let mut calls = 0;
+        let result: Result<()> = retry_with_backoff(2, delay, || {
+            calls += 1;
+            async move { Err(Error::Timeout(delay)) }
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert_eq!(calls, 2);
+
+        // Other failures aren't retried
+        let mut calls = 0;
+        let result: Result<()> = retry_with_backoff(5, delay, || {
+            calls += 1;
+            async { Err(Error::InvalidData("bad reply".to_string())) }
+        })
+        .await;
+        assert!(matches!(result, Err(Error::InvalidData(_))));
+        assert_eq!(calls, 1);
     }
 }
@@ -1,30 +1,44 @@
 //! Core data types for Brisby
 
+use crate::hash::HashAlgorithm;
+use crate::transport::NymAddress;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-/// A 32-byte BLAKE3 hash
+/// A 32-byte content or chunk hash
 pub type ContentHash = [u8; 32];
 
 /// Information about a file chunk
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChunkInfo {
     /// Index of the chunk (0-based)
     pub index: u32,
-    /// BLAKE3 hash of the chunk data
+    /// Hash of the chunk data
     pub hash: ContentHash,
     /// Size of the chunk in bytes (may be smaller for last chunk)
     pub size: u32,
 }
 
 /// Metadata for a shared file
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileMetadata {
-    /// BLAKE3 hash of the file (computed from chunk hashes)
+    /// Hash of the full file contents
     pub content_hash: ContentHash,
+    /// Algorithm behind `content_hash` and the chunk hashes
+    #[serde(default = "legacy_hash_algo")]
+    pub hash_algo: HashAlgorithm,
     /// Original filename
     pub filename: String,
     /// File size in bytes
     pub size: u64,
+    /// Size of every chunk but the last, in bytes, or the largest a chunk can
+    /// be with content-defined chunking
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+    /// Smallest and average chunk sizes, for files chunked by content;
+    /// `None` for fixed-size chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_defined: Option<ContentDefinedSizes>,
     /// MIME type (if detected)
     pub mime_type: Option<String>,
     /// List of chunks
@@ -33,6 +47,49 @@ pub struct FileMetadata {
     pub keywords: Vec<String>,
     /// Unix timestamp when the file was added
     pub created_at: u64,
+    /// How the chunks are encrypted, for private shares; `None` if they
+    /// aren't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ChunkEncryption>,
+}
+
+/// How a private share's chunks are encrypted, see `chunk::encrypt_chunk`
+///
+/// The chunk hashes, content hash and size all describe the encrypted
+/// chunks, so seeders and index providers store, verify and serve them
+/// without the key. Only downloaders holding the key decrypt, as they write
+/// the file out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkEncryption {
+    /// Picked at random per file. Chunk `i` is encrypted with ChaCha20
+    /// under this nonce followed by `i` as 4 little-endian bytes, so no two
+    /// chunks share a keystream.
+    pub nonce: [u8; 8],
+    /// Keyed hash of the nonce, for telling a wrong key from the right one
+    /// before anything is decrypted with it
+    pub key_check: [u8; 16],
+}
+
+/// Chunk sizes of a file chunked by content, see
+/// `chunk::ChunkingStrategy::ContentDefined`
+///
+/// The largest chunk size goes alongside, as the file's chunk size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentDefinedSizes {
+    /// Smallest a chunk other than the last can be
+    pub min_size: u32,
+    /// Size chunk boundaries are tuned to fall apart by, on average
+    pub avg_size: u32,
+}
+
+/// Algorithm of metadata saved before it was recorded: a flat BLAKE3 hash
+fn legacy_hash_algo() -> HashAlgorithm {
+    HashAlgorithm::Blake3
+}
+
+/// Chunk size assumed for metadata saved before it was recorded
+fn default_chunk_size() -> u32 {
+    crate::CHUNK_SIZE as u32
 }
 
 /// Entry stored in the search index (at index providers)
@@ -44,16 +101,52 @@ pub struct IndexEntry {
     pub filename: String,
     /// Keywords (searchable)
     pub keywords: Vec<String>,
+    /// Explicit user-provided tags (searchable, weighted above keywords)
+    #[serde(default)]
+    pub tags: Vec<String>,
     /// File size in bytes
     pub size: u64,
     /// Number of chunks
     pub chunk_count: u32,
+    /// Size of every chunk but the last, in bytes, or the largest a chunk
+    /// can be with content-defined chunking
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+    /// Smallest and average chunk sizes, for files chunked by content
+    #[serde(default)]
+    pub content_defined: Option<ContentDefinedSizes>,
+    /// MIME type, as detected by the publisher
+    #[serde(default)]
+    pub mime_type: Option<String>,
     /// Unix timestamp when published
     pub published_at: u64,
     /// Time-to-live in seconds
     pub ttl: u64,
 }
 
+/// Restrictions on search results beyond matching the query
+///
+/// Unset fields don't restrict anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchFilter {
+    /// Smallest file size, in bytes
+    pub min_size: Option<u64>,
+    /// Largest file size, in bytes
+    pub max_size: Option<u64>,
+    /// A full MIME type like `video/mp4`, or a top-level type like `video`
+    /// for any of its subtypes
+    pub mime_type: Option<String>,
+    /// Only files first published after this Unix timestamp
+    pub published_after: Option<u64>,
+}
+
+impl SearchFilter {
+    /// Whether the filter lets every result through
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 /// A seeder (peer with file chunks) in the DHT
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Seeder {
@@ -63,6 +156,10 @@ pub struct Seeder {
     pub chunk_bitmap: Vec<u8>,
     /// Unix timestamp when last seen
     pub last_seen: u64,
+    /// Unix timestamp the listing lapses at unless the seeder renews it,
+    /// 0 if unknown
+    #[serde(default)]
+    pub expires_at: u64,
 }
 
 /// Search result returned by index providers
@@ -76,20 +173,117 @@ pub struct SearchResult {
     pub size: u64,
     /// Number of chunks
     pub chunk_count: u32,
+    /// Size of every chunk but the last, in bytes, or the largest a chunk
+    /// can be with content-defined chunking
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+    /// Smallest and average chunk sizes, for files chunked by content
+    #[serde(default)]
+    pub content_defined: Option<ContentDefinedSizes>,
     /// Relevance score (higher is better)
     pub relevance: f32,
     /// Known seeders for this file
     pub seeders: Vec<String>,
+    /// Rendezvous tokens for seeders that published anonymously
+    ///
+    /// Those seeders are only reachable through the index provider that
+    /// returned the result, see `SeederRoute::Relay`.
+    #[serde(default)]
+    pub relay_tokens: Vec<Vec<u8>>,
+    /// Number of seeders the index lists for this file, public and
+    /// anonymous; may exceed the seeders returned
+    #[serde(default)]
+    pub seeder_count: u32,
+}
+
+impl SearchResult {
+    /// Drop results weaker than `min_relevance`, as a fraction of the best
+    /// result's relevance
+    ///
+    /// Relevance scores are unbounded, so they are normalized against the top
+    /// result before comparing. A threshold of 0 keeps every result.
+    pub fn retain_min_relevance(results: &mut Vec<SearchResult>, min_relevance: f32) {
+        if min_relevance.is_nan() || min_relevance <= 0.0 {
+            return;
+        }
+
+        let best = results.iter().map(|r| r.relevance).fold(f32::MIN, f32::max);
+        if best <= 0.0 {
+            return;
+        }
+        results.retain(|r| r.relevance / best >= min_relevance);
+    }
+}
+
+/// How to reach a seeder listed in search results
+///
+/// Seeder lists are plain strings, so a relay route is written as
+/// `relay:<token hex>@<index provider address>` alongside ordinary addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeederRoute {
+    /// Send requests straight to the seeder's address
+    Direct(NymAddress),
+    /// Send requests to an index provider, which relays them to the
+    /// anonymous seeder behind `token`
+    Relay {
+        index_provider: NymAddress,
+        token: Vec<u8>,
+    },
+}
+
+impl SeederRoute {
+    const RELAY_PREFIX: &'static str = "relay:";
+
+    /// Interpret a seeder address string
+    ///
+    /// Anything that isn't a well-formed relay route is a direct address.
+    pub fn parse(seeder: &str) -> Self {
+        let relay = seeder
+            .strip_prefix(Self::RELAY_PREFIX)
+            .and_then(|rest| rest.split_once('@'))
+            .and_then(|(token, index_provider)| {
+                let token = hex::decode(token).ok().filter(|t| !t.is_empty())?;
+                (!index_provider.is_empty()).then(|| (token, NymAddress::new(index_provider)))
+            });
+
+        match relay {
+            Some((token, index_provider)) => SeederRoute::Relay {
+                index_provider,
+                token,
+            },
+            None => SeederRoute::Direct(NymAddress::new(seeder)),
+        }
+    }
+}
+
+impl fmt::Display for SeederRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeederRoute::Direct(address) => write!(f, "{}", address),
+            SeederRoute::Relay {
+                index_provider,
+                token,
+            } => write!(f, "{}{}@{}", Self::RELAY_PREFIX, hex::encode(token), index_provider),
+        }
+    }
 }
 
 impl FileMetadata {
-    /// Extract keywords from a filename
-    pub fn extract_keywords(filename: &str) -> Vec<String> {
-        filename
-            .split(|c: char| !c.is_alphanumeric())
-            .filter(|s| s.len() >= 2)
-            .map(|s| s.to_lowercase())
-            .collect()
+    /// How the file was split into chunks
+    pub fn chunking(&self) -> crate::chunk::ChunkingStrategy {
+        crate::chunk::ChunkingStrategy::from_sizes(self.chunk_size, self.content_defined)
+    }
+
+    /// Proof that chunk `chunk_index` belongs to this file, see
+    /// `chunk::verify_chunk_with_proof`
+    ///
+    /// `None` if the chunk doesn't exist or the content hash isn't a Merkle root.
+    pub fn merkle_proof(&self, chunk_index: u32) -> Option<Vec<ContentHash>> {
+        if !self.hash_algo.is_merkle() {
+            return None;
+        }
+        let leaves: Vec<ContentHash> = self.chunks.iter().map(|c| c.hash).collect();
+        crate::merkle::MerkleTree::new(&leaves, self.size).proof(chunk_index)
     }
 }
 
@@ -3,10 +3,12 @@
 //! This crate provides a distributed hash table for peer discovery,
 //! mapping content hashes to seeders who have the file.
 
+pub mod node;
 pub mod routing;
 pub mod storage;
 
 use brisby_core::ContentHash;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -19,6 +21,12 @@ pub enum DhtError {
 
     #[error("Network error: {0}")]
     Network(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid routing table file: {0}")]
+    InvalidRoutingTable(#[from] serde_json::Error),
 }
 
 pub type Result<T> = std::result::Result<T, DhtError>;
@@ -32,6 +40,13 @@ pub struct DhtConfig {
     pub alpha: usize,
     /// Node ID (32 bytes)
     pub node_id: ContentHash,
+    /// How long a lookup waits for a node to answer before giving up on it
+    pub request_timeout: Duration,
+    /// Nodes not seen for longer than this aren't restored from a saved
+    /// routing table
+    pub max_node_age: Duration,
+    /// How often a node with a routing table file saves it
+    pub persist_interval: Duration,
 }
 
 impl Default for DhtConfig {
@@ -40,6 +55,9 @@ impl Default for DhtConfig {
             k: 20,
             alpha: 3,
             node_id: generate_random_node_id(),
+            request_timeout: Duration::from_secs(10),
+            max_node_age: Duration::from_secs(24 * 60 * 60),
+            persist_interval: Duration::from_secs(5 * 60),
         }
     }
 }
@@ -0,0 +1,867 @@
+//! A DHT node: its iterative lookups and the requests it answers
+//!
+//! `DhtNode::find_node` is the classic Kademlia lookup: starting from the
+//! closest nodes in the routing table, it asks up to `alpha` of the closest
+//! nodes not yet asked for their nodes closest to the target, merges what
+//! they return into the candidates, and repeats until the `k` closest
+//! candidates that haven't failed have all answered. A node that doesn't
+//! answer within `DhtConfig::request_timeout` is dropped from the lookup.
+//! Nodes that answer are added to the routing table; when one's bucket is
+//! full, the bucket's least recently seen node is pinged and only replaced if
+//! it doesn't answer, as Kademlia favours nodes that have stayed up.
+//!
+//! `DhtNode::find_value` walks the same way for a content hash, but stops as
+//! soon as a node answers with seeders for it. `DhtNode::handle_request`
+//! answers both kinds of request from the routing table and storage, and
+//! requests that arrive while a lookup waits on replies are answered too.
+//! Requests go out with random IDs, so a reply can't be matched to one by
+//! guessing the next ID.
+//!
+//! With a routing table file, the nodes known are saved every
+//! `DhtConfig::persist_interval` (checked after each lookup) and on
+//! `shutdown`, and restored on the next start, so a restarted node doesn't
+//! need a full bootstrap.
+
+use crate::routing::{xor_distance, NodeInfo, RoutingTable, UpsertResult};
+use crate::storage::DhtStorage;
+use crate::{DhtConfig, DhtError, Result};
+use brisby_core::proto::{self, error_codes, Envelope, Payload, ProtoSeeder};
+use brisby_core::{ContentHash, NymAddress, ReceivedMessage, Seeder, Transport};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Most seeders a node stores for one content hash
+pub const MAX_SEEDERS_PER_KEY: usize = 50;
+
+/// Where a lookup candidate stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Contact {
+    NotAsked,
+    Answered,
+    Failed,
+}
+
+/// What a node answered during a lookup
+#[derive(Default)]
+struct Reply {
+    nodes: Vec<proto::NodeInfo>,
+    seeders: Vec<ProtoSeeder>,
+}
+
+/// How a `find_value` lookup ended
+#[derive(Debug, Clone)]
+pub enum FindValueResult {
+    /// Seeders of the content hash, as listed by the first node that had any
+    Found(Vec<Seeder>),
+    /// Nobody had any: the `k` closest nodes that answered, closest first,
+    /// which are where the value should be stored
+    Closest(Vec<NodeInfo>),
+}
+
+/// A node taking part in the DHT over a transport
+pub struct DhtNode<T: Transport> {
+    config: DhtConfig,
+    routing: RoutingTable,
+    storage: DhtStorage,
+    transport: Arc<T>,
+    /// Where the routing table is saved, if anywhere
+    routing_file: Option<PathBuf>,
+    last_persisted: Instant,
+}
+
+impl<T: Transport> DhtNode<T> {
+    /// Create a node with an empty routing table and storage
+    pub fn new(config: DhtConfig, transport: Arc<T>) -> Self {
+        let routing = RoutingTable::new(config.node_id, config.k);
+        Self {
+            config,
+            routing,
+            storage: DhtStorage::new(MAX_SEEDERS_PER_KEY),
+            transport,
+            routing_file: None,
+            last_persisted: Instant::now(),
+        }
+    }
+
+    /// Keep the routing table in `path`, restoring the nodes saved there
+    ///
+    /// Nodes last seen more than `DhtConfig::max_node_age` ago are dropped
+    /// rather than restored. A missing file starts an empty table.
+    pub fn with_routing_file(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let min_last_seen = unix_now().saturating_sub(self.config.max_node_age.as_secs());
+        let restored = self.routing.load_from(&path, min_last_seen)?;
+        tracing::debug!("Restored {} DHT node(s) from {}", restored, path.display());
+        self.routing_file = Some(path);
+        Ok(self)
+    }
+
+    /// The file the routing table is kept in, if any
+    pub fn routing_file(&self) -> Option<&Path> {
+        self.routing_file.as_deref()
+    }
+
+    /// Save the routing table, if it has a file
+    pub fn persist(&mut self) -> Result<()> {
+        if let Some(path) = &self.routing_file {
+            self.routing.save_to(path)?;
+            self.last_persisted = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Save the routing table if `DhtConfig::persist_interval` has passed
+    /// since it was last saved
+    fn persist_if_due(&mut self) {
+        if self.last_persisted.elapsed() < self.config.persist_interval {
+            return;
+        }
+        if let Err(e) = self.persist() {
+            tracing::warn!("Failed to save the DHT routing table: {}", e);
+        }
+    }
+
+    /// Save the routing table before the node goes away
+    pub fn shutdown(mut self) -> Result<()> {
+        self.persist()
+    }
+
+    /// Our node ID
+    pub fn node_id(&self) -> &ContentHash {
+        &self.config.node_id
+    }
+
+    /// Add a known node, e.g. a bootstrap node
+    ///
+    /// The node is discarded if its bucket is full, without pinging anyone.
+    pub fn add_node(&mut self, node: NodeInfo) {
+        if node.node_id != self.config.node_id {
+            self.routing.upsert(node);
+        }
+    }
+
+    /// The routing table
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing
+    }
+
+    /// Seeders stored on this node
+    pub fn storage(&self) -> &DhtStorage {
+        &self.storage
+    }
+
+    /// Seeders stored on this node, for storing more
+    pub fn storage_mut(&mut self) -> &mut DhtStorage {
+        &mut self.storage
+    }
+
+    /// Find the `k` nodes closest to `target` that answer, closest first
+    ///
+    /// Replies are read straight off the transport, so nothing else should
+    /// be receiving on it during the lookup. DHT requests arriving meanwhile
+    /// are answered; other messages are dropped.
+    pub async fn find_node(&mut self, target: &ContentHash) -> Result<Vec<NodeInfo>> {
+        match self.lookup(target, false).await? {
+            FindValueResult::Closest(nodes) => Ok(nodes),
+            FindValueResult::Found(_) => unreachable!("FIND_NODE lookups don't find values"),
+        }
+    }
+
+    /// Find seeders of `content_hash`, from this node's storage or by
+    /// walking towards it like `find_node`
+    ///
+    /// The walk stops at the first node that answers with seeders. Seeders
+    /// without an address (anonymous ones, only reachable through an index
+    /// provider) are left out.
+    pub async fn find_value(&mut self, content_hash: &ContentHash) -> Result<FindValueResult> {
+        if let Some(seeders) = self.storage.get(content_hash) {
+            if !seeders.is_empty() {
+                return Ok(FindValueResult::Found(seeders.clone()));
+            }
+        }
+        self.lookup(content_hash, true).await
+    }
+
+    /// Answer a DHT request, or `None` if `request` isn't one
+    pub fn handle_request(&self, request: &Envelope) -> Option<Envelope> {
+        let payload = match request.payload.as_ref()? {
+            Payload::FindNodeRequest(req) => {
+                let Ok(target) = ContentHash::try_from(req.target_id.as_slice()) else {
+                    return Some(invalid_key(request.request_id));
+                };
+                Payload::FindNodeResponse(proto::FindNodeResponse {
+                    nodes: self.closest_proto(&target),
+                })
+            }
+            Payload::PingRequest(_) => Payload::PingResponse(proto::PingResponse {
+                responder_id: self.config.node_id.to_vec(),
+            }),
+            Payload::FindValueRequest(req) => {
+                let Ok(key) = ContentHash::try_from(req.key.as_slice()) else {
+                    return Some(invalid_key(request.request_id));
+                };
+                let response = match self.storage.get(&key).filter(|s| !s.is_empty()) {
+                    Some(seeders) => proto::FindValueResponse {
+                        seeders: seeders.iter().map(seeder_to_proto).collect(),
+                        nodes: Vec::new(),
+                    },
+                    None => proto::FindValueResponse {
+                        seeders: Vec::new(),
+                        nodes: self.closest_proto(&key),
+                    },
+                };
+                Payload::FindValueResponse(response)
+            }
+            _ => return None,
+        };
+        Some(Envelope::new(request.request_id, payload))
+    }
+
+    fn closest_proto(&self, target: &ContentHash) -> Vec<proto::NodeInfo> {
+        self.routing
+            .closest_nodes(target, self.config.k)
+            .iter()
+            .map(NodeInfo::to_proto)
+            .collect()
+    }
+
+    /// Walk towards `target`, asking for its value too if `find_value`
+    async fn lookup(&mut self, target: &ContentHash, find_value: bool) -> Result<FindValueResult> {
+        let k = self.config.k;
+        let mut candidates: Vec<(NodeInfo, Contact)> = self
+            .routing
+            .closest_nodes(target, k)
+            .into_iter()
+            .map(|node| (node, Contact::NotAsked))
+            .collect();
+
+        loop {
+            // The next round asks the closest of the `k` closest live
+            // candidates that haven't been asked yet
+            let to_ask: Vec<NodeInfo> = candidates
+                .iter()
+                .filter(|(_, contact)| *contact != Contact::Failed)
+                .take(k)
+                .filter(|(_, contact)| *contact == Contact::NotAsked)
+                .take(self.config.alpha.max(1))
+                .map(|(node, _)| node.clone())
+                .collect();
+            if to_ask.is_empty() {
+                break;
+            }
+
+            let mut replies = self.query_round(target, &to_ask, find_value).await?;
+            let now = unix_now();
+            for node in &to_ask {
+                let answered = replies.contains_key(&node.node_id);
+                let contact = if answered {
+                    Contact::Answered
+                } else {
+                    Contact::Failed
+                };
+                if let Some(entry) = candidates
+                    .iter_mut()
+                    .find(|(c, _)| c.node_id == node.node_id)
+                {
+                    entry.1 = contact;
+                }
+                if answered {
+                    self.insert_node(NodeInfo {
+                        last_seen: now,
+                        ..node.clone()
+                    })
+                    .await?;
+                }
+            }
+
+            // The closest node with seeders wins
+            for node in &to_ask {
+                let Some(reply) = replies.get_mut(&node.node_id) else {
+                    continue;
+                };
+                let seeders: Vec<Seeder> = reply
+                    .seeders
+                    .drain(..)
+                    .filter_map(seeder_from_proto)
+                    .collect();
+                if !seeders.is_empty() {
+                    self.persist_if_due();
+                    return Ok(FindValueResult::Found(seeders));
+                }
+            }
+
+            for found in replies.into_values().flat_map(|reply| reply.nodes) {
+                let Some(found) = NodeInfo::from_proto(&found, now) else {
+                    continue;
+                };
+                let known = candidates.iter().any(|(c, _)| c.node_id == found.node_id);
+                if !known && found.node_id != self.config.node_id {
+                    candidates.push((found, Contact::NotAsked));
+                }
+            }
+            candidates.sort_by_key(|(node, _)| xor_distance(&node.node_id, target));
+        }
+
+        self.persist_if_due();
+        Ok(FindValueResult::Closest(
+            candidates
+                .into_iter()
+                .filter(|(_, contact)| *contact == Contact::Answered)
+                .map(|(node, _)| node)
+                .take(k)
+                .collect(),
+        ))
+    }
+
+    /// Add a node that answered us, pinging the least recently seen node of
+    /// its bucket if that's full
+    ///
+    /// The old node stays, refreshed, if it answers; otherwise the new one
+    /// takes its place.
+    async fn insert_node(&mut self, node: NodeInfo) -> Result<()> {
+        if node.node_id == self.config.node_id {
+            return Ok(());
+        }
+        let UpsertResult::Full { candidate } = self.routing.upsert(node.clone()) else {
+            return Ok(());
+        };
+        if self.ping(&candidate).await? {
+            self.routing.upsert(NodeInfo {
+                last_seen: unix_now(),
+                ..candidate
+            });
+        } else {
+            tracing::debug!(
+                "Replacing unresponsive node {} with {}",
+                candidate.nym_address,
+                node.nym_address
+            );
+            self.routing.replace(&candidate.node_id, node);
+        }
+        Ok(())
+    }
+
+    /// Check whether `node` answers a PING within the request timeout
+    async fn ping(&mut self, node: &NodeInfo) -> Result<bool> {
+        let request_id = random_request_id();
+        let request = Envelope::new(
+            request_id,
+            Payload::PingRequest(proto::PingRequest {
+                sender_id: self.config.node_id.to_vec(),
+            }),
+        );
+        let address = NymAddress::new(node.nym_address.clone());
+        if let Err(e) = self.transport.send(&address, request.to_bytes()).await {
+            tracing::debug!("PING to {} failed: {}", address, e);
+            return Ok(false);
+        }
+
+        let deadline = Instant::now() + self.config.request_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            let Some(msg) = self
+                .transport
+                .receive_timeout(remaining)
+                .await
+                .map_err(|e| DhtError::Network(e.to_string()))?
+            else {
+                continue;
+            };
+            match Envelope::from_bytes(&msg.data) {
+                Ok(envelope) if envelope.request_id == request_id => {
+                    return Ok(matches!(envelope.payload, Some(Payload::PingResponse(_))));
+                }
+                Ok(envelope) => self.answer_meanwhile(&msg, &envelope).await,
+                Err(_) => tracing::trace!("Dropping undecodable message while pinging"),
+            }
+        }
+    }
+
+    /// Send each of `nodes` a FIND_NODE (or FIND_VALUE) for `target` at once
+    ///
+    /// Returns what each node that answered in time sent back, by node ID.
+    /// A node that can't be sent to, or answers with an error, is left out.
+    async fn query_round(
+        &mut self,
+        target: &ContentHash,
+        nodes: &[NodeInfo],
+        find_value: bool,
+    ) -> Result<HashMap<ContentHash, Reply>> {
+        let kind = if find_value {
+            "FIND_VALUE"
+        } else {
+            "FIND_NODE"
+        };
+        let mut waiting: HashMap<u64, ContentHash> = HashMap::new();
+        for node in nodes {
+            let request_id = random_request_id();
+            let payload = if find_value {
+                Payload::FindValueRequest(proto::FindValueRequest {
+                    key: target.to_vec(),
+                })
+            } else {
+                Payload::FindNodeRequest(proto::FindNodeRequest {
+                    target_id: target.to_vec(),
+                })
+            };
+            let request = Envelope::new(request_id, payload);
+            let address = NymAddress::new(node.nym_address.clone());
+            match self.transport.send(&address, request.to_bytes()).await {
+                Ok(()) => {
+                    waiting.insert(request_id, node.node_id);
+                }
+                Err(e) => tracing::debug!("{} to {} failed: {}", kind, address, e),
+            }
+        }
+
+        let mut replies = HashMap::new();
+        let deadline = Instant::now() + self.config.request_timeout;
+        while !waiting.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                tracing::debug!("{} node(s) didn't answer {} in time", waiting.len(), kind);
+                break;
+            }
+            let Some(msg) = self
+                .transport
+                .receive_timeout(remaining)
+                .await
+                .map_err(|e| DhtError::Network(e.to_string()))?
+            else {
+                continue;
+            };
+            let Ok(envelope) = Envelope::from_bytes(&msg.data) else {
+                tracing::trace!("Dropping undecodable message during lookup");
+                continue;
+            };
+            let Some(node_id) = waiting.remove(&envelope.request_id) else {
+                self.answer_meanwhile(&msg, &envelope).await;
+                continue;
+            };
+            let reply = match envelope.payload {
+                Some(Payload::FindNodeResponse(response)) if !find_value => Reply {
+                    nodes: response.nodes,
+                    ..Reply::default()
+                },
+                Some(Payload::FindValueResponse(response)) if find_value => Reply {
+                    nodes: response.nodes,
+                    seeders: response.seeders,
+                },
+                other => {
+                    tracing::debug!("Unexpected {} reply: {:?}", kind, other);
+                    continue;
+                }
+            };
+            replies.insert(node_id, reply);
+        }
+        Ok(replies)
+    }
+
+    /// Answer a message that isn't the reply being waited on, if it's a DHT
+    /// request, so other nodes' lookups don't stall on ours
+    async fn answer_meanwhile(&self, msg: &ReceivedMessage, envelope: &Envelope) {
+        let (Some(response), Some(sender_tag)) = (self.handle_request(envelope), &msg.sender_tag)
+        else {
+            tracing::trace!("Dropping unexpected message {}", envelope.request_id);
+            return;
+        };
+        if let Err(e) = self.transport.send_reply(sender_tag, response.to_bytes()).await {
+            tracing::debug!("Failed to answer request {}: {}", envelope.request_id, e);
+        }
+    }
+}
+
+/// A fresh request ID, random so replies can't be forged by guessing it
+fn random_request_id() -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("Failed to generate random bytes");
+    u64::from_le_bytes(bytes)
+}
+
+fn invalid_key(request_id: u64) -> Envelope {
+    proto::error_response(
+        request_id,
+        error_codes::INVALID_MESSAGE,
+        "key must be 32 bytes".to_string(),
+    )
+}
+
+fn seeder_to_proto(seeder: &Seeder) -> ProtoSeeder {
+    ProtoSeeder {
+        nym_address: seeder.nym_address.clone(),
+        chunk_bitmap: seeder.chunk_bitmap.clone(),
+        last_seen: seeder.last_seen,
+        expires_at: seeder.expires_at,
+        relay_token: Vec::new(),
+    }
+}
+
+fn seeder_from_proto(seeder: ProtoSeeder) -> Option<Seeder> {
+    (!seeder.nym_address.is_empty()).then_some(Seeder {
+        nym_address: seeder.nym_address,
+        chunk_bitmap: seeder.chunk_bitmap,
+        last_seen: seeder.last_seen,
+        expires_at: seeder.expires_at,
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brisby_core::transport::mock::{MockNetwork, MockTransport};
+    use std::time::Duration;
+
+    fn node(first_byte: u8, name: &str) -> NodeInfo {
+        let mut node_id = [0u8; 32];
+        node_id[0] = first_byte;
+        NodeInfo {
+            node_id,
+            nym_address: name.to_string(),
+            last_seen: 0,
+        }
+    }
+
+    fn config(local: &NodeInfo) -> DhtConfig {
+        DhtConfig {
+            k: 3,
+            alpha: 2,
+            node_id: local.node_id,
+            request_timeout: Duration::from_millis(200),
+            ..DhtConfig::default()
+        }
+    }
+
+    fn seeder(address: &str) -> Seeder {
+        Seeder {
+            nym_address: address.to_string(),
+            chunk_bitmap: vec![0xff],
+            last_seen: 1000,
+            expires_at: 0,
+        }
+    }
+
+    fn found_addresses(result: FindValueResult) -> Vec<String> {
+        let FindValueResult::Found(seeders) = result else {
+            panic!("expected seeders, got {:?}", result);
+        };
+        seeders.into_iter().map(|s| s.nym_address).collect()
+    }
+
+    /// Run `peer` as a DHT node knowing `neighbours` and storing `seeders`
+    /// for the zero key, answering every request sent to it
+    async fn serve(
+        network: &MockNetwork,
+        peer: &NodeInfo,
+        neighbours: Vec<NodeInfo>,
+        seeders: &[Seeder],
+    ) {
+        let mut transport = network.transport(peer.nym_address.as_str());
+        transport.connect().await.unwrap();
+        let mut dht = DhtNode::new(config(peer), Arc::new(transport));
+        for neighbour in neighbours {
+            dht.add_node(neighbour);
+        }
+        for seeder in seeders {
+            dht.storage_mut().store([0u8; 32], seeder.clone());
+        }
+
+        tokio::spawn(async move {
+            loop {
+                let msg = dht.transport.receive().await.unwrap();
+                let request = Envelope::from_bytes(&msg.data).unwrap();
+                let response = dht.handle_request(&request).unwrap();
+                let tag = msg.sender_tag.unwrap();
+                dht.transport
+                    .send_reply(&tag, response.to_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
+    async fn local_node(network: &MockNetwork, known: &NodeInfo) -> DhtNode<MockTransport> {
+        let local = node(0xff, "local");
+        let mut transport = network.transport("local");
+        transport.connect().await.unwrap();
+        let mut dht = DhtNode::new(config(&local), Arc::new(transport));
+        dht.add_node(known.clone());
+        dht
+    }
+
+    #[tokio::test]
+    async fn test_find_node_converges_on_closest() {
+        let network = MockNetwork::new();
+        let local = node(0xff, "local");
+        let (a, b, c, d, e) = (
+            node(0x80, "a"),
+            node(0x40, "b"),
+            node(0x20, "c"),
+            node(0x02, "d"),
+            node(0x01, "e"),
+        );
+
+        // Only `a` is known at first. Each hop leads closer to the zero
+        // target, `c` never answers and `e` is only known to `d`
+        serve(&network, &a, vec![b.clone(), c.clone()], &[]).await;
+        serve(&network, &b, vec![a.clone(), d.clone()], &[]).await;
+        let _silent = network.transport("c");
+        serve(&network, &d, vec![local, b.clone(), e.clone()], &[]).await;
+        serve(&network, &e, vec![d.clone()], &[]).await;
+        let mut dht = local_node(&network, &a).await;
+
+        let closest = tokio::time::timeout(Duration::from_secs(5), dht.find_node(&[0u8; 32]))
+            .await
+            .unwrap()
+            .unwrap();
+        let names: Vec<&str> = closest.iter().map(|n| n.nym_address.as_str()).collect();
+        assert_eq!(names, vec!["e", "d", "b"]);
+
+        // Everyone who answered is in the routing table, the silent node isn't
+        let known: Vec<String> = dht
+            .routing_table()
+            .closest_nodes(&[0u8; 32], 10)
+            .into_iter()
+            .map(|n| n.nym_address)
+            .collect();
+        assert_eq!(known, vec!["e", "d", "b", "a"]);
+        assert!(closest.iter().all(|n| n.last_seen > 0));
+    }
+
+    #[tokio::test]
+    async fn test_find_node_with_empty_routing_table() {
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+        let transport = Arc::new(transport);
+        let mut dht = DhtNode::new(DhtConfig::default(), transport.clone());
+
+        assert!(dht.find_node(&[7u8; 32]).await.unwrap().is_empty());
+        assert!(transport.get_sent_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_value_stops_at_first_seeders() {
+        let network = MockNetwork::new();
+        let (a, b, c) = (node(0x80, "a"), node(0x40, "b"), node(0x01, "c"));
+
+        // `b` has the value; `c` is closer but the walk never gets there
+        serve(&network, &a, vec![b.clone()], &[]).await;
+        serve(
+            &network,
+            &b,
+            vec![c.clone()],
+            &[seeder("seeder-1"), seeder("seeder-2")],
+        )
+        .await;
+        serve(&network, &c, vec![], &[seeder("seeder-3")]).await;
+        let mut dht = local_node(&network, &a).await;
+
+        let result = tokio::time::timeout(Duration::from_secs(5), dht.find_value(&[0u8; 32]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found_addresses(result), vec!["seeder-1", "seeder-2"]);
+        assert_eq!(dht.transport.get_sent_messages().len(), 2);
+        assert_eq!(dht.routing_table().closest_nodes(&[0u8; 32], 10).len(), 2);
+
+        // Values stored locally don't need a walk at all
+        dht.storage_mut().store([0u8; 32], seeder("seeder-4"));
+        let result = dht.find_value(&[0u8; 32]).await.unwrap();
+        assert_eq!(found_addresses(result), vec!["seeder-4"]);
+        assert_eq!(dht.transport.get_sent_messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_find_value_not_found_returns_closest() {
+        let network = MockNetwork::new();
+        let (a, b) = (node(0x80, "a"), node(0x40, "b"));
+        serve(&network, &a, vec![b.clone()], &[]).await;
+        serve(&network, &b, vec![a.clone()], &[]).await;
+        let mut dht = local_node(&network, &a).await;
+
+        let result = tokio::time::timeout(Duration::from_secs(5), dht.find_value(&[0u8; 32]))
+            .await
+            .unwrap()
+            .unwrap();
+        let FindValueResult::Closest(closest) = result else {
+            panic!("nobody has the value, got {:?}", result);
+        };
+        let names: Vec<&str> = closest.iter().map(|n| n.nym_address.as_str()).collect();
+        assert_eq!(names, vec!["b", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_answers_requests_during_lookup() {
+        let network = MockNetwork::new();
+        let (a, b) = (node(0x80, "a"), node(0x40, "b"));
+        serve(&network, &a, vec![b.clone()], &[]).await;
+        serve(&network, &b, vec![], &[]).await;
+        let mut dht = local_node(&network, &a).await;
+
+        // Another node's request reaches us while we wait on our own replies
+        let mut peer = network.transport("peer");
+        peer.connect().await.unwrap();
+        let request = Envelope::new(
+            7,
+            Payload::FindNodeRequest(proto::FindNodeRequest {
+                target_id: vec![0u8; 32],
+            }),
+        );
+        peer.send(&NymAddress::new("local"), request.to_bytes())
+            .await
+            .unwrap();
+
+        let closest = tokio::time::timeout(Duration::from_secs(5), dht.find_node(&[0u8; 32]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(closest.len(), 2);
+
+        let reply = peer
+            .receive_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .expect("the request was answered");
+        let response = Envelope::from_bytes(&reply.data).unwrap();
+        assert_eq!(response.request_id, 7);
+        assert!(matches!(response.payload, Some(Payload::FindNodeResponse(_))));
+
+        // Our own requests went out with unpredictable IDs
+        let ids: Vec<u64> = dht
+            .transport
+            .get_sent_messages()
+            .iter()
+            .map(|(_, data)| Envelope::from_bytes(data).unwrap().request_id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_full_bucket_keeps_live_nodes() {
+        let network = MockNetwork::new();
+        let local = node(0xff, "local");
+        // With one node per bucket, `live` and `newer` share a bucket, as do
+        // `silent` and `newest`
+        let (live, newer) = (node(0x01, "live"), node(0x02, "newer"));
+        let (silent, newest) = (node(0x80, "silent"), node(0x81, "newest"));
+        serve(&network, &live, vec![], &[]).await;
+        let _silent = network.transport("silent");
+        let mut transport = network.transport("local");
+        transport.connect().await.unwrap();
+        let one_per_bucket = DhtConfig {
+            k: 1,
+            ..config(&local)
+        };
+        let mut dht = DhtNode::new(one_per_bucket, Arc::new(transport));
+        dht.add_node(live.clone());
+        dht.add_node(silent.clone());
+        let addresses = |dht: &DhtNode<MockTransport>| -> Vec<String> {
+            dht.routing_table()
+                .closest_nodes(&[0u8; 32], 10)
+                .into_iter()
+                .map(|n| n.nym_address)
+                .collect()
+        };
+
+        // Without pinging, a newcomer to a full bucket is discarded
+        dht.add_node(newer.clone());
+        assert_eq!(addresses(&dht), vec!["live", "silent"]);
+
+        // A node that answers its ping stays, refreshed
+        dht.insert_node(newer.clone()).await.unwrap();
+        assert_eq!(addresses(&dht), vec!["live", "silent"]);
+        let refreshed = dht.routing_table().closest_nodes(&[0u8; 32], 1);
+        assert!(refreshed[0].last_seen > 0);
+
+        // One that doesn't is replaced by the newcomer
+        dht.insert_node(newest.clone()).await.unwrap();
+        assert_eq!(addresses(&dht), vec!["live", "newest"]);
+        let pinged: Vec<String> = dht
+            .transport
+            .get_sent_messages()
+            .into_iter()
+            .map(|(to, _)| to.as_str().to_string())
+            .collect();
+        assert_eq!(pinged, vec!["live", "silent"]);
+    }
+
+    #[test]
+    fn test_handle_request_rejects_bad_key() {
+        let dht = DhtNode::new(DhtConfig::default(), Arc::new(MockTransport::new()));
+        let request = Envelope::new(
+            5,
+            Payload::FindValueRequest(proto::FindValueRequest { key: vec![1, 2, 3] }),
+        );
+        let response = dht.handle_request(&request).unwrap();
+        assert_eq!(response.request_id, 5);
+        assert!(matches!(response.payload, Some(Payload::ErrorResponse(_))));
+
+        let ping = Envelope::new(6, Payload::PingRequest(proto::PingRequest::default()));
+        let response = dht.handle_request(&ping).unwrap();
+        let Some(Payload::PingResponse(pong)) = response.payload else {
+            panic!("expected a pong, got {:?}", response.payload);
+        };
+        assert_eq!(pong.responder_id, dht.node_id().to_vec());
+
+        let search = Envelope::new(7, Payload::SearchRequest(proto::SearchRequest::default()));
+        assert!(dht.handle_request(&search).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_survives_restart() {
+        let network = MockNetwork::new();
+        let local = node(0xff, "local");
+        let (a, b) = (node(0x80, "a"), node(0x40, "b"));
+        serve(&network, &a, vec![b.clone()], &[]).await;
+        serve(&network, &b, vec![], &[]).await;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("routing.json");
+        let mut transport = network.transport("local");
+        transport.connect().await.unwrap();
+        let always_persist = DhtConfig {
+            persist_interval: Duration::ZERO,
+            ..config(&local)
+        };
+        let mut dht = DhtNode::new(always_persist, Arc::new(transport))
+            .with_routing_file(&path)
+            .unwrap();
+        assert!(dht.routing_table().is_empty());
+        dht.add_node(a.clone());
+
+        // Saved once a lookup is done, with the nodes that answered
+        dht.find_node(&[0u8; 32]).await.unwrap();
+        let mut saved = RoutingTable::new(local.node_id, 3);
+        assert_eq!(saved.load_from(&path, 0).unwrap(), 2);
+
+        // Saved again on shutdown; a restart restores all but stale nodes
+        dht.add_node(NodeInfo {
+            last_seen: 1,
+            ..node(0x20, "stale")
+        });
+        dht.shutdown().unwrap();
+        let restarted = DhtNode::new(config(&local), Arc::new(MockTransport::new()))
+            .with_routing_file(&path)
+            .unwrap();
+        let names: Vec<String> = restarted
+            .routing_table()
+            .closest_nodes(&[0u8; 32], 10)
+            .into_iter()
+            .map(|n| n.nym_address)
+            .collect();
+        assert_eq!(names, vec!["b", "a"]);
+        assert_eq!(restarted.routing_file(), Some(path.as_path()));
+    }
+}
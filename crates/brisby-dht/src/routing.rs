@@ -1,7 +1,10 @@
 //! Kademlia routing table implementation
 
-use brisby_core::ContentHash;
+use crate::Result;
+use brisby_core::{proto, ContentHash};
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::path::Path;
 
 /// XOR distance between two node IDs
 pub fn xor_distance(a: &ContentHash, b: &ContentHash) -> ContentHash {
@@ -24,13 +27,46 @@ pub fn bucket_index(distance: &ContentHash) -> usize {
 }
 
 /// Information about a node in the routing table
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NodeInfo {
     pub node_id: ContentHash,
     pub nym_address: String,
     pub last_seen: u64,
 }
 
+impl NodeInfo {
+    /// Node as received in a message, seen at `last_seen`
+    ///
+    /// Returns `None` if the node ID isn't 32 bytes.
+    pub fn from_proto(node: &proto::NodeInfo, last_seen: u64) -> Option<Self> {
+        Some(Self {
+            node_id: node.node_id.as_slice().try_into().ok()?,
+            nym_address: node.nym_address.clone(),
+            last_seen,
+        })
+    }
+
+    /// Node as sent in a message
+    pub fn to_proto(&self) -> proto::NodeInfo {
+        proto::NodeInfo {
+            node_id: self.node_id.to_vec(),
+            nym_address: self.nym_address.clone(),
+        }
+    }
+}
+
+/// What `upsert` did with a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpsertResult {
+    /// The node is new and was added
+    Added,
+    /// The node was already known and is now the most recently seen
+    Updated,
+    /// The bucket is full and the node was left out; `candidate` is the
+    /// least recently seen node, which it should replace if it's gone
+    Full { candidate: NodeInfo },
+}
+
 /// A k-bucket in the routing table
 #[derive(Debug, Clone)]
 pub struct KBucket {
@@ -49,23 +85,39 @@ impl KBucket {
     }
 
     /// Add or update a node in the bucket
-    /// Returns true if the node was added/updated, false if bucket is full
-    pub fn upsert(&mut self, node: NodeInfo) -> bool {
+    ///
+    /// A full bucket keeps the nodes it has, since long-lived nodes are the
+    /// likeliest to stay up, and names the one to ping before replacing it.
+    pub fn upsert(&mut self, node: NodeInfo) -> UpsertResult {
         // Check if node already exists
         if let Some(pos) = self.nodes.iter().position(|n| n.node_id == node.node_id) {
             // Move to back (most recently seen)
             self.nodes.remove(pos);
             self.nodes.push_back(node);
-            return true;
+            return UpsertResult::Updated;
         }
 
         // Add new node if space available
         if self.nodes.len() < self.k {
             self.nodes.push_back(node);
-            return true;
+            return UpsertResult::Added;
+        }
+
+        UpsertResult::Full {
+            candidate: self.nodes.front().expect("full bucket").clone(),
         }
+    }
 
-        false
+    /// Replace the node `old` with `node`, as the most recently seen
+    ///
+    /// Returns false, changing nothing, if `old` isn't in the bucket.
+    pub fn replace(&mut self, old: &ContentHash, node: NodeInfo) -> bool {
+        let Some(pos) = self.nodes.iter().position(|n| n.node_id == *old) else {
+            return false;
+        };
+        self.nodes.remove(pos);
+        self.nodes.push_back(node);
+        true
     }
 
     /// Get all nodes in the bucket
@@ -99,10 +151,24 @@ impl RoutingTable {
     }
 
     /// Add or update a node in the routing table
-    pub fn upsert(&mut self, node: NodeInfo) {
-        let distance = xor_distance(&self.local_id, &node.node_id);
-        let bucket_idx = bucket_index(&distance);
-        self.buckets[bucket_idx].upsert(node);
+    ///
+    /// If its bucket is full the node is discarded; callers that can ping
+    /// the returned candidate use `replace` when it doesn't answer.
+    pub fn upsert(&mut self, node: NodeInfo) -> UpsertResult {
+        self.bucket_mut(&node.node_id).upsert(node)
+    }
+
+    /// Replace the node `old` with `node`, which must fall in the same
+    /// bucket, e.g. an eviction candidate that didn't answer a ping
+    ///
+    /// Returns false, changing nothing, if `old` isn't in that bucket.
+    pub fn replace(&mut self, old: &ContentHash, node: NodeInfo) -> bool {
+        self.bucket_mut(&node.node_id).replace(old, node)
+    }
+
+    fn bucket_mut(&mut self, node_id: &ContentHash) -> &mut KBucket {
+        let distance = xor_distance(&self.local_id, node_id);
+        &mut self.buckets[bucket_index(&distance)]
     }
 
     /// Find the k closest nodes to a target
@@ -124,6 +190,59 @@ impl RoutingTable {
         all_nodes.truncate(count);
         all_nodes
     }
+
+    /// Get the K parameter (nodes per bucket)
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Every node in the table
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.buckets.iter().flat_map(|b| b.nodes())
+    }
+
+    /// Number of nodes in the table
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    /// Check whether the table is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write every node to `path` as JSON
+    ///
+    /// The file is replaced atomically, so a crash mid-save leaves the
+    /// previous one in place.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        let nodes: Vec<&NodeInfo> = self.nodes().collect();
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&nodes)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Add the nodes saved in `path` by `save_to`, skipping any last seen
+    /// before `min_last_seen` and our own ID
+    ///
+    /// A missing file adds nothing. Returns the number of nodes added.
+    pub fn load_from(&mut self, path: &Path, min_last_seen: u64) -> Result<usize> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let nodes: Vec<NodeInfo> = serde_json::from_slice(&bytes)?;
+        let before = self.len();
+        for node in nodes {
+            if node.last_seen >= min_last_seen && node.node_id != self.local_id {
+                self.upsert(node);
+            }
+        }
+        Ok(self.len() - before)
+    }
 }
 
 #[cfg(test)]
@@ -153,4 +272,82 @@ mod tests {
         dist[31] = 0;
         assert_eq!(bucket_index(&dist), 255);
     }
+
+    #[test]
+    fn test_full_bucket_names_eviction_candidate() {
+        let node = |last_byte: u8| {
+            let mut node_id = [0u8; 32];
+            node_id[0] = 0x80;
+            node_id[31] = last_byte;
+            NodeInfo {
+                node_id,
+                nym_address: format!("node-{}", last_byte),
+                last_seen: last_byte as u64,
+            }
+        };
+        let mut table = RoutingTable::new([0u8; 32], 2);
+        assert_eq!(table.upsert(node(1)), UpsertResult::Added);
+        assert_eq!(table.upsert(node(2)), UpsertResult::Added);
+        assert_eq!(table.upsert(node(1)), UpsertResult::Updated);
+
+        // Node 2 is now the least recently seen, and the newcomer is left out
+        assert_eq!(
+            table.upsert(node(3)),
+            UpsertResult::Full { candidate: node(2) }
+        );
+        assert_eq!(table.len(), 2);
+
+        assert!(table.replace(&node(2).node_id, node(3)));
+        assert!(!table.replace(&node(2).node_id, node(4)));
+        let addresses: Vec<&str> = table.nodes().map(|n| n.nym_address.as_str()).collect();
+        assert_eq!(addresses, vec!["node-1", "node-3"]);
+    }
+
+    #[test]
+    fn test_save_and_load_drops_stale_nodes() {
+        let node = |first_byte: u8, last_seen: u64| {
+            let mut node_id = [0u8; 32];
+            node_id[0] = first_byte;
+            NodeInfo {
+                node_id,
+                nym_address: format!("node-{}", first_byte),
+                last_seen,
+            }
+        };
+        let local_id = [0xffu8; 32];
+        let mut table = RoutingTable::new(local_id, 20);
+        table.upsert(node(0x01, 5000));
+        table.upsert(node(0x02, 1000));
+        table.upsert(node(0x80, 4000));
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("routing.json");
+        table.save_to(&path).unwrap();
+
+        // Everything comes back as it was
+        let mut all = RoutingTable::new(local_id, 20);
+        assert_eq!(all.load_from(&path, 0).unwrap(), 3);
+        assert_eq!(
+            all.closest_nodes(&[0u8; 32], 10),
+            table.closest_nodes(&[0u8; 32], 10)
+        );
+
+        // Nodes last seen too long ago are left out
+        let mut fresh = RoutingTable::new(local_id, 20);
+        assert_eq!(fresh.load_from(&path, 3000).unwrap(), 2);
+        let addresses: Vec<String> = fresh
+            .closest_nodes(&[0u8; 32], 10)
+            .into_iter()
+            .map(|n| n.nym_address)
+            .collect();
+        assert_eq!(addresses, vec!["node-1", "node-128"]);
+
+        let mut missing = RoutingTable::new(local_id, 20);
+        assert_eq!(
+            missing.load_from(&temp_dir.path().join("none"), 0).unwrap(),
+            0
+        );
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(missing.load_from(&path, 0).is_err());
+    }
 }
@@ -21,7 +21,7 @@ impl DhtStorage {
 
     /// Store a seeder for a content hash
     pub fn store(&mut self, key: ContentHash, seeder: Seeder) {
-        let seeders = self.entries.entry(key).or_insert_with(Vec::new);
+        let seeders = self.entries.entry(key).or_default();
 
         // Check if seeder already exists (by nym_address)
         if let Some(existing) = seeders.iter_mut().find(|s| s.nym_address == seeder.nym_address) {
@@ -70,6 +70,7 @@ mod tests {
             nym_address: "test-address".to_string(),
             chunk_bitmap: vec![0xff],
             last_seen: 1000,
+            expires_at: 0,
         };
 
         storage.store(key, seeder.clone());
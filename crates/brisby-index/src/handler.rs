@@ -1,30 +1,135 @@
 //! Message handler for the index provider
 //!
 //! Processes incoming protocol messages and routes them to appropriate handlers.
+//!
+//! Seeders that publish anonymously are listed by rendezvous token instead of
+//! address. Downloaders reach them by wrapping requests in a `RelayRequest`,
+//! which is forwarded under a fresh request ID; the seeder's reply comes back
+//! over the SURBs of the forwarded message and is passed on to the downloader
+//! under its original request ID. Neither side learns the other's address.
+//!
+//! A publish isn't listed straight away. The provider answers it with a
+//! `PublishChallenge` naming a random chunk, and the publisher must send that
+//! chunk back with its Merkle proof in a `PublishProof`. The provider holds no
+//! file data, so the content hash (the Merkle root) is the only thing it can
+//! check an answer against; a bare chunk hash or a MAC over the chunk couldn't
+//! be verified. Once the proof checks out the entry is stored and the
+//! `PublishResponse` sent, so a publisher that doesn't hold the file can't
+//! list it.
+//!
+//! Relayed requests and liveness pings to seeders (see `liveness`) go out
+//! under random request IDs, since the replies to both arrive the same way,
+//! without a reply path, and the ID is all that ties a reply to its request.
+//! Sequential IDs would let anyone who can guess one forge a seeder's reply.
 
 use brisby_core::proto::{
-    self, error_codes, Envelope, Payload, PublishRequest, PublishResponse, SearchRequest,
-    SearchResponse, SearchResult as ProtoSearchResult,
+    self, capabilities, error_codes, CapabilitiesResponse, Envelope, FindValueRequest,
+    FindValueResponse, Payload, ProtoSeeder, PublishChallenge, PublishProof, PublishRequest,
+    PublishResponse, RelayRequest, SearchRequest, SearchResponse,
+    SearchResult as ProtoSearchResult, UnpublishRequest, UnpublishResponse,
+};
+use brisby_core::chunk::ChunkingStrategy;
+use brisby_core::keywords::QueryMode;
+use brisby_core::{
+    ContentHash, HashAlgorithm, IndexEntry, NymAddress, ReceivedMessage, SenderTag, Transport,
+    PROTOCOL_VERSION,
 };
-use brisby_core::{IndexEntry, ReceivedMessage, SenderTag, Transport};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::search::{self, SearchIndex, RELAY_TOKEN_LEN, UNPUBLISH_TOKEN_LEN};
+
+/// Features this index provider advertises in capability responses
+pub const INDEX_CAPABILITIES: u64 = capabilities::SEARCH
+    | capabilities::PUBLISH
+    | capabilities::MIN_RELEVANCE
+    | capabilities::RELAY
+    | capabilities::UNPUBLISH
+    | capabilities::PUBLISH_PROOF
+    | capabilities::PAGINATION
+    | capabilities::SEARCH_FILTERS
+    | capabilities::ADVANCED_QUERY
+    | capabilities::FIND_VALUE;
+
+/// How long a relayed request waits for the seeder's reply
+const RELAY_TIMEOUT: Duration = Duration::from_secs(120);
 
-use crate::search::SearchIndex;
+/// Maximum relayed requests awaiting a reply at once
+const MAX_PENDING_RELAYS: usize = 4096;
+
+/// How long a publisher has to answer a proof-of-possession challenge
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Maximum publishes awaiting proof of possession at once
+const MAX_PENDING_CHALLENGES: usize = 4096;
+
+/// Length of the nonce identifying a challenge
+const CHALLENGE_NONCE_LEN: usize = 16;
+
+/// Message to send after handling an incoming one
+#[derive(Debug)]
+pub enum Outgoing {
+    /// Reply to a sender through its SURBs
+    Reply(SenderTag, Vec<u8>),
+    /// Forward a relayed request to an anonymous seeder
+    Forward(NymAddress, Vec<u8>),
+}
+
+/// A relayed request awaiting the seeder's reply
+struct PendingRelay {
+    /// Reply path to the downloader
+    sender_tag: SenderTag,
+    /// Request ID the downloader used
+    request_id: u64,
+    forwarded_at: Instant,
+}
+
+/// A validated publish awaiting proof of possession
+struct PendingChallenge {
+    request: PublishRequest,
+    chunk_index: u32,
+    issued_at: Instant,
+}
+
+/// A liveness ping awaiting the seeder's reply
+struct PendingProbe {
+    nym_address: String,
+    /// Files the seeder is listed for
+    content_hashes: Vec<ContentHash>,
+    sent_at: Instant,
+}
 
 /// Handler for processing protocol messages
 pub struct MessageHandler {
     index: SearchIndex,
+    /// Relayed requests keyed by the request ID used towards the seeder
+    relays: Mutex<HashMap<u64, PendingRelay>>,
+    /// Liveness pings keyed by request ID
+    probes: Mutex<HashMap<u64, PendingProbe>>,
+    /// Publishes awaiting proof of possession, keyed by challenge nonce
+    challenges: Mutex<HashMap<Vec<u8>, PendingChallenge>>,
 }
 
 impl MessageHandler {
     /// Create a new message handler
     pub fn new(index: SearchIndex) -> Self {
-        Self { index }
+        Self {
+            index,
+            relays: Mutex::new(HashMap::new()),
+            probes: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
+        }
     }
 
-    /// Process an incoming message and return a response
-    pub fn handle(&self, msg: &ReceivedMessage) -> Option<(SenderTag, Vec<u8>)> {
-        // We need a sender_tag to reply
-        let sender_tag = msg.sender_tag.as_ref()?;
+    /// Process an incoming message and return what to send in response
+    pub fn handle(&self, msg: &ReceivedMessage) -> Option<Outgoing> {
+        // Replies from seeders to relayed requests are the only messages
+        // that arrive without a reply path
+        let Some(sender_tag) = msg.sender_tag.as_ref() else {
+            return self.relay_reply(&msg.data);
+        };
 
         // Decode the envelope
         let envelope = match Envelope::from_bytes(&msg.data) {
@@ -36,14 +141,27 @@ impl MessageHandler {
                     error_codes::INVALID_MESSAGE,
                     format!("decode error: {}", e),
                 );
-                return Some((sender_tag.clone(), response.to_bytes()));
+                return Some(Outgoing::Reply(sender_tag.clone(), response.to_bytes()));
             }
         };
 
         let request_id = envelope.request_id;
         let response = match envelope.payload {
+            Some(Payload::RelayRequest(req)) => {
+                return Some(self.handle_relay(request_id, sender_tag, req));
+            }
             Some(Payload::PublishRequest(req)) => self.handle_publish(request_id, req),
+            Some(Payload::PublishProof(proof)) => self.handle_publish_proof(request_id, proof),
+            Some(Payload::UnpublishRequest(req)) => self.handle_unpublish(request_id, req),
             Some(Payload::SearchRequest(req)) => self.handle_search(request_id, req),
+            Some(Payload::FindValueRequest(req)) => self.handle_find_value(request_id, req),
+            Some(Payload::CapabilitiesRequest(_)) => Envelope::new(
+                request_id,
+                Payload::CapabilitiesResponse(CapabilitiesResponse {
+                    protocol_version: PROTOCOL_VERSION as u32,
+                    features: INDEX_CAPABILITIES,
+                }),
+            ),
             Some(other) => {
                 tracing::warn!("Unexpected message type: {:?}", other);
                 proto::error_response(
@@ -62,11 +180,177 @@ impl MessageHandler {
             }
         };
 
-        Some((sender_tag.clone(), response.to_bytes()))
+        Some(Outgoing::Reply(sender_tag.clone(), response.to_bytes()))
+    }
+
+    /// Handle a relay request, forwarding it to the seeder or answering the
+    /// downloader with an error
+    fn handle_relay(&self, request_id: u64, sender_tag: &SenderTag, req: RelayRequest) -> Outgoing {
+        let reject = |code, message: String| {
+            let response = proto::error_response(request_id, code, message);
+            Outgoing::Reply(sender_tag.clone(), response.to_bytes())
+        };
+
+        let seeder = match self.index.relay_address(&req.token) {
+            Ok(Some(address)) => NymAddress::new(address),
+            Ok(None) => return reject(error_codes::NOT_FOUND, "unknown relay token".to_string()),
+            Err(e) => {
+                tracing::error!("Relay lookup failed: {}", e);
+                return reject(
+                    error_codes::UNAVAILABLE,
+                    format!("relay lookup error: {}", e),
+                );
+            }
+        };
+
+        // Only chunk requests are relayed, so the index can't be used to send
+        // anonymous seeders arbitrary traffic
+        let mut inner = match Envelope::from_bytes(&req.payload) {
+            Ok(inner) if inner.as_chunk_request().is_some() => inner,
+            Ok(_) => {
+                return reject(
+                    error_codes::INVALID_MESSAGE,
+                    "only chunk requests can be relayed".to_string(),
+                )
+            }
+            Err(e) => {
+                return reject(
+                    error_codes::INVALID_MESSAGE,
+                    format!("relayed payload decode error: {}", e),
+                )
+            }
+        };
+
+        let relay_id = forward_id();
+        {
+            let mut relays = self.relays.lock().unwrap();
+            let now = Instant::now();
+            relays.retain(|_, relay| now.duration_since(relay.forwarded_at) < RELAY_TIMEOUT);
+            if relays.len() >= MAX_PENDING_RELAYS {
+                return reject(
+                    error_codes::UNAVAILABLE,
+                    "too many relayed requests in flight".to_string(),
+                );
+            }
+            relays.insert(
+                relay_id,
+                PendingRelay {
+                    sender_tag: sender_tag.clone(),
+                    request_id: inner.request_id,
+                    forwarded_at: now,
+                },
+            );
+        }
+
+        inner.request_id = relay_id;
+        Outgoing::Forward(seeder, inner.to_bytes())
+    }
+
+    /// Pass a seeder's reply to a relayed request back to the downloader,
+    /// or note its answer to a liveness ping
+    fn relay_reply(&self, data: &[u8]) -> Option<Outgoing> {
+        let mut envelope = match Envelope::from_bytes(data) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                tracing::warn!("Failed to decode relayed reply: {}", e);
+                return None;
+            }
+        };
+
+        if let Some(probe) = self.probes.lock().unwrap().remove(&envelope.request_id) {
+            tracing::debug!("Seeder {} answered its liveness ping", probe.nym_address);
+            return None;
+        }
+
+        let Some(relay) = self.relays.lock().unwrap().remove(&envelope.request_id) else {
+            tracing::debug!("Dropping reply to unknown relay {}", envelope.request_id);
+            return None;
+        };
+
+        envelope.request_id = relay.request_id;
+        Some(Outgoing::Reply(relay.sender_tag, envelope.to_bytes()))
+    }
+
+    /// Ping up to `max_seeders` seeders that published since
+    /// `published_since`, returning the pings to send
+    ///
+    /// Seeders already awaiting an answer aren't pinged again. Those that
+    /// don't answer are removed by `expire_probes`.
+    pub fn start_probes(
+        &self,
+        published_since: u64,
+        max_seeders: usize,
+    ) -> Vec<(NymAddress, Vec<u8>)> {
+        let candidates = match self.index.probe_candidates(published_since, max_seeders) {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                tracing::error!("Failed to pick seeders to ping: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut by_address: BTreeMap<String, Vec<ContentHash>> = BTreeMap::new();
+        for (content_hash, nym_address) in candidates {
+            by_address.entry(nym_address).or_default().push(content_hash);
+        }
+
+        let mut probes = self.probes.lock().unwrap();
+        let mut pings = Vec::new();
+        for (nym_address, content_hashes) in by_address {
+            if probes.values().any(|probe| probe.nym_address == nym_address) {
+                continue;
+            }
+            let probe_id = forward_id();
+            let ping = Envelope::new(
+                probe_id,
+                Payload::PingRequest(proto::PingRequest {
+                    sender_id: Vec::new(),
+                }),
+            );
+            pings.push((NymAddress::new(nym_address.clone()), ping.to_bytes()));
+            probes.insert(
+                probe_id,
+                PendingProbe {
+                    nym_address,
+                    content_hashes,
+                    sent_at: Instant::now(),
+                },
+            );
+        }
+        pings
+    }
+
+    /// Stop listing seeders that haven't answered their ping within
+    /// `timeout`, returning how many listings were removed
+    pub fn expire_probes(&self, timeout: Duration) -> usize {
+        let expired: Vec<PendingProbe> = {
+            let mut probes = self.probes.lock().unwrap();
+            let ids: Vec<u64> = probes
+                .iter()
+                .filter(|(_, probe)| probe.sent_at.elapsed() >= timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| probes.remove(id)).collect()
+        };
+
+        let mut removed = 0;
+        for probe in expired {
+            for content_hash in &probe.content_hashes {
+                match self.index.remove_seeder(content_hash, &probe.nym_address) {
+                    Ok(true) => removed += 1,
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("Failed to remove unresponsive seeder: {}", e),
+                }
+            }
+            tracing::info!(
+                "Seeder {} didn't answer its liveness ping, no longer listing it",
+                probe.nym_address
+            );
+        }
+        removed
     }
 
     /// Handle a publish request
-    fn handle_publish(&self, request_id: u64, req: PublishRequest) -> Envelope {
+    fn handle_publish(&self, request_id: u64, mut req: PublishRequest) -> Envelope {
         // Validate content hash
         if req.content_hash.len() != 32 {
             return proto::error_response(
@@ -103,6 +387,24 @@ impl MessageHandler {
             }
         }
 
+        // Validate tags
+        if req.tags.len() > 50 {
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                "too many tags (max 50)".to_string(),
+            );
+        }
+        for tag in &req.tags {
+            if tag.len() > 100 {
+                return proto::error_response(
+                    request_id,
+                    error_codes::INVALID_DATA,
+                    "tag too long (max 100 chars)".to_string(),
+                );
+            }
+        }
+
         // Validate nym_address
         if req.nym_address.is_empty() || req.nym_address.len() > 500 {
             return proto::error_response(
@@ -112,6 +414,49 @@ impl MessageHandler {
             );
         }
 
+        // Publishers that predate chunk_size all used CHUNK_SIZE. Larger
+        // chunks than a seeder will decompress couldn't be served anyway.
+        if req.chunk_size == 0 {
+            req.chunk_size = brisby_core::CHUNK_SIZE as u32;
+        }
+        let chunking = ChunkingStrategy::from_sizes(
+            req.chunk_size,
+            req.content_defined.clone().map(Into::into),
+        );
+        if chunking.validate().is_err()
+            || chunking.max_chunk_size() > brisby_core::chunk::MAX_DECOMPRESSED_CHUNK_SIZE
+        {
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                format!("unusable chunk sizes {:?}", chunking),
+            );
+        }
+
+        // Validate chunk_count against size. The request carries no chunk list,
+        // so this is what keeps downloaders from expecting the wrong chunks.
+        // Content-defined chunks only bound the count.
+        let expected_chunks = chunking.chunk_count_range(req.size);
+        if !expected_chunks.contains(&(req.chunk_count as u64)) {
+            let expected = if expected_chunks.start() == expected_chunks.end() {
+                expected_chunks.start().to_string()
+            } else {
+                format!("{} to {}", expected_chunks.start(), expected_chunks.end())
+            };
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                format!(
+                    "chunk_count {} inconsistent with size {} (expected {})",
+                    req.chunk_count, req.size, expected
+                ),
+            );
+        }
+
+        if let Err(error) = self.listed_layout(&req) {
+            return proto::error_response(request_id, error_codes::INVALID_DATA, error);
+        }
+
         tracing::info!(
             "Publish request: {} ({} bytes, {} chunks)",
             req.filename,
@@ -119,6 +464,156 @@ impl MessageHandler {
             req.chunk_count
         );
 
+        // An empty file has no chunk to prove possession of
+        if req.chunk_count == 0 {
+            return self.store_published(request_id, req);
+        }
+        self.challenge(request_id, req)
+    }
+
+    /// Hold a validated publish and challenge the publisher for a random chunk
+    fn challenge(&self, request_id: u64, req: PublishRequest) -> Envelope {
+        let mut random = [0u8; CHALLENGE_NONCE_LEN + 4];
+        getrandom::getrandom(&mut random).expect("Failed to generate random bytes");
+        let (nonce, index_bytes) = random.split_at(CHALLENGE_NONCE_LEN);
+        let chunk_index = u32::from_le_bytes(index_bytes.try_into().unwrap()) % req.chunk_count;
+
+        let mut challenges = self.challenges.lock().unwrap();
+        let now = Instant::now();
+        challenges.retain(|_, pending| now.duration_since(pending.issued_at) < CHALLENGE_TIMEOUT);
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            return proto::error_response(
+                request_id,
+                error_codes::UNAVAILABLE,
+                "too many publishes awaiting proof".to_string(),
+            );
+        }
+
+        let content_hash = req.content_hash.clone();
+        challenges.insert(
+            nonce.to_vec(),
+            PendingChallenge {
+                request: req,
+                chunk_index,
+                issued_at: now,
+            },
+        );
+        Envelope::new(
+            request_id,
+            Payload::PublishChallenge(PublishChallenge {
+                content_hash,
+                chunk_index,
+                nonce: nonce.to_vec(),
+            }),
+        )
+    }
+
+    /// Handle the answer to a publish challenge, storing the entry if the
+    /// chunk checks out against the content hash
+    fn handle_publish_proof(&self, request_id: u64, proof: PublishProof) -> Envelope {
+        let pending = self.challenges.lock().unwrap().remove(&proof.nonce);
+        let Some(pending) = pending.filter(|p| p.issued_at.elapsed() < CHALLENGE_TIMEOUT) else {
+            return proto::error_response(
+                request_id,
+                error_codes::NOT_FOUND,
+                "unknown or expired publish challenge".to_string(),
+            );
+        };
+
+        let mut content_hash = [0u8; 32];
+        content_hash.copy_from_slice(&pending.request.content_hash);
+        // Someone else may have listed the hash since the challenge went out
+        let (size, chunk_count) = match self.listed_layout(&pending.request) {
+            Ok(layout) => layout,
+            Err(error) => {
+                return Envelope::new(
+                    request_id,
+                    Payload::PublishResponse(PublishResponse {
+                        success: false,
+                        error,
+                        unpublish_token: Vec::new(),
+                    }),
+                );
+            }
+        };
+        let chunking = ChunkingStrategy::from_sizes(
+            pending.request.chunk_size,
+            pending.request.content_defined.clone().map(Into::into),
+        );
+        let expected_len = chunking.chunk_size_range(pending.chunk_index, chunk_count, size);
+        let path: Option<Vec<_>> = proof
+            .proof
+            .iter()
+            .map(|hash| <[u8; 32]>::try_from(hash.as_slice()).ok())
+            .collect();
+        // Publishers only offer Merkle roots: seeders move files with flat
+        // hashes over when loading them, since those can't be proven per chunk
+        let verified = expected_len.contains(&(proof.data.len() as u64))
+            && path.is_some_and(|path| {
+                brisby_core::chunk::verify_chunk_with_proof(
+                    HashAlgorithm::Blake3Merkle,
+                    &proof.data,
+                    pending.chunk_index,
+                    chunk_count,
+                    size,
+                    &path,
+                    &content_hash,
+                )
+            });
+
+        if !verified {
+            tracing::info!(
+                "Rejecting publish of {}: proof of possession failed",
+                brisby_core::hash_to_hex(&content_hash)
+            );
+            return Envelope::new(
+                request_id,
+                Payload::PublishResponse(PublishResponse {
+                    success: false,
+                    error: "proof of possession failed".to_string(),
+                    unpublish_token: Vec::new(),
+                }),
+            );
+        }
+        self.store_published(request_id, pending.request)
+    }
+
+    /// Size and chunk count a publish has to be checked against: those the
+    /// hash is already listed with, or the publisher's if it isn't listed
+    ///
+    /// The first-seen layout stays so results don't flip between two. A
+    /// publisher claiming a different one is wrong, or the hash collides;
+    /// either way it isn't listed.
+    fn listed_layout(&self, req: &PublishRequest) -> Result<(u64, u32), String> {
+        let mut content_hash = [0u8; 32];
+        content_hash.copy_from_slice(&req.content_hash);
+        match self.index.layout(&content_hash) {
+            Ok(Some(listed)) if listed != (req.size, req.chunk_count) => {
+                tracing::warn!(
+                    "Rejecting publish of {} by {}: {} bytes in {} chunks, but listed as \
+                     {} bytes in {} chunks",
+                    brisby_core::hash_to_hex(&content_hash),
+                    req.nym_address,
+                    req.size,
+                    req.chunk_count,
+                    listed.0,
+                    listed.1
+                );
+                Err(format!(
+                    "already listed as {} bytes in {} chunks",
+                    listed.0, listed.1
+                ))
+            }
+            Ok(listed) => Ok(listed.unwrap_or((req.size, req.chunk_count))),
+            Err(e) => {
+                tracing::error!("Failed to look up listed layout: {}", e);
+                Err(format!("storage error: {}", e))
+            }
+        }
+    }
+
+    /// Store a publish that has passed validation and any challenge
+    fn store_published(&self, request_id: u64, req: PublishRequest) -> Envelope {
         let mut content_hash = [0u8; 32];
         content_hash.copy_from_slice(&req.content_hash);
 
@@ -127,8 +622,12 @@ impl MessageHandler {
             content_hash,
             filename: req.filename.clone(),
             keywords: req.keywords.clone(),
+            tags: req.tags.clone(),
             size: req.size,
             chunk_count: req.chunk_count,
+            chunk_size: req.chunk_size,
+            content_defined: req.content_defined.clone().map(Into::into),
+            mime_type: (!req.mime_type.is_empty()).then(|| req.mime_type.clone()),
             published_at: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
@@ -137,7 +636,26 @@ impl MessageHandler {
         };
 
         // Store in index
-        match self.index.upsert(&entry, &req.nym_address) {
+        let stored = if req.anonymous {
+            let mut token = vec![0u8; RELAY_TOKEN_LEN];
+            getrandom::getrandom(&mut token).expect("Failed to generate random bytes");
+            self.index
+                .upsert_anonymous(&entry, &req.nym_address, &token)
+                .map(|_| ())
+        } else {
+            self.index.upsert(&entry, &req.nym_address)
+        };
+
+        // Only whoever got this reply can unpublish the listing
+        let mut unpublish_token = vec![0u8; UNPUBLISH_TOKEN_LEN];
+        getrandom::getrandom(&mut unpublish_token).expect("Failed to generate random bytes");
+        let stored = stored.and_then(|()| {
+            self.index
+                .set_unpublish_token(&content_hash, &req.nym_address, &unpublish_token)
+                .map(|_| ())
+        });
+
+        match stored {
             Ok(()) => {
                 tracing::info!("Published: {}", brisby_core::hash_to_hex(&content_hash));
                 Envelope::new(
@@ -145,6 +663,7 @@ impl MessageHandler {
                     Payload::PublishResponse(PublishResponse {
                         success: true,
                         error: String::new(),
+                        unpublish_token,
                     }),
                 )
             }
@@ -155,9 +674,103 @@ impl MessageHandler {
                     Payload::PublishResponse(PublishResponse {
                         success: false,
                         error: format!("storage error: {}", e),
+                        unpublish_token: Vec::new(),
+                    }),
+                )
+            }
+        }
+    }
+
+    /// Handle an unpublish request
+    ///
+    /// The mixnet hides who sent it, so knowing a seeder's address isn't
+    /// enough: the request has to carry the token handed to the seeder when
+    /// it last published the file.
+    fn handle_unpublish(&self, request_id: u64, req: UnpublishRequest) -> Envelope {
+        let Ok(content_hash) = <[u8; 32]>::try_from(req.content_hash.as_slice()) else {
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                "invalid content hash length".to_string(),
+            );
+        };
+        if req.nym_address.is_empty() || req.nym_address.len() > 500 {
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                "invalid nym address".to_string(),
+            );
+        }
+
+        let unpublished =
+            self.index.unpublish(&content_hash, &req.nym_address, &req.unpublish_token);
+        let (success, error) = match unpublished {
+            Ok(true) => {
+                tracing::info!("Unpublished: {}", brisby_core::hash_to_hex(&content_hash));
+                (true, String::new())
+            }
+            Ok(false) => (false, "not published by this seeder with this token".to_string()),
+            Err(e) => {
+                tracing::error!("Failed to remove seeder: {}", e);
+                (false, format!("storage error: {}", e))
+            }
+        };
+        Envelope::new(
+            request_id,
+            Payload::UnpublishResponse(UnpublishResponse { success, error }),
+        )
+    }
+
+    /// List the seeders of the content hash in a `FindValueRequest`
+    ///
+    /// The index has no DHT routing table, so the response never names
+    /// closer nodes: an unknown hash just has no seeders.
+    fn handle_find_value(&self, request_id: u64, req: FindValueRequest) -> Envelope {
+        let Ok(content_hash) = <[u8; 32]>::try_from(req.key.as_slice()) else {
+            return proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                "invalid content hash length".to_string(),
+            );
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self.index.seeders(&content_hash, now) {
+            Ok(listed) => {
+                tracing::info!(
+                    "Seeder lookup: {} has {} seeders",
+                    brisby_core::hash_to_hex(&content_hash),
+                    listed.len()
+                );
+                let seeders = listed
+                    .into_iter()
+                    .map(|seeder| ProtoSeeder {
+                        nym_address: seeder.nym_address.unwrap_or_default(),
+                        chunk_bitmap: Vec::new(),
+                        last_seen: seeder.published_at,
+                        expires_at: seeder.expires_at,
+                        relay_token: seeder.relay_token.unwrap_or_default(),
+                    })
+                    .collect();
+                Envelope::new(
+                    request_id,
+                    Payload::FindValueResponse(FindValueResponse {
+                        seeders,
+                        nodes: Vec::new(),
                     }),
                 )
             }
+            Err(e) => {
+                tracing::error!("Seeder lookup failed: {}", e);
+                proto::error_response(
+                    request_id,
+                    error_codes::UNAVAILABLE,
+                    format!("lookup error: {}", e),
+                )
+            }
         }
     }
 
@@ -180,17 +793,40 @@ impl MessageHandler {
             );
         }
 
-        tracing::info!("Search request: '{}' (max {})", query, req.max_results);
+        tracing::info!(
+            "Search request: '{}' (max {}, offset {})",
+            query,
+            req.max_results,
+            req.offset
+        );
+
+        let max_results = req.result_limit();
 
-        let max_results = if req.max_results == 0 || req.max_results > 100 {
-            100
+        // Relevance is normalized against the best result, so only 0..=1 is
+        // meaningful; anything else (including NaN) means no threshold
+        let min_relevance = if (0.0..=1.0).contains(&req.min_relevance) {
+            req.min_relevance
         } else {
-            req.max_results
+            0.0
         };
 
-        match self.index.search(query, max_results) {
-            Ok(results) => {
-                tracing::info!("Found {} results", results.len());
+        let mode = if req.advanced {
+            QueryMode::Advanced
+        } else {
+            QueryMode::Keywords
+        };
+        let filter = req.filter();
+        let found = self
+            .index
+            .search(query, mode, max_results, req.offset, min_relevance, &filter)
+            .and_then(|results| {
+                let total_results =
+                    self.index.count_matches(query, mode, min_relevance, &filter)?;
+                Ok((results, total_results))
+            });
+        match found {
+            Ok((results, total_results)) => {
+                tracing::info!("Found {} results of {}", results.len(), total_results);
 
                 let proto_results: Vec<ProtoSearchResult> = results
                     .into_iter()
@@ -199,8 +835,12 @@ impl MessageHandler {
                         filename: r.filename,
                         size: r.size,
                         chunk_count: r.chunk_count,
+                        chunk_size: r.chunk_size,
+                        content_defined: r.content_defined.map(Into::into),
                         relevance: r.relevance,
                         seeders: r.seeders,
+                        relay_tokens: r.relay_tokens,
+                        seeder_count: r.seeder_count,
                     })
                     .collect();
 
@@ -208,9 +848,15 @@ impl MessageHandler {
                     request_id,
                     Payload::SearchResponse(SearchResponse {
                         results: proto_results,
+                        total_results,
                     }),
                 )
             }
+            Err(e) if search::is_query_error(&e) => proto::error_response(
+                request_id,
+                error_codes::INVALID_DATA,
+                format!("malformed search query: {}", e),
+            ),
             Err(e) => {
                 tracing::error!("Search failed: {}", e);
                 proto::error_response(
@@ -223,33 +869,80 @@ impl MessageHandler {
     }
 }
 
+/// A random, non-zero request ID for a message forwarded to a seeder
+fn forward_id() -> u64 {
+    loop {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).expect("Failed to generate random bytes");
+        let id = u64::from_le_bytes(bytes);
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// Most requests handled at once by `run_message_loop`
+pub const MAX_CONCURRENT_REQUESTS: usize = 32;
+
 /// Run the index provider message loop
+///
+/// Each message is handled on the blocking thread pool, so a slow search
+/// doesn't hold up other clients; at most `MAX_CONCURRENT_REQUESTS` are in
+/// flight, and no more are received until one finishes. Replies are sent
+/// from the loop as handling completes, so they may go out in a different
+/// order than the requests came in.
 pub async fn run_message_loop<T: Transport>(
     transport: &T,
-    handler: &MessageHandler,
+    handler: &Arc<MessageHandler>,
 ) -> brisby_core::Result<()> {
     tracing::info!("Starting message loop");
 
+    let mut in_flight = JoinSet::new();
     loop {
-        // Wait for incoming message
-        match transport.receive_timeout(std::time::Duration::from_secs(30)).await {
-            Ok(Some(msg)) => {
-                if let Some((sender_tag, response_bytes)) = handler.handle(&msg) {
-                    if let Err(e) = transport.send_reply(&sender_tag, response_bytes).await {
-                        tracing::error!("Failed to send reply: {}", e);
+        tokio::select! {
+            Some(handled) = in_flight.join_next() => match handled {
+                Ok(outgoing) => send_outgoing(transport, outgoing).await,
+                Err(e) => tracing::error!("Message handler failed: {}", e),
+            },
+            // Wait for incoming message, unless the loop is full, in which
+            // case messages stay queued at the transport
+            received = transport.receive_timeout(std::time::Duration::from_secs(30)),
+                if in_flight.len() < MAX_CONCURRENT_REQUESTS =>
+            {
+                match received {
+                    Ok(Some(msg)) => {
+                        let handler = handler.clone();
+                        in_flight.spawn_blocking(move || handler.handle(&msg));
+                    }
+                    Ok(None) => {
+                        // Timeout, continue
+                        tracing::debug!("No messages received in timeout period");
+                    }
+                    Err(e) => {
+                        tracing::error!("Error receiving message: {}", e);
+                        // Brief sleep before retrying
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                     }
                 }
             }
-            Ok(None) => {
-                // Timeout, continue
-                tracing::debug!("No messages received in timeout period");
+        }
+    }
+}
+
+/// Send what handling a message produced
+async fn send_outgoing<T: Transport>(transport: &T, outgoing: Option<Outgoing>) {
+    match outgoing {
+        Some(Outgoing::Reply(sender_tag, response_bytes)) => {
+            if let Err(e) = transport.send_reply(&sender_tag, response_bytes).await {
+                tracing::error!("Failed to send reply: {}", e);
             }
-            Err(e) => {
-                tracing::error!("Error receiving message: {}", e);
-                // Brief sleep before retrying
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Some(Outgoing::Forward(seeder, request_bytes)) => {
+            if let Err(e) = transport.send(&seeder, request_bytes).await {
+                tracing::error!("Failed to relay request: {}", e);
             }
         }
+        None => {}
     }
 }
 
@@ -260,25 +953,310 @@ mod tests {
     use brisby_core::transport::mock::MockTransport;
     use tempfile::NamedTempFile;
 
-    fn setup_handler() -> (MessageHandler, NamedTempFile) {
+    fn setup_handler() -> (Arc<MessageHandler>, NamedTempFile) {
         let temp = NamedTempFile::new().unwrap();
         let index = SearchIndex::open(temp.path()).unwrap();
-        (MessageHandler::new(index), temp)
+        (Arc::new(MessageHandler::new(index)), temp)
+    }
+
+    /// A three-chunk file and the request publishing it
+    fn publishable_file() -> (Vec<u8>, brisby_core::FileMetadata, ReceivedMessage) {
+        use brisby_core::CHUNK_SIZE;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let path = temp_dir.path().join("test.bin");
+        std::fs::write(&path, &content).unwrap();
+        let (metadata, _) = brisby_core::chunk::chunk_file(&path).unwrap();
+
+        let request = proto::Envelope::new(
+            1,
+            proto::Payload::PublishRequest(proto::PublishRequest {
+                content_hash: metadata.content_hash.to_vec(),
+                filename: "test.bin".to_string(),
+                keywords: vec!["test".to_string()],
+                size: metadata.size,
+                chunk_count: metadata.chunks.len() as u32,
+                nym_address: "test-address".to_string(),
+                tags: vec![],
+                anonymous: false,
+                mime_type: String::new(),
+                chunk_size: metadata.chunk_size,
+                content_defined: None,
+            }),
+        );
+        let msg = ReceivedMessage::new(request.to_bytes(), Some(SenderTag::new(vec![0u8; 16])));
+        (content, metadata, msg)
+    }
+
+    fn reply(handler: &MessageHandler, envelope: Envelope) -> Envelope {
+        let msg = ReceivedMessage::new(envelope.to_bytes(), Some(SenderTag::new(vec![0u8; 16])));
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
+        Envelope::from_bytes(&response_bytes).unwrap()
     }
 
     #[test]
     fn test_handle_publish() {
         let (handler, _temp) = setup_handler();
+        let (content, metadata, msg) = publishable_file();
+
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+        let challenge = response.into_publish_challenge().expect("Expected PublishChallenge");
+        assert_eq!(challenge.content_hash, metadata.content_hash);
+        assert!(challenge.chunk_index < 3);
+        // Nothing is listed until the challenge is answered
+        assert_eq!(handler.index.stats().unwrap().entry_count, 0);
+
+        let index = challenge.chunk_index;
+        let proof = proto::PublishProof {
+            nonce: challenge.nonce,
+            data: content
+                .chunks(brisby_core::CHUNK_SIZE)
+                .nth(index as usize)
+                .unwrap()
+                .to_vec()
+                .into(),
+            proof: metadata
+                .merkle_proof(index)
+                .unwrap()
+                .iter()
+                .map(|hash| hash.to_vec())
+                .collect(),
+        };
+        let response = reply(&handler, Envelope::new(1, proto::Payload::PublishProof(proof)));
+
+        let resp = response.into_publish_response().expect("Expected PublishResponse");
+        assert!(resp.success);
+        assert_eq!(handler.index.stats().unwrap().entry_count, 1);
+        assert!(handler.challenges.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_publish_rejects_wrong_proof() {
+        let (handler, _temp) = setup_handler();
+        let (_, metadata, msg) = publishable_file();
+
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
+        let challenge = Envelope::from_bytes(&response_bytes)
+            .unwrap()
+            .into_publish_challenge()
+            .expect("Expected PublishChallenge");
+
+        // A publisher without the file can only make up the chunk
+        let index = challenge.chunk_index;
+        let forged = proto::PublishProof {
+            nonce: challenge.nonce.clone(),
+            data: vec![0u8; brisby_core::CHUNK_SIZE].into(),
+            proof: metadata
+                .merkle_proof(index)
+                .unwrap()
+                .iter()
+                .map(|hash| hash.to_vec())
+                .collect(),
+        };
+        let response = reply(&handler, Envelope::new(1, proto::Payload::PublishProof(forged)));
+        let resp = response.into_publish_response().expect("Expected PublishResponse");
+        assert!(!resp.success);
+        assert!(resp.error.contains("proof of possession"));
+        assert_eq!(handler.index.stats().unwrap().entry_count, 0);
+
+        // The challenge is used up, so it can't be retried with another guess
+        let retry = proto::PublishProof {
+            nonce: challenge.nonce,
+            ..Default::default()
+        };
+        let response = reply(&handler, Envelope::new(1, proto::Payload::PublishProof(retry)));
+        let err = response.into_error_response().expect("Expected ErrorResponse");
+        assert_eq!(err.code, error_codes::NOT_FOUND);
+    }
+
+    /// Publish `request` for `content`, answering the challenge
+    fn publish_with_proof(
+        handler: &MessageHandler,
+        content: &[u8],
+        metadata: &brisby_core::FileMetadata,
+        request: proto::PublishRequest,
+    ) -> proto::PublishResponse {
+        let challenge = reply(handler, Envelope::new(1, proto::Payload::PublishRequest(request)))
+            .into_publish_challenge()
+            .expect("Expected PublishChallenge");
+        let index = challenge.chunk_index;
+        let offset: usize =
+            metadata.chunks[..index as usize].iter().map(|chunk| chunk.size as usize).sum();
+        let size = metadata.chunks[index as usize].size as usize;
+        let proof = proto::PublishProof {
+            nonce: challenge.nonce,
+            data: content[offset..offset + size].to_vec().into(),
+            proof: metadata
+                .merkle_proof(index)
+                .unwrap()
+                .iter()
+                .map(|hash| hash.to_vec())
+                .collect(),
+        };
+        reply(handler, Envelope::new(1, proto::Payload::PublishProof(proof)))
+            .into_publish_response()
+            .expect("Expected PublishResponse")
+    }
+
+    #[test]
+    fn test_publish_rejects_conflicting_layout() {
+        let (handler, _temp) = setup_handler();
+        let (content, metadata, msg) = publishable_file();
+        let request = Envelope::from_bytes(&msg.data)
+            .unwrap()
+            .into_publish_request()
+            .unwrap();
+        assert!(publish_with_proof(&handler, &content, &metadata, request.clone()).success);
+
+        // A second publisher claims a size with the same chunk count, which
+        // is consistent on its own but not with the listing
+        let conflicting = proto::PublishRequest {
+            size: metadata.size - 50,
+            nym_address: "other-address".to_string(),
+            ..request
+        };
+        let response =
+            reply(&handler, Envelope::new(1, proto::Payload::PublishRequest(conflicting)));
+        let err = response.into_error_response().expect("Expected ErrorResponse");
+        assert_eq!(err.code, error_codes::INVALID_DATA);
+        assert!(err.message.contains("already listed"));
+
+        let results = handler
+            .index
+            .search("test", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].size, metadata.size);
+        assert_eq!(results[0].chunk_count, 3);
+        assert_eq!(results[0].seeder_count, 1);
+        assert_eq!(
+            handler.index.layout(&metadata.content_hash).unwrap(),
+            Some((metadata.size, 3))
+        );
+    }
+
+    #[test]
+    fn test_publish_with_other_chunk_size() {
+        let (handler, _temp) = setup_handler();
+        let (content, _, msg) = publishable_file();
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.bin");
+        std::fs::write(&path, &content).unwrap();
+        let (metadata, _) = brisby_core::chunk::chunk_file_with_size(&path, 100_000).unwrap();
+        assert_eq!(metadata.chunks.len(), 6);
+
+        let request = Envelope::from_bytes(&msg.data)
+            .unwrap()
+            .into_publish_request()
+            .unwrap();
+        let request = proto::PublishRequest {
+            content_hash: metadata.content_hash.to_vec(),
+            chunk_count: 6,
+            chunk_size: 100_000,
+            ..request
+        };
+        assert!(publish_with_proof(&handler, &content, &metadata, request.clone()).success);
+        let results = handler
+            .index
+            .search("test", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+            .unwrap();
+        assert_eq!(results[0].chunk_size, 100_000);
+
+        // The chunk count still has to match the chunk size
+        let response = reply(
+            &handler,
+            Envelope::new(
+                1,
+                proto::Payload::PublishRequest(proto::PublishRequest { chunk_size: 0, ..request }),
+            ),
+        );
+        let err = response.into_error_response().expect("Expected ErrorResponse");
+        assert!(err.message.contains("chunk_count"));
+    }
+
+    #[test]
+    fn test_publish_content_defined() {
+        let (handler, _temp) = setup_handler();
+        let (_, _, msg) = publishable_file();
+        // Content that doesn't repeat, so boundaries fall at varying places
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let content: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.bin");
+        std::fs::write(&path, &content).unwrap();
+        let strategy = ChunkingStrategy::ContentDefined {
+            min_size: 1024,
+            avg_size: 8192,
+            max_size: 65536,
+        };
+        let (metadata, _) = brisby_core::chunk::chunk_file_with_strategy(&path, strategy).unwrap();
+        // Nothing like the count fixed-size chunks would give
+        assert_ne!(metadata.chunks.len() as u64, content.len().div_ceil(65536) as u64);
+
+        let request = Envelope::from_bytes(&msg.data)
+            .unwrap()
+            .into_publish_request()
+            .unwrap();
+        let request = proto::PublishRequest {
+            content_hash: metadata.content_hash.to_vec(),
+            size: metadata.size,
+            chunk_count: metadata.chunks.len() as u32,
+            chunk_size: metadata.chunk_size,
+            content_defined: metadata.content_defined.map(Into::into),
+            ..request
+        };
+        assert!(publish_with_proof(&handler, &content, &metadata, request.clone()).success);
+        let results = handler
+            .index
+            .search("test", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+            .unwrap();
+        assert_eq!(results[0].chunk_size, 65536);
+        assert_eq!(results[0].content_defined, metadata.content_defined);
+
+        // More chunks than the smallest chunk size allows
+        let too_many = proto::PublishRequest {
+            chunk_count: content.len().div_ceil(1024) as u32 + 1,
+            ..request
+        };
+        let response =
+            reply(&handler, Envelope::new(1, proto::Payload::PublishRequest(too_many)));
+        let err = response.into_error_response().expect("Expected ErrorResponse");
+        assert!(err.message.contains("chunk_count"));
+    }
+
+    #[test]
+    fn test_publish_rejects_mismatched_chunk_count() {
+        let (handler, _temp) = setup_handler();
 
         let request = proto::Envelope::new(
             1,
             proto::Payload::PublishRequest(proto::PublishRequest {
                 content_hash: vec![1u8; 32],
                 filename: "test.txt".to_string(),
-                keywords: vec!["test".to_string()],
+                keywords: vec![],
                 size: 1024,
-                chunk_count: 1,
+                chunk_count: 5,
                 nym_address: "test-address".to_string(),
+                tags: vec![],
+                anonymous: false,
+                mime_type: String::new(),
+                chunk_size: 0,
+                content_defined: None,
             }),
         );
 
@@ -287,15 +1265,15 @@ mod tests {
             Some(SenderTag::new(vec![0u8; 16])),
         );
 
-        let (_, response_bytes) = handler.handle(&msg).unwrap();
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
         let response = Envelope::from_bytes(&response_bytes).unwrap();
 
-        match response.payload {
-            Some(Payload::PublishResponse(resp)) => {
-                assert!(resp.success);
-            }
-            _ => panic!("Expected PublishResponse"),
-        }
+        let err = response.into_error_response().expect("Expected ErrorResponse");
+        assert_eq!(err.code, error_codes::INVALID_DATA);
+        assert!(err.message.contains("chunk_count"));
+        assert_eq!(handler.index.stats().unwrap().entry_count, 0);
     }
 
     #[test]
@@ -307,8 +1285,12 @@ mod tests {
             content_hash: [1u8; 32],
             filename: "movie.mkv".to_string(),
             keywords: vec!["action".to_string(), "movie".to_string()],
+            tags: vec![],
             size: 1024 * 1024 * 100,
             chunk_count: 400,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
             published_at: 1000,
             ttl: 3600,
         };
@@ -320,6 +1302,9 @@ mod tests {
             proto::Payload::SearchRequest(proto::SearchRequest {
                 query: "movie".to_string(),
                 max_results: 10,
+                min_relevance: 0.0,
+                offset: 0,
+                ..Default::default()
             }),
         );
 
@@ -328,18 +1313,432 @@ mod tests {
             Some(SenderTag::new(vec![0u8; 16])),
         );
 
-        let (_, response_bytes) = handler.handle(&msg).unwrap();
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
         let response = Envelope::from_bytes(&response_bytes).unwrap();
 
-        match response.payload {
-            Some(Payload::SearchResponse(resp)) => {
-                assert_eq!(resp.results.len(), 1);
-                assert_eq!(resp.results[0].filename, "movie.mkv");
+        let resp = response.into_search_response().expect("Expected SearchResponse");
+        assert_eq!(resp.results.len(), 1);
+        assert_eq!(resp.results[0].filename, "movie.mkv");
+        assert_eq!(resp.total_results, 1);
+
+        // Advanced queries reach FTS5 as written, and malformed ones are the
+        // searcher's mistake rather than an index failure
+        let queries = [
+            ("movie OR drama", None),
+            ("movie AND", Some(error_codes::INVALID_DATA)),
+        ];
+        for (query, code) in queries {
+            let request = proto::Envelope::new(
+                4,
+                proto::Payload::SearchRequest(proto::SearchRequest {
+                    query: query.to_string(),
+                    advanced: true,
+                    ..Default::default()
+                }),
+            );
+            let msg = ReceivedMessage::new(
+                request.to_bytes(),
+                Some(SenderTag::new(vec![0u8; 16])),
+            );
+            let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+                panic!("expected a reply");
+            };
+            let response = Envelope::from_bytes(&response_bytes).unwrap();
+            match code {
+                None => assert_eq!(response.into_search_response().unwrap().results.len(), 1),
+                Some(code) => assert_eq!(response.into_error_response().unwrap().code, code),
             }
-            _ => panic!("Expected SearchResponse"),
         }
     }
 
+    #[test]
+    fn test_handle_find_value() {
+        let (handler, _temp) = setup_handler();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let entry = IndexEntry {
+            content_hash: [1u8; 32],
+            filename: "movie.mkv".to_string(),
+            keywords: vec!["movie".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: now,
+            ttl: 3600,
+        };
+        handler.index.upsert(&entry, "public-seeder").unwrap();
+        let token = handler
+            .index
+            .upsert_anonymous(&entry, "private-seeder", &[7u8; RELAY_TOKEN_LEN])
+            .unwrap();
+
+        let lookup = |key: Vec<u8>| {
+            let request = Envelope::new(5, Payload::FindValueRequest(FindValueRequest { key }));
+            let msg = ReceivedMessage::new(
+                request.to_bytes(),
+                Some(SenderTag::new(vec![0u8; 16])),
+            );
+            let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+                panic!("expected a reply");
+            };
+            Envelope::from_bytes(&response_bytes).unwrap()
+        };
+
+        let resp = lookup(vec![1u8; 32]).into_find_value_response().unwrap();
+        assert_eq!(resp.seeders.len(), 2);
+        let public = resp.seeders.iter().find(|s| s.relay_token.is_empty()).unwrap();
+        assert_eq!(public.nym_address, "public-seeder");
+        assert_eq!((public.last_seen, public.expires_at), (now, now + 3600));
+        // The anonymous seeder's address never leaves the index
+        let anonymous = resp.seeders.iter().find(|s| !s.relay_token.is_empty()).unwrap();
+        assert_eq!(anonymous.relay_token, token);
+        assert!(anonymous.nym_address.is_empty());
+
+        let resp = lookup(vec![2u8; 32]).into_find_value_response().unwrap();
+        assert!(resp.seeders.is_empty());
+        let err = lookup(vec![1u8; 5]).into_error_response().unwrap();
+        assert_eq!(err.code, error_codes::INVALID_DATA);
+    }
+
+    #[test]
+    fn test_liveness_probes_remove_silent_seeders() {
+        let (handler, _temp) = setup_handler();
+        let entry = |byte: u8| IndexEntry {
+            content_hash: [byte; 32],
+            filename: format!("file-{}.bin", byte),
+            keywords: vec!["file".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 5000,
+            ttl: 3600,
+        };
+        handler.index.upsert(&entry(1), "online").unwrap();
+        handler.index.upsert(&entry(1), "offline").unwrap();
+        handler.index.upsert(&entry(2), "offline").unwrap();
+
+        // One ping per seeder, and none while one is still unanswered
+        let pings = handler.start_probes(0, 10);
+        let addresses: Vec<&str> = pings.iter().map(|(to, _)| to.as_str()).collect();
+        assert_eq!(addresses, vec!["offline", "online"]);
+        assert!(handler.start_probes(0, 10).is_empty());
+
+        // Answers under guessed request IDs don't count
+        for guess in 0..8 {
+            let forged = Envelope::new(
+                guess,
+                Payload::PingResponse(proto::PingResponse {
+                    responder_id: vec![],
+                }),
+            );
+            assert!(handler.handle(&ReceivedMessage::new(forged.to_bytes(), None)).is_none());
+        }
+
+        // The online seeder answers over its SURBs
+        let ping = Envelope::from_bytes(&pings[1].1).unwrap();
+        assert!(ping.as_ping_request().is_some());
+        let pong = Envelope::new(
+            ping.request_id,
+            Payload::PingResponse(proto::PingResponse {
+                responder_id: vec![],
+            }),
+        );
+        assert!(handler.handle(&ReceivedMessage::new(pong.to_bytes(), None)).is_none());
+
+        // Nothing is removed before the timeout, then all of the silent
+        // seeder's listings are
+        assert_eq!(handler.expire_probes(Duration::from_secs(60)), 0);
+        assert_eq!(handler.expire_probes(Duration::ZERO), 2);
+        let listed = |byte: u8| -> Vec<Option<String>> {
+            handler
+                .index
+                .seeders(&[byte; 32], 5000)
+                .unwrap()
+                .into_iter()
+                .map(|seeder| seeder.nym_address)
+                .collect()
+        };
+        assert_eq!(listed(1), vec![Some("online".to_string())]);
+        assert!(listed(2).is_empty());
+        assert_eq!(handler.index.stats().unwrap().entry_count, 1);
+    }
+
+    #[test]
+    fn test_handle_capabilities() {
+        let (handler, _temp) = setup_handler();
+
+        let request = proto::Envelope::new(
+            3,
+            proto::Payload::CapabilitiesRequest(proto::CapabilitiesRequest {}),
+        );
+        let msg = ReceivedMessage::new(
+            request.to_bytes(),
+            Some(SenderTag::new(vec![0u8; 16])),
+        );
+
+        let Some(Outgoing::Reply(_, response_bytes)) = handler.handle(&msg) else {
+            panic!("expected a reply");
+        };
+        let response = Envelope::from_bytes(&response_bytes).unwrap();
+        assert_eq!(response.request_id, 3);
+
+        let caps = response
+            .into_capabilities_response()
+            .expect("Expected CapabilitiesResponse");
+        assert_eq!(caps.protocol_version, PROTOCOL_VERSION as u32);
+        assert!(caps.supports(capabilities::SEARCH));
+        assert!(caps.supports(capabilities::PUBLISH));
+        assert!(caps.supports(capabilities::MIN_RELEVANCE));
+        assert!(caps.supports(capabilities::UNPUBLISH));
+        assert!(caps.supports(capabilities::PUBLISH_PROOF));
+        assert!(caps.supports(capabilities::PAGINATION));
+        assert!(caps.supports(capabilities::SEARCH_FILTERS));
+        assert!(caps.supports(capabilities::ADVANCED_QUERY));
+        assert!(caps.supports(capabilities::FIND_VALUE));
+        assert!(!caps.supports(1 << 63));
+    }
+
+    #[tokio::test]
+    async fn test_unpublish_removes_seeder() {
+        use brisby_client::network::{publish_to_index_provider, unpublish_from_index_provider};
+        use brisby_core::transport::mock::MockNetwork;
+
+        let index_address = NymAddress::new("index.mock");
+        let (handler, _temp) = setup_handler();
+        let network = MockNetwork::new();
+        let mut index_transport = network.transport(index_address.clone());
+        index_transport.connect().await.unwrap();
+        let index_loop = run_message_loop(&index_transport, &handler);
+        tokio::pin!(index_loop);
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("mirrored.txt");
+        std::fs::write(&path, b"mirrored content").unwrap();
+        let (metadata, _) = brisby_core::chunk::chunk_file(&path).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let mut seeders = Vec::new();
+        for address in ["first.mock", "second.mock"] {
+            let mut transport = network.transport(address);
+            transport.connect().await.unwrap();
+            let our_address = NymAddress::new(address);
+            let token = tokio::select! {
+                result = publish_to_index_provider(
+                    &transport, &index_address, &metadata, &our_address, false,
+                    |_| std::fs::read(&path).ok(),
+                ) => result.unwrap(),
+                result = &mut index_loop => panic!("index loop exited: {:?}", result),
+            };
+            assert_eq!(token.len(), UNPUBLISH_TOKEN_LEN);
+            seeders.push((transport, our_address, token));
+        }
+
+        // Someone who knows a seeder's address but not its token can't
+        // unlist it
+        let hash = metadata.content_hash;
+        let mut forger = network.transport("forger.mock");
+        forger.connect().await.unwrap();
+        for token in [vec![], vec![0u8; UNPUBLISH_TOKEN_LEN], seeders[1].2.clone()] {
+            tokio::select! {
+                result = unpublish_from_index_provider(
+                    &forger, &index_address, &hash, &seeders[0].1, &token, timeout,
+                ) => assert!(result.unwrap_err().to_string().contains("not published")),
+                result = &mut index_loop => panic!("index loop exited: {:?}", result),
+            }
+        }
+        let results = handler
+            .index
+            .search("mirrored", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+            .unwrap();
+        assert_eq!(results[0].seeder_count, 2);
+
+        for (i, (transport, our_address, token)) in seeders.iter().enumerate() {
+            tokio::select! {
+                result = unpublish_from_index_provider(
+                    transport, &index_address, &hash, our_address, token, timeout,
+                ) => result.unwrap(),
+                result = &mut index_loop => panic!("index loop exited: {:?}", result),
+            }
+            let results = handler
+                .index
+                .search("mirrored", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+                .unwrap();
+            if i == 0 {
+                assert_eq!(results[0].seeders, vec!["second.mock"]);
+            } else {
+                assert!(results.is_empty());
+            }
+        }
+
+        // Unpublishing again is refused, since nothing is listed
+        let (transport, our_address, token) = &seeders[0];
+        tokio::select! {
+            result = unpublish_from_index_provider(
+                transport, &index_address, &hash, our_address, token, timeout,
+            ) => assert!(result.unwrap_err().to_string().contains("not published")),
+            result = &mut index_loop => panic!("index loop exited: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_file_stored_with_flat_hash() {
+        use brisby_client::metadata_file::{self, MetadataFormat};
+        use brisby_client::network::publish_to_index_provider;
+        use brisby_client::seeder::ChunkStore;
+        use brisby_core::transport::mock::MockNetwork;
+        use brisby_core::{FileMetadata, CHUNK_SIZE};
+
+        // Store a file the way older seeders did, under a whole-file hash
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage_dir = temp_dir.path().join("chunks");
+        let path = temp_dir.path().join("legacy.bin");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let merkle = ChunkStore::new(storage_dir.clone()).add_file(&path).unwrap();
+        let legacy = FileMetadata {
+            content_hash: HashAlgorithm::Blake3.hash(&content),
+            hash_algo: HashAlgorithm::Blake3,
+            ..merkle.clone()
+        };
+        let merkle_dir = storage_dir.join(brisby_core::hash_to_hex(&merkle.content_hash));
+        let legacy_dir = storage_dir.join(brisby_core::hash_to_hex(&legacy.content_hash));
+        std::fs::rename(merkle_dir, &legacy_dir).unwrap();
+        metadata_file::write(&legacy_dir, &legacy, MetadataFormat::Json).unwrap();
+
+        let index_address = NymAddress::new("index.mock");
+        let (handler, _temp) = setup_handler();
+        let network = MockNetwork::new();
+        let mut index_transport = network.transport(index_address.clone());
+        index_transport.connect().await.unwrap();
+        let index_loop = run_message_loop(&index_transport, &handler);
+        tokio::pin!(index_loop);
+        let mut transport = network.transport("seeder.mock");
+        transport.connect().await.unwrap();
+        let our_address = NymAddress::new("seeder.mock");
+
+        // The flat hash can't be proven, so it is refused without asking
+        let err = publish_to_index_provider(
+            &transport, &index_address, &legacy, &our_address, false, |_| None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<brisby_client::network::PublishRejected>());
+        assert!(err.to_string().contains("flat content hash"), "{}", err);
+
+        // Loading the store moves it to its Merkle hash, which publishes
+        let mut store = ChunkStore::new(storage_dir);
+        assert_eq!(store.load_all().unwrap(), 1);
+        let metadata = store.get_metadata(&merkle.content_hash).unwrap().clone();
+        assert_eq!(metadata.hash_algo, HashAlgorithm::Blake3Merkle);
+        tokio::select! {
+            result = publish_to_index_provider(
+                &transport, &index_address, &metadata, &our_address, false,
+                |index| store.read_chunk(&metadata.content_hash, index),
+            ) => { result.unwrap(); }
+            result = &mut index_loop => panic!("index loop exited: {:?}", result),
+        }
+
+        let results = handler
+            .index
+            .search("legacy", QueryMode::Keywords, 10, 0, 0.0, &Default::default())
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].content_hash, merkle.content_hash);
+    }
+
+    #[tokio::test]
+    async fn test_relayed_download_hides_seeder_address() {
+        use brisby_client::downloader::Downloader;
+        use brisby_client::network::{publish_to_index_provider, search_index_provider};
+        use brisby_client::seeder::{run_seeder_loop, ChunkStore, Seeder};
+        use brisby_core::transport::mock::MockNetwork;
+        use brisby_core::{SeederRoute, CHUNK_SIZE};
+
+        const SEEDER_ADDRESS: &str = "seeder.mock";
+        let index_address = NymAddress::new("index.mock");
+
+        let (handler, _temp) = setup_handler();
+        let network = MockNetwork::new();
+        let mut index_transport = network.transport(index_address.clone());
+        let mut seeder_transport = network.transport(SEEDER_ADDRESS);
+        let mut downloader_transport = network.transport("downloader.mock");
+        index_transport.connect().await.unwrap();
+        seeder_transport.connect().await.unwrap();
+        downloader_transport.connect().await.unwrap();
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let path = temp_dir.path().join("whistleblower.bin");
+        std::fs::write(&path, &content).unwrap();
+        let mut store = ChunkStore::new(temp_dir.path().join("chunks"));
+        let metadata = store.add_file(&path).unwrap();
+        let seeder = Arc::new(Seeder::new(store));
+
+        let index_loop = run_message_loop(&index_transport, &handler);
+        tokio::pin!(index_loop);
+
+        // The seeder publishes anonymously
+        let our_address = NymAddress::new(SEEDER_ADDRESS);
+        tokio::select! {
+            result = publish_to_index_provider(
+                &seeder_transport, &index_address, &metadata, &our_address, true,
+                |index| content.chunks(CHUNK_SIZE).nth(index as usize).map(<[u8]>::to_vec),
+            ) => { result.unwrap(); }
+            result = &mut index_loop => panic!("index loop exited: {:?}", result),
+        }
+
+        // Searching yields a relay route through the index, not the address
+        let no_filter = Default::default();
+        let results = tokio::select! {
+            results = search_index_provider(
+                &downloader_transport, &index_address, "whistleblower", QueryMode::Keywords,
+                10, 0, 0.0, &no_filter,
+            ) => results.unwrap().results,
+            result = &mut index_loop => panic!("index loop exited: {:?}", result),
+        };
+        assert_eq!(results.len(), 1);
+        let seeders = &results[0].seeders;
+        assert_eq!(seeders.len(), 1);
+        assert!(!seeders[0].contains(SEEDER_ADDRESS));
+        assert!(matches!(
+            SeederRoute::parse(&seeders[0]),
+            SeederRoute::Relay { ref index_provider, .. } if *index_provider == index_address
+        ));
+
+        let seeders: Vec<NymAddress> = seeders.iter().map(NymAddress::new).collect();
+        let downloader = Downloader::new(&downloader_transport);
+        let chunks = tokio::select! {
+            chunks = downloader.download_parallel(&metadata, &seeders, 2, |_, _| {}) => {
+                chunks.unwrap()
+            }
+            result = &mut index_loop => panic!("index loop exited: {:?}", result),
+            result = run_seeder_loop(&seeder_transport, seeder.clone()) => {
+                panic!("seeder loop exited: {:?}", result)
+            }
+        };
+
+        let output = temp_dir.path().join("downloaded.bin");
+        downloader
+            .reassemble_to_file(chunks, &metadata, &output)
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+
+        // Every request went through the index provider
+        let sent = downloader_transport.get_sent_messages();
+        assert!(sent.iter().all(|(recipient, _)| *recipient == index_address));
+        assert!(handler.relays.lock().unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_message_loop_with_mock() {
         let (handler, _temp) = setup_handler();
@@ -352,6 +1751,9 @@ mod tests {
             proto::Payload::SearchRequest(proto::SearchRequest {
                 query: "test".to_string(),
                 max_results: 10,
+                min_relevance: 0.0,
+                offset: 0,
+                ..Default::default()
             }),
         );
         transport.queue_message(ReceivedMessage::new(
@@ -373,4 +1775,49 @@ mod tests {
         let replies = transport.get_sent_replies();
         assert_eq!(replies.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_message_loop_replies_to_every_queued_request() {
+        let (handler, _temp) = setup_handler();
+        let mut transport = MockTransport::new();
+        transport.connect().await.unwrap();
+
+        // More requests than are handled at once, each from its own sender
+        let count = MAX_CONCURRENT_REQUESTS as u64 + 8;
+        for request_id in 1..=count {
+            let request = proto::Envelope::new(
+                request_id,
+                proto::Payload::SearchRequest(proto::SearchRequest {
+                    query: format!("query{}", request_id),
+                    max_results: 10,
+                    ..Default::default()
+                }),
+            );
+            transport.queue_message(ReceivedMessage::new(
+                request.to_bytes(),
+                Some(SenderTag::new(request_id.to_le_bytes().to_vec())),
+            ));
+        }
+
+        let _ = tokio::time::timeout(
+            Duration::from_millis(500),
+            run_message_loop(&transport, &handler),
+        )
+        .await;
+
+        // Every reply went to the sender of the request it answers
+        let replies = transport.get_sent_replies();
+        assert_eq!(replies.len(), count as usize);
+        let mut answered: Vec<u64> = replies
+            .iter()
+            .map(|(tag, data)| {
+                let response = Envelope::from_bytes(data).unwrap();
+                assert_eq!(tag.as_bytes(), response.request_id.to_le_bytes());
+                assert!(matches!(response.payload, Some(Payload::SearchResponse(_))));
+                response.request_id
+            })
+            .collect();
+        answered.sort();
+        assert_eq!(answered, (1..=count).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,177 @@
+//! Local health/readiness endpoint for the index provider
+//!
+//! Serves a small JSON status document over plain HTTP so supervisors and
+//! container runtimes can tell whether the provider is connected and serving.
+//! This is purely operational and never touches the Brisby protocol.
+
+use crate::search::SearchIndex;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Shared state reported by the health endpoint
+pub struct HealthState {
+    /// Whether the transport is connected to the network
+    connected: AtomicBool,
+    /// When the provider started
+    started_at: Instant,
+    /// Path to the search index, opened per request for the entry count
+    index_path: PathBuf,
+}
+
+/// JSON body returned by the health endpoint
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub connected: bool,
+    pub entry_count: Option<u64>,
+    pub uptime_secs: u64,
+}
+
+impl HealthState {
+    /// Create health state for the index at `index_path`
+    pub fn new(index_path: PathBuf) -> Self {
+        Self {
+            connected: AtomicBool::new(false),
+            started_at: Instant::now(),
+            index_path,
+        }
+    }
+
+    /// Record whether the transport is connected
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Build the current health report
+    pub fn report(&self) -> HealthReport {
+        let connected = self.connected.load(Ordering::SeqCst);
+        let entry_count = SearchIndex::open_secondary(&self.index_path)
+            .and_then(|index| index.stats())
+            .map(|stats| stats.entry_count)
+            .ok();
+
+        HealthReport {
+            status: if connected { "ready" } else { "not_ready" },
+            connected,
+            entry_count,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Bind the health endpoint, refusing anything but a loopback address
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("health endpoint must bind to localhost, got {}", addr),
+        ));
+    }
+    TcpListener::bind(addr).await
+}
+
+/// Serve health reports until the task is cancelled
+pub async fn serve(listener: TcpListener, state: Arc<HealthState>) {
+    if let Ok(addr) = listener.local_addr() {
+        tracing::info!("Health endpoint listening on http://{}", addr);
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &state).await {
+                        tracing::debug!("Health request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::error!("Health endpoint accept failed: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Answer a single request with the current report
+///
+/// Any request path gets the same document; a 503 status is used while the
+/// provider is not connected so plain HTTP probes work without parsing.
+async fn respond(mut stream: TcpStream, state: &HealthState) -> std::io::Result<()> {
+    // Read (and ignore) the request head
+    let mut buf = [0u8; 1024];
+    let _ = stream.read(&mut buf).await?;
+
+    let report = state.report();
+    let body = serde_json::to_string(&report).unwrap_or_default();
+    let status_line = if report.connected {
+        "HTTP/1.1 200 OK"
+    } else {
+        "HTTP/1.1 503 Service Unavailable"
+    };
+
+    let response = format!(
+        "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    async fn fetch(addr: SocketAddr) -> (String, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoint_reports_state() {
+        let temp = NamedTempFile::new().unwrap();
+        SearchIndex::open(temp.path()).unwrap();
+
+        let state = Arc::new(HealthState::new(temp.path().to_path_buf()));
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, state.clone()));
+
+        let (head, json) = fetch(addr).await;
+        assert!(head.starts_with("HTTP/1.1 503"));
+        assert_eq!(json["status"], "not_ready");
+        assert_eq!(json["connected"], false);
+        assert_eq!(json["entry_count"], 0);
+        assert!(json["uptime_secs"].is_u64());
+
+        state.set_connected(true);
+
+        let (head, json) = fetch(addr).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(json["status"], "ready");
+        assert_eq!(json["connected"], true);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_bind_rejects_non_loopback() {
+        assert!(bind("0.0.0.0:0".parse().unwrap()).await.is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Pruning seeders that have gone offline
+//!
+//! Listings otherwise only go once their TTL runs out, so search results can
+//! name seeders that went offline hours ago. Every round, the probe task pings
+//! a few seeders picked at random among those that published recently, one
+//! ping per seeder whatever the number of files it lists, and stops listing
+//! any seeder that hasn't answered an earlier round's ping within the timeout.
+//!
+//! Rounds are at least `MIN_PROBE_INTERVAL` apart and ping at most
+//! `MAX_PROBES_PER_ROUND` seeders, whatever is configured, so probing can't
+//! flood the mixnet.
+
+use brisby_core::Transport;
+use std::time::Duration;
+
+use crate::handler::MessageHandler;
+
+/// Default time between probe rounds
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Shortest time allowed between probe rounds
+pub const MIN_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default number of seeders pinged per round
+pub const DEFAULT_PROBES_PER_ROUND: usize = 10;
+
+/// Most seeders pinged per round
+pub const MAX_PROBES_PER_ROUND: usize = 100;
+
+/// Default time a seeder has to answer a ping
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Only seeders that published within this long are pinged
+pub const DEFAULT_PROBE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often and how widely seeders are pinged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeConfig {
+    /// Time between rounds
+    pub interval: Duration,
+    /// Seeders pinged per round
+    pub seeders_per_round: usize,
+    /// Time a seeder has to answer before it's removed; checked at the start
+    /// of each round
+    pub timeout: Duration,
+    /// Only seeders that published within this long are pinged
+    pub window: Duration,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_PROBE_INTERVAL,
+            seeders_per_round: DEFAULT_PROBES_PER_ROUND,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+            window: DEFAULT_PROBE_WINDOW,
+        }
+    }
+}
+
+impl ProbeConfig {
+    /// This config with the rate held within the limits
+    pub fn bounded(self) -> Self {
+        Self {
+            interval: self.interval.max(MIN_PROBE_INTERVAL),
+            seeders_per_round: self.seeders_per_round.min(MAX_PROBES_PER_ROUND),
+            ..self
+        }
+    }
+}
+
+/// Run probe rounds forever, sending pings over `transport`
+///
+/// The replies arrive through the message loop, which hands them to
+/// `handler`.
+pub async fn run_probe_task<T: Transport>(
+    transport: &T,
+    handler: &MessageHandler,
+    config: ProbeConfig,
+) {
+    let config = config.bounded();
+    tracing::info!(
+        "Starting seeder liveness probes ({} seeders every {:?})",
+        config.seeders_per_round,
+        config.interval
+    );
+
+    loop {
+        tokio::time::sleep(config.interval).await;
+
+        let removed = handler.expire_probes(config.timeout);
+        if removed > 0 {
+            tracing::info!("Liveness probes: removed {} listings", removed);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let since = now.saturating_sub(config.window.as_secs());
+        for (seeder, ping) in handler.start_probes(since, config.seeders_per_round) {
+            if let Err(e) = transport.send(&seeder, ping).await {
+                tracing::debug!("Failed to ping {}: {}", seeder, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_rate_is_bounded() {
+        let eager = ProbeConfig {
+            interval: Duration::from_millis(1),
+            seeders_per_round: 100_000,
+            ..ProbeConfig::default()
+        };
+        let bounded = eager.bounded();
+        assert_eq!(bounded.interval, MIN_PROBE_INTERVAL);
+        assert_eq!(bounded.seeders_per_round, MAX_PROBES_PER_ROUND);
+        assert_eq!(bounded.timeout, DEFAULT_PROBE_TIMEOUT);
+        assert_eq!(ProbeConfig::default().bounded(), ProbeConfig::default());
+    }
+}
@@ -3,15 +3,20 @@
 use anyhow::Result;
 use brisby_core::Transport;
 use clap::Parser;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 mod handler;
+mod health;
+mod liveness;
 mod search;
 
 use handler::MessageHandler;
-use search::SearchIndex;
+use liveness::ProbeConfig;
+use search::{EvictionPolicy, IndexLimit, SearchIndex};
 
 /// Cleanup interval for expired entries (1 hour)
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
@@ -24,6 +29,12 @@ struct Cli {
     #[arg(short, long, default_value = ".brisby/index")]
     data_dir: PathBuf,
 
+    /// Path to the Nym identity directory, kept separate from the data
+    /// directory (default: <config dir>/brisby-index/identity, or
+    /// <data dir>/nym if an identity is already there)
+    #[arg(long)]
+    identity_dir: Option<PathBuf>,
+
     /// Enable verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -31,6 +42,45 @@ struct Cli {
     /// Use mock transport instead of real Nym (for testing)
     #[arg(long)]
     mock: bool,
+
+    /// Serve a JSON health/readiness endpoint on this localhost address
+    /// (e.g. 127.0.0.1:9090)
+    #[arg(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// Rows deleted per transaction when removing expired entries; smaller
+    /// batches keep queries responsive during cleanup of a large index
+    #[arg(long, default_value_t = search::DEFAULT_CLEANUP_BATCH_SIZE)]
+    cleanup_batch_size: usize,
+
+    /// Seconds a seeder stays listed, marked stale, after its TTL runs out,
+    /// so one that briefly goes offline isn't dropped from results (0 removes
+    /// it right away)
+    #[arg(long, default_value_t = search::DEFAULT_EXPIRY_GRACE_SECS)]
+    expiry_grace: u64,
+
+    /// Most files to keep in the index; beyond this, entries are evicted as
+    /// new files are published (0 for no limit)
+    #[arg(long, default_value = "0")]
+    max_entries: u64,
+
+    /// Which entries to evict first when over --max-entries:
+    /// oldest-published or fewest-seeders
+    #[arg(long, default_value_t = EvictionPolicy::default())]
+    eviction_policy: EvictionPolicy,
+
+    /// Seconds between rounds of pinging seeders to drop offline ones (0
+    /// disables; at least 10)
+    #[arg(long, default_value_t = liveness::DEFAULT_PROBE_INTERVAL.as_secs())]
+    probe_interval: u64,
+
+    /// Seeders pinged per round (at most 100)
+    #[arg(long, default_value_t = liveness::DEFAULT_PROBES_PER_ROUND)]
+    probes_per_round: usize,
+
+    /// Seconds a seeder has to answer a ping before it's no longer listed
+    #[arg(long, default_value_t = liveness::DEFAULT_PROBE_TIMEOUT.as_secs())]
+    probe_timeout: u64,
 }
 
 #[tokio::main]
@@ -57,9 +107,21 @@ async fn main() -> Result<()> {
 
     // Initialize search index
     let index_path = cli.data_dir.join("index.db");
-    let index = SearchIndex::open(&index_path)?;
+    let limit = (cli.max_entries > 0).then_some(IndexLimit {
+        max_entries: cli.max_entries,
+        policy: cli.eviction_policy,
+    });
+    let index = SearchIndex::open(&index_path)?
+        .with_limit(limit)
+        .with_expiry_grace(cli.expiry_grace);
     tracing::info!("Opened search index at {:?}", index_path);
 
+    // The cap may have been lowered since the last run
+    let evicted = index.evict_over_limit(cli.cleanup_batch_size)?;
+    if evicted > 0 {
+        tracing::info!("Evicted {} entries over the {} entry cap", evicted, cli.max_entries);
+    }
+
     // Show index stats
     if let Ok(stats) = index.stats() {
         tracing::info!(
@@ -70,14 +132,32 @@ async fn main() -> Result<()> {
     }
 
     // Create message handler
-    let handler = MessageHandler::new(index);
+    let handler = Arc::new(MessageHandler::new(index));
+    let probe_config = (cli.probe_interval > 0).then(|| ProbeConfig {
+        interval: Duration::from_secs(cli.probe_interval),
+        seeders_per_round: cli.probes_per_round,
+        timeout: Duration::from_secs(cli.probe_timeout),
+        ..ProbeConfig::default()
+    });
 
     // Spawn cleanup task
     let cleanup_index_path = index_path.clone();
+    let cleanup_batch_size = cli.cleanup_batch_size;
+    let expiry_grace = cli.expiry_grace;
     let cleanup_handle = tokio::spawn(async move {
-        run_cleanup_task(&cleanup_index_path).await;
+        run_cleanup_task(&cleanup_index_path, cleanup_batch_size, expiry_grace).await;
     });
 
+    // Spawn health endpoint if requested
+    let health_state = Arc::new(health::HealthState::new(index_path.clone()));
+    let health_handle = match cli.health_addr {
+        Some(addr) => {
+            let listener = health::bind(addr).await?;
+            Some(tokio::spawn(health::serve(listener, health_state.clone())))
+        }
+        None => None,
+    };
+
     if cli.mock {
         // Use mock transport for testing
         tracing::info!("Using mock transport (test mode)");
@@ -85,6 +165,7 @@ async fn main() -> Result<()> {
         transport.connect().await?;
         tracing::info!("Mock transport connected");
         tracing::info!("Address: {}", transport.our_address().unwrap());
+        health_state.set_connected(true);
 
         // Run message loop with ctrl-c handler
         tokio::select! {
@@ -93,6 +174,7 @@ async fn main() -> Result<()> {
                     tracing::error!("Message loop error: {}", e);
                 }
             }
+            _ = run_probes(&transport, &handler, probe_config) => {}
             _ = tokio::signal::ctrl_c() => {
                 tracing::info!("Received shutdown signal");
             }
@@ -102,13 +184,16 @@ async fn main() -> Result<()> {
         #[cfg(feature = "nym")]
         {
             use brisby_core::NymTransport;
-            let storage_path = cli.data_dir.join("nym");
-            let mut transport = NymTransport::with_storage(storage_path);
+            let identity_dir = identity_dir(&cli)?;
+            std::fs::create_dir_all(&identity_dir)?;
+            tracing::info!("Using Nym identity at {:?}", identity_dir);
+            let mut transport = NymTransport::with_storage(identity_dir);
 
             tracing::info!("Connecting to Nym network...");
             transport.connect().await?;
             tracing::info!("Connected to Nym network");
             tracing::info!("Address: {}", transport.our_address().unwrap());
+            health_state.set_connected(true);
 
             // Run message loop with ctrl-c handler
             tokio::select! {
@@ -117,11 +202,13 @@ async fn main() -> Result<()> {
                         tracing::error!("Message loop error: {}", e);
                     }
                 }
+                _ = run_probes(&transport, &handler, probe_config) => {}
                 _ = tokio::signal::ctrl_c() => {
                     tracing::info!("Received shutdown signal");
                 }
             }
 
+            health_state.set_connected(false);
             transport.disconnect().await?;
         }
 
@@ -134,29 +221,69 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Cancel cleanup task
+    // Cancel background tasks
     cleanup_handle.abort();
+    if let Some(handle) = health_handle {
+        handle.abort();
+    }
 
     tracing::info!("Shutting down");
     Ok(())
 }
 
+/// Where the Nym identity is kept: `--identity-dir` if given, otherwise an
+/// identity from before the flag existed, at `<data dir>/nym`, so the
+/// provider's address doesn't change; failing both, under the config dir
+///
+/// Fails if there is no config or home directory either, rather than
+/// keeping the identity wherever the provider happens to be started from.
+#[cfg(feature = "nym")]
+fn identity_dir(cli: &Cli) -> Result<PathBuf> {
+    if let Some(dir) = &cli.identity_dir {
+        return Ok(dir.clone());
+    }
+    let legacy = cli.data_dir.join("nym");
+    if legacy.is_dir() {
+        return Ok(legacy);
+    }
+    let base = dirs::config_dir().or_else(dirs::home_dir).ok_or_else(|| {
+        anyhow::anyhow!("no config or home directory for the Nym identity, pass --identity-dir")
+    })?;
+    Ok(base.join("brisby-index").join("identity"))
+}
+
+/// Run liveness probes if enabled, or wait forever
+async fn run_probes<T: Transport>(
+    transport: &T,
+    handler: &MessageHandler,
+    config: Option<ProbeConfig>,
+) {
+    match config {
+        Some(config) => liveness::run_probe_task(transport, handler, config).await,
+        None => std::future::pending().await,
+    }
+}
+
 /// Run periodic cleanup of expired index entries
-async fn run_cleanup_task(index_path: &PathBuf) {
+///
+/// Seeders are removed once `expiry_grace` seconds have passed since their
+/// TTL ran out.
+async fn run_cleanup_task(index_path: &Path, batch_size: usize, expiry_grace: u64) {
     tracing::info!("Starting cleanup task (interval: {:?})", CLEANUP_INTERVAL);
 
     loop {
         tokio::time::sleep(CLEANUP_INTERVAL).await;
 
         // Open a separate connection for cleanup
-        match SearchIndex::open(index_path) {
+        match SearchIndex::open_secondary(index_path) {
             Ok(index) => {
+                let index = index.with_expiry_grace(expiry_grace);
                 let now = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
                     .unwrap_or_default()
                     .as_secs();
 
-                match index.cleanup_expired(now) {
+                match index.cleanup_expired(now, batch_size) {
                     Ok(removed) => {
                         if removed > 0 {
                             tracing::info!("Cleanup: removed {} expired entries", removed);
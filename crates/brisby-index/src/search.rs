@@ -1,29 +1,180 @@
 //! Search index for the index provider
 
-use brisby_core::{IndexEntry, SearchResult};
-use rusqlite::{params, Connection, Result};
+use brisby_core::keywords::{self, QueryMode};
+use brisby_core::{ContentDefinedSizes, ContentHash, IndexEntry, SearchFilter, SearchResult};
+use rusqlite::types::Value;
+use rusqlite::{ffi, params, Connection, ErrorCode, OpenFlags, OptionalExtension, Result, ToSql};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Maximum number of seeders returned with each search result
+pub const MAX_SEEDERS_PER_RESULT: usize = 50;
+
+/// Default number of rows `cleanup_expired` deletes per transaction
+pub const DEFAULT_CLEANUP_BATCH_SIZE: usize = 1000;
+
+/// Default seconds an expired seeder stays listed as stale, see
+/// `SearchIndex::with_expiry_grace`
+pub const DEFAULT_EXPIRY_GRACE_SECS: u64 = 3600;
+
+/// How long a connection waits for another to release the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Length of the rendezvous tokens handed out for anonymous seeders
+pub const RELAY_TOKEN_LEN: usize = 16;
+
+/// Length of the tokens publishers unpublish with
+pub const UNPUBLISH_TOKEN_LEN: usize = 32;
+
+/// BM25 column weights for (filename, keywords, tags)
+///
+/// Explicit tags are a deliberate statement about the content, so a tag match
+/// outranks an incidental token in the filename or extracted keywords.
+const FILENAME_WEIGHT: f64 = 1.0;
+const KEYWORDS_WEIGHT: f64 = 1.0;
+const TAGS_WEIGHT: f64 = 4.0;
+
+/// Which entries go first when the index holds more than its cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Entries whose most recent publish is oldest
+    #[default]
+    OldestPublished,
+    /// Entries with the fewest seeders, oldest publish first among equals
+    FewestSeeders,
+}
+
+impl EvictionPolicy {
+    /// `ORDER BY` clause ranking entries for eviction, first to go first
+    fn order_by(&self) -> &'static str {
+        match self {
+            EvictionPolicy::OldestPublished => "last_published ASC",
+            EvictionPolicy::FewestSeeders => "seeder_count ASC, last_published ASC",
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvictionPolicy::OldestPublished => "oldest-published",
+            EvictionPolicy::FewestSeeders => "fewest-seeders",
+        })
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "oldest-published" => Ok(EvictionPolicy::OldestPublished),
+            "fewest-seeders" => Ok(EvictionPolicy::FewestSeeders),
+            other => Err(format!(
+                "unknown eviction policy '{}' (expected oldest-published or fewest-seeders)",
+                other
+            )),
+        }
+    }
+}
+
+/// Cap on the number of files the index holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexLimit {
+    pub max_entries: u64,
+    pub policy: EvictionPolicy,
+}
+
+/// One seeder of a file, see `SearchIndex::seeders`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedSeeder {
+    /// Address of a public seeder; None for an anonymous one
+    pub nym_address: Option<String>,
+    /// Rendezvous token of an anonymous seeder
+    pub relay_token: Option<Vec<u8>>,
+    /// When the seeder last published the file
+    pub published_at: u64,
+    /// When the listing expires unless the seeder publishes again
+    pub expires_at: u64,
+    /// Expired, but still within the grace period before it is removed
+    pub stale: bool,
+}
 
 /// Search index for the index provider
+///
+/// The connection sits behind a mutex so the message loop can handle
+/// requests on several threads. Each method holds it only for its own
+/// statements, or its own transaction.
 pub struct SearchIndex {
-    conn: Connection,
+    conn: Mutex<Connection>,
+    /// Entries beyond this are evicted as new ones are published
+    limit: Option<IndexLimit>,
+    /// Seconds past expiry before a seeder is removed
+    expiry_grace: u64,
 }
 
 impl SearchIndex {
     /// Open or create the search index database
-    pub fn open(path: &std::path::Path) -> Result<Self> {
+    ///
+    /// An existing database is checked with `PRAGMA quick_check` first. If it
+    /// is corrupt, it is moved aside to `<name>.corrupt-<unix time>` and a
+    /// fresh database is created in its place, with whatever rows can still
+    /// be read from the corrupt one copied over. Open further connections to
+    /// a database already in use with `open_secondary`, so a corrupt file is
+    /// never moved out from under a live connection.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Err(e) = Self::check_integrity(path) {
+            if !is_corruption(&e) {
+                return Err(e);
+            }
+            return Self::recover(path, &e);
+        }
+        match Self::open_secondary(path) {
+            Err(e) if is_corruption(&e) => Self::recover(path, &e),
+            result => result,
+        }
+    }
+
+    /// Open another connection to the database, without checking it or
+    /// recovering from corruption
+    pub fn open_secondary(path: &Path) -> Result<Self> {
         let conn = Connection::open(path)?;
+        // Cleanup and health checks use their own connections
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+
+        // Older databases predate the tags column and need their FTS table rebuilt
+        let migrated = Self::migrate_tags_column(&conn)?;
+        Self::migrate_relay_token_column(&conn)?;
+        Self::migrate_unpublish_token_column(&conn)?;
+        Self::migrate_filter_columns(&conn)?;
+        Self::migrate_chunking_columns(&conn)?;
 
         // Create tables if they don't exist
-        // entries: file metadata (one row per file)
-        // seeders: who has the file (multiple rows per file)
+        // entries: file metadata (one row per file); published_at is when the
+        //          file was first published, for filtering by recency;
+        //          chunk_size defaults to CHUNK_SIZE, and the smallest and
+        //          average chunk sizes are only set for content-defined chunks
+        // seeders: who has the file (multiple rows per file); anonymous
+        //          seeders have a relay_token and their address is never returned;
+        //          unpublish_token_hash is the BLAKE3 hash of the token that
+        //          unpublishes the row, NULL for rows from before tokens
         conn.execute_batch(
             r#"
             CREATE TABLE IF NOT EXISTS entries (
                 content_hash BLOB PRIMARY KEY,
                 filename TEXT NOT NULL,
                 keywords TEXT NOT NULL,
+                tags TEXT NOT NULL DEFAULT '',
                 size INTEGER NOT NULL,
-                chunk_count INTEGER NOT NULL
+                chunk_count INTEGER NOT NULL,
+                mime_type TEXT,
+                published_at INTEGER NOT NULL DEFAULT 0,
+                chunk_size INTEGER NOT NULL DEFAULT 262144,
+                min_chunk_size INTEGER,
+                avg_chunk_size INTEGER
             );
 
             CREATE TABLE IF NOT EXISTS seeders (
@@ -31,6 +182,8 @@ impl SearchIndex {
                 nym_address TEXT NOT NULL,
                 published_at INTEGER NOT NULL,
                 ttl INTEGER NOT NULL,
+                relay_token BLOB,
+                unpublish_token_hash BLOB,
                 PRIMARY KEY (content_hash, nym_address),
                 FOREIGN KEY (content_hash) REFERENCES entries(content_hash) ON DELETE CASCADE
             );
@@ -38,107 +191,648 @@ impl SearchIndex {
             CREATE VIRTUAL TABLE IF NOT EXISTS entries_fts USING fts5(
                 filename,
                 keywords,
+                tags,
                 content='entries',
                 content_rowid='rowid'
             );
 
             CREATE TRIGGER IF NOT EXISTS entries_ai AFTER INSERT ON entries BEGIN
-                INSERT INTO entries_fts(rowid, filename, keywords)
-                VALUES (new.rowid, new.filename, new.keywords);
+                INSERT INTO entries_fts(rowid, filename, keywords, tags)
+                VALUES (new.rowid, new.filename, new.keywords, new.tags);
             END;
 
             CREATE TRIGGER IF NOT EXISTS entries_ad AFTER DELETE ON entries BEGIN
-                INSERT INTO entries_fts(entries_fts, rowid, filename, keywords)
-                VALUES ('delete', old.rowid, old.filename, old.keywords);
+                INSERT INTO entries_fts(entries_fts, rowid, filename, keywords, tags)
+                VALUES ('delete', old.rowid, old.filename, old.keywords, old.tags);
             END;
 
             CREATE TRIGGER IF NOT EXISTS entries_au AFTER UPDATE ON entries BEGIN
-                INSERT INTO entries_fts(entries_fts, rowid, filename, keywords)
-                VALUES ('delete', old.rowid, old.filename, old.keywords);
-                INSERT INTO entries_fts(rowid, filename, keywords)
-                VALUES (new.rowid, new.filename, new.keywords);
+                INSERT INTO entries_fts(entries_fts, rowid, filename, keywords, tags)
+                VALUES ('delete', old.rowid, old.filename, old.keywords, old.tags);
+                INSERT INTO entries_fts(rowid, filename, keywords, tags)
+                VALUES (new.rowid, new.filename, new.keywords, new.tags);
             END;
 
             CREATE INDEX IF NOT EXISTS idx_seeders_published_at ON seeders(published_at);
             CREATE INDEX IF NOT EXISTS idx_seeders_ttl ON seeders(ttl);
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_seeders_relay_token ON seeders(relay_token);
+            "#,
+        )?;
+
+        if migrated {
+            conn.execute("INSERT INTO entries_fts(entries_fts) VALUES ('rebuild')", [])?;
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            limit: None,
+            expiry_grace: 0,
+        })
+    }
+
+    /// Keep at most `limit.max_entries` files, evicting by `limit.policy`
+    ///
+    /// The cap is applied whenever a publish adds a file and by
+    /// `evict_over_limit`, on top of TTL expiry.
+    pub fn with_limit(mut self, limit: Option<IndexLimit>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Keep seeders listed for `secs` after their TTL runs out
+    ///
+    /// A seeder that restarts or drops off the network for a moment misses
+    /// its republish; within the grace period it is still listed, marked
+    /// stale, and publishing again makes it fresh. `cleanup_expired` only
+    /// removes seeders once the grace period is over too. None by default.
+    pub fn with_expiry_grace(mut self, secs: u64) -> Self {
+        self.expiry_grace = secs;
+        self
+    }
+
+    /// Lock the connection for the statements that follow
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic mid-statement leaves nothing half-done that SQLite
+        // wouldn't roll back, so a poisoned lock is still usable
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Fail with a corruption error if the database at `path` is damaged
+    fn check_integrity(path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let problems = conn
+            .prepare("PRAGMA quick_check")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>>>()?;
+        if problems == ["ok"] {
+            return Ok(());
+        }
+        Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CORRUPT),
+            Some(problems.join("; ")),
+        ))
+    }
+
+    /// Replace the corrupt database at `path` with a fresh one, salvaging
+    /// what rows it can
+    fn recover(path: &Path, error: &rusqlite::Error) -> Result<Self> {
+        tracing::warn!("Search index {} is corrupt: {}", path.display(), error);
+        let backup = move_aside(path).map_err(io_error)?;
+        tracing::warn!("Moved corrupt index to {}", backup.display());
+
+        let index = Self::open_secondary(path)?;
+        let (entries, seeders) = index.salvage_rows(&backup)?;
+        tracing::warn!(
+            "Started a fresh index, recovering {} entries and {} seeders",
+            entries,
+            seeders
+        );
+
+        Ok(index)
+    }
+
+    /// Copy whatever rows can still be read from the corrupt database at `from`
+    ///
+    /// Each table is read in storage order until the first unreadable page,
+    /// so rows before the damage are kept. Returns the number of entries and
+    /// seeders copied.
+    fn salvage_rows(&self, from: &Path) -> Result<(usize, usize)> {
+        let Ok(old) = Connection::open_with_flags(from, OpenFlags::SQLITE_OPEN_READ_ONLY) else {
+            return Ok((0, 0));
+        };
+        // The corrupt database may predate later columns
+        let tags = if has_column(&old, "entries", "tags") { "tags" } else { "''" };
+        let relay_token = if has_column(&old, "seeders", "relay_token") {
+            "relay_token"
+        } else {
+            "NULL"
+        };
+        let unpublish_token_hash = if has_column(&old, "seeders", "unpublish_token_hash") {
+            "unpublish_token_hash"
+        } else {
+            "NULL"
+        };
+        let mime_type = if has_column(&old, "entries", "mime_type") {
+            "mime_type"
+        } else {
+            "NULL"
+        };
+        let published_at = if has_column(&old, "entries", "published_at") {
+            "published_at"
+        } else {
+            "0"
+        };
+        let chunk_size = if has_column(&old, "entries", "chunk_size") {
+            "chunk_size"
+        } else {
+            "262144"
+        };
+        let (min_chunk_size, avg_chunk_size) = if has_column(&old, "entries", "min_chunk_size") {
+            ("min_chunk_size", "avg_chunk_size")
+        } else {
+            ("NULL", "NULL")
+        };
+
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let entries = copy_rows(
+            &conn,
+            &old,
+            &format!(
+                "SELECT content_hash, filename, keywords, {tags}, size, chunk_count,
+                        {mime_type}, {published_at}, {chunk_size}, {min_chunk_size},
+                        {avg_chunk_size}
+                 FROM entries"
+            ),
+            "INSERT OR IGNORE INTO entries
+                 (content_hash, filename, keywords, tags, size, chunk_count,
+                  mime_type, published_at, chunk_size, min_chunk_size, avg_chunk_size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            11,
+        );
+        let seeders = copy_rows(
+            &conn,
+            &old,
+            &format!(
+                "SELECT content_hash, nym_address, published_at, ttl, {relay_token},
+                        {unpublish_token_hash}
+                 FROM seeders"
+            ),
+            "INSERT OR IGNORE INTO seeders
+                 (content_hash, nym_address, published_at, ttl, relay_token, unpublish_token_hash)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6
+             WHERE EXISTS (SELECT 1 FROM entries WHERE content_hash = ?1)",
+            6,
+        );
+        tx.commit()?;
+
+        Ok((entries, seeders))
+    }
+
+    /// Add the tags column to a pre-existing entries table
+    ///
+    /// Drops the old two-column FTS table and its triggers so they are
+    /// recreated with the tags column. Returns true if a migration happened.
+    fn migrate_tags_column(conn: &Connection) -> Result<bool> {
+        let has_entries: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'entries')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_entries {
+            return Ok(false);
+        }
+
+        let has_tags: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('entries') WHERE name = 'tags')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_tags {
+            return Ok(false);
+        }
+
+        conn.execute_batch(
+            r#"
+            ALTER TABLE entries ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+            DROP TRIGGER IF EXISTS entries_ai;
+            DROP TRIGGER IF EXISTS entries_ad;
+            DROP TRIGGER IF EXISTS entries_au;
+            DROP TABLE IF EXISTS entries_fts;
             "#,
         )?;
 
-        Ok(Self { conn })
+        Ok(true)
+    }
+
+    /// Add the relay_token column to a pre-existing seeders table
+    fn migrate_relay_token_column(conn: &Connection) -> Result<()> {
+        let has_seeders: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'seeders')",
+            [],
+            |row| row.get(0),
+        )?;
+        let has_relay_token: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('seeders') WHERE name = 'relay_token')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_seeders && !has_relay_token {
+            conn.execute("ALTER TABLE seeders ADD COLUMN relay_token BLOB", [])?;
+        }
+        Ok(())
+    }
+
+    /// Add the unpublish_token_hash column to a pre-existing seeders table
+    ///
+    /// Seeders listed before it existed can't unpublish; they expire instead.
+    fn migrate_unpublish_token_column(conn: &Connection) -> Result<()> {
+        let has_seeders: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'seeders')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_seeders && !has_column(conn, "seeders", "unpublish_token_hash") {
+            conn.execute("ALTER TABLE seeders ADD COLUMN unpublish_token_hash BLOB", [])?;
+        }
+        Ok(())
+    }
+
+    /// Add the mime_type and published_at columns to a pre-existing entries
+    /// table
+    ///
+    /// Existing entries count as first published when their oldest
+    /// remaining seeder published them.
+    fn migrate_filter_columns(conn: &Connection) -> Result<()> {
+        let has_entries: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'entries')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_entries {
+            return Ok(());
+        }
+        if !has_column(conn, "entries", "mime_type") {
+            conn.execute("ALTER TABLE entries ADD COLUMN mime_type TEXT", [])?;
+        }
+        if !has_column(conn, "entries", "published_at") {
+            conn.execute(
+                "ALTER TABLE entries ADD COLUMN published_at INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+            if has_column(conn, "seeders", "published_at") {
+                conn.execute(
+                    "UPDATE entries SET published_at = COALESCE(
+                        (SELECT MIN(published_at) FROM seeders
+                         WHERE seeders.content_hash = entries.content_hash),
+                        0
+                    )",
+                    [],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the chunk_size, min_chunk_size and avg_chunk_size columns to a
+    /// pre-existing entries table
+    ///
+    /// Files published before chunking was recorded were all in fixed-size
+    /// chunks of `CHUNK_SIZE`.
+    fn migrate_chunking_columns(conn: &Connection) -> Result<()> {
+        let has_entries: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'entries')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_entries {
+            return Ok(());
+        }
+        if !has_column(conn, "entries", "chunk_size") {
+            conn.execute(
+                "ALTER TABLE entries ADD COLUMN chunk_size INTEGER NOT NULL DEFAULT 262144",
+                [],
+            )?;
+        }
+        if !has_column(conn, "entries", "min_chunk_size") {
+            conn.execute_batch(
+                "ALTER TABLE entries ADD COLUMN min_chunk_size INTEGER;
+                 ALTER TABLE entries ADD COLUMN avg_chunk_size INTEGER;",
+            )?;
+        }
+        Ok(())
     }
 
     /// Add or update an entry in the index
     ///
     /// Inserts or updates the file metadata, and adds the seeder.
-    /// Multiple seeders can publish the same file.
+    /// Multiple seeders can publish the same file. The size and chunk count
+    /// a file was first published with are kept; see `layout`.
     pub fn upsert(&self, entry: &IndexEntry, nym_address: &str) -> Result<()> {
-        let keywords = entry.keywords.join(" ");
+        self.upsert_seeder(entry, nym_address, None)?;
+        Ok(())
+    }
+
+    /// Add or update an entry for a seeder that wants its address kept private
+    ///
+    /// The seeder is listed in search results by a rendezvous token rather
+    /// than its address; requests reach it through `relay_address`. `token`
+    /// should be random and is only used if the seeder doesn't already have
+    /// one, so republishing keeps the token handed out earlier. Returns the
+    /// token in effect.
+    pub fn upsert_anonymous(
+        &self,
+        entry: &IndexEntry,
+        nym_address: &str,
+        token: &[u8],
+    ) -> Result<Vec<u8>> {
+        let token_in_effect = self.upsert_seeder(entry, nym_address, Some(token))?;
+        Ok(token_in_effect.unwrap_or_else(|| token.to_vec()))
+    }
+
+    /// Size and chunk count `content_hash` is listed with, if it is listed
+    ///
+    /// Later publishes of the same hash can't change these, so a publisher
+    /// misreporting them can't make results flip between values.
+    pub fn layout(&self, content_hash: &ContentHash) -> Result<Option<(u64, u32)>> {
+        self.conn()
+            .query_row(
+                "SELECT size, chunk_count FROM entries WHERE content_hash = ?",
+                params![content_hash.as_slice()],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u32)),
+            )
+            .optional()
+    }
+
+    /// Look up the address of the anonymous seeder behind a rendezvous token
+    pub fn relay_address(&self, token: &[u8]) -> Result<Option<String>> {
+        self.conn()
+            .query_row(
+                "SELECT nym_address FROM seeders WHERE relay_token = ?",
+                params![token],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Seeders of `content_hash` still listed at `current_time`, most
+    /// recently published first
+    ///
+    /// Seeders past their expiry but within the grace period (see
+    /// `with_expiry_grace`) are included, marked stale. Like `search`, lists
+    /// at most `MAX_SEEDERS_PER_RESULT` and gives anonymous seeders only by
+    /// relay token.
+    pub fn seeders(
+        &self,
+        content_hash: &ContentHash,
+        current_time: u64,
+    ) -> Result<Vec<ListedSeeder>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT nym_address, relay_token, published_at, ttl FROM seeders
+             WHERE content_hash = ?1
+                AND NOT (?2 >= published_at AND (?2 - published_at) - ttl >= ?4)
+             ORDER BY published_at DESC, nym_address
+             LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                content_hash.as_slice(),
+                current_time as i64,
+                MAX_SEEDERS_PER_RESULT as i64,
+                self.expiry_grace as i64
+            ],
+            |row| {
+                let relay_token: Option<Vec<u8>> = row.get(1)?;
+                let published_at = row.get::<_, i64>(2)? as u64;
+                let ttl = row.get::<_, i64>(3)? as u64;
+                Ok(ListedSeeder {
+                    nym_address: match relay_token {
+                        Some(_) => None,
+                        None => Some(row.get(0)?),
+                    },
+                    relay_token,
+                    published_at,
+                    expires_at: published_at.saturating_add(ttl),
+                    stale: current_time >= published_at && current_time - published_at >= ttl,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// Set the token that unpublishes `nym_address`'s listing of
+    /// `content_hash`, replacing any earlier one
+    ///
+    /// Only its hash is stored. Returns whether the seeder is listed.
+    pub fn set_unpublish_token(
+        &self,
+        content_hash: &ContentHash,
+        nym_address: &str,
+        token: &[u8],
+    ) -> Result<bool> {
+        let updated = self.conn().execute(
+            "UPDATE seeders SET unpublish_token_hash = ?
+             WHERE content_hash = ? AND nym_address = ?",
+            params![
+                blake3::hash(token).as_bytes().as_slice(),
+                content_hash.as_slice(),
+                nym_address
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Stop listing `nym_address` as a seeder of `content_hash` at its own
+    /// request, if `token` is the one last set by `set_unpublish_token`
+    ///
+    /// Returns whether the seeder was listed with that token.
+    pub fn unpublish(
+        &self,
+        content_hash: &ContentHash,
+        nym_address: &str,
+        token: &[u8],
+    ) -> Result<bool> {
+        self.delete_seeder(content_hash, nym_address, Some(&blake3::hash(token).as_bytes()[..]))
+    }
+
+    /// Stop listing `nym_address` as a seeder of `content_hash`
+    ///
+    /// The entry goes too once its last seeder is removed. Returns whether
+    /// the seeder was listed.
+    pub fn remove_seeder(&self, content_hash: &ContentHash, nym_address: &str) -> Result<bool> {
+        self.delete_seeder(content_hash, nym_address, None)
+    }
+
+    /// Delete a seeder row, only if its token hash matches when one is given,
+    /// and the entry with its last seeder
+    fn delete_seeder(
+        &self,
+        content_hash: &ContentHash,
+        nym_address: &str,
+        token_hash: Option<&[u8]>,
+    ) -> Result<bool> {
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM seeders WHERE content_hash = ?1 AND nym_address = ?2
+             AND (?3 IS NULL OR unpublish_token_hash = ?3)",
+            params![content_hash.as_slice(), nym_address, token_hash],
+        )?;
+        tx.execute(
+            "DELETE FROM entries WHERE content_hash = ?1
+             AND NOT EXISTS (SELECT 1 FROM seeders WHERE content_hash = ?1)",
+            params![content_hash.as_slice()],
+        )?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    /// Listings of up to `max_seeders` seeders, picked at random among
+    /// those that published since `published_since`, to check they're
+    /// still up
+    ///
+    /// Every recent listing of each seeder picked is returned, so one ping
+    /// covers all the files it seeds.
+    pub fn probe_candidates(
+        &self,
+        published_since: u64,
+        max_seeders: usize,
+    ) -> Result<Vec<(ContentHash, String)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT content_hash, nym_address FROM seeders
+             WHERE published_at >= ?1 AND nym_address IN (
+                 SELECT DISTINCT nym_address FROM seeders WHERE published_at >= ?1
+                 ORDER BY random() LIMIT ?2
+             )
+             ORDER BY nym_address, content_hash",
+        )?;
+        let rows = stmt.query_map(
+            params![published_since as i64, max_seeders as i64],
+            |row| {
+                let hash_bytes: Vec<u8> = row.get(0)?;
+                let mut content_hash = [0u8; 32];
+                if hash_bytes.len() == 32 {
+                    content_hash.copy_from_slice(&hash_bytes);
+                }
+                Ok((content_hash, row.get(1)?))
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// Add or update the entry and seeder rows in one transaction, returning
+    /// the seeder's relay token in effect
+    ///
+    /// A savepoint rather than a transaction, so callers batching upserts in
+    /// a transaction of their own can still call this.
+    fn upsert_seeder(
+        &self,
+        entry: &IndexEntry,
+        nym_address: &str,
+        relay_token: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let keywords = keywords::normalize(&entry.keywords).join(" ");
+        let tags = entry.tags.join(" ");
+        let mime_type = entry.mime_type.as_deref().and_then(normalize_mime_type);
 
-        // Insert or update file metadata (using ON CONFLICT to avoid CASCADE delete)
-        self.conn.execute(
+        let mut conn = self.conn();
+        let tx = conn.savepoint()?;
+
+        // Only a new file can take the index over its cap
+        let is_new = self.limit.is_some()
+            && !tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM entries WHERE content_hash = ?)",
+                params![entry.content_hash.as_slice()],
+                |row| row.get::<_, bool>(0),
+            )?;
+
+        // Insert or update file metadata (using ON CONFLICT to avoid CASCADE delete).
+        // published_at, size and the chunking keep the first publish; a publisher
+        // that didn't detect a MIME type doesn't erase one another publisher sent.
+        tx.execute(
             r#"
-            INSERT INTO entries (content_hash, filename, keywords, size, chunk_count)
-            VALUES (?, ?, ?, ?, ?)
+            INSERT INTO entries
+                (content_hash, filename, keywords, tags, size, chunk_count, mime_type, published_at,
+                 chunk_size, min_chunk_size, avg_chunk_size)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(content_hash) DO UPDATE SET
                 filename = excluded.filename,
                 keywords = excluded.keywords,
-                size = excluded.size,
-                chunk_count = excluded.chunk_count
+                tags = excluded.tags,
+                mime_type = COALESCE(excluded.mime_type, entries.mime_type)
             "#,
             params![
                 entry.content_hash.as_slice(),
                 entry.filename,
                 keywords,
+                tags,
                 entry.size as i64,
                 entry.chunk_count as i64,
+                mime_type,
+                entry.published_at as i64,
+                entry.chunk_size as i64,
+                entry.content_defined.map(|sizes| sizes.min_size as i64),
+                entry.content_defined.map(|sizes| sizes.avg_size as i64),
             ],
         )?;
 
-        // Insert or update seeder info
-        self.conn.execute(
+        // Insert or update seeder info. Publishing publicly drops any token;
+        // republishing anonymously keeps the existing one.
+        tx.execute(
             r#"
-            INSERT INTO seeders (content_hash, nym_address, published_at, ttl)
-            VALUES (?, ?, ?, ?)
+            INSERT INTO seeders (content_hash, nym_address, published_at, ttl, relay_token)
+            VALUES (?, ?, ?, ?, ?)
             ON CONFLICT(content_hash, nym_address) DO UPDATE SET
                 published_at = excluded.published_at,
-                ttl = excluded.ttl
+                ttl = excluded.ttl,
+                relay_token = CASE
+                    WHEN excluded.relay_token IS NULL THEN NULL
+                    ELSE COALESCE(seeders.relay_token, excluded.relay_token)
+                END
             "#,
             params![
                 entry.content_hash.as_slice(),
                 nym_address,
                 entry.published_at as i64,
                 entry.ttl as i64,
+                relay_token,
             ],
         )?;
+        let token_in_effect = tx.query_row(
+            "SELECT relay_token FROM seeders WHERE content_hash = ? AND nym_address = ?",
+            params![entry.content_hash.as_slice(), nym_address],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+        drop(conn);
 
-        Ok(())
-    }
+        if is_new {
+            self.evict_over_limit(DEFAULT_CLEANUP_BATCH_SIZE)?;
+        }
 
-    /// Escape a query string for safe use with FTS5
-    ///
-    /// Wraps each word in double quotes to prevent FTS5 from interpreting
-    /// special characters (like hyphens, colons) as operators.
-    fn escape_fts_query(query: &str) -> String {
-        query
-            .split_whitespace()
-            .map(|word| {
-                // Escape any double quotes within the word and wrap in quotes
-                let escaped = word.replace('"', "\"\"");
-                format!("\"{}\"", escaped)
-            })
-            .collect::<Vec<_>>()
-            .join(" ")
+        Ok(token_in_effect)
     }
 
     /// Search for entries matching a query
     ///
     /// Returns results with all known seeders aggregated for each file.
-    pub fn search(&self, query: &str, max_results: u32) -> Result<Vec<SearchResult>> {
-        // Escape query for safe FTS5 usage
-        let safe_query = Self::escape_fts_query(query);
+    /// Anonymous seeders appear only as relay tokens. Results are ordered by
+    /// relevance, then by seeder count.
+    /// The query is read as keywords or FTS5 syntax according to `mode`; a
+    /// malformed advanced query fails, see `is_query_error`. Only entries
+    /// passing `filter` match. Results below `min_relevance`
+    /// times the best result's relevance are filtered out after ranking; 0
+    /// keeps every match. The threshold is relative to the best match passing
+    /// the filter, not the best one after `offset`, so pages line up with
+    /// `count_matches`.
+    pub fn search(
+        &self,
+        query: &str,
+        mode: QueryMode,
+        max_results: u32,
+        offset: u32,
+        min_relevance: f32,
+        filter: &SearchFilter,
+    ) -> Result<Vec<SearchResult>> {
+        let Some(match_expression) = keywords::query_expression(query, mode) else {
+            return Ok(vec![]);
+        };
+        let rank_cutoff = self.rank_cutoff(&match_expression, min_relevance, filter)?;
+        let (conditions, filter_params) = filter_conditions(filter);
 
-        // First get FTS matches with BM25 ranking, then join with seeders
-        let mut stmt = self.conn.prepare(
+        // First get FTS matches with BM25 ranking, then attach the most recently
+        // published seeders. The correlated subquery caps the seeder list so the
+        // concatenated string stays bounded no matter how many seeders a file has.
+        // Equally relevant files go in order of seeder count, so the ones most
+        // likely to download come first and files nobody seeds any more last.
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
             r#"
             SELECT
                 e.content_hash,
@@ -146,23 +840,68 @@ impl SearchIndex {
                 e.size,
                 e.chunk_count,
                 fts_matches.rank,
-                GROUP_CONCAT(s.nym_address, '|') as seeders
+                (
+                    SELECT GROUP_CONCAT(nym_address, '|')
+                    FROM (
+                        SELECT s.nym_address
+                        FROM seeders s
+                        WHERE s.content_hash = e.content_hash AND s.relay_token IS NULL
+                        ORDER BY s.published_at DESC
+                        LIMIT ?
+                    )
+                ) as seeders,
+                (
+                    SELECT GROUP_CONCAT(hex(relay_token), '|')
+                    FROM (
+                        SELECT s.relay_token
+                        FROM seeders s
+                        WHERE s.content_hash = e.content_hash AND s.relay_token IS NOT NULL
+                        ORDER BY s.published_at DESC
+                        LIMIT ?
+                    )
+                ) as relay_tokens,
+                fts_matches.seeder_count,
+                e.chunk_size,
+                e.min_chunk_size,
+                e.avg_chunk_size
             FROM (
-                SELECT rowid, bm25(entries_fts) as rank
-                FROM entries_fts
-                WHERE entries_fts MATCH ?
-                ORDER BY rank
-                LIMIT ?
+                SELECT
+                    m.rowid,
+                    m.rank,
+                    (
+                        SELECT COUNT(s.nym_address)
+                        FROM seeders s
+                        WHERE s.content_hash = e.content_hash
+                    ) as seeder_count
+                FROM (
+                    SELECT rowid, bm25(entries_fts, ?, ?, ?) as rank
+                    FROM entries_fts
+                    WHERE entries_fts MATCH ?
+                ) m
+                JOIN entries e ON e.rowid = m.rowid
+                WHERE m.rank <= ?{conditions}
+                ORDER BY m.rank, seeder_count DESC, m.rowid
+                LIMIT ? OFFSET ?
             ) fts_matches
             JOIN entries e ON e.rowid = fts_matches.rowid
-            LEFT JOIN seeders s ON e.content_hash = s.content_hash
-            GROUP BY e.content_hash
-            ORDER BY fts_matches.rank
+            ORDER BY fts_matches.rank, fts_matches.seeder_count DESC, fts_matches.rowid
             "#,
-        )?;
+        ))?;
 
+        let seeder_cap = MAX_SEEDERS_PER_RESULT as i64;
+        let mut query_params: Vec<&dyn ToSql> = vec![
+            &seeder_cap,
+            &seeder_cap,
+            &FILENAME_WEIGHT,
+            &KEYWORDS_WEIGHT,
+            &TAGS_WEIGHT,
+            &match_expression,
+            &rank_cutoff,
+        ];
+        query_params.extend(filter_params.iter().map(|value| value as &dyn ToSql));
+        query_params.extend([&max_results as &dyn ToSql, &offset]);
         let results = stmt
-            .query_map(params![safe_query, max_results], |row| {
+            .query_map(query_params.as_slice(), |row| {
                 let hash_bytes: Vec<u8> = row.get(0)?;
                 let mut content_hash = [0u8; 32];
                 if hash_bytes.len() == 32 {
@@ -180,13 +919,31 @@ impl SearchIndex {
                     })
                     .unwrap_or_default();
 
+                let min_chunk_size = row.get::<_, Option<i64>>(9)?;
+                let content_defined = match (min_chunk_size, row.get::<_, Option<i64>>(10)?) {
+                    (Some(min_size), Some(avg_size)) => Some(ContentDefinedSizes {
+                        min_size: min_size as u32,
+                        avg_size: avg_size as u32,
+                    }),
+                    _ => None,
+                };
+
+                let tokens_str: Option<String> = row.get(6)?;
+                let relay_tokens: Vec<Vec<u8>> = tokens_str
+                    .map(|s| s.split('|').filter_map(|t| hex::decode(t).ok()).collect())
+                    .unwrap_or_default();
+
                 Ok(SearchResult {
                     content_hash,
                     filename: row.get(1)?,
                     size: row.get::<_, i64>(2)? as u64,
                     chunk_count: row.get::<_, i64>(3)? as u32,
+                    chunk_size: row.get::<_, i64>(8)? as u32,
+                    content_defined,
                     relevance: -row.get::<_, f64>(4)? as f32,
                     seeders,
+                    relay_tokens,
+                    seeder_count: row.get::<_, i64>(7)? as u32,
                 })
             })?
             .collect::<Result<Vec<_>>>()?;
@@ -194,34 +951,209 @@ impl SearchIndex {
         Ok(results)
     }
 
+    /// Number of entries `search` pages through for a query
+    pub fn count_matches(
+        &self,
+        query: &str,
+        mode: QueryMode,
+        min_relevance: f32,
+        filter: &SearchFilter,
+    ) -> Result<u32> {
+        let Some(match_expression) = keywords::query_expression(query, mode) else {
+            return Ok(0);
+        };
+        let rank_cutoff = self.rank_cutoff(&match_expression, min_relevance, filter)?;
+        let (conditions, filter_params) = filter_conditions(filter);
+
+        let mut query_params: Vec<&dyn ToSql> = vec![
+            &FILENAME_WEIGHT,
+            &KEYWORDS_WEIGHT,
+            &TAGS_WEIGHT,
+            &match_expression,
+            &rank_cutoff,
+        ];
+        query_params.extend(filter_params.iter().map(|value| value as &dyn ToSql));
+        self.conn().query_row(
+            &format!(
+                r#"
+                SELECT COUNT(*)
+                FROM (
+                    SELECT rowid, bm25(entries_fts, ?, ?, ?) as rank
+                    FROM entries_fts
+                    WHERE entries_fts MATCH ?
+                ) m
+                JOIN entries e ON e.rowid = m.rowid
+                WHERE m.rank <= ?{conditions}
+                "#
+            ),
+            query_params.as_slice(),
+            |row| row.get(0),
+        )
+    }
+
+    /// Highest BM25 rank a match may have to pass `min_relevance`
+    ///
+    /// BM25 ranks are negative, best lowest, and relevance is the negated
+    /// rank, so a fraction of the best relevance is that fraction of the best
+    /// rank. Without a threshold every rank passes.
+    fn rank_cutoff(
+        &self,
+        match_expression: &str,
+        min_relevance: f32,
+        filter: &SearchFilter,
+    ) -> Result<f64> {
+        if min_relevance.is_nan() || min_relevance <= 0.0 {
+            return Ok(f64::MAX);
+        }
+        let (conditions, filter_params) = filter_conditions(filter);
+
+        // bm25() can't be aggregated, so take the top of the ranking instead
+        let mut query_params: Vec<&dyn ToSql> =
+            vec![&FILENAME_WEIGHT, &KEYWORDS_WEIGHT, &TAGS_WEIGHT, &match_expression];
+        query_params.extend(filter_params.iter().map(|value| value as &dyn ToSql));
+        let best: Option<f64> = self
+            .conn()
+            .query_row(
+                &format!(
+                    r#"
+                    SELECT m.rank
+                    FROM (
+                        SELECT rowid, bm25(entries_fts, ?, ?, ?) as rank
+                        FROM entries_fts
+                        WHERE entries_fts MATCH ?
+                    ) m
+                    JOIN entries e ON e.rowid = m.rowid
+                    WHERE 1{conditions}
+                    ORDER BY m.rank
+                    LIMIT 1
+                    "#
+                ),
+                query_params.as_slice(),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match best {
+            Some(best) if best < 0.0 => best * min_relevance as f64,
+            _ => f64::MAX,
+        })
+    }
+
     /// Remove expired seeders and orphaned entries
     ///
-    /// First removes seeders whose TTL has expired, then removes any entries
-    /// that no longer have any seeders.
-    pub fn cleanup_expired(&self, current_time: u64) -> Result<usize> {
-        // Delete expired seeders (using subtraction to avoid overflow in published_at + ttl)
-        let expired_seeders = self.conn.execute(
-            "DELETE FROM seeders WHERE ? >= published_at AND (? - published_at) >= ttl",
-            params![current_time as i64, current_time as i64],
+    /// First removes seeders whose TTL and grace period (see
+    /// `with_expiry_grace`) have both run out, then removes any entries that
+    /// no longer have any seeders. Rows are deleted `batch_size` at a
+    /// time, each batch its own short transaction, so queries on other
+    /// connections get the database between batches instead of waiting for
+    /// one large delete to finish.
+    pub fn cleanup_expired(&self, current_time: u64, batch_size: usize) -> Result<usize> {
+        let batch_size = batch_size.max(1);
+
+        // Delete expired seeders (using subtraction to avoid overflow in
+        // published_at + ttl + grace)
+        let expired_seeders = self.delete_in_batches(
+            "DELETE FROM seeders WHERE rowid IN (
+                SELECT rowid FROM seeders
+                WHERE ?2 >= published_at AND (?2 - published_at) - ttl >= ?3
+                LIMIT ?1
+            )",
+            batch_size,
+            &[&(current_time as i64), &(self.expiry_grace as i64)],
         )?;
 
         // Delete entries with no remaining seeders
-        let orphaned_entries = self.conn.execute(
-            "DELETE FROM entries WHERE content_hash NOT IN (SELECT DISTINCT content_hash FROM seeders)",
-            [],
+        let orphaned_entries = self.delete_in_batches(
+            "DELETE FROM entries WHERE rowid IN (
+                SELECT rowid FROM entries
+                WHERE content_hash NOT IN (SELECT DISTINCT content_hash FROM seeders)
+                LIMIT ?1
+            )",
+            batch_size,
+            &[],
         )?;
 
         Ok(expired_seeders + orphaned_entries)
     }
 
+    /// Run a delete limited to `?1` rows until it deletes less than a full batch
+    ///
+    /// `extra_params` bind `?2` onwards.
+    fn delete_in_batches(
+        &self,
+        sql: &str,
+        batch_size: usize,
+        extra_params: &[&dyn ToSql],
+    ) -> Result<usize> {
+        let limit = batch_size as i64;
+        let mut bound: Vec<&dyn ToSql> = vec![&limit];
+        bound.extend_from_slice(extra_params);
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(sql)?;
+        let mut total = 0;
+        loop {
+            let deleted = stmt.execute(bound.as_slice())?;
+            total += deleted;
+            if deleted < batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Evict entries beyond the cap set with `with_limit`
+    ///
+    /// Entries are ranked by the eviction policy and removed with their
+    /// seeders, `batch_size` per transaction. Returns the number of entries
+    /// evicted.
+    pub fn evict_over_limit(&self, batch_size: usize) -> Result<usize> {
+        let Some(limit) = self.limit else {
+            return Ok(0);
+        };
+
+        let select = format!(
+            "SELECT entries.content_hash,
+                    COALESCE(MAX(seeders.published_at), 0) AS last_published,
+                    COUNT(seeders.nym_address) AS seeder_count
+             FROM entries LEFT JOIN seeders ON seeders.content_hash = entries.content_hash
+             GROUP BY entries.content_hash
+             ORDER BY {}
+             LIMIT ?1",
+            limit.policy.order_by()
+        );
+
+        let mut evicted = 0;
+        loop {
+            let conn = self.conn();
+            let count: i64 = conn
+                .query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))?;
+            let excess = (count as u64).saturating_sub(limit.max_entries);
+            if excess == 0 {
+                return Ok(evicted);
+            }
+
+            let batch = excess.min(batch_size.max(1) as u64) as i64;
+            let victims: Vec<Vec<u8>> = conn
+                .prepare(&select)?
+                .query_map(params![batch], |row| row.get(0))?
+                .collect::<Result<_>>()?;
+
+            let tx = conn.unchecked_transaction()?;
+            for content_hash in &victims {
+                tx.execute("DELETE FROM seeders WHERE content_hash = ?", params![content_hash])?;
+                tx.execute("DELETE FROM entries WHERE content_hash = ?", params![content_hash])?;
+            }
+            tx.commit()?;
+            evicted += victims.len();
+        }
+    }
+
     /// Get statistics about the index
     pub fn stats(&self) -> Result<IndexStats> {
-        let count: i64 = self
-            .conn
+        let conn = self.conn();
+        let count: i64 = conn
             .query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))?;
 
-        let total_size: i64 = self
-            .conn
+        let total_size: i64 = conn
             .query_row("SELECT COALESCE(SUM(size), 0) FROM entries", [], |row| {
                 row.get(0)
             })?;
@@ -233,6 +1165,147 @@ impl SearchIndex {
     }
 }
 
+/// Copy rows from `select` on `from` into `insert` on `to`, stopping at the
+/// first error; returns the number of rows inserted
+fn copy_rows(
+    to: &Connection,
+    from: &Connection,
+    select: &str,
+    insert: &str,
+    columns: usize,
+) -> usize {
+    let mut copied = 0;
+    let result = (|| -> Result<()> {
+        let mut select = from.prepare(select)?;
+        let mut insert = to.prepare(insert)?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let values = (0..columns)
+                .map(|i| row.get::<_, Value>(i))
+                .collect::<Result<Vec<_>>>()?;
+            copied += insert.execute(rusqlite::params_from_iter(values))?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        tracing::warn!("Stopped salvaging rows after {}: {}", copied, e);
+    }
+    copied
+}
+
+/// Lowercased `type/subtype`, or `None` if `mime_type` isn't shaped like one
+fn normalize_mime_type(mime_type: &str) -> Option<String> {
+    let mime_type = mime_type.trim().to_ascii_lowercase();
+    let (kind, subtype) = mime_type.split_once('/')?;
+    let valid = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    (mime_type.len() <= 255 && valid(kind) && valid(subtype)).then_some(mime_type)
+}
+
+/// SQL conditions restricting the entries aliased `e` to `filter`, each
+/// starting with `AND`, and the parameters they bind
+///
+/// A MIME type without a subtype, like `video`, matches every subtype.
+fn filter_conditions(filter: &SearchFilter) -> (String, Vec<Value>) {
+    let mut conditions = String::new();
+    let mut params: Vec<Value> = Vec::new();
+    if let Some(min_size) = filter.min_size {
+        conditions.push_str(" AND e.size >= ?");
+        params.push((min_size.min(i64::MAX as u64) as i64).into());
+    }
+    if let Some(max_size) = filter.max_size {
+        conditions.push_str(" AND e.size <= ?");
+        params.push((max_size.min(i64::MAX as u64) as i64).into());
+    }
+    if let Some(mime_type) = &filter.mime_type {
+        let mime_type = mime_type.trim().to_ascii_lowercase();
+        if mime_type.contains('/') {
+            conditions.push_str(" AND e.mime_type = ?");
+            params.push(mime_type.into());
+        } else {
+            let prefix = format!("{}/", mime_type);
+            conditions.push_str(" AND substr(e.mime_type, 1, ?) = ?");
+            params.push((prefix.len() as i64).into());
+            params.push(prefix.into());
+        }
+    }
+    if let Some(published_after) = filter.published_after {
+        conditions.push_str(" AND e.published_at > ?");
+        params.push((published_after.min(i64::MAX as u64) as i64).into());
+    }
+    (conditions, params)
+}
+
+/// Starts of the messages FTS5 fails malformed queries with
+const QUERY_ERROR_PREFIXES: [&str; 4] = [
+    "fts5:",
+    "no such column",
+    "unknown special query",
+    "unterminated string",
+];
+
+/// Whether an error came from a malformed advanced query rather than the
+/// database
+pub fn is_query_error(error: &rusqlite::Error) -> bool {
+    match error {
+        rusqlite::Error::SqliteFailure(e, Some(message)) => {
+            e.code == ErrorCode::Unknown
+                && QUERY_ERROR_PREFIXES
+                    .iter()
+                    .any(|prefix| message.starts_with(prefix))
+        }
+        _ => false,
+    }
+}
+
+/// Whether an error means the database file is damaged
+fn is_corruption(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+    )
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        params![table, column],
+        |row| row.get(0),
+    )
+    .unwrap_or(false)
+}
+
+/// Rename a corrupt database, and any journal files with it, out of the way
+fn move_aside(path: &Path) -> std::io::Result<PathBuf> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let with_suffix = |path: &Path, suffix: &str| {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+
+    let backup = with_suffix(path, &format!(".corrupt-{}", now));
+    std::fs::rename(path, &backup)?;
+    for journal in ["-journal", "-wal", "-shm"] {
+        let journal_path = with_suffix(path, journal);
+        if journal_path.exists() {
+            std::fs::rename(&journal_path, with_suffix(&backup, journal))?;
+        }
+    }
+    Ok(backup)
+}
+
+fn io_error(error: std::io::Error) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_IOERR), Some(error.to_string()))
+}
+
 /// Statistics about the search index
 #[derive(Debug, Clone)]
 pub struct IndexStats {
@@ -245,6 +1318,20 @@ mod tests {
     use super::*;
     use tempfile::NamedTempFile;
 
+    /// Keyword search for the first ten matches of `query`, unfiltered
+    fn search(index: &SearchIndex, query: &str) -> Vec<SearchResult> {
+        index
+            .search(query, QueryMode::Keywords, 10, 0, 0.0, &SearchFilter::default())
+            .unwrap()
+    }
+
+    /// Number of keyword matches of `query`, unfiltered
+    fn count(index: &SearchIndex, query: &str, min_relevance: f32) -> u32 {
+        index
+            .count_matches(query, QueryMode::Keywords, min_relevance, &SearchFilter::default())
+            .unwrap()
+    }
+
     #[test]
     fn test_upsert_and_search() {
         let temp = NamedTempFile::new().unwrap();
@@ -254,20 +1341,210 @@ mod tests {
             content_hash: [1u8; 32],
             filename: "test_movie.mkv".to_string(),
             keywords: vec!["test".to_string(), "movie".to_string()],
+            tags: vec![],
             size: 1024 * 1024 * 100,
             chunk_count: 400,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
             published_at: 1000,
             ttl: 3600,
         };
 
         index.upsert(&entry, "test-nym-address").unwrap();
 
-        let results = index.search("movie", 10).unwrap();
+        let results = search(&index, "movie");
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].filename, "test_movie.mkv");
         assert_eq!(results[0].seeders, vec!["test-nym-address"]);
     }
 
+    #[test]
+    fn test_keywords_consistent_with_client() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("Big_Buck-Bunny.1080p.MKV");
+        std::fs::write(&path, b"video").unwrap();
+        let (metadata, _) = brisby_core::chunk::chunk_file(&path).unwrap();
+        let local_path = dir.path().join("local.db");
+        let local = brisby_client::local_index::LocalIndex::open(&local_path).unwrap();
+        local.add(&metadata).unwrap();
+
+        // A client that didn't normalize its keywords before publishing
+        let index = SearchIndex::open(&dir.path().join("index.db")).unwrap();
+        let entry = IndexEntry {
+            content_hash: metadata.content_hash,
+            filename: metadata.filename.clone(),
+            keywords: ["Big_Buck-Bunny", "1080P", "mkv"].map(String::from).to_vec(),
+            tags: vec![],
+            size: metadata.size,
+            chunk_count: metadata.chunks.len() as u32,
+            chunk_size: metadata.chunk_size,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&entry, "seeder").unwrap();
+
+        let stored: String = index
+            .conn()
+            .query_row("SELECT keywords FROM entries", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, metadata.keywords.join(" "));
+        assert_eq!(metadata.keywords, keywords::extract("Big_Buck-Bunny.1080p.MKV"));
+
+        for query in ["bunny 1080p", "BIG-BUCK", "\"mkv\"", "b x"] {
+            let found_locally = local.search(query, 10).unwrap().len();
+            let found_by_index = search(&index, query).len();
+            assert_eq!(found_locally, found_by_index, "query {:?}", query);
+        }
+        let results = search(&index, "bunny 1080p");
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_probe_candidates_picks_recent_seeders() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let entry = |byte: u8, published_at: u64| IndexEntry {
+            content_hash: [byte; 32],
+            filename: format!("file-{}.bin", byte),
+            keywords: vec!["file".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at,
+            ttl: 3600,
+        };
+        index.upsert(&entry(1, 5000), "busy").unwrap();
+        index.upsert(&entry(2, 5000), "busy").unwrap();
+        index.upsert(&entry(3, 1000), "busy").unwrap();
+        index.upsert(&entry(1, 5000), "quiet").unwrap();
+        index.upsert(&entry(4, 1000), "gone-quiet").unwrap();
+
+        // Each seeder picked comes with all of its recent listings
+        let all = index.probe_candidates(4000, 10).unwrap();
+        assert_eq!(
+            all,
+            vec![
+                ([1u8; 32], "busy".to_string()),
+                ([2u8; 32], "busy".to_string()),
+                ([1u8; 32], "quiet".to_string()),
+            ]
+        );
+
+        // The limit counts seeders, not listings
+        for _ in 0..10 {
+            let sample = index.probe_candidates(4000, 1).unwrap();
+            let first = &sample[0].1;
+            assert!(sample.iter().all(|(_, address)| address == first));
+            assert_eq!(sample.len(), if first == "busy" { 2 } else { 1 });
+        }
+        assert!(index.probe_candidates(6000, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entry_removed_with_last_seeder() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let entry = IndexEntry {
+            content_hash: [4u8; 32],
+            filename: "retired.iso".to_string(),
+            keywords: vec!["retired".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&entry, "seeder-one").unwrap();
+        index.upsert_anonymous(&entry, "seeder-two", &[7u8; RELAY_TOKEN_LEN]).unwrap();
+
+        assert!(index.remove_seeder(&entry.content_hash, "seeder-one").unwrap());
+        assert!(!index.remove_seeder(&entry.content_hash, "seeder-one").unwrap());
+        let results = search(&index, "retired");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].seeders.is_empty());
+        assert_eq!(results[0].relay_tokens.len(), 1);
+
+        assert!(index.remove_seeder(&entry.content_hash, "seeder-two").unwrap());
+        assert!(search(&index, "retired").is_empty());
+        assert_eq!(index.stats().unwrap().entry_count, 0);
+        assert!(index.relay_address(&[7u8; RELAY_TOKEN_LEN]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unpublish_needs_latest_token() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let entry = IndexEntry {
+            content_hash: [5u8; 32],
+            filename: "kept.iso".to_string(),
+            keywords: vec!["kept".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&entry, "seeder").unwrap();
+        let hash = &entry.content_hash;
+
+        // Listings from before tokens can't be unpublished at all
+        assert!(!index.unpublish(hash, "seeder", &[]).unwrap());
+
+        assert!(index.set_unpublish_token(hash, "seeder", &[1u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert!(index.set_unpublish_token(hash, "seeder", &[2u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert!(!index.set_unpublish_token(hash, "other", &[3u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert!(!index.unpublish(hash, "seeder", &[1u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert!(!index.unpublish(hash, "other", &[2u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert_eq!(search(&index, "kept").len(), 1);
+
+        assert!(index.unpublish(hash, "seeder", &[2u8; UNPUBLISH_TOKEN_LEN]).unwrap());
+        assert!(search(&index, "kept").is_empty());
+    }
+
+    #[test]
+    fn test_seeders_of_hash() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let entry = |published_at, ttl| IndexEntry {
+            content_hash: [4u8; 32],
+            filename: "shared.iso".to_string(),
+            keywords: vec!["shared".to_string()],
+            tags: vec![],
+            size: 4096,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at,
+            ttl,
+        };
+        index.upsert(&entry(1000, 3600), "early").unwrap();
+        index.upsert(&entry(2000, 3600), "late").unwrap();
+        index.upsert(&entry(1500, 100), "lapsed").unwrap();
+        let token = index.upsert_anonymous(&entry(1800, 3600), "hidden", &[9u8; 16]).unwrap();
+
+        let seeders = index.seeders(&[4u8; 32], 1700).unwrap();
+        let addresses: Vec<_> = seeders.iter().map(|s| s.nym_address.as_deref()).collect();
+        assert_eq!(addresses, vec![Some("late"), None, Some("early")]);
+        assert_eq!((seeders[0].published_at, seeders[0].expires_at), (2000, 5600));
+        assert_eq!(seeders[1].relay_token.as_deref(), Some(token.as_slice()));
+        assert!(seeders[2].relay_token.is_none());
+
+        assert!(index.seeders(&[5u8; 32], 1700).unwrap().is_empty());
+    }
+
     #[test]
     fn test_multiple_seeders_aggregated() {
         let temp = NamedTempFile::new().unwrap();
@@ -278,8 +1555,12 @@ mod tests {
             content_hash: [2u8; 32],
             filename: "shared_file.txt".to_string(),
             keywords: vec!["shared".to_string()],
+            tags: vec![],
             size: 1024,
             chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
             published_at: 1000,
             ttl: 3600,
         };
@@ -289,13 +1570,666 @@ mod tests {
         // Second seeder publishes same file
         index.upsert(&entry, "seeder-two").unwrap();
 
-        let results = index.search("shared", 10).unwrap();
+        let results = search(&index, "shared");
         assert_eq!(results.len(), 1); // Should be deduplicated by content_hash
         assert_eq!(results[0].seeders.len(), 2);
         assert!(results[0].seeders.contains(&"seeder-one".to_string()));
         assert!(results[0].seeders.contains(&"seeder-two".to_string()));
     }
 
+    #[test]
+    fn test_equal_matches_ordered_by_seeder_count() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let publish = |byte: u8, filename: &str, seeders: usize| {
+            let entry = IndexEntry {
+                content_hash: [byte; 32],
+                filename: filename.to_string(),
+                keywords: keywords::extract(filename),
+                tags: vec![],
+                size: 1024,
+                chunk_count: 1,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: None,
+                published_at: 1000,
+                ttl: 3600,
+            };
+            for i in 0..seeders.max(1) {
+                index.upsert(&entry, &format!("seeder-{}", i)).unwrap();
+            }
+        };
+        publish(1, "track_aa.mp3", 1);
+        publish(2, "track_bb.mp3", 3);
+        publish(3, "track_cc.mp3", 2);
+        publish(4, "track_dd.mp3", 0);
+        publish(5, "track_track.mp3", 1);
+        // A file whose seeders all lapsed, not yet cleaned up
+        index
+            .conn()
+            .execute("DELETE FROM seeders WHERE content_hash = ?", [[4u8; 32].as_slice()])
+            .unwrap();
+
+        // Relevance still comes first, then the most seeded
+        let results = search(&index, "track");
+        let order: Vec<_> = results
+            .iter()
+            .map(|r| (r.filename.as_str(), r.seeder_count))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("track_track.mp3", 1),
+                ("track_bb.mp3", 3),
+                ("track_cc.mp3", 2),
+                ("track_aa.mp3", 1),
+                ("track_dd.mp3", 0),
+            ]
+        );
+
+        // Pages follow the same order
+        let page = index
+            .search("track", QueryMode::Keywords, 2, 2, 0.0, &SearchFilter::default())
+            .unwrap();
+        let names: Vec<_> = page.iter().map(|r| r.filename.as_str()).collect();
+        assert_eq!(names, vec!["track_cc.mp3", "track_aa.mp3"]);
+    }
+
+    #[test]
+    fn test_many_seeders_capped_without_truncation() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        let mut entry = IndexEntry {
+            content_hash: [4u8; 32],
+            filename: "popular.iso".to_string(),
+            keywords: vec!["popular".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+
+        // Long addresses so the unbounded concatenation would be large
+        let addresses: Vec<String> = (0..500)
+            .map(|i| format!("{:0>120}.seeder@gateway", i))
+            .collect();
+        for (i, address) in addresses.iter().enumerate() {
+            entry.published_at = 1000 + i as u64;
+            index.upsert(&entry, address).unwrap();
+        }
+
+        let results = search(&index, "popular");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].seeders.len(), MAX_SEEDERS_PER_RESULT);
+        for seeder in &results[0].seeders {
+            assert!(addresses.contains(seeder), "malformed seeder: {}", seeder);
+        }
+        // Most recently published seeders come first
+        assert_eq!(results[0].seeders[0], addresses[addresses.len() - 1]);
+    }
+
+    #[test]
+    fn test_cleanup_deletes_in_batches_without_blocking_reads() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        index.conn().execute_batch("BEGIN").unwrap();
+        for i in 0..2000u32 {
+            let mut content_hash = [0u8; 32];
+            content_hash[..4].copy_from_slice(&i.to_be_bytes());
+            let entry = IndexEntry {
+                content_hash,
+                filename: format!("expired_{}.log", i),
+                keywords: vec!["expired".to_string()],
+                tags: vec![],
+                size: 1024,
+                chunk_count: 1,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: None,
+                published_at: 1000,
+                ttl: 60,
+            };
+            index.upsert(&entry, "seeder-a").unwrap();
+            index.upsert(&entry, "seeder-b").unwrap();
+        }
+        index.conn().execute_batch("COMMIT").unwrap();
+
+        // Cleanup on one connection while another keeps querying
+        let cleanup = std::thread::spawn(move || index.cleanup_expired(5000, 100).unwrap());
+        let reader = SearchIndex::open(temp.path()).unwrap();
+        let mut slowest = Duration::ZERO;
+        while !cleanup.is_finished() {
+            let started = std::time::Instant::now();
+            reader.stats().unwrap();
+            slowest = slowest.max(started.elapsed());
+        }
+        assert!(slowest < Duration::from_secs(1), "read blocked for {:?}", slowest);
+
+        // 4000 seeders and 2000 entries, 100 at a time
+        assert_eq!(cleanup.join().unwrap(), 6000);
+        assert_eq!(reader.stats().unwrap().entry_count, 0);
+        assert!(search(&reader, "expired").is_empty());
+    }
+
+    #[test]
+    fn test_expired_seeder_kept_through_grace_period() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap().with_expiry_grace(600);
+        let entry = IndexEntry {
+            content_hash: [6u8; 32],
+            filename: "flaky.iso".to_string(),
+            keywords: vec!["flaky".to_string()],
+            tags: vec![],
+            size: 4096,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&entry, "flaky").unwrap();
+
+        // Fresh until the TTL runs out
+        let seeders = index.seeders(&[6u8; 32], 4599).unwrap();
+        assert_eq!(seeders.len(), 1);
+        assert!(!seeders[0].stale);
+
+        // Just expired: still listed, as stale, and survives cleanup
+        assert_eq!(index.cleanup_expired(4600, 100).unwrap(), 0);
+        let seeders = index.seeders(&[6u8; 32], 4600).unwrap();
+        assert_eq!(seeders.len(), 1);
+        assert!(seeders[0].stale);
+        assert_eq!(search(&index, "flaky").len(), 1);
+
+        // Republishing within the window makes it fresh again
+        index.upsert(&IndexEntry { published_at: 4700, ..entry.clone() }, "flaky").unwrap();
+        assert!(!index.seeders(&[6u8; 32], 4800).unwrap()[0].stale);
+
+        // Gone, with its entry, once the grace period is over too
+        assert_eq!(index.cleanup_expired(8899, 100).unwrap(), 0);
+        assert_eq!(index.cleanup_expired(8900, 100).unwrap(), 2);
+        assert!(index.seeders(&[6u8; 32], 8900).unwrap().is_empty());
+        assert!(search(&index, "flaky").is_empty());
+    }
+
+    #[test]
+    fn test_cap_evicts_by_policy() {
+        let entry = |byte: u8, published_at| IndexEntry {
+            content_hash: [byte; 32],
+            filename: format!("file{}.bin", byte),
+            keywords: vec!["capped".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at,
+            ttl: 3600,
+        };
+        let hashes = |index: &SearchIndex| {
+            let mut hashes: Vec<u8> = search(index, "capped")
+                .iter()
+                .map(|result| result.content_hash[0])
+                .collect();
+            hashes.sort();
+            hashes
+        };
+
+        // Oldest publish goes first; republishing counts as fresh
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap().with_limit(Some(IndexLimit {
+            max_entries: 3,
+            policy: EvictionPolicy::OldestPublished,
+        }));
+        index.upsert(&entry(1, 100), "seeder-a").unwrap();
+        index.upsert(&entry(2, 200), "seeder-a").unwrap();
+        index.upsert(&entry(3, 300), "seeder-a").unwrap();
+        index.upsert(&entry(1, 400), "seeder-b").unwrap();
+        index.upsert(&entry(4, 500), "seeder-a").unwrap();
+        assert_eq!(hashes(&index), vec![1, 3, 4]);
+        assert_eq!(index.stats().unwrap().entry_count, 3);
+
+        // Popular files outlive fresher ones with a single seeder
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        index.upsert(&entry(1, 100), "seeder-a").unwrap();
+        index.upsert(&entry(1, 100), "seeder-b").unwrap();
+        index.upsert(&entry(2, 200), "seeder-a").unwrap();
+        index.upsert(&entry(3, 300), "seeder-a").unwrap();
+        index.upsert(&entry(4, 400), "seeder-a").unwrap();
+        let index = index.with_limit(Some(IndexLimit {
+            max_entries: 2,
+            policy: EvictionPolicy::FewestSeeders,
+        }));
+        assert_eq!(index.evict_over_limit(1).unwrap(), 2);
+        assert_eq!(hashes(&index), vec![1, 4]);
+
+        // Seeders of evicted files go with them
+        let seeders: i64 = index
+            .conn()
+            .query_row("SELECT COUNT(*) FROM seeders", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(seeders, 3);
+        assert_eq!("fewest-seeders".parse(), Ok(EvictionPolicy::FewestSeeders));
+    }
+
+    #[test]
+    fn test_anonymous_seeder_listed_by_token() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        let entry = IndexEntry {
+            content_hash: [5u8; 32],
+            filename: "leaks.pdf".to_string(),
+            keywords: vec!["leaks".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&entry, "public-seeder").unwrap();
+        let token = index
+            .upsert_anonymous(&entry, "private-seeder", &[1u8; RELAY_TOKEN_LEN])
+            .unwrap();
+        assert_eq!(token, vec![1u8; RELAY_TOKEN_LEN]);
+
+        let results = search(&index, "leaks");
+        assert_eq!(results[0].seeders, vec!["public-seeder"]);
+        assert_eq!(results[0].relay_tokens, vec![token.clone()]);
+        assert_eq!(
+            index.relay_address(&token).unwrap().as_deref(),
+            Some("private-seeder")
+        );
+        assert!(index.relay_address(&[2u8; RELAY_TOKEN_LEN]).unwrap().is_none());
+
+        // Republishing keeps the token that was handed out
+        let again = index
+            .upsert_anonymous(&entry, "private-seeder", &[3u8; RELAY_TOKEN_LEN])
+            .unwrap();
+        assert_eq!(again, token);
+
+        // Publishing publicly retires it
+        index.upsert(&entry, "private-seeder").unwrap();
+        let results = search(&index, "leaks");
+        assert_eq!(results[0].seeders.len(), 2);
+        assert!(results[0].relay_tokens.is_empty());
+        assert!(index.relay_address(&token).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_min_relevance_filters_weak_matches() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        // Tagged entry matches strongly, the filename-only one weakly
+        let strong = IndexEntry {
+            content_hash: [7u8; 32],
+            filename: "live_set.flac".to_string(),
+            keywords: vec!["live".to_string(), "set".to_string()],
+            tags: vec!["jazz".to_string()],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        let weak = IndexEntry {
+            content_hash: [8u8; 32],
+            filename: "jazz_cafe_receipt_scan.pdf".to_string(),
+            keywords: vec![
+                "jazz".to_string(),
+                "cafe".to_string(),
+                "receipt".to_string(),
+                "scan".to_string(),
+            ],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        index.upsert(&strong, "seeder").unwrap();
+        index.upsert(&weak, "seeder").unwrap();
+
+        // A threshold of 0 keeps every match
+        let all = search(&index, "jazz");
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].filename, "live_set.flac");
+        let weak_fraction = all[1].relevance / all[0].relevance;
+        assert!(weak_fraction < 1.0);
+
+        // A threshold between the two drops the weak match
+        let threshold = (weak_fraction + 1.0) / 2.0;
+        let filtered = index
+            .search("jazz", QueryMode::Keywords, 10, 0, threshold, &SearchFilter::default())
+            .unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].filename, "live_set.flac");
+        assert_eq!(count(&index, "jazz", threshold), 1);
+
+        // The threshold is relative to the best match overall, so a later
+        // page holding only weak matches is still filtered
+        assert!(index
+            .search("jazz", QueryMode::Keywords, 10, 1, threshold, &SearchFilter::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_search_pages_through_matches() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        for i in 0..7u8 {
+            let entry = IndexEntry {
+                content_hash: [i + 1; 32],
+                filename: format!("episode_{}.mkv", i),
+                keywords: vec!["episode".to_string()],
+                tags: vec![],
+                size: 1024,
+                chunk_count: 1,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: None,
+                published_at: 1000,
+                ttl: 3600,
+            };
+            index.upsert(&entry, "seeder").unwrap();
+        }
+
+        assert_eq!(count(&index, "episode", 0.0), 7);
+        assert_eq!(count(&index, "nothing", 0.0), 0);
+        assert_eq!(count(&index, "--", 0.0), 0);
+
+        // Pages don't overlap and together cover every match
+        let mut seen = std::collections::HashSet::new();
+        for offset in [0, 3, 6] {
+            let page = index
+                .search("episode", QueryMode::Keywords, 3, offset, 0.0, &SearchFilter::default())
+                .unwrap();
+            assert_eq!(page.len(), if offset == 6 { 1 } else { 3 });
+            for result in page {
+                assert!(seen.insert(result.content_hash));
+            }
+        }
+        assert_eq!(seen.len(), 7);
+        assert!(index
+            .search("episode", QueryMode::Keywords, 3, 7, 0.0, &SearchFilter::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_search_filters_by_size_type_and_recency() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        let files = [
+            ("small.mp4", 100, Some("video/mp4"), 1000),
+            ("big.mp4", 5000, Some("video/mp4"), 1000),
+            ("big.mkv", 6000, Some("Video/X-Matroska"), 3000),
+            ("big.flac", 7000, Some("audio/flac"), 3000),
+            ("big.unknown", 8000, None, 3000),
+        ];
+        for (i, (filename, size, mime_type, published_at)) in files.into_iter().enumerate() {
+            let entry = IndexEntry {
+                content_hash: [i as u8 + 1; 32],
+                filename: filename.to_string(),
+                keywords: vec!["movie".to_string()],
+                tags: vec![],
+                size,
+                chunk_count: 1,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: mime_type.map(str::to_string),
+                published_at,
+                ttl: 3600,
+            };
+            index.upsert(&entry, "seeder").unwrap();
+        }
+        let found = |filter: &SearchFilter| {
+            let mut names: Vec<String> = index
+                .search("movie", QueryMode::Keywords, 10, 0, 0.0, filter)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.filename)
+                .collect();
+            names.sort();
+            let total = index.count_matches("movie", QueryMode::Keywords, 0.0, filter).unwrap();
+            assert_eq!(total as usize, names.len());
+            names
+        };
+
+        assert_eq!(found(&SearchFilter::default()).len(), 5);
+        let large = SearchFilter {
+            min_size: Some(1000),
+            max_size: Some(7000),
+            ..Default::default()
+        };
+        assert_eq!(found(&large), ["big.flac", "big.mkv", "big.mp4"]);
+
+        // A top-level type matches every subtype, case aside
+        let large_video = SearchFilter {
+            mime_type: Some("VIDEO".to_string()),
+            ..large.clone()
+        };
+        assert_eq!(found(&large_video), ["big.mkv", "big.mp4"]);
+        let exact = SearchFilter {
+            mime_type: Some("video/mp4".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(found(&exact), ["big.mp4", "small.mp4"]);
+
+        let recent_large_video = SearchFilter {
+            published_after: Some(2000),
+            ..large_video.clone()
+        };
+        assert_eq!(found(&recent_large_video), ["big.mkv"]);
+
+        // Republishing doesn't make a file count as new
+        let mut republished = IndexEntry {
+            content_hash: [2; 32],
+            filename: "big.mp4".to_string(),
+            keywords: vec!["movie".to_string()],
+            tags: vec![],
+            size: 5000,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 4000,
+            ttl: 3600,
+        };
+        index.upsert(&republished, "other-seeder").unwrap();
+        assert_eq!(found(&recent_large_video), ["big.mkv"]);
+        // ...nor does a publisher that didn't detect the type erase it
+        assert_eq!(found(&exact), ["big.mp4", "small.mp4"]);
+        republished.mime_type = Some("not a mime type".to_string());
+        index.upsert(&republished, "other-seeder").unwrap();
+        assert_eq!(found(&exact), ["big.mp4", "small.mp4"]);
+    }
+
+    #[test]
+    fn test_tag_match_outranks_filename_token() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+
+        // "jazz" only appears incidentally in the filename
+        let incidental = IndexEntry {
+            content_hash: [5u8; 32],
+            filename: "jazz_cafe_receipt.pdf".to_string(),
+            keywords: vec!["jazz".to_string(), "cafe".to_string(), "receipt".to_string()],
+            tags: vec![],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        // "jazz" is a deliberate tag on an otherwise unrelated filename
+        let tagged = IndexEntry {
+            content_hash: [6u8; 32],
+            filename: "track01_final_master.flac".to_string(),
+            keywords: vec!["track01".to_string(), "final".to_string(), "master".to_string()],
+            tags: vec!["jazz".to_string()],
+            size: 1024,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+
+        index.upsert(&incidental, "seeder").unwrap();
+        index.upsert(&tagged, "seeder").unwrap();
+
+        let results = search(&index, "jazz");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].content_hash, tagged.content_hash);
+        assert!(results[0].relevance > results[1].relevance);
+    }
+
+    #[test]
+    fn test_migrates_index_without_tags_column() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp.path()).unwrap();
+            conn.execute_batch(
+                r#"
+                CREATE TABLE entries (
+                    content_hash BLOB PRIMARY KEY,
+                    filename TEXT NOT NULL,
+                    keywords TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    chunk_count INTEGER NOT NULL
+                );
+                CREATE VIRTUAL TABLE entries_fts USING fts5(
+                    filename, keywords, content='entries', content_rowid='rowid'
+                );
+                CREATE TRIGGER entries_ai AFTER INSERT ON entries BEGIN
+                    INSERT INTO entries_fts(rowid, filename, keywords)
+                    VALUES (new.rowid, new.filename, new.keywords);
+                END;
+                INSERT INTO entries VALUES (X'07', 'legacy.txt', 'legacy', 10, 1);
+                "#,
+            )
+            .unwrap();
+        }
+
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let results = search(&index, "legacy");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "legacy.txt");
+    }
+
+    #[test]
+    fn test_migrates_seeders_without_relay_token_column() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp.path()).unwrap();
+            conn.execute_batch(
+                r#"
+                CREATE TABLE seeders (
+                    content_hash BLOB NOT NULL,
+                    nym_address TEXT NOT NULL,
+                    published_at INTEGER NOT NULL,
+                    ttl INTEGER NOT NULL,
+                    PRIMARY KEY (content_hash, nym_address)
+                );
+                "#,
+            )
+            .unwrap();
+        }
+
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let entry = IndexEntry {
+            content_hash: [6u8; 32],
+            filename: "old.txt".to_string(),
+            keywords: vec![],
+            tags: vec![],
+            size: 10,
+            chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
+            published_at: 1000,
+            ttl: 3600,
+        };
+        let token = index
+            .upsert_anonymous(&entry, "seeder", &[1u8; RELAY_TOKEN_LEN])
+            .unwrap();
+        assert_eq!(index.relay_address(&token).unwrap().as_deref(), Some("seeder"));
+    }
+
+    #[test]
+    fn test_migrates_entries_without_filter_columns() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp.path()).unwrap();
+            conn.execute_batch(
+                r#"
+                CREATE TABLE entries (
+                    content_hash BLOB PRIMARY KEY,
+                    filename TEXT NOT NULL,
+                    keywords TEXT NOT NULL,
+                    tags TEXT NOT NULL DEFAULT '',
+                    size INTEGER NOT NULL,
+                    chunk_count INTEGER NOT NULL
+                );
+                CREATE TABLE seeders (
+                    content_hash BLOB NOT NULL,
+                    nym_address TEXT NOT NULL,
+                    published_at INTEGER NOT NULL,
+                    ttl INTEGER NOT NULL,
+                    relay_token BLOB,
+                    PRIMARY KEY (content_hash, nym_address)
+                );
+                CREATE VIRTUAL TABLE entries_fts USING fts5(
+                    filename, keywords, tags, content='entries', content_rowid='rowid'
+                );
+                CREATE TRIGGER entries_ai AFTER INSERT ON entries BEGIN
+                    INSERT INTO entries_fts(rowid, filename, keywords, tags)
+                    VALUES (new.rowid, new.filename, new.keywords, new.tags);
+                END;
+                INSERT INTO entries VALUES (X'08', 'older.txt', 'older', '', 10, 1);
+                INSERT INTO seeders VALUES (X'08', 'a', 2000, 3600, NULL);
+                INSERT INTO seeders VALUES (X'08', 'b', 1500, 3600, NULL);
+                "#,
+            )
+            .unwrap();
+        }
+
+        // Entries take the time of their oldest seeder's publish
+        let index = SearchIndex::open(temp.path()).unwrap();
+        let since = |published_after| SearchFilter {
+            published_after: Some(published_after),
+            ..Default::default()
+        };
+        let count = |filter| index.count_matches("older", QueryMode::Keywords, 0.0, &filter);
+        assert_eq!(count(since(1000)).unwrap(), 1);
+        assert_eq!(count(since(1500)).unwrap(), 0);
+    }
+
     #[test]
     fn test_search_with_special_characters() {
         let temp = NamedTempFile::new().unwrap();
@@ -305,8 +2239,12 @@ mod tests {
             content_hash: [3u8; 32],
             filename: "test-file-with-hyphens.txt".to_string(),
             keywords: vec!["test-keyword".to_string(), "another:colon".to_string()],
+            tags: vec![],
             size: 1024,
             chunk_count: 1,
+            chunk_size: brisby_core::CHUNK_SIZE as u32,
+            content_defined: None,
+            mime_type: None,
             published_at: 1000,
             ttl: 3600,
         };
@@ -314,36 +2252,129 @@ mod tests {
         index.upsert(&entry, "seeder").unwrap();
 
         // Search with hyphenated query should work
-        let results = index.search("test-file", 10).unwrap();
+        let results = search(&index, "test-file");
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].filename, "test-file-with-hyphens.txt");
 
         // Search with colon should also work
-        let results = index.search("another:colon", 10).unwrap();
+        let results = search(&index, "another:colon");
         assert_eq!(results.len(), 1);
     }
 
     #[test]
-    fn test_escape_fts_query() {
-        // Basic word
-        assert_eq!(SearchIndex::escape_fts_query("hello"), "\"hello\"");
+    fn test_operator_syntax_only_in_advanced_mode() {
+        let temp = NamedTempFile::new().unwrap();
+        let index = SearchIndex::open(temp.path()).unwrap();
+        for (i, filename) in ["foo-bar.txt", "a_and_b.txt", "big_buck.mkv"].iter().enumerate() {
+            let entry = IndexEntry {
+                content_hash: [i as u8; 32],
+                filename: filename.to_string(),
+                keywords: keywords::extract(filename),
+                tags: vec![],
+                size: 1024,
+                chunk_count: 1,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: None,
+                published_at: 1000,
+                ttl: 3600,
+            };
+            index.upsert(&entry, "seeder").unwrap();
+        }
 
-        // Hyphenated word
-        assert_eq!(SearchIndex::escape_fts_query("test-file"), "\"test-file\"");
+        // Operator characters are matched as words, never as syntax
+        assert_eq!(search(&index, "foo\"bar")[0].filename, "foo-bar.txt");
+        assert_eq!(search(&index, "a AND")[0].filename, "a_and_b.txt");
+        assert!(search(&index, "*").is_empty());
+        assert_eq!(count(&index, "*", 0.0), 0);
 
-        // Multiple words
-        assert_eq!(
-            SearchIndex::escape_fts_query("hello world"),
-            "\"hello\" \"world\""
-        );
+        let advanced = |query| {
+            index.search(query, QueryMode::Advanced, 10, 0, 0.0, &SearchFilter::default())
+        };
+        let mut found: Vec<_> = advanced("big OR foo*")
+            .unwrap()
+            .into_iter()
+            .map(|result| result.filename)
+            .collect();
+        found.sort();
+        assert_eq!(found, vec!["big_buck.mkv", "foo-bar.txt"]);
+        for malformed in ["a AND", "foo\"bar", "(big", "nosuchcolumn:big"] {
+            let error = advanced(malformed).unwrap_err();
+            assert!(is_query_error(&error), "{:?}: {}", malformed, error);
+        }
+    }
 
-        // Word with colon
-        assert_eq!(SearchIndex::escape_fts_query("file:name"), "\"file:name\"");
 
-        // Word with quotes (should be escaped)
-        assert_eq!(
-            SearchIndex::escape_fts_query("say \"hello\""),
-            "\"say\" \"\"\"hello\"\"\""
-        );
+    fn corrupt_backups(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains(".corrupt-"))
+            .collect()
+    }
+
+    #[test]
+    fn test_recovers_rows_from_corrupt_database() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("index.db");
+
+        let index = SearchIndex::open(&path).unwrap();
+        for i in 0..300u32 {
+            let mut content_hash = [0u8; 32];
+            content_hash[..4].copy_from_slice(&i.to_be_bytes());
+            let entry = IndexEntry {
+                content_hash,
+                filename: format!("episode_{}.mkv", i),
+                keywords: vec!["episode".to_string()],
+                tags: vec![],
+                size: 1024,
+                chunk_count: 4,
+                chunk_size: brisby_core::CHUNK_SIZE as u32,
+                content_defined: None,
+                mime_type: None,
+                published_at: 1000,
+                ttl: 3600,
+            };
+            index.upsert(&entry, &format!("seeder-{}", i)).unwrap();
+        }
+        let (root_page, page_size): (u64, u64) = index
+            .conn()
+            .query_row(
+                "SELECT rootpage, (SELECT page_size FROM pragma_page_size)
+                 FROM sqlite_master WHERE name = 'idx_seeders_ttl'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        drop(index);
+
+        // Scribble over an index page, as a bad disk might
+        let mut bytes = std::fs::read(&path).unwrap();
+        let start = ((root_page - 1) * page_size) as usize;
+        bytes[start..start + page_size as usize].fill(0xa5);
+        std::fs::write(&path, bytes).unwrap();
+        assert!(SearchIndex::check_integrity(&path).is_err());
+
+        // Opening moves the damaged file aside and keeps the readable rows
+        let index = SearchIndex::open(&path).unwrap();
+        assert_eq!(corrupt_backups(dir.path()).len(), 1);
+        assert!(SearchIndex::check_integrity(&path).is_ok());
+        assert_eq!(index.stats().unwrap().entry_count, 300);
+        let results = search(&index, "episode_7");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].seeders, vec!["seeder-7"]);
+    }
+
+    #[test]
+    fn test_replaces_unreadable_database() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("index.db");
+        std::fs::write(&path, vec![0x5au8; 8192]).unwrap();
+
+        let index = SearchIndex::open(&path).unwrap();
+        assert_eq!(index.stats().unwrap().entry_count, 0);
+        let backups = corrupt_backups(dir.path());
+        assert_eq!(backups.len(), 1);
+        assert_eq!(std::fs::read(&backups[0]).unwrap(), vec![0x5au8; 8192]);
     }
 }
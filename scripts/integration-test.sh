@@ -161,7 +161,7 @@ start_index_provider() {
         return 0
     fi
 
-    "$BRISBY_INDEX" -d "$INDEX_DIR" > "$INDEX_LOG" 2>&1 &
+    "$BRISBY_INDEX" -d "$INDEX_DIR" --identity-dir "$INDEX_DIR/identity" > "$INDEX_LOG" 2>&1 &
     INDEX_PID=$!
 
     log_info "Waiting for index provider to connect to Nym (up to ${NYM_TIMEOUT}s)..."
@@ -191,7 +191,7 @@ start_seeder() {
         return 0
     fi
 
-    "$BRISBY" -d "$SEEDER_DIR" seed -f "$TEST_FILE" -p --index-provider "$INDEX_ADDR" > "$SEEDER_LOG" 2>&1 &
+    "$BRISBY" -d "$SEEDER_DIR" --identity-dir "$SEEDER_DIR/identity" seed -f "$TEST_FILE" -p --index-provider "$INDEX_ADDR" > "$SEEDER_LOG" 2>&1 &
     SEEDER_PID=$!
 
     log_info "Waiting for seeder to connect to Nym (up to ${NYM_TIMEOUT}s)..."